@@ -0,0 +1,154 @@
+// IP Display Audio - GStreamer Backend
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Playback through the system's GStreamer. Chunks go into an
+//! `appsrc ! [opusdec !] audioconvert ! audioresample ! autoaudiosink`
+//! pipeline, so the sound reaches PipeWire, PulseAudio or ALSA, whichever
+//! the desktop runs.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSrc;
+
+use crate::{AudioCodec, AudioFormat, Player};
+
+/// Elements every pipeline is built from
+const PIPELINE_ELEMENTS: [&str; 4] = ["appsrc", "audioconvert", "audioresample", "autoaudiosink"];
+
+/// Most sound queued before chunks are dropped, in fractions of a second
+const MAX_QUEUED_DIVISOR: u32 = 5;
+
+/// Queue limit for compressed audio, whose byte rate isn't known
+const MAX_QUEUED_COMPRESSED: u64 = 16 * 1024;
+
+pub struct GstPlayer {
+    stream: Option<Stream>,
+}
+
+impl GstPlayer {
+    /// Initialise GStreamer and check the pipeline can be built
+    pub fn new() -> Result<Self> {
+        gst::init().context("Failed to initialise GStreamer")?;
+        for name in PIPELINE_ELEMENTS {
+            if gst::ElementFactory::find(name).is_none() {
+                return Err(anyhow::anyhow!(
+                    "GStreamer has no {} element; install its base plugins", name
+                ));
+            }
+        }
+        Ok(Self { stream: None })
+    }
+}
+
+impl Player for GstPlayer {
+    fn name(&self) -> &'static str {
+        "gstreamer"
+    }
+
+    fn play(&mut self, format: AudioFormat, data: &[u8]) -> Result<()> {
+        if self.stream.as_ref().map(|stream| stream.format) != Some(format) {
+            self.stream = Some(Stream::open(format)?);
+        }
+
+        // A failed pipeline is rebuilt with the next chunk
+        let played = self.stream.as_ref().unwrap().play(data);
+        if played.is_err() {
+            self.reset();
+        }
+        played
+    }
+
+    fn reset(&mut self) {
+        self.stream = None;
+    }
+}
+
+/// A running pipeline for one format
+struct Stream {
+    format: AudioFormat,
+    pipeline: gst::Pipeline,
+    src: AppSrc,
+    max_queued: u64,
+}
+
+impl Stream {
+    fn open(format: AudioFormat) -> Result<Self> {
+        let src = AppSrc::builder()
+            .caps(&caps(format))
+            .is_live(true)
+            .do_timestamp(true)
+            .format(gst::Format::Time)
+            .build();
+        let mut elements = vec![src.clone().upcast::<gst::Element>()];
+        if format.codec == AudioCodec::Opus {
+            elements.push(
+                gst::ElementFactory::make("opusdec")
+                    .build()
+                    .context("GStreamer has no opusdec element; install its base plugins")?,
+            );
+        }
+        for name in &PIPELINE_ELEMENTS[1..] {
+            elements.push(gst::ElementFactory::make(name).build()?);
+        }
+
+        let pipeline = gst::Pipeline::new();
+        pipeline.add_many(&elements)?;
+        gst::Element::link_many(&elements)?;
+        pipeline.set_state(gst::State::Playing)?;
+
+        let max_queued = format
+            .byte_rate()
+            .map_or(MAX_QUEUED_COMPRESSED, |rate| u64::from(rate / MAX_QUEUED_DIVISOR));
+        Ok(Self { format, pipeline, src, max_queued })
+    }
+
+    fn play(&self, data: &[u8]) -> Result<()> {
+        self.check_bus()?;
+        // The sink has fallen behind; catching up beats lagging the picture
+        if self.src.current_level_bytes() > self.max_queued {
+            return Ok(());
+        }
+        self.src
+            .push_buffer(gst::Buffer::from_slice(data.to_vec()))
+            .map_err(|e| anyhow::anyhow!("GStreamer refused the samples: {:?}", e))?;
+        Ok(())
+    }
+
+    /// The first error the pipeline has posted, if any
+    fn check_bus(&self) -> Result<()> {
+        let bus = self.pipeline.bus().context("Pipeline has no bus")?;
+        match bus.pop_filtered(&[gst::MessageType::Error]) {
+            Some(message) => match message.view() {
+                gst::MessageView::Error(error) => Err(anyhow::anyhow!("GStreamer: {}", error.error())),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// What appsrc announces for `format`
+fn caps(format: AudioFormat) -> gst::Caps {
+    let (rate, channels) = (format.rate as i32, format.channels as i32);
+    match format.codec {
+        AudioCodec::Pcm => gst::Caps::builder("audio/x-raw")
+            .field("format", "S16LE")
+            .field("layout", "interleaved")
+            .field("rate", rate)
+            .field("channels", channels)
+            .build(),
+        AudioCodec::Opus => gst::Caps::builder("audio/x-opus")
+            .field("channel-mapping-family", 0i32)
+            .field("rate", rate)
+            .field("channels", channels)
+            .build(),
+    }
+}
@@ -0,0 +1,47 @@
+// IP Display Audio - Playback
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Playback of the sound a server streams with its display. AUDIO packets
+//! each carry their format and a chunk of samples, which a player queues
+//! for the sound server as they arrive. Each backend sits behind its own
+//! feature, since each links a system library.
+
+use anyhow::Result;
+
+#[cfg(feature = "gstreamer")]
+pub mod gstreamer;
+
+// Formats are part of the wire format, so they come from the protocol
+pub use ipds_protocol::{AudioCodec, AudioFormat, MAX_CHANNELS};
+
+/// Something that plays one stream's sound at a time
+pub trait Player: Send {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+
+    /// Queue a chunk of samples in `format` after the last one. Players
+    /// drop chunks rather than let the sound fall far behind the picture.
+    /// A change of format starts a new stream.
+    fn play(&mut self, format: AudioFormat, data: &[u8]) -> Result<()>;
+
+    /// Stop and forget the current stream, e.g. after a reconnect
+    fn reset(&mut self);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(AudioCodec::try_from(1).unwrap(), AudioCodec::Opus);
+        assert!(AudioCodec::try_from(2).is_err());
+
+        let pcm = AudioFormat::new(AudioCodec::Pcm, 48000, 2).unwrap();
+        assert_eq!(pcm.byte_rate(), Some(192_000));
+        assert_eq!(AudioFormat::new(AudioCodec::Opus, 48000, 2).unwrap().byte_rate(), None);
+        assert!(AudioFormat::new(AudioCodec::Pcm, 0, 2).is_err());
+        assert!(AudioFormat::new(AudioCodec::Pcm, 48000, 9).is_err());
+    }
+}
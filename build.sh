@@ -15,7 +15,7 @@ echo "Kernel module built successfully"
 
 # Build Rust client
 echo "Building Rust client..."
-cd ../client
+cd ..
 cargo build --release
 
 echo "Rust client built successfully"
@@ -26,4 +26,4 @@ echo "To install:"
 echo "  sudo insmod kernel/ipdisp.ko"
 echo ""
 echo "To run client:"
-echo "  ./client/target/release/ip-display-client --server <ip>"
+echo "  ./target/release/ip-display-client --server <ip>"
@@ -0,0 +1,136 @@
+// IP Display Client - Picture Adjustments
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Brightness, contrast, gamma and saturation, for captures that come out
+//! too dark or washed out when the source can't be changed. Gamma is a
+//! curve, so it is a lookup table applied to each frame as it is copied
+//! for display. The rest are linear, and become one colour matrix that
+//! GSK applies while compositing.
+
+/// Lowest and highest value each setting takes
+pub const BRIGHTNESS_RANGE: (f64, f64) = (-1.0, 1.0);
+pub const CONTRAST_RANGE: (f64, f64) = (0.0, 2.0);
+pub const GAMMA_RANGE: (f64, f64) = (0.2, 5.0);
+pub const SATURATION_RANGE: (f64, f64) = (0.0, 2.0);
+
+/// Rec. 709 luma weights, for desaturating
+const LUMA: [f64; 3] = [0.2126, 0.7152, 0.0722];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adjustments {
+    /// Added to each channel, 0 unchanged
+    pub brightness: f64,
+    /// Spread around mid grey, 1 unchanged
+    pub contrast: f64,
+    /// Above 1 lifts the midtones, 1 unchanged
+    pub gamma: f64,
+    /// 0 is greyscale, 1 unchanged
+    pub saturation: f64,
+}
+
+impl Default for Adjustments {
+    fn default() -> Self {
+        Self { brightness: 0.0, contrast: 1.0, gamma: 1.0, saturation: 1.0 }
+    }
+}
+
+impl Adjustments {
+    /// Each setting limited to its range
+    pub fn clamped(self) -> Self {
+        let clamp = |value: f64, (low, high): (f64, f64)| value.clamp(low, high);
+        Self {
+            brightness: clamp(self.brightness, BRIGHTNESS_RANGE),
+            contrast: clamp(self.contrast, CONTRAST_RANGE),
+            gamma: clamp(self.gamma, GAMMA_RANGE),
+            saturation: clamp(self.saturation, SATURATION_RANGE),
+        }
+    }
+
+    /// Byte values after gamma, or `None` when gamma leaves them alone
+    pub fn gamma_table(&self) -> Option<[u8; 256]> {
+        if (self.gamma - 1.0).abs() < 0.005 {
+            return None;
+        }
+        let mut table = [0u8; 256];
+        for (value, out) in table.iter_mut().enumerate() {
+            *out = ((value as f64 / 255.0).powf(1.0 / self.gamma) * 255.0).round() as u8;
+        }
+        Some(table)
+    }
+
+    /// Contrast, then brightness, then saturation, as a matrix and offset
+    /// for straight RGBA in 0..1, or `None` when they leave colours alone.
+    /// The matrix is row-major and multiplies row vectors (`v * M`), as
+    /// graphene and GSK's colour matrix nodes expect.
+    pub fn color_matrix(&self) -> Option<([f32; 16], [f32; 4])> {
+        let unchanged = self.brightness.abs() < 0.005
+            && (self.contrast - 1.0).abs() < 0.005
+            && (self.saturation - 1.0).abs() < 0.005;
+        if unchanged {
+            return None;
+        }
+
+        // out = S * (contrast * in + offset): the offset is the same for
+        // every channel, and S keeps a grey grey, so it passes through
+        let offset = (0.5 * (1.0 - self.contrast) + self.brightness) as f32;
+        let mut matrix = [0f32; 16];
+        for (row, weight) in LUMA.iter().enumerate() {
+            for column in 0..3 {
+                let identity = if row == column { self.saturation } else { 0.0 };
+                matrix[row * 4 + column] = ((identity + (1.0 - self.saturation) * weight) * self.contrast) as f32;
+            }
+        }
+        matrix[15] = 1.0;
+        Some((matrix, [offset, offset, offset, 0.0]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An RGB colour through `color_matrix`
+    fn apply(adjustments: Adjustments, rgb: [f32; 3]) -> [f32; 3] {
+        let (matrix, offset) = adjustments.color_matrix().unwrap();
+        let input = [rgb[0], rgb[1], rgb[2], 1.0];
+        let mut out = [0f32; 3];
+        for (column, value) in out.iter_mut().enumerate() {
+            *value = (0..4).map(|row| input[row] * matrix[row * 4 + column]).sum::<f32>() + offset[column];
+        }
+        out
+    }
+
+    fn close(a: [f32; 3], b: [f32; 3]) -> bool {
+        a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-4)
+    }
+
+    #[test]
+    fn test_adjustments() {
+        let none = Adjustments::default();
+        assert!(none.gamma_table().is_none() && none.color_matrix().is_none());
+
+        let brighter = Adjustments { brightness: 0.1, ..none };
+        assert!(close(apply(brighter, [0.2, 0.4, 0.6]), [0.3, 0.5, 0.7]));
+
+        let contrast = Adjustments { contrast: 2.0, ..none };
+        assert!(close(apply(contrast, [0.5, 0.75, 0.25]), [0.5, 1.0, 0.0]));
+
+        // Greyscale is the luma, and greys never change colour
+        let grey = Adjustments { saturation: 0.0, ..none };
+        let luma = 0.2126 + 0.7152 * 0.5;
+        assert!(close(apply(grey, [1.0, 0.5, 0.0]), [luma; 3]));
+        let vivid = Adjustments { saturation: 1.8, contrast: 1.2, ..none };
+        let out = apply(vivid, [0.4, 0.4, 0.4]);
+        assert!(close(out, [out[0]; 3]));
+
+        let table = Adjustments { gamma: 2.2, ..none }.gamma_table().unwrap();
+        assert_eq!((table[0], table[255]), (0, 255));
+        assert!(table[64] > 64 && table[128] > 128);
+        let darker = Adjustments { gamma: 0.5, ..none }.gamma_table().unwrap();
+        assert_eq!(darker[128], 64);
+
+        let wild = Adjustments { brightness: 3.0, contrast: -1.0, gamma: 0.0, saturation: 9.0 }.clamped();
+        assert_eq!(wild, Adjustments { brightness: 1.0, contrast: 0.0, gamma: 0.2, saturation: 2.0 });
+    }
+}
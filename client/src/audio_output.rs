@@ -0,0 +1,78 @@
+// IP Display Client - Audio Output
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! The server's sound, played with a backend from `ipdisp-audio` as AUDIO
+//! packets arrive on the primary link. Sound is never queued with the
+//! frames, so it plays as soon as it arrives and pacing can't hold it up.
+
+use anyhow::Result;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use crate::protocol::{parse_audio, PacketHeader};
+use ip_display_client::audio::{AudioFormat, Player};
+
+/// The player of a stream's primary link
+pub type SharedAudio = Arc<Mutex<AudioOutput>>;
+
+pub struct AudioOutput {
+    player: Box<dyn Player>,
+    format: Option<AudioFormat>,
+    /// Set after a failure has been logged, until something plays again
+    failing: bool,
+}
+
+impl AudioOutput {
+    pub fn open() -> Result<SharedAudio> {
+        let player = gstreamer()?;
+        info!("Playing the server's audio with {}", player.name());
+        Ok(Arc::new(Mutex::new(Self { player, format: None, failing: false })))
+    }
+
+    /// Play an AUDIO packet. Sound that can't be played is dropped, with a
+    /// warning the first time, rather than taking the picture down too.
+    pub fn play(&mut self, header: &PacketHeader, payload: &[u8]) {
+        let played = parse_audio(payload, header.byte_order).and_then(|(format, samples)| {
+            if self.format != Some(format) {
+                info!("Server audio: {}", format);
+                self.format = Some(format);
+            }
+            self.player.play(format, samples)
+        });
+        match played {
+            Ok(()) => self.failing = false,
+            Err(e) if !self.failing => {
+                warn!("Can't play the server's audio: {}", e);
+                self.failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Start over with the next packet, e.g. after a reconnect
+    pub fn reset(&mut self) {
+        self.player.reset();
+        self.format = None;
+    }
+}
+
+impl fmt::Debug for AudioOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AudioOutput")
+            .field("player", &self.player.name())
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+#[cfg(feature = "audio")]
+fn gstreamer() -> Result<Box<dyn Player>> {
+    Ok(Box::new(ip_display_client::audio::gstreamer::GstPlayer::new()?))
+}
+
+#[cfg(not(feature = "audio"))]
+fn gstreamer() -> Result<Box<dyn Player>> {
+    Err(anyhow::anyhow!("Built without audio support (the audio feature)"))
+}
@@ -0,0 +1,152 @@
+// IP Display Client - Authentication Providers
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Ways of answering a server's auth challenge. Every provider answers the
+//! same way, with a token id and an HMAC of the challenge, so the server
+//! can tell from the id which of its providers should check it.
+//!
+//! Pairings are always asked first; the others are tried in the order
+//! they were configured. A server that signs users in some other way,
+//! such as with a password, asks them through a `SignInPrompt` instead.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use tokio::sync::oneshot;
+
+use crate::pairing::{PairedServer, PairingStore, MAC_SIZE, TOKEN_ID_SIZE};
+use crate::protocol::PromptStyle;
+
+/// What goes in an `Auth` packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthResponse {
+    pub id: [u8; TOKEN_ID_SIZE],
+    pub mac: [u8; MAC_SIZE],
+}
+
+impl AuthResponse {
+    fn answer(credential: &PairedServer, challenge: &[u8]) -> Self {
+        Self { id: credential.id, mac: credential.auth_mac(challenge) }
+    }
+}
+
+/// What a server's sign-in has to tell or ask the user. Questions come
+/// with `reply`; answering with nothing declines, and dropping it leaves
+/// the server to give up waiting.
+#[derive(Debug)]
+pub struct SignInPrompt {
+    /// `server:port`, to tell the user who is asking
+    pub server: String,
+    pub style: PromptStyle,
+    pub text: String,
+    pub reply: Option<oneshot::Sender<String>>,
+}
+
+/// A source of credentials for the server's auth challenge
+pub trait AuthProvider: fmt::Debug + Send + Sync {
+    /// Shown in logs, and matches the server's name for the provider
+    fn name(&self) -> &str;
+
+    /// Answer `challenge` from `server` (`server:port`), or `None` if this
+    /// provider has no credential for it
+    fn respond(&self, server: &str, challenge: &[u8]) -> Option<AuthResponse>;
+}
+
+impl AuthProvider for PairingStore {
+    fn name(&self) -> &str {
+        "pairing"
+    }
+
+    fn respond(&self, server: &str, challenge: &[u8]) -> Option<AuthResponse> {
+        self.get(server).map(|paired| AuthResponse::answer(paired, challenge))
+    }
+}
+
+/// A secret shared with every server started with the same `auth_token`.
+/// Its key is the SHA-256 of the secret.
+pub struct TokenAuth {
+    credential: PairedServer,
+}
+
+impl TokenAuth {
+    pub fn new(secret: &str) -> Result<Self> {
+        let secret = secret.trim();
+        if secret.is_empty() {
+            return Err(anyhow::anyhow!("The auth token is empty"));
+        }
+        Ok(Self { credential: PairedServer::from_key(Sha256::digest(secret).into()) })
+    }
+
+    /// Read the secret from its own file, so it stays out of `ps`
+    pub fn load(path: &Path) -> Result<Self> {
+        let secret = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::new(&secret).with_context(|| format!("No token in {}", path.display()))
+    }
+}
+
+impl fmt::Debug for TokenAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never log the key
+        f.debug_struct("TokenAuth").finish_non_exhaustive()
+    }
+}
+
+impl AuthProvider for TokenAuth {
+    fn name(&self) -> &str {
+        "token"
+    }
+
+    fn respond(&self, _server: &str, challenge: &[u8]) -> Option<AuthResponse> {
+        Some(AuthResponse::answer(&self.credential, challenge))
+    }
+}
+
+/// The first answer any provider has, with the name of the one that gave it
+pub fn respond<'a>(
+    providers: impl IntoIterator<Item = &'a dyn AuthProvider>,
+    server: &str,
+    challenge: &[u8],
+) -> Option<(&'a str, AuthResponse)> {
+    providers
+        .into_iter()
+        .find_map(|provider| Some((provider.name(), provider.respond(server, challenge)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hmac::{Hmac, Mac};
+
+    fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_token_matches_server() {
+        // What the kernel's token provider derives from auth_token
+        let key = Sha256::digest(b"correct horse");
+        let challenge = [0x5a; 16];
+        let token = TokenAuth::new("correct horse\n").unwrap();
+        let response = token.respond("display:8080", &challenge).unwrap();
+        assert_eq!(response.id[..], hmac(&key, b"ipdisp token id")[..TOKEN_ID_SIZE]);
+        assert_eq!(response.mac[..], hmac(&key, &challenge)[..]);
+        assert!(TokenAuth::new(" \n").is_err());
+
+        // A pairing for the server wins over the token
+        let mut pairings = PairingStore::default();
+        let paired = PairedServer { id: [7; TOKEN_ID_SIZE], token: [0xa5; 32], content_key: None };
+        pairings.insert("display:8080", paired.clone()).unwrap();
+        let providers: [&dyn AuthProvider; 2] = [&pairings, &token];
+        let (name, answer) = respond(providers, "display:8080", &challenge).unwrap();
+        assert_eq!((name, answer.id), ("pairing", paired.id));
+        let (name, _) = respond(providers, "other:8080", &challenge).unwrap();
+        assert_eq!(name, "token");
+        assert!(respond([&pairings as &dyn AuthProvider], "other:8080", &challenge).is_none());
+    }
+}
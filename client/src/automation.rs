@@ -0,0 +1,300 @@
+// IP Display Client - Snapshot Automation
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Saving a snapshot of the stream when something happens to it: the
+//! connection dropping, or a watched region changing. Snapshots are written
+//! to a directory and/or POSTed to a webhook as `image/jpeg`, with the
+//! event, server, time and stream stats in `X-Ipdisp-*` headers.
+//!
+//! Only plain `http://` webhooks are supported; put a TLS proxy in front of
+//! an `https://` endpoint.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{watch, RwLock};
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
+
+use ip_display_client::region::RegionWatch;
+use ip_display_client::renderer::FrameRenderer;
+use ip_display_client::stats::StatsSnapshot;
+use ip_display_client::thumbnail::Capture;
+
+use crate::network;
+use crate::AppState;
+
+/// How often the connection and watched region are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Least time between two snapshots for the same trigger, so a flapping
+/// link or a busy region doesn't flood the webhook
+pub const SNAPSHOT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// How long a webhook gets to accept a snapshot
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest webhook response we read
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+/// What a snapshot is taken for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Trigger {
+    /// The connection to the server dropped
+    Disconnect,
+    /// The watched region changed
+    Region,
+}
+
+impl Trigger {
+    /// Name of the event, in file names and the `X-Ipdisp-Event` header
+    pub fn event(self) -> &'static str {
+        match self {
+            Trigger::Disconnect => "disconnect",
+            Trigger::Region => "region-change",
+        }
+    }
+}
+
+/// An `http://host[:port]/path` endpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl FromStr for Webhook {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        if url.starts_with("https://") {
+            return Err(anyhow::anyhow!("https webhooks are not supported; use a TLS proxy"));
+        }
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow::anyhow!("Webhook must be an http:// URL, got {}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let (host, port) = network::parse_server_address(authority)
+            .unwrap_or_else(|| (authority.trim_start_matches('[').trim_end_matches(']').to_string(), 80));
+        if host.is_empty() {
+            return Err(anyhow::anyhow!("Webhook {} has no host", url));
+        }
+        Ok(Self { host, port, path: path.to_string() })
+    }
+}
+
+impl Webhook {
+    /// The POST carrying `jpeg` for `event`
+    fn request_bytes(&self, event: &str, server: &str, taken: SystemTime, stats: &StatsSnapshot, jpeg: &[u8]) -> Vec<u8> {
+        let millis = taken.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\
+             X-Ipdisp-Event: {}\r\nX-Ipdisp-Server: {}\r\nX-Ipdisp-Time: {}\r\n\
+             X-Ipdisp-Fps: {:.1}\r\nX-Ipdisp-Bitrate: {:.0}\r\nConnection: close\r\n\r\n",
+            self.path,
+            network::server_address(&self.host, self.port),
+            jpeg.len(),
+            event,
+            server,
+            millis,
+            stats.fps,
+            stats.bytes_per_sec() * 8.0,
+        )
+        .into_bytes();
+        request.extend_from_slice(jpeg);
+        request
+    }
+
+    /// POST a snapshot, failing unless the endpoint answers 2xx
+    async fn post(&self, event: &str, server: &str, taken: SystemTime, stats: &StatsSnapshot, jpeg: &[u8]) -> Result<()> {
+        let request = self.request_bytes(event, server, taken, stats, jpeg);
+        let exchange = async {
+            let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+            stream.write_all(&request).await?;
+            stream.flush().await?;
+
+            let mut response = Vec::new();
+            let mut chunk = [0u8; 512];
+            while !response.windows(2).any(|pair| pair == b"\r\n") && response.len() < MAX_RESPONSE_SIZE {
+                let n = stream.read(&mut chunk).await?;
+                if n == 0 {
+                    break;
+                }
+                response.extend_from_slice(&chunk[..n]);
+            }
+            anyhow::Ok(response)
+        };
+        let response = tokio::time::timeout(WEBHOOK_TIMEOUT, exchange)
+            .await
+            .map_err(|_| anyhow::anyhow!("Webhook timed out"))??;
+
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(anyhow::anyhow!("Webhook refused the snapshot: {}", status)),
+        }
+    }
+}
+
+/// What to snapshot and where to send it
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    pub triggers: Vec<Trigger>,
+    pub watch: Option<RegionWatch>,
+    pub dir: Option<PathBuf>,
+    pub webhook: Option<Webhook>,
+}
+
+/// Check for the configured triggers once a second and snapshot what
+/// `renderer` shows when one fires. Must be called on the GTK thread.
+pub fn start(
+    config: SnapshotConfig,
+    renderer: &FrameRenderer,
+    stats: watch::Receiver<StatsSnapshot>,
+    state: Arc<RwLock<AppState>>,
+    rt: &tokio::runtime::Handle,
+    tasks: &TaskTracker,
+) {
+    let SnapshotConfig { triggers, mut watch, dir, webhook } = config;
+    let sink = Arc::new((dir, webhook));
+
+    // Only frames that arrived since the last check can change the region
+    let dirty = Rc::new(Cell::new(false));
+    let damage = Rc::clone(&dirty);
+    renderer.connect_frame_updated(move |_| damage.set(true));
+
+    let renderer = renderer.clone();
+    let (rt, tasks) = (rt.clone(), tasks.clone());
+    let mut was_connected = false;
+    let mut fired: HashMap<Trigger, Instant> = HashMap::new();
+    glib::timeout_add_local(CHECK_INTERVAL, move || {
+        let (connected, server) = {
+            let state_guard = state.blocking_read();
+            (state_guard.connected, network::server_address(&state_guard.server, state_guard.port))
+        };
+        let Some(surface) = renderer.get_surface() else {
+            was_connected = connected;
+            return glib::ControlFlow::Continue;
+        };
+
+        let mut events = Vec::new();
+        if triggers.contains(&Trigger::Disconnect) && was_connected && !connected {
+            events.push(Trigger::Disconnect);
+        }
+        was_connected = connected;
+        if let Some(watch) = watch.as_mut().filter(|_| dirty.replace(false)) {
+            match watch.check(&surface) {
+                Ok(true) if triggers.contains(&Trigger::Region) => events.push(Trigger::Region),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to check region {}: {}", watch.region(), e),
+            }
+        }
+
+        let now = Instant::now();
+        for trigger in events {
+            if fired.get(&trigger).is_some_and(|last| now.duration_since(*last) < SNAPSHOT_COOLDOWN) {
+                continue;
+            }
+            fired.insert(trigger, now);
+
+            // Full size; only the copy has to happen on this thread
+            let capture = match Capture::take(&surface, surface.width() as u32) {
+                Ok(capture) => capture,
+                Err(e) => {
+                    warn!("Failed to capture {} snapshot: {}", trigger.event(), e);
+                    continue;
+                }
+            };
+            let sink = Arc::clone(&sink);
+            let (server, stats) = (server.clone(), stats.borrow().clone());
+            tasks.spawn_on(async move {
+                if let Err(e) = deliver(trigger, capture, &server, &stats, &sink.0, sink.1.as_ref()).await {
+                    warn!("Failed to deliver {} snapshot: {:#}", trigger.event(), e);
+                }
+            }, &rt);
+        }
+        glib::ControlFlow::Continue
+    });
+}
+
+/// Encode a snapshot, then save and/or POST it
+async fn deliver(
+    trigger: Trigger,
+    capture: Capture,
+    server: &str,
+    stats: &StatsSnapshot,
+    dir: &Option<PathBuf>,
+    webhook: Option<&Webhook>,
+) -> Result<()> {
+    let snapshot = tokio::task::spawn_blocking(move || capture.encode()).await??;
+    let millis = snapshot.taken.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+
+    if let Some(dir) = dir {
+        tokio::fs::create_dir_all(dir).await?;
+        let path = dir.join(format!("{}-{}.jpg", trigger.event(), millis));
+        tokio::fs::write(&path, &snapshot.jpeg)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        info!("Saved {} snapshot to {}", trigger.event(), path.display());
+    }
+    if let Some(webhook) = webhook {
+        webhook.post(trigger.event(), server, snapshot.taken, stats, &snapshot.jpeg).await?;
+        info!("Posted {} snapshot to {}", trigger.event(), webhook.host);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ip_display_client::stats::LinkRate;
+
+    #[test]
+    fn test_webhook() {
+        let webhook: Webhook = "http://hooks.example.com:8000/ipdisp/snapshot".parse().unwrap();
+        assert_eq!(
+            webhook,
+            Webhook { host: "hooks.example.com".to_string(), port: 8000, path: "/ipdisp/snapshot".to_string() }
+        );
+        let webhook: Webhook = "http://[fe80::1]".parse().unwrap();
+        assert_eq!((webhook.host.as_str(), webhook.port, webhook.path.as_str()), ("fe80::1", 80, "/"));
+        assert_eq!("http://[::1]:9000/x".parse::<Webhook>().unwrap().port, 9000);
+        assert!("https://hooks.example.com/".parse::<Webhook>().is_err());
+        assert!("hooks.example.com/".parse::<Webhook>().is_err());
+
+        let stats = StatsSnapshot {
+            fps: 29.97,
+            links: vec![LinkRate { label: "eth0".to_string(), bytes_per_sec: 125_000.0 }],
+            ..Default::default()
+        };
+        let taken = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let request = webhook.request_bytes("disconnect", "10.0.0.5:8080", taken, &stats, b"JPEG");
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST / HTTP/1.1\r\nHost: [fe80::1]:80\r\n"));
+        for header in [
+            "Content-Length: 4\r\n",
+            "X-Ipdisp-Event: disconnect\r\n",
+            "X-Ipdisp-Server: 10.0.0.5:8080\r\n",
+            "X-Ipdisp-Time: 1700000000123\r\n",
+            "X-Ipdisp-Fps: 30.0\r\n",
+            "X-Ipdisp-Bitrate: 1000000\r\n",
+        ] {
+            assert!(request.contains(header), "missing {:?}", header);
+        }
+        assert!(request.ends_with("\r\n\r\nJPEG"));
+    }
+}
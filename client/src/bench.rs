@@ -0,0 +1,201 @@
+// IP Display Client - Pixel Pipeline Benchmark
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! `ip-display-client bench`: times each stage a frame can go through on
+//! its way to the screen over synthetic frames, so regressions in the
+//! renderer show up as numbers. Stages a build leaves out are reported as
+//! skipped rather than dropped, so runs from different builds line up.
+
+use anyhow::Result;
+use cairo::{Format, ImageSurface};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::convert::{self, ChromaLayout, Yuv420};
+use crate::renderer::TestPattern;
+
+/// Frame sizes the benchmark runs at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BenchSize {
+    #[value(name = "1080p")]
+    Hd,
+    #[value(name = "4k")]
+    Uhd,
+}
+
+impl BenchSize {
+    pub fn size(self) -> (u32, u32) {
+        match self {
+            BenchSize::Hd => (1920, 1080),
+            BenchSize::Uhd => (3840, 2160),
+        }
+    }
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct BenchOptions {
+    /// Frame sizes to run at
+    #[arg(long = "size", value_enum, default_values_t = [BenchSize::Hd, BenchSize::Uhd])]
+    pub sizes: Vec<BenchSize>,
+    /// Timed runs of each stage, after one to warm up
+    #[arg(long, default_value_t = 30)]
+    pub iterations: u32,
+}
+
+/// How long one stage took per frame, or `None` if this build can't run it
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageResult {
+    pub stage: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub per_frame: Option<Duration>,
+}
+
+impl StageResult {
+    pub fn fps(&self) -> Option<f64> {
+        self.per_frame.map(|time| 1.0 / time.as_secs_f64())
+    }
+
+    pub fn megapixels_per_second(&self) -> Option<f64> {
+        self.fps().map(|fps| fps * self.width as f64 * self.height as f64 / 1e6)
+    }
+}
+
+/// Every stage at every size in `options`
+pub fn run(options: &BenchOptions) -> Result<Vec<StageResult>> {
+    let mut results = Vec::new();
+    for size in &options.sizes {
+        let (width, height) = size.size();
+        results.extend(measure(width, height, options.iterations.max(1))?);
+    }
+    Ok(results)
+}
+
+/// Every stage over one synthetic `width` x `height` frame
+pub fn measure(width: u32, height: u32, iterations: u32) -> Result<Vec<StageResult>> {
+    let (w, h) = (width as usize, height as usize);
+    let rgba = TestPattern::Gradient.draw(width, height);
+    let rgb: Vec<u8> = rgba.chunks_exact(4).flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+    let nv12: Vec<u8> = (0..Yuv420::size(w, h, ChromaLayout::Interleaved)).map(|i| (i * 7 % 251) as u8).collect();
+    let mut bgra = vec![0u8; w * h * 4];
+    let time = |run: &mut dyn FnMut() -> Result<()>| -> Result<Option<Duration>> {
+        run()?;
+        let start = Instant::now();
+        for _ in 0..iterations {
+            run()?;
+        }
+        Ok(Some(start.elapsed() / iterations))
+    };
+
+    let mut stages = Vec::new();
+    stages.push(("RGB to BGRA", time(&mut || {
+        convert::rgb_to_bgra(&rgb, &mut bgra);
+        Ok(())
+    })?));
+    stages.push(("RGBA premultiply", time(&mut || {
+        convert::rgba_to_bgra_premul(&rgba, &mut bgra);
+        Ok(())
+    })?));
+    let frame = Yuv420::new(&nv12, w, h, ChromaLayout::Interleaved)
+        .ok_or_else(|| anyhow::anyhow!("Bad NV12 size"))?;
+    stages.push(("NV12 to BGRA", time(&mut || {
+        for (row, dst) in bgra.chunks_exact_mut(w * 4).enumerate() {
+            frame.row_to_bgra(row, dst);
+        }
+        Ok(())
+    })?));
+    stages.push(("JPEG decode", match jpeg(&rgb, width, height)? {
+        Some(jpeg) => time(&mut || convert::decode_jpeg(&jpeg, width, height).map(drop))?,
+        None => None,
+    }));
+
+    let mut source = ImageSurface::create(Format::ARgb32, width as i32, height as i32)?;
+    source.data()?.copy_from_slice(&bgra[..]);
+    let target = ImageSurface::create(Format::ARgb32, (width / 2).max(1) as i32, (height / 2).max(1) as i32)?;
+    stages.push(("Scale to half", time(&mut || {
+        let context = cairo::Context::new(&target)?;
+        context.scale(target.width() as f64 / width as f64, target.height() as f64 / height as f64);
+        context.set_source_surface(&source, 0.0, 0.0)?;
+        context.source().set_filter(cairo::Filter::Good);
+        context.paint()?;
+        Ok(())
+    })?));
+    stages.push(("wgpu NV12 to BGRA", gpu(&nv12, width, height, &mut bgra, &time)?));
+
+    Ok(stages
+        .into_iter()
+        .map(|(stage, per_frame)| StageResult { stage, width, height, per_frame })
+        .collect())
+}
+
+/// The frame as a JPEG, if this build can both make and decode one
+#[cfg(feature = "snapshots")]
+fn jpeg(rgb: &[u8], width: u32, height: u32) -> Result<Option<Vec<u8>>> {
+    let mut jpeg = Vec::new();
+    jpeg_encoder::Encoder::new(&mut jpeg, 85).encode(rgb, width as u16, height as u16, jpeg_encoder::ColorType::Rgb)?;
+    Ok(convert::decode_jpeg(&jpeg, width, height).is_ok().then_some(jpeg))
+}
+
+#[cfg(not(feature = "snapshots"))]
+fn jpeg(_rgb: &[u8], _width: u32, _height: u32) -> Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+type Timer<'a> = dyn Fn(&mut dyn FnMut() -> Result<()>) -> Result<Option<Duration>> + 'a;
+
+#[cfg(feature = "wgpu")]
+fn gpu(nv12: &[u8], width: u32, height: u32, bgra: &mut [u8], time: &Timer) -> Result<Option<Duration>> {
+    let Ok(mut gpu) = ipdisp_gpu::GpuConverter::new() else { return Ok(None) };
+    let frame = ipdisp_gpu::Frame::Yuv420 { data: nv12, layout: ChromaLayout::Interleaved };
+    time(&mut || gpu.convert(frame, (width, height), (width, height), bgra))
+}
+
+#[cfg(not(feature = "wgpu"))]
+fn gpu(_nv12: &[u8], _width: u32, _height: u32, _bgra: &mut [u8], _time: &Timer) -> Result<Option<Duration>> {
+    Ok(None)
+}
+
+/// `results` as a table
+pub fn report(results: &[StageResult]) -> String {
+    let mut table = format!("Pixel conversion: {}\n", convert::backend().name());
+    let _ = writeln!(table, "{:<20} {:>9} {:>10} {:>9} {:>10}", "Stage", "Size", "ms/frame", "fps", "Mpixel/s");
+    for result in results {
+        let size = format!("{}x{}", result.width, result.height);
+        let _ = match (result.per_frame, result.fps(), result.megapixels_per_second()) {
+            (Some(time), Some(fps), Some(rate)) => writeln!(
+                table,
+                "{:<20} {:>9} {:>10.2} {:>9.1} {:>10.1}",
+                result.stage,
+                size,
+                time.as_secs_f64() * 1000.0,
+                fps,
+                rate
+            ),
+            _ => writeln!(table, "{:<20} {:>9} {:>10}", result.stage, size, "skipped"),
+        };
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench() {
+        let results = measure(64, 32, 1).unwrap();
+        let stages: Vec<_> = results.iter().map(|result| result.stage).collect();
+        assert_eq!(
+            stages,
+            ["RGB to BGRA", "RGBA premultiply", "NV12 to BGRA", "JPEG decode", "Scale to half", "wgpu NV12 to BGRA"]
+        );
+        assert!(results[..3].iter().all(|result| result.per_frame.is_some()));
+        assert_eq!(results[3].per_frame.is_some(), cfg!(all(feature = "snapshots", feature = "mjpeg")));
+        assert!(results[4].fps().unwrap() > 0.0);
+
+        let table = report(&results);
+        assert!(table.lines().any(|line| line.starts_with("Scale to half") && line.contains("64x32")));
+        assert_eq!(table.lines().count(), 2 + results.len());
+    }
+}
@@ -0,0 +1,166 @@
+// IP Display Client - Frame Buffer Pool
+// Copyright (c) 2024
+// Licensed under MIT
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Buffers kept for reuse: one being received, the frame queue, frames held
+/// for pacing (at most a cadence queue's worth), and one being rendered.
+pub const DEFAULT_POOL_SIZE: usize =
+    crate::frame_channel::FRAME_QUEUE_DEPTH + crate::pacing::CADENCE_QUEUE_DEPTH + 2;
+
+#[derive(Debug)]
+struct PoolInner {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+}
+
+/// Pool of reusable frame payload buffers. Buffers handed out by `get`
+/// go back to the pool when dropped, so at steady state no frame allocates.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    inner: Arc<PoolInner>,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                free: Mutex::new(Vec::with_capacity(max_buffers)),
+                max_buffers,
+            }),
+        }
+    }
+
+    /// Take a zeroed buffer of `len` bytes, reusing a pooled allocation
+    /// when one is available.
+    pub fn get(&self, len: usize) -> PooledBuffer {
+        let mut data = self.inner.free.lock().unwrap().pop().unwrap_or_default();
+        data.clear();
+        data.resize(len, 0);
+
+        PooledBuffer {
+            data,
+            pool: Some(Arc::clone(&self.inner)),
+        }
+    }
+
+    /// Free the pooled buffers, e.g. when frames change size for good
+    pub fn clear(&self) {
+        self.inner.free.lock().unwrap().clear();
+    }
+
+    /// Buffers waiting for reuse
+    #[cfg(test)]
+    pub fn available(&self) -> usize {
+        self.inner.free.lock().unwrap().len()
+    }
+}
+
+/// Frame payload that returns its allocation to the originating pool on drop.
+/// Buffers built from a plain `Vec` are detached and simply freed.
+pub struct PooledBuffer {
+    data: Vec<u8>,
+    pool: Option<Arc<PoolInner>>,
+}
+
+impl PooledBuffer {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl From<Vec<u8>> for PooledBuffer {
+    fn from(data: Vec<u8>) -> Self {
+        Self { data, pool: None }
+    }
+}
+
+impl Clone for PooledBuffer {
+    fn clone(&self) -> Self {
+        Self::from(self.data.clone())
+    }
+}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuffer")
+            .field("len", &self.data.len())
+            .field("pooled", &self.pool.is_some())
+            .finish()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            let mut free = pool.free.lock().unwrap();
+            if free.len() < pool.max_buffers {
+                free.push(std::mem::take(&mut self.data));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_reused_after_drop() {
+        let pool = BufferPool::new(2);
+        let buffer = pool.get(1024);
+        let ptr = buffer.as_ptr();
+        drop(buffer);
+
+        assert_eq!(pool.available(), 1);
+        let buffer = pool.get(512);
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert_eq!(buffer.len(), 512);
+        assert!(buffer.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_clear() {
+        let pool = BufferPool::new(2);
+        let buffer = pool.get(1024);
+        drop(buffer);
+        pool.clear();
+
+        assert_eq!(pool.available(), 0);
+        assert_eq!(pool.get(16).len(), 16);
+    }
+
+    #[test]
+    fn test_pool_is_bounded() {
+        let pool = BufferPool::new(1);
+        let a = pool.get(16);
+        let b = pool.get(16);
+        drop(a);
+        drop(b);
+
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    fn test_detached_buffer() {
+        let buffer = PooledBuffer::from(vec![1, 2, 3]);
+        assert_eq!(buffer.as_slice(), &[1, 2, 3]);
+    }
+}
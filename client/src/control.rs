@@ -0,0 +1,391 @@
+// IP Display Client - Remote Control
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Remote control over a loopback TCP port (`--control-port`), for
+//! automating the client on systems without a desktop to script it
+//! through. Each line sent is a JSON-RPC 2.0 request, answered with one
+//! line unless it is a notification:
+//!
+//! ```text
+//! {"jsonrpc": "2.0", "id": 1, "method": "connect", "params": {"server": "10.0.0.5:8080"}}
+//! {"jsonrpc": "2.0", "id": 1, "result": {"server": "10.0.0.5:8080"}}
+//! ```
+//!
+//! | Method | Params | Does |
+//! |--------|--------|------|
+//! | `connect` | `server`, optional | Switch to `host:port` and/or reconnect after `disconnect` |
+//! | `disconnect` | | Leave the server and stay away until `connect` |
+//! | `set-scale` | `scale`: 1, 2 or 4 | Switch to the fixed quality with that resolution divisor |
+//! | `screenshot` | `path` | Save what the window shows as a PNG on this machine |
+//! | `stats` | | The stream statistics the HUD shows |
+//! | `thumbnail` | | The latest thumbnail: `width`, `height`, `taken_ms` (Unix time) and base64 `jpeg` |
+//!
+//! Requests are carried out on the GTK thread, in the order they arrive.
+//! `thumbnail` needs the `snapshots` feature; the thumbnail is refreshed
+//! every `--thumbnail-interval` seconds while the picture changes, so
+//! asking for it costs no encoding.
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "snapshots")]
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{oneshot, RwLock};
+#[cfg(feature = "snapshots")]
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "snapshots")]
+use ip_display_client::thumbnail::Thumbnail;
+
+use crate::network;
+use crate::quality::QualityMode;
+use crate::ui::DisplayWindow;
+use crate::AppState;
+
+/// How often a link left by `disconnect` checks whether to come back
+pub const RESUME_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Longest request line read
+const MAX_REQUEST_SIZE: usize = 65536;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const REQUEST_FAILED: i64 = -32000;
+
+/// What a control client asked the window to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlRequest {
+    Connect(Option<String>),
+    Disconnect,
+    SetScale(u32),
+    Screenshot(PathBuf),
+    Stats,
+    #[cfg(feature = "snapshots")]
+    Thumbnail,
+}
+
+/// The request's result, or why it failed
+pub type ControlReply = std::result::Result<Value, String>;
+
+/// A request and where its reply goes
+pub type ControlCall = (ControlRequest, oneshot::Sender<ControlReply>);
+
+impl ControlRequest {
+    fn parse(method: &str, params: &Value) -> std::result::Result<Self, (i64, String)> {
+        let invalid = |message: &str| (INVALID_PARAMS, message.to_string());
+        match method {
+            "connect" => match params.get("server") {
+                None | Some(Value::Null) => Ok(ControlRequest::Connect(None)),
+                Some(Value::String(server)) if network::parse_server_address(server).is_some() => {
+                    Ok(ControlRequest::Connect(Some(server.clone())))
+                }
+                Some(_) => Err(invalid("server must be host:port")),
+            },
+            "disconnect" => Ok(ControlRequest::Disconnect),
+            "set-scale" => match params.get("scale").and_then(Value::as_u64) {
+                Some(scale @ (1 | 2 | 4)) => Ok(ControlRequest::SetScale(scale as u32)),
+                _ => Err(invalid("scale must be 1, 2 or 4")),
+            },
+            "screenshot" => match params.get("path").and_then(Value::as_str) {
+                Some(path) if !path.is_empty() => Ok(ControlRequest::Screenshot(PathBuf::from(path))),
+                _ => Err(invalid("path is required")),
+            },
+            "stats" => Ok(ControlRequest::Stats),
+            #[cfg(feature = "snapshots")]
+            "thumbnail" => Ok(ControlRequest::Thumbnail),
+            _ => Err((METHOD_NOT_FOUND, format!("No method {}", method))),
+        }
+    }
+}
+
+/// Listen for control clients on loopback `port` until `shutdown`,
+/// handing their requests to `calls`
+pub fn start(
+    port: u16,
+    calls: UnboundedSender<ControlCall>,
+    rt: &tokio::runtime::Handle,
+    tasks: &TaskTracker,
+    shutdown: &CancellationToken,
+) -> Result<SocketAddr> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port))
+        .map_err(|e| anyhow::anyhow!("Failed to listen for control clients on port {}: {}", port, e))?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    info!("Accepting JSON-RPC control on {}", addr);
+
+    let (tasks, shutdown) = (tasks.clone(), shutdown.clone());
+    tasks.clone().spawn_on(async move {
+        let listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => return warn!("Control listener failed to start: {}", e),
+        };
+        while let Some(accepted) = shutdown.run_until_cancelled(listener.accept()).await {
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Control listener failed to accept: {}", e);
+                    continue;
+                }
+            };
+            debug!("Control client connected from {}", peer);
+            let (calls, shutdown) = (calls.clone(), shutdown.clone());
+            tasks.spawn(async move {
+                if let Err(e) = serve(stream, &calls, &shutdown).await {
+                    debug!("Control client {} went away: {}", peer, e);
+                }
+            });
+        }
+    }, rt);
+    Ok(addr)
+}
+
+/// Answer one client's requests until it hangs up
+async fn serve(stream: TcpStream, calls: &UnboundedSender<ControlCall>, shutdown: &CancellationToken) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut line = String::new();
+        let mut limited = (&mut reader).take(MAX_REQUEST_SIZE as u64);
+        let Some(read) = shutdown.run_until_cancelled(limited.read_line(&mut line)).await else { return Ok(()) };
+        if read? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() == MAX_REQUEST_SIZE {
+            return Err(anyhow::anyhow!("Request longer than {} bytes", MAX_REQUEST_SIZE));
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some(response) = respond(&line, calls).await {
+            let mut response = response.to_string();
+            response.push('\n');
+            writer.write_all(response.as_bytes()).await?;
+        }
+    }
+}
+
+/// The response to one request line, `None` for a notification
+async fn respond(line: &str, calls: &UnboundedSender<ControlCall>) -> Option<Value> {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return Some(error(Value::Null, PARSE_ERROR, &e.to_string())),
+    };
+    let id = request.get("id").cloned();
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return Some(error(id.unwrap_or(Value::Null), INVALID_REQUEST, "method is required"));
+    };
+
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    let result = match ControlRequest::parse(method, &params) {
+        Ok(request) => {
+            let (reply_tx, reply) = oneshot::channel();
+            if calls.send((request, reply_tx)).is_err() {
+                Err((REQUEST_FAILED, "The window has closed".to_string()))
+            } else {
+                match reply.await {
+                    Ok(Ok(result)) => Ok(result),
+                    Ok(Err(message)) => Err((REQUEST_FAILED, message)),
+                    Err(_) => Err((REQUEST_FAILED, "The window has closed".to_string())),
+                }
+            }
+        }
+        Err(e) => Err(e),
+    };
+
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => error(id, code, &message),
+    })
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+/// What requests act on
+pub struct ControlTarget {
+    pub window: Rc<DisplayWindow>,
+    pub state: Arc<RwLock<AppState>>,
+    /// The thumbnailer's latest, when one runs
+    #[cfg(feature = "snapshots")]
+    pub thumbnails: Option<watch::Receiver<Option<Thumbnail>>>,
+}
+
+/// Carry out `request` on `target`. Must be called on the GTK thread.
+pub fn handle(target: &ControlTarget, request: ControlRequest) -> ControlReply {
+    let (window, state) = (&target.window, &target.state);
+    match request {
+        ControlRequest::Connect(server) => {
+            state.blocking_write().user_disconnected = false;
+            if let Some(server) = &server {
+                window.activate_action("connect-recent", Some(&glib::ToVariant::to_variant(server)));
+            }
+            let state = state.blocking_read();
+            Ok(json!({ "server": network::server_address(&state.server, state.port) }))
+        }
+        ControlRequest::Disconnect => {
+            info!("Disconnecting at a control client's request");
+            state.blocking_write().user_disconnected = true;
+            Ok(Value::Null)
+        }
+        ControlRequest::SetScale(scale) => {
+            let mode = match scale {
+                1 => QualityMode::Best,
+                2 => QualityMode::Medium,
+                _ => QualityMode::Low,
+            };
+            window.activate_action("quality", Some(&glib::ToVariant::to_variant(mode.name())));
+            Ok(json!({ "quality": mode.name() }))
+        }
+        ControlRequest::Screenshot(path) => {
+            let surface = window.renderer().get_surface().ok_or("Nothing is shown yet")?;
+            let mut file = File::create(&path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+            surface
+                .write_to_png(&mut file)
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            window.set_status(&format!("Screenshot saved to {}", path.display()));
+            Ok(json!({ "path": path, "width": surface.width(), "height": surface.height() }))
+        }
+        ControlRequest::Stats => {
+            let stats = window.stats().snapshot();
+            let state = state.blocking_read();
+            Ok(json!({
+                "connected": state.connected,
+                "server": network::server_address(&state.server, state.port),
+                "width": stats.frame_width,
+                "height": stats.frame_height,
+                "fps": stats.fps,
+                "bits_per_second": stats.bytes_per_sec() * 8.0,
+                "latency_ms": stats.latency_ms,
+                "rtt_ms": stats.rtt_ms,
+                "corrupt_frames": stats.corrupt_frames,
+                "resyncs": stats.resyncs,
+                "paced_drops": stats.paced_drops,
+            }))
+        }
+        #[cfg(feature = "snapshots")]
+        ControlRequest::Thumbnail => {
+            let thumbnail = target
+                .thumbnails
+                .as_ref()
+                .and_then(|thumbnails| thumbnails.borrow().clone())
+                .ok_or("No thumbnail has been taken yet")?;
+            Ok(thumbnail_json(&thumbnail))
+        }
+    }
+}
+
+#[cfg(feature = "snapshots")]
+fn thumbnail_json(thumbnail: &Thumbnail) -> Value {
+    let taken_ms = thumbnail.taken.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    json!({
+        "width": thumbnail.width,
+        "height": thumbnail.height,
+        "taken_ms": taken_ms,
+        "jpeg": glib::base64_encode(&thumbnail.jpeg).as_str(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::Lines;
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+    async fn call(writer: &mut OwnedWriteHalf, lines: &mut Lines<BufReader<OwnedReadHalf>>, request: &str) -> Value {
+        writer.write_all(format!("{}\n", request).as_bytes()).await.unwrap();
+        serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let parse = |method: &str, params: Value| ControlRequest::parse(method, &params);
+        assert_eq!(parse("connect", Value::Null), Ok(ControlRequest::Connect(None)));
+        assert_eq!(
+            parse("connect", json!({ "server": "10.0.0.5:8080" })),
+            Ok(ControlRequest::Connect(Some("10.0.0.5:8080".to_string())))
+        );
+        assert_eq!(parse("connect", json!({ "server": 5 })).unwrap_err().0, INVALID_PARAMS);
+        assert_eq!(parse("set-scale", json!({ "scale": 2 })), Ok(ControlRequest::SetScale(2)));
+        assert_eq!(parse("set-scale", json!({ "scale": 3 })).unwrap_err().0, INVALID_PARAMS);
+        assert_eq!(parse("screenshot", json!({})).unwrap_err().0, INVALID_PARAMS);
+        assert_eq!(parse("reboot", Value::Null).unwrap_err().0, METHOD_NOT_FOUND);
+    }
+
+    #[cfg(feature = "snapshots")]
+    #[test]
+    fn test_thumbnail_json() {
+        assert_eq!(ControlRequest::parse("thumbnail", &Value::Null), Ok(ControlRequest::Thumbnail));
+        let thumbnail = Thumbnail {
+            width: 320,
+            height: 180,
+            jpeg: Arc::from(&b"\xff\xd8\xff"[..]),
+            taken: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+        };
+        assert_eq!(
+            thumbnail_json(&thumbnail),
+            json!({ "width": 320, "height": 180, "taken_ms": 1_700_000_000_123u64, "jpeg": "/9j/" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_control_listener() {
+        let (tasks, shutdown) = (TaskTracker::new(), CancellationToken::new());
+        let (calls, mut requests) = tokio::sync::mpsc::unbounded_channel::<ControlCall>();
+        let addr = start(0, calls, &tokio::runtime::Handle::current(), &tasks, &shutdown).unwrap();
+
+        // Stands in for the GTK thread
+        tokio::spawn(async move {
+            while let Some((request, reply)) = requests.recv().await {
+                let _ = reply.send(match request {
+                    ControlRequest::Stats => Ok(json!({ "fps": 60.0 })),
+                    other => Err(format!("{:?} refused", other)),
+                });
+            }
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        let response = call(&mut writer, &mut lines, r#"{"jsonrpc": "2.0", "id": 1, "method": "stats"}"#).await;
+        assert_eq!(response, json!({ "jsonrpc": "2.0", "id": 1, "result": { "fps": 60.0 } }));
+        let response = call(&mut writer, &mut lines, r#"{"jsonrpc": "2.0", "id": "a", "method": "disconnect"}"#).await;
+        assert_eq!(response["error"]["code"], REQUEST_FAILED);
+        assert_eq!(response["id"], "a");
+        let response = call(&mut writer, &mut lines, r#"{"jsonrpc": "2.0", "id": 2, "method": "fly"}"#).await;
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+        let response = call(&mut writer, &mut lines, "not json").await;
+        assert_eq!((response["error"]["code"].clone(), response["id"].clone()), (json!(PARSE_ERROR), Value::Null));
+
+        // Notifications get no answer, so the next line is for the request after
+        let response = call(&mut writer, &mut lines, concat!(
+            r#"{"jsonrpc": "2.0", "method": "stats"}"#,
+            "\n",
+            r#"{"jsonrpc": "2.0", "id": 3, "method": "stats"}"#
+        ))
+        .await;
+        assert_eq!(response["id"], 3);
+
+        shutdown.cancel();
+        tasks.close();
+        tasks.wait().await;
+    }
+}
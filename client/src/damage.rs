@@ -0,0 +1,133 @@
+// IP Display Client - Damage Updates
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! DAMAGE packets: the server sends only the rectangle of an RGBA32 frame
+//! that changed since the last one, so an idle desktop costs next to
+//! nothing. The client patches its copy of the last whole frame and shows
+//! that. It only keeps a copy once a server has sent damage, so servers
+//! without it cost nothing; the first DAMAGE asks for a whole frame again.
+
+use anyhow::Result;
+
+use crate::protocol::{Damage, FrameData, FrameFormat, PacketHeader, PacketType};
+use ip_display_client::region::Region;
+
+/// The rectangle a DAMAGE packet replaces, in the frame its header gives
+/// the size of
+pub fn parse_damage(header: &PacketHeader, payload: &[u8]) -> Result<Region> {
+    let Damage { x, y, width, height } = Damage::from_packet(header, payload)?;
+    Ok(Region { x, y, width, height })
+}
+
+/// The last whole RGBA32 frame, which damage is applied to
+#[derive(Debug, Default)]
+pub struct DamageCanvas {
+    /// Set once the server has sent damage
+    wanted: bool,
+    width: u32,
+    height: u32,
+    pixels: Option<Vec<u8>>,
+}
+
+impl DamageCanvas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// One that keeps every frame from the start, for recordings, where a
+    /// whole frame can't be asked for
+    #[cfg(feature = "recording")]
+    pub fn keeping() -> Self {
+        Self { wanted: true, ..Self::default() }
+    }
+
+    /// Copy a frame that damage may follow, once the server sends damage
+    pub fn keep(&mut self, frame: &FrameData) {
+        if !self.wanted || frame.header.format != FrameFormat::Rgba32 {
+            return;
+        }
+        let (width, height) = (frame.header.width, frame.header.height);
+        if frame.stride() != width as usize * 4 {
+            self.pixels = None;
+            return;
+        }
+        let pixels = self.pixels.get_or_insert_with(Vec::new);
+        pixels.clear();
+        pixels.extend_from_slice(&frame.data);
+        (self.width, self.height) = (width, height);
+    }
+
+    /// Forget the last frame, e.g. on a new connection
+    pub fn reset(&mut self) {
+        self.pixels = None;
+    }
+
+    /// Patch the last frame with `damage`; the header and pixels of the
+    /// frame it makes, or `None` with no frame of its size to patch, when
+    /// the server has to be asked for a whole one
+    pub fn apply(&mut self, damage: &FrameData) -> Result<Option<(PacketHeader, &[u8])>> {
+        self.wanted = true;
+        let header = &damage.header;
+        let region = parse_damage(header, &damage.data)?;
+        let Some(pixels) = self.pixels.as_mut().filter(|_| (self.width, self.height) == (header.width, header.height))
+        else {
+            return Ok(None);
+        };
+
+        let (stride, row) = (self.width as usize * 4, region.width as usize * 4);
+        let rows = damage.data[Damage::SIZE..].chunks_exact(row.max(1));
+        for (y, source) in (region.y as usize..).zip(rows.take(region.height as usize)) {
+            let start = y * stride + region.x as usize * 4;
+            pixels[start..start + row].copy_from_slice(source);
+        }
+
+        let mut frame = header.clone();
+        frame.packet_type = PacketType::Display;
+        frame.format = FrameFormat::Rgba32;
+        frame.size = pixels.len() as u32;
+        frame.stride = 0;
+        frame.crc32 = None;
+        Ok(Some((frame, pixels)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn damage(x: u32, y: u32, width: u32, height: u32, value: u8) -> FrameData {
+        let mut payload = Vec::new();
+        for word in [x, y, width, height] {
+            payload.extend_from_slice(&word.to_be_bytes());
+        }
+        payload.resize(Damage::SIZE + (width * height * 4) as usize, value);
+        let mut header = PacketHeader::new(4, 3, FrameFormat::Rgba32, payload.len() as u32);
+        header.packet_type = PacketType::Damage;
+        FrameData::new(header, payload).unwrap()
+    }
+
+    #[test]
+    fn test_damage() {
+        let mut canvas = DamageCanvas::new();
+        let whole = FrameData::new(PacketHeader::new(4, 3, FrameFormat::Rgba32, 48), vec![0u8; 48]).unwrap();
+
+        // Nothing kept until the server is seen to send damage
+        canvas.keep(&whole);
+        assert!(canvas.apply(&damage(1, 1, 2, 1, 9)).unwrap().is_none());
+        canvas.keep(&whole);
+
+        let (header, pixels) = canvas.apply(&damage(1, 1, 2, 1, 9)).unwrap().unwrap();
+        assert_eq!((header.packet_type, header.size), (PacketType::Display, 48));
+        let changed: Vec<usize> = (0..12).filter(|pixel| pixels[pixel * 4] == 9).collect();
+        assert_eq!(changed, [5, 6]);
+
+        // Empty damage leaves the frame as it was
+        let (_, pixels) = canvas.apply(&damage(0, 0, 0, 0, 0)).unwrap().unwrap();
+        assert_eq!(pixels.iter().filter(|&&byte| byte == 9).count(), 8);
+
+        assert!(canvas.apply(&damage(3, 0, 2, 1, 1)).is_err());
+        let short = damage(0, 0, 1, 1, 1);
+        assert!(parse_damage(&short.header, &short.data[..Damage::SIZE]).is_err());
+    }
+}
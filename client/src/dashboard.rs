@@ -0,0 +1,275 @@
+// IP Display Client - Dashboards
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Several streams tiled in one window, for wall displays. A layout file
+//! lists connection profiles (see `profiles`) in grid order, each with the
+//! size its tile asks for:
+//!
+//! ```text
+//! columns = 2
+//!
+//! [lobby]
+//! width = 960
+//! height = 540
+//!
+//! [server-room]
+//! ```
+//!
+//! Each tile has its own connection and state, and shows its own frame
+//! rate and bit rate.
+
+use anyhow::{Context, Result};
+use gtk4::prelude::*;
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::warn;
+
+use crate::protocol::FrameData;
+use crate::renderer::FrameRenderer;
+use crate::paintable::StreamPaintable;
+use crate::stats::{format_bitrate, FpsCounter};
+use crate::ui;
+use crate::AppState;
+
+/// Size a tile asks for when the layout doesn't say
+pub const DEFAULT_TILE_WIDTH: i32 = 640;
+pub const DEFAULT_TILE_HEIGHT: i32 = 360;
+
+/// How often tile captions are refreshed
+const CAPTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// One stream's place in the grid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileSpec {
+    /// Profile to connect with, also the tile's caption
+    pub profile: String,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub columns: u32,
+    pub tiles: Vec<TileSpec>,
+}
+
+impl Layout {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("In {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let mut columns = None;
+        let mut tiles: Vec<TileSpec> = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(profile) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                tiles.push(TileSpec {
+                    profile: profile.trim().to_string(),
+                    width: DEFAULT_TILE_WIDTH,
+                    height: DEFAULT_TILE_HEIGHT,
+                });
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(anyhow::anyhow!("Line {}: expected `key = value`", number + 1));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let value: u32 = value
+                .parse()
+                .ok()
+                .filter(|&value| value > 0 && value <= i32::MAX as u32)
+                .ok_or_else(|| anyhow::anyhow!("Line {}: {} must be a positive number", number + 1, key))?;
+            match (tiles.last_mut(), key) {
+                (None, "columns") => columns = Some(value),
+                (Some(tile), "width") => tile.width = value as i32,
+                (Some(tile), "height") => tile.height = value as i32,
+                _ => return Err(anyhow::anyhow!("Line {}: unexpected {}", number + 1, key)),
+            }
+        }
+
+        if tiles.is_empty() {
+            return Err(anyhow::anyhow!("No tiles in the layout"));
+        }
+        // Roughly square unless told otherwise
+        let columns = columns.unwrap_or_else(|| (tiles.len() as f64).sqrt().ceil() as u32);
+        Ok(Self { columns, tiles })
+    }
+
+    /// Column and row of each tile, filling rows left to right
+    pub fn positions(&self) -> impl Iterator<Item = (&TileSpec, i32, i32)> {
+        let columns = self.columns as usize;
+        self.tiles
+            .iter()
+            .enumerate()
+            .map(move |(i, tile)| (tile, (i % columns) as i32, (i / columns) as i32))
+    }
+}
+
+/// A stream drawn in a dashboard cell, captioned with its stats
+#[derive(Debug)]
+pub struct StreamTile {
+    name: String,
+    state: Arc<RwLock<AppState>>,
+    renderer: FrameRenderer,
+    root: gtk4::Overlay,
+    caption: gtk4::Label,
+    fps: RefCell<FpsCounter>,
+}
+
+impl StreamTile {
+    pub fn new(spec: &TileSpec, state: Arc<RwLock<AppState>>) -> Result<Rc<Self>> {
+        let renderer = FrameRenderer::new()?;
+        let paintable = StreamPaintable::new(&renderer);
+        {
+            let state_guard = state.blocking_read();
+            paintable.set_filter(state_guard.scale_filter);
+            paintable.set_orientation(state_guard.orientation);
+            paintable.set_adjustments(state_guard.adjustments);
+        }
+        let picture = gtk4::Picture::for_paintable(&paintable);
+        picture.set_can_shrink(true);
+        picture.add_css_class("stream");
+
+        let caption = gtk4::Label::new(Some(&spec.profile));
+        caption.set_halign(gtk4::Align::Start);
+        caption.set_valign(gtk4::Align::End);
+        caption.add_css_class("caption");
+
+        let root = gtk4::Overlay::new();
+        root.set_child(Some(&picture));
+        root.add_overlay(&caption);
+        root.set_size_request(spec.width, spec.height);
+        root.set_hexpand(true);
+        root.set_vexpand(true);
+
+        let tile = Rc::new(Self {
+            name: spec.profile.clone(),
+            state,
+            renderer,
+            root,
+            caption,
+            fps: RefCell::new(FpsCounter::new()),
+        });
+
+        let tile_weak = Rc::downgrade(&tile);
+        glib::timeout_add_local(CAPTION_INTERVAL, move || match tile_weak.upgrade() {
+            Some(tile) => {
+                tile.update_caption();
+                glib::ControlFlow::Continue
+            }
+            None => glib::ControlFlow::Break,
+        });
+        Ok(tile)
+    }
+
+    pub fn widget(&self) -> &gtk4::Overlay {
+        &self.root
+    }
+
+    pub fn show_frame(&self, frame: &FrameData) {
+        match ui::render_frame(&self.renderer, frame) {
+            Ok(true) => self.fps.borrow_mut().tick(Instant::now()),
+            Ok(false) => {}
+            Err(e) => warn!("Failed to update {}: {}", self.name, e),
+        }
+    }
+
+    fn update_caption(&self) {
+        let now = Instant::now();
+        let text = {
+            let state = self.state.blocking_read();
+            if state.connected {
+                let bytes_per_sec: f64 = state.links.iter().map(|link| link.rx.bytes_per_sec(now)).sum();
+                format!(
+                    "{}  {:.0} fps  {}",
+                    self.name,
+                    self.fps.borrow_mut().fps(now),
+                    format_bitrate(bytes_per_sec)
+                )
+            } else {
+                format!("{}  disconnected", self.name)
+            }
+        };
+        self.caption.set_text(&text);
+    }
+}
+
+/// Window holding `tiles` in the layout's grid
+pub fn window(app: &gtk4::Application, layout: &Layout, tiles: &[Rc<StreamTile>]) -> gtk4::ApplicationWindow {
+    let grid = gtk4::Grid::new();
+    grid.set_row_spacing(2);
+    grid.set_column_spacing(2);
+    grid.set_row_homogeneous(true);
+    grid.set_column_homogeneous(true);
+    for ((_, column, row), tile) in layout.positions().zip(tiles) {
+        grid.attach(tile.widget(), column, row, 1, 1);
+    }
+
+    let window = gtk4::ApplicationWindow::builder()
+        .application(app)
+        .title("IP Display Dashboard")
+        .child(&grid)
+        .build();
+
+    let css = gtk4::CssProvider::new();
+    css.load_from_data(
+        "picture.stream { background-color: black; } \
+         label.caption { color: white; background-color: rgba(0, 0, 0, 0.6); padding: 2px 6px; }",
+    );
+    gtk4::style_context_add_provider_for_display(
+        &WidgetExt::display(&window),
+        &css,
+        gtk4::STYLE_PROVIDER_PRIORITY_APPLICATION,
+    );
+    window
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let layout = Layout::parse(
+            "# NOC wall\n\
+             columns = 2\n\
+             \n\
+             [lobby]\n\
+             width = 960\n\
+             height = 540\n\
+             [server-room]\n\
+             [loading-dock]\n",
+        )
+        .unwrap();
+        assert_eq!(layout.columns, 2);
+        assert_eq!(
+            layout.tiles[0],
+            TileSpec { profile: "lobby".to_string(), width: 960, height: 540 }
+        );
+        assert_eq!(layout.tiles[1].width, DEFAULT_TILE_WIDTH);
+        let cells: Vec<_> = layout.positions().map(|(tile, column, row)| (tile.profile.as_str(), column, row)).collect();
+        assert_eq!(cells, [("lobby", 0, 0), ("server-room", 1, 0), ("loading-dock", 0, 1)]);
+
+        // Without columns the grid comes out roughly square
+        let five = "[a]\n[b]\n[c]\n[d]\n[e]\n";
+        assert_eq!(Layout::parse(five).unwrap().columns, 3);
+
+        assert!(Layout::parse("columns = 2\n").is_err());
+        assert!(Layout::parse("[a]\ncolumns = 2\n").is_err());
+        assert!(Layout::parse("[a]\nwidth = -5\n").is_err());
+        assert!(Layout::parse("width = 5\n[a]\n").is_err());
+    }
+}
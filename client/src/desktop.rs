@@ -0,0 +1,93 @@
+// IP Display Client - Desktop Integration
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! `ip-display-client install-handler`: a desktop entry that makes the
+//! client the handler for `ipds://` links, so a link on a dashboard opens
+//! the stream it names. The entry runs this executable with the link as
+//! its URI argument, so a running client opens it in a new window.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+/// Name of the desktop entry, which is also the application's
+pub const DESKTOP_FILE: &str = "ip-display-client.desktop";
+
+/// Where desktop entries of the user go: `$XDG_DATA_HOME/applications`
+pub fn applications_dir() -> Option<PathBuf> {
+    let data = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share")))?;
+    Some(data.join("applications"))
+}
+
+/// A desktop entry running `exe` for `ipds://` links
+pub fn desktop_entry(exe: &Path) -> String {
+    format!(
+        "[Desktop Entry]\n\
+         Type=Application\n\
+         Name=IP Display Client\n\
+         Comment=Show the display of an IP Display server\n\
+         Exec={} %u\n\
+         Terminal=false\n\
+         Categories=Network;RemoteAccess;\n\
+         MimeType=x-scheme-handler/ipds;\n",
+        exec_quote(&exe.to_string_lossy())
+    )
+}
+
+/// `path` as a desktop entry's `Exec` takes it: quoted, with `"`, `` ` ``,
+/// `$` and `\` escaped, if it has anything but plain path characters in it
+fn exec_quote(path: &str) -> String {
+    if path.chars().all(|c| c.is_ascii_alphanumeric() || "/._-+".contains(c)) {
+        return path.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in path.chars() {
+        if matches!(c, '"' | '`' | '$' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    // The whole value is a string, whose own escapes come on top
+    quoted.replace('\\', "\\\\")
+}
+
+/// Write the desktop entry for this executable and make it the default
+/// for `ipds://`; where the entry went
+pub fn install() -> Result<PathBuf> {
+    let exe = std::env::current_exe().context("Can't find this executable")?;
+    let dir = applications_dir().ok_or_else(|| anyhow::anyhow!("No data directory for desktop entries"))?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("Can't create {}", dir.display()))?;
+    let path = dir.join(DESKTOP_FILE);
+    std::fs::write(&path, desktop_entry(&exe)).with_context(|| format!("Can't write {}", path.display()))?;
+
+    // Only refreshes a cache, which not every desktop has
+    let _ = Command::new("update-desktop-database").arg(&dir).status();
+    let status = Command::new("xdg-mime")
+        .args(["default", DESKTOP_FILE, "x-scheme-handler/ipds"])
+        .status()
+        .with_context(|| format!("Wrote {}, but can't run xdg-mime to make it the default", path.display()))?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("Wrote {}, but xdg-mime failed: {}", path.display(), status));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_desktop_entry() {
+        let entry = desktop_entry(Path::new("/usr/bin/ip-display-client"));
+        assert!(entry.contains("\nExec=/usr/bin/ip-display-client %u\n"), "{}", entry);
+        assert!(entry.contains("\nMimeType=x-scheme-handler/ipds;\n"));
+
+        assert_eq!(exec_quote("/opt/IP Display/client"), "\"/opt/IP Display/client\"");
+        assert_eq!(exec_quote("/home/$me/client"), "\"/home/\\\\$me/client\"");
+    }
+}
@@ -0,0 +1,202 @@
+// IP Display Client - Forward Error Correction
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Reed–Solomon erasure coding, so a receiver can rebuild datagrams lost
+//! on the way instead of dropping the frame. A group of up to `MAX_DATA`
+//! data shards gets up to `MAX_PARITY` parity shards, and any of them
+//! missing can be made up from the same number of parity shards that
+//! arrived. The code is systematic (data shards go out as they are) and
+//! uses a Cauchy matrix over GF(2^8), every square part of which can be
+//! inverted, so which shards were lost doesn't matter.
+
+use anyhow::Result;
+
+/// Data shards in one group
+pub const MAX_DATA: usize = 128;
+
+/// Parity shards for one group
+pub const MAX_PARITY: usize = 256 - MAX_DATA;
+
+/// Powers of the generator 2 for the field polynomial x^8+x^4+x^3+x^2+1,
+/// twice over so products of two logarithms need no reduction, and
+/// logarithms
+const TABLES: ([u8; 512], [u8; 256]) = tables();
+
+const fn tables() -> ([u8; 512], [u8; 256]) {
+    let (mut exp, mut log) = ([0u8; 512], [0u8; 256]);
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    (exp, log)
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    let (exp, log) = &TABLES;
+    match (a, b) {
+        (0, _) | (_, 0) => 0,
+        _ => exp[log[a as usize] as usize + log[b as usize] as usize],
+    }
+}
+
+fn inverse(a: u8) -> u8 {
+    let (exp, log) = &TABLES;
+    exp[255 - log[a as usize] as usize]
+}
+
+/// What parity shard `row` takes of data shard `column`: 1 / (x + y) with
+/// x and y drawn from disjoint halves of the field
+fn coefficient(row: usize, column: usize) -> u8 {
+    inverse((MAX_DATA + row) as u8 ^ column as u8)
+}
+
+/// `target += factor * shard`, a shorter shard counting as zero-padded
+fn add_scaled(target: &mut [u8], factor: u8, shard: &[u8]) {
+    for (out, &byte) in target.iter_mut().zip(shard) {
+        *out ^= mul(factor, byte);
+    }
+}
+
+/// `parity` shards for `data`, each as long as the longest data shard
+pub fn encode(data: &[&[u8]], parity: usize) -> Vec<Vec<u8>> {
+    assert!(data.len() <= MAX_DATA && parity <= MAX_PARITY);
+    let length = data.iter().map(|shard| shard.len()).max().unwrap_or(0);
+    (0..parity)
+        .map(|row| {
+            let mut shard = vec![0u8; length];
+            for (column, data) in data.iter().enumerate() {
+                add_scaled(&mut shard, coefficient(row, column), data);
+            }
+            shard
+        })
+        .collect()
+}
+
+/// Fill in the missing shards among the first `data` of `shards`, the data
+/// shards of a group followed by its parity shards. Rebuilt shards are as
+/// long as the parity shards, so a short last one comes back padded.
+pub fn recover(shards: &mut [Option<Vec<u8>>], data: usize) -> Result<()> {
+    let missing: Vec<usize> = (0..data).filter(|&i| shards[i].is_none()).collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let rows: Vec<usize> = (data..shards.len()).filter(|&i| shards[i].is_some()).take(missing.len()).collect();
+    if rows.len() < missing.len() {
+        return Err(anyhow::anyhow!("{} shards lost, only {} parity shards to rebuild them", missing.len(), rows.len()));
+    }
+
+    // What each parity shard still owes to the missing data, once the
+    // data that arrived is taken out
+    let length = shards.iter().flatten().map(|shard| shard.len()).max().unwrap_or(0);
+    let owed: Vec<Vec<u8>> = rows
+        .iter()
+        .map(|&index| {
+            let mut owed = shards[index].clone().unwrap_or_default();
+            owed.resize(length, 0);
+            for (column, shard) in shards[..data].iter().enumerate() {
+                if let Some(shard) = shard {
+                    add_scaled(&mut owed, coefficient(index - data, column), shard);
+                }
+            }
+            owed
+        })
+        .collect();
+
+    // Solve for the missing shards with the inverse of their columns of
+    // the parity rows
+    let matrix: Vec<Vec<u8>> = rows
+        .iter()
+        .map(|&index| missing.iter().map(|&column| coefficient(index - data, column)).collect())
+        .collect();
+    let inverted = invert(matrix)?;
+    for (&column, weights) in missing.iter().zip(&inverted) {
+        let mut shard = vec![0u8; length];
+        for (&weight, owed) in weights.iter().zip(&owed) {
+            add_scaled(&mut shard, weight, owed);
+        }
+        shards[column] = Some(shard);
+    }
+    Ok(())
+}
+
+/// Gauss–Jordan elimination over GF(2^8)
+fn invert(mut matrix: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+    let size = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..size).map(|row| (0..size).map(|column| (row == column) as u8).collect()).collect();
+    for column in 0..size {
+        let pivot = (column..size)
+            .find(|&row| matrix[row][column] != 0)
+            .ok_or_else(|| anyhow::anyhow!("Singular FEC matrix"))?;
+        matrix.swap(column, pivot);
+        inverse.swap(column, pivot);
+
+        let scale = self::inverse(matrix[column][column]);
+        for value in matrix[column].iter_mut().chain(inverse[column].iter_mut()) {
+            *value = mul(*value, scale);
+        }
+        for row in 0..size {
+            let factor = matrix[row][column];
+            if row == column || factor == 0 {
+                continue;
+            }
+            let (pivot_row, pivot_inverse) = (matrix[column].clone(), inverse[column].clone());
+            add_scaled(&mut matrix[row], factor, &pivot_row);
+            add_scaled(&mut inverse[row], factor, &pivot_inverse);
+        }
+    }
+    Ok(inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field() {
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inverse(a)), 1);
+        }
+        assert_eq!(mul(2, 0x80), 0x1d);
+        assert_eq!(mul(0, 7), 0);
+    }
+
+    #[test]
+    fn test_recover() {
+        let mut data: Vec<Vec<u8>> = (0..5u8).map(|i| (0..8).map(|j| i * 31 + j).collect()).collect();
+        data[4].truncate(3);
+        let slices: Vec<&[u8]> = data.iter().map(Vec::as_slice).collect();
+        let parity = encode(&slices, 3);
+        assert_eq!(parity.len(), 3);
+        assert!(parity.iter().all(|shard| shard.len() == 8));
+
+        // Any three shards can go, parity ones included
+        for lost in [[0, 1, 2], [1, 3, 4], [0, 4, 6], [2, 5, 7]] {
+            let mut shards: Vec<Option<Vec<u8>>> = data.iter().chain(&parity).cloned().map(Some).collect();
+            for index in lost {
+                shards[index] = None;
+            }
+            recover(&mut shards, 5).unwrap();
+            for (index, shard) in data.iter().enumerate() {
+                assert_eq!(&shards[index].as_ref().unwrap()[..shard.len()], &shard[..], "lost {:?}", lost);
+            }
+        }
+
+        let mut shards: Vec<Option<Vec<u8>>> = data.iter().chain(&parity).cloned().map(Some).collect();
+        for index in [0, 1, 2, 5] {
+            shards[index] = None;
+        }
+        assert!(recover(&mut shards, 5).is_err());
+    }
+}
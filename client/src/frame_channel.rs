@@ -0,0 +1,187 @@
+// IP Display Client - Frame Channel
+// Copyright (c) 2024
+// Licensed under MIT
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tracing::debug;
+
+use crate::protocol::FrameData;
+
+/// Number of frames that may wait for the GTK thread before the oldest is dropped
+pub const FRAME_QUEUE_DEPTH: usize = 2;
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<VecDeque<FrameData>>,
+    capacity: usize,
+    notify: Notify,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+    senders: AtomicUsize,
+}
+
+/// Sending half, owned by the network task (one clone per link when links
+/// are aggregated). Never blocks: when the queue is full the oldest pending
+/// frame is discarded so the newest always wins.
+#[derive(Debug)]
+pub struct FrameSender {
+    shared: Arc<Shared>,
+}
+
+/// Receiving half, polled from the GTK main context.
+#[derive(Debug)]
+pub struct FrameReceiver {
+    shared: Arc<Shared>,
+}
+
+pub fn channel(capacity: usize) -> (FrameSender, FrameReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::with_capacity(capacity.max(1))),
+        capacity: capacity.max(1),
+        notify: Notify::new(),
+        sent: AtomicU64::new(0),
+        dropped: AtomicU64::new(0),
+        closed: AtomicBool::new(false),
+        senders: AtomicUsize::new(1),
+    });
+
+    (
+        FrameSender { shared: Arc::clone(&shared) },
+        FrameReceiver { shared },
+    )
+}
+
+impl FrameSender {
+    pub fn send(&self, frame: FrameData) {
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            while queue.len() >= self.shared.capacity {
+                queue.pop_front();
+                let dropped = self.shared.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("Render queue full, dropped stale frame ({} total)", dropped);
+            }
+            queue.push_back(frame);
+        }
+        self.shared.sent.fetch_add(1, Ordering::Relaxed);
+
+        self.shared.notify.notify_one();
+    }
+
+    /// True once the receiver has gone away (window closed)
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+}
+
+impl Clone for FrameSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self { shared: Arc::clone(&self.shared) }
+    }
+}
+
+impl Drop for FrameSender {
+    fn drop(&mut self) {
+        // The channel closes with the last sender
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.closed.store(true, Ordering::Release);
+            self.shared.notify.notify_one();
+        }
+    }
+}
+
+impl FrameReceiver {
+    /// Wait for the next frame. Returns `None` once the sender is gone and
+    /// the queue has been drained.
+    pub async fn recv(&self) -> Option<FrameData> {
+        loop {
+            if let Some(frame) = self.try_recv() {
+                return Some(frame);
+            }
+
+            if self.shared.closed.load(Ordering::Acquire) {
+                return None;
+            }
+
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<FrameData> {
+        self.shared.queue.lock().unwrap().pop_front()
+    }
+
+    /// Frames sent so far, including any dropped since
+    pub fn sent(&self) -> u64 {
+        self.shared.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for FrameReceiver {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{FrameFormat, PacketHeader};
+
+    fn frame(width: u32) -> FrameData {
+        let header = PacketHeader::new(width, 1, FrameFormat::Rgba32, width * 4);
+        FrameData::new(header, vec![0u8; width as usize * 4]).unwrap()
+    }
+
+    #[test]
+    fn test_overflow_drops_oldest() {
+        let (tx, rx) = channel(2);
+        tx.send(frame(1));
+        tx.send(frame(2));
+        tx.send(frame(3));
+
+        assert_eq!(rx.sent(), 3);
+        assert_eq!(rx.dropped(), 1);
+        assert_eq!(rx.try_recv().unwrap().header.width, 2);
+        assert_eq!(rx.try_recv().unwrap().header.width, 3);
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recv_ends_after_sender_dropped() {
+        let (tx, rx) = channel(FRAME_QUEUE_DEPTH);
+        tx.send(frame(4));
+        drop(tx);
+
+        assert_eq!(rx.recv().await.unwrap().header.width, 4);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_closes_with_last_sender() {
+        let (tx, rx) = channel(FRAME_QUEUE_DEPTH);
+        let second = tx.clone();
+        drop(tx);
+        
+        second.send(frame(5));
+        assert_eq!(rx.recv().await.unwrap().header.width, 5);
+        drop(second);
+        assert!(rx.recv().await.is_none());
+    }
+    
+    #[test]
+    fn test_sender_sees_receiver_close() {
+        let (tx, rx) = channel(1);
+        assert!(!tx.is_closed());
+        drop(rx);
+        assert!(tx.is_closed());
+    }
+}
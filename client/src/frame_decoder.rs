@@ -0,0 +1,189 @@
+// IP Display Client - Frame Decoder
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Compressed frames decoded on their way from the network to the window,
+//! with a backend from `ipdisp-decoder`. The window then shows the
+//! pictures like any RGBA32 frame. The built-in path needs none of this:
+//! it decodes MJPEG in the renderer and can't show H.264 or H.265.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use crate::protocol::{FrameData, FrameFormat, PacketHeader};
+use ip_display_client::decoder::{Codec, Decoder, Picture};
+
+/// Where compressed frames are decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum DecoderChoice {
+    /// MJPEG in the renderer; no H.264 or H.265
+    #[default]
+    Builtin,
+    /// The system's GStreamer, with whatever codecs it has installed
+    Gstreamer,
+    /// VA-API on the GPU through GStreamer, and nothing else
+    Vaapi,
+}
+
+/// One decoder fed by every link of a stream
+pub type SharedDecoder = Arc<Mutex<FrameDecoder>>;
+
+pub struct FrameDecoder {
+    decoder: Box<dyn Decoder>,
+}
+
+impl FrameDecoder {
+    /// The decoder to run frames through, or `None` for the built-in path
+    pub fn open(choice: DecoderChoice) -> Result<Option<Self>> {
+        let decoder: Box<dyn Decoder> = match choice {
+            DecoderChoice::Builtin => return Ok(None),
+            DecoderChoice::Gstreamer => gstreamer()?,
+            DecoderChoice::Vaapi => vaapi()?,
+        };
+
+        let (supported, missing): (Vec<_>, Vec<_>) = Codec::ALL.into_iter().partition(|&codec| decoder.supports(codec));
+        info!("Decoding {} with {}", list(&supported), decoder.name());
+        if !missing.is_empty() {
+            warn!("{} has no decoder for {}", decoder.name(), list(&missing));
+        }
+        Ok(Some(Self { decoder }))
+    }
+
+    /// Frame formats this decoder takes
+    pub fn formats(&self) -> Vec<FrameFormat> {
+        Codec::ALL.into_iter().filter(|&codec| self.decoder.supports(codec)).map(frame_format).collect()
+    }
+
+    /// `frame` decoded to RGBA32, passed through if it isn't in a format
+    /// this decoder takes, or `None` while the decoder holds it back
+    pub fn decode(&mut self, frame: FrameData) -> Result<Option<FrameData>> {
+        let Some(codec) = codec(frame.header.format).filter(|&codec| self.decoder.supports(codec)) else {
+            return Ok(Some(frame));
+        };
+        let Some(picture) = self.decoder.decode(codec, &frame.data, frame.header.timestamp)? else {
+            return Ok(None);
+        };
+        to_frame(&frame, picture).map(Some)
+    }
+
+    /// Start over with the next frame, e.g. after a reconnect
+    pub fn reset(&mut self) {
+        self.decoder.reset();
+    }
+}
+
+#[cfg(feature = "gstreamer")]
+fn gstreamer() -> Result<Box<dyn Decoder>> {
+    Ok(Box::new(ip_display_client::decoder::gstreamer::GstDecoder::new()?))
+}
+
+#[cfg(not(feature = "gstreamer"))]
+fn gstreamer() -> Result<Box<dyn Decoder>> {
+    Err(anyhow::anyhow!("Built without GStreamer support (the gstreamer feature)"))
+}
+
+#[cfg(feature = "vaapi")]
+fn vaapi() -> Result<Box<dyn Decoder>> {
+    Ok(Box::new(ip_display_client::decoder::gstreamer::GstDecoder::vaapi()?))
+}
+
+#[cfg(not(feature = "vaapi"))]
+fn vaapi() -> Result<Box<dyn Decoder>> {
+    Err(anyhow::anyhow!("Built without VA-API decoding (the vaapi feature)"))
+}
+
+fn codec(format: FrameFormat) -> Option<Codec> {
+    match format {
+        FrameFormat::H264 => Some(Codec::H264),
+        FrameFormat::H265 => Some(Codec::H265),
+        FrameFormat::Jpeg => Some(Codec::Mjpeg),
+        _ => None,
+    }
+}
+
+fn frame_format(codec: Codec) -> FrameFormat {
+    match codec {
+        Codec::H264 => FrameFormat::H264,
+        Codec::H265 => FrameFormat::H265,
+        Codec::Mjpeg => FrameFormat::Jpeg,
+    }
+}
+
+fn list(codecs: &[Codec]) -> String {
+    let names: Vec<_> = codecs.iter().map(Codec::to_string).collect();
+    if names.is_empty() { "nothing".to_string() } else { names.join(", ") }
+}
+
+/// A picture as an RGBA32 frame, keeping the compressed frame's header
+/// otherwise so pacing and stats see the same frame
+fn to_frame(compressed: &FrameData, picture: Picture) -> Result<FrameData> {
+    let header = PacketHeader {
+        width: picture.width,
+        height: picture.height,
+        format: FrameFormat::Rgba32,
+        timestamp: picture.timestamp,
+        size: picture.data.len() as u32,
+        stride: 0,
+        crc32: None,
+        ..compressed.header.clone()
+    };
+    let mut frame = FrameData::new(header, picture.data)?;
+    frame.received = compressed.received;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Takes MJPEG only, and shows each frame one frame late
+    struct Delayed {
+        held: Option<u64>,
+    }
+
+    impl Decoder for Delayed {
+        fn name(&self) -> &'static str {
+            "delayed"
+        }
+
+        fn supports(&self, codec: Codec) -> bool {
+            codec == Codec::Mjpeg
+        }
+
+        fn decode(&mut self, _codec: Codec, _data: &[u8], timestamp: u64) -> Result<Option<Picture>> {
+            Ok(self.held.replace(timestamp).map(|held| Picture { width: 2, height: 1, timestamp: held, data: vec![7; 8] }))
+        }
+
+        fn reset(&mut self) {
+            self.held = None;
+        }
+    }
+
+    fn frame(format: FrameFormat, timestamp: u64) -> FrameData {
+        let mut header = PacketHeader::new(2, 1, format, 3);
+        header.timestamp = timestamp;
+        header.crc32 = Some(1);
+        FrameData::new(header, vec![1, 2, 3]).unwrap()
+    }
+
+    #[test]
+    fn test_frame_decoder() {
+        let mut decoder = FrameDecoder { decoder: Box::new(Delayed { held: None }) };
+        assert_eq!(decoder.formats(), [FrameFormat::Jpeg]);
+
+        // Formats it doesn't take go straight through
+        let h264 = decoder.decode(frame(FrameFormat::H264, 1)).unwrap().unwrap();
+        assert_eq!((h264.header.format, h264.data.len()), (FrameFormat::H264, 3));
+
+        assert!(decoder.decode(frame(FrameFormat::Jpeg, 10)).unwrap().is_none());
+        let shown = decoder.decode(frame(FrameFormat::Jpeg, 20)).unwrap().unwrap();
+        assert_eq!(shown.header.format, FrameFormat::Rgba32);
+        assert_eq!((shown.header.timestamp, shown.header.size, shown.header.crc32), (10, 8, None));
+        shown.validate().unwrap();
+
+        decoder.reset();
+        assert!(decoder.decode(frame(FrameFormat::Jpeg, 30)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,125 @@
+// IP Display Client - GPU Renderer
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! The wgpu backend (`--renderer wgpu`). RGBA32 and 4:2:0 frames are
+//! uploaded, converted and scaled on the GPU, then presented through the
+//! same Cairo surface as the CPU renderer. The other packed formats and
+//! JPEG are rare or cheap enough to stay on the CPU.
+
+use anyhow::Result;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use tracing::info;
+
+use crate::convert::{self, ChromaLayout};
+use crate::renderer::{FrameRenderer, Renderer};
+use ipdisp_gpu::{Frame, GpuConverter};
+
+pub struct WgpuRenderer {
+    gpu: RefCell<GpuConverter>,
+    output: FrameRenderer,
+    /// Converted pixels on their way to the surface, kept between frames
+    pixels: RefCell<Vec<u8>>,
+    /// Size frames are drawn at; the stream's own when `None`
+    output_size: Cell<Option<(u32, u32)>>,
+}
+
+impl WgpuRenderer {
+    /// Open a GPU and present through `output`
+    pub fn new(output: &FrameRenderer) -> Result<Self> {
+        let gpu = GpuConverter::new()?;
+        info!("Converting frames on {}", gpu.adapter_name());
+        Ok(Self {
+            gpu: RefCell::new(gpu),
+            output: output.clone(),
+            pixels: RefCell::new(Vec::new()),
+            output_size: Cell::new(None),
+        })
+    }
+
+    /// Draw GPU-converted frames at `size` instead of the stream's, for
+    /// applications that show a fixed-size picture. The window leaves this
+    /// unset, since regions, thumbnails and touch work in stream pixels.
+    pub fn set_output_size(&self, size: Option<(u32, u32)>) {
+        self.output_size.set(size);
+    }
+
+    fn present(&self, frame: Frame, size: (u32, u32), opaque: bool) -> Result<()> {
+        let (width, height) = self.output_size.get().unwrap_or(size);
+        let mut pixels = self.pixels.borrow_mut();
+        pixels.resize(width as usize * height as usize * 4, 0);
+        self.gpu.borrow_mut().convert(frame, size, (width, height), &mut pixels)?;
+        self.output.update_frame_bgra(width, height, &pixels, opaque)
+    }
+}
+
+impl fmt::Debug for WgpuRenderer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WgpuRenderer")
+            .field("adapter", &self.gpu.borrow().adapter_name())
+            .field("output_size", &self.output_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Renderer for WgpuRenderer {
+    fn name(&self) -> &'static str {
+        "wgpu"
+    }
+
+    fn update_frame(&self, width: u32, height: u32, stride: usize, rgba_data: &[u8]) -> Result<()> {
+        if stride < width as usize * 4 {
+            return Err(anyhow::anyhow!("Row stride {} too small for {} pixels", stride, width));
+        }
+        let opaque = rgba_data.chunks(stride).all(|row| convert::is_opaque(&row[..(width as usize * 4).min(row.len())]));
+        self.present(Frame::Rgba { data: rgba_data, stride }, (width, height), opaque)
+    }
+
+    fn update_frame_rgb(&self, width: u32, height: u32, stride: usize, rgb_data: &[u8]) -> Result<()> {
+        self.output.update_frame_rgb(width, height, stride, rgb_data)
+    }
+
+    fn update_frame_rgb565(&self, width: u32, height: u32, stride: usize, data: &[u8]) -> Result<()> {
+        self.output.update_frame_rgb565(width, height, stride, data)
+    }
+
+    fn update_frame_rgb10a2(&self, width: u32, height: u32, stride: usize, data: &[u8]) -> Result<()> {
+        self.output.update_frame_rgb10a2(width, height, stride, data)
+    }
+
+    fn update_frame_yuv(&self, width: u32, height: u32, data: &[u8], layout: ChromaLayout) -> Result<()> {
+        self.present(Frame::Yuv420 { data, layout }, (width, height), true)
+    }
+
+    fn update_frame_jpeg(&self, width: u32, height: u32, data: &[u8]) -> Result<()> {
+        self.output.update_frame_jpeg(width, height, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wgpu_renderer() {
+        // Only where there is a GPU or a software driver such as llvmpipe
+        let output = FrameRenderer::new().unwrap();
+        let Ok(renderer) = WgpuRenderer::new(&output) else { return };
+
+        // Mid grey with neutral chroma
+        let nv12 = [vec![126u8; 8 * 4], vec![128u8; 8 * 2]].concat();
+        renderer.update_frame_yuv(8, 4, &nv12, ChromaLayout::Interleaved).unwrap();
+        assert_eq!(output.get_dimensions(), (8, 4));
+        let surface = output.get_surface().unwrap();
+        assert_eq!(surface.format(), cairo::Format::Rgb24);
+        surface.with_data(|data| assert!(data[..3].iter().all(|&c| c.abs_diff(128) <= 2))).unwrap();
+
+        // Scaled on the GPU when asked, and translucent RGBA keeps its alpha
+        renderer.set_output_size(Some((4, 2)));
+        let rgba = [255u8, 0, 0, 128].repeat(8 * 4);
+        Renderer::update_frame(&renderer, 8, 4, 32, &rgba).unwrap();
+        assert_eq!(output.get_dimensions(), (4, 2));
+        assert_eq!(output.get_surface().unwrap().format(), cairo::Format::ARgb32);
+    }
+}
@@ -0,0 +1,102 @@
+// IP Display Client - Bandwidth Graph
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Bit rate and frame rate over the last minute of a `StatsHistory`, drawn
+//! as a sparkline in the status bar and larger, with its peaks, in the
+//! statistics HUD, so drops that come back every so often stand out. Each
+//! line is scaled to its own peak; the newest sample is at the right.
+
+use anyhow::Result;
+
+use crate::stats::{format_bitrate, StatsHistory, HISTORY_SECS};
+
+/// Colour of the bit rate line
+pub const BITRATE_COLOR: (f64, f64, f64) = (0.3, 0.7, 1.0);
+
+/// Colour of the frame rate line
+pub const FPS_COLOR: (f64, f64, f64) = (1.0, 0.6, 0.2);
+
+/// Where `values` go in a `width` × `height` area with `peak` at the top,
+/// spaced so a full history spans the width and ending at the right edge
+pub fn points(values: &[f64], peak: f64, width: f64, height: f64) -> Vec<(f64, f64)> {
+    let step = width / (HISTORY_SECS - 1) as f64;
+    let start = width - step * values.len().saturating_sub(1) as f64;
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let level = if peak > 0.0 { (value / peak).clamp(0.0, 1.0) } else { 0.0 };
+            (start + step * i as f64, height - level * height)
+        })
+        .collect()
+}
+
+/// Draw `history` into the `width` × `height` area at `x`, `y`, labelled
+/// with its peaks when `labels` is set
+pub fn draw(
+    context: &cairo::Context,
+    history: &StatsHistory,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    labels: bool,
+) -> Result<()> {
+    let peak = history.peak();
+    let bitrates: Vec<f64> = history.samples().map(|sample| sample.bytes_per_sec).collect();
+    let rates: Vec<f64> = history.samples().map(|sample| sample.fps).collect();
+
+    context.save()?;
+    context.rectangle(x, y, width, height);
+    context.clip();
+    context.set_line_width(if labels { 1.5 } else { 1.0 });
+    for (values, peak, (r, g, b)) in [(bitrates, peak.bytes_per_sec, BITRATE_COLOR), (rates, peak.fps, FPS_COLOR)] {
+        // Keep the line inside the area
+        let points = points(&values, peak, width - 2.0, height - 2.0);
+        for (i, (px, py)) in points.into_iter().enumerate() {
+            if i == 0 {
+                context.move_to(x + 1.0 + px, y + 1.0 + py);
+            } else {
+                context.line_to(x + 1.0 + px, y + 1.0 + py);
+            }
+        }
+        context.set_source_rgb(r, g, b);
+        context.stroke()?;
+    }
+
+    if labels {
+        context.select_font_face("Monospace", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
+        context.set_font_size(11.0);
+        let line = context.font_extents()?.height();
+        let (r, g, b) = BITRATE_COLOR;
+        context.set_source_rgb(r, g, b);
+        context.move_to(x + 4.0, y + line);
+        context.show_text(&format!("peak {}", format_bitrate(peak.bytes_per_sec)))?;
+        let (r, g, b) = FPS_COLOR;
+        context.set_source_rgb(r, g, b);
+        context.move_to(x + 4.0, y + line * 2.0);
+        context.show_text(&format!("peak {:.1} fps", peak.fps))?;
+    }
+    context.restore()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_points() {
+        let width = (HISTORY_SECS - 1) as f64;
+        assert_eq!(points(&[5.0, 10.0], 10.0, width, 20.0), [(width - 1.0, 10.0), (width, 0.0)]);
+
+        // A full history spans the width, and nothing goes past the top
+        let full = points(&[20.0; HISTORY_SECS], 10.0, width, 20.0);
+        assert_eq!((full[0], full[HISTORY_SECS - 1]), ((0.0, 0.0), (width, 0.0)));
+
+        // Nothing received stays on the floor
+        assert_eq!(points(&[0.0], 0.0, width, 20.0), [(width, 20.0)]);
+        assert!(points(&[], 1.0, width, 20.0).is_empty());
+    }
+}
@@ -0,0 +1,376 @@
+// IP Display Client - Gzip Writer
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! A small gzip (RFC 1952) encoder for the logs we write, so they can be
+//! read back with `zcat`. Input is matched against the last 32 KiB with a
+//! hash chain and coded with DEFLATE's fixed Huffman tables (RFC 1951
+//! 3.2.6): nowhere near a real compressor on arbitrary data, but text logs
+//! with repetitive lines shrink several times over.
+//!
+//! `flush` ends the current block and byte-aligns the stream the way
+//! zlib's sync flush does, so everything written up to then can be
+//! decompressed even if `finish` is never reached.
+
+use std::io::{self, Write};
+
+/// How far back a match may start
+const WINDOW_SIZE: usize = 32 * 1024;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+const HASH_BITS: u32 = 15;
+
+/// Candidates tried per position before settling for the best so far
+const MAX_CHAIN: usize = 32;
+
+/// Input buffered before it is compressed into a block
+const BLOCK_SIZE: usize = 64 * 1024;
+
+const END_OF_BLOCK: u16 = 256;
+
+/// Base lengths of length codes 257..=285, and their extra bits
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195,
+    227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+/// Base distances of distance codes 0..=29, and their extra bits
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073,
+    4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// Fixed gzip header: deflate, no flags, no mtime, unknown OS
+const HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+pub struct GzipWriter<W: Write> {
+    inner: W,
+    /// Up to `WINDOW_SIZE` bytes already compressed, then those waiting
+    window: Vec<u8>,
+    /// Start of the bytes in `window` not yet compressed
+    pending: usize,
+    /// Stream position of `window[0]`
+    offset: usize,
+    /// Latest stream position + 1 with each hash, 0 for none
+    head: Vec<usize>,
+    /// Previous stream position + 1 with the same hash, by position
+    prev: Vec<usize>,
+    bits: u64,
+    bit_count: u32,
+    out: Vec<u8>,
+    crc: crc32fast::Hasher,
+    size: u32,
+    finished: bool,
+}
+
+impl<W: Write> GzipWriter<W> {
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&HEADER)?;
+        Ok(Self {
+            inner,
+            window: Vec::new(),
+            pending: 0,
+            offset: 0,
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; WINDOW_SIZE],
+            bits: 0,
+            bit_count: 0,
+            out: Vec::new(),
+            crc: crc32fast::Hasher::new(),
+            size: 0,
+            finished: false,
+        })
+    }
+
+    /// Write the last block and the trailer. Later writes fail.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.compress_block(true);
+        self.align();
+        let crc = std::mem::take(&mut self.crc).finalize();
+        self.out.extend_from_slice(&crc.to_le_bytes());
+        self.out.extend_from_slice(&self.size.to_le_bytes());
+        self.finished = true;
+        self.write_out()?;
+        self.inner.flush()
+    }
+
+    fn write_out(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.out)?;
+        self.out.clear();
+        Ok(())
+    }
+
+    /// Append `count` bits of `value`, least significant first
+    fn put_bits(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    /// Append a Huffman code, which goes most significant bit first
+    fn put_code(&mut self, code: u32, length: u32) {
+        self.put_bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    fn align(&mut self) {
+        if self.bit_count > 0 {
+            self.put_bits(0, 8 - self.bit_count);
+        }
+    }
+
+    /// A literal/length symbol in the fixed code
+    fn put_symbol(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.put_code(0x30 + symbol, 8),
+            144..=255 => self.put_code(0x190 + symbol - 144, 9),
+            256..=279 => self.put_code(symbol - 256, 7),
+            _ => self.put_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn put_match(&mut self, length: usize, distance: usize) {
+        let code = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap();
+        self.put_symbol(257 + code as u16);
+        self.put_bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code] as u32);
+
+        let code = DISTANCE_BASE.iter().rposition(|&base| base as usize <= distance).unwrap();
+        self.put_code(code as u32, 5);
+        self.put_bits((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code] as u32);
+    }
+
+    fn hash(&self, at: usize) -> usize {
+        let bytes = &self.window[at..at + MIN_MATCH];
+        (((bytes[0] as usize) << 10) ^ ((bytes[1] as usize) << 5) ^ bytes[2] as usize) & ((1 << HASH_BITS) - 1)
+    }
+
+    /// Make `window[at]` a candidate for later matches
+    fn insert(&mut self, at: usize) {
+        if at + MIN_MATCH > self.window.len() {
+            return;
+        }
+        let hash = self.hash(at);
+        let position = self.offset + at;
+        self.prev[position % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = position + 1;
+    }
+
+    /// Length and distance of the longest earlier match for `window[at..]`
+    fn longest_match(&self, at: usize) -> (usize, usize) {
+        if at + MIN_MATCH > self.window.len() {
+            return (0, 0);
+        }
+        let position = self.offset + at;
+        let limit = MAX_MATCH.min(self.window.len() - at);
+        let mut best = (0, 0);
+        let mut candidate = self.head[self.hash(at)];
+        for _ in 0..MAX_CHAIN {
+            let Some(start) = candidate.checked_sub(1) else { break };
+            if start < self.offset || position - start > WINDOW_SIZE {
+                break;
+            }
+            let earlier = start - self.offset;
+            let length = self.window[earlier..]
+                .iter()
+                .zip(&self.window[at..at + limit])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.0 {
+                best = (length, position - start);
+                if length == limit {
+                    break;
+                }
+            }
+            let next = self.prev[start % WINDOW_SIZE];
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+        best
+    }
+
+    /// Code everything pending as one fixed-Huffman block
+    fn compress_block(&mut self, last: bool) {
+        self.put_bits(last as u32, 1);
+        self.put_bits(1, 2);
+        let mut at = self.pending;
+        while at < self.window.len() {
+            let (length, distance) = self.longest_match(at);
+            if length >= MIN_MATCH {
+                self.put_match(length, distance);
+                for skipped in at..at + length {
+                    self.insert(skipped);
+                }
+                at += length;
+            } else {
+                self.put_symbol(self.window[at] as u16);
+                self.insert(at);
+                at += 1;
+            }
+        }
+        self.put_symbol(END_OF_BLOCK);
+
+        // Keep only what later matches can reach
+        let excess = self.window.len().saturating_sub(WINDOW_SIZE);
+        self.window.drain(..excess);
+        self.offset += excess;
+        self.pending = self.window.len();
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("gzip stream already finished"));
+        }
+        self.crc.update(buf);
+        self.size = self.size.wrapping_add(buf.len() as u32);
+        self.window.extend_from_slice(buf);
+        if self.window.len() - self.pending >= BLOCK_SIZE {
+            self.compress_block(false);
+            self.write_out()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// End the block so far and byte-align with an empty stored block
+    fn flush(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        if self.pending < self.window.len() {
+            self.compress_block(false);
+        }
+        self.put_bits(0, 3);
+        self.align();
+        self.out.extend_from_slice(&[0, 0, 0xff, 0xff]);
+        self.write_out()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inflates what `GzipWriter` produces: fixed and stored blocks only
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        assert_eq!(data[..10], HEADER);
+        let mut reader = BitReader { data: &data[10..], at: 0 };
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let last = reader.bits(1) == 1;
+            match reader.bits(2) {
+                0 => {
+                    reader.at = reader.at.div_ceil(8) * 8;
+                    let length = reader.bits(16) as usize;
+                    assert_eq!(reader.bits(16) as usize, !length & 0xffff);
+                    for _ in 0..length {
+                        out.push(reader.bits(8) as u8);
+                    }
+                }
+                1 => loop {
+                    let symbol = reader.symbol();
+                    if symbol < 256 {
+                        out.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == END_OF_BLOCK as u32 {
+                        break;
+                    }
+                    let code = (symbol - 257) as usize;
+                    let length = LENGTH_BASE[code] as usize + reader.bits(LENGTH_EXTRA[code] as u32) as usize;
+                    let code = reader.code(5) as usize;
+                    let distance = DISTANCE_BASE[code] as usize + reader.bits(DISTANCE_EXTRA[code] as u32) as usize;
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                },
+                kind => panic!("unexpected block type {}", kind),
+            }
+            if last {
+                break;
+            }
+        }
+
+        let trailer = &data[10 + reader.at.div_ceil(8)..];
+        assert_eq!(trailer[..4], crc32fast::hash(&out).to_le_bytes());
+        assert_eq!(trailer[4..8], (out.len() as u32).to_le_bytes());
+        out
+    }
+
+    struct BitReader<'a> {
+        data: &'a [u8],
+        at: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |value, i| {
+                let bit = (self.data[self.at / 8] >> (self.at % 8)) & 1;
+                self.at += 1;
+                value | (bit as u32) << i
+            })
+        }
+
+        fn code(&mut self, length: u32) -> u32 {
+            (0..length).fold(0, |code, _| code << 1 | self.bits(1))
+        }
+
+        fn symbol(&mut self) -> u32 {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return code + 256;
+            }
+            let code = code << 1 | self.bits(1);
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => code - 0xc0 + 280,
+                _ => (code << 1 | self.bits(1)) - 0x190 + 144,
+            }
+        }
+    }
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let mut input = Vec::new();
+        for i in 0..5000u32 {
+            input.extend_from_slice(format!("{}.{:03} 0< Display 1920x1080 Xrgb8888 size=8294400\n", i / 30, i % 1000).as_bytes());
+        }
+        // Bytes the fixed code spends nine bits on, and runs longer than a match
+        input.extend((0..=255u8).cycle().take(1000));
+        input.extend([b'z'; 600]);
+
+        let mut writer = GzipWriter::new(Vec::new()).unwrap();
+        let (head, tail) = input.split_at(100_000);
+        writer.write_all(head).unwrap();
+        writer.flush().unwrap();
+        writer.flush().unwrap();
+        writer.write_all(tail).unwrap();
+        writer.finish().unwrap();
+        assert!(writer.write_all(b"late").is_err());
+
+        let compressed = writer.inner;
+        assert!(compressed.len() * 4 < input.len(), "{} bytes from {}", compressed.len(), input.len());
+        assert_eq!(gunzip(&compressed), input);
+
+        // Nothing written is still a valid stream
+        let mut empty = GzipWriter::new(Vec::new()).unwrap();
+        empty.finish().unwrap();
+        assert!(gunzip(&empty.inner).is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+// IP Display Client - Stall Handling
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! What a window shows once the stream stops, so a frozen frame isn't
+//! taken for live output. The server only sends frames when the screen
+//! changes, so a stall is measured from the last packet of any kind:
+//! an idle desktop still sends heartbeats.
+
+use std::time::Duration;
+
+/// How long the stream may be silent before the policy applies
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long the last frame takes to fade out
+pub const FADE: Duration = Duration::from_millis(500);
+
+/// How dark the last frame gets under the "Signal lost" banner
+const SIGNAL_LOST_DIM: f64 = 0.6;
+
+/// What to show in place of a stalled stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum HoldPolicy {
+    /// Keep showing the last frame
+    #[default]
+    Hold,
+    /// Fade the last frame to black
+    Black,
+    /// Dim the last frame under a "Signal lost" banner
+    SignalLost,
+}
+
+/// How to cover the last frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Veil {
+    /// Alpha of the black drawn over the frame
+    pub opacity: f64,
+    /// Whether to say the signal is lost
+    pub banner: bool,
+}
+
+impl HoldPolicy {
+    /// How to cover the last frame after `silent` without a packet, once
+    /// that is past `timeout`
+    pub fn veil(self, silent: Duration, timeout: Duration) -> Option<Veil> {
+        let fade = (silent.checked_sub(timeout)?.as_secs_f64() / FADE.as_secs_f64()).min(1.0);
+        match self {
+            HoldPolicy::Hold => None,
+            HoldPolicy::Black => Some(Veil { opacity: fade, banner: false }),
+            HoldPolicy::SignalLost => Some(Veil { opacity: fade * SIGNAL_LOST_DIM, banner: true }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_veil() {
+        let timeout = Duration::from_secs(2);
+        let at = |ms| Duration::from_millis(ms);
+        assert_eq!(HoldPolicy::Black.veil(at(1999), timeout), None);
+        assert_eq!(HoldPolicy::Hold.veil(at(60_000), timeout), None);
+
+        assert_eq!(HoldPolicy::Black.veil(at(2000), timeout), Some(Veil { opacity: 0.0, banner: false }));
+        assert_eq!(HoldPolicy::Black.veil(at(2250), timeout), Some(Veil { opacity: 0.5, banner: false }));
+        assert_eq!(HoldPolicy::Black.veil(at(9000), timeout), Some(Veil { opacity: 1.0, banner: false }));
+        let veil = HoldPolicy::SignalLost.veil(at(9000), timeout).unwrap();
+        assert!(veil.banner && (veil.opacity - SIGNAL_LOST_DIM).abs() < 1e-9);
+    }
+}
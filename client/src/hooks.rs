@@ -0,0 +1,118 @@
+// IP Display Client - Connection Hooks
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Commands run when a connection comes up, goes down or fails, for
+//! kiosks that switch a TV's input over CEC or power it on and off with
+//! the stream. They are set with `--on-connect`, `--on-disconnect` and
+//! `--on-error`, or `on_connect = ...` and so on in a profile, and run
+//! with `sh -c` so they can be small pipelines. Each gets:
+//!
+//! | Variable | Value |
+//! |----------|-------|
+//! | `IPDISP_EVENT` | `connect`, `disconnect` or `error` |
+//! | `IPDISP_SERVER` | `host:port` of the server |
+//! | `IPDISP_LINK` | Index of the link, 0 unless aggregating |
+//! | `IPDISP_PROFILE` | Profile in use, if any |
+//! | `IPDISP_ERROR` | What went wrong, for `error` |
+//!
+//! Hooks run in the background and the stream doesn't wait for them.
+//! `error` runs once per outage rather than on every retry.
+
+use std::process::Stdio;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    Connect,
+    Disconnect,
+    Error,
+}
+
+impl HookEvent {
+    pub fn name(self) -> &'static str {
+        match self {
+            HookEvent::Connect => "connect",
+            HookEvent::Disconnect => "disconnect",
+            HookEvent::Error => "error",
+        }
+    }
+}
+
+/// The commands to run, each optional
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hooks {
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+    pub on_error: Option<String>,
+    /// Passed on as `IPDISP_PROFILE`
+    pub profile: Option<String>,
+}
+
+impl Hooks {
+    fn command(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Connect => self.on_connect.as_deref(),
+            HookEvent::Disconnect => self.on_disconnect.as_deref(),
+            HookEvent::Error => self.on_error.as_deref(),
+        }
+    }
+
+    /// Start the command for `event`, if there is one. Must be called on
+    /// the Tokio runtime; the handle finishes when the command does.
+    pub fn run(&self, event: HookEvent, server: &str, link: usize, error: Option<&str>) -> Option<JoinHandle<()>> {
+        let command = self.command(event)?.to_string();
+        let mut process = Command::new("sh");
+        process
+            .arg("-c")
+            .arg(&command)
+            .env("IPDISP_EVENT", event.name())
+            .env("IPDISP_SERVER", server)
+            .env("IPDISP_LINK", link.to_string())
+            .env("IPDISP_PROFILE", self.profile.as_deref().unwrap_or_default())
+            .env("IPDISP_ERROR", error.unwrap_or_default())
+            .stdin(Stdio::null());
+        debug!("Running {} hook: {}", event.name(), command);
+
+        let mut child = match process.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to run {} hook: {}", event.name(), e);
+                return None;
+            }
+        };
+        Some(tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("{} hook `{}` failed: {}", event.name(), command, status),
+                Err(e) => warn!("Failed to wait for {} hook: {}", event.name(), e),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let path = std::env::temp_dir().join(format!("ipdisp-hook-{}", std::process::id()));
+        let hooks = Hooks {
+            on_error: Some(format!(
+                "echo \"$IPDISP_EVENT $IPDISP_SERVER $IPDISP_LINK $IPDISP_PROFILE $IPDISP_ERROR\" > {}",
+                path.display()
+            )),
+            profile: Some("lobby".to_string()),
+            ..Default::default()
+        };
+
+        assert!(hooks.run(HookEvent::Connect, "10.0.0.5:8080", 0, None).is_none());
+        hooks.run(HookEvent::Error, "10.0.0.5:8080", 1, Some("refused")).unwrap().await.unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, "error 10.0.0.5:8080 1 lobby refused\n");
+    }
+}
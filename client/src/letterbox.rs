@@ -0,0 +1,90 @@
+// IP Display Client - Letterbox
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! What fills the window around a stream that doesn't share its aspect
+//! ratio (`--letterbox`). Black bars are the default, but a panel that
+//! shows the same bars for months keeps them, so a kiosk can pick a colour,
+//! an image, or the stream's own edges blurred out to the window's, as TV
+//! scalers do.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::Result;
+use gtk4::gio;
+use gtk4::prelude::*;
+
+/// How the bars beside the stream are filled
+#[derive(Debug, Clone, PartialEq)]
+pub enum Letterbox {
+    Color(gdk4::RGBA),
+    /// Scaled to cover the window, centred
+    Image(PathBuf),
+    /// The stream stretched to the window and blurred, behind the stream
+    Blur,
+}
+
+impl Default for Letterbox {
+    fn default() -> Self {
+        Letterbox::Color(gdk4::RGBA::BLACK)
+    }
+}
+
+impl Letterbox {
+    /// Style for the stream's picture, and for the backdrop under it when
+    /// blurring
+    pub fn css(&self) -> String {
+        match self {
+            Letterbox::Color(color) => format!("picture.stream {{ background-color: {}; }}", color),
+            Letterbox::Image(path) => format!(
+                "picture.stream {{ background-color: black; background-image: url(\"{}\"); \
+                 background-size: cover; background-position: center; background-repeat: no-repeat; }}",
+                gio::File::for_path(path).uri()
+            ),
+            Letterbox::Blur => "picture.stream { background-color: transparent; } \
+                 picture.backdrop { background-color: black; filter: blur(40px) brightness(60%); }"
+                .to_string(),
+        }
+    }
+}
+
+impl FromStr for Letterbox {
+    type Err = anyhow::Error;
+
+    /// A colour (`#202020`, `rgb(32,32,32)`, `dimgray`), `blur`, or the
+    /// path of an image
+    fn from_str(text: &str) -> Result<Self> {
+        if text == "blur" {
+            return Ok(Letterbox::Blur);
+        }
+        if let Ok(color) = gdk4::RGBA::parse(text) {
+            return Ok(Letterbox::Color(color));
+        }
+        let path = PathBuf::from(text);
+        if path.is_file() {
+            return Ok(Letterbox::Image(path));
+        }
+        Err(anyhow::anyhow!("Expected a colour, blur or an image file, got {}", text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_letterbox() {
+        assert_eq!("blur".parse::<Letterbox>().unwrap(), Letterbox::Blur);
+        let gray: Letterbox = "#202020".parse().unwrap();
+        assert_eq!(gray.css(), "picture.stream { background-color: rgb(32,32,32); }");
+        assert!(matches!("dimgray".parse(), Ok(Letterbox::Color(_))));
+        assert!("no-such-image.png".parse::<Letterbox>().is_err());
+
+        let path = std::env::temp_dir().join(format!("ipdisp-letterbox-{}.png", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let image: Letterbox = path.to_str().unwrap().parse().unwrap();
+        assert!(image.css().contains(&format!("url(\"file://{}\")", path.display())), "{}", image.css());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,26 @@
+// IP Display Client - Library
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Pieces of the client that applications embedding a display can use
+//! without the GTK front end
+
+pub use ipdisp_audio as audio;
+pub use ipdisp_codecs as convert;
+pub use ipdisp_decoder as decoder;
+pub use ipdisp_sources as sources;
+pub use ipds_protocol as protocol;
+
+pub mod adjustments;
+pub mod bench;
+#[cfg(feature = "wgpu")]
+pub mod gpu_renderer;
+pub mod graph;
+pub mod hold;
+pub mod osd;
+pub mod paintable;
+pub mod region;
+pub mod renderer;
+pub mod stats;
+#[cfg(feature = "snapshots")]
+pub mod thumbnail;
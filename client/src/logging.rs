@@ -0,0 +1,144 @@
+// IP Display Client - Logging
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Where log messages go and what they look like. By default they are
+//! written to stderr as text, filtered by `RUST_LOG`. Kiosks can write
+//! them to a file as one JSON object per line instead, for a collector
+//! such as Vector or Fluent Bit to pick up. Log files are started afresh
+//! every hour or day and named for it (`client.log.2024-05-01`), keeping
+//! the newest `MAX_LOG_FILES`.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+/// Rotated log files kept before the oldest is deleted
+pub const MAX_LOG_FILES: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, fields included
+    Json,
+}
+
+/// How often `--log-file` starts a new file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogRotation {
+    /// One file that keeps growing
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct LogOptions {
+    /// How log messages are written
+    #[arg(long, value_enum, default_value_t = LogFormat::default())]
+    pub log_format: LogFormat,
+    /// Write log messages to this file instead of stderr
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+    /// Messages to log, e.g. `debug` or `info,ip_display_client=trace`;
+    /// overrides RUST_LOG
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+    /// How often to start a new file with --log-file
+    #[arg(long, value_enum, default_value_t = LogRotation::default())]
+    pub log_rotation: LogRotation,
+}
+
+/// Install the global subscriber for `options`. Messages for a log file
+/// are written from a background thread; keep the guard until exit so
+/// the last of them are flushed.
+pub fn init(options: &LogOptions) -> Result<Option<WorkerGuard>> {
+    let filter = filter(options.log_level.as_deref())?;
+    let (writer, guard) = match &options.log_file {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(appender(path, options.log_rotation)?);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stderr), None),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(options.log_file.is_none());
+    match options.log_format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    }
+    .map_err(|e| anyhow::anyhow!("Failed to set up logging: {}", e))?;
+    Ok(guard)
+}
+
+/// `level` if given, else `RUST_LOG`, else `info`
+fn filter(level: Option<&str>) -> Result<EnvFilter> {
+    match level {
+        Some(level) => EnvFilter::try_new(level).with_context(|| format!("Invalid log level: {}", level)),
+        None => Ok(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))),
+    }
+}
+
+/// A file appender writing to `path`, or to `path` with the hour or day
+/// appended when rotating
+fn appender(path: &Path, rotation: LogRotation) -> Result<RollingFileAppender> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Log file {} has no file name", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let rotation = match rotation {
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(name.to_string_lossy())
+        .max_log_files(MAX_LOG_FILES)
+        .build(dir)
+        .with_context(|| format!("Failed to open log file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_logging_options() {
+        assert!(filter(Some("debug")).is_ok());
+        assert!(filter(Some("info,ip_display_client=trace")).is_ok());
+        assert!(filter(Some("ip_display_client=loud")).is_err());
+
+        let dir = std::env::temp_dir().join(format!("ipdisp-log-test-{}", std::process::id()));
+        let path = dir.join("client.log");
+        let mut file = appender(&path, LogRotation::Never).unwrap();
+        file.write_all(b"{\"message\":\"hello\"}\n").unwrap();
+        file.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"message\":\"hello\"}\n");
+
+        // Rotated files are named for their period
+        let mut file = appender(&path, LogRotation::Daily).unwrap();
+        file.write_all(b"rotated\n").unwrap();
+        file.flush().unwrap();
+        let names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(names.iter().any(|name| name.starts_with("client.log.") && name.len() == "client.log.2024-05-01".len()));
+    }
+}
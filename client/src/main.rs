@@ -5,23 +5,128 @@
 use anyhow::Result;
 use clap::Parser;
 use gtk4::prelude::*;
+use std::cell::Cell;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(feature = "snapshots")]
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
-use tracing::{info, warn, error};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn, error};
 
 mod protocol;
 mod ui;
 mod network;
-mod renderer;
+mod frame_channel;
+mod buffer_pool;
+mod pacing;
+mod pairing;
+mod quality;
+mod noise;
+mod usage;
+mod auth;
+mod profiles;
+mod dashboard;
+#[cfg(feature = "snapshots")]
+mod automation;
+mod gzip;
+mod protocol_log;
+#[cfg(feature = "recording")]
+mod stream_dump;
+mod metrics;
+mod logging;
+mod control;
+mod hooks;
+mod frame_decoder;
+mod source;
+mod vnc;
+mod multicast;
+mod fec;
+mod touch;
+mod pointer;
+mod upload;
+mod letterbox;
+mod desktop;
+mod damage;
+mod audio_output;
 
-use protocol::{PacketHeader, MAGIC, VERSION};
+use ip_display_client::{adjustments, bench, convert, graph, hold, osd, paintable, renderer, stats};
+use ipdisp_core::{timesync, DEFAULT_HEARTBEAT_TIMEOUT};
+use ipdisp_server::capture::Screen;
+use ipdisp_server::demo::{DemoPattern, DemoSource, DEMO_FPS};
+use ipdisp_server::Service;
+use ip_display_client::bench::BenchOptions;
+use ip_display_client::hold::{HoldPolicy, DEFAULT_STALL_TIMEOUT};
+use ip_display_client::adjustments::Adjustments;
+use ip_display_client::paintable::{Flip, Orientation, Rotation, ScaleFilter};
+use ip_display_client::renderer::{RendererKind, TestPattern};
+use ip_display_client::region::Region;
+#[cfg(feature = "snapshots")]
+use ip_display_client::region::RegionWatch;
+#[cfg(feature = "snapshots")]
+use ip_display_client::thumbnail::{Thumbnailer, DEFAULT_THUMBNAIL_INTERVAL, DEFAULT_THUMBNAIL_WIDTH};
+use frame_channel::{FrameReceiver, FrameSender, FRAME_QUEUE_DEPTH};
+use audio_output::AudioOutput;
+use frame_decoder::{DecoderChoice, FrameDecoder, SharedDecoder};
+use protocol::{Capabilities, Command, FileStatus, FrameData, FrameFormat, InputControl, SessionMode, TouchDevice};
 use ui::DisplayWindow;
-use network::NetworkClient;
+use letterbox::Letterbox;
+use network::{
+    LinkMerger, LinkMode, LinkPath, LinkStats, ModeChange, NetworkClient, Transport, RECONNECT_DELAY,
+    SHUTDOWN_TIMEOUT,
+};
+use pacing::{PacingPreference, DEFAULT_PLAYOUT_DELAY};
+use pairing::{PairPrompt, PairingStore};
+use noise::KnownServers;
+use auth::{AuthProvider, SignInPrompt, TokenAuth};
+use profiles::{ProfileStore, RecentServers};
+use dashboard::{Layout, StreamTile};
+#[cfg(feature = "snapshots")]
+use automation::{SnapshotConfig, Trigger, Webhook};
+use protocol_log::ProtocolLog;
+#[cfg(feature = "recording")]
+use stream_dump::{ReplaySource, StreamDump};
+use metrics::Metrics;
+use logging::LogOptions;
+use control::{ControlCall, ControlTarget};
+use source::{DisplaySource, SourceSpec};
+use multicast::MulticastSender;
+use upload::{UploadEvent, Uploader};
+use hooks::Hooks;
+use quality::{AdaptiveQuality, Degradation, QualityLimits, QualityMode, QualitySample, QUALITY_INTERVAL};
+use usage::{
+    Budget, BudgetAction, BudgetLevel, Period, UsageLedger, MAX_CYCLE_DAY,
+    USAGE_SAVE_INTERVAL,
+};
 
 #[derive(Parser, Debug)]
 #[command(name = "ip-display-client")]
 #[command(about = "GTK4 client for IP Display Driver")]
+#[command(args_override_self = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<ClientCommand>,
+    
+    /// Start with the options of this profile from the profiles file;
+    /// options given here override it
+    #[arg(long)]
+    profile: Option<String>,
+    
+    /// Stream to show, as --source takes it (e.g. ipds://host:port). An
+    /// ipds:// link can add ?tls=1 and &profile=NAME. With the client
+    /// already running, it opens in a new window there instead
+    #[arg(value_name = "URI", conflicts_with_all = ["source", "layout", "demo", "multicast"])]
+    uri: Option<String>,
+    
+    /// Run on its own rather than handing the stream to a client that is
+    /// already running
+    #[arg(long)]
+    new_instance: bool,
+    
     /// Server IP address
     #[arg(short, long, default_value = "127.0.0.1")]
     server: String,
@@ -34,10 +139,76 @@ struct Args {
     #[arg(short, long)]
     fullscreen: bool,
     
+    /// No window decorations, menu or status bar, with the window sized to
+    /// the stream (toggle with F9)
+    #[arg(long)]
+    borderless: bool,
+    
+    /// Keep the window above others, where the window manager allows it
+    #[arg(long)]
+    on_top: bool,
+    
+    /// Digital signage: fullscreen without decorations, menus or a way out
+    /// by keyboard, reconnecting forever
+    #[arg(long)]
+    kiosk: bool,
+    
+    /// In kiosk mode, also ignore the keyboard, pointer, touch and close
+    /// requests
+    #[arg(long, requires = "kiosk")]
+    block_input: bool,
+    
     /// Enable vertical sync
     #[arg(long)]
     vsync: bool,
     
+    /// With vsync, favour low latency or evenly spaced frames; `cadence`
+    /// replays frames at their sender timestamps and `sync` at the server's
+    /// deadlines, in step with other clients (both with or without vsync)
+    #[arg(long, value_enum, default_value_t = PacingPreference::Latency)]
+    pacing: PacingPreference,
+    
+    /// Network interface to reach the server through (e.g. a direct cable)
+    #[arg(long)]
+    bind_interface: Option<String>,
+    
+    /// Local address to connect from
+    #[arg(long)]
+    bind_address: Option<IpAddr>,
+    
+    /// Experimental: open a second connection through this interface and
+    /// aggregate it with the first (e.g. Wi-Fi next to Ethernet)
+    #[arg(long)]
+    aggregate_interface: Option<String>,
+    
+    /// How the server uses aggregated links
+    #[arg(long, value_enum, default_value_t = LinkMode::Failover)]
+    link_mode: LinkMode,
+    
+    /// Carry the stream over plain TCP or inside a WebSocket, for servers
+    /// behind proxies that only pass HTTP
+    #[arg(long, value_enum, default_value_t = Transport::Tcp)]
+    transport: Transport,
+    
+    /// Path to request the WebSocket on with `--transport ws`
+    #[arg(long, default_value = "/")]
+    ws_path: String,
+    
+    /// What to do with a frame larger than the display the server
+    /// announced: reconnect to hear its size again, follow the frame, or
+    /// skip it
+    #[arg(long, value_enum, default_value_t = ModeChange::Reconnect)]
+    mode_change: ModeChange,
+    
+    /// Drop the connection and reconnect after this many seconds without
+    /// hearing from the server
+    #[arg(long, default_value_t = DEFAULT_HEARTBEAT_TIMEOUT.as_secs())]
+    heartbeat_timeout: u64,
+    
+    /// Delay added to every frame with `--pacing cadence`, in milliseconds
+    #[arg(long, default_value_t = DEFAULT_PLAYOUT_DELAY.as_millis() as u32)]
+    playout_delay: u32,
+    
     /// Window width
     #[arg(long, default_value = "1920")]
     width: i32,
@@ -45,17 +216,458 @@ struct Args {
     /// Window height
     #[arg(long, default_value = "1080")]
     height: i32,
+    
+    /// Don't request the monitor's native mode from the server when fullscreened
+    #[arg(long)]
+    no_auto_mode: bool,
+    
+    /// Resize the window to fit the stream when the remote display changes
+    /// size mid-session
+    #[arg(long)]
+    resize_window: bool,
+    
+    /// Keep the stream coming while the window is minimized or hidden; it
+    /// always does with --multicast-relay, --dump-stream or snapshots
+    #[arg(long)]
+    no_idle_pause: bool,
+    
+    /// Present frames as they arrive on a variable refresh rate display
+    /// (needs the GL renderer)
+    #[arg(long)]
+    vrr: bool,
+    
+    /// Pair with the server: it shows a 6-digit code to type in here
+    #[arg(long)]
+    pair: bool,
+    
+    /// Encrypt the connection with Noise, trusting the server's key the
+    /// first time and refusing it if it changes (the server needs a
+    /// noise_key)
+    #[arg(long)]
+    noise: bool,
+    
+    /// Tile the profiles listed in this layout file in one window instead
+    /// of showing a single stream
+    #[arg(long)]
+    layout: Option<PathBuf>,
+    
+    /// Also open a window for each of these profiles, each with its own
+    /// connection, to watch several servers from one process
+    #[arg(long, value_name = "PROFILE", conflicts_with = "layout")]
+    open: Vec<String>,
+    
+    /// File holding the shared secret of servers using the token auth
+    /// provider; tried when there is no pairing with the server
+    #[arg(long)]
+    auth_token_file: Option<PathBuf>,
+    
+    /// Ask the server to checksum each frame and resend corrupted ones
+    #[arg(long)]
+    checksum: bool,
+    
+    /// Acknowledge every frame shown, so the server measures our latency
+    /// and sends no more frames than we keep up with
+    #[arg(long)]
+    ack: bool,
+    
+    /// Have the server add a touchscreen matching the remote display, if
+    /// this machine has one
+    #[arg(long)]
+    forward_touch: bool,
+    
+    /// What to do besides watching: view only, also send input, or full
+    /// control with uploads too; the server may allow less
+    #[arg(long, default_value_t = SessionMode::Full)]
+    session_mode: SessionMode,
+    
+    /// Play the server's sound, if it has any (needs the audio feature)
+    #[arg(long)]
+    audio: bool,
+    
+    /// Largest file to upload when one is dropped on the window, in MB;
+    /// 0 ignores drops
+    #[arg(long, value_name = "MB", default_value_t = upload::DEFAULT_UPLOAD_LIMIT_MB)]
+    upload_limit: u64,
+    
+    /// Show only this part of the remote display, as X,Y,WIDTH,HEIGHT, so
+    /// several clients can each show a tile of a video wall. The server is
+    /// asked to send just that part.
+    #[arg(long, value_name = "X,Y,WIDTH,HEIGHT")]
+    crop: Option<Region>,
+    
+    /// What to show once nothing has come from the server for
+    /// --stall-timeout: the last frame, or it faded to black or under a
+    /// "Signal lost" banner
+    #[arg(long, value_enum, default_value_t = HoldPolicy::Hold)]
+    on_stall: HoldPolicy,
+    
+    /// What fills the window beside a stream of another aspect ratio: a
+    /// colour such as #202020 (black by default), an image file, or blur for
+    /// the stream's own edges stretched out and blurred
+    #[arg(long, value_name = "COLOR|IMAGE|blur")]
+    letterbox: Option<Letterbox>,
+    
+    /// Milliseconds without a frame or heartbeat before --on-stall applies
+    #[arg(long, default_value_t = DEFAULT_STALL_TIMEOUT.as_millis() as u64)]
+    stall_timeout: u64,
+    
+    /// Stream quality; auto lowers it while frames are being dropped
+    #[arg(long, value_enum, default_value_t = QualityMode::Auto)]
+    quality: QualityMode,
+    
+    /// How the stream is filtered when the window zooms it; auto keeps
+    /// pixels sharp at whole-number zooms and smooths the rest
+    #[arg(long, value_enum, default_value_t = ScaleFilter::Auto)]
+    scale_filter: ScaleFilter,
+    
+    /// Degrees to turn the stream clockwise, for rotated panels or sources
+    #[arg(long, value_enum, default_value_t = Rotation::None)]
+    rotate: Rotation,
+    
+    /// Mirror the stream; applied before --rotate
+    #[arg(long, value_enum, value_delimiter = ',')]
+    flip: Vec<Flip>,
+    
+    /// Added to each colour channel, from -1 to 1
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    brightness: f64,
+    
+    /// Contrast around mid grey, from 0 to 2
+    #[arg(long, default_value_t = 1.0)]
+    contrast: f64,
+    
+    /// Gamma, from 0.2 to 5; above 1 lifts dark captures
+    #[arg(long, default_value_t = 1.0)]
+    gamma: f64,
+    
+    /// Saturation, from 0 (greyscale) to 2
+    #[arg(long, default_value_t = 1.0)]
+    saturation: f64,
+    
+    /// What converts frames for display; wgpu uploads, converts and scales
+    /// them on the GPU, for machines where GTK's GL renderer misbehaves
+    #[arg(long, value_enum, default_value_t = RendererKind::Cairo)]
+    renderer: RendererKind,
+    
+    /// Start showing a test pattern in place of the stream, for checking
+    /// scaling and colour (Help → Show Test Pattern turns it off)
+    #[arg(long, value_enum, value_name = "PATTERN")]
+    test_pattern: Option<TestPattern>,
+    
+    /// Where H.264, H.265 and MJPEG frames are decoded; gstreamer uses
+    /// whatever codecs the system's GStreamer has, vaapi only its VA-API
+    /// decoders
+    #[arg(long, value_enum, default_value_t = DecoderChoice::Builtin)]
+    decoder: DecoderChoice,
+    
+    /// What auto quality gives up under bandwidth pressure, in order
+    #[arg(long, value_enum, value_delimiter = ',',
+          default_values_t = Degradation::DEFAULT_ORDER)]
+    degrade: Vec<Degradation>,
+    
+    #[cfg(feature = "snapshots")]
+    /// Keep a small JPEG preview of the stream at this path, for dashboards
+    #[arg(long)]
+    thumbnail: Option<PathBuf>,
+    
+    #[cfg(feature = "snapshots")]
+    /// Seconds between thumbnails while the stream is changing
+    #[arg(long, default_value_t = DEFAULT_THUMBNAIL_INTERVAL.as_secs())]
+    thumbnail_interval: u64,
+    
+    /// Have the server sign a hash of the stream every second; each one
+    /// that matches its frame is appended to this file
+    #[arg(long)]
+    content_log: Option<PathBuf>,
+    
+    /// The server's content signing key (its content_public_key in sysfs),
+    /// for checking content hashes from a server we haven't paired with
+    #[arg(long, value_name = "HEX", value_parser = parse_content_key)]
+    content_key: Option<[u8; 32]>,
+    
+    /// Megabytes this server may stream per billing cycle, for metered links
+    #[arg(long)]
+    monthly_budget: Option<u64>,
+    
+    /// Whether running out of budget only warns or pauses the stream until
+    /// the next cycle
+    #[arg(long, value_enum, default_value_t = BudgetAction::Warn)]
+    budget_action: BudgetAction,
+    
+    /// Day of the month (UTC) billing cycles start on
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=MAX_CYCLE_DAY as i64))]
+    budget_cycle_day: u32,
+    
+    #[cfg(feature = "snapshots")]
+    /// Save a snapshot of the stream when these happen
+    #[arg(long, value_enum, value_delimiter = ',')]
+    snapshot_on: Vec<Trigger>,
+    
+    #[cfg(feature = "snapshots")]
+    /// Region of the stream the region trigger watches, as X,Y,WIDTHxHEIGHT
+    #[arg(long)]
+    watch_region: Option<Region>,
+    
+    #[cfg(feature = "snapshots")]
+    /// Percent the watched region must change by to count
+    #[arg(long, default_value_t = 5.0)]
+    region_threshold: f64,
+    
+    #[cfg(feature = "snapshots")]
+    /// Directory snapshots are saved to
+    #[arg(long)]
+    snapshot_dir: Option<PathBuf>,
+    
+    #[cfg(feature = "snapshots")]
+    /// http:// URL snapshots are POSTed to
+    #[arg(long)]
+    webhook: Option<Webhook>,
+    
+    /// Log packet headers and control messages, but no pixel data, to this
+    /// gzipped file for bug reports
+    #[arg(long, value_name = "PATH", conflicts_with = "layout")]
+    record_protocol_metadata: Option<PathBuf>,
+    
+    /// Record every packet the server sends, pixels included, to this
+    /// file for replaying with --play
+    #[cfg(feature = "recording")]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["layout", "source", "multicast"])]
+    dump_stream: Option<PathBuf>,
+    
+    /// Play back a stream recorded with --dump-stream at its original
+    /// pace instead of connecting to a server
+    #[cfg(feature = "recording")]
+    #[arg(long, value_name = "PATH",
+          conflicts_with_all = ["layout", "dump_stream", "pair", "uri", "demo", "source", "multicast"])]
+    play: Option<PathBuf>,
+    
+    /// Show a moving test pattern from a built-in server instead of
+    /// connecting to one, for trying out the client
+    #[arg(long, value_enum, value_name = "PATTERN", num_args = 0..=1, default_missing_value = "bounce",
+          conflicts_with_all = ["layout", "pair"])]
+    demo: Option<DemoPattern>,
+    
+    /// Where to take the stream from instead of --server: ipds://host:port,
+    /// file:///recording.ipds, vnc://host:port, spice://host:port or
+    /// ndi:NAME
+    #[arg(long, value_name = "URI", conflicts_with_all = ["layout", "demo", "pair"])]
+    source: Option<String>,
+    
+    /// Show the frames another client relays to this UDP multicast group,
+    /// e.g. 239.1.1.1:5000
+    #[arg(long, value_name = "GROUP:PORT",
+          conflicts_with_all = ["layout", "demo", "pair", "source"])]
+    multicast: Option<SocketAddr>,
+    
+    /// Send every frame shown on to this UDP multicast group, for any
+    /// number of --multicast viewers
+    #[arg(long, value_name = "GROUP:PORT", conflicts_with_all = ["layout", "multicast"])]
+    multicast_relay: Option<SocketAddr>,
+    
+    /// Add this much Reed–Solomon parity to relayed frames, as a
+    /// percentage, so viewers can rebuild frames that lose datagrams
+    #[arg(long, value_name = "PERCENT", default_value_t = 0, requires = "multicast_relay",
+          value_parser = clap::value_parser!(u8).range(0..=100))]
+    multicast_fec: u8,
+    
+    /// Serve frame rate, bit rate, latency and drop counters on this port
+    /// at /metrics, in the Prometheus text format
+    #[arg(long, value_name = "PORT", conflicts_with = "layout")]
+    metrics_port: Option<u16>,
+    
+    /// Shell command to run when a link connects, with the server in
+    /// IPDISP_SERVER
+    #[arg(long, value_name = "COMMAND")]
+    on_connect: Option<String>,
+    
+    /// Shell command to run when a link's connection ends
+    #[arg(long, value_name = "COMMAND")]
+    on_disconnect: Option<String>,
+    
+    /// Shell command to run when connecting fails or a connection breaks,
+    /// with the reason in IPDISP_ERROR; once per outage
+    #[arg(long, value_name = "COMMAND")]
+    on_error: Option<String>,
+    
+    /// Accept JSON-RPC requests (connect, disconnect, set-scale,
+    /// screenshot, stats) on this loopback port, one per line
+    #[arg(long, value_name = "PORT", conflicts_with = "layout")]
+    control_port: Option<u16>,
+    
+    #[command(flatten)]
+    log: LogOptions,
+}
+
+/// Things to do instead of opening a window
+#[derive(clap::Subcommand, Debug)]
+enum ClientCommand {
+    /// Time the pixel pipeline's stages over synthetic 1080p and 4K frames
+    Bench(BenchOptions),
+    /// Open ipds:// links with this client, through a desktop entry in
+    /// ~/.local/share/applications
+    InstallHandler,
+}
+
+/// `--content-key`: 64 hex digits
+fn parse_content_key(text: &str) -> Result<[u8; 32], String> {
+    pairing::decode_hex(text.trim())
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| "expected 64 hex digits".to_string())
+}
+
+#[cfg(feature = "snapshots")]
+impl Args {
+    /// What `--snapshot-on` asks for, if anything
+    fn snapshot_config(&self) -> Result<Option<SnapshotConfig>> {
+        if self.snapshot_on.is_empty() {
+            return Ok(None);
+        }
+        if self.snapshot_dir.is_none() && self.webhook.is_none() {
+            return Err(anyhow::anyhow!("--snapshot-on needs --snapshot-dir or --webhook"));
+        }
+        let watch = if self.snapshot_on.contains(&Trigger::Region) {
+            let region = self
+                .watch_region
+                .ok_or_else(|| anyhow::anyhow!("--snapshot-on region needs --watch-region"))?;
+            Some(RegionWatch::new(region, self.region_threshold.clamp(0.0, 100.0) / 100.0))
+        } else {
+            None
+        };
+        Ok(Some(SnapshotConfig {
+            triggers: self.snapshot_on.clone(),
+            watch,
+            dir: self.snapshot_dir.clone(),
+            webhook: self.webhook.clone(),
+        }))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct AppState {
+    /// Whether any link is connected
     pub connected: bool,
     pub server: String,
     pub port: u16,
+    /// Bumped when the user switches servers, so links reconnect
+    pub server_generation: u64,
+    /// Servers connected to lately, for the File menu
+    pub recent: RecentServers,
+    pub bind_interface: Option<String>,
+    pub bind_address: Option<IpAddr>,
+    pub aggregate_interface: Option<String>,
+    pub link_mode: LinkMode,
+    pub transport: Transport,
+    /// HTTP path of the WebSocket endpoint
+    pub ws_path: String,
+    /// Groups this client's links on the server (0 = not aggregating)
+    pub session_id: u32,
+    /// State and receive statistics per network path, filled in on connect
+    pub links: Vec<LinkStats>,
+    pub heartbeat_timeout: Duration,
     pub display_width: u32,
     pub display_height: u32,
+    /// When the remote display last changed size; frames received before
+    /// then are at the old size
+    pub display_resized_at: Option<Instant>,
+    /// Frames larger than the announced display without new display info
+    pub mode_change: ModeChange,
     pub fullscreen: bool,
+    pub borderless: bool,
+    pub on_top: bool,
+    pub kiosk: bool,
+    /// Kiosk mode ignores all local input
+    pub block_input: bool,
     pub vsync: bool,
+    pub pacing: PacingPreference,
+    pub playout_delay_ms: u32,
+    /// Delay after capture the server presents frames at with `--pacing sync`
+    pub sync_delay: Option<Duration>,
+    pub auto_mode: bool,
+    /// Size the window to the stream when the remote display is resized
+    pub resize_window: bool,
+    /// Ask the server to pause the stream while nobody can see the window
+    pub idle_pause: bool,
+    /// The stream is paused, asked again on reconnect
+    pub paused: bool,
+    /// Refresh rate of the monitor showing the stream, in mHz (0 = unknown)
+    pub refresh_mhz: u32,
+    /// Variable refresh presentation; cleared if the renderer can't do it
+    pub vrr: bool,
+    /// Tokens for servers we've paired with
+    pub pairings: PairingStore,
+    /// Credentials to try after pairings, in order
+    pub auth_providers: Vec<Arc<dyn AuthProvider>>,
+    /// Our Noise static key with `--noise`; connections are encrypted
+    /// when it's set
+    pub noise_key: Option<[u8; noise::KEY_SIZE]>,
+    /// Servers' Noise keys as first seen
+    pub known_servers: KnownServers,
+    /// Ask for a CRC-32 on every frame
+    pub checksum: bool,
+    /// Frames dropped because their CRC-32 didn't match
+    pub corrupt_frames: u64,
+    /// Acknowledge frames as they are drawn
+    pub ack: bool,
+    /// Features the server agreed to; those offered until it answers
+    pub capabilities: Capabilities,
+    /// Times the stream lost its place and was scanned for the next header
+    pub resyncs: u64,
+    /// Ask the server for a virtual touchscreen; cleared without local touch
+    pub forward_touch: bool,
+    /// The server's virtual touchscreen, once it has registered one
+    pub touch_device: Option<TouchDevice>,
+    /// The server's DRM card index, once it has answered Identify
+    pub display_index: Option<u32>,
+    /// Session mode asked of the server, again on reconnect
+    pub session_mode: SessionMode,
+    /// Most the server allows, as it last answered; requests beyond this
+    /// or `session_mode` aren't sent
+    pub allowed_mode: SessionMode,
+    /// Who drives the server's input devices, as it last said; `None` from
+    /// servers that let every client drive at once
+    pub input_control: Option<InputControl>,
+    /// Part of the remote display shown, asked of the server on connect
+    pub crop: Option<Region>,
+    /// What a stalled stream turns into, and after how long
+    pub on_stall: HoldPolicy,
+    /// What fills the window beside the stream
+    pub letterbox: Letterbox,
+    pub stall_timeout: Duration,
+    /// When the server last sent anything, heartbeats included
+    pub last_signal: Option<Instant>,
+    pub quality_mode: QualityMode,
+    pub scale_filter: ScaleFilter,
+    pub orientation: Orientation,
+    pub adjustments: Adjustments,
+    pub renderer: RendererKind,
+    /// Shown in place of the stream at startup
+    pub test_pattern: Option<TestPattern>,
+    pub decoder: DecoderChoice,
+    /// Compressed formats the decoder handles; the renderer does the rest
+    pub decoded_formats: Vec<FrameFormat>,
+    /// Limits last asked of the server, repeated on reconnect
+    pub quality: QualityLimits,
+    /// Order the adaptive controller lowers quality in
+    pub degradation: Vec<Degradation>,
+    /// Ask for signed content hashes and log the verified ones here
+    pub content_log: Option<PathBuf>,
+    /// `--content-key`, for servers without a pairing
+    pub content_key: Option<[u8; 32]>,
+    /// Frames that matched their signed hash
+    pub content_verified: u64,
+    /// Badly signed hashes and frames that didn't match theirs
+    pub content_mismatches: u64,
+    /// Bytes received per account and billing cycle
+    pub usage: UsageLedger,
+    /// What received bytes are counted against in `usage`
+    pub usage_account: String,
+    pub budget: Option<Budget>,
+    /// Last budget warning given, so each is logged once per cycle
+    pub budget_notice: Option<(Period, BudgetLevel)>,
+    /// Stay away from the server until asked to connect again
+    pub user_disconnected: bool,
+    /// Commands run on connection events
+    pub hooks: Hooks,
 }
 
 impl Default for AppState {
@@ -64,124 +676,1056 @@ impl Default for AppState {
             connected: false,
             server: "127.0.0.1".to_string(),
             port: 8080,
+            server_generation: 0,
+            recent: RecentServers::default(),
+            bind_interface: None,
+            bind_address: None,
+            aggregate_interface: None,
+            link_mode: LinkMode::default(),
+            transport: Transport::default(),
+            ws_path: "/".to_string(),
+            session_id: 0,
+            links: Vec::new(),
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
             display_width: 1920,
             display_height: 1080,
+            display_resized_at: None,
+            mode_change: ModeChange::default(),
             fullscreen: false,
+            borderless: false,
+            on_top: false,
+            kiosk: false,
+            block_input: false,
             vsync: false,
+            pacing: PacingPreference::default(),
+            playout_delay_ms: DEFAULT_PLAYOUT_DELAY.as_millis() as u32,
+            sync_delay: None,
+            auto_mode: true,
+            resize_window: false,
+            idle_pause: false,
+            paused: false,
+            refresh_mhz: 0,
+            vrr: false,
+            pairings: PairingStore::default(),
+            auth_providers: Vec::new(),
+            noise_key: None,
+            known_servers: KnownServers::default(),
+            checksum: false,
+            corrupt_frames: 0,
+            ack: false,
+            capabilities: Capabilities::empty(),
+            resyncs: 0,
+            forward_touch: false,
+            session_mode: SessionMode::default(),
+            allowed_mode: SessionMode::default(),
+            input_control: None,
+            crop: None,
+            on_stall: HoldPolicy::default(),
+            letterbox: Letterbox::default(),
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            last_signal: None,
+            touch_device: None,
+            display_index: None,
+            quality_mode: QualityMode::default(),
+            scale_filter: ScaleFilter::default(),
+            orientation: Orientation::default(),
+            adjustments: Adjustments::default(),
+            renderer: RendererKind::default(),
+            test_pattern: None,
+            decoder: DecoderChoice::default(),
+            decoded_formats: Vec::new(),
+            quality: QualityLimits::default(),
+            degradation: Degradation::DEFAULT_ORDER.to_vec(),
+            content_log: None,
+            content_key: None,
+            content_verified: 0,
+            content_mismatches: 0,
+            usage: UsageLedger::default(),
+            usage_account: String::new(),
+            budget: None,
+            budget_notice: None,
+            user_disconnected: false,
+            hooks: Hooks::default(),
+        }
+    }
+}
+
+impl AppState {
+    /// State for the options in `args`, with empty pairing, usage and
+    /// recent server stores; `--noise` loads our key and the servers'
+    fn from_args(args: &Args) -> Result<Self> {
+        let mut auth_providers: Vec<Arc<dyn AuthProvider>> = Vec::new();
+        if let Some(path) = &args.auth_token_file {
+            auth_providers.push(Arc::new(TokenAuth::load(path)?));
         }
+        
+        // A store that can't be read would trust every server afresh
+        let (noise_key, known_servers) = if args.noise {
+            let known_servers = KnownServers::default_path().map(KnownServers::load).transpose()?;
+            (Some(noise::load_or_create_key()?), known_servers.unwrap_or_default())
+        } else {
+            (None, KnownServers::default())
+        };
+        
+        // Relays, recordings and snapshots need frames whether or not
+        // anyone is looking
+        let unattended = args.multicast_relay.is_some();
+        #[cfg(feature = "recording")]
+        let unattended = unattended || args.dump_stream.is_some();
+        #[cfg(feature = "snapshots")]
+        let unattended = unattended || args.thumbnail.is_some() || !args.snapshot_on.is_empty();
+        
+        // An ipds:// source is the same as --server and --port
+        let (server, port) = match args.source.as_deref().map(source::parse).transpose()? {
+            Some(SourceSpec::Ipds { server, port }) => (server, port),
+            _ => (args.server.clone(), args.port),
+        };
+        
+        Ok(AppState {
+            server,
+            port,
+            bind_interface: args.bind_interface.clone(),
+            bind_address: args.bind_address,
+            aggregate_interface: args.aggregate_interface.clone(),
+            link_mode: args.link_mode,
+            transport: args.transport,
+            ws_path: args.ws_path.clone(),
+            session_id: if args.aggregate_interface.is_some() { random_session_id() } else { 0 },
+            heartbeat_timeout: Duration::from_secs(args.heartbeat_timeout.max(1)),
+            display_width: args.width as u32,
+            display_height: args.height as u32,
+            display_resized_at: None,
+            mode_change: args.mode_change,
+            fullscreen: args.fullscreen || args.kiosk,
+            borderless: args.borderless,
+            on_top: args.on_top,
+            kiosk: args.kiosk,
+            block_input: args.block_input,
+            vsync: args.vsync,
+            pacing: args.pacing,
+            playout_delay_ms: args.playout_delay,
+            auto_mode: !args.no_auto_mode,
+            resize_window: args.resize_window,
+            idle_pause: !args.no_idle_pause && !unattended,
+            vrr: args.vrr,
+            auth_providers,
+            noise_key,
+            known_servers,
+            checksum: args.checksum,
+            ack: args.ack,
+            forward_touch: args.forward_touch && !args.block_input,
+            session_mode: args.session_mode,
+            crop: args.crop,
+            on_stall: args.on_stall,
+            letterbox: args.letterbox.clone().unwrap_or_default(),
+            stall_timeout: Duration::from_millis(args.stall_timeout),
+            last_signal: None,
+            quality_mode: args.quality,
+            scale_filter: args.scale_filter,
+            orientation: Orientation::new(args.rotate, &args.flip),
+            adjustments: Adjustments {
+                brightness: args.brightness,
+                contrast: args.contrast,
+                gamma: args.gamma,
+                saturation: args.saturation,
+            }
+            .clamped(),
+            renderer: args.renderer,
+            test_pattern: args.test_pattern,
+            decoder: args.decoder,
+            quality: args.quality.limits().unwrap_or_default(),
+            degradation: args.degrade.clone(),
+            content_log: args.content_log.clone(),
+            content_key: args.content_key,
+            hooks: Hooks {
+                on_connect: args.on_connect.clone(),
+                on_disconnect: args.on_disconnect.clone(),
+                on_error: args.on_error.clone(),
+                profile: args.profile.clone(),
+            },
+            usage_account: args.profile.clone().unwrap_or_else(|| network::server_address(&args.server, args.port)),
+            budget: args.monthly_budget.map(|megabytes| Budget {
+                limit_bytes: megabytes.saturating_mul(1_000_000),
+                action: args.budget_action,
+                cycle_day: args.budget_cycle_day,
+            }),
+            ..Default::default()
+        })
+    }
+    
+    /// What we may do: the mode asked for, or less if the server said so
+    pub fn effective_session_mode(&self) -> SessionMode {
+        self.session_mode.min(self.allowed_mode)
+    }
+    
+    /// Offset to the server's clock, once a Ping/Pong has completed on a
+    /// connected link; with several, the one with the shortest round trip
+    pub fn clock(&self) -> Option<timesync::ClockEstimate> {
+        self.links
+            .iter()
+            .filter(|link| link.connected)
+            .filter_map(|link| link.clock)
+            .min_by_key(|clock| clock.rtt_ns)
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
+fn main() -> Result<()> {
+    // Parse command line arguments, then again behind the profile's
+    let mut args = Args::parse();
+    match &args.command {
+        Some(ClientCommand::Bench(options)) => {
+            print!("{}", bench::report(&bench::run(options)?));
+            return Ok(());
+        }
+        Some(ClientCommand::InstallHandler) => {
+            println!("Installed {} for ipds:// links", desktop::install()?.display());
+            return Ok(());
+        }
+        None => {}
+    }
+    // A link can name the profile, but --profile wins
+    let link = args.uri.as_deref().map(source::parse_link).transpose()?;
+    if let Some(name) = args.profile.clone().or_else(|| link.as_ref()?.profile.clone()) {
+        args = apply_profile(&name)?;
+    }
+    if args.source.is_none() {
+        args.source = link.map(|link| link.source);
+    }
     
-    // Parse command line arguments
-    let args = Args::parse();
+    // Held until exit so the end of the log file is written
+    let _log_guard = logging::init(&args.log)?;
     
     info!("Starting IP Display Client v{}", env!("CARGO_PKG_VERSION"));
     info!("Connecting to {}:{}", args.server, args.port);
+    info!("Pixel conversion: {}", convert::backend().name());
+    
+    // Network I/O runs on Tokio worker threads; GTK stays on this thread
+    let runtime = tokio::runtime::Runtime::new()?;
     
     // Initialize GTK
     gtk4::init()?;
     
-    // Create application state
-    let state = Arc::new(RwLock::new(AppState {
-        server: args.server.clone(),
-        port: args.port,
-        display_width: args.width as u32,
-        display_height: args.height as u32,
-        fullscreen: args.fullscreen,
-        vsync: args.vsync,
-        ..Default::default()
-    }));
-    
-    // Create GTK application
+    // One process shows every window: launched while it runs, the client
+    // hands it the URI to open (or just raises it) and exits
+    let flags = if args.new_instance {
+        gio::ApplicationFlags::NON_UNIQUE
+    } else {
+        gio::ApplicationFlags::HANDLES_OPEN
+    };
     let app = gtk4::Application::builder()
         .application_id("com.ipdisp.client")
+        .flags(flags)
         .build();
+    app.register(gio::Cancellable::NONE)
+        .map_err(|e| anyhow::anyhow!("Can't register the application: {}", e))?;
+    if app.is_remote() {
+        match &args.uri {
+            Some(uri) => {
+                info!("Already running; opening {} there", uri);
+                app.open(&[gio::File::for_uri(uri)], "");
+            }
+            None => {
+                info!("Already running; raising its window");
+                app.activate();
+            }
+        }
+        return Ok(());
+    }
+    
+    let pairings = match PairingStore::default_path().map(PairingStore::load).transpose() {
+        Ok(pairings) => pairings.unwrap_or_default(),
+        Err(e) => {
+            warn!("Ignoring saved pairings: {:#}", e);
+            PairingStore::default()
+        }
+    };
+    
+    let recent = match RecentServers::default_path().map(RecentServers::load).transpose() {
+        Ok(recent) => recent.unwrap_or_default(),
+        Err(e) => {
+            warn!("Ignoring recent servers: {:#}", e);
+            RecentServers::default()
+        }
+    };
+    
+    let usage = match UsageLedger::default_path().map(UsageLedger::load).transpose() {
+        Ok(usage) => usage.unwrap_or_default(),
+        Err(e) => {
+            warn!("Not recording bandwidth usage: {:#}", e);
+            UsageLedger::default()
+        }
+    };
+    
+    // Create application state
+    let state = Arc::new(RwLock::new(AppState { pairings: pairings.clone(), recent, usage, ..AppState::from_args(&args)? }));
     
-    let state_clone = Arc::clone(&state);
+    // A dashboard's tiles each get their own state from their profile
+    let dashboard = match &args.layout {
+        Some(path) => {
+            let layout = Layout::load(path)?;
+            let states = dashboard_states(&layout, &pairings)?;
+            Some((layout, states))
+        }
+        None => None,
+    };
+    
+    // Windows opened with --open are set up like dashboard tiles, but get
+    // the full window and their profile's window options
+    let mut windows = vec![(Arc::clone(&state), WindowOptions::from_args(&args)?)];
+    for args in profile_args(args.open.iter().map(String::as_str))? {
+        let state = AppState { pairings: pairings.clone(), ..AppState::from_args(&args)? };
+        windows.push((Arc::new(RwLock::new(state)), WindowOptions::from_args(&args)?));
+    }
+    
+    // Closing the window cancels `shutdown`; network tasks are tracked so
+    // they get to say goodbye to the server before the runtime goes away
+    let shutdown = CancellationToken::new();
+    let tasks = TaskTracker::new();
+    
+    let usage_state = Arc::clone(&state);
+    let rt = runtime.handle().clone();
+    let (app_shutdown, app_tasks) = (shutdown.clone(), tasks.clone());
+    let started = Cell::new(false);
     app.connect_activate(move |app| {
-        let rt = tokio::runtime::Handle::current();
-        let state = Arc::clone(&state_clone);
-        
-        rt.spawn(async move {
-            if let Err(e) = run_app(app, state).await {
-                error!("Application error: {}", e);
+        // Asked again by another launch
+        if started.replace(true) {
+            if let Some(window) = app.active_window() {
+                window.present();
             }
-        });
+            return;
+        }
+        let result = match &dashboard {
+            Some((layout, states)) => run_dashboard(app, layout, states, &rt, &app_shutdown, &app_tasks),
+            // Each window stops only its own connection when closed
+            None => windows.iter().try_for_each(|(state, options)| {
+                run_app(app, Arc::clone(state), &rt, &app_shutdown.child_token(), &app_tasks, options.clone())
+            }),
+        };
+        if let Err(e) = result {
+            error!("Application error: {}", e);
+        }
     });
     
-    // Run the application
-    app.run();
+    // Streams another launch hands over open in windows of their own, with
+    // the default options
+    let rt = runtime.handle().clone();
+    let (app_shutdown, app_tasks) = (shutdown.clone(), tasks.clone());
+    app.connect_open(move |app, files, _| {
+        for file in files {
+            let uri = file.uri();
+            info!("Opening {} for another launch", uri);
+            let result = link_args(&uri).and_then(|args| {
+                let state = AppState { pairings: pairings.clone(), ..AppState::from_args(&args)? };
+                let options = WindowOptions::from_args(&args)?;
+                run_app(app, Arc::new(RwLock::new(state)), &rt, &app_shutdown.child_token(), &app_tasks, options)
+            });
+            if let Err(e) = result {
+                error!("Can't open {}: {:#}", uri, e);
+            }
+        }
+    });
+    
+    // Run the application; our own arguments were already consumed by clap
+    app.run_with_args::<&str>(&[]);
+    
+    shutdown.cancel();
+    tasks.close();
+    if runtime.block_on(tokio::time::timeout(SHUTDOWN_TIMEOUT, tasks.wait())).is_err() {
+        warn!("Network tasks did not stop within {:?}", SHUTDOWN_TIMEOUT);
+    }
+    
+    if let Err(e) = usage_state.blocking_write().usage.save() {
+        warn!("Failed to save bandwidth usage: {:#}", e);
+    }
     
     Ok(())
 }
 
-async fn run_app(app: &gtk4::Application, state: Arc<RwLock<AppState>>) -> Result<()> {
+/// Parse the arguments again with the profile's options in front of them
+fn apply_profile(name: &str) -> Result<Args> {
+    let path = ProfileStore::default_path().ok_or_else(|| anyhow::anyhow!("No config directory for profiles"))?;
+    let store = ProfileStore::load(&path)?;
+    let Some(profile) = store.get(name) else {
+        let known: Vec<&str> = store.names().collect();
+        return Err(anyhow::anyhow!(
+            "No profile {} in {} (have: {})", name, path.display(), known.join(", ")
+        ));
+    };
+    
+    let mut argv: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let program = if argv.is_empty() { "ip-display-client".into() } else { argv.remove(0) };
+    let args = std::iter::once(program)
+        .chain(profile.args().into_iter().map(Into::into))
+        .chain(argv);
+    Args::try_parse_from(args).map_err(|e| anyhow::anyhow!("Profile {}: {}", name, e))
+}
+
+/// The options of each named profile, as if run with `--profile`
+fn profile_args<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Vec<Args>> {
+    let path = ProfileStore::default_path().ok_or_else(|| anyhow::anyhow!("No config directory for profiles"))?;
+    let profiles = ProfileStore::load(&path)?;
+    names
+        .into_iter()
+        .map(|name| {
+            let profile = profiles
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("No profile {} in {}", name, path.display()))?;
+            let argv = std::iter::once("ip-display-client".to_string()).chain(profile.args());
+            Args::try_parse_from(argv).map_err(|e| anyhow::anyhow!("Profile {}: {}", name, e))
+        })
+        .collect()
+}
+
+/// Options for a window another launch opens `uri` in: the defaults, or
+/// those of the profile the link names, with the link as --source
+fn link_args(uri: &str) -> Result<Args> {
+    let link = source::parse_link(uri)?;
+    let mut args = match &link.profile {
+        Some(name) => profile_args([name.as_str()])?.remove(0),
+        None => Args::try_parse_from(["ip-display-client"])?,
+    };
+    args.source = Some(link.source);
+    Ok(args)
+}
+
+/// State for each tile of `layout`, from the profile it names. Tiles
+/// share the saved pairings but keep no usage or recent servers.
+fn dashboard_states(layout: &Layout, pairings: &PairingStore) -> Result<Vec<Arc<RwLock<AppState>>>> {
+    profile_args(layout.tiles.iter().map(|tile| tile.profile.as_str()))?
+        .iter()
+        .map(|args| {
+            let state = AppState { pairings: pairings.clone(), ..AppState::from_args(args)? };
+            Ok(Arc::new(RwLock::new(state)))
+        })
+        .collect()
+}
+
+/// Non-zero id that's unlikely to collide with another client's
+fn random_session_id() -> u32 {
+    use std::hash::{BuildHasher, Hasher};
+    
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    (hasher.finish() as u32).max(1)
+}
+
+/// What the main window does besides showing the stream
+#[derive(Debug, Clone)]
+struct WindowOptions {
+    /// Pair with the server, prompting for its code
+    pair: bool,
+    /// Where to write a thumbnail, if anywhere
+    #[cfg(feature = "snapshots")]
+    thumbnail: Option<PathBuf>,
+    /// How often to take one, for that file and the control API
+    #[cfg(feature = "snapshots")]
+    thumbnail_interval: Duration,
+    #[cfg(feature = "snapshots")]
+    snapshots: Option<SnapshotConfig>,
+    protocol_log: Option<Arc<ProtocolLog>>,
+    #[cfg(feature = "recording")]
+    stream_dump: Option<Arc<StreamDump>>,
+    /// A recorded stream to show instead of connecting
+    #[cfg(feature = "recording")]
+    play: Option<PathBuf>,
+    /// Connect to a built-in server showing this instead
+    demo: Option<DemoPattern>,
+    /// Show this source instead of an IP Display server
+    source: Option<String>,
+    /// Send frames on to this multicast group, with this percentage of
+    /// parity
+    multicast_relay: Option<(SocketAddr, u8)>,
+    /// Serve metrics on this port
+    metrics_port: Option<u16>,
+    /// Take JSON-RPC requests on this port
+    control_port: Option<u16>,
+    /// Largest file to upload, in bytes; 0 ignores drops
+    upload_limit: u64,
+    /// Play the server's sound
+    audio: bool,
+}
+
+impl WindowOptions {
+    fn from_args(args: &Args) -> Result<Self> {
+        Ok(Self {
+            pair: args.pair,
+            #[cfg(feature = "snapshots")]
+            thumbnail: args.thumbnail.clone(),
+            #[cfg(feature = "snapshots")]
+            thumbnail_interval: Duration::from_secs(args.thumbnail_interval.max(1)),
+            #[cfg(feature = "snapshots")]
+            snapshots: args.snapshot_config()?,
+            protocol_log: match &args.record_protocol_metadata {
+                Some(path) => Some(Arc::new(ProtocolLog::create(path)?)),
+                None => None,
+            },
+            #[cfg(feature = "recording")]
+            stream_dump: match &args.dump_stream {
+                Some(path) => Some(Arc::new(StreamDump::create(path)?)),
+                None => None,
+            },
+            #[cfg(feature = "recording")]
+            play: args.play.clone(),
+            demo: args.demo,
+            source: match args.source.as_deref().map(source::parse).transpose()? {
+                Some(SourceSpec::Other(uri)) => Some(uri),
+                _ => args.multicast.map(|group| format!("multicast://{}", group)),
+            },
+            multicast_relay: args.multicast_relay.map(|group| (group, args.multicast_fec)),
+            metrics_port: args.metrics_port,
+            control_port: args.control_port,
+            upload_limit: args.upload_limit.saturating_mul(1_000_000),
+            audio: args.audio,
+        })
+    }
+}
+
+fn run_app(
+    app: &gtk4::Application,
+    state: Arc<RwLock<AppState>>,
+    rt: &tokio::runtime::Handle,
+    shutdown: &CancellationToken,
+    tasks: &TaskTracker,
+    options: WindowOptions,
+) -> Result<()> {
+    let WindowOptions {
+        pair,
+        #[cfg(feature = "snapshots")]
+        thumbnail,
+        #[cfg(feature = "snapshots")]
+        thumbnail_interval,
+        #[cfg(feature = "snapshots")]
+        snapshots,
+        protocol_log,
+        #[cfg(feature = "recording")]
+        stream_dump,
+        #[cfg(feature = "recording")]
+        play,
+        demo,
+        source,
+        multicast_relay,
+        metrics_port,
+        control_port,
+        upload_limit,
+        audio,
+    } = options;
+    
+    // The demo server stands in for a real one, and isn't worth keeping
+    // among the recent servers
+    if let Some(pattern) = demo {
+        let screen = Screen::capture(Box::new(DemoSource::new(pattern)), DEMO_FPS, shutdown)?;
+        let addr = ipdisp_server::start((Ipv4Addr::LOCALHOST, 0).into(), Service::new(screen), rt, tasks, shutdown)?;
+        let mut state_guard = state.blocking_write();
+        state_guard.server = addr.ip().to_string();
+        state_guard.port = addr.port();
+        state_guard.transport = Transport::Tcp;
+        state_guard.recent = RecentServers::default();
+    }
+    #[cfg(feature = "recording")]
+    let replay = play.map(|path| Arc::new(ReplaySource::new(path, Arc::clone(&state))) as Arc<dyn DisplaySource>);
+    #[cfg(not(feature = "recording"))]
+    let replay: Option<Arc<dyn DisplaySource>> = None;
+    
+    // Requests from the UI are forwarded to the server by a separate task
+    let (command_tx, mut command_rx) = tokio::sync::mpsc::unbounded_channel::<Command>();
+    
     // Create main window
-    let window = DisplayWindow::new(app, Arc::clone(&state)).await?;
+    let window = DisplayWindow::new(app, Arc::clone(&state), command_tx.clone(), shutdown.clone())?;
+    
+    // Create network client(s): the primary link, plus a second path when
+    // aggregating
+    let (primary, secondary) = {
+        let state_guard = state.blocking_read();
+        let primary = LinkPath {
+            index: 0,
+            interface: state_guard.bind_interface.clone(),
+            address: state_guard.bind_address,
+        };
+        let secondary = state_guard.aggregate_interface.clone().map(|interface| LinkPath {
+            index: 1,
+            interface: Some(interface),
+            address: None,
+        });
+        (primary, secondary)
+    };
+    let mut network_client = NetworkClient::new(Arc::clone(&state), primary)?;
+    
+    // Pairing runs over the primary link and asks the window for the code
+    let (prompt_tx, mut prompt_rx) = tokio::sync::mpsc::unbounded_channel::<PairPrompt>();
+    if pair {
+        network_client = network_client.with_pair_prompts(prompt_tx);
+    }
     
-    // Create network client
-    let network_client = NetworkClient::new(Arc::clone(&state)).await?;
+    // A server's sign-in asks in the window too
+    let (sign_in_tx, mut sign_in_rx) = tokio::sync::mpsc::unbounded_channel::<SignInPrompt>();
+    network_client = network_client.with_sign_in_prompts(sign_in_tx);
     
-    // Connect to server
-    let server_addr = {
-        let state_guard = state.read().await;
-        format!("{}:{}", state_guard.server, state_guard.port)
+    // Replies to Server menu requests go to the status bar
+    let (status_tx, mut status_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    network_client = network_client.with_status_messages(status_tx);
+    
+    // Files dropped on the window go to the server over the primary link,
+    // one at a time; other sources have nowhere to put them
+    let (file_status_tx, file_status_rx) = tokio::sync::mpsc::unbounded_channel::<FileStatus>();
+    network_client = network_client.with_file_statuses(file_status_tx);
+    if upload_limit > 0 && replay.is_none() && source.is_none() {
+        let (path_tx, mut path_rx) = tokio::sync::mpsc::unbounded_channel::<PathBuf>();
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::unbounded_channel::<UploadEvent>();
+        window.accept_uploads(path_tx);
+        let mut uploader = Uploader::new(command_tx.clone(), file_status_rx, upload_limit);
+        let upload_shutdown = shutdown.clone();
+        tasks.spawn_on(async move {
+            while let Some(Some(path)) = upload_shutdown.run_until_cancelled(path_rx.recv()).await {
+                upload_shutdown.run_until_cancelled(uploader.upload(&path, &event_tx)).await;
+            }
+        }, rt);
+        let upload_window = Rc::clone(&window);
+        glib::MainContext::default().spawn_local(async move {
+            while let Some(event) = event_rx.recv().await {
+                upload_window.on_upload_event(event);
+            }
+        });
+    }
+    // Only the primary link asks for sound, so a session plays it once
+    if audio && replay.is_none() && source.is_none() {
+        network_client = network_client.with_audio(AudioOutput::open()?);
+    }
+    if let Some(log) = &protocol_log {
+        network_client = network_client.with_protocol_log(Arc::clone(log));
+    }
+    #[cfg(feature = "recording")]
+    if let Some(dump) = &stream_dump {
+        network_client = network_client.with_stream_dump(Arc::clone(dump));
+        window.set_status(&format!("Recording the stream to {}", dump.path().display()));
+    }
+    let metrics = match metrics_port {
+        Some(port) => {
+            let metrics = Arc::new(Metrics::default());
+            let stats = window.stats().subscribe();
+            metrics::start(port, Arc::clone(&metrics), stats, Arc::clone(&state), rt, tasks, shutdown)?;
+            network_client = network_client.with_metrics(Arc::clone(&metrics));
+            Some(metrics)
+        }
+        None => None,
     };
     
-    match network_client.connect(&server_addr).await {
-        Ok(_) => {
-            info!("Connected to server successfully");
-            let mut state_guard = state.write().await;
-            state_guard.connected = true;
+    // Show window; once mapped we know which monitor's refresh to target
+    window.show();
+    {
+        let mut state_guard = state.blocking_write();
+        state_guard.refresh_mhz = window.monitor_refresh_mhz();
+        
+        // VRR only helps when GSK hands frames to the compositor via GL
+        if state_guard.vrr && !window.uses_gl_renderer() {
+            warn!("VRR requested but GTK is not using the GL renderer; presenting at fixed refresh");
+            state_guard.vrr = false;
         }
-        Err(e) => {
-            warn!("Failed to connect to server: {}", e);
-            // Continue anyway - allow user to retry
+        
+        if state_guard.forward_touch && !window.has_touchscreen() {
+            warn!("Touch forwarding requested but no touchscreen found; not asking the server for one");
+            state_guard.forward_touch = false;
+        }
+        
+        // VRR displays refresh when we present, so there's no refresh to
+        // pace to: frames go up as they arrive, or as they fall due under
+        // timestamp playout
+        let timed = matches!(state_guard.pacing, PacingPreference::Cadence | PacingPreference::Sync);
+        if (state_guard.vsync && !state_guard.vrr) || timed {
+            window.start_pacing(state_guard.vrr);
         }
     }
     
-    // Show window
-    window.show();
+    // Frames flow from the network task to the GTK thread through here,
+    // decoded on the way when a decoder backend was picked
+    let (frame_tx, frame_rx) = frame_channel::channel(FRAME_QUEUE_DEPTH);
+    let decoder = open_decoder(&state)?;
+    
+    // Start the sources; aggregated links feed the same frame channel. A
+    // replay or another kind of source stands in for all of them.
+    let pump = FramePump {
+        frames: frame_tx,
+        merger: Arc::new(LinkMerger::default()),
+        decoder,
+        metrics: metrics.clone(),
+        relay: match multicast_relay {
+            Some((group, fec)) => Some(Arc::new(rt.block_on(MulticastSender::bind(group, fec))?)),
+            None => None,
+        },
+        crop: state.blocking_read().crop,
+        kiosk: state.blocking_read().kiosk,
+    };
+    let mut links: Vec<Arc<dyn DisplaySource>> = Vec::new();
+    let primary: Arc<dyn DisplaySource> = match (replay, source) {
+        (Some(replay), _) => replay,
+        (None, Some(uri)) => source::open(&uri, &state)?,
+        (None, None) => {
+            if let Some(path) = secondary {
+                let mut client = NetworkClient::new(Arc::clone(&state), path)?;
+                if let Some(log) = protocol_log {
+                    client = client.with_protocol_log(log);
+                }
+                #[cfg(feature = "recording")]
+                if let Some(dump) = stream_dump {
+                    client = client.with_stream_dump(dump);
+                }
+                if let Some(metrics) = &metrics {
+                    client = client.with_metrics(Arc::clone(metrics));
+                }
+                spawn_keepalive(rt, tasks, shutdown, &client);
+                let client: Arc<dyn DisplaySource> = Arc::new(client);
+                links.push(Arc::clone(&client));
+                spawn_source(rt, tasks, shutdown, client, pump.clone());
+            }
+            spawn_keepalive(rt, tasks, shutdown, &network_client);
+            Arc::new(network_client)
+        }
+    };
+    spawn_source(rt, tasks, shutdown, Arc::clone(&primary), pump);
+    links.insert(0, primary);
     
-    // Start network loop
-    let window_weak = window.downgrade();
-    let network_client_clone = network_client.clone();
-    tokio::spawn(async move {
-        if let Err(e) = network_loop(network_client_clone, window_weak).await {
-            error!("Network loop error: {}", e);
+    // Requests from the menus go to the primary source; every link carries
+    // frames, so each is paused, and each has its own session mode
+    let command_shutdown = shutdown.clone();
+    tasks.spawn_on(async move {
+        while let Some(Some(command)) = command_shutdown.run_until_cancelled(command_rx.recv()).await {
+            let targets = match command {
+                Command::Pause { .. } | Command::SessionMode { .. } => &links[..],
+                _ => &links[..1],
+            };
+            for link in targets {
+                if let Err(e) = link.send_input(&command).await {
+                    warn!("Failed to send {:?}: {}", command, e);
+                }
+            }
+        }
+    }, rt);
+    
+    let prompt_window = Rc::clone(&window);
+    glib::MainContext::default().spawn_local(async move {
+        while let Some(prompt) = prompt_rx.recv().await {
+            prompt_window.prompt_pairing_code(prompt);
         }
     });
     
+    let sign_in_window = Rc::clone(&window);
+    glib::MainContext::default().spawn_local(async move {
+        while let Some(prompt) = sign_in_rx.recv().await {
+            sign_in_window.prompt_sign_in(prompt);
+        }
+    });
+    
+    // Thumbnails for `--thumbnail` and the control API's `thumbnail`
+    #[cfg(feature = "snapshots")]
+    let thumbnails = (thumbnail.is_some() || control_port.is_some())
+        .then(|| Thumbnailer::start(window.renderer(), thumbnail_interval, DEFAULT_THUMBNAIL_WIDTH))
+        .transpose()?
+        .map(|thumbnailer| thumbnailer.subscribe());
+    
+    // Control requests are carried out on this thread, one at a time
+    if let Some(port) = control_port {
+        let (call_tx, mut call_rx) = tokio::sync::mpsc::unbounded_channel::<ControlCall>();
+        control::start(port, call_tx, rt, tasks, shutdown)?;
+        let target = ControlTarget {
+            window: Rc::clone(&window),
+            state: Arc::clone(&state),
+            #[cfg(feature = "snapshots")]
+            thumbnails: thumbnails.clone(),
+        };
+        glib::MainContext::default().spawn_local(async move {
+            while let Some((request, reply)) = call_rx.recv().await {
+                let _ = reply.send(control::handle(&target, request));
+            }
+        });
+    }
+    
+    let status_window = Rc::clone(&window);
+    glib::MainContext::default().spawn_local(async move {
+        while let Some(message) = status_rx.recv().await {
+            status_window.set_status(&message);
+        }
+    });
+    
+    // Usage is written out now and then rather than on every frame
+    let usage_state = Arc::clone(&state);
+    let usage_shutdown = shutdown.clone();
+    tasks.spawn_on(async move {
+        let mut interval = tokio::time::interval(USAGE_SAVE_INTERVAL);
+        while usage_shutdown.run_until_cancelled(interval.tick()).await.is_some() {
+            if let Err(e) = usage_state.write().await.usage.save() {
+                warn!("Failed to save bandwidth usage: {:#}", e);
+            }
+        }
+    }, rt);
+    
+    #[cfg(feature = "snapshots")]
+    if let Some((path, mut updates)) = thumbnail.zip(thumbnails) {
+        let shutdown = shutdown.clone();
+        tasks.spawn_on(async move {
+            while let Some(Ok(())) = shutdown.run_until_cancelled(updates.changed()).await {
+                let Some(thumbnail) = updates.borrow_and_update().clone() else { continue };
+                if let Err(e) = write_thumbnail(&path, &thumbnail.jpeg).await {
+                    warn!("Failed to write thumbnail to {}: {}", path.display(), e);
+                }
+            }
+        }, rt);
+    }
+    
+    #[cfg(feature = "snapshots")]
+    if let Some(config) = snapshots {
+        automation::start(config, window.renderer(), window.stats().subscribe(), Arc::clone(&state), rt, tasks);
+    }
+    
+    // Adaptive quality follows how many frames the render queue drops
+    let frame_rx = Rc::new(frame_rx);
+    start_adaptive_quality(Rc::clone(&frame_rx), state, command_tx);
+    
+    // Render loop on the GTK main context, below redraw priority so a slow
+    // draw makes the queue overflow (dropping frames) instead of backing up
+    glib::MainContext::default().spawn_local_with_priority(
+        glib::Priority::DEFAULT_IDLE,
+        async move {
+            while let Some(frame) = frame_rx.recv().await {
+                if let Err(e) = window.submit_frame(frame) {
+                    warn!("Failed to update frame: {}", e);
+                }
+                if let Some(metrics) = &metrics {
+                    metrics.set_render_drops(frame_rx.dropped());
+                }
+            }
+        },
+    );
+    
     Ok(())
 }
 
-async fn network_loop(
-    client: NetworkClient, 
-    window: glib::WeakRef<DisplayWindow>
+/// Open the dashboard window and connect each of its tiles
+fn run_dashboard(
+    app: &gtk4::Application,
+    layout: &Layout,
+    states: &[Arc<RwLock<AppState>>],
+    rt: &tokio::runtime::Handle,
+    shutdown: &CancellationToken,
+    tasks: &TaskTracker,
 ) -> Result<()> {
-    loop {
-        match client.receive_frame().await {
-            Ok(Some((header, data))) => {
-                // Update display
-                if let Some(window) = window.upgrade() {
-                    if let Err(e) = window.update_frame(&header, &data).await {
-                        warn!("Failed to update frame: {}", e);
-                    }
+    let tiles = layout
+        .tiles
+        .iter()
+        .zip(states)
+        .map(|(spec, state)| StreamTile::new(spec, Arc::clone(state)))
+        .collect::<Result<Vec<_>>>()?;
+    
+    let window = dashboard::window(app, layout, &tiles);
+    let window_shutdown = shutdown.clone();
+    window.connect_close_request(move |_| {
+        window_shutdown.cancel();
+        glib::Propagation::Proceed
+    });
+    window.present();
+    
+    for (tile, state) in tiles.into_iter().zip(states) {
+        let link = {
+            let state_guard = state.blocking_read();
+            LinkPath { index: 0, interface: state_guard.bind_interface.clone(), address: state_guard.bind_address }
+        };
+        let client = NetworkClient::new(Arc::clone(state), link)?;
+        let (frame_tx, frame_rx) = frame_channel::channel(FRAME_QUEUE_DEPTH);
+        let decoder = open_decoder(state)?;
+        let pump = FramePump {
+            frames: frame_tx,
+            merger: Arc::new(LinkMerger::default()),
+            decoder,
+            metrics: None,
+            relay: None,
+            crop: state.blocking_read().crop,
+            kiosk: state.blocking_read().kiosk,
+        };
+        spawn_keepalive(rt, tasks, shutdown, &client);
+        spawn_source(rt, tasks, shutdown, Arc::new(client), pump);
+        
+        glib::MainContext::default().spawn_local_with_priority(glib::Priority::DEFAULT_IDLE, async move {
+            while let Some(frame) = frame_rx.recv().await {
+                tile.show_frame(&frame);
+            }
+        });
+    }
+    
+    Ok(())
+}
+
+/// The decoder `state` asks for, shared by the links of one stream
+fn open_decoder(state: &Arc<RwLock<AppState>>) -> Result<Option<SharedDecoder>> {
+    let mut state_guard = state.blocking_write();
+    let Some(decoder) = FrameDecoder::open(state_guard.decoder)? else { return Ok(None) };
+    state_guard.decoded_formats = decoder.formats();
+    Ok(Some(Arc::new(std::sync::Mutex::new(decoder))))
+}
+
+/// Replace the file at `path` in one step, so readers never see half a JPEG
+#[cfg(feature = "snapshots")]
+async fn write_thumbnail(path: &Path, jpeg: &[u8]) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    tokio::fs::write(&partial, jpeg).await?;
+    tokio::fs::rename(&partial, path).await?;
+    Ok(())
+}
+
+/// Every `QUALITY_INTERVAL`, ask the server for lower or higher quality
+/// when in auto mode, based on dropped frames and throughput
+fn start_adaptive_quality(
+    frames: Rc<FrameReceiver>,
+    state: Arc<RwLock<AppState>>,
+    commands: tokio::sync::mpsc::UnboundedSender<Command>,
+) {
+    let mut controller = AdaptiveQuality::new(&state.blocking_read().degradation);
+    let mut last = (frames.sent(), frames.dropped(), 0u64);
+    glib::timeout_add_local(QUALITY_INTERVAL, move || {
+        let mut state_guard = state.blocking_write();
+        let now = std::time::Instant::now();
+        let current = (frames.sent(), frames.dropped(), state_guard.corrupt_frames);
+        let dropped = (current.1 - last.1) + (current.2 - last.2);
+        let sample = QualitySample {
+            frames: (current.0 - last.0).saturating_sub(current.1 - last.1),
+            dropped,
+            bytes_per_sec: state_guard.links.iter().map(|link| link.rx.bytes_per_sec(now)).sum(),
+        };
+        last = current;
+        
+        if state_guard.quality_mode != QualityMode::Auto {
+            return glib::ControlFlow::Continue;
+        }
+        // Coming back from a fixed mode resumes the controller's limits
+        let changed = controller.update(sample);
+        let resumed = (state_guard.quality != controller.limits()).then(|| controller.limits());
+        if let Some(limits) = changed.or(resumed) {
+            info!("Adapting stream quality: {:?}", limits);
+            state_guard.quality = limits;
+            let _ = commands.send(limits.to_command());
+        }
+        glib::ControlFlow::Continue
+    });
+}
+
+/// Where a window's sources send their frames
+#[derive(Clone)]
+struct FramePump {
+    frames: FrameSender,
+    /// Drops frames that arrive after a newer one on another link
+    merger: Arc<LinkMerger>,
+    decoder: Option<SharedDecoder>,
+    metrics: Option<Arc<Metrics>>,
+    /// Sends frames on, still encoded, to multicast viewers
+    relay: Option<Arc<MulticastSender>>,
+    /// Cut frames down to this, when the source sent more
+    crop: Option<Region>,
+    /// Start the source over when it fails, as a kiosk never gives up
+    kiosk: bool,
+}
+
+/// Pings keep the clock estimate fresh; heartbeats cover quiet periods
+fn spawn_keepalive(rt: &tokio::runtime::Handle, tasks: &TaskTracker, shutdown: &CancellationToken, client: &NetworkClient) {
+    let (client, shutdown) = (client.clone(), shutdown.clone());
+    tasks.spawn_on(async move { client.keepalive(shutdown).await }, rt);
+}
+
+/// Connect `source` and show its frames until the window closes or the
+/// source ends, then close it so a server can say goodbye
+fn spawn_source(
+    rt: &tokio::runtime::Handle,
+    tasks: &TaskTracker,
+    shutdown: &CancellationToken,
+    source: Arc<dyn DisplaySource>,
+    pump: FramePump,
+) {
+    let shutdown = shutdown.clone();
+    tasks.spawn_on(async move {
+        let run = async {
+            if let Err(e) = source.connect().await {
+                // Network sources keep retrying as frames are asked for
+                warn!("Failed to connect to {} source: {:#}", source.name(), e);
+            }
+            
+            while let Err(e) = source_loop(source.as_ref(), &pump).await {
+                error!("{} source error: {:#}", source.name(), e);
+                if !pump.kiosk || pump.frames.is_closed() {
+                    break;
                 }
+                tokio::time::sleep(RECONNECT_DELAY).await;
             }
-            Ok(None) => {
-                // No data received, continue
-                tokio::time::sleep(tokio::time::Duration::from_millis(16)).await;
+        };
+        
+        // Whatever the loop was waiting on is abandoned; the connection is
+        // closed right after
+        if shutdown.run_until_cancelled(run).await.is_none() {
+            info!("Shutting down {} source", source.name());
+        }
+        if let Err(e) = source.close().await {
+            warn!("Failed to close connection: {}", e);
+        }
+    }, rt);
+}
+
+async fn source_loop(source: &dyn DisplaySource, pump: &FramePump) -> Result<()> {
+    let decoder = pump.decoder.as_ref();
+    let mut connections = source.stats().connections;
+    
+    while !pump.frames.is_closed() {
+        let Some(frame) = source.next_frame().await? else {
+            info!("{} source ended after {} frames", source.name(), source.stats().frames);
+            return Ok(());
+        };
+        
+        // A new connection starts the stream over, keyframe first
+        let stats = source.stats();
+        if stats.connections != connections {
+            connections = stats.connections;
+            if let Some(decoder) = decoder {
+                decoder.lock().unwrap().reset();
             }
-            Err(e) => {
-                error!("Network error: {}", e);
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        }
+        
+        if !pump.merger.accept(frame.header.timestamp) {
+            debug!("Dropping frame overtaken on another link");
+            if let Some(metrics) = &pump.metrics {
+                metrics.overtaken();
+            }
+            continue;
+        }
+        if let Some(relay) = &pump.relay {
+            if let Err(e) = relay.send(&frame).await {
+                warn!("Failed to relay frame: {}", e);
             }
         }
+        
+        let started = std::time::Instant::now();
+        let decoded = decode_frame(decoder, frame).await;
+        if let Some(metrics) = pump.metrics.as_ref().filter(|_| decoder.is_some()) {
+            metrics.decoded(started.elapsed());
+        }
+        match decoded {
+            Ok(Some(frame)) => match crop(frame, pump.crop) {
+                Ok(frame) => pump.frames.send(frame),
+                Err(e) => warn!("Failed to crop frame: {}", e),
+            },
+            Ok(None) => debug!("Decoder is holding a frame back"),
+            Err(e) => warn!("Failed to decode frame: {}", e),
+        }
     }
+    
+    info!("Display window closed, stopping {} source", source.name());
+    Ok(())
+}
+
+/// `frame` cut down to `region`, if the source didn't already
+fn crop(frame: FrameData, region: Option<Region>) -> Result<FrameData> {
+    match region {
+        Some(region) => frame.crop(region),
+        None => Ok(frame),
+    }
+}
+
+/// `frame` through the decoder, if there is one, off the async workers
+async fn decode_frame(decoder: Option<&SharedDecoder>, frame: FrameData) -> Result<Option<FrameData>> {
+    let Some(decoder) = decoder.cloned() else { return Ok(Some(frame)) };
+    tokio::task::spawn_blocking(move || decoder.lock().unwrap().decode(frame)).await?
 }
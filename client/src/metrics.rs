@@ -0,0 +1,283 @@
+// IP Display Client - Metrics Export
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Stream statistics over HTTP in the Prometheus text format
+//! (`--metrics-port`), for keeping an eye on a fleet of clients. Any GET
+//! is answered with the current metrics; there is no authentication, so
+//! firewall the port where the statistics shouldn't be public.
+//!
+//! Gauges come from the window's `StatsHub`. Counters for what only the
+//! network tasks and the render loop see are kept in `Metrics`.
+
+use anyhow::Result;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, RwLock};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+use ip_display_client::stats::StatsSnapshot;
+
+use crate::AppState;
+
+/// How long a scraper gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest request read; anything past it is ignored
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Counters for events the `StatsHub` doesn't see
+#[derive(Debug, Default)]
+pub struct Metrics {
+    reconnects: AtomicU64,
+    decoded_frames: AtomicU64,
+    decode_nanos: AtomicU64,
+    render_drops: AtomicU64,
+    overtaken_frames: AtomicU64,
+}
+
+impl Metrics {
+    /// A link got its connection back after losing it
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A frame spent `time` in the decoder
+    pub fn decoded(&self, time: Duration) {
+        self.decoded_frames.fetch_add(1, Ordering::Relaxed);
+        self.decode_nanos.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Frames the render queue has dropped so far
+    pub fn set_render_drops(&self, dropped: u64) {
+        self.render_drops.store(dropped, Ordering::Relaxed);
+    }
+
+    /// A frame arrived after a newer one on another link
+    pub fn overtaken(&self) {
+        self.overtaken_frames.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Serve metrics on `port` of every interface until `shutdown`
+pub fn start(
+    port: u16,
+    metrics: Arc<Metrics>,
+    stats: watch::Receiver<StatsSnapshot>,
+    state: Arc<RwLock<AppState>>,
+    rt: &tokio::runtime::Handle,
+    tasks: &TaskTracker,
+    shutdown: &CancellationToken,
+) -> Result<SocketAddr> {
+    let listener = std::net::TcpListener::bind(("0.0.0.0", port))
+        .map_err(|e| anyhow::anyhow!("Failed to listen for metrics on port {}: {}", port, e))?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    let (tasks, shutdown) = (tasks.clone(), shutdown.clone());
+    tasks.clone().spawn_on(async move {
+        let listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => return warn!("Metrics endpoint failed to start: {}", e),
+        };
+        while let Some(accepted) = shutdown.run_until_cancelled(listener.accept()).await {
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Metrics endpoint failed to accept: {}", e);
+                    continue;
+                }
+            };
+            let (metrics, stats, state) = (Arc::clone(&metrics), stats.clone(), Arc::clone(&state));
+            tasks.spawn(async move {
+                if let Err(e) = serve(stream, &metrics, &stats, &state).await {
+                    debug!("Metrics request from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }, rt);
+    Ok(addr)
+}
+
+/// Answer one request and close the connection
+async fn serve(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+    stats: &watch::Receiver<StatsSnapshot>,
+    state: &RwLock<AppState>,
+) -> Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| anyhow::anyhow!("Request timed out"))??;
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(_)) => {
+            let connected = state.read().await.connected;
+            let body = render(&stats.borrow(), metrics, connected);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The request up to the end of its headers
+async fn read_request(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&chunk[..n]);
+    }
+    Ok(request)
+}
+
+/// Everything in the Prometheus text format
+pub fn render(stats: &StatsSnapshot, metrics: &Metrics, connected: bool) -> String {
+    let mut text = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+        let _ = writeln!(text, "# HELP ipdisp_{} {}", name, help);
+        let _ = writeln!(text, "# TYPE ipdisp_{} {}", name, kind);
+        for (suffix, value) in samples {
+            let _ = writeln!(text, "ipdisp_{}{} {}", name, suffix, value);
+        }
+    };
+    let one = |value: f64| [(String::new(), value)];
+    let counter = |counter: &AtomicU64| one(counter.load(Ordering::Relaxed) as f64);
+
+    family("connected", "gauge", "Whether the client is connected to its server", &one(connected as u8 as f64));
+    family("frames_per_second", "gauge", "Frames shown over the last second", &one(stats.fps));
+    family("frame_width_pixels", "gauge", "Width of the frame on screen", &one(stats.frame_width as f64));
+    family("frame_height_pixels", "gauge", "Height of the frame on screen", &one(stats.frame_height as f64));
+    let links: Vec<_> = stats
+        .links
+        .iter()
+        .map(|link| (format!("{{link=\"{}\"}}", escape(&link.label)), link.bytes_per_sec * 8.0))
+        .collect();
+    family("receive_bits_per_second", "gauge", "Receive rate of each link over the last second", &links);
+    if let Some(latency) = stats.latency_ms {
+        family("latency_seconds", "gauge", "Time from the server stamping a frame to drawing it", &one(latency / 1e3));
+    }
+    if let Some(rtt) = stats.rtt_ms {
+        family("round_trip_seconds", "gauge", "Round trip of the best recent clock probe", &one(rtt / 1e3));
+    }
+    family("decode_seconds", "summary", "Time frames spent in the decoder", &[
+        ("_sum".to_string(), metrics.decode_nanos.load(Ordering::Relaxed) as f64 / 1e9),
+        ("_count".to_string(), metrics.decoded_frames.load(Ordering::Relaxed) as f64),
+    ]);
+    family("reconnects_total", "counter", "Connections regained after being lost", &counter(&metrics.reconnects));
+    family("render_queue_drops_total", "counter", "Frames dropped waiting to be drawn", &counter(&metrics.render_drops));
+    family(
+        "overtaken_frames_total",
+        "counter",
+        "Frames dropped for arriving after a newer one on another link",
+        &counter(&metrics.overtaken_frames),
+    );
+    if let Some(dropped) = stats.paced_drops {
+        family("paced_drops_total", "counter", "Frames the pacer dropped", &one(dropped as f64));
+    }
+    family("corrupt_frames_total", "counter", "Frames dropped for a bad CRC-32", &one(stats.corrupt_frames as f64));
+    family("resyncs_total", "counter", "Times the stream lost its place and was scanned for a header", &one(stats.resyncs as f64));
+    family(
+        "content_mismatches_total",
+        "counter",
+        "Content hashes that were badly signed or didn't match their frame",
+        &one(stats.content_mismatches as f64),
+    );
+    text
+}
+
+/// `value` as a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ip_display_client::stats::LinkRate;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.reconnected();
+        metrics.decoded(Duration::from_millis(3));
+        metrics.decoded(Duration::from_millis(5));
+        metrics.set_render_drops(7);
+        let stats = StatsSnapshot {
+            fps: 59.5,
+            latency_ms: Some(25.0),
+            links: vec![LinkRate { label: "eth\"0\"".to_string(), bytes_per_sec: 1000.0 }],
+            corrupt_frames: 2,
+            resyncs: 1,
+            ..Default::default()
+        };
+
+        let text = render(&stats, &metrics, true);
+        for line in [
+            "ipdisp_connected 1",
+            "ipdisp_frames_per_second 59.5",
+            "ipdisp_receive_bits_per_second{link=\"eth\\\"0\\\"\"} 8000",
+            "ipdisp_latency_seconds 0.025",
+            "ipdisp_decode_seconds_sum 0.008",
+            "ipdisp_decode_seconds_count 2",
+            "ipdisp_reconnects_total 1",
+            "ipdisp_render_queue_drops_total 7",
+            "ipdisp_corrupt_frames_total 2",
+            "ipdisp_resyncs_total 1",
+            "# TYPE ipdisp_reconnects_total counter",
+        ] {
+            assert!(text.lines().any(|candidate| candidate == line), "missing {:?} in\n{}", line, text);
+        }
+        // Pacing was off and the clocks were never synchronised
+        assert!(!text.contains("paced_drops") && !text.contains("round_trip"));
+    }
+
+    #[tokio::test]
+    async fn test_endpoint() {
+        let (tasks, shutdown) = (TaskTracker::new(), CancellationToken::new());
+        let hub = ip_display_client::stats::StatsHub::new();
+        hub.publish(StatsSnapshot { fps: 30.0, ..Default::default() });
+        let state = Arc::new(RwLock::new(AppState::default()));
+        let addr = start(0, Arc::default(), hub.subscribe(), state, &tokio::runtime::Handle::current(), &tasks, &shutdown)
+            .unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", addr.port())).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP ipdisp_connected"));
+        assert!(response.contains("\nipdisp_connected 0\n"));
+        assert!(response.contains("\nipdisp_frames_per_second 30\n"));
+
+        let mut stream = TcpStream::connect(("127.0.0.1", addr.port())).await.unwrap();
+        stream.write_all(b"POST /metrics HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"));
+
+        shutdown.cancel();
+        tasks.close();
+        tasks.wait().await;
+    }
+}
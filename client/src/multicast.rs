@@ -0,0 +1,566 @@
+// IP Display Client - Multicast Streaming
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! One stream for many windows over UDP multicast. A client connected to
+//! a server with `--multicast-relay GROUP:PORT` sends every frame it shows
+//! on to the group, and any number of clients show it with `--multicast
+//! GROUP:PORT` (`multicast://GROUP:PORT`), without the server or the
+//! network carrying a copy per viewer.
+//!
+//! Each frame goes out as its packet would over TCP, always with a full
+//! header, cut into datagrams that each start with a `FRAGMENT_HEADER_SIZE`
+//! header (big endian): "IPDM", the frame's sequence number (u32), and the
+//! fragment's index and the fragment count (u16 each). A receiver puts
+//! together the newest frame; one that loses a fragment is dropped once
+//! the next frame starts arriving. There is no retransmission, so a lossy
+//! network shows compressed streams better than raw ones, which take
+//! thousands of datagrams a frame.
+//!
+//! `--multicast-fec PERCENT` on the relay adds Reed–Solomon parity (see
+//! `fec`) so viewers can make up for lost datagrams. The group has no
+//! handshake to negotiate it in, so those datagrams say how much they
+//! carry instead, in a `FEC_HEADER_SIZE` header starting "IPDF": after the
+//! sequence number, index and data fragment count come the data
+//! fragments per group (u8) and parity fragments per group (u8), two
+//! reserved bytes and the packet's length (u32). Each group's parity
+//! fragments follow all the data fragments, group by group, and a frame
+//! is complete once every group has as many fragments as it has data.
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::net::UdpSocket;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::fec;
+use crate::protocol::{Command, FrameData, PacketHeader};
+use crate::source::{DisplaySource, SourceFuture, SourceStats};
+use crate::AppState;
+
+const FRAGMENT_MAGIC: u32 = 0x4950444d; // "IPDM"
+pub const FRAGMENT_HEADER_SIZE: usize = 12;
+const FEC_MAGIC: u32 = 0x49504446; // "IPDF"
+pub const FEC_HEADER_SIZE: usize = 20;
+
+/// Packet bytes in a datagram, leaving room for the IPv6 and UDP headers
+/// in a 1500-byte MTU
+pub const MAX_FRAGMENT: usize = 1400;
+
+/// Socket buffers big enough to hold a few compressed frames
+const SOCKET_BUFFER: usize = 4 << 20;
+
+/// Hops a relayed datagram may take; enough for a routed campus network
+const RELAY_TTL: u32 = 8;
+
+/// Data fragments sharing parity fragments with `--multicast-fec`; a
+/// frame of up to this many takes `PERCENT` of it in parity
+const FEC_GROUP: usize = 20;
+
+/// How a frame's fragments are laid out
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Layout {
+    /// Data fragments
+    count: usize,
+    /// Data fragments per group
+    group: usize,
+    /// Parity fragments per group, none without FEC
+    parity: usize,
+    /// Packet bytes, which rebuilt fragments pad out to a whole fragment
+    length: usize,
+}
+
+impl Layout {
+    fn new(count: usize, parity: usize, length: usize) -> Self {
+        match parity {
+            0 => Self { count, group: count, parity, length },
+            _ => Self { count, group: FEC_GROUP, parity, length },
+        }
+    }
+
+    fn groups(&self) -> usize {
+        self.count.div_ceil(self.group)
+    }
+
+    /// Data and parity fragments
+    fn total(&self) -> usize {
+        self.count + self.groups() * self.parity
+    }
+
+    fn group_of(&self, index: usize) -> usize {
+        match index.checked_sub(self.count) {
+            None => index / self.group,
+            Some(parity) => parity / self.parity,
+        }
+    }
+
+    fn data(&self, group: usize) -> Range<usize> {
+        group * self.group..((group + 1) * self.group).min(self.count)
+    }
+
+    fn parity(&self, group: usize) -> Range<usize> {
+        let start = self.count + group * self.parity;
+        start..start + self.parity
+    }
+
+    fn header(&self, sequence: u32, index: usize) -> Vec<u8> {
+        let mut header = Vec::with_capacity(FEC_HEADER_SIZE + MAX_FRAGMENT);
+        let magic = if self.parity == 0 { FRAGMENT_MAGIC } else { FEC_MAGIC };
+        header.extend_from_slice(&magic.to_be_bytes());
+        header.extend_from_slice(&sequence.to_be_bytes());
+        header.extend_from_slice(&(index as u16).to_be_bytes());
+        header.extend_from_slice(&(self.count as u16).to_be_bytes());
+        if self.parity > 0 {
+            header.extend_from_slice(&[self.group as u8, self.parity as u8, 0, 0]);
+            header.extend_from_slice(&(self.length as u32).to_be_bytes());
+        }
+        header
+    }
+
+    /// The frame sequence number, fragment index, layout and fragment of a
+    /// datagram
+    fn parse(datagram: &[u8]) -> Result<(u32, usize, Self, &[u8])> {
+        let magic = datagram.get(..4).map(|magic| u32::from_be_bytes(magic.try_into().unwrap()));
+        let size = match magic {
+            Some(FRAGMENT_MAGIC) => FRAGMENT_HEADER_SIZE,
+            Some(FEC_MAGIC) => FEC_HEADER_SIZE,
+            Some(magic) => return Err(anyhow::anyhow!("Not an IP Display datagram: 0x{:08x}", magic)),
+            None => FRAGMENT_HEADER_SIZE,
+        };
+        let (header, piece) = datagram
+            .split_at_checked(size)
+            .ok_or_else(|| anyhow::anyhow!("Datagram too short: {} bytes", datagram.len()))?;
+        let word = |at: usize| u32::from_be_bytes(header[at..at + 4].try_into().unwrap());
+        let half = |at: usize| u16::from_be_bytes(header[at..at + 2].try_into().unwrap()) as usize;
+        let (sequence, index, count) = (word(4), half(8), half(10));
+        if count == 0 {
+            return Err(anyhow::anyhow!("Frame {} has no fragments", sequence));
+        }
+
+        let layout = match size {
+            FEC_HEADER_SIZE => {
+                let (group, parity) = (header[12] as usize, header[13] as usize);
+                if !(1..=fec::MAX_DATA).contains(&group) || !(1..=fec::MAX_PARITY).contains(&parity) {
+                    return Err(anyhow::anyhow!("Invalid FEC of {} parity per {} fragments", parity, group));
+                }
+                Self { count, group, parity, length: word(16) as usize }
+            }
+            _ => Self::new(count, 0, count * MAX_FRAGMENT),
+        };
+        if index >= layout.total() || piece.len() > MAX_FRAGMENT || layout.length > count * MAX_FRAGMENT {
+            return Err(anyhow::anyhow!("Invalid fragment {} of {} ({} bytes)", index, layout.total(), piece.len()));
+        }
+        Ok((sequence, index, layout, piece))
+    }
+}
+
+/// Cut `packet` into the datagrams of frame `sequence`, with `parity`
+/// parity fragments per `FEC_GROUP` data fragments
+pub fn fragment(sequence: u32, packet: &[u8], parity: usize) -> Result<Vec<Vec<u8>>> {
+    let pieces: Vec<&[u8]> = match packet {
+        [] => vec![packet],
+        _ => packet.chunks(MAX_FRAGMENT).collect(),
+    };
+    let layout = Layout::new(pieces.len(), parity.min(fec::MAX_PARITY), packet.len());
+    if layout.total() > u16::MAX as usize {
+        return Err(anyhow::anyhow!("Packet of {} bytes is too big to multicast", packet.len()));
+    }
+
+    let parity: Vec<Vec<u8>> = match layout.parity {
+        0 => Vec::new(),
+        _ => (0..layout.groups()).flat_map(|group| fec::encode(&pieces[layout.data(group)], layout.parity)).collect(),
+    };
+    Ok(pieces
+        .into_iter()
+        .chain(parity.iter().map(Vec::as_slice))
+        .enumerate()
+        .map(|(index, piece)| {
+            let mut datagram = layout.header(sequence, index);
+            datagram.extend_from_slice(piece);
+            datagram
+        })
+        .collect())
+}
+
+/// Puts the newest frame back together from its datagrams
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    sequence: Option<u32>,
+    layout: Layout,
+    pieces: Vec<Option<Vec<u8>>>,
+    /// Fragments in for each group
+    arrived: Vec<usize>,
+    received: usize,
+    complete: bool,
+    /// Frames given up on with fragments missing
+    lost: u64,
+    /// Frames rebuilt from parity fragments
+    recovered: u64,
+}
+
+impl Reassembler {
+    /// Take one datagram; returns the packet once enough of its fragments
+    /// are in
+    pub fn push(&mut self, datagram: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (sequence, index, layout, piece) = Layout::parse(datagram)?;
+        match self.sequence {
+            Some(current) if current == sequence => {
+                if layout != self.layout {
+                    return Err(anyhow::anyhow!("Fragment layout changed within frame {}", sequence));
+                }
+            }
+            // Sequence numbers wrap, so newer is anything up to half the
+            // range ahead
+            Some(current) if sequence.wrapping_sub(current) > u32::MAX / 2 => {
+                debug!("Dropping late fragment of frame {}", sequence);
+                return Ok(None);
+            }
+            _ => {
+                if self.received > 0 && !self.complete {
+                    self.lost += 1;
+                    debug!("Frame {:?} lost {} fragments", self.sequence, self.pieces.len() - self.received);
+                }
+                self.sequence = Some(sequence);
+                self.layout = layout;
+                self.pieces = vec![None; layout.total()];
+                self.arrived = vec![0; layout.groups()];
+                self.received = 0;
+                self.complete = false;
+            }
+        }
+
+        let slot = &mut self.pieces[index];
+        if slot.is_some() || self.complete {
+            return Ok(None);
+        }
+        *slot = Some(piece.to_vec());
+        self.received += 1;
+        self.arrived[layout.group_of(index)] += 1;
+        if (0..layout.groups()).any(|group| self.arrived[group] < layout.data(group).len()) {
+            return Ok(None);
+        }
+
+        self.complete = true;
+        let mut packet = Vec::with_capacity(layout.length);
+        let mut rebuilt = false;
+        for group in 0..layout.groups() {
+            let data = layout.data(group);
+            let mut shards: Vec<Option<Vec<u8>>> =
+                data.clone().chain(layout.parity(group)).map(|index| self.pieces[index].take()).collect();
+            if shards[..data.len()].iter().any(Option::is_none) {
+                fec::recover(&mut shards, data.len())?;
+                rebuilt = true;
+            }
+            packet.extend(shards.into_iter().take(data.len()).flatten().flatten());
+        }
+        if rebuilt {
+            self.recovered += 1;
+            debug!("Frame {} rebuilt from parity fragments", sequence);
+        }
+        packet.truncate(layout.length);
+        Ok(Some(packet))
+    }
+
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+}
+
+/// Sends the frames a window shows on to a multicast group
+pub struct MulticastSender {
+    socket: UdpSocket,
+    group: SocketAddr,
+    sequence: AtomicU32,
+    /// Parity fragments per `FEC_GROUP` data fragments
+    parity: usize,
+}
+
+impl MulticastSender {
+    /// Send to `group`, adding `fec` percent of parity fragments
+    pub async fn bind(group: SocketAddr, fec: u8) -> Result<Self> {
+        let local: SocketAddr = match group {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_send_buffer_size(SOCKET_BUFFER)?;
+        match group {
+            SocketAddr::V4(_) => socket.set_multicast_ttl_v4(RELAY_TTL)?,
+            SocketAddr::V6(_) => socket.set_multicast_hops_v6(RELAY_TTL)?,
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&local.into())?;
+        let socket = UdpSocket::from_std(socket.into())?;
+        let parity = (FEC_GROUP * fec as usize).div_ceil(100);
+        info!("Relaying frames to {} with {} parity fragments per {}", group, parity, FEC_GROUP);
+        Ok(Self { socket, group, sequence: AtomicU32::new(0), parity })
+    }
+
+    /// Send `frame` as one sequence of datagrams
+    pub async fn send(&self, frame: &FrameData) -> Result<()> {
+        let mut packet = frame.header.to_bytes();
+        packet.extend_from_slice(&frame.data);
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        for datagram in fragment(sequence, &packet, self.parity)? {
+            self.socket.send_to(&datagram, self.group).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Shows the frames sent to a multicast group, or to a unicast address and
+/// port of this machine
+pub struct MulticastSource {
+    group: SocketAddr,
+    state: Arc<RwLock<AppState>>,
+    receiver: Mutex<Option<(UdpSocket, Reassembler)>>,
+    stats: StdMutex<SourceStats>,
+}
+
+impl MulticastSource {
+    pub fn new(group: SocketAddr, state: Arc<RwLock<AppState>>) -> Self {
+        Self { group, state, receiver: Mutex::default(), stats: StdMutex::default() }
+    }
+
+    /// Bind the group's port, shared with other viewers on this machine,
+    /// and join the group on the `--bind-address` interface, if it is IPv4
+    async fn join(&self) -> Result<UdpSocket> {
+        let socket = Socket::new(Domain::for_address(self.group), Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_recv_buffer_size(SOCKET_BUFFER)?;
+        socket.set_nonblocking(true)?;
+        let ip = self.group.ip();
+        let local: IpAddr = match ip {
+            _ if ip.is_multicast() => ip,
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        socket.bind(&SocketAddr::new(local, self.group.port()).into())
+            .with_context(|| format!("Failed to bind {}", self.group))?;
+
+        match ip {
+            IpAddr::V4(group) if group.is_multicast() => {
+                let interface = match self.state.read().await.bind_address {
+                    Some(IpAddr::V4(address)) => address,
+                    _ => Ipv4Addr::UNSPECIFIED,
+                };
+                socket.join_multicast_v4(&group, &interface)
+                    .with_context(|| format!("Failed to join {}", group))?;
+            }
+            IpAddr::V6(group) if group.is_multicast() => {
+                socket.join_multicast_v6(&group, 0).with_context(|| format!("Failed to join {}", group))?;
+            }
+            _ => {}
+        }
+        Ok(UdpSocket::from_std(socket.into())?)
+    }
+
+    /// The frame in a reassembled packet, if it is one
+    async fn unpack(&self, mut packet: Vec<u8>) -> Result<Option<FrameData>> {
+        let header = PacketHeader::from_bytes(&packet)?;
+        let payload = packet.split_off(header.to_bytes().len());
+        header.validate()?;
+        if !header.is_frame_packet() {
+            return Ok(None);
+        }
+        let frame = FrameData::new(header, payload)?;
+        if !frame.checksum_ok() {
+            return Err(anyhow::anyhow!("Frame {} failed its CRC-32 check", frame.header.timestamp));
+        }
+        frame.validate()?;
+
+        let mut state = self.state.write().await;
+        state.display_width = frame.header.width;
+        state.display_height = frame.header.height;
+        Ok(Some(frame))
+    }
+}
+
+impl DisplaySource for MulticastSource {
+    fn name(&self) -> &'static str {
+        "multicast"
+    }
+
+    fn connect(&self) -> SourceFuture<'_, ()> {
+        Box::pin(async move {
+            let socket = self.join().await?;
+            info!("Receiving frames sent to {}", self.group);
+            *self.receiver.lock().await = Some((socket, Reassembler::default()));
+            self.state.write().await.connected = true;
+            self.stats.lock().unwrap().connections += 1;
+            Ok(())
+        })
+    }
+
+    fn next_frame(&self) -> SourceFuture<'_, Option<FrameData>> {
+        Box::pin(async move {
+            let mut receiver = self.receiver.lock().await;
+            let (socket, reassembler) = receiver
+                .as_mut()
+                .ok_or_else(|| anyhow::anyhow!("Not receiving from {}", self.group))?;
+            let mut datagram = vec![0u8; FEC_HEADER_SIZE + MAX_FRAGMENT];
+            loop {
+                let (length, from) = socket.recv_from(&mut datagram).await?;
+                let packet = match reassembler.push(&datagram[..length]) {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        debug!("Ignoring datagram from {}: {}", from, e);
+                        continue;
+                    }
+                };
+                match self.unpack(packet).await {
+                    Ok(Some(frame)) => {
+                        self.stats.lock().unwrap().frames += 1;
+                        return Ok(Some(frame));
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("Skipping multicast frame from {}: {}", from, e),
+                }
+            }
+        })
+    }
+
+    fn send_input<'a>(&'a self, command: &'a Command) -> SourceFuture<'a, ()> {
+        debug!("Multicast streams don't take {:?}", command.packet_type());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn stats(&self) -> SourceStats {
+        *self.stats.lock().unwrap()
+    }
+
+    fn close(&self) -> SourceFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some((_, reassembler)) = self.receiver.lock().await.take() {
+                info!(
+                    "Left {} after {} frames, {} lost, {} rebuilt",
+                    self.group,
+                    self.stats().frames,
+                    reassembler.lost(),
+                    reassembler.recovered()
+                );
+                self.state.write().await.connected = false;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::FrameFormat;
+
+    #[test]
+    fn test_reassembler() {
+        let packet: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        let datagrams = fragment(7, &packet, 0).unwrap();
+        assert_eq!(datagrams.len(), 3);
+        assert_eq!(datagrams[2].len(), FRAGMENT_HEADER_SIZE + 200);
+
+        // Out of order and duplicated
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&datagrams[2]).unwrap(), None);
+        assert_eq!(reassembler.push(&datagrams[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&datagrams[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&datagrams[1]).unwrap().unwrap(), packet);
+        assert_eq!(reassembler.push(&datagrams[1]).unwrap(), None);
+
+        // A frame missing a fragment gives way to the next; late ones are
+        // ignored
+        let next = fragment(8, &[1, 2, 3], 0).unwrap();
+        let after = fragment(9, &[4], 0).unwrap();
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(&datagrams[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&next[0]).unwrap().unwrap(), [1, 2, 3]);
+        assert_eq!(reassembler.push(&datagrams[1]).unwrap(), None);
+        assert_eq!(reassembler.lost(), 1);
+        assert_eq!(reassembler.push(&after[0]).unwrap().unwrap(), [4]);
+
+        // Sequence numbers wrap
+        let wrapped = fragment(0, &[5], 0).unwrap();
+        let mut reassembler = Reassembler::default();
+        reassembler.push(&fragment(u32::MAX, &[6], 0).unwrap()[0]).unwrap();
+        assert_eq!(reassembler.push(&wrapped[0]).unwrap().unwrap(), [5]);
+
+        assert!(reassembler.push(&[0; 4]).is_err());
+        assert!(reassembler.push(b"IPDSxxxxxxxxxx").is_err());
+        let mut bad = next[0].clone();
+        bad[8..10].copy_from_slice(&5u16.to_be_bytes());
+        assert!(reassembler.push(&bad).is_err());
+        assert!(fragment(0, &vec![0; MAX_FRAGMENT * 65536], 0).is_err());
+    }
+
+    #[test]
+    fn test_reassembler_fec() {
+        // 45 fragments in groups of 20, 20 and 5, each with two parity
+        let packet: Vec<u8> = (0..MAX_FRAGMENT as u32 * 44 + 300).map(|i| (i * 7) as u8).collect();
+        let datagrams = fragment(3, &packet, 2).unwrap();
+        assert_eq!(datagrams.len(), 45 + 3 * 2);
+        assert_eq!(datagrams[44].len(), FEC_HEADER_SIZE + 300);
+        assert_eq!(datagrams[50].len(), FEC_HEADER_SIZE + MAX_FRAGMENT);
+
+        // Two lost from the first group and one from the last, with the
+        // last group's first parity fragment
+        let mut reassembler = Reassembler::default();
+        let mut packets = Vec::new();
+        for (index, datagram) in datagrams.iter().enumerate() {
+            if ![0, 7, 44, 49].contains(&index) {
+                packets.extend(reassembler.push(datagram).unwrap());
+            }
+        }
+        assert_eq!(packets, vec![packet]);
+        assert_eq!((reassembler.lost(), reassembler.recovered()), (0, 1));
+
+        // Three from one group is too many
+        let mut reassembler = Reassembler::default();
+        for (index, datagram) in datagrams.iter().enumerate() {
+            if ![1, 2, 3].contains(&index) {
+                assert_eq!(reassembler.push(datagram).unwrap(), None);
+            }
+        }
+        reassembler.push(&fragment(4, &[1], 2).unwrap()[0]).unwrap();
+        assert_eq!(reassembler.lost(), 1);
+
+        let mut bad = datagrams[0].clone();
+        bad[13] = 0;
+        assert!(reassembler.push(&bad).is_err());
+        assert!(reassembler.push(&datagrams[0][..FRAGMENT_HEADER_SIZE]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_multicast_source() {
+        // Unicast on loopback, which works without a multicast route
+        let probe = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = probe.local_addr().unwrap();
+        drop(probe);
+
+        let state = Arc::new(RwLock::new(AppState::default()));
+        let source = MulticastSource::new(address, Arc::clone(&state));
+        source.connect().await.unwrap();
+        assert!(state.read().await.connected);
+
+        let sender = MulticastSender::bind(address, 0).await.unwrap();
+        let header = PacketHeader::new(40, 20, FrameFormat::Rgba32, 3200);
+        let frame = FrameData::new(header, vec![9u8; 3200]).unwrap();
+        sender.send(&frame).await.unwrap();
+
+        let received = source.next_frame().await.unwrap().unwrap();
+        assert_eq!(received.header.timestamp, frame.header.timestamp);
+        assert_eq!(&received.data[..], &frame.data[..]);
+        assert_eq!(state.read().await.display_width, 40);
+        assert_eq!(source.stats(), SourceStats { connections: 1, frames: 1 });
+
+        source.close().await.unwrap();
+        assert!(!state.read().await.connected);
+    }
+}
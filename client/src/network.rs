@@ -3,88 +3,953 @@
 // Licensed under MIT
 
 use anyhow::Result;
+use clap::ValueEnum;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex as StdMutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use sha2::{Digest, Sha256};
 use tracing::{debug, info, warn, error};
+use ipdisp_core::HEARTBEAT_INTERVAL;
+#[cfg(feature = "websocket")]
+use ipdisp_transports::websocket::{self, WsReader, WsWriter};
+#[cfg(feature = "quic")]
+use ipdisp_transports::quic::{self, QuicReader, QuicWriter};
 
-use crate::protocol::{PacketHeader, FrameData, HEADER_SIZE};
+use crate::audio_output::SharedAudio;
+use crate::buffer_pool::{BufferPool, DEFAULT_POOL_SIZE};
+use crate::damage::DamageCanvas;
+use crate::control::RESUME_CHECK_INTERVAL;
+use crate::source::{DisplaySource, SourceFuture, SourceStats};
+use crate::pacing::PacingPreference;
+use crate::auth::{self, AuthProvider, SignInPrompt};
+use crate::pairing::{self, PairPrompt, Pairing};
+use crate::protocol_log::ProtocolLog;
+#[cfg(feature = "recording")]
+use crate::stream_dump::StreamDump;
+use crate::metrics::Metrics;
+use crate::noise::{self, Handshake, NoiseReader, NoiseWriter};
+use crate::hooks::HookEvent;
+use crate::protocol::{
+    find_header, parse_header, AuthPrompt, Capabilities, Command, CompactHeader, ContentHash, Damage, DisplayMode, FileStatus, FrameFormat, PacketHeader, PacketType,
+    FrameData, InputControl, PromptStyle, ServerMessage, SessionMode,
+    COMPACT_HEADER_SIZE, RESUME_TOKEN_SIZE,
+    HEADER_SIZE, MAX_AUDIO_SIZE, MAX_CONTROL_SIZE, Resync,
+};
+use crate::quality::QualityLimits;
+use crate::stats::ThroughputMeter;
+use crate::timesync::{self, ClockEstimate, ClockSync};
+use crate::usage::{BudgetLevel, Period, BUDGET_RECHECK_INTERVAL};
 use crate::AppState;
 
+/// First retry delay after losing the server; doubles up to the maximum
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Longest retry delay in kiosk mode, so signage is back soon after the
+/// server
+pub const KIOSK_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long closing the window waits for links to say goodbye
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Contacts to ask for on the server's virtual touchscreen (GDK doesn't
+/// report how many the local panel tracks)
+pub const TOUCH_SLOTS: u32 = 10;
+
+/// How the server spreads frames over aggregated links
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LinkMode {
+    /// Everything goes over the first live link; the other is a standby
+    #[default]
+    Failover = 0,
+    /// Frames alternate between live links
+    Stripe = 1,
+}
+
+/// How IPDS packets are carried to and from the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Transport {
+    /// Straight over TCP
+    #[default]
+    Tcp,
+    /// In binary WebSocket messages, for servers behind HTTP-only proxies
+    #[cfg(feature = "websocket")]
+    Ws,
+    /// Over QUIC, for lossy links and addresses that change; the kernel
+    /// module doesn't speak it, `ipdisp-server --quic` does
+    #[cfg(feature = "quic")]
+    Quic,
+}
+
+/// What to do with a frame larger than the display the server announced,
+/// when no new display info came ahead of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ModeChange {
+    /// Drop the connection; the server announces its display again on
+    /// reconnect. Servers send display info before frames at a new size,
+    /// so a larger frame usually means the stream has lost its place
+    #[default]
+    Reconnect,
+    /// Take the frame's size as the new display size
+    Follow,
+    /// Discard the frame unread and keep the last one up
+    Skip,
+}
+
+/// Read side of a connection, whichever transport it uses
+#[derive(Debug)]
+enum LinkReader {
+    Tcp(OwnedReadHalf),
+    #[cfg(feature = "websocket")]
+    WebSocket(WsReader<OwnedReadHalf>),
+    #[cfg(feature = "quic")]
+    Quic(QuicReader),
+    /// Any of the above once the Noise handshake is done
+    Noise(Box<NoiseReader<LinkReader>>),
+}
+
+impl AsyncRead for LinkReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LinkReader::Tcp(reader) => Pin::new(reader).poll_read(cx, buf),
+            #[cfg(feature = "websocket")]
+            LinkReader::WebSocket(reader) => Pin::new(reader).poll_read(cx, buf),
+            #[cfg(feature = "quic")]
+            LinkReader::Quic(reader) => Pin::new(reader).poll_read(cx, buf),
+            LinkReader::Noise(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Bytes handed back are read again before the stream, as the header a
+/// resync read ahead to find
+#[derive(Debug)]
+struct Pushback<R> {
+    inner: R,
+    unread: Vec<u8>,
+}
+
+impl<R> Pushback<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, unread: Vec::new() }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Pushback<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.unread.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = this.unread.len().min(buf.remaining());
+        buf.put_slice(&this.unread[..n]);
+        this.unread.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Scan past `garbage`, a header that made no sense, to the next full
+/// header, which is handed back to be read again; returns how many bytes
+/// were skipped
+async fn skip_to_header<R: AsyncRead + Unpin>(stream: &mut Pushback<R>, garbage: &[u8]) -> io::Result<usize> {
+    let mut window = garbage.get(1..).unwrap_or_default().to_vec();
+    let mut skipped = garbage.len() - window.len();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let start = match find_header(&window) {
+            Resync::Found(start) => {
+                window.drain(..start);
+                window.append(&mut stream.unread);
+                stream.unread = window;
+                return Ok(skipped + start);
+            }
+            Resync::NeedMore(start) => start,
+        };
+        window.drain(..start);
+        skipped += start;
+        
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        window.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Write side of a connection, whichever transport it uses
+#[derive(Debug)]
+enum LinkWriter {
+    Tcp(OwnedWriteHalf),
+    #[cfg(feature = "websocket")]
+    WebSocket(WsWriter<OwnedWriteHalf>),
+    #[cfg(feature = "quic")]
+    Quic(QuicWriter),
+    /// Any of the above once the Noise handshake is done
+    Noise(Box<NoiseWriter<LinkWriter>>),
+}
+
+impl AsyncWrite for LinkWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            LinkWriter::Tcp(writer) => Pin::new(writer).poll_write(cx, buf),
+            #[cfg(feature = "websocket")]
+            LinkWriter::WebSocket(writer) => Pin::new(writer).poll_write(cx, buf),
+            #[cfg(feature = "quic")]
+            LinkWriter::Quic(writer) => Pin::new(writer).poll_write(cx, buf),
+            LinkWriter::Noise(writer) => Pin::new(writer).poll_write(cx, buf),
+        }
+    }
+    
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LinkWriter::Tcp(writer) => Pin::new(writer).poll_flush(cx),
+            #[cfg(feature = "websocket")]
+            LinkWriter::WebSocket(writer) => Pin::new(writer).poll_flush(cx),
+            #[cfg(feature = "quic")]
+            LinkWriter::Quic(writer) => Pin::new(writer).poll_flush(cx),
+            LinkWriter::Noise(writer) => Pin::new(writer).poll_flush(cx),
+        }
+    }
+    
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LinkWriter::Tcp(writer) => Pin::new(writer).poll_shutdown(cx),
+            #[cfg(feature = "websocket")]
+            LinkWriter::WebSocket(writer) => Pin::new(writer).poll_shutdown(cx),
+            #[cfg(feature = "quic")]
+            LinkWriter::Quic(writer) => Pin::new(writer).poll_shutdown(cx),
+            LinkWriter::Noise(writer) => Pin::new(writer).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Where one connection to the server leaves this machine from
+#[derive(Debug, Clone, Default)]
+pub struct LinkPath {
+    /// Position in `AppState::links`
+    pub index: usize,
+    pub interface: Option<String>,
+    pub address: Option<IpAddr>,
+}
+
+/// Reordering between links is never more than this; a bigger step back
+/// means the server's clock restarted
+const REORDER_WINDOW_NS: u64 = 1_000_000_000;
+
+/// Merges frames arriving over several links, dropping any that show up
+/// after a newer one has already been passed on
+#[derive(Debug, Default)]
+pub struct LinkMerger {
+    newest: AtomicU64,
+}
+
+impl LinkMerger {
+    /// Whether a frame stamped `timestamp` should be shown
+    pub fn accept(&self, timestamp: u64) -> bool {
+        let newest = self.newest.fetch_max(timestamp, Ordering::AcqRel);
+        if timestamp >= newest {
+            return true;
+        }
+        
+        if newest - timestamp > REORDER_WINDOW_NS {
+            self.newest.store(timestamp, Ordering::Release);
+            return true;
+        }
+        
+        false
+    }
+}
+
+/// How long a connection attempt gets before the next address is tried
+/// alongside it (RFC 8305's recommended Connection Attempt Delay)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// `host:port` as `lookup_host` and HTTP expect it, bracketing IPv6
+/// literals; `host` may already be bracketed
+pub fn server_address(host: &str, port: u16) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.parse::<std::net::Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Split a `server_address` back into host and port
+pub fn parse_server_address(addr: &str) -> Option<(String, u16)> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+/// Who holds input control, as the status bar says it: "you", "nobody"
+/// or the holder's address
+pub fn control_holder(control: &InputControl) -> String {
+    match (control.yours, control.owner) {
+        (true, _) => "you".to_string(),
+        (false, 0) => "nobody".to_string(),
+        (false, _) => Ipv4Addr::from(control.address).to_string(),
+    }
+}
+
+/// Alternate address families, starting with whichever the resolver put
+/// first, so a broken IPv6 (or IPv4) path costs one attempt delay rather
+/// than a timeout per address
+fn interleave_families(targets: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = targets.first() else { return targets };
+    let first_v4 = first.is_ipv4();
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) =
+        targets.into_iter().partition(|target| target.is_ipv4() == first_v4);
+    
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        ordered.extend(preferred.pop_front());
+        ordered.extend(other.pop_front());
+    }
+    ordered
+}
+
+/// Happy Eyeballs: try `targets` in order, starting the next one whenever
+/// the last has been pending for `CONNECTION_ATTEMPT_DELAY` or has failed,
+/// and keep the first connection to complete
+async fn connect_any(
+    targets: Vec<SocketAddr>,
+    socket_for: impl Fn(SocketAddr) -> Result<TcpSocket>,
+) -> Result<TcpStream> {
+    let mut pending = targets.into_iter().peekable();
+    // Dropping the set abandons the attempts still in flight
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    
+    loop {
+        if let Some(target) = pending.next() {
+            match socket_for(target) {
+                Ok(socket) => {
+                    attempts.spawn(async move { (target, socket.connect(target).await) });
+                }
+                Err(e) => {
+                    last_error = Some(e);
+                    continue;
+                }
+            }
+        }
+        
+        let finished = if pending.peek().is_some() {
+            match tokio::time::timeout(CONNECTION_ATTEMPT_DELAY, attempts.join_next()).await {
+                Ok(finished) => finished,
+                Err(_) => continue,
+            }
+        } else {
+            attempts.join_next().await
+        };
+        
+        match finished {
+            Some(Ok((_, Ok(stream)))) => return Ok(stream),
+            Some(Ok((target, Err(e)))) => {
+                debug!("Connecting to {} failed: {}", target, e);
+                last_error = Some(e.into());
+            }
+            Some(Err(e)) => last_error = Some(e.into()),
+            None => break,
+        }
+    }
+    
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No address to connect to")))
+}
+
+/// Count received bytes against this cycle's usage, warning once as the
+/// budget runs low and again when it runs out
+fn record_usage(state: &mut AppState, bytes: u64) {
+    let cycle_day = state.budget.map_or(1, |budget| budget.cycle_day);
+    let period = Period::containing(SystemTime::now(), cycle_day);
+    let used = state.usage.add(&state.usage_account, period, bytes);
+    
+    let Some(budget) = state.budget else { return };
+    let level = budget.level(used);
+    if level == BudgetLevel::Under || state.budget_notice >= Some((period, level)) {
+        return;
+    }
+    state.budget_notice = Some((period, level));
+    let (used_mb, limit_mb) = (used / 1_000_000, budget.limit_bytes / 1_000_000);
+    match level {
+        BudgetLevel::Nearly => warn!("{} of {} MB bandwidth budget used for {}", used_mb, limit_mb, period),
+        _ => warn!("Bandwidth budget of {} MB used up for {}", limit_mb, period),
+    }
+}
+
+/// State and receive statistics for one network path to the server
+#[derive(Debug, Clone)]
+pub struct LinkStats {
+    /// Interface name and/or local address the path leaves from
+    pub label: String,
+    pub connected: bool,
+    /// This path's offset to the server's clock, which its own Ping/Pong
+    /// exchanges keep up to date
+    pub clock: Option<ClockEstimate>,
+    pub rx: ThroughputMeter,
+}
+
+impl LinkStats {
+    fn new(label: String) -> Self {
+        Self { label, connected: false, clock: None, rx: ThroughputMeter::new() }
+    }
+}
+
+// The socket is split so commands can be written while the receive loop
+// is parked in read_exact waiting for the next frame.
 #[derive(Debug, Clone)]
 pub struct NetworkClient {
     state: Arc<RwLock<AppState>>,
-    connection: Arc<RwLock<Option<TcpStream>>>,
+    reader: Arc<Mutex<Option<Pushback<LinkReader>>>>,
+    writer: Arc<Mutex<Option<LinkWriter>>>,
+    buffers: BufferPool,
+    clock: Arc<StdMutex<ClockSync>>,
+    /// Header of the last frame received, which compact headers build on
+    previous_frame: Arc<StdMutex<Option<PacketHeader>>>,
+    /// Display size from the server's last info packet on this connection
+    mode: Arc<StdMutex<Option<DisplayMode>>>,
+    /// Last whole frame, which DAMAGE packets patch
+    canvas: Arc<StdMutex<DamageCanvas>>,
+    /// Signed hash the server sent for the frame that follows it
+    pending_hash: Arc<StdMutex<Option<ContentHash>>>,
+    last_sent: Arc<StdMutex<Instant>>,
+    link: Arc<LinkPath>,
+    /// Set when the user asked to pair; asks the UI for the server's code
+    pair_prompts: Option<UnboundedSender<PairPrompt>>,
+    /// Where a server's sign-in questions go, for servers that ask
+    sign_in_prompts: Option<UnboundedSender<SignInPrompt>>,
+    /// Server replies worth showing in the status bar
+    status_messages: Option<UnboundedSender<String>>,
+    /// The server's progress on uploads, for the uploader
+    file_statuses: Option<UnboundedSender<FileStatus>>,
+    /// Plays the server's sound, when asked for
+    audio: Option<SharedAudio>,
+    /// Where headers and control messages are recorded for bug reports
+    protocol_log: Option<Arc<ProtocolLog>>,
+    /// Where every packet is recorded as received, for `--play`
+    #[cfg(feature = "recording")]
+    stream_dump: Option<Arc<StreamDump>>,
+    /// Counters for `--metrics-port`
+    metrics: Option<Arc<Metrics>>,
+    /// Set once the `error` hook has run, until the next connect
+    failing: Arc<AtomicBool>,
+    /// Where `next_frame` is up to with reconnecting
+    reconnect: Arc<StdMutex<Reconnect>>,
+    /// Offered when reconnecting to the same server
+    resume_token: Arc<StdMutex<Option<ResumeToken>>>,
+    /// The last CAPABILITIES exactly as received, for the Noise prologue
+    capabilities_packet: Arc<StdMutex<Vec<u8>>>,
+    source_stats: Arc<StdMutex<SourceStats>>,
+}
+
+/// Server address and the token it gave a link's session
+type ResumeToken = (String, [u8; RESUME_TOKEN_SIZE]);
+
+#[derive(Debug)]
+struct Reconnect {
+    /// Server generation last connected to; it changes when the user
+    /// picks another server
+    generation: u64,
+    /// Wait before the next attempt
+    delay: Duration,
 }
 
 impl NetworkClient {
-    pub async fn new(state: Arc<RwLock<AppState>>) -> Result<Self> {
+    pub fn new(state: Arc<RwLock<AppState>>, link: LinkPath) -> Result<Self> {
         Ok(Self {
             state,
-            connection: Arc::new(RwLock::new(None)),
+            link: Arc::new(link),
+            reader: Arc::new(Mutex::new(None)),
+            writer: Arc::new(Mutex::new(None)),
+            buffers: BufferPool::new(DEFAULT_POOL_SIZE),
+            clock: Arc::new(StdMutex::new(ClockSync::new())),
+            previous_frame: Arc::new(StdMutex::new(None)),
+            mode: Arc::new(StdMutex::new(None)),
+            canvas: Arc::new(StdMutex::new(DamageCanvas::new())),
+            pending_hash: Arc::new(StdMutex::new(None)),
+            last_sent: Arc::new(StdMutex::new(Instant::now())),
+            pair_prompts: None,
+            sign_in_prompts: None,
+            status_messages: None,
+            file_statuses: None,
+            audio: None,
+            protocol_log: None,
+            #[cfg(feature = "recording")]
+            stream_dump: None,
+            metrics: None,
+            failing: Arc::new(AtomicBool::new(false)),
+            reconnect: Arc::new(StdMutex::new(Reconnect { generation: 0, delay: RECONNECT_DELAY })),
+            resume_token: Arc::default(),
+            capabilities_packet: Arc::default(),
+            source_stats: Arc::default(),
         })
     }
     
+    /// Pair with the server after the first successful connect
+    pub fn with_pair_prompts(mut self, prompts: UnboundedSender<PairPrompt>) -> Self {
+        self.pair_prompts = Some(prompts);
+        self
+    }
+    
+    /// Let servers ask the user to sign in, e.g. with a password
+    pub fn with_sign_in_prompts(mut self, prompts: UnboundedSender<SignInPrompt>) -> Self {
+        self.sign_in_prompts = Some(prompts);
+        self
+    }
+    
+    /// Report the outcome of supervision requests to the UI
+    pub fn with_status_messages(mut self, messages: UnboundedSender<String>) -> Self {
+        self.status_messages = Some(messages);
+        self
+    }
+    
+    /// Pass the server's progress on uploads on
+    pub fn with_file_statuses(mut self, statuses: UnboundedSender<FileStatus>) -> Self {
+        self.file_statuses = Some(statuses);
+        self
+    }
+    
+    /// Ask the server for its sound and play it
+    pub fn with_audio(mut self, audio: SharedAudio) -> Self {
+        self.audio = Some(audio);
+        self
+    }
+    
+    /// Record what goes over this link, minus the pixels
+    pub fn with_protocol_log(mut self, log: Arc<ProtocolLog>) -> Self {
+        self.protocol_log = Some(log);
+        self
+    }
+    
+    /// Record everything received over this link, pixels included
+    #[cfg(feature = "recording")]
+    pub fn with_stream_dump(mut self, dump: Arc<StreamDump>) -> Self {
+        self.stream_dump = Some(dump);
+        self
+    }
+    
+    /// Count reconnects, decoding and dropped frames on this link
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+    
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_deref()
+    }
+    
+    /// Connect and say hello, then run the `connect` hook, or the `error`
+    /// hook if this is the first failure since the last connect
     pub async fn connect(&self, addr: &str) -> Result<()> {
+        let result = self.open(addr).await;
+        match &result {
+            Ok(()) => {
+                self.failing.store(false, Ordering::Relaxed);
+                self.source_stats.lock().unwrap().connections += 1;
+                self.run_hook(HookEvent::Connect, addr, None).await;
+            }
+            Err(e) => self.report_error(addr, e).await,
+        }
+        result
+    }
+    
+    async fn open(&self, addr: &str) -> Result<()> {
         info!("Connecting to {}", addr);
         
-        let stream = TcpStream::connect(addr).await?;
-        debug!("TCP connection established");
+        let transport = self.state.read().await.transport;
+        #[cfg(feature = "quic")]
+        let opened = if transport == Transport::Quic {
+            self.open_quic(addr).await?
+        } else {
+            self.open_tcp(addr, transport).await?
+        };
+        #[cfg(not(feature = "quic"))]
+        let opened = self.open_tcp(addr, transport).await?;
+        let (reader, writer, local_addr) = opened;
         
         // Store connection
-        {
-            let mut conn = self.connection.write().await;
-            *conn = Some(stream);
+        *self.reader.lock().await = Some(Pushback::new(reader));
+        *self.writer.lock().await = Some(writer);
+        if let Some(log) = &self.protocol_log {
+            log.connected(self.link.index, addr);
         }
         
+        // A new server means a new clock, and its first frame has a full
+        // header; it announces its display again
+        *self.clock.lock().unwrap() = ClockSync::new();
+        *self.previous_frame.lock().unwrap() = None;
+        *self.mode.lock().unwrap() = None;
+        self.canvas.lock().unwrap().reset();
+        if let Some(audio) = &self.audio {
+            audio.lock().unwrap().reset();
+        }
+        *self.pending_hash.lock().unwrap() = None;
+        
         // Update state
-        {
+        let (hello, noise_key, heartbeat_timeout) = {
             let mut state = self.state.write().await;
             state.connected = true;
+            state.sync_delay = None;
+            
+            let label = match &self.link.interface {
+                Some(interface) => format!("{} ({})", interface, local_addr.ip()),
+                None => local_addr.ip().to_string(),
+            };
+            let index = self.link.index;
+            if state.links.len() <= index {
+                state.links.resize_with(index + 1, || LinkStats::new(String::new()));
+            }
+            state.links[index] = LinkStats { connected: true, ..LinkStats::new(label) };
+            
+            // Content hashes are signed with our pairing token
+            let mut capabilities = Capabilities::COMPACT_HEADER | Capabilities::FORMAT_ANNOUNCE | Capabilities::DAMAGE;
+            capabilities.set(Capabilities::CRC32, state.checksum);
+            capabilities.set(Capabilities::SYNC, state.pacing == PacingPreference::Sync);
+            capabilities.set(Capabilities::AUDIO, self.audio.is_some());
+            // Frames of an aggregated session come over several links, so
+            // the server can't hold one link to the ACKs
+            capabilities.set(Capabilities::ACK, state.ack && state.session_id == 0);
+            capabilities.set(Capabilities::NOISE, state.noise_key.is_some());
+            // Input only goes over the first link, so only it follows who
+            // holds control
+            capabilities.set(Capabilities::INPUT_CONTROL, self.link.index == 0);
+            // Nor is the user asked to sign in more than once
+            capabilities.set(Capabilities::AUTH_PROMPT, self.sign_in_prompts.is_some() && self.link.index == 0);
+            // The server only resumes a session for the credential it was
+            // proved with, and challenges clients that ask for one
+            let server = server_address(&state.server, state.port);
+            let credential = state.pairings.get(&server).is_some() || !state.auth_providers.is_empty();
+            capabilities.set(Capabilities::RESUME, credential);
+            if state.content_log.is_some() {
+                if state.pairings.get(&server).is_some() {
+                    capabilities |= Capabilities::CONTENT_HASH;
+                } else {
+                    warn!("Content hashes need a pairing with {}; run with --pair", server);
+                }
+            }
+            state.capabilities = capabilities;
+            // Until it answers, as servers from before session modes don't
+            state.allowed_mode = SessionMode::Full;
+            if self.link.index == 0 {
+                state.input_control = None;
+                state.display_index = None;
+            }
+            
+            let hello = Command::Hello {
+                refresh_mhz: state.refresh_mhz,
+                session_id: state.session_id,
+                link_mode: state.link_mode as u32,
+                capabilities,
+                mode: state.session_mode,
+            };
+            (hello, state.noise_key, state.heartbeat_timeout)
+        };
+        
+        // Handshake: tell the server what our display can show and which
+        // session this link belongs to
+        let hello_bytes = hello.to_bytes();
+        self.send_bytes(&hello, &hello_bytes).await?;
+        
+        // Everything after this goes encrypted
+        if let Some(key) = noise_key {
+            tokio::time::timeout(heartbeat_timeout, self.secure(&key, &hello_bytes))
+                .await
+                .map_err(|_| anyhow::anyhow!("Server didn't finish the Noise handshake"))??;
+        }
+        
+        // Ask for the session this link had before a network change; the
+        // requests below are repeated anyway, for servers that forgot it
+        let server = {
+            let state = self.state.read().await;
+            server_address(&state.server, state.port)
+        };
+        let token = self.resume_token.lock().unwrap().clone().filter(|(last, _)| *last == server);
+        if let Some((_, token)) = token {
+            self.send(&Command::Resume { token }).await?;
+        }
+        
+        // Only the primary link asks, so an aggregated session gets a
+        // single touchscreen
+        let (forward_touch, quality, crop, paused, session_mode) = {
+            let state = self.state.read().await;
+            (state.forward_touch, state.quality, state.crop, state.paused, state.session_mode)
+        };
+        let touch_device = Command::TouchDevice { slots: TOUCH_SLOTS };
+        if self.link.index == 0 && forward_touch && session_mode.allows(&touch_device) {
+            self.send(&touch_device).await?;
+        }
+        
+        // Every link carries frames, so each asks for the crop
+        if let Some(region) = crop {
+            self.send(&Command::Crop { x: region.x, y: region.y, width: region.width, height: region.height }).await?;
+        }
+        
+        // Reconnecting while the window is hidden
+        if paused {
+            self.send(&Command::Pause { paused }).await?;
+        }
+        
+        // A new connection starts at full quality on the server
+        if quality != QualityLimits::FULL {
+            self.send(&quality.to_command()).await?;
+        }
+        
+        if self.link.index == 0 {
+            if let Err(e) = self.state.write().await.recent.touch(addr) {
+                warn!("Failed to save recent servers: {:#}", e);
+            }
         }
         
         info!("Successfully connected to server");
         Ok(())
     }
     
+    /// Open a TCP connection, as a WebSocket if that's the transport
+    async fn open_tcp(&self, addr: &str, transport: Transport) -> Result<(LinkReader, LinkWriter, SocketAddr)> {
+        let stream = self.open_stream(addr).await?;
+        let local_addr = stream.local_addr()?;
+        debug!("TCP connection established from {}", local_addr);
+        
+        #[cfg(feature = "websocket")]
+        let stream = self.upgrade(stream, addr, transport).await?;
+        
+        let (read_half, write_half) = stream.into_split();
+        Ok(match transport {
+            #[cfg(feature = "websocket")]
+            Transport::Ws => {
+                let (reader, writer) = websocket::split(read_half, write_half);
+                (LinkReader::WebSocket(reader), LinkWriter::WebSocket(writer), local_addr)
+            }
+            _ => (LinkReader::Tcp(read_half), LinkWriter::Tcp(write_half), local_addr),
+        })
+    }
+    
+    /// Open a QUIC connection to the first address `addr` resolves to that
+    /// answers, from a UDP socket bound as configured
+    #[cfg(feature = "quic")]
+    async fn open_quic(&self, addr: &str) -> Result<(LinkReader, LinkWriter, SocketAddr)> {
+        let mut last_error = anyhow::anyhow!("No usable address for {}", addr);
+        for target in interleave_families(self.targets(addr).await?) {
+            let local = self.link.address.unwrap_or(if target.is_ipv4() {
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            } else {
+                IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED)
+            });
+            let socket = tokio::net::UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+            if let Some(interface) = &self.link.interface {
+                Self::bind_udp_device(&socket, interface)?;
+            }
+            let socket = socket.into_std()?;
+            let local_addr = socket.local_addr()?;
+            match quic::connect(socket, target).await {
+                Ok((reader, writer)) => {
+                    debug!("QUIC connection established from {}", local_addr);
+                    return Ok((LinkReader::Quic(reader), LinkWriter::Quic(writer), local_addr));
+                }
+                Err(e) => last_error = e.context(format!("QUIC connection to {} failed", target)),
+            }
+        }
+        Err(last_error)
+    }
+    
+    /// Do the WebSocket handshake if that's the transport
+    #[cfg(feature = "websocket")]
+    async fn upgrade(&self, mut stream: TcpStream, addr: &str, transport: Transport) -> Result<TcpStream> {
+        if transport == Transport::Ws {
+            let ws_path = self.state.read().await.ws_path.clone();
+            websocket::handshake(&mut stream, addr, &ws_path).await?;
+            debug!("WebSocket established on {}", ws_path);
+        }
+        Ok(stream)
+    }
+    
+    /// Connect to whichever address `addr` resolves to answers first,
+    /// among those that fit the configured bind interface/address
+    async fn open_stream(&self, addr: &str) -> Result<TcpStream> {
+        let targets = self.targets(addr).await?;
+        connect_any(interleave_families(targets), |target| self.socket_for(target)).await
+    }
+    
+    /// What `addr` resolves to that fits the configured bind address
+    async fn targets(&self, addr: &str) -> Result<Vec<SocketAddr>> {
+        let bind_address = self.link.address;
+        let targets: Vec<SocketAddr> = tokio::net::lookup_host(addr)
+            .await?
+            .filter(|target| !matches!(bind_address, Some(local) if local.is_ipv4() != target.is_ipv4()))
+            .collect();
+        if targets.is_empty() {
+            return Err(anyhow::anyhow!("No usable address for {}", addr));
+        }
+        Ok(targets)
+    }
+    
+    /// Unconnected socket for `target`, bound as configured
+    fn socket_for(&self, target: SocketAddr) -> Result<TcpSocket> {
+        let socket = if target.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        if let Some(interface) = &self.link.interface {
+            Self::bind_device(&socket, interface)?;
+        }
+        if let Some(local) = self.link.address {
+            socket.bind(SocketAddr::new(local, 0))?;
+        }
+        Ok(socket)
+    }
+    
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    fn bind_device(socket: &TcpSocket, interface: &str) -> Result<()> {
+        socket.bind_device(Some(interface.as_bytes())).map_err(|e| {
+            anyhow::anyhow!("Failed to bind to interface {}: {}", interface, e)
+        })
+    }
+    
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    fn bind_device(_socket: &TcpSocket, interface: &str) -> Result<()> {
+        Err(anyhow::anyhow!("Binding to interface {} is not supported on this platform", interface))
+    }
+    
+    #[cfg(all(feature = "quic", any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    fn bind_udp_device(socket: &tokio::net::UdpSocket, interface: &str) -> Result<()> {
+        socket.bind_device(Some(interface.as_bytes())).map_err(|e| {
+            anyhow::anyhow!("Failed to bind to interface {}: {}", interface, e)
+        })
+    }
+    
+    #[cfg(all(feature = "quic", not(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))))]
+    fn bind_udp_device(_socket: &tokio::net::UdpSocket, interface: &str) -> Result<()> {
+        Err(anyhow::anyhow!("Binding to interface {} is not supported on this platform", interface))
+    }
+    
     pub async fn disconnect(&self) -> Result<()> {
         info!("Disconnecting from server");
         
         // Close connection
-        {
-            let mut conn = self.connection.write().await;
-            if let Some(mut stream) = conn.take() {
-                let _ = stream.shutdown().await;
-            }
+        if let Some(mut write_half) = self.writer.lock().await.take() {
+            let _ = write_half.shutdown().await;
         }
+        self.reader.lock().await.take();
         
-        // Update state
-        {
+        // Update state; the client stays connected while another link is
+        let (was_connected, server) = {
             let mut state = self.state.write().await;
-            state.connected = false;
+            let was_connected = match state.links.get_mut(self.link.index) {
+                Some(link) => {
+                    link.clock = None;
+                    std::mem::replace(&mut link.connected, false)
+                }
+                None => false,
+            };
+            state.connected = state.links.iter().any(|link| link.connected);
+            (was_connected, server_address(&state.server, state.port))
+        };
+        if was_connected {
+            self.run_hook(HookEvent::Disconnect, &server, None).await;
         }
         
         info!("Disconnected from server");
         Ok(())
     }
     
+    async fn run_hook(&self, event: HookEvent, server: &str, error: Option<&str>) {
+        let hooks = self.state.read().await.hooks.clone();
+        hooks.run(event, server, self.link.index, error);
+    }
+    
+    /// Run the `error` hook for `error`, unless it already ran since the
+    /// last connect
+    async fn report_error(&self, server: &str, error: &anyhow::Error) {
+        if !self.failing.swap(true, Ordering::Relaxed) {
+            self.run_hook(HookEvent::Error, server, Some(&format!("{:#}", error))).await;
+        }
+    }
+    
+    /// Tell the server we're leaving, then disconnect. Used on shutdown so
+    /// the server frees the client straight away instead of waiting for the
+    /// heartbeat timeout.
+    pub async fn close(&self) -> Result<()> {
+        if !self.is_connected().await {
+            return Ok(());
+        }
+        
+        if let Err(e) = self.send(&Command::Goodbye).await {
+            debug!("Failed to send goodbye: {}", e);
+        }
+        self.disconnect().await
+    }
+    
+    /// Whether a pausing bandwidth budget is used up for this cycle
+    pub async fn budget_exhausted(&self) -> bool {
+        let state = self.state.read().await;
+        state.budget.is_some_and(|budget| budget.pauses(&state.usage, &state.usage_account, SystemTime::now()))
+    }
+    
+    /// Whether a control client asked to disconnect
+    pub async fn user_disconnected(&self) -> bool {
+        self.state.read().await.user_disconnected
+    }
+    
+    /// Longest wait between reconnect attempts
+    pub async fn max_reconnect_delay(&self) -> Duration {
+        if self.is_kiosk().await {
+            KIOSK_RECONNECT_DELAY
+        } else {
+            MAX_RECONNECT_DELAY
+        }
+    }
+    
+    pub async fn is_kiosk(&self) -> bool {
+        self.state.read().await.kiosk
+    }
+    
     pub async fn is_connected(&self) -> bool {
-        let conn = self.connection.read().await;
-        conn.is_some()
+        self.writer.lock().await.is_some()
     }
     
-    pub async fn receive_frame(&self) -> Result<Option<(PacketHeader, Vec<u8>)>> {
-        let mut conn = self.connection.write().await;
+    /// Receive the next packet, dropping the connection if the server has
+    /// been silent for longer than the heartbeat timeout or the read side
+    /// has failed, so the network loop can reconnect
+    pub async fn receive_frame(&self) -> Result<Option<FrameData>> {
+        let timeout = self.state.read().await.heartbeat_timeout;
+        let (result, timed_out) = match tokio::time::timeout(timeout, self.receive_packet()).await {
+            Ok(result) => (result, false),
+            Err(_) => (Err(anyhow::anyhow!("No data from server for {:?}", timeout)), true),
+        };
+        
+        if (timed_out || self.reader.lock().await.is_none()) && self.is_connected().await {
+            warn!("Connection to server lost");
+            if let Err(e) = &result {
+                self.report_error(&self.target().await.0, e).await;
+            }
+            self.disconnect().await?;
+        }
+        
+        result
+    }
+    
+    async fn receive_packet(&self) -> Result<Option<FrameData>> {
+        let mut conn = self.reader.lock().await;
         let stream = match conn.as_mut() {
             Some(s) => s,
             None => return Ok(None),
         };
         
-        // Read header
-        let mut header_buf = vec![0u8; HEADER_SIZE];
+        // Read header; compact headers are as long as the start of a full one
+        let mut header_buf = vec![0u8; COMPACT_HEADER_SIZE];
         match stream.read_exact(&mut header_buf).await {
-            Ok(()) => {}
+            Ok(_) => {}
             Err(e) if e.kind() == tokio::io::ErrorKind::UnexpectedEof => {
                 warn!("Connection closed by server");
                 *conn = None;
@@ -96,43 +961,306 @@ impl NetworkClient {
                 return Err(e.into());
             }
         }
+        let received_ns = timesync::now_ns();
         
-        // Parse header
-        let header = match PacketHeader::from_bytes(&header_buf) {
-            Ok(h) => h,
-            Err(e) => {
-                error!("Invalid packet header: {}", e);
-                return Err(e);
+        // The rest of a full header, then optional extensions such as the
+        // payload CRC
+        let (compact, fixed_size) = if CompactHeader::is_compact(header_buf[0]) {
+            let compact = CompactHeader::from_bytes(header_buf[..].try_into()?)?;
+            header_buf.resize(COMPACT_HEADER_SIZE + compact.extension_size(), 0);
+            (Some(compact), COMPACT_HEADER_SIZE)
+        } else {
+            // The version says how long the fixed part is
+            let fixed_size = match PacketHeader::fixed_size(header_buf[..].try_into()?) {
+                Ok(fixed_size) => fixed_size,
+                Err(e) => return self.resync(&mut conn, &header_buf, e).await,
+            };
+            header_buf.resize(fixed_size, 0);
+            if let Err(e) = stream.read_exact(&mut header_buf[COMPACT_HEADER_SIZE..]).await {
+                error!("Failed to read header: {}", e);
+                *conn = None;
+                return Err(e.into());
             }
+            header_buf.resize(fixed_size + PacketHeader::extension_size(header_buf[..HEADER_SIZE].try_into()?)?, 0);
+            (None, fixed_size)
         };
+        if header_buf.len() > fixed_size {
+            if let Err(e) = stream.read_exact(&mut header_buf[fixed_size..]).await {
+                error!("Failed to read header extension: {}", e);
+                *conn = None;
+                return Err(e.into());
+            }
+        }
+        
+        // Parse header; a compact one repeats the previous frame's
+        let parsed = parse_header(&header_buf, self.previous_frame.lock().unwrap().as_ref());
+        let header = match parsed {
+            Ok(h) => h,
+            Err(e) => return self.resync(&mut conn, &header_buf, e).await,
+        };
+        if header.is_frame_packet() {
+            *self.previous_frame.lock().unwrap() = Some(header.clone());
+        }
         
         debug!("Received header: {}x{} format={:?} size={}", 
                header.width, header.height, header.format, header.size);
+        if let Some(log) = &self.protocol_log {
+            log.received(self.link.index, &header, compact.is_some());
+        }
         
         // Validate header
         if let Err(e) = header.validate() {
-            error!("Header validation failed: {}", e);
-            return Err(e);
+            return self.resync(&mut conn, &header_buf, e).await;
         }
         
         // Handle info packets (no data payload)
         if header.is_info_packet() {
+            self.dump(&header_buf, &[]);
             info!("Received display info: {}x{}", header.width, header.height);
             
-            // Update display dimensions in state
-            {
-                let mut state = self.state.write().await;
-                state.display_width = header.width;
-                state.display_height = header.height;
+            self.set_mode(DisplayMode { width: header.width, height: header.height }).await;
+            return Ok(Some(FrameData::new(header, Vec::new())?));
+        }
+        
+        // Heartbeats only prove the server is still there
+        if header.packet_type == PacketType::Heartbeat {
+            self.dump(&header_buf, &[]);
+            self.state.write().await.last_signal = Some(Instant::now());
+            return Ok(Some(FrameData::new(header, Vec::new())?));
+        }
+        
+        // The server is going away on purpose, e.g. its module is being
+        // unloaded; reconnect as after any closed connection
+        if header.packet_type == PacketType::Goodbye {
+            self.dump(&header_buf, &[]);
+            info!("Server is shutting down");
+            *conn = None;
+            if let Some(messages) = &self.status_messages {
+                let _ = messages.send("Server shut down; reconnecting".to_string());
+            }
+            return Ok(None);
+        }
+        
+        // Control packets: Pongs update the clock estimate, challenges are
+        // answered here and pairing replies are left to `pair_if_requested`
+        if let Some(expected) = ServerMessage::payload_size(header.packet_type) {
+            // A newer server may have appended fields this version skips
+            if !(expected..=MAX_CONTROL_SIZE).contains(&(header.size as usize)) {
+                let e = anyhow::anyhow!("Unexpected {:?} size: {}", header.packet_type, header.size);
+                return self.resync(&mut conn, &header_buf, e).await;
+            }
+            
+            let mut payload = vec![0u8; header.size as usize];
+            if let Err(e) = stream.read_exact(&mut payload).await {
+                error!("Failed to read {:?}: {}", header.packet_type, e);
+                *conn = None;
+                return Err(e.into());
+            }
+            drop(conn);
+            self.dump(&header_buf, &payload);
+            if header.packet_type == PacketType::Capabilities {
+                *self.capabilities_packet.lock().unwrap() = [&header_buf[..], &payload[..]].concat();
+            }
+            if let Some(log) = &self.protocol_log {
+                log.control(self.link.index, header.packet_type, &payload);
+            }
+            
+            match ServerMessage::from_payload(header.packet_type, &payload, header.byte_order)? {
+                ServerMessage::Pong(pong) => {
+                    let sample = ClockEstimate::from_exchange(
+                        pong.client_ns, pong.server_rx_ns, pong.server_tx_ns, received_ns,
+                    );
+                    let estimate = self.clock.lock().unwrap().add(sample);
+                    debug!("Clock offset {} ns, rtt {} ns", estimate.offset_ns, estimate.rtt_ns);
+                    if let Some(link) = self.state.write().await.links.get_mut(self.link.index) {
+                        link.clock = Some(estimate);
+                    }
+                }
+                ServerMessage::AuthChallenge { nonce } => self.answer_challenge(&nonce).await?,
+                ServerMessage::AuthPrompt(prompt) => self.prompt_sign_in(prompt).await?,
+                ServerMessage::TouchDevice(device) => {
+                    info!("Server registered a {}x{} touchscreen with {} contacts",
+                          device.width, device.height, device.slots);
+                    self.state.write().await.touch_device = Some(device);
+                }
+                ServerMessage::ContentHash(hash) => {
+                    let server = self.server_name().await;
+                    let mut state = self.state.write().await;
+                    // The key pinned when we paired, else the one we were given
+                    let key = state.pairings.get(&server).and_then(|paired| paired.content_key).or(state.content_key);
+                    let signed = key.is_some_and(|key| pairing::verify_content(&key, hash.signed(), &hash.signature));
+                    if signed {
+                        *self.pending_hash.lock().unwrap() = Some(hash);
+                    } else {
+                        warn!("Content hash for frame {} is not signed by {}", hash.timestamp, server);
+                        state.content_mismatches += 1;
+                    }
+                }
+                ServerMessage::Sync(sync) => {
+                    info!("Server presents frames {:?} after capture", sync.delay());
+                    self.state.write().await.sync_delay = Some(sync.delay());
+                }
+                ServerMessage::Supervise(result) => {
+                    let message = format!("Server: {}", result.message);
+                    if result.succeeded() {
+                        info!("{}", message);
+                    } else {
+                        warn!("{} (error {})", message, -result.status);
+                    }
+                    if let Some(messages) = &self.status_messages {
+                        let _ = messages.send(message);
+                    }
+                }
+                ServerMessage::FileStatus(status) => {
+                    if let Some(statuses) = &self.file_statuses {
+                        let _ = statuses.send(status);
+                    }
+                }
+                ServerMessage::Capabilities(agreed) => {
+                    let mut state = self.state.write().await;
+                    let declined = state.capabilities - agreed;
+                    if declined.is_empty() {
+                        info!("Server agreed to {}", agreed);
+                    } else {
+                        info!("Server agreed to {}; not to {}", agreed, declined);
+                    }
+                    state.capabilities = agreed;
+                }
+                ServerMessage::Identify { index } => {
+                    info!("Server is flashing card{}", index);
+                    self.state.write().await.display_index = Some(index);
+                }
+                ServerMessage::SessionMode(mode) => {
+                    let mut state = self.state.write().await;
+                    if mode < state.session_mode {
+                        let message = format!("Server allows the {} session mode only", mode);
+                        warn!("{}", message);
+                        if let Some(messages) = &self.status_messages {
+                            let _ = messages.send(message);
+                        }
+                    } else {
+                        info!("Session mode {}", mode);
+                    }
+                    state.allowed_mode = mode;
+                }
+                ServerMessage::InputControl(control) => {
+                    let mut state = self.state.write().await;
+                    let holder = control_holder(&control);
+                    let changed = state.input_control
+                        .is_none_or(|last| (last.owner, last.yours) != (control.owner, control.yours));
+                    let message = if control.status != 0 {
+                        Some(format!("Input control refused; {} has it", holder))
+                    } else if changed && !control.shared {
+                        Some(format!("Input control: {}", holder))
+                    } else {
+                        None
+                    };
+                    if let Some(message) = message {
+                        info!("{}", message);
+                        if let Some(messages) = &self.status_messages {
+                            let _ = messages.send(message);
+                        }
+                    }
+                    state.input_control = Some(control);
+                }
+                ServerMessage::Resume { token, resumed } => {
+                    let server = {
+                        let state = self.state.read().await;
+                        server_address(&state.server, state.port)
+                    };
+                    *self.resume_token.lock().unwrap() = Some((server, token));
+                    if resumed {
+                        info!("Resumed the session on link {}", self.link.index);
+                        if let Some(messages) = &self.status_messages {
+                            let _ = messages.send("Resumed the session".to_string());
+                        }
+                    }
+                }
+                ServerMessage::Format(announcement) => {
+                    // Frames carry their own format, so this is only a
+                    // chance to drop buffers sized for the old one; the
+                    // last frame stays up until a new one can be shown
+                    info!("Server switching to {:?} at {}x{}",
+                          announcement.format, announcement.width, announcement.height);
+                    self.buffers.clear();
+                    let decoded = self.state.read().await.decoded_formats.contains(&announcement.format);
+                    if matches!(announcement.format, FrameFormat::H264 | FrameFormat::H265) && !decoded {
+                        warn!("Can't decode {:?}; keeping the last frame on screen", announcement.format);
+                    }
+                }
+                _ => {}
+            }
+            
+            // Passed on as this version knows it
+            payload.truncate(expected);
+            let header = PacketHeader { size: expected as u32, ..header };
+            return Ok(Some(FrameData::new(header, payload)?));
+        }
+        
+        // Sound is played as it arrives rather than queued with the frames
+        if header.packet_type == PacketType::Audio {
+            if header.size as usize > MAX_AUDIO_SIZE {
+                return Err(anyhow::anyhow!("Audio packet too large: {} bytes", header.size));
             }
             
-            return Ok(Some((header, Vec::new())));
+            let mut payload = vec![0u8; header.size as usize];
+            if let Err(e) = stream.read_exact(&mut payload).await {
+                error!("Failed to read audio: {}", e);
+                *conn = None;
+                return Err(e.into());
+            }
+            drop(conn);
+            self.dump(&header_buf, &payload);
+            if let Some(audio) = &self.audio {
+                audio.lock().unwrap().play(&header, &payload);
+            }
+            return Ok(Some(FrameData::new(header, Vec::new())?));
+        }
+        
+        // Frames are held to the display the server announced, so a stream
+        // that has lost its place is caught before its payload is read
+        let mut mode = self.mode.lock().unwrap().unwrap_or(DisplayMode::LARGEST);
+        if !mode.fits(&header) {
+            let larger = DisplayMode { width: header.width, height: header.height };
+            let policy = self.state.read().await.mode_change;
+            let reason = format!("{}x{} frame on a {}x{} display", header.width, header.height, mode.width, mode.height);
+            if !DisplayMode::LARGEST.fits(&header) || larger.check_size(&header).is_err() {
+                return self.resync(&mut conn, &header_buf, anyhow::anyhow!(reason)).await;
+            }
+            if policy == ModeChange::Reconnect {
+                error!("{}, reconnecting", reason);
+                *conn = None;
+                return Err(anyhow::anyhow!(reason));
+            }
+            
+            if policy == ModeChange::Skip {
+                warn!("{}, skipping it", reason);
+                let skipped = tokio::io::copy(&mut (&mut *stream).take(header.size as u64), &mut tokio::io::sink()).await;
+                if !matches!(skipped, Ok(n) if n == header.size as u64) {
+                    *conn = None;
+                }
+                return Ok(None);
+            }
+            
+            warn!("{}, following it", reason);
+            self.set_mode(larger).await;
+            mode = larger;
+        }
+        if let Err(e) = mode.check_size(&header) {
+            return self.resync(&mut conn, &header_buf, e).await;
+        }
+        
+        // Damage is read like a frame, then patched into the last one
+        let damage = header.packet_type == PacketType::Damage;
+        if damage && header.size as usize > Damage::max_size(&header) {
+            return Err(anyhow::anyhow!("Damage larger than its {}x{} frame: {} bytes",
+                                       header.width, header.height, header.size));
         }
         
         // Read frame data
-        let mut data = vec![0u8; header.size as usize];
+        let mut data = self.buffers.get(header.size as usize);
         match stream.read_exact(&mut data).await {
-            Ok(()) => {}
+            Ok(_) => {}
             Err(e) if e.kind() == tokio::io::ErrorKind::UnexpectedEof => {
                 warn!("Connection closed while reading frame data");
                 *conn = None;
@@ -146,29 +1274,535 @@ impl NetworkClient {
         }
         
         debug!("Received frame data: {} bytes", data.len());
+        self.dump(&header_buf, &data);
+        
+        {
+            let mut state = self.state.write().await;
+            let received = header_buf.len() + data.len();
+            if let Some(link) = state.links.get_mut(self.link.index) {
+                link.rx.record(Instant::now(), received);
+            }
+            state.last_signal = Some(Instant::now());
+            record_usage(&mut state, received as u64);
+        }
+        
+        // A corrupted frame is skipped rather than shown; the server sends
+        // a fresh one when asked
+        let mut frame = FrameData::new(header, data)?;
+        if !frame.checksum_ok() {
+            warn!("Frame {} failed its CRC-32 check, requesting a resend", frame.header.timestamp);
+            drop(conn);
+            self.state.write().await.corrupt_frames += 1;
+            self.send(&Command::Resend { timestamp: frame.header.timestamp }).await?;
+            return Ok(None);
+        }
+        
+        // Without a whole frame to patch, e.g. right after connecting, the
+        // server is asked for one, and sends whole frames until then
+        if damage {
+            let patched = self.canvas.lock().unwrap().apply(&frame)?.map(|(header, pixels)| {
+                let mut data = self.buffers.get(pixels.len());
+                data.copy_from_slice(pixels);
+                (header, data)
+            });
+            let Some((header, data)) = patched else {
+                debug!("Damage before a whole frame, requesting one");
+                drop(conn);
+                self.send(&Command::Resend { timestamp: frame.header.timestamp }).await?;
+                return Ok(None);
+            };
+            frame = FrameData::new(header, data)?;
+        }
         
         // Validate frame data
-        let frame = FrameData::new(header.clone(), data.clone())?;
         if let Err(e) = frame.validate() {
             error!("Frame validation failed: {}", e);
             return Err(e);
         }
+        drop(conn);
+        
+        if !damage {
+            self.canvas.lock().unwrap().keep(&frame);
+        }
         
-        Ok(Some((header, data)))
+        let pending = self.pending_hash.lock().unwrap().take();
+        if let Some(hash) = pending {
+            self.check_content(&frame, &hash).await;
+        }
+        
+        Ok(Some(frame))
     }
     
-    pub async fn send_command(&self, command: &[u8]) -> Result<()> {
-        let mut conn = self.connection.write().await;
-        let stream = match conn.as_mut() {
-            Some(s) => s,
-            None => return Err(anyhow::anyhow!("Not connected")),
+    /// Skip past a header that made no sense to the next full one, which
+    /// is read next time; patches and a content hash from before may not
+    /// match what follows
+    async fn resync(
+        &self,
+        conn: &mut Option<Pushback<LinkReader>>,
+        garbage: &[u8],
+        reason: anyhow::Error,
+    ) -> Result<Option<FrameData>> {
+        warn!("Lost the stream's place ({}), scanning for the next header", reason);
+        let Some(stream) = conn.as_mut() else { return Ok(None) };
+        match skip_to_header(stream, garbage).await {
+            Ok(skipped) => {
+                info!("Resynchronised after skipping {} bytes", skipped);
+                self.canvas.lock().unwrap().reset();
+                *self.pending_hash.lock().unwrap() = None;
+                self.state.write().await.resyncs += 1;
+                Ok(None)
+            }
+            Err(e) => {
+                error!("Failed to resynchronise: {}", e);
+                *conn = None;
+                Err(e.into())
+            }
+        }
+    }
+    
+    /// Update the display size in state; frames already on their way are at
+    /// the old size
+    async fn set_mode(&self, mode: DisplayMode) {
+        *self.mode.lock().unwrap() = Some(mode);
+        let mut state = self.state.write().await;
+        if (state.display_width, state.display_height) != (mode.width, mode.height) {
+            state.display_resized_at = Some(Instant::now());
+        }
+        state.display_width = mode.width;
+        state.display_height = mode.height;
+    }
+    
+    #[cfg(feature = "recording")]
+    fn dump(&self, header: &[u8], payload: &[u8]) {
+        if let Some(dump) = &self.stream_dump {
+            dump.record(self.link.index, header, payload);
+        }
+    }
+    
+    #[cfg(not(feature = "recording"))]
+    fn dump(&self, _header: &[u8], _payload: &[u8]) {}
+    
+    /// Compare a frame with the signed hash the server sent ahead of it and
+    /// log the result for later audits
+    async fn check_content(&self, frame: &FrameData, hash: &ContentHash) {
+        let matches = hash.timestamp == frame.header.timestamp
+            && <[u8; 32]>::from(Sha256::digest(&frame.data[..])) == hash.digest;
+        
+        let log = {
+            let mut state = self.state.write().await;
+            if !matches {
+                state.content_mismatches += 1;
+                warn!("Frame {} does not match the server's content hash", frame.header.timestamp);
+                return;
+            }
+            state.content_verified += 1;
+            state.content_log.clone()
         };
         
-        stream.write_all(command).await?;
-        stream.flush().await?;
+        let Some(path) = log else { return };
+        let line = format!("{} {} {}\n", hash.timestamp, pairing::encode_hex(&hash.digest), pairing::encode_hex(&hash.signature));
+        let written = async {
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?
+                .write_all(line.as_bytes())
+                .await
+        };
+        if let Err(e) = written.await {
+            warn!("Failed to write content hash to {}: {}", path.display(), e);
+        }
+    }
+    
+    /// `server:port` as given by the user, which pairings are stored under
+    async fn server_name(&self) -> String {
+        let state = self.state.read().await;
+        server_address(&state.server, state.port)
+    }
+    
+    /// The server to connect to, and the generation that changes each
+    /// time the user picks another
+    pub async fn target(&self) -> (String, u64) {
+        let state = self.state.read().await;
+        (server_address(&state.server, state.port), state.server_generation)
+    }
+    
+    /// Prove we may watch this server, with a pairing or another
+    /// configured credential
+    async fn answer_challenge(&self, challenge: &[u8]) -> Result<()> {
+        let server = self.server_name().await;
+        let answer = {
+            let state = self.state.read().await;
+            let pairings: &dyn AuthProvider = &state.pairings;
+            let others = state.auth_providers.iter().map(|provider| provider.as_ref());
+            auth::respond(std::iter::once(pairings).chain(others), &server, challenge)
+                .map(|(provider, response)| (provider.to_string(), response))
+        };
+        match answer {
+            Some((provider, response)) => {
+                debug!("Answering {}'s auth challenge with {}", server, provider);
+                self.send(&Command::Auth { id: response.id, mac: response.mac }).await
+            }
+            // The server has other ways to sign in, and can go on to them
+            None if self.state.read().await.capabilities.supports(Capabilities::AUTH_PROMPT) => {
+                debug!("No credential for {}'s auth challenge", server);
+                self.send(&Command::AuthReply { text: String::new() }).await
+            }
+            None => {
+                warn!("{} only streams to authenticated clients; run with --pair or --auth-token-file", server);
+                Ok(())
+            }
+        }
+    }
+    
+    /// Show the user what the server's sign-in says, and send their answer
+    /// to a question back once they give it, without holding up the
+    /// packets behind it
+    async fn prompt_sign_in(&self, AuthPrompt { style, text }: AuthPrompt) -> Result<()> {
+        let server = self.server_name().await;
+        if style == PromptStyle::Error {
+            warn!("{}: {}", server, text);
+        } else {
+            info!("{}: {}", server, text);
+        }
+        let (reply, answered) = if style.expects_reply() {
+            let (reply, answered) = oneshot::channel();
+            (Some(reply), Some(answered))
+        } else {
+            (None, None)
+        };
+        let shown = self.sign_in_prompts.as_ref()
+            .is_some_and(|prompts| prompts.send(SignInPrompt { server, style, text, reply }).is_ok());
+        match answered {
+            Some(answered) if shown => {
+                let client = self.clone();
+                tokio::spawn(async move {
+                    if let Ok(text) = answered.await {
+                        if let Err(e) = client.send(&Command::AuthReply { text }).await {
+                            warn!("Failed to answer the server's sign-in: {}", e);
+                        }
+                    }
+                });
+                Ok(())
+            }
+            // No one to ask, so decline
+            Some(_) => self.send(&Command::AuthReply { text: String::new() }).await,
+            None => Ok(()),
+        }
+    }
+    
+    /// Run the pairing exchange if the user asked for it: the server shows
+    /// a code, the user types it into our prompt, and on a match both sides
+    /// store a token for later connections
+    pub async fn pair_if_requested(&self) -> Result<()> {
+        let Some(prompts) = &self.pair_prompts else {
+            return Ok(());
+        };
+        
+        tokio::time::timeout(pairing::PAIR_TIMEOUT, self.pair(prompts))
+            .await
+            .map_err(|_| anyhow::anyhow!("Pairing timed out"))?
+    }
+    
+    async fn pair(&self, prompts: &UnboundedSender<PairPrompt>) -> Result<()> {
+        let server = self.server_name().await;
+        info!("Pairing with {}", server);
+        
+        let pairing = Pairing::new();
+        self.send(&Command::PairCommit { commitment: pairing.commitment() }).await?;
+        let server_key = self.wait_for(PacketType::PairKey).await?;
+        let (public, nonce) = pairing.reveal();
+        self.send(&Command::PairReveal { public, nonce }).await?;
+        let keys = pairing.finish(&server_key.data)?;
+        
+        let (reply, entered) = oneshot::channel();
+        prompts
+            .send(PairPrompt { server: server.clone(), reply })
+            .map_err(|_| anyhow::anyhow!("No window to ask for the pairing code"))?;
+        let code = entered.await.map_err(|_| anyhow::anyhow!("Pairing cancelled"))?;
+        if !keys.matches(&code) {
+            return Err(anyhow::anyhow!(
+                "Code doesn't match the one {} should be showing; is this the right display?", server
+            ));
+        }
+        
+        self.send(&Command::PairConfirm { mac: keys.client_confirm() }).await?;
+        // The server only confirms once someone there has approved
+        let message = format!("Approve the pairing on {} by writing the code to its pairing_code", server);
+        info!("{}", message);
+        if let Some(messages) = &self.status_messages {
+            let _ = messages.send(message);
+        }
+        let confirm = self.wait_for(PacketType::PairConfirm).await?;
+        let Some(content_key) = keys.verify_server_confirm(&confirm.data) else {
+            return Err(anyhow::anyhow!("Server failed to confirm the pairing"));
+        };
+        
+        // The server lists the pairing under this id, to revoke it by
+        let token = keys.token(content_key);
+        let id = pairing::encode_hex(&token.id);
+        self.state.write().await.pairings.insert(&server, token)?;
+        info!("Paired with {} as {}", server, id);
+        Ok(())
+    }
+    
+    /// Run the Noise handshake after our hello and switch the connection
+    /// to records, refusing a server whose key isn't the one seen before
+    async fn secure(&self, key: &[u8; noise::KEY_SIZE], hello: &[u8]) -> Result<()> {
+        let server = self.server_name().await;
+        self.wait_for(PacketType::Capabilities).await?;
+        if !self.state.read().await.capabilities.supports(Capabilities::NOISE) {
+            return Err(anyhow::anyhow!("{} doesn't offer encryption; load its module with noise_key", server));
+        }
+        
+        // Both sides bind the handshake to what was agreed in the clear
+        let prologue = [hello, &self.capabilities_packet.lock().unwrap()[..]].concat();
+        let mut handshake = Handshake::new(key, &prologue)?;
+        self.send(&Command::Noise { message: handshake.start()? }).await?;
+        let reply = self.wait_for(PacketType::Noise).await?;
+        let server_key = handshake.read_reply(&reply.data)?;
+        self.state.write().await.known_servers.check(&server, &server_key)?;
+        let (last, transport) = handshake.finish()?;
+        
+        // The server sends nothing more until it has our last message, and
+        // only records after it
+        {
+            let mut conn = self.reader.lock().await;
+            let stream = conn.take().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+            let reader = NoiseReader::new(stream.inner, Arc::clone(&transport));
+            *conn = Some(Pushback::new(LinkReader::Noise(Box::new(reader))));
+        }
+        
+        // Sent and switched under one lock, so no heartbeat slips out
+        // unencrypted after it
+        let last = Command::Noise { message: last };
+        {
+            let mut conn = self.writer.lock().await;
+            let mut stream = conn.take().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+            stream.write_all(&last.to_bytes()).await?;
+            stream.flush().await?;
+            *conn = Some(LinkWriter::Noise(Box::new(NoiseWriter::new(stream, transport))));
+        }
+        if let Some(log) = &self.protocol_log {
+            log.sent(self.link.index, &last);
+        }
         
+        info!("Connection to {} encrypted", server);
         Ok(())
     }
+    
+    /// Read packets until one of `packet_type` arrives, skipping the rest
+    async fn wait_for(&self, packet_type: PacketType) -> Result<FrameData> {
+        loop {
+            match self.receive_packet().await? {
+                Some(packet) if packet.header.packet_type == packet_type => return Ok(packet),
+                Some(_) => {}
+                None => return Err(anyhow::anyhow!("Connection closed while waiting for {:?}", packet_type)),
+            }
+        }
+    }
+    
+    /// Send a request; every packet to the server goes out as a `Command`
+    pub async fn send(&self, command: &Command) -> Result<()> {
+        self.send_bytes(command, &command.to_bytes()).await
+    }
+    
+    /// Send `command` as `bytes`, when the exact bytes matter; headers
+    /// carry the time, so serializing it again would give others
+    async fn send_bytes(&self, command: &Command, bytes: &[u8]) -> Result<()> {
+        match command {
+            // Never log what the user typed at a password prompt
+            Command::AuthReply { .. } => debug!("Sending AuthReply"),
+            _ => debug!("Sending {:?}", command),
+        }
+        {
+            let mut conn = self.writer.lock().await;
+            let stream = match conn.as_mut() {
+                Some(s) => s,
+                None => return Err(anyhow::anyhow!("Not connected")),
+            };
+            
+            stream.write_all(bytes).await?;
+            stream.flush().await?;
+            *self.last_sent.lock().unwrap() = Instant::now();
+        }
+        if let Some(log) = &self.protocol_log {
+            log.sent(self.link.index, command);
+        }
+        Ok(())
+    }
+    
+    /// Ping the server for clock sync and, when nothing else has gone out
+    /// lately, send heartbeats so it knows we're alive. Returns on shutdown.
+    pub async fn keepalive(&self, shutdown: CancellationToken) {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL / 2);
+        let mut last_ping: Option<Instant> = None;
+        
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = interval.tick() => {}
+            }
+            if !self.is_connected().await {
+                continue;
+            }
+            
+            let ping_due = !matches!(last_ping, Some(sent) if sent.elapsed() < timesync::PING_INTERVAL);
+            let command = if ping_due {
+                last_ping = Some(Instant::now());
+                Command::Ping { client_ns: timesync::now_ns() }
+            } else if self.last_sent.lock().unwrap().elapsed() >= HEARTBEAT_INTERVAL {
+                Command::Heartbeat
+            } else {
+                continue;
+            };
+            
+            if let Err(e) = self.send(&command).await {
+                debug!("Failed to send {:?}: {}", command, e);
+            }
+        }
+    }
+}
+
+/// The server the user picked, reconnecting whenever the connection is
+/// lost and following the user to other servers
+impl DisplaySource for NetworkClient {
+    fn name(&self) -> &'static str {
+        "ipds"
+    }
+    
+    fn connect(&self) -> SourceFuture<'_, ()> {
+        Box::pin(async move {
+            let (server_addr, generation) = self.target().await;
+            self.reconnect.lock().unwrap().generation = generation;
+            
+            // A paused budget waits in next_frame without connecting
+            if self.budget_exhausted().await {
+                return Err(anyhow::anyhow!("Bandwidth budget used up for this cycle"));
+            }
+            NetworkClient::connect(self, &server_addr).await?;
+            info!("Connected to server successfully");
+            if let Err(e) = self.pair_if_requested().await {
+                warn!("Pairing failed: {}", e);
+            }
+            Ok(())
+        })
+    }
+    
+    fn next_frame(&self) -> SourceFuture<'_, Option<FrameData>> {
+        Box::pin(async move {
+            loop {
+                // The user picked another server; go there straight away
+                let (server_addr, current) = self.target().await;
+                let switched = {
+                    let mut reconnect = self.reconnect.lock().unwrap();
+                    let switched = current != reconnect.generation;
+                    if switched {
+                        reconnect.generation = current;
+                        reconnect.delay = Duration::ZERO;
+                    }
+                    switched
+                };
+                if switched && self.is_connected().await {
+                    info!("Switching to {}", server_addr);
+                    self.close().await?;
+                }
+                
+                if self.budget_exhausted().await {
+                    if self.is_connected().await {
+                        warn!("Bandwidth budget used up; pausing the stream until the next billing cycle");
+                        self.close().await?;
+                    }
+                    tokio::time::sleep(BUDGET_RECHECK_INTERVAL).await;
+                    continue;
+                }
+                
+                if self.user_disconnected().await {
+                    if self.is_connected().await {
+                        self.close().await?;
+                    }
+                    tokio::time::sleep(RESUME_CHECK_INTERVAL).await;
+                    self.reconnect.lock().unwrap().delay = Duration::ZERO;
+                    continue;
+                }
+                
+                if !self.is_connected().await {
+                    let delay = self.reconnect.lock().unwrap().delay;
+                    tokio::time::sleep(delay).await;
+                    let delay = match NetworkClient::connect(self, &server_addr).await {
+                        Ok(_) => {
+                            info!("Reconnected to server");
+                            if let Some(metrics) = self.metrics() {
+                                metrics.reconnected();
+                            }
+                            RECONNECT_DELAY
+                        }
+                        Err(e) => {
+                            warn!("Reconnect failed, retrying in {:?}: {}", delay, e);
+                            (delay * 2).clamp(RECONNECT_DELAY, self.max_reconnect_delay().await)
+                        }
+                    };
+                    self.reconnect.lock().unwrap().delay = delay;
+                    continue;
+                }
+                
+                match self.receive_frame().await {
+                    Ok(Some(frame)) if frame.header.is_frame_packet() => {
+                        self.source_stats.lock().unwrap().frames += 1;
+                        return Ok(Some(frame));
+                    }
+                    Ok(Some(_)) => {
+                        // Display info, Pongs and heartbeats are handled by
+                        // receive_frame
+                    }
+                    Ok(None) => {
+                        // No data received, continue
+                        tokio::time::sleep(Duration::from_millis(16)).await;
+                    }
+                    Err(e) => {
+                        error!("Network error: {}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        })
+    }
+    
+    fn send_input<'a>(&'a self, command: &'a Command) -> SourceFuture<'a, ()> {
+        Box::pin(async move {
+            // Taken in order with the requests around it, so input sent
+            // before a switch to view only still goes
+            let mode = match command {
+                Command::SessionMode { mode } => {
+                    self.state.write().await.session_mode = *mode;
+                    *mode
+                }
+                _ => self.state.read().await.effective_session_mode(),
+            };
+            // The server would refuse it anyway
+            if !mode.allows(command) {
+                debug!("Not sending {:?} in the {} session mode", command.packet_type(), mode);
+                return Ok(());
+            }
+            // Nor would it replay input while another client holds control
+            if matches!(command, Command::Touch { .. } | Command::Pointer { .. } | Command::Key { .. })
+                && self.state.read().await.input_control.is_some_and(|control| !control.can_drive())
+            {
+                debug!("Not sending {:?} while another client has input control", command.packet_type());
+                return Ok(());
+            }
+            self.send(command).await
+        })
+    }
+    
+    fn stats(&self) -> SourceStats {
+        *self.source_stats.lock().unwrap()
+    }
+    
+    fn close(&self) -> SourceFuture<'_, ()> {
+        Box::pin(NetworkClient::close(self))
+    }
 }
 
 impl Drop for NetworkClient {
@@ -182,12 +1816,487 @@ impl Drop for NetworkClient {
 mod tests {
     use super::*;
     use crate::AppState;
+    use crate::auth::TokenAuth;
+    use crate::protocol::{InputControlAction, Pong, SyncDelay};
+    use ipdisp_server::capture::Screen;
+    use ipdisp_server::demo::{DemoPattern, DemoSource, DEMO_FPS, DEMO_HEIGHT, DEMO_WIDTH};
+    use ipdisp_server::{Service, SERVER_CAPABILITIES};
+    use tokio_util::task::TaskTracker;
     
     #[tokio::test]
     async fn test_network_client_creation() {
         let state = Arc::new(RwLock::new(AppState::default()));
-        let client = NetworkClient::new(state).await.unwrap();
+        let client = NetworkClient::new(state, LinkPath::default()).unwrap();
+        
+        assert!(!client.is_connected().await);
+    }
+    
+    #[test]
+    fn test_link_merger_drops_reordered_frames() {
+        let merger = LinkMerger::default();
+        assert!(merger.accept(1_000_000_000));
+        assert!(merger.accept(1_016_000_000));
+        
+        // Overtaken by a frame on the other link
+        assert!(!merger.accept(1_008_000_000));
+        
+        // Server restart: timestamps start over
+        assert!(merger.accept(5_000));
+        assert!(merger.accept(21_000));
+    }
+    
+    #[tokio::test]
+    async fn test_silent_server_times_out() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(AppState {
+            heartbeat_timeout: Duration::from_millis(100),
+            ..AppState::default()
+        }));
+        
+        let client = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap();
+        client.connect(&addr.to_string()).await.unwrap();
+        let (_server, _) = listener.accept().await.unwrap();
+        assert!(client.is_connected().await);
+        
+        // The server accepted but never sends anything
+        assert!(client.receive_frame().await.is_err());
+        assert!(!client.is_connected().await);
+        assert!(!state.read().await.connected);
+    }
+    
+    #[tokio::test]
+    async fn test_links_connect_and_sync_separately() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(AppState::default()));
+        
+        let first = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap();
+        let second = NetworkClient::new(Arc::clone(&state), LinkPath { index: 1, ..LinkPath::default() }).unwrap();
+        first.connect(&addr.to_string()).await.unwrap();
+        let (_first_server, _) = listener.accept().await.unwrap();
+        second.connect(&addr.to_string()).await.unwrap();
+        let (mut second_server, _) = listener.accept().await.unwrap();
+        
+        // Only the second link has a clock
+        let now = timesync::now_ns();
+        let pong = Pong { client_ns: now, server_rx_ns: now, server_tx_ns: now };
+        second_server.write_all(&ServerMessage::Pong(pong).to_bytes()).await.unwrap();
+        second.receive_frame().await.unwrap();
+        {
+            let state = state.read().await;
+            assert!(state.links[0].clock.is_none());
+            assert!(state.links[1].clock.is_some());
+            assert_eq!(state.clock(), state.links[1].clock);
+        }
+        
+        // Losing one link leaves the client connected over the other
+        second.disconnect().await.unwrap();
+        assert!(state.read().await.connected);
+        assert!(state.read().await.clock().is_none());
+        first.disconnect().await.unwrap();
+        assert!(!state.read().await.connected);
+    }
+    
+    #[tokio::test]
+    async fn test_close_says_goodbye() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(AppState::default()));
         
+        let client = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap();
+        client.connect(&addr.to_string()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        client.close().await.unwrap();
         assert!(!client.is_connected().await);
+        
+        // Hello, then Goodbye, then the connection closes
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        let hello = PacketHeader::from_bytes(&received).unwrap();
+        assert_eq!(hello.packet_type, PacketType::Hello);
+        let goodbye = PacketHeader::from_bytes(&received[HEADER_SIZE + hello.size as usize..]).unwrap();
+        assert_eq!(goodbye.packet_type, PacketType::Goodbye);
+        assert_eq!(received.len(), 2 * HEADER_SIZE + hello.size as usize);
+    }
+    
+    #[tokio::test]
+    async fn test_server_goodbye() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(AppState::default()));
+        
+        let client = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap();
+        client.connect(&addr.to_string()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        
+        // A server shutting down says so, and the link is dropped for the
+        // reconnect loop without an error
+        server.write_all(&Command::Goodbye.to_bytes()).await.unwrap();
+        assert!(client.receive_frame().await.unwrap().is_none());
+        assert!(!client.is_connected().await);
+    }
+    
+    #[tokio::test]
+    async fn test_newer_control_message() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(AppState::default()));
+        
+        let client = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap();
+        client.connect(&addr.to_string()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        
+        // Fields appended by a newer server are skipped, and the stream
+        // stays in step
+        let mut payload = ServerMessage::Sync(SyncDelay { delay_ns: 40_000_000 }).to_payload();
+        payload.extend_from_slice(&[0xee; 12]);
+        let mut packet = PacketHeader::control(PacketType::Sync, payload.len() as u32).to_bytes();
+        packet.extend_from_slice(&payload);
+        server.write_all(&packet).await.unwrap();
+        server.write_all(&ServerMessage::Heartbeat.to_bytes()).await.unwrap();
+        
+        let sync = client.receive_frame().await.unwrap().unwrap();
+        assert_eq!((sync.header.size as usize, sync.data.len()), (SyncDelay::SIZE, SyncDelay::SIZE));
+        assert_eq!(state.read().await.sync_delay, Some(Duration::from_millis(40)));
+        let heartbeat = client.receive_frame().await.unwrap().unwrap();
+        assert_eq!(heartbeat.header.packet_type, PacketType::Heartbeat);
+        assert_eq!(state.read().await.resyncs, 0);
+    }
+    
+    #[tokio::test]
+    async fn test_frames_held_to_display_mode() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(AppState { mode_change: ModeChange::Skip, ..AppState::default() }));
+        
+        let client = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap();
+        client.connect(&addr.to_string()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.write_all(&PacketHeader::new(4, 2, FrameFormat::Rgba32, 0).to_bytes()).await.unwrap();
+        assert!(client.receive_frame().await.unwrap().unwrap().header.is_info_packet());
+        
+        // A larger frame is skipped, and the stream carries on after it
+        for (width, height) in [(8, 2), (4, 2)] {
+            let size = width * height * 4;
+            server.write_all(&PacketHeader::new(width, height, FrameFormat::Rgba32, size).to_bytes()).await.unwrap();
+            server.write_all(&vec![0u8; size as usize]).await.unwrap();
+        }
+        assert!(client.receive_frame().await.unwrap().is_none());
+        assert_eq!(client.receive_frame().await.unwrap().unwrap().header.width, 4);
+        
+        // Followed, it becomes the display size
+        state.write().await.mode_change = ModeChange::Follow;
+        server.write_all(&PacketHeader::new(8, 2, FrameFormat::Rgba32, 64).to_bytes()).await.unwrap();
+        server.write_all(&[0u8; 64]).await.unwrap();
+        assert_eq!(client.receive_frame().await.unwrap().unwrap().header.width, 8);
+        assert_eq!(state.read().await.display_width, 8);
+        
+        // A payload no frame of the display could have is never read; the
+        // stream picks up at the next header past it and the garbage
+        server.write_all(&PacketHeader::new(8, 2, FrameFormat::Jpeg, u32::MAX).to_bytes()).await.unwrap();
+        server.write_all(&[0x49; 5000]).await.unwrap();
+        server.write_all(&PacketHeader::new(4, 2, FrameFormat::Rgba32, 32).to_bytes()).await.unwrap();
+        server.write_all(&[0u8; 32]).await.unwrap();
+        assert!(client.receive_frame().await.unwrap().is_none());
+        assert_eq!(client.receive_frame().await.unwrap().unwrap().header.width, 4);
+        assert!(client.is_connected().await);
+        assert_eq!(state.read().await.resyncs, 1);
+    }
+    
+    /// Next request from the client, read as the server would
+    async fn read_command(server: &mut TcpStream) -> Command {
+        let mut header = [0u8; HEADER_SIZE];
+        server.read_exact(&mut header).await.unwrap();
+        let header = PacketHeader::from_bytes(&header).unwrap();
+        let mut payload = vec![0u8; header.size as usize];
+        server.read_exact(&mut payload).await.unwrap();
+        Command::from_payload(header.packet_type, &payload, header.byte_order).unwrap()
+    }
+    
+    /// The server's side of the Noise handshake with `key`, returning the
+    /// keys for its records
+    async fn accept_noise(server: &mut TcpStream, key: &[u8]) -> snow::StatelessTransportState {
+        let mut prologue = vec![0u8; HEADER_SIZE];
+        server.read_exact(&mut prologue).await.unwrap();
+        let header = PacketHeader::from_bytes(&prologue).unwrap();
+        prologue.resize(HEADER_SIZE + header.size as usize, 0);
+        server.read_exact(&mut prologue[HEADER_SIZE..]).await.unwrap();
+        let Command::Hello { capabilities, .. } =
+            Command::from_payload(header.packet_type, &prologue[HEADER_SIZE..], header.byte_order).unwrap()
+        else {
+            panic!("no hello")
+        };
+        assert!(capabilities.supports(Capabilities::NOISE));
+        let agreed = ServerMessage::Capabilities(Capabilities::NOISE).to_bytes();
+        server.write_all(&agreed).await.unwrap();
+        prologue.extend_from_slice(&agreed);
+        
+        let params = crate::protocol::NOISE_PATTERN.parse().unwrap();
+        let mut responder =
+            snow::Builder::new(params).local_private_key(key).prologue(&prologue).build_responder().unwrap();
+        let mut buf = [0u8; 256];
+        let Command::Noise { message } = read_command(server).await else { panic!("no handshake") };
+        responder.read_message(&message, &mut buf).unwrap();
+        let len = responder.write_message(&[], &mut buf).unwrap();
+        server.write_all(&ServerMessage::Noise { message: buf[..len].to_vec() }.to_bytes()).await.unwrap();
+        let Command::Noise { message } = read_command(server).await else { panic!("no handshake") };
+        responder.read_message(&message, &mut buf).unwrap();
+        responder.into_stateless_transport_mode().unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_noise_pins_server_key() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(AppState { noise_key: Some([3; 32]), ..AppState::default() }));
+        
+        let server = tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            let transport = accept_noise(&mut server, &[9; 32]).await;
+            
+            let heartbeat = ServerMessage::Heartbeat.to_bytes();
+            let mut record = vec![0u8; 2 + heartbeat.len() + 16];
+            let len = transport.write_message(0, &heartbeat, &mut record[2..]).unwrap();
+            record[..2].copy_from_slice(&(len as u16).to_be_bytes());
+            server.write_all(&record).await.unwrap();
+            
+            // Our request comes back as a record of its own
+            let mut length = [0u8; 2];
+            server.read_exact(&mut length).await.unwrap();
+            let mut record = vec![0u8; u16::from_be_bytes(length) as usize];
+            server.read_exact(&mut record).await.unwrap();
+            let mut plain = vec![0u8; record.len()];
+            let len = transport.read_message(0, &record, &mut plain).unwrap();
+            let header = PacketHeader::from_bytes(&plain[..len]).unwrap();
+            assert_eq!((header.packet_type, header.size, len), (PacketType::Heartbeat, 0, HEADER_SIZE));
+            listener
+        });
+        
+        let client = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap();
+        client.connect(&addr.to_string()).await.unwrap();
+        let heartbeat = client.receive_frame().await.unwrap().unwrap();
+        assert_eq!(heartbeat.header.packet_type, PacketType::Heartbeat);
+        client.send(&Command::Heartbeat).await.unwrap();
+        let listener = server.await.unwrap();
+        
+        // The same server with another key is refused
+        let server = tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            accept_noise(&mut server, &[10; 32]).await;
+        });
+        let error = client.connect(&addr.to_string()).await.unwrap_err();
+        assert!(error.to_string().contains("has changed"), "{}", error);
+        server.abort();
+    }
+    
+    #[tokio::test]
+    async fn test_session_mode_limits_input() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(AppState { session_mode: SessionMode::View, ..AppState::default() }));
+        
+        let server = tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            let Command::Hello { mode, .. } = read_command(&mut server).await else { panic!("no hello") };
+            assert_eq!(mode, SessionMode::View);
+            // The key pressed while viewing never comes
+            assert_eq!(read_command(&mut server).await, Command::SessionMode { mode: SessionMode::Full });
+            assert_eq!(read_command(&mut server).await, Command::Key { code: 30, pressed: true });
+            server.write_all(&ServerMessage::SessionMode(SessionMode::Input).to_bytes()).await.unwrap();
+            server
+        });
+        
+        let client = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap();
+        client.connect(&addr.to_string()).await.unwrap();
+        let key = Command::Key { code: 30, pressed: true };
+        client.send_input(&key).await.unwrap();
+        client.send_input(&Command::SessionMode { mode: SessionMode::Full }).await.unwrap();
+        client.send_input(&key).await.unwrap();
+        let _server = server.await.unwrap();
+        
+        // The server allows less than asked
+        let reply = client.receive_frame().await.unwrap().unwrap();
+        assert_eq!(reply.header.packet_type, PacketType::SessionMode);
+        let state = state.read().await;
+        assert_eq!((state.session_mode, state.effective_session_mode()), (SessionMode::Full, SessionMode::Input));
+    }
+    
+    #[tokio::test]
+    async fn test_input_control_holds_back_input() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(AppState::default()));
+        let held = InputControl { owner: 4, address: [10, 0, 0, 5], ..InputControl::default() };
+        
+        let server = tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            let Command::Hello { capabilities, .. } = read_command(&mut server).await else { panic!("no hello") };
+            assert!(capabilities.supports(Capabilities::INPUT_CONTROL));
+            server.write_all(&ServerMessage::InputControl(held).to_bytes()).await.unwrap();
+            // The key pressed while another client drives never comes
+            assert_eq!(read_command(&mut server).await, Command::InputControl { action: InputControlAction::Steal });
+            let taken = InputControl { owner: 6, yours: true, ..held };
+            server.write_all(&ServerMessage::InputControl(taken).to_bytes()).await.unwrap();
+            assert_eq!(read_command(&mut server).await, Command::Key { code: 30, pressed: true });
+            server
+        });
+        
+        let client = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap();
+        client.connect(&addr.to_string()).await.unwrap();
+        let key = Command::Key { code: 30, pressed: true };
+        assert_eq!(client.receive_frame().await.unwrap().unwrap().header.packet_type, PacketType::InputControl);
+        assert_eq!(control_holder(&state.read().await.input_control.unwrap()), "10.0.0.5");
+        client.send_input(&key).await.unwrap();
+        client.send_input(&Command::InputControl { action: InputControlAction::Steal }).await.unwrap();
+        assert_eq!(client.receive_frame().await.unwrap().unwrap().header.packet_type, PacketType::InputControl);
+        assert_eq!(control_holder(&state.read().await.input_control.unwrap()), "you");
+        client.send_input(&key).await.unwrap();
+        let _server = server.await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_resume_after_reconnect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(RwLock::new(AppState::default()));
+        let token = [9; RESUME_TOKEN_SIZE];
+        
+        let server = tokio::spawn(async move {
+            // Without a credential there is no session to resume
+            let (mut server, _) = listener.accept().await.unwrap();
+            let Command::Hello { capabilities, .. } = read_command(&mut server).await else { panic!("no hello") };
+            assert!(!capabilities.supports(Capabilities::RESUME));
+            
+            let (mut server, _) = listener.accept().await.unwrap();
+            let Command::Hello { capabilities, .. } = read_command(&mut server).await else { panic!("no hello") };
+            assert!(capabilities.supports(Capabilities::RESUME));
+            server.write_all(&ServerMessage::Resume { token, resumed: false }.to_bytes()).await.unwrap();
+            
+            // The same session over a new connection
+            let (mut server, _) = listener.accept().await.unwrap();
+            assert!(matches!(read_command(&mut server).await, Command::Hello { .. }));
+            assert_eq!(read_command(&mut server).await, Command::Resume { token });
+            server.write_all(&ServerMessage::Resume { token, resumed: true }.to_bytes()).await.unwrap();
+            server
+        });
+        
+        let (messages, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let client = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap().with_status_messages(messages);
+        client.connect(&addr.to_string()).await.unwrap();
+        state.write().await.auth_providers.push(Arc::new(TokenAuth::new("correct horse").unwrap()));
+        client.connect(&addr.to_string()).await.unwrap();
+        assert_eq!(client.receive_frame().await.unwrap().unwrap().header.packet_type, PacketType::Resume);
+        client.connect(&addr.to_string()).await.unwrap();
+        assert_eq!(client.receive_frame().await.unwrap().unwrap().header.packet_type, PacketType::Resume);
+        assert_eq!(received.recv().await.unwrap(), "Resumed the session");
+        let _server = server.await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_skip_to_header() {
+        let header = PacketHeader::control(PacketType::Heartbeat, 0).to_bytes();
+        let mut data = vec![0xffu8; 10_000];
+        data.extend_from_slice(&header);
+        data.extend_from_slice(b"next");
+        
+        // The header found is read again, and what followed it
+        let mut stream = Pushback::new(&data[..]);
+        assert_eq!(skip_to_header(&mut stream, &[0x49, 0x50]).await.unwrap(), 10_002);
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest[..header.len()], header[..]);
+        assert_eq!(&rest[header.len()..], b"next");
+        
+        let mut stream = Pushback::new(&[0xffu8; 100][..]);
+        assert!(skip_to_header(&mut stream, &[]).await.is_err());
+    }
+    
+    #[test]
+    fn test_address_families() {
+        assert_eq!(server_address("display", 8080), "display:8080");
+        assert_eq!(server_address("10.0.0.2", 8080), "10.0.0.2:8080");
+        assert_eq!(server_address("fe80::1", 8080), "[fe80::1]:8080");
+        assert_eq!(server_address("[::1]", 8080), "[::1]:8080");
+        assert_eq!(parse_server_address("display:8080"), Some(("display".to_string(), 8080)));
+        assert_eq!(parse_server_address("[fe80::1]:8080"), Some(("fe80::1".to_string(), 8080)));
+        assert_eq!(parse_server_address("display"), None);
+        
+        let v6 = |n: u16| SocketAddr::new(IpAddr::V6(std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, n)), 80);
+        let v4 = |n: u8| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, n)), 80);
+        assert_eq!(
+            interleave_families(vec![v6(1), v6(2), v6(3), v4(1)]),
+            vec![v6(1), v4(1), v6(2), v6(3)],
+        );
+        assert_eq!(interleave_families(vec![v4(1), v4(2), v6(1)]), vec![v4(1), v6(1), v4(2)]);
+    }
+    
+    #[tokio::test]
+    async fn test_connect_any_falls_back() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused = {
+            let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            closed.local_addr().unwrap()
+        };
+        let open = listener.local_addr().unwrap();
+        
+        // A refused address moves straight on to the next
+        let stream = connect_any(vec![refused, open], |_| Ok(TcpSocket::new_v4()?)).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+        assert!(connect_any(vec![refused], |_| Ok(TcpSocket::new_v4()?)).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_demo_server() {
+        let (tasks, shutdown) = (TaskTracker::new(), CancellationToken::new());
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let screen = Screen::capture(Box::new(DemoSource::new(DemoPattern::Bounce)), DEMO_FPS, &shutdown).unwrap();
+        let addr = ipdisp_server::start(addr, Service::new(screen), &tokio::runtime::Handle::current(), &tasks, &shutdown).unwrap();
+        
+        let state = Arc::new(RwLock::new(AppState { checksum: true, session_mode: SessionMode::View, ..AppState::default() }));
+        let client = NetworkClient::new(Arc::clone(&state), LinkPath::default()).unwrap();
+        client.connect(&addr.to_string()).await.unwrap();
+        
+        // The features agreed and the session mode, display info, then
+        // CRC-checked frames, the later ones compact
+        let reply = client.receive_frame().await.unwrap().unwrap();
+        assert_eq!(reply.header.packet_type, PacketType::Capabilities);
+        assert_eq!(state.read().await.capabilities, SERVER_CAPABILITIES);
+        let reply = client.receive_frame().await.unwrap().unwrap();
+        assert_eq!(reply.header.packet_type, PacketType::SessionMode);
+        assert_eq!(state.read().await.allowed_mode, SessionMode::View);
+        let info = client.receive_frame().await.unwrap().unwrap();
+        assert!(info.header.is_info_packet());
+        assert_eq!(state.read().await.display_width, DEMO_WIDTH);
+        let mut timestamps = Vec::new();
+        while timestamps.len() < 3 {
+            let Some(frame) = client.receive_frame().await.unwrap() else { continue };
+            assert_eq!((frame.header.width, frame.header.height), (DEMO_WIDTH, DEMO_HEIGHT));
+            assert!(frame.header.crc32.is_some() && frame.checksum_ok());
+            timestamps.push(frame.header.timestamp);
+        }
+        assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+        
+        // Paused, only heartbeats come until the stream resumes
+        client.send(&Command::Pause { paused: true }).await.unwrap();
+        loop {
+            let packet = client.receive_frame().await.unwrap();
+            if packet.is_some_and(|packet| packet.header.packet_type == PacketType::Heartbeat) {
+                break;
+            }
+        }
+        client.send(&Command::Pause { paused: false }).await.unwrap();
+        let frame = loop {
+            match client.receive_frame().await.unwrap() {
+                Some(frame) if frame.header.is_frame_packet() => break frame,
+                _ => {}
+            }
+        };
+        assert!(frame.header.timestamp > timestamps[2]);
+        
+        client.close().await.unwrap();
+        shutdown.cancel();
+        tasks.close();
+        tasks.wait().await;
     }
 }
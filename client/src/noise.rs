@@ -0,0 +1,555 @@
+// IP Display Client - Noise Transport
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Encryption without certificates, for `--noise`. The handshake is Noise
+//! XX (see `ipds_protocol::NOISE_PATTERN`), so each side learns the other's
+//! static key. There is nobody to vouch for the server's, so the first key
+//! seen for a server is trusted and kept in `KnownServers`, and a different
+//! one later is refused. `NoiseReader` and `NoiseWriter` turn the records
+//! that follow back into a byte stream for the network code.
+
+use anyhow::{Context as _, Result};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+use crate::pairing::{decode_hex, encode_hex, write_private};
+use crate::protocol::{NOISE_MAX_RECORD, NOISE_PATTERN, NOISE_TAG_SIZE};
+
+/// Bytes in a Curve25519 key
+pub const KEY_SIZE: usize = 32;
+
+/// Most plaintext one record holds
+const MAX_PLAIN: usize = NOISE_MAX_RECORD - NOISE_TAG_SIZE;
+
+/// Record length prefix
+const LENGTH_SIZE: usize = 2;
+
+/// `$XDG_CONFIG_HOME/ip-display-client/<name>`, falling back to `~/.config`
+fn config_path(name: &str) -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config.join("ip-display-client").join(name))
+}
+
+/// Our static key, `noise_key` in the config directory (hex), made on
+/// first use
+pub fn load_or_create_key() -> Result<[u8; KEY_SIZE]> {
+    let path = config_path("noise_key").ok_or_else(|| anyhow::anyhow!("No config directory for the Noise key"))?;
+    match fs::read_to_string(&path) {
+        Ok(contents) => decode_hex(contents.trim())
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| anyhow::anyhow!("Malformed Noise key in {}", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = Builder::new(NOISE_PATTERN.parse()?).generate_keypair()?;
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            write_private(&path, format!("{}\n", encode_hex(&keypair.private)).as_bytes())
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            Ok(keypair.private.try_into().expect("Curve25519 keys are 32 bytes"))
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Servers' static keys as first seen, one `server:port key` line each
+/// (hex)
+#[derive(Debug, Clone, Default)]
+pub struct KnownServers {
+    path: Option<PathBuf>,
+    keys: HashMap<String, [u8; KEY_SIZE]>,
+}
+
+impl KnownServers {
+    /// `$XDG_CONFIG_HOME/ip-display-client/known_servers`, falling back to
+    /// `~/.config`
+    pub fn default_path() -> Option<PathBuf> {
+        config_path("known_servers")
+    }
+
+    /// Load from `path`; a missing file knows no servers
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut keys = HashMap::new();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            let parsed = (|| {
+                let server = fields.next()?;
+                let key = decode_hex(fields.next()?)?.try_into().ok()?;
+                Some((server.to_string(), key))
+            })();
+            match parsed {
+                Some((server, key)) => {
+                    keys.insert(server, key);
+                }
+                None => return Err(anyhow::anyhow!("Malformed line in {}", path.display())),
+            }
+        }
+
+        Ok(Self { path: Some(path), keys })
+    }
+
+    /// Accept `key` for `server` if it's the one seen before, or if
+    /// there's none yet, which is then remembered
+    pub fn check(&mut self, server: &str, key: &[u8; KEY_SIZE]) -> Result<()> {
+        match self.keys.get(server) {
+            Some(known) if known == key => return Ok(()),
+            Some(_) => {
+                let file = self.path.as_ref().map_or("the known servers".to_string(), |path| path.display().to_string());
+                return Err(anyhow::anyhow!(
+                    "{}'s Noise key has changed to {}; if that's expected, delete its line from {}",
+                    server, encode_hex(key), file
+                ));
+            }
+            None => {}
+        }
+
+        warn!("Trusting {}'s Noise key {} on first use", server, encode_hex(key));
+        self.keys.insert(server.to_string(), *key);
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut contents = String::new();
+        for (server, key) in &self.keys {
+            contents.push_str(&format!("{} {}\n", server, encode_hex(key)));
+        }
+        write_private(path, contents.as_bytes())
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Our side of the handshake: `start`, then `read_reply` with the server's
+/// answer, then `finish`
+#[derive(Debug)]
+pub struct Handshake {
+    state: HandshakeState,
+}
+
+impl Handshake {
+    /// `prologue` is our HELLO and the server's CAPABILITIES, byte for byte
+    /// as they went over the wire, so tampering with either fails the
+    /// handshake
+    pub fn new(static_key: &[u8; KEY_SIZE], prologue: &[u8]) -> Result<Self> {
+        let state = Builder::new(NOISE_PATTERN.parse()?)
+            .local_private_key(static_key)
+            .prologue(prologue)
+            .build_initiator()?;
+        Ok(Self { state })
+    }
+
+    /// Our first message, sent in a NOISE packet
+    pub fn start(&mut self) -> Result<Vec<u8>> {
+        let mut message = vec![0u8; NOISE_MAX_RECORD];
+        let len = self.state.write_message(&[], &mut message)?;
+        message.truncate(len);
+        Ok(message)
+    }
+
+    /// Take the server's reply, returning its static key
+    pub fn read_reply(&mut self, reply: &[u8]) -> Result<[u8; KEY_SIZE]> {
+        let mut payload = vec![0u8; NOISE_MAX_RECORD];
+        self.state
+            .read_message(reply, &mut payload)
+            .map_err(|e| anyhow::anyhow!("Bad Noise reply from the server: {}", e))?;
+        let key = self.state.get_remote_static().ok_or_else(|| anyhow::anyhow!("Server sent no Noise key"))?;
+        Ok(key.try_into()?)
+    }
+
+    /// Our last message, and the keys for the records after it
+    pub fn finish(mut self) -> Result<(Vec<u8>, Arc<StatelessTransportState>)> {
+        let mut message = vec![0u8; NOISE_MAX_RECORD];
+        let len = self.state.write_message(&[], &mut message)?;
+        message.truncate(len);
+        Ok((message, Arc::new(self.state.into_stateless_transport_mode()?)))
+    }
+}
+
+/// Decrypts the records on `inner` into one stream
+#[derive(Debug)]
+pub struct NoiseReader<R> {
+    inner: R,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+    /// The record being read; the first `filled` bytes are in
+    record: Vec<u8>,
+    filled: usize,
+    /// Plaintext of the last record, handed out from `offset`
+    plain: Vec<u8>,
+    offset: usize,
+}
+
+impl<R> NoiseReader<R> {
+    pub fn new(inner: R, transport: Arc<StatelessTransportState>) -> Self {
+        Self {
+            inner,
+            transport,
+            nonce: 0,
+            record: vec![0; LENGTH_SIZE + NOISE_MAX_RECORD],
+            filled: 0,
+            plain: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for NoiseReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.offset == this.plain.len() {
+            let needed = if this.filled < LENGTH_SIZE {
+                LENGTH_SIZE
+            } else {
+                LENGTH_SIZE + u16::from_be_bytes([this.record[0], this.record[1]]) as usize
+            };
+            if this.filled < needed {
+                let mut part = ReadBuf::new(&mut this.record[this.filled..needed]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut part))?;
+                let read = part.filled().len();
+                if read == 0 {
+                    // A clean end between records is an ordinary EOF
+                    if this.filled == 0 {
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.filled += read;
+                continue;
+            }
+            if needed < LENGTH_SIZE + NOISE_TAG_SIZE {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "Noise record too short")));
+            }
+
+            this.plain.resize(needed - LENGTH_SIZE, 0);
+            let len = this
+                .transport
+                .read_message(this.nonce, &this.record[LENGTH_SIZE..needed], &mut this.plain)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "forged or corrupt Noise record"))?;
+            this.plain.truncate(len);
+            this.nonce += 1;
+            this.filled = 0;
+            this.offset = 0;
+        }
+
+        let n = (this.plain.len() - this.offset).min(buf.remaining());
+        buf.put_slice(&this.plain[this.offset..this.offset + n]);
+        this.offset += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Sends each write, up to a record's worth, as one record on `inner`
+#[derive(Debug)]
+pub struct NoiseWriter<W> {
+    inner: W,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+    /// Sealed bytes not yet accepted by `inner`
+    pending: Vec<u8>,
+    sent: usize,
+}
+
+impl<W> NoiseWriter<W> {
+    pub fn new(inner: W, transport: Arc<StatelessTransportState>) -> Self {
+        Self { inner, transport, nonce: 0, pending: Vec::new(), sent: 0 }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> NoiseWriter<W> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.sent < self.pending.len() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.sent..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sent += written;
+        }
+        self.pending.clear();
+        self.sent = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for NoiseWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+
+        // The server reads each request from a record of its own
+        let plain = &buf[..buf.len().min(MAX_PLAIN)];
+        this.pending.resize(LENGTH_SIZE + plain.len() + NOISE_TAG_SIZE, 0);
+        let len = this
+            .transport
+            .write_message(this.nonce, plain, &mut this.pending[LENGTH_SIZE..])
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        this.pending[..LENGTH_SIZE].copy_from_slice(&(len as u16).to_be_bytes());
+        this.nonce += 1;
+        // Accepted once sealed; the bytes go out on the next write or flush
+        Poll::Ready(Ok(plain.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+    use chacha20poly1305::ChaCha20Poly1305;
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+    
+    use crate::protocol::{Capabilities, Command, ServerMessage, SessionMode, HEADER_SIZE};
+
+    /// Both ends of a finished handshake: ours and the server's transport
+    fn handshake() -> (Arc<StatelessTransportState>, Arc<StatelessTransportState>) {
+        let params = NOISE_PATTERN.parse().unwrap();
+        let server_key = Builder::new(NOISE_PATTERN.parse().unwrap()).generate_keypair().unwrap();
+        let mut server = Builder::new(params)
+            .local_private_key(&server_key.private)
+            .prologue(b"hello")
+            .build_responder()
+            .unwrap();
+        let client_key = Builder::new(NOISE_PATTERN.parse().unwrap()).generate_keypair().unwrap();
+        let mut client = Handshake::new(&client_key.private.try_into().unwrap(), b"hello").unwrap();
+
+        let mut buf = [0u8; 256];
+        server.read_message(&client.start().unwrap(), &mut buf).unwrap();
+        let len = server.write_message(&[], &mut buf).unwrap();
+        assert_eq!(client.read_reply(&buf[..len]).unwrap()[..], server_key.public[..]);
+        let (last, transport) = client.finish().unwrap();
+        server.read_message(&last, &mut buf).unwrap();
+        (transport, Arc::new(server.into_stateless_transport_mode().unwrap()))
+    }
+
+    fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+        let mut hash = Sha256::new();
+        for part in parts {
+            hash.update(part);
+        }
+        hash.finalize().into()
+    }
+    
+    fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+    
+    fn seal(key: &[u8; 32], n: u64, ad: &[u8], plain: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&n.to_le_bytes());
+        ChaCha20Poly1305::new(key.into()).encrypt(&nonce.into(), Payload { msg: plain, aad: ad }).unwrap()
+    }
+    
+    fn open(key: &[u8; 32], n: u64, ad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&n.to_le_bytes());
+        ChaCha20Poly1305::new(key.into()).decrypt(&nonce.into(), Payload { msg: sealed, aad: ad }).ok()
+    }
+    
+    /// The server's side as ipdisp_noise.c takes it, step for step, rather
+    /// than snow's, so the two implementations are checked against each other
+    struct KernelResponder {
+        h: [u8; 32],
+        ck: [u8; 32],
+        k: [u8; 32],
+        n: u64,
+        e: [u8; 32],
+        secret: [u8; 32],
+    }
+    
+    impl KernelResponder {
+        /// ipdisp_noise_start, with the prologue in the pieces it has them
+        fn start(secret: [u8; 32], prologue: &[&[u8]]) -> Self {
+            let name: [u8; 32] = NOISE_PATTERN.as_bytes().try_into().unwrap();
+            let mut parts = vec![&name[..]];
+            parts.extend_from_slice(prologue);
+            Self { h: sha256(&parts), ck: name, k: [0; 32], n: 0, e: [0x42; 32], secret }
+        }
+        
+        fn mix_hash(&mut self, data: &[u8]) {
+            self.h = sha256(&[&self.h, data]);
+        }
+        
+        fn hkdf(&self, ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+            let temp = hmac(&self.ck, ikm);
+            let out1 = hmac(&temp, &[1]);
+            let out2 = hmac(&temp, &[&out1[..], &[2]].concat());
+            (out1, out2)
+        }
+        
+        fn mix_dh(&mut self, secret: [u8; 32], public: [u8; 32]) {
+            (self.ck, self.k) = self.hkdf(&x25519(secret, public));
+            self.n = 0;
+        }
+        
+        fn encrypt_and_hash(&mut self, plain: &[u8]) -> Vec<u8> {
+            let sealed = seal(&self.k, self.n, &self.h, plain);
+            self.n += 1;
+            self.mix_hash(&sealed);
+            sealed
+        }
+        
+        fn decrypt_and_hash(&mut self, sealed: &[u8]) -> Option<Vec<u8>> {
+            let plain = open(&self.k, self.n, &self.h, sealed)?;
+            self.n += 1;
+            self.mix_hash(sealed);
+            Some(plain)
+        }
+        
+        /// ipdisp_noise_reply: e, ee, s, es
+        fn reply(&mut self, message: &[u8]) -> Vec<u8> {
+            let re: [u8; 32] = message.try_into().unwrap();
+            self.mix_hash(&re);
+            self.mix_hash(&[]);
+            let e = x25519(self.e, X25519_BASEPOINT_BYTES);
+            self.mix_hash(&e);
+            self.mix_dh(self.e, re);
+            let mut reply = e.to_vec();
+            reply.extend(self.encrypt_and_hash(&x25519(self.secret, X25519_BASEPOINT_BYTES)));
+            self.mix_dh(self.secret, re);
+            reply.extend(self.encrypt_and_hash(&[]));
+            reply
+        }
+        
+        /// ipdisp_noise_finish: s, se, then the client's sending key and ours
+        fn finish(&mut self, message: &[u8]) -> Option<([u8; 32], [u8; 32])> {
+            let rs: [u8; 32] = self.decrypt_and_hash(&message[..KEY_SIZE + NOISE_TAG_SIZE])?.try_into().ok()?;
+            self.mix_dh(self.e, rs);
+            self.decrypt_and_hash(&message[KEY_SIZE + NOISE_TAG_SIZE..])?;
+            Some(self.hkdf(&[]))
+        }
+    }
+    
+    #[test]
+    fn test_kernel_transcript() {
+        let hello = Command::Hello {
+            refresh_mhz: 60000,
+            session_id: 7,
+            link_mode: 0,
+            capabilities: Capabilities::NOISE,
+            mode: SessionMode::View,
+        }
+        .to_bytes();
+        let agreed = ServerMessage::Capabilities(Capabilities::NOISE).to_bytes();
+        let prologue = [&hello[..], &agreed[..]].concat();
+        let server_secret = [9u8; KEY_SIZE];
+        
+        // The kernel hashes the header, the payload and the capabilities
+        // separately; together they are what we hash in one go
+        let mut server =
+            KernelResponder::start(server_secret, &[&hello[..HEADER_SIZE], &hello[HEADER_SIZE..], &agreed]);
+        let mut client = Handshake::new(&[3; KEY_SIZE], &prologue).unwrap();
+        let reply = server.reply(&client.start().unwrap());
+        assert_eq!(client.read_reply(&reply).unwrap(), x25519(server_secret, X25519_BASEPOINT_BYTES));
+        let hash = client.state.get_handshake_hash().to_vec();
+        assert_eq!(hash, server.h);
+        let (last, transport) = client.finish().unwrap();
+        let (recv_key, send_key) = server.finish(&last).unwrap();
+        
+        // Each side reads what the other sealed
+        let mut sealed = [0u8; 64];
+        let len = transport.write_message(0, b"IPDS", &mut sealed).unwrap();
+        assert_eq!(open(&recv_key, 0, &[], &sealed[..len]).unwrap(), b"IPDS");
+        let mut plain = [0u8; 64];
+        let len = transport.read_message(0, &seal(&send_key, 0, &[], b"more"), &mut plain).unwrap();
+        assert_eq!(&plain[..len], b"more");
+        
+        // A capability stripped on the way breaks the handshake
+        let stripped = ServerMessage::Capabilities(Capabilities::empty()).to_bytes();
+        let mut server = KernelResponder::start(server_secret, &[&hello[..], &stripped[..]]);
+        let mut client = Handshake::new(&[3; KEY_SIZE], &prologue).unwrap();
+        let reply = server.reply(&client.start().unwrap());
+        assert!(client.read_reply(&reply).is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_records_round_trip() {
+        let (client, server) = handshake();
+
+        let mut wire = Vec::new();
+        let mut writer = NoiseWriter::new(&mut wire, Arc::clone(&client));
+        writer.write_all(b"hello").await.unwrap();
+        // Too big for one record, so it takes two
+        writer.write_all(&[7u8; MAX_PLAIN + 10]).await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(u16::from_be_bytes([wire[0], wire[1]]) as usize, 5 + NOISE_TAG_SIZE);
+
+        // The server decrypts what we send with its own nonces; our reader
+        // only takes what it sends
+        let mut plain = [0u8; 16];
+        let len = server.read_message(0, &wire[LENGTH_SIZE..LENGTH_SIZE + 5 + NOISE_TAG_SIZE], &mut plain).unwrap();
+        assert_eq!(&plain[..len], b"hello");
+        assert!(NoiseReader::new(&wire[..], Arc::clone(&client)).read_to_end(&mut Vec::new()).await.is_err());
+
+        let mut sealed = [0u8; 64];
+        let mut wire = Vec::new();
+        for (nonce, message) in [&b"IPDS"[..], b"more"].into_iter().enumerate() {
+            let len = server.write_message(nonce as u64, message, &mut sealed).unwrap();
+            wire.extend_from_slice(&(len as u16).to_be_bytes());
+            wire.extend_from_slice(&sealed[..len]);
+        }
+        let mut received = Vec::new();
+        NoiseReader::new(&wire[..], Arc::clone(&client)).read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"IPDSmore");
+
+        // A flipped bit or a cut-off record is an error
+        let mut forged = wire.clone();
+        forged[5] ^= 1;
+        assert!(NoiseReader::new(&forged[..], Arc::clone(&client)).read_to_end(&mut Vec::new()).await.is_err());
+        let truncated = &wire[..wire.len() - 1];
+        assert!(NoiseReader::new(truncated, client).read_to_end(&mut Vec::new()).await.is_err());
+    }
+
+    #[test]
+    fn test_known_servers_pin_keys() {
+        let dir = std::env::temp_dir().join(format!("ipdisp-known-servers-{}", std::process::id()));
+        let path = dir.join("known_servers");
+        let _ = fs::remove_file(&path);
+
+        let mut known = KnownServers::load(path.clone()).unwrap();
+        known.check("display:7878", &[1; KEY_SIZE]).unwrap();
+        known.check("display:7878", &[1; KEY_SIZE]).unwrap();
+
+        // Remembered across runs, and a new key for the same server is
+        // refused while other servers are unaffected
+        let mut known = KnownServers::load(path.clone()).unwrap();
+        let error = known.check("display:7878", &[2; KEY_SIZE]).unwrap_err();
+        assert!(error.to_string().contains("has changed"));
+        known.check("other:7878", &[2; KEY_SIZE]).unwrap();
+        assert_eq!(KnownServers::load(path).unwrap().keys.len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+// IP Display Client - On-Screen Display
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Short messages drawn over the stream, such as "Connected" or
+//! "Screenshot saved", for the moments nothing else can show them: the
+//! status bar is gone in fullscreen and borderless windows. Each message
+//! stays up for a few seconds, then fades out; the newest is at the bottom.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+/// How long a message is shown in full
+pub const OSD_HOLD: Duration = Duration::from_millis(2500);
+
+/// How long a message takes to fade out after that
+pub const OSD_FADE: Duration = Duration::from_millis(500);
+
+/// Most messages shown at once; older ones make way
+const MAX_MESSAGES: usize = 3;
+
+/// Messages waiting to fade out
+#[derive(Debug, Default)]
+pub struct Osd {
+    /// Text and when it was posted, oldest first
+    messages: VecDeque<(String, Instant)>,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Show `text` from `now`; the same text again starts it over
+    pub fn post(&mut self, text: &str, now: Instant) {
+        self.messages.retain(|(shown, _)| shown != text);
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((text.to_string(), now));
+    }
+
+    /// Whether anything is left to draw
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Messages still up at `now` and how opaque each is, forgetting the
+    /// rest
+    pub fn visible(&mut self, now: Instant) -> Vec<(&str, f64)> {
+        self.messages.retain(|(_, posted)| opacity(now.saturating_duration_since(*posted)).is_some());
+        self.messages
+            .iter()
+            .filter_map(|(text, posted)| Some((text.as_str(), opacity(now.saturating_duration_since(*posted))?)))
+            .collect()
+    }
+
+    /// Draw the messages still up at `now` along the bottom of a `width` ×
+    /// `height` area
+    pub fn draw(&mut self, context: &cairo::Context, width: i32, height: i32, now: Instant) -> Result<()> {
+        let messages = self.visible(now);
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        context.save()?;
+        context.select_font_face("Sans", cairo::FontSlant::Normal, cairo::FontWeight::Bold);
+        context.set_font_size((height as f64 / 30.0).max(14.0));
+        let line = context.font_extents()?.height();
+        let padding = line / 3.0;
+        let mut bottom = height as f64 - line;
+        for (text, opacity) in messages.into_iter().rev() {
+            let extents = context.text_extents(text)?;
+            let (box_width, box_height) = (extents.x_advance() + padding * 2.0, line + padding);
+            let (x, y) = ((width as f64 - box_width) / 2.0, bottom - box_height);
+            rounded_rectangle(context, x, y, box_width, box_height, padding);
+            context.set_source_rgba(0.0, 0.0, 0.0, 0.6 * opacity);
+            context.fill()?;
+
+            context.set_source_rgba(1.0, 1.0, 1.0, opacity);
+            context.move_to(x + padding, y + box_height / 2.0 - (extents.y_bearing() + extents.height() / 2.0));
+            context.show_text(text)?;
+            bottom = y - padding;
+        }
+        context.restore()?;
+        Ok(())
+    }
+}
+
+/// How opaque a message `age` old is; `None` once it has faded out
+fn opacity(age: Duration) -> Option<f64> {
+    let fading = age.saturating_sub(OSD_HOLD);
+    (fading < OSD_FADE).then(|| 1.0 - fading.as_secs_f64() / OSD_FADE.as_secs_f64())
+}
+
+fn rounded_rectangle(context: &cairo::Context, x: f64, y: f64, width: f64, height: f64, radius: f64) {
+    use std::f64::consts::{FRAC_PI_2, PI};
+    context.new_sub_path();
+    context.arc(x + width - radius, y + radius, radius, -FRAC_PI_2, 0.0);
+    context.arc(x + width - radius, y + height - radius, radius, 0.0, FRAC_PI_2);
+    context.arc(x + radius, y + height - radius, radius, FRAC_PI_2, PI);
+    context.arc(x + radius, y + radius, radius, PI, PI + FRAC_PI_2);
+    context.close_path();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fade() {
+        let now = Instant::now();
+        let mut osd = Osd::new();
+        osd.post("Connected", now);
+        assert_eq!(osd.visible(now + OSD_HOLD), vec![("Connected", 1.0)]);
+        assert_eq!(osd.visible(now + OSD_HOLD + OSD_FADE / 2), vec![("Connected", 0.5)]);
+        assert!(osd.visible(now + OSD_HOLD + OSD_FADE).is_empty());
+        assert!(osd.is_empty());
+    }
+
+    #[test]
+    fn test_queue() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        let mut osd = Osd::new();
+        for text in ["one", "two", "three", "two"] {
+            osd.post(text, now);
+        }
+        osd.post("three", later);
+        let texts = |osd: &mut Osd, at| osd.visible(at).into_iter().map(|(text, _)| text.to_string()).collect::<Vec<_>>();
+        assert_eq!(texts(&mut osd, now), ["one", "two", "three"]);
+
+        // Posting again moves it to the bottom and starts it over
+        osd.post("four", later);
+        assert_eq!(texts(&mut osd, later), ["two", "three", "four"]);
+        assert_eq!(texts(&mut osd, now + OSD_HOLD + OSD_FADE), ["three", "four"]);
+    }
+}
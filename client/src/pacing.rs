@@ -0,0 +1,322 @@
+// IP Display Client - Frame Pacing
+// Copyright (c) 2024
+// Licensed under MIT
+
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::protocol::FrameData;
+use crate::timesync::{self, ClockEstimate};
+
+/// Frames the smooth policy may hold back to even out arrival jitter
+pub const SMOOTH_QUEUE_DEPTH: usize = 2;
+
+/// Frames the cadence policy may hold while a burst plays out
+pub const CADENCE_QUEUE_DEPTH: usize = 8;
+
+/// Frames the sync policy may hold; covers a 250 ms server delay at 60 Hz
+pub const SYNC_QUEUE_DEPTH: usize = 16;
+
+/// Default extra delay added to every frame under the cadence policy, long
+/// enough to absorb a couple of frames of network jitter at 60 Hz
+pub const DEFAULT_PLAYOUT_DELAY: Duration = Duration::from_millis(33);
+
+/// A frame arriving this much later than the fastest one seen means the
+/// sender's clock restarted or the path changed; start over from it
+const RESYNC_THRESHOLD_NS: i128 = 250_000_000;
+
+/// What to favour when frames don't line up with the display refresh
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum PacingPreference {
+    /// Show the newest frame on every refresh, dropping any that were
+    /// superseded in between
+    #[default]
+    Latency,
+    /// Show frames in order, one per refresh, at the cost of up to
+    /// `SMOOTH_QUEUE_DEPTH` refreshes of extra delay
+    Smooth,
+    /// Show each frame at its sender timestamp plus a fixed playout delay,
+    /// so bursts from TCP are spread back out to their original cadence
+    Cadence,
+    /// Show each frame at the deadline the server publishes, so every
+    /// client that asks presents it at the same moment. Until the server
+    /// has published one, and the clocks are synchronised, like `Cadence`.
+    Sync,
+}
+
+/// Maps sender timestamps onto the local clock. Without a shared clock the
+/// fastest transit seen so far stands in for the offset between the two, so
+/// a frame is due `delay` after the time it would have arrived with no
+/// queueing anywhere along the way.
+#[derive(Debug)]
+pub struct PlayoutClock {
+    epoch: Instant,
+    min_transit_ns: Option<i128>,
+    delay: Duration,
+}
+
+impl PlayoutClock {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            epoch: Instant::now(),
+            min_transit_ns: None,
+            delay,
+        }
+    }
+
+    /// Local time at which a frame stamped `sender_ns` that arrived at
+    /// `arrival` should be shown
+    pub fn due(&mut self, sender_ns: u64, arrival: Instant) -> Instant {
+        let local_ns = arrival.saturating_duration_since(self.epoch).as_nanos() as i128;
+        let transit = local_ns - sender_ns as i128;
+
+        let min_transit = match self.min_transit_ns {
+            Some(min) if transit >= min && transit - min < RESYNC_THRESHOLD_NS => min,
+            _ => transit,
+        };
+        self.min_transit_ns = Some(min_transit);
+
+        let ideal_ns = (sender_ns as i128 + min_transit).max(0) as u64;
+        self.epoch + Duration::from_nanos(ideal_ns) + self.delay
+    }
+}
+
+/// The server's playout delay and where its clock sits relative to ours
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncDeadline {
+    pub clock: ClockEstimate,
+    pub delay: Duration,
+}
+
+impl SyncDeadline {
+    /// Local time at which a frame stamped `sender_ns` is due everywhere
+    pub fn due(self, sender_ns: u64) -> Instant {
+        let deadline_ns = sender_ns.saturating_add(self.delay.as_nanos() as u64);
+        timesync::instant_at(self.clock.to_local_ns(deadline_ns))
+    }
+}
+
+/// Holds received frames until the next display refresh. Driven from the
+/// drawing area's tick callback when vsync is enabled.
+#[derive(Debug)]
+pub struct FrameScheduler {
+    preference: PacingPreference,
+    pending: VecDeque<(FrameData, Instant)>,
+    clock: PlayoutClock,
+    sync: Option<SyncDeadline>,
+    dropped: u64,
+}
+
+impl FrameScheduler {
+    pub fn new(preference: PacingPreference, playout_delay: Duration) -> Self {
+        Self {
+            preference,
+            pending: VecDeque::with_capacity(CADENCE_QUEUE_DEPTH + 1),
+            clock: PlayoutClock::new(playout_delay),
+            sync: None,
+            dropped: 0,
+        }
+    }
+
+    /// Follow the server's deadlines under the sync policy, once it has
+    /// published a delay and the clock offset is known
+    pub fn set_sync(&mut self, sync: Option<SyncDeadline>) {
+        self.sync = sync;
+    }
+
+    pub fn push(&mut self, frame: FrameData) {
+        let due = match (self.preference, self.sync) {
+            // Frames without a timestamp are shown as soon as possible
+            _ if frame.header.timestamp == 0 => frame.received,
+            (PacingPreference::Sync, Some(sync)) => sync.due(frame.header.timestamp),
+            (PacingPreference::Cadence | PacingPreference::Sync, _) => {
+                self.clock.due(frame.header.timestamp, frame.received)
+            }
+            _ => frame.received,
+        };
+        self.pending.push_back((frame, due));
+
+        let depth = match self.preference {
+            PacingPreference::Latency => 1,
+            PacingPreference::Smooth => SMOOTH_QUEUE_DEPTH,
+            PacingPreference::Cadence => CADENCE_QUEUE_DEPTH,
+            PacingPreference::Sync => SYNC_QUEUE_DEPTH,
+        };
+        while self.pending.len() > depth {
+            self.pending.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    /// Frame to present on the refresh at `now`, if one is ready. Under the
+    /// cadence and sync policies this is the newest frame already due; older
+    /// due frames came faster than the display refreshes and are dropped.
+    pub fn next_for_tick(&mut self, now: Instant) -> Option<FrameData> {
+        if !matches!(self.preference, PacingPreference::Cadence | PacingPreference::Sync) {
+            return self.pending.pop_front().map(|(frame, _)| frame);
+        }
+
+        let mut ready = None;
+        while matches!(self.pending.front(), Some((_, due)) if *due <= now) {
+            if ready.is_some() {
+                self.dropped += 1;
+            }
+            ready = self.pending.pop_front().map(|(frame, _)| frame);
+        }
+        ready
+    }
+
+    /// When the first frame waiting is due, for presenting it without
+    /// waiting for a refresh
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.front().map(|(_, due)| *due)
+    }
+
+    /// Forget the frames waiting, which a resize of the stream makes stale;
+    /// not counted as drops
+    pub fn discard_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Frames discarded because a newer one replaced them before a refresh
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{FrameFormat, PacketHeader};
+
+    fn frame(width: u32) -> FrameData {
+        let header = PacketHeader::new(width, 1, FrameFormat::Rgba32, width * 4);
+        FrameData::new(header, vec![0u8; width as usize * 4]).unwrap()
+    }
+
+    fn stamped(width: u32, sender_ms: u64, received: Instant) -> FrameData {
+        let mut frame = frame(width);
+        frame.header.timestamp = sender_ms * 1_000_000;
+        frame.received = received;
+        frame
+    }
+
+    #[test]
+    fn test_latency_presents_newest() {
+        let mut scheduler = FrameScheduler::new(PacingPreference::Latency, DEFAULT_PLAYOUT_DELAY);
+        let now = Instant::now();
+        scheduler.push(frame(1));
+        scheduler.push(frame(2));
+        scheduler.push(frame(3));
+
+        assert_eq!(scheduler.next_for_tick(now).unwrap().header.width, 3);
+        assert!(scheduler.next_for_tick(now).is_none());
+        assert_eq!(scheduler.dropped(), 2);
+    }
+
+    #[test]
+    fn test_smooth_presents_in_order() {
+        let mut scheduler = FrameScheduler::new(PacingPreference::Smooth, DEFAULT_PLAYOUT_DELAY);
+        let now = Instant::now();
+        scheduler.push(frame(1));
+        scheduler.push(frame(2));
+
+        // Two frames in one refresh interval are spread over two refreshes
+        assert_eq!(scheduler.next_for_tick(now).unwrap().header.width, 1);
+        scheduler.push(frame(3));
+        assert_eq!(scheduler.next_for_tick(now).unwrap().header.width, 2);
+        assert_eq!(scheduler.next_for_tick(now).unwrap().header.width, 3);
+        assert_eq!(scheduler.dropped(), 0);
+
+        // Bursts beyond the queue depth still drop the oldest
+        for width in 4..8 {
+            scheduler.push(frame(width));
+        }
+        assert_eq!(scheduler.dropped(), 2);
+        assert_eq!(scheduler.next_for_tick(now).unwrap().header.width, 6);
+
+        // A resize leaves nothing to present, without counting drops
+        scheduler.discard_pending();
+        assert!(scheduler.next_for_tick(now).is_none());
+        assert_eq!(scheduler.dropped(), 2);
+    }
+
+    #[test]
+    fn test_playout_clock_restores_cadence() {
+        let mut clock = PlayoutClock::new(Duration::ZERO);
+        let start = clock.epoch + Duration::from_secs(10);
+
+        // Sent 16 ms apart, the second one delayed 30 ms in transit
+        let first = clock.due(1_000_000_000, start);
+        let second = clock.due(1_016_000_000, start + ms(46));
+        assert_eq!(second - first, ms(16));
+
+        // A faster frame lowers the estimate for everything after it
+        let third = clock.due(1_032_000_000, start + ms(30));
+        assert_eq!(third, start + ms(30));
+
+        // A jump far beyond the threshold resynchronises
+        let late = start + Duration::from_secs(5);
+        assert_eq!(clock.due(1_048_000_000, late), late);
+    }
+
+    #[test]
+    fn test_cadence_spreads_burst() {
+        let mut scheduler = FrameScheduler::new(PacingPreference::Cadence, ms(20));
+        let start = Instant::now();
+
+        scheduler.push(stamped(1, 1000, start));
+        assert_eq!(scheduler.next_due(), Some(start + ms(20)));
+        assert!(scheduler.next_for_tick(start).is_none());
+        assert_eq!(scheduler.next_for_tick(start + ms(20)).unwrap().header.width, 1);
+        assert_eq!(scheduler.next_due(), None);
+
+        // Three frames sent 16 ms apart arrive together after a stall
+        for n in 1..4 {
+            scheduler.push(stamped(n as u32 + 1, 1000 + n * 16, start + ms(50)));
+        }
+        assert_eq!(scheduler.next_for_tick(start + ms(50)).unwrap().header.width, 2);
+        assert_eq!(scheduler.next_for_tick(start + ms(55)).unwrap().header.width, 3);
+        assert!(scheduler.next_for_tick(start + ms(60)).is_none());
+        assert_eq!(scheduler.next_for_tick(start + ms(70)).unwrap().header.width, 4);
+        assert_eq!(scheduler.dropped(), 0);
+
+        // A late tick shows only the newest due frame
+        scheduler.push(stamped(5, 1064, start + ms(70)));
+        scheduler.push(stamped(6, 1080, start + ms(75)));
+        assert_eq!(scheduler.next_for_tick(start + ms(120)).unwrap().header.width, 6);
+        assert_eq!(scheduler.dropped(), 1);
+    }
+
+    #[test]
+    fn test_sync_follows_server_deadline() {
+        let mut scheduler = FrameScheduler::new(PacingPreference::Sync, ms(20));
+        let start = Instant::now();
+        let local_ns = timesync::now_ns() as i64 / 1_000_000 * 1_000_000;
+
+        // Server clock 5 s ahead of ours, frames due 100 ms after capture
+        let clock = ClockEstimate { offset_ns: 5_000_000_000, rtt_ns: 1_000_000 };
+        scheduler.set_sync(Some(SyncDeadline { clock, delay: ms(100) }));
+        let captured = (local_ns + 5_000_000_000) as u64;
+        let due = timesync::instant_at(local_ns) + ms(100);
+
+        // However late it arrives, a frame waits for the shared deadline
+        scheduler.push(stamped(1, captured / 1_000_000, start));
+        scheduler.push(stamped(2, captured / 1_000_000 + 16, start + ms(60)));
+        assert!(scheduler.next_for_tick(due - ms(5)).is_none());
+        assert_eq!(scheduler.next_for_tick(due).unwrap().header.width, 1);
+        assert!(scheduler.next_for_tick(due + ms(10)).is_none());
+        assert_eq!(scheduler.next_for_tick(due + ms(16)).unwrap().header.width, 2);
+
+        // Without a deadline it falls back to cadence timing
+        scheduler.set_sync(None);
+        scheduler.push(stamped(3, 2000, start));
+        assert!(scheduler.next_for_tick(start).is_none());
+        assert_eq!(scheduler.next_for_tick(start + ms(20)).unwrap().header.width, 3);
+    }
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+}
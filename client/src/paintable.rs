@@ -0,0 +1,450 @@
+// IP Display Client - Stream Paintable
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! The remote display as a `GdkPaintable`, for showing the stream in a
+//! `GtkPicture`, `GtkVideo` or anything else that takes a paintable. GTK
+//! then does the scaling and the stream goes through the scene graph as a
+//! texture instead of being painted with Cairo.
+//!
+//! The exception is zooming with `ScaleFilter::Nearest`, or shrinking,
+//! which GTK's texture scaling can't do well before GTK 4.10. Those frames
+//! are drawn with Cairo.
+//!
+//! The stream can also be turned and mirrored (`Orientation`) for panels
+//! and capture sources mounted that way. That is a transform in the scene
+//! graph. Frames, thumbnails and snapshots stay as the server sent them,
+//! and so do they with picture `Adjustments`.
+
+use cairo::{Format, ImageSurface};
+use glib::subclass::prelude::*;
+use gtk4::gdk;
+use gtk4::gdk::prelude::*;
+use gtk4::gdk::subclass::prelude::*;
+use gtk4::{graphene, gsk, prelude::SnapshotExt};
+use tracing::warn;
+
+use crate::adjustments::Adjustments;
+use crate::renderer::FrameRenderer;
+
+/// How the stream is filtered when it is drawn at another size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ScaleFilter {
+    /// Nearest at whole-number zooms such as 200%, smooth otherwise
+    #[default]
+    Auto,
+    /// Nearest neighbour: sharp pixels, but uneven at fractional zooms
+    Nearest,
+    /// Interpolated: no shimmer at fractional zooms, softer text
+    Smooth,
+}
+
+impl ScaleFilter {
+    /// The filter to draw with at `zoom` (drawn size over frame size)
+    pub fn resolve(self, zoom_x: f64, zoom_y: f64) -> ScaleFilter {
+        let whole = |zoom: f64| zoom >= 1.0 && (zoom - zoom.round()).abs() < 0.01;
+        match self {
+            ScaleFilter::Auto if whole(zoom_x) && whole(zoom_y) => ScaleFilter::Nearest,
+            ScaleFilter::Auto => ScaleFilter::Smooth,
+            filter => filter,
+        }
+    }
+}
+
+/// How far the stream is turned clockwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Rotation {
+    #[default]
+    #[value(name = "0")]
+    None,
+    #[value(name = "90")]
+    Quarter,
+    #[value(name = "180")]
+    Half,
+    #[value(name = "270")]
+    ThreeQuarters,
+}
+
+impl Rotation {
+    pub const ALL: [Rotation; 4] = [Rotation::None, Rotation::Quarter, Rotation::Half, Rotation::ThreeQuarters];
+
+    pub fn degrees(self) -> u32 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Quarter => 90,
+            Rotation::Half => 180,
+            Rotation::ThreeQuarters => 270,
+        }
+    }
+
+    pub fn from_degrees(degrees: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|rotation| rotation.degrees() == degrees)
+    }
+
+    /// Whether width and height trade places
+    pub fn swaps_axes(self) -> bool {
+        matches!(self, Rotation::Quarter | Rotation::ThreeQuarters)
+    }
+}
+
+/// A mirror image, applied to the frame before it is turned
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Flip {
+    /// Left and right swap
+    Horizontal,
+    /// Top and bottom swap
+    Vertical,
+}
+
+/// How the stream is turned and mirrored on screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Orientation {
+    pub rotation: Rotation,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+}
+
+impl Orientation {
+    pub fn new(rotation: Rotation, flips: &[Flip]) -> Self {
+        Self {
+            rotation,
+            flip_horizontal: flips.contains(&Flip::Horizontal),
+            flip_vertical: flips.contains(&Flip::Vertical),
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Size of a `width` x `height` frame once oriented
+    pub fn size<T>(&self, width: T, height: T) -> (T, T) {
+        if self.rotation.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// Takes points of a `width` x `height` frame to where they are shown
+    pub fn matrix(&self, width: f64, height: f64) -> cairo::Matrix {
+        let mirror_x = if self.flip_horizontal { -1.0 } else { 1.0 };
+        let mirror_y = if self.flip_vertical { -1.0 } else { 1.0 };
+        let mirror = cairo::Matrix::new(
+            mirror_x,
+            0.0,
+            0.0,
+            mirror_y,
+            if self.flip_horizontal { width } else { 0.0 },
+            if self.flip_vertical { height } else { 0.0 },
+        );
+        // Clockwise on screen, where y points down
+        let turn = match self.rotation {
+            Rotation::None => cairo::Matrix::identity(),
+            Rotation::Quarter => cairo::Matrix::new(0.0, 1.0, -1.0, 0.0, height, 0.0),
+            Rotation::Half => cairo::Matrix::new(-1.0, 0.0, 0.0, -1.0, width, height),
+            Rotation::ThreeQuarters => cairo::Matrix::new(0.0, -1.0, 1.0, 0.0, 0.0, width),
+        };
+        cairo::Matrix::multiply(&mirror, &turn)
+    }
+
+    /// The point of a `width` x `height` frame shown at `x`, `y`
+    pub fn to_frame(&self, x: f64, y: f64, width: f64, height: f64) -> (f64, f64) {
+        // Turns and mirrors are always invertible
+        let mut matrix = self.matrix(width, height);
+        matrix.invert();
+        matrix.transform_point(x, y)
+    }
+}
+
+mod imp {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    #[derive(Debug, Default)]
+    pub struct StreamPaintable {
+        pub(super) texture: RefCell<Option<gdk::MemoryTexture>>,
+        pub(super) filter: Cell<ScaleFilter>,
+        pub(super) orientation: Cell<Orientation>,
+        pub(super) adjustments: Cell<Adjustments>,
+        /// Gamma as a lookup table, when it changes anything
+        pub(super) gamma: Cell<Option<[u8; 256]>>,
+        /// The last frame as the renderer drew it, to apply a new gamma to
+        pub(super) frame: RefCell<Option<ImageSurface>>,
+        /// The texture as a Cairo surface, made the first time a frame is
+        /// drawn with Cairo
+        pub(super) surface: RefCell<Option<ImageSurface>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for StreamPaintable {
+        const NAME: &'static str = "IpDisplayStreamPaintable";
+        type Type = super::StreamPaintable;
+        type Interfaces = (gdk::Paintable,);
+    }
+
+    impl ObjectImpl for StreamPaintable {}
+
+    impl PaintableImpl for StreamPaintable {
+        fn current_image(&self) -> gdk::Paintable {
+            match self.texture.borrow().as_ref() {
+                Some(texture) => texture.clone().upcast(),
+                None => gdk::Paintable::new_empty(0, 0),
+            }
+        }
+
+        fn intrinsic_width(&self) -> i32 {
+            self.oriented_size().0
+        }
+
+        fn intrinsic_height(&self) -> i32 {
+            self.oriented_size().1
+        }
+
+        fn intrinsic_aspect_ratio(&self) -> f64 {
+            match self.oriented_size() {
+                (width, height) if height > 0 => width as f64 / height as f64,
+                _ => 0.0,
+            }
+        }
+
+        fn snapshot(&self, snapshot: &gdk::Snapshot, width: f64, height: f64) {
+            let texture = self.texture.borrow();
+            let Some(texture) = texture.as_ref() else { return };
+
+            // Draw the frame upright at its unturned size, inside the
+            // orientation's transform and the colour adjustments
+            let orientation = self.orientation.get();
+            let gtk_snapshot = snapshot.downcast_ref::<gtk4::Snapshot>();
+            let color_matrix = self.adjustments.get().color_matrix();
+            if let (Some(gtk_snapshot), Some((matrix, offset))) = (gtk_snapshot, color_matrix) {
+                gtk_snapshot.push_color_matrix(&graphene::Matrix::from_float(matrix), &graphene::Vec4::from_float(offset));
+            }
+            let (width, height) = match gtk_snapshot {
+                Some(gtk_snapshot) if !orientation.is_identity() => {
+                    let (width, height) = orientation.size(width, height);
+                    let m = orientation.matrix(width, height);
+                    let matrix = graphene::Matrix::from_2d(m.xx(), m.yx(), m.xy(), m.yy(), m.x0(), m.y0());
+                    gtk_snapshot.save();
+                    gtk_snapshot.transform(Some(&gsk::Transform::new().matrix(&matrix)));
+                    (width, height)
+                }
+                _ => (width, height),
+            };
+            let (zoom_x, zoom_y) = (width / texture.width() as f64, height / texture.height() as f64);
+            let same_size = (zoom_x - 1.0).abs() < 0.01 && (zoom_y - 1.0).abs() < 0.01;
+            let filter = match self.filter.get().resolve(zoom_x, zoom_y) {
+                ScaleFilter::Nearest if !same_size => Some(cairo::Filter::Nearest),
+                ScaleFilter::Smooth if zoom_x < 1.0 || zoom_y < 1.0 => Some(cairo::Filter::Good),
+                _ => None,
+            };
+
+            match (filter, gtk_snapshot) {
+                (Some(filter), Some(gtk_snapshot)) => {
+                    if let Err(e) = self.snapshot_cairo(gtk_snapshot, texture, filter, width, height) {
+                        warn!("Failed to draw the frame: {}", e);
+                    }
+                }
+                _ => texture.snapshot(snapshot, width, height),
+            }
+            if let Some(gtk_snapshot) = gtk_snapshot.filter(|_| !orientation.is_identity()) {
+                gtk_snapshot.restore();
+            }
+            if let Some(gtk_snapshot) = gtk_snapshot.filter(|_| color_matrix.is_some()) {
+                gtk_snapshot.pop();
+            }
+        }
+    }
+
+    impl StreamPaintable {
+        fn oriented_size(&self) -> (i32, i32) {
+            match self.texture.borrow().as_ref() {
+                Some(texture) => self.orientation.get().size(texture.width(), texture.height()),
+                None => (0, 0),
+            }
+        }
+
+        fn snapshot_cairo(
+            &self,
+            snapshot: &gtk4::Snapshot,
+            texture: &gdk::MemoryTexture,
+            filter: cairo::Filter,
+            width: f64,
+            height: f64,
+        ) -> Result<(), cairo::Error> {
+            let mut surface = self.surface.borrow_mut();
+            if surface.is_none() {
+                *surface = Some(texture_surface(texture)?);
+            }
+            let Some(surface) = surface.as_ref() else { return Ok(()) };
+
+            let context = snapshot.append_cairo(&graphene::Rect::new(0.0, 0.0, width as f32, height as f32));
+            context.scale(width / texture.width() as f64, height / texture.height() as f64);
+            context.set_source_surface(surface, 0.0, 0.0)?;
+            context.source().set_filter(filter);
+            context.paint()
+        }
+    }
+}
+
+glib::wrapper! {
+    /// Shows whatever the `FrameRenderer` it was made for last drew. Each
+    /// frame is copied into a new texture, so holding on to
+    /// `current_image()` keeps that frame.
+    pub struct StreamPaintable(ObjectSubclass<imp::StreamPaintable>)
+        @implements gdk::Paintable;
+}
+
+impl StreamPaintable {
+    /// Follow `renderer`, starting from its current frame if it has one
+    pub fn new(renderer: &FrameRenderer) -> Self {
+        let paintable: Self = glib::Object::new();
+        if let Some(surface) = renderer.get_surface() {
+            paintable.set_frame(&surface);
+        }
+
+        let weak = paintable.downgrade();
+        renderer.connect_frame_updated(move |surface| {
+            if let Some(paintable) = weak.upgrade() {
+                paintable.set_frame(surface);
+            }
+        });
+        paintable
+    }
+
+    /// Filter frames with `filter` when drawn at another size
+    pub fn set_filter(&self, filter: ScaleFilter) {
+        if self.imp().filter.replace(filter) != filter {
+            self.invalidate_contents();
+        }
+    }
+
+    /// Turn and/or mirror the stream
+    pub fn set_orientation(&self, orientation: Orientation) {
+        if self.imp().orientation.replace(orientation) != orientation {
+            self.invalidate_size();
+            self.invalidate_contents();
+        }
+    }
+
+    /// Change brightness, contrast, gamma and saturation
+    pub fn set_adjustments(&self, adjustments: Adjustments) {
+        let imp = self.imp();
+        if imp.adjustments.replace(adjustments) == adjustments {
+            return;
+        }
+        let gamma = adjustments.gamma_table();
+        if imp.gamma.replace(gamma) != gamma {
+            let frame = imp.frame.borrow().clone();
+            if let Some(frame) = frame {
+                self.set_frame(&frame);
+            }
+        }
+        self.invalidate_contents();
+    }
+
+    fn set_frame(&self, surface: &ImageSurface) {
+        let (width, height) = (surface.width(), surface.height());
+        self.imp().frame.replace(Some(surface.clone()));
+
+        // Both surface formats the renderer uses are 32-bit native-endian
+        // premultiplied ARGB; Rgb24 rows always carry alpha 255
+        let (format, alpha) = if cfg!(target_endian = "little") {
+            (gdk::MemoryFormat::B8g8r8a8Premultiplied, 3)
+        } else {
+            (gdk::MemoryFormat::A8r8g8b8Premultiplied, 0)
+        };
+
+        let gamma = self.imp().gamma.get();
+        let mut bytes = None;
+        let read = surface.with_data(|data| {
+            bytes = Some(match gamma {
+                // Applied to premultiplied values, which is only exact for
+                // opaque pixels; streams rarely have any others
+                Some(table) => {
+                    let mut adjusted = data.to_vec();
+                    for pixel in adjusted.chunks_exact_mut(4) {
+                        for (i, value) in pixel.iter_mut().enumerate() {
+                            if i != alpha {
+                                *value = table[*value as usize];
+                            }
+                        }
+                    }
+                    glib::Bytes::from_owned(adjusted)
+                }
+                None => glib::Bytes::from(data),
+            })
+        });
+        if let Err(e) = read {
+            warn!("Failed to read the frame for the paintable: {}", e);
+            return;
+        }
+        let Some(bytes) = bytes else { return };
+        let texture = gdk::MemoryTexture::new(width, height, format, &bytes, surface.stride() as usize);
+
+        self.imp().surface.replace(None);
+        let resized = self
+            .imp()
+            .texture
+            .replace(Some(texture))
+            .is_none_or(|old| old.width() != width || old.height() != height);
+        if resized {
+            self.invalidate_size();
+        }
+        self.invalidate_contents();
+    }
+}
+
+/// Copy of `texture` Cairo can draw
+fn texture_surface(texture: &gdk::MemoryTexture) -> Result<ImageSurface, cairo::Error> {
+    let mut surface = ImageSurface::create(Format::ARgb32, texture.width(), texture.height())?;
+    let stride = surface.stride() as usize;
+    if let Ok(mut data) = surface.data() {
+        // Downloads are native-endian premultiplied ARGB, as Cairo wants
+        texture.download(&mut data, stride);
+    }
+    Ok(surface)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scale_filter() {
+        assert_eq!(ScaleFilter::Auto.resolve(1.0, 1.0), ScaleFilter::Nearest);
+        assert_eq!(ScaleFilter::Auto.resolve(2.0, 2.0), ScaleFilter::Nearest);
+        assert_eq!(ScaleFilter::Auto.resolve(2.004, 1.998), ScaleFilter::Nearest);
+        assert_eq!(ScaleFilter::Auto.resolve(1.37, 1.37), ScaleFilter::Smooth);
+        assert_eq!(ScaleFilter::Auto.resolve(2.0, 1.5), ScaleFilter::Smooth);
+        assert_eq!(ScaleFilter::Auto.resolve(0.5, 0.5), ScaleFilter::Smooth);
+        assert_eq!(ScaleFilter::Nearest.resolve(1.37, 1.37), ScaleFilter::Nearest);
+        assert_eq!(ScaleFilter::Smooth.resolve(2.0, 2.0), ScaleFilter::Smooth);
+    }
+
+    #[test]
+    fn test_orientation() {
+        // Where the top-left and top-right corners of a 40x30 frame go
+        let corners = |orientation: Orientation| {
+            let matrix = orientation.matrix(40.0, 30.0);
+            (matrix.transform_point(0.0, 0.0), matrix.transform_point(40.0, 0.0))
+        };
+        let turned = |degrees| Orientation::new(Rotation::from_degrees(degrees).unwrap(), &[]);
+        assert_eq!(corners(Orientation::default()), ((0.0, 0.0), (40.0, 0.0)));
+        assert_eq!(corners(turned(90)), ((30.0, 0.0), (30.0, 40.0)));
+        assert_eq!(corners(turned(180)), ((40.0, 30.0), (0.0, 30.0)));
+        assert_eq!(corners(turned(270)), ((0.0, 40.0), (0.0, 0.0)));
+        assert_eq!(corners(Orientation::new(Rotation::None, &[Flip::Horizontal])), ((40.0, 0.0), (0.0, 0.0)));
+        assert_eq!(corners(Orientation::new(Rotation::None, &[Flip::Vertical])), ((0.0, 30.0), (40.0, 30.0)));
+        // Mirrored first, then turned
+        assert_eq!(corners(Orientation::new(Rotation::Quarter, &[Flip::Horizontal])), ((30.0, 40.0), (30.0, 0.0)));
+
+        assert_eq!(turned(90).size(40, 30), (30, 40));
+        assert_eq!(turned(180).size(40, 30), (40, 30));
+        assert!(Rotation::from_degrees(45).is_none());
+
+        let orientation = Orientation::new(Rotation::ThreeQuarters, &[Flip::Vertical]);
+        let (x, y) = orientation.to_frame(10.0, 25.0, 40.0, 30.0);
+        assert!((x - 15.0).abs() < 1e-9 && (y - 20.0).abs() < 1e-9, "{} {}", x, y);
+    }
+}
@@ -0,0 +1,406 @@
+// IP Display Client - Pairing
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Pairing with a server by confirming a 6-digit code it displays.
+//!
+//! The client commits to its X25519 key before seeing the server's, so
+//! neither side (nor anyone in between) can steer the code; entering the
+//! code the server shows proves both ends computed the same transcript.
+//! Both then derive a long-term token that answers the server's auth
+//! challenge on later connections, and the server's confirmation carries
+//! the Ed25519 key its content hashes are signed with, which we pin.
+
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 16;
+pub const MAC_SIZE: usize = 32;
+pub const TOKEN_ID_SIZE: usize = 8;
+
+/// Length of the `PairKey`/`PairReveal` payloads: public key then nonce
+pub const KEY_PAYLOAD_SIZE: usize = KEY_SIZE + NONCE_SIZE;
+
+/// How long the server shows a code before giving up on the pairing
+pub const PAIR_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Labels separating the values derived from the pairing key
+const CLIENT_CONFIRM_LABEL: &[u8] = b"ipdisp client confirm";
+const SERVER_CONFIRM_LABEL: &[u8] = b"ipdisp server confirm";
+const TOKEN_LABEL: &[u8] = b"ipdisp token";
+const TOKEN_ID_LABEL: &[u8] = b"ipdisp token id";
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; MAC_SIZE] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// First step of a pairing, before the server's key is known
+pub struct Pairing {
+    secret: EphemeralSecret,
+    public: PublicKey,
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl Pairing {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        Self { secret, public, nonce }
+    }
+
+    /// Hash of our key and nonce, sent before the server reveals its own
+    pub fn commitment(&self) -> [u8; KEY_SIZE] {
+        commitment(self.public.as_bytes(), &self.nonce)
+    }
+
+    /// Our key and nonce, revealed once the server has sent its own
+    pub fn reveal(&self) -> ([u8; KEY_SIZE], [u8; NONCE_SIZE]) {
+        (*self.public.as_bytes(), self.nonce)
+    }
+
+    /// Combine with the server's `PairKey` payload
+    pub fn finish(self, server_payload: &[u8]) -> Result<PairingKeys> {
+        if server_payload.len() != KEY_PAYLOAD_SIZE {
+            return Err(anyhow::anyhow!("Unexpected PairKey size: {}", server_payload.len()));
+        }
+        let mut server_public = [0u8; KEY_SIZE];
+        server_public.copy_from_slice(&server_payload[..KEY_SIZE]);
+
+        let shared = self.secret.diffie_hellman(&PublicKey::from(server_public));
+        if !shared.was_contributory() {
+            return Err(anyhow::anyhow!("Server sent a low-order key"));
+        }
+
+        let transcript = transcript(
+            &commitment(self.public.as_bytes(), &self.nonce),
+            server_payload,
+            self.public.as_bytes(),
+            &self.nonce,
+        );
+        Ok(PairingKeys::derive(&transcript, shared.as_bytes()))
+    }
+}
+
+impl Default for Pairing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn commitment(public: &[u8; KEY_SIZE], nonce: &[u8; NONCE_SIZE]) -> [u8; KEY_SIZE] {
+    Sha256::new().chain_update(public).chain_update(nonce).finalize().into()
+}
+
+fn transcript(
+    commitment: &[u8; KEY_SIZE],
+    server_payload: &[u8],
+    client_public: &[u8; KEY_SIZE],
+    client_nonce: &[u8; NONCE_SIZE],
+) -> [u8; KEY_SIZE] {
+    Sha256::new()
+        .chain_update(commitment)
+        .chain_update(server_payload)
+        .chain_update(client_public)
+        .chain_update(client_nonce)
+        .finalize()
+        .into()
+}
+
+/// Everything both sides agree on once keys are exchanged
+pub struct PairingKeys {
+    /// The code the server should be showing
+    pub code: u32,
+    key: [u8; KEY_SIZE],
+}
+
+impl PairingKeys {
+    fn derive(transcript: &[u8; KEY_SIZE], shared: &[u8; KEY_SIZE]) -> Self {
+        let code = u32::from_be_bytes([transcript[0], transcript[1], transcript[2], transcript[3]]) % 1_000_000;
+        Self { code, key: hmac(transcript, shared) }
+    }
+
+    /// Whether what the user typed matches the code we expect
+    pub fn matches(&self, entered: &str) -> bool {
+        let digits: String = entered.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+        digits.len() == 6 && digits.parse::<u32>().ok() == Some(self.code)
+    }
+
+    /// Sent once the user has confirmed the code
+    pub fn client_confirm(&self) -> [u8; MAC_SIZE] {
+        hmac(&self.key, CLIENT_CONFIRM_LABEL)
+    }
+
+    /// Check the server's `PairConfirm`, a MAC over its content signing
+    /// key followed by the key, and return the key if it holds
+    pub fn verify_server_confirm(&self, payload: &[u8]) -> Option<[u8; KEY_SIZE]> {
+        if payload.len() != MAC_SIZE + KEY_SIZE {
+            return None;
+        }
+        let (mac, content_key) = payload.split_at(MAC_SIZE);
+        let mut expected = HmacSha256::new_from_slice(&self.key).expect("HMAC takes any key length");
+        expected.update(SERVER_CONFIRM_LABEL);
+        expected.update(content_key);
+        expected.verify_slice(mac).ok()?;
+        content_key.try_into().ok()
+    }
+
+    /// The long-term credential, pinning the server's `content_key`
+    pub fn token(&self, content_key: [u8; KEY_SIZE]) -> PairedServer {
+        let mut id = [0u8; TOKEN_ID_SIZE];
+        id.copy_from_slice(&hmac(&self.key, TOKEN_ID_LABEL)[..TOKEN_ID_SIZE]);
+        PairedServer { id, token: hmac(&self.key, TOKEN_LABEL), content_key: Some(content_key) }
+    }
+}
+
+/// Asks the UI for the code a server is showing. Dropping `reply` cancels
+/// the pairing.
+#[derive(Debug)]
+pub struct PairPrompt {
+    /// `server:port`, to tell the user which display to look at
+    pub server: String,
+    pub reply: oneshot::Sender<String>,
+}
+
+/// Long-term credential for one server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairedServer {
+    /// Tells the server which token to check
+    pub id: [u8; TOKEN_ID_SIZE],
+    pub token: [u8; KEY_SIZE],
+    /// The server's content signing key, pinned when we paired
+    pub content_key: Option<[u8; KEY_SIZE]>,
+}
+
+impl PairedServer {
+    /// Credential for a key both ends already hold, its id derived the
+    /// way a paired token's is
+    pub fn from_key(key: [u8; KEY_SIZE]) -> Self {
+        let mut id = [0u8; TOKEN_ID_SIZE];
+        id.copy_from_slice(&hmac(&key, TOKEN_ID_LABEL)[..TOKEN_ID_SIZE]);
+        Self { id, token: key, content_key: None }
+    }
+
+    /// Answer to an `AuthChallenge`
+    pub fn auth_mac(&self, challenge: &[u8]) -> [u8; MAC_SIZE] {
+        hmac(&self.token, challenge)
+    }
+}
+
+/// Whether `signature` is the Ed25519 signature of `signed` under the
+/// server's content signing key `public`
+pub fn verify_content(public: &[u8; KEY_SIZE], signed: &[u8], signature: &[u8]) -> bool {
+    let (Ok(key), Ok(signature)) = (VerifyingKey::from_bytes(public), Signature::from_slice(signature)) else {
+        return false;
+    };
+    key.verify_strict(signed, &signature).is_ok()
+}
+
+/// Tokens for paired servers, one `server:port id token content_key` line
+/// each (hex); pairings from older clients have no content key
+#[derive(Debug, Clone, Default)]
+pub struct PairingStore {
+    path: Option<PathBuf>,
+    servers: HashMap<String, PairedServer>,
+}
+
+impl PairingStore {
+    /// `$XDG_CONFIG_HOME/ip-display-client/pairings`, falling back to
+    /// `~/.config`
+    pub fn default_path() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(config.join("ip-display-client").join("pairings"))
+    }
+
+    /// Load from `path`; a missing file is an empty store
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut servers = HashMap::new();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            let parsed = (|| {
+                let server = fields.next()?;
+                let id = decode_hex(fields.next()?)?.try_into().ok()?;
+                let token = decode_hex(fields.next()?)?.try_into().ok()?;
+                // Pairings from before content keys have none
+                let content_key = match fields.next() {
+                    Some(key) => Some(decode_hex(key)?.try_into().ok()?),
+                    None => None,
+                };
+                Some((server.to_string(), PairedServer { id, token, content_key }))
+            })();
+            match parsed {
+                Some((server, paired)) => {
+                    servers.insert(server, paired);
+                }
+                None => return Err(anyhow::anyhow!("Malformed line in {}", path.display())),
+            }
+        }
+
+        Ok(Self { path: Some(path), servers })
+    }
+
+    pub fn get(&self, server: &str) -> Option<&PairedServer> {
+        self.servers.get(server)
+    }
+
+    /// Remember `server` and write the store back out, readable only by us
+    pub fn insert(&mut self, server: &str, paired: PairedServer) -> Result<()> {
+        self.servers.insert(server.to_string(), paired);
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut contents = String::new();
+        for (server, paired) in &self.servers {
+            contents.push_str(&format!("{} {} {}", server, encode_hex(&paired.id), encode_hex(&paired.token)));
+            if let Some(content_key) = &paired.content_key {
+                contents.push_str(&format!(" {}", encode_hex(content_key)));
+            }
+            contents.push('\n');
+        }
+        write_private(path, contents.as_bytes())
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+#[cfg(unix)]
+pub fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+#[cfg(not(unix))]
+pub fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The server's half, as the kernel module does it
+    fn server_key() -> (EphemeralSecret, Vec<u8>) {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let mut payload = PublicKey::from(&secret).as_bytes().to_vec();
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        payload.extend_from_slice(&nonce);
+        (secret, payload)
+    }
+
+    #[test]
+    fn test_both_sides_agree() {
+        let client = Pairing::new();
+        let commit = client.commitment();
+        let (server_secret, server_payload) = server_key();
+        let (client_public, client_nonce) = client.reveal();
+
+        // Server checks the commitment against the reveal
+        assert_eq!(commitment(&client_public, &client_nonce), commit);
+        let shared = server_secret.diffie_hellman(&PublicKey::from(client_public));
+        let server = PairingKeys::derive(
+            &transcript(&commit, &server_payload, &client_public, &client_nonce),
+            shared.as_bytes(),
+        );
+
+        let keys = client.finish(&server_payload).unwrap();
+        assert_eq!(keys.code, server.code);
+        assert!(keys.code < 1_000_000);
+        assert!(keys.matches(&format!("{:03}-{:03}", keys.code / 1000, keys.code % 1000)));
+        assert!(!keys.matches(&format!("{:06}", (keys.code + 1) % 1_000_000)));
+
+        assert_eq!(keys.client_confirm(), server.client_confirm());
+        let content_key = [0x3c; KEY_SIZE];
+        let mut confirm = hmac(&server.key, &[SERVER_CONFIRM_LABEL, &content_key].concat()).to_vec();
+        confirm.extend_from_slice(&content_key);
+        assert_eq!(keys.verify_server_confirm(&confirm), Some(content_key));
+        assert_eq!(keys.token(content_key), server.token(content_key));
+
+        // A swapped content key breaks the MAC
+        confirm[MAC_SIZE] ^= 1;
+        assert_eq!(keys.verify_server_confirm(&confirm), None);
+        assert_eq!(keys.verify_server_confirm(&keys.client_confirm()), None);
+    }
+
+    #[test]
+    fn test_verify_content() {
+        // RFC 8032 test 2, as the kernel's ipdisp_sign makes it
+        let public = decode_hex("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c").unwrap();
+        let signature = decode_hex(concat!(
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da",
+            "085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ))
+        .unwrap();
+        let public: [u8; KEY_SIZE] = public.try_into().unwrap();
+        assert!(verify_content(&public, &[0x72], &signature));
+        assert!(!verify_content(&public, &[0x73], &signature));
+        assert!(!verify_content(&public, &[0x72], &signature[..63]));
+    }
+
+    #[test]
+    fn test_store_round_trip() {
+        let dir = std::env::temp_dir().join(format!("ipdisp-pairing-{}", std::process::id()));
+        let path = dir.join("pairings");
+        let paired = PairedServer { id: [7; TOKEN_ID_SIZE], token: [0xa5; KEY_SIZE], content_key: Some([0x3c; KEY_SIZE]) };
+        let old = PairedServer { content_key: None, ..paired.clone() };
+
+        let mut store = PairingStore::load(path.clone()).unwrap();
+        assert!(store.get("display:8080").is_none());
+        store.insert("display:8080", paired.clone()).unwrap();
+        store.insert("old:8080", old.clone()).unwrap();
+
+        let reloaded = PairingStore::load(path).unwrap();
+        assert_eq!(reloaded.get("display:8080"), Some(&paired));
+        assert_eq!(reloaded.get("old:8080"), Some(&old));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
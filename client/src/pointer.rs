@@ -0,0 +1,184 @@
+// IP Display Client - Captured Pointer
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Captured pointer mode (F8 or View → Capture Pointer) for games and CAD
+//! views that turn with the mouse. The local cursor is hidden and motion
+//! goes to the server's virtual mouse as relative deltas, which don't stop
+//! at the edge of the window. GTK 4 can't confine the pointer, so on X11 it
+//! is moved back to the middle of the stream with `xdotool` before it gets
+//! there; Wayland compositors leave it where it is. Keys pressed meanwhile
+//! go to the server's virtual keyboard, all but F8, which lets go.
+
+use std::time::{Duration, Instant};
+
+use crate::protocol::{POINTER_BUTTON_LEFT, POINTER_BUTTON_MIDDLE, POINTER_BUTTON_RIGHT};
+
+/// How close the first motion after moving the pointer back must land to
+/// count as the move, in pixels
+const WARP_SLACK: f64 = 2.0;
+
+/// How long to wait for the pointer to be moved back before going on from
+/// wherever it is
+const WARP_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Relative motion, wheel steps and buttons of a captured pointer
+#[derive(Debug, Default)]
+pub struct PointerLock {
+    /// Where the pointer was last seen, in device pixels
+    last: Option<(f64, f64)>,
+    /// Motion of less than a pixel not sent yet
+    remainder: (f64, f64),
+    /// Smooth scrolling of less than a wheel step
+    scrolled: f64,
+    /// Where the pointer is being moved back to, and since when
+    warp: Option<((f64, f64), Instant)>,
+    buttons: u32,
+    /// Evdev codes of keys held down on the server
+    keys: Vec<u32>,
+}
+
+impl PointerLock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whole pixels moved since the last position, now that the pointer is
+    /// at `x`, `y`; none while it is being moved back
+    pub fn motion(&mut self, x: f64, y: f64, now: Instant) -> Option<(i32, i32)> {
+        if let Some(((target_x, target_y), since)) = self.warp {
+            let arrived = (x - target_x).abs() <= WARP_SLACK && (y - target_y).abs() <= WARP_SLACK;
+            if !arrived && now.duration_since(since) < WARP_TIMEOUT {
+                return None;
+            }
+            self.warp = None;
+            self.last = Some((x, y));
+            return None;
+        }
+        let (last_x, last_y) = self.last.replace((x, y))?;
+        let (dx, dy) = (x - last_x + self.remainder.0, y - last_y + self.remainder.1);
+        let (whole_x, whole_y) = (dx.trunc(), dy.trunc());
+        self.remainder = (dx - whole_x, dy - whole_y);
+        (whole_x != 0.0 || whole_y != 0.0).then_some((whole_x as i32, whole_y as i32))
+    }
+
+    /// Where to move the pointer back to, once `x`, `y` has left the middle
+    /// of a `width` × `height` stream
+    pub fn warp_target(&self, x: f64, y: f64, width: f64, height: f64) -> Option<(f64, f64)> {
+        let outside = |position: f64, size: f64| position < size / 4.0 || position > size * 3.0 / 4.0;
+        (self.warp.is_none() && (outside(x, width) || outside(y, height))).then_some((width / 2.0, height / 2.0))
+    }
+
+    /// The pointer is being moved to `target`
+    pub fn warping(&mut self, target: (f64, f64), now: Instant) {
+        self.warp = Some((target, now));
+    }
+
+    /// Whole wheel steps for scrolling `dy` (positive towards the user),
+    /// positive away from the user as the server's wheel counts them
+    pub fn scroll(&mut self, dy: f64) -> i32 {
+        self.scrolled += dy;
+        let steps = self.scrolled.trunc();
+        self.scrolled -= steps;
+        -(steps as i32)
+    }
+
+    /// Press or release GDK button `button`; false for buttons the server's
+    /// mouse doesn't have
+    pub fn button(&mut self, button: u32, pressed: bool) -> bool {
+        let bit = match button {
+            1 => POINTER_BUTTON_LEFT,
+            2 => POINTER_BUTTON_MIDDLE,
+            3 => POINTER_BUTTON_RIGHT,
+            _ => return false,
+        };
+        if pressed {
+            self.buttons |= bit;
+        } else {
+            self.buttons &= !bit;
+        }
+        true
+    }
+
+    /// Buttons held down
+    pub fn buttons(&self) -> u32 {
+        self.buttons
+    }
+
+    /// Press or release the key with evdev code `code`; false when there is
+    /// nothing to send: a repeat of a held key, or the release of one
+    /// pressed before the pointer was captured
+    pub fn key(&mut self, code: u32, pressed: bool) -> bool {
+        let held = self.keys.iter().position(|&key| key == code);
+        match (held, pressed) {
+            (None, true) => self.keys.push(code),
+            (Some(index), false) => {
+                self.keys.swap_remove(index);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Keys held down
+    pub fn keys(&self) -> &[u32] {
+        &self.keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_motion() {
+        let now = Instant::now();
+        let mut lock = PointerLock::new();
+        assert_eq!(lock.motion(100.0, 100.0, now), None);
+        assert_eq!(lock.motion(103.0, 98.0, now), Some((3, -2)));
+
+        // Fractions add up rather than getting lost
+        assert_eq!(lock.motion(103.6, 98.0, now), None);
+        assert_eq!(lock.motion(104.2, 98.0, now), Some((1, 0)));
+
+        // The jump back to the middle isn't motion
+        assert_eq!(lock.warp_target(500.0, 300.0, 800.0, 600.0), None);
+        assert_eq!(lock.warp_target(650.0, 300.0, 800.0, 600.0), Some((400.0, 300.0)));
+        lock.warping((400.0, 300.0), now);
+        assert_eq!(lock.warp_target(700.0, 300.0, 800.0, 600.0), None);
+        assert_eq!(lock.motion(620.0, 300.0, now), None);
+        assert_eq!(lock.motion(401.0, 300.0, now), None);
+        assert_eq!(lock.motion(405.0, 300.0, now), Some((4, 0)));
+
+        // Nor is it when the pointer never comes back
+        lock.warping((400.0, 300.0), now);
+        assert_eq!(lock.motion(700.0, 300.0, now + WARP_TIMEOUT), None);
+        assert_eq!(lock.motion(702.0, 300.0, now + WARP_TIMEOUT), Some((2, 0)));
+    }
+
+    #[test]
+    fn test_wheel_and_buttons() {
+        let mut lock = PointerLock::new();
+        assert_eq!(lock.scroll(1.0), -1);
+        assert_eq!(lock.scroll(-0.6), 0);
+        assert_eq!(lock.scroll(-0.6), 1);
+
+        assert!(lock.button(1, true));
+        assert!(lock.button(3, true));
+        assert!(!lock.button(8, true));
+        assert_eq!(lock.buttons(), POINTER_BUTTON_LEFT | POINTER_BUTTON_RIGHT);
+        assert!(lock.button(1, false));
+        assert_eq!(lock.buttons(), POINTER_BUTTON_RIGHT);
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut lock = PointerLock::new();
+        assert!(!lock.key(30, false));
+        assert!(lock.key(30, true));
+        assert!(!lock.key(30, true));
+        assert!(lock.key(42, true));
+        assert!(lock.key(30, false));
+        assert_eq!(lock.keys(), [42]);
+    }
+}
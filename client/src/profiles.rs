@@ -0,0 +1,233 @@
+// IP Display Client - Connection Profiles
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Named connection profiles and the servers connected to recently.
+//!
+//! Profiles live in `$XDG_CONFIG_HOME/ip-display-client/profiles`, one
+//! `[name]` section each, holding `option = value` lines named after the
+//! command line options:
+//!
+//! ```text
+//! [work]
+//! server = display.example.com
+//! transport = ws
+//! ws-path = /ipdisp
+//! auth-token-file = /home/me/.config/ip-display-client/work.token
+//! quality = high
+//! checksum = true
+//! ```
+//!
+//! `--profile work` puts these in front of the real arguments, so anything
+//! given on the command line still wins.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Entries kept in the recent servers list
+pub const MAX_RECENT_SERVERS: usize = 10;
+
+/// A named set of options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    settings: Vec<(String, String)>,
+}
+
+impl Profile {
+    /// The settings as command line arguments; a `false` flag is left out
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (option, value) in &self.settings {
+            match value.as_str() {
+                "true" => args.push(format!("--{}", option)),
+                "false" => {}
+                _ => args.extend([format!("--{}", option), value.clone()]),
+            }
+        }
+        args
+    }
+}
+
+/// All profiles in the config file, in the order they appear
+#[derive(Debug, Clone, Default)]
+pub struct ProfileStore {
+    profiles: Vec<Profile>,
+}
+
+impl ProfileStore {
+    /// `$XDG_CONFIG_HOME/ip-display-client/profiles`, falling back to
+    /// `~/.config`
+    pub fn default_path() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(config.join("ip-display-client").join("profiles"))
+    }
+
+    /// Load from `path`; a missing file has no profiles
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents).with_context(|| format!("In {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let mut profiles: Vec<Profile> = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                profiles.push(Profile { name: name.trim().to_string(), settings: Vec::new() });
+                continue;
+            }
+
+            let Some((option, value)) = line.split_once('=') else {
+                return Err(anyhow::anyhow!("Line {}: expected `option = value`", number + 1));
+            };
+            let Some(profile) = profiles.last_mut() else {
+                return Err(anyhow::anyhow!("Line {}: setting outside a [profile]", number + 1));
+            };
+            let option = option.trim().trim_start_matches("--").replace('_', "-");
+            profile.settings.push((option, value.trim().to_string()));
+        }
+        Ok(Self { profiles })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.iter().map(|profile| profile.name.as_str())
+    }
+}
+
+/// `server:port` of the servers connected to, most recent first
+#[derive(Debug, Clone, Default)]
+pub struct RecentServers {
+    path: Option<PathBuf>,
+    servers: Vec<String>,
+}
+
+impl RecentServers {
+    /// `$XDG_STATE_HOME/ip-display-client/recent`, falling back to
+    /// `~/.local/state`
+    pub fn default_path() -> Option<PathBuf> {
+        let state = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state")))?;
+        Some(state.join("ip-display-client").join("recent"))
+    }
+
+    /// Load from `path`; a missing file is an empty list
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let servers = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .take(MAX_RECENT_SERVERS)
+                .map(String::from)
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        Ok(Self { path: Some(path), servers })
+    }
+
+    pub fn servers(&self) -> &[String] {
+        &self.servers
+    }
+
+    /// Move `server` to the top of the list and save it
+    pub fn touch(&mut self, server: &str) -> Result<()> {
+        if self.servers.first().is_some_and(|first| first == server) {
+            return Ok(());
+        }
+        self.servers.retain(|known| known != server);
+        self.servers.insert(0, server.to_string());
+        self.servers.truncate(MAX_RECENT_SERVERS);
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut contents = self.servers.join("\n");
+        contents.push('\n');
+        fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let store = ProfileStore::parse(
+            "# Displays\n\
+             [work]\n\
+             server = display.example.com\n\
+             --port = 9000\n\
+             auth_token_file = /tmp/work.token\n\
+             checksum = true\n\
+             vsync = false\n\
+             \n\
+             [home]\n\
+             server = fe80::1\n",
+        )
+        .unwrap();
+
+        assert_eq!(store.names().collect::<Vec<_>>(), ["work", "home"]);
+        assert_eq!(
+            store.get("work").unwrap().args(),
+            [
+                "--server", "display.example.com", "--port", "9000", "--auth-token-file", "/tmp/work.token",
+                "--checksum",
+            ]
+        );
+        assert_eq!(store.get("home").unwrap().args(), ["--server", "fe80::1"]);
+        assert!(store.get("lab").is_none());
+
+        // Options given after the profile's override them
+        let args = ["ip-display-client"]
+            .into_iter()
+            .map(String::from)
+            .chain(store.get("work").unwrap().args())
+            .chain(["--port", "9999", "--checksum"].map(String::from));
+        let args = <crate::Args as clap::Parser>::try_parse_from(args).unwrap();
+        assert_eq!((args.server.as_str(), args.port, args.checksum), ("display.example.com", 9999, true));
+
+        assert!(ProfileStore::parse("server = display\n").is_err());
+        assert!(ProfileStore::parse("[work]\nserver display\n").is_err());
+    }
+
+    #[test]
+    fn test_recent_servers() {
+        let dir = std::env::temp_dir().join(format!("ipdisp-recent-{}", std::process::id()));
+        let path = dir.join("recent");
+
+        let mut recent = RecentServers::load(path.clone()).unwrap();
+        for server in ["a:8080", "b:8080", "a:8080", "[::1]:8080"] {
+            recent.touch(server).unwrap();
+        }
+        assert_eq!(recent.servers(), ["[::1]:8080", "a:8080", "b:8080"]);
+
+        for i in 0..MAX_RECENT_SERVERS {
+            recent.touch(&format!("host{}:8080", i)).unwrap();
+        }
+        let reloaded = RecentServers::load(path).unwrap();
+        assert_eq!(reloaded.servers().len(), MAX_RECENT_SERVERS);
+        assert_eq!(reloaded.servers()[0], format!("host{}:8080", MAX_RECENT_SERVERS - 1));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
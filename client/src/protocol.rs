@@ -2,154 +2,107 @@
 // Copyright (c) 2024
 // Licensed under MIT
 
+//! The wire format comes from `ipds-protocol`, shared with the demo
+//! server; this adds the frames the client receives into pooled buffers.
+
 use anyhow::Result;
-use bytes::{Buf, BufMut, BytesMut};
-use serde::{Deserialize, Serialize};
-use std::convert::TryFrom;
+use std::time::Instant;
 
-// Protocol constants
-pub const MAGIC: u32 = 0x49504453; // "IPDS"
-pub const VERSION: u32 = 1;
-pub const HEADER_SIZE: usize = 32;
+use crate::buffer_pool::PooledBuffer;
+use crate::convert::{ChromaLayout, Yuv420};
+use ip_display_client::region::Region;
 
-#[repr(u32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub enum FrameFormat {
-    Rgba32 = 0,
-    Rgb24 = 1,
-    H264 = 2,
-    H265 = 3,
-}
+pub use ipds_protocol::*;
 
-impl TryFrom<u32> for FrameFormat {
-    type Error = anyhow::Error;
-    
-    fn try_from(value: u32) -> Result<Self> {
-        match value {
-            0 => Ok(FrameFormat::Rgba32),
-            1 => Ok(FrameFormat::Rgb24),
-            2 => Ok(FrameFormat::H264),
-            3 => Ok(FrameFormat::H265),
-            _ => Err(anyhow::anyhow!("Invalid frame format: {}", value)),
-        }
-    }
-}
+/// Bytes a row may be padded with past its pixels, e.g. for GPU pitch
+/// alignment
+pub const MAX_ROW_PADDING: usize = 256;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PacketHeader {
-    pub magic: u32,
-    pub version: u32,
+/// Display size a server announced in its info packet; frames are held to
+/// it before their payload is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayMode {
     pub width: u32,
     pub height: u32,
-    pub format: FrameFormat,
-    pub timestamp: u64,
-    pub size: u32,
-    pub reserved: u32,
 }
 
-impl PacketHeader {
-    pub fn new(width: u32, height: u32, format: FrameFormat, size: u32) -> Self {
-        Self {
-            magic: MAGIC,
-            version: VERSION,
-            width,
-            height,
-            format,
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64,
-            size,
-            reserved: 0,
-        }
-    }
+impl DisplayMode {
+    /// What a server that hasn't sent display info is held to
+    pub const LARGEST: DisplayMode = DisplayMode { width: 7680, height: 4320 };
     
-    pub fn from_bytes(data: &[u8]) -> Result<Self> {
-        if data.len() < HEADER_SIZE {
-            return Err(anyhow::anyhow!("Header too short: {} bytes", data.len()));
-        }
-        
-        let mut buf = &data[..HEADER_SIZE];
-        
-        let magic = buf.get_u32();
-        let version = buf.get_u32();
-        let width = buf.get_u32();
-        let height = buf.get_u32();
-        let format_raw = buf.get_u32();
-        let timestamp = buf.get_u64();
-        let size = buf.get_u32();
-        let reserved = buf.get_u32();
-        
-        if magic != MAGIC {
-            return Err(anyhow::anyhow!("Invalid magic number: 0x{:08x}", magic));
-        }
-        
-        if version != VERSION {
-            return Err(anyhow::anyhow!("Unsupported version: {}", version));
-        }
-        
-        let format = FrameFormat::try_from(format_raw)?;
-        
-        Ok(Self {
-            magic,
-            version,
-            width,
-            height,
-            format,
-            timestamp,
-            size,
-            reserved,
-        })
-    }
-    
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = BytesMut::with_capacity(HEADER_SIZE);
-        
-        buf.put_u32(self.magic);
-        buf.put_u32(self.version);
-        buf.put_u32(self.width);
-        buf.put_u32(self.height);
-        buf.put_u32(self.format as u32);
-        buf.put_u64(self.timestamp);
-        buf.put_u32(self.size);
-        buf.put_u32(self.reserved);
-        
-        buf.to_vec()
+    /// Whether a frame is no larger than the mode; scaled and cropped
+    /// frames are smaller
+    pub fn fits(&self, header: &PacketHeader) -> bool {
+        header.width <= self.width && header.height <= self.height
     }
     
-    pub fn is_info_packet(&self) -> bool {
-        self.size == 0
+    /// Largest frame payload at this size: four bytes a pixel, the most any
+    /// format takes, and padded rows
+    pub fn max_frame_size(&self) -> usize {
+        (self.width as usize * 4 + MAX_ROW_PADDING) * self.height as usize
     }
     
-    pub fn validate(&self) -> Result<()> {
-        if self.magic != MAGIC {
-            return Err(anyhow::anyhow!("Invalid magic number"));
+    /// Check the payload size of a frame that fits, so a stream that has
+    /// lost its place is caught before anything is allocated for it
+    pub fn check_size(&self, header: &PacketHeader) -> Result<()> {
+        if header.size as usize > self.max_frame_size() {
+            return Err(anyhow::anyhow!("{} byte payload is too large for a {}x{} display",
+                                       header.size, self.width, self.height));
         }
-        
-        if self.version != VERSION {
-            return Err(anyhow::anyhow!("Unsupported version"));
+        if header.packet_type == PacketType::Display {
+            if let Some(expected) = raw_size(header).filter(|&expected| expected != header.size as usize) {
+                return Err(anyhow::anyhow!("{}x{} {:?} frame of {} bytes, expected {}",
+                                           header.width, header.height, header.format, header.size, expected));
+            }
         }
-        
-        if self.width == 0 || self.height == 0 {
-            return Err(anyhow::anyhow!("Invalid dimensions: {}x{}", self.width, self.height));
+        Ok(())
+    }
+}
+
+/// Bytes from one row to the next of a packed RGB frame
+fn row_stride(header: &PacketHeader) -> usize {
+    match (header.stride, header.format.bytes_per_pixel()) {
+        (0, Some(bpp)) => header.width as usize * bpp,
+        (stride, _) => stride as usize,
+    }
+}
+
+/// How the chroma of a raw YUV frame is stored
+fn chroma_layout(format: FrameFormat) -> Option<ChromaLayout> {
+    match format {
+        FrameFormat::Yuv420p => Some(ChromaLayout::Planar),
+        FrameFormat::Nv12 => Some(ChromaLayout::Interleaved),
+        FrameFormat::P010 => Some(ChromaLayout::Interleaved16),
+        _ => None,
+    }
+}
+
+/// Payload size a frame header implies; `None` for compressed formats,
+/// which are as long as they compress to
+pub fn raw_size(header: &PacketHeader) -> Option<usize> {
+    match header.format {
+        FrameFormat::Rgba32 | FrameFormat::Rgb24 | FrameFormat::Rgb565 | FrameFormat::Rgba1010102 => {
+            Some(row_stride(header) * header.height as usize)
         }
-        
-        if self.width > 7680 || self.height > 4320 {
-            return Err(anyhow::anyhow!("Dimensions too large: {}x{}", self.width, self.height));
+        FrameFormat::Yuv420p | FrameFormat::Nv12 | FrameFormat::P010 => {
+            let layout = chroma_layout(header.format).unwrap();
+            Some(Yuv420::size(header.width as usize, header.height as usize, layout))
         }
-        
-        Ok(())
+        FrameFormat::H264 | FrameFormat::H265 | FrameFormat::Jpeg => None,
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct FrameData {
     pub header: PacketHeader,
-    pub data: Vec<u8>,
+    pub data: PooledBuffer,
+    /// When the payload finished arriving, for playout scheduling
+    pub received: Instant,
 }
 
 impl FrameData {
-    pub fn new(header: PacketHeader, data: Vec<u8>) -> Result<Self> {
+    pub fn new(header: PacketHeader, data: impl Into<PooledBuffer>) -> Result<Self> {
+        let data = data.into();
         if data.len() != header.size as usize {
             return Err(anyhow::anyhow!(
                 "Data size mismatch: expected {}, got {}", 
@@ -157,24 +110,27 @@ impl FrameData {
             ));
         }
         
-        Ok(Self { header, data })
+        Ok(Self { header, data, received: Instant::now() })
+    }
+    
+    /// Bytes from one row to the next of a packed RGB frame
+    pub fn stride(&self) -> usize {
+        row_stride(&self.header)
     }
     
     pub fn expected_size(&self) -> usize {
-        match self.header.format {
-            FrameFormat::Rgba32 => (self.header.width * self.header.height * 4) as usize,
-            FrameFormat::Rgb24 => (self.header.width * self.header.height * 3) as usize,
-            FrameFormat::H264 | FrameFormat::H265 => self.data.len(),
-        }
+        raw_size(&self.header).unwrap_or(self.data.len())
     }
     
+    /// Check the payload fits the header; the CRC-32 is left to
+    /// `checksum_ok`, which readers call first to tell corruption apart
     pub fn validate(&self) -> Result<()> {
         self.header.validate()?;
         
         if !self.header.is_info_packet() {
             let expected = self.expected_size();
             if self.data.len() != expected && 
-               matches!(self.header.format, FrameFormat::Rgba32 | FrameFormat::Rgb24) {
+               !matches!(self.header.format, FrameFormat::H264 | FrameFormat::H265 | FrameFormat::Jpeg) {
                 return Err(anyhow::anyhow!(
                     "Invalid data size for format {:?}: expected {}, got {}",
                     self.header.format, expected, self.data.len()
@@ -185,16 +141,94 @@ impl FrameData {
         Ok(())
     }
     
+    /// Whether the payload matches the header's CRC-32, if it has one
+    pub fn checksum_ok(&self) -> bool {
+        self.header.crc32.is_none_or(|crc| crc32fast::hash(&self.data) == crc)
+    }
+    
+    /// How the chroma of a raw YUV frame is stored
+    pub fn chroma_layout(&self) -> Option<ChromaLayout> {
+        chroma_layout(self.header.format)
+    }
+    
+    /// The `region` of a packed RGB frame, for a server that sent the
+    /// whole display; a frame already the region's size is taken as cropped
+    pub fn crop(self, region: Region) -> Result<FrameData> {
+        let header = &self.header;
+        if (header.width, header.height) == (region.width, region.height) {
+            return Ok(self);
+        }
+        if region.x + region.width > header.width || region.y + region.height > header.height {
+            return Err(anyhow::anyhow!("Crop {} is outside the {}x{} frame", region, header.width, header.height));
+        }
+        let Some(bpp) = header.format.bytes_per_pixel() else {
+            return Err(anyhow::anyhow!("Can't crop {:?} frames here; the server has to", header.format));
+        };
+        
+        let (stride, row_size) = (self.stride(), region.width as usize * bpp);
+        let mut data = Vec::with_capacity(row_size * region.height as usize);
+        for row in self.data.chunks_exact(stride).skip(region.y as usize).take(region.height as usize) {
+            let start = region.x as usize * bpp;
+            data.extend_from_slice(&row[start..start + row_size]);
+        }
+        let header = PacketHeader {
+            width: region.width,
+            height: region.height,
+            size: data.len() as u32,
+            stride: 0,
+            crc32: None,
+            ..self.header
+        };
+        Ok(FrameData { header, data: data.into(), received: self.received })
+    }
+    
+    /// The frame as packed RGBA32; the renderers convert on upload
+    /// instead, so only the tests need this
+    #[cfg(test)]
     pub fn to_rgba32(&self) -> Result<Vec<u8>> {
         match self.header.format {
-            FrameFormat::Rgba32 => Ok(self.data.clone()),
-            FrameFormat::Rgb24 => {
-                let mut rgba_data = Vec::with_capacity(self.data.len() * 4 / 3);
-                for chunk in self.data.chunks_exact(3) {
-                    rgba_data.extend_from_slice(&[chunk[0], chunk[1], chunk[2], 255]);
+            FrameFormat::Rgba32 | FrameFormat::Rgb24 | FrameFormat::Rgb565 | FrameFormat::Rgba1010102 => {
+                let width = self.header.width as usize;
+                let bpp = self.header.format.bytes_per_pixel().unwrap();
+                let mut rgba_data = vec![0u8; width * self.header.height as usize * 4];
+                // Padding at the end of each row is dropped
+                for (row, out) in self.data.chunks_exact(self.stride()).zip(rgba_data.chunks_exact_mut(width * 4)) {
+                    let row = &row[..width * bpp];
+                    match self.header.format {
+                        FrameFormat::Rgba32 => out.copy_from_slice(row),
+                        FrameFormat::Rgb24 => {
+                            for (px, chunk) in out.chunks_exact_mut(4).zip(row.chunks_exact(3)) {
+                                px.copy_from_slice(&[chunk[0], chunk[1], chunk[2], 255]);
+                            }
+                        }
+                        FrameFormat::Rgb565 => crate::convert::rgb565_to_bgra(row, out),
+                        _ => crate::convert::rgb10a2_to_bgra_premul(row, out),
+                    }
+                    if matches!(self.header.format, FrameFormat::Rgb565 | FrameFormat::Rgba1010102) {
+                        for px in out.chunks_exact_mut(4) {
+                            px.swap(0, 2);
+                        }
+                    }
+                }
+                Ok(rgba_data)
+            }
+            FrameFormat::Yuv420p | FrameFormat::Nv12 | FrameFormat::P010 => {
+                let (width, height) = (self.header.width as usize, self.header.height as usize);
+                let frame = Yuv420::new(&self.data, width, height, self.chroma_layout().unwrap())
+                    .ok_or_else(|| anyhow::anyhow!("Invalid YUV frame size"))?;
+                let mut rgba_data = vec![0u8; width * height * 4];
+                for (row, out) in rgba_data.chunks_exact_mut(width * 4).enumerate() {
+                    frame.row_to_bgra(row, out);
+                    for px in out.chunks_exact_mut(4) {
+                        px.swap(0, 2);
+                    }
                 }
                 Ok(rgba_data)
             }
+            FrameFormat::Jpeg => {
+                let rgb = crate::convert::decode_jpeg(&self.data, self.header.width, self.header.height)?;
+                Ok(rgb.chunks_exact(3).flat_map(|px| [px[0], px[1], px[2], 255]).collect())
+            }
             FrameFormat::H264 | FrameFormat::H265 => {
                 Err(anyhow::anyhow!("Codec formats not yet supported"))
             }
@@ -206,19 +240,6 @@ impl FrameData {
 mod tests {
     use super::*;
     
-    #[test]
-    fn test_header_serialization() {
-        let header = PacketHeader::new(1920, 1080, FrameFormat::Rgba32, 1024);
-        let bytes = header.to_bytes();
-        let parsed = PacketHeader::from_bytes(&bytes).unwrap();
-        
-        assert_eq!(header.magic, parsed.magic);
-        assert_eq!(header.width, parsed.width);
-        assert_eq!(header.height, parsed.height);
-        assert_eq!(header.format, parsed.format);
-        assert_eq!(header.size, parsed.size);
-    }
-    
     #[test]
     fn test_frame_validation() {
         let header = PacketHeader::new(1920, 1080, FrameFormat::Rgba32, 1920 * 1080 * 4);
@@ -226,6 +247,49 @@ mod tests {
         let frame = FrameData::new(header, data).unwrap();
         
         assert!(frame.validate().is_ok());
+        
+        // 3x3 4:2:0 has 2x2 chroma
+        let header = PacketHeader::new(3, 3, FrameFormat::Nv12, 17);
+        let frame = FrameData::new(header, vec![128u8; 17]).unwrap();
+        assert_eq!(frame.expected_size(), 17);
+        assert!(frame.validate().is_ok());
+        assert_eq!(frame.chroma_layout(), Some(ChromaLayout::Interleaved));
+        
+        let header = PacketHeader::new(3, 3, FrameFormat::Yuv420p, 16);
+        assert!(FrameData::new(header, vec![128u8; 16]).unwrap().validate().is_err());
+        
+        // P010 doubles every sample, RGB565 packs a pixel into two bytes
+        let header = PacketHeader::new(3, 3, FrameFormat::P010, 34);
+        assert!(FrameData::new(header, vec![0u8; 34]).unwrap().validate().is_ok());
+        let header = PacketHeader::new(3, 3, FrameFormat::Rgb565, 18);
+        assert!(FrameData::new(header, vec![0u8; 18]).unwrap().validate().is_ok());
+        assert_eq!(FrameFormat::try_from(8).unwrap(), FrameFormat::P010);
+        
+        // A JPEG is as long as it compresses to
+        let header = PacketHeader::new(640, 480, FrameFormat::Jpeg, 5);
+        assert!(FrameData::new(header, vec![0xffu8; 5]).unwrap().validate().is_ok());
+        assert_eq!(FrameFormat::try_from(9).unwrap(), FrameFormat::Jpeg);
+    }
+    
+    #[test]
+    fn test_frame_checksum() {
+        let data = vec![1u8, 2, 3, 4];
+        let mut header = PacketHeader::new(1, 1, FrameFormat::Rgba32, 4);
+        header.crc32 = Some(crc32fast::hash(&data));
+        
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + CRC_SIZE);
+        assert_eq!(PacketHeader::extension_size(bytes[..HEADER_SIZE].try_into().unwrap()).unwrap(), CRC_SIZE);
+        let parsed = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.packet_type, PacketType::Display);
+        assert_eq!(parsed.crc32, header.crc32);
+        
+        let frame = FrameData::new(parsed.clone(), data).unwrap();
+        assert!(frame.checksum_ok());
+        assert!(frame.validate().is_ok());
+        
+        let frame = FrameData::new(parsed, vec![1, 2, 3, 5]).unwrap();
+        assert!(!frame.checksum_ok());
     }
     
     #[test]
@@ -239,4 +303,91 @@ mod tests {
         assert_eq!(rgba[0..4], [255, 0, 0, 255]);
         assert_eq!(rgba[4..8], [0, 255, 0, 255]);
     }
+    
+    #[test]
+    fn test_padded_rows() {
+        // 2x2 RGB24 with rows padded to 8 bytes, in a version 2 header
+        let mut header = PacketHeader::new(2, 2, FrameFormat::Rgb24, 16);
+        header.version = VERSION_STRIDE;
+        header.stride = 8;
+        header.crc32 = Some(7);
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE_V2 + CRC_SIZE);
+        assert_eq!(PacketHeader::fixed_size(bytes[..8].try_into().unwrap()).unwrap(), HEADER_SIZE_V2);
+        let parsed = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.stride, 8);
+        assert_eq!(parsed.crc32, Some(7));
+        header.crc32 = None;
+        
+        let data = vec![255, 0, 0, 0, 255, 0, 9, 9, 0, 0, 255, 255, 255, 255, 9, 9];
+        let frame = FrameData::new(header.clone(), data).unwrap();
+        assert_eq!(frame.expected_size(), 16);
+        assert!(frame.validate().is_ok());
+        let rgba = frame.to_rgba32().unwrap();
+        assert_eq!(rgba.len(), 16);
+        assert_eq!(rgba[4..12], [0, 255, 0, 255, 0, 0, 255, 255]);
+        
+        // Rows can't overlap, and YUV frames have no stride
+        header.stride = 5;
+        assert!(header.validate().is_err());
+        header.stride = 8;
+        header.format = FrameFormat::Nv12;
+        assert!(header.validate().is_err());
+        
+        let mut bytes = PacketHeader::new(2, 2, FrameFormat::Rgb24, 12).to_bytes();
+        bytes[7] = 3;
+        assert!(PacketHeader::from_bytes(&bytes).is_err());
+    }
+    
+    #[test]
+    fn test_frame_crop() {
+        // 3x2 RGB24 with padded rows; the right column of the bottom row
+        let mut header = PacketHeader::new(3, 2, FrameFormat::Rgb24, 24);
+        header.version = VERSION_STRIDE;
+        header.stride = 12;
+        let data: Vec<u8> = (0..24).collect();
+        header.crc32 = Some(crc32fast::hash(&data));
+        let frame = FrameData::new(header, data).unwrap();
+        let region = Region { x: 2, y: 1, width: 1, height: 1 };
+        let cropped = frame.clone().crop(region).unwrap();
+        assert_eq!((cropped.header.width, cropped.header.height, cropped.header.stride), (1, 1, 0));
+        assert_eq!(&cropped.data[..], &[18, 19, 20]);
+        cropped.validate().unwrap();
+        
+        // Already cropped by the server, or not possible
+        assert_eq!(cropped.clone().crop(region).unwrap().data.len(), 3);
+        assert!(frame.clone().crop(Region { x: 2, y: 1, width: 2, height: 1 }).is_err());
+        let jpeg = FrameData::new(PacketHeader::new(3, 2, FrameFormat::Jpeg, 2), vec![0xff, 0xd8]).unwrap();
+        assert!(jpeg.crop(region).is_err());
+    }
+    
+    #[test]
+    fn test_display_mode_limits() {
+        let mode = DisplayMode { width: 1280, height: 720 };
+        let frame = PacketHeader::new(1280, 720, FrameFormat::Rgba32, 1280 * 720 * 4);
+        assert!(mode.fits(&frame));
+        assert!(mode.check_size(&frame).is_ok());
+        
+        // Scaled down is fine, larger than announced isn't
+        assert!(mode.fits(&PacketHeader::new(640, 360, FrameFormat::Nv12, 345_600)));
+        assert!(!mode.fits(&PacketHeader::new(1920, 1080, FrameFormat::Rgba32, 1920 * 1080 * 4)));
+        
+        // Raw frames are exactly their size, and nothing is larger than
+        // padded RGBA
+        let short = PacketHeader::new(1280, 720, FrameFormat::Rgb24, 1280 * 720 * 4);
+        assert!(mode.check_size(&short).is_err());
+        let jpeg = PacketHeader::new(1280, 720, FrameFormat::Jpeg, 200_000);
+        assert!(mode.check_size(&jpeg).is_ok());
+        let huge = PacketHeader::new(1280, 720, FrameFormat::Jpeg, u32::MAX);
+        assert!(mode.check_size(&huge).is_err());
+        let mut padded = PacketHeader::new(1280, 720, FrameFormat::Rgba32, (1280 * 4 + 256) * 720);
+        padded.version = VERSION_STRIDE;
+        padded.stride = 1280 * 4 + 256;
+        assert!(mode.check_size(&padded).is_ok());
+        
+        // Damage is bounded by its own checks
+        let mut damage = PacketHeader::new(1280, 720, FrameFormat::Rgba32, 100);
+        damage.packet_type = PacketType::Damage;
+        assert!(mode.check_size(&damage).is_ok());
+    }
 }
@@ -0,0 +1,227 @@
+// IP Display Client - Protocol Metadata Log
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! A record of what went over the wire, for attaching to bug reports: one
+//! line per packet header and control message in each direction, never
+//! the frame data, gzipped so `zcat` reads it back:
+//!
+//! ```text
+//! 0.412 0< Display 1920x1080 Rgba32 size=8294400 ts=1700000000123456789 crc=5d2f0c11 compact
+//! 0.430 0> Quality { max_kbps: 20000, scale: 1, max_fps: 0, format: Rgba32 }
+//! ```
+//!
+//! Each line starts with seconds since recording began, the link and `<`
+//! for received or `>` for sent. Pairing keys, MACs, challenges and
+//! content hashes are logged by size only, so a log can't be used to
+//! impersonate either side or tell what was on screen.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::gzip::GzipWriter;
+use crate::protocol::{ByteOrder, Command, PacketHeader, PacketType};
+
+/// Most time a line can sit in memory before it is flushed to the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+struct Recorder {
+    writer: GzipWriter<BufWriter<File>>,
+    last_flush: Instant,
+}
+
+/// A gzipped protocol log, shared by every link of a connection
+pub struct ProtocolLog {
+    started: Instant,
+    /// `None` once writing has failed
+    recorder: Mutex<Option<Recorder>>,
+}
+
+impl fmt::Debug for ProtocolLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProtocolLog").field("started", &self.started).finish_non_exhaustive()
+    }
+}
+
+impl ProtocolLog {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = GzipWriter::new(BufWriter::new(file))?;
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        writeln!(
+            writer,
+            "# ip-display-client {} protocol metadata, started at {}.{:03}",
+            env!("CARGO_PKG_VERSION"),
+            started.as_secs(),
+            started.subsec_millis()
+        )?;
+        Ok(Self {
+            started: Instant::now(),
+            recorder: Mutex::new(Some(Recorder { writer, last_flush: Instant::now() })),
+        })
+    }
+
+    pub fn connected(&self, link: usize, addr: &str) {
+        self.record(link, '-', format_args!("connected to {}", addr));
+    }
+
+    /// A header as parsed, whether or not its payload turns out to be valid
+    pub fn received(&self, link: usize, header: &PacketHeader, compact: bool) {
+        self.record(link, '<', format_args!("{}", HeaderLine { header, compact }));
+    }
+
+    /// The payload of a control packet the server sent
+    pub fn control(&self, link: usize, packet_type: PacketType, payload: &[u8]) {
+        if is_secret(packet_type) {
+            self.record(link, '<', format_args!("{:?} payload [{} bytes]", packet_type, payload.len()));
+        } else {
+            self.record(link, '<', format_args!("{:?} payload {}", packet_type, Hex(payload)));
+        }
+    }
+
+    pub fn sent(&self, link: usize, command: &Command) {
+        if is_secret(command.packet_type()) {
+            self.record(link, '>', format_args!("{:?} [redacted]", command.packet_type()));
+        } else {
+            self.record(link, '>', format_args!("{:?}", command));
+        }
+    }
+
+    fn record(&self, link: usize, direction: char, line: fmt::Arguments) {
+        let mut recorder = self.recorder.lock().unwrap();
+        let Some(active) = recorder.as_mut() else { return };
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let mut written = writeln!(active.writer, "{:.3} {}{} {}", elapsed, link, direction, line);
+        if written.is_ok() && now.duration_since(active.last_flush) >= FLUSH_INTERVAL {
+            active.last_flush = now;
+            written = active.writer.flush();
+        }
+        if let Err(e) = written {
+            warn!("Failed to write protocol log, no longer recording: {}", e);
+            *recorder = None;
+        }
+    }
+}
+
+impl Drop for ProtocolLog {
+    fn drop(&mut self) {
+        if let Some(mut recorder) = self.recorder.lock().unwrap().take() {
+            if let Err(e) = recorder.writer.finish() {
+                warn!("Failed to finish protocol log: {}", e);
+            }
+        }
+    }
+}
+
+/// Packets whose payload could be replayed, holds a password or says
+/// something about the screen or the files uploaded
+fn is_secret(packet_type: PacketType) -> bool {
+    matches!(
+        packet_type,
+        PacketType::PairCommit
+            | PacketType::PairKey
+            | PacketType::PairReveal
+            | PacketType::PairConfirm
+            | PacketType::AuthChallenge
+            | PacketType::Auth
+            | PacketType::AuthPrompt
+            | PacketType::AuthReply
+            | PacketType::ContentHash
+            | PacketType::FileData
+    )
+}
+
+struct HeaderLine<'a> {
+    header: &'a PacketHeader,
+    compact: bool,
+}
+
+impl fmt::Display for HeaderLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = self.header;
+        write!(
+            f,
+            "{:?} {}x{} {:?} size={} ts={}",
+            header.packet_type, header.width, header.height, header.format, header.size, header.timestamp
+        )?;
+        if header.stride != 0 {
+            write!(f, " stride={}", header.stride)?;
+        }
+        if let Some(crc) = header.crc32 {
+            write!(f, " crc={:08x}", crc)?;
+        }
+        if header.byte_order == ByteOrder::Little {
+            write!(f, " le")?;
+        }
+        if self.compact {
+            write!(f, " compact")?;
+        }
+        Ok(())
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::FrameFormat;
+    use std::io::Read;
+    use std::process::{Command as Process, Stdio};
+
+    #[test]
+    fn test_protocol_log() {
+        let path = std::env::temp_dir().join(format!("ipdisp-protocol-{}.log.gz", std::process::id()));
+        let log = ProtocolLog::create(&path).unwrap();
+        log.connected(0, "10.0.0.5:8080");
+        let mut header = PacketHeader::new(1920, 1080, FrameFormat::Rgba32, 8_294_400);
+        header.timestamp = 42;
+        header.crc32 = Some(0x5d2f0c11);
+        log.received(0, &header, true);
+        log.control(1, PacketType::Sync, &[0, 0, 0, 0, 0, 0x0f, 0x42, 0x40]);
+        log.control(0, PacketType::AuthChallenge, &[0xab; 32]);
+        log.sent(0, &Command::Auth { id: [0xcd; 8], mac: [0xcd; 32] });
+        log.sent(0, &Command::AuthReply { text: "hunter2".into() });
+        log.sent(1, &Command::Resend { timestamp: 42 });
+        drop(log);
+
+        // Only check the contents where gzip is around to read them
+        let Ok(mut zcat) = Process::new("gzip").args(["-dc"]).arg(&path).stdout(Stdio::piped()).spawn() else {
+            std::fs::remove_file(&path).unwrap();
+            return;
+        };
+        let mut text = String::new();
+        zcat.stdout.take().unwrap().read_to_string(&mut text).unwrap();
+        assert!(zcat.wait().unwrap().success());
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<_> = text.lines().map(|line| line.split_once(' ').unwrap().1).collect();
+        assert!(text.starts_with("# ip-display-client "));
+        assert_eq!(
+            lines[1..],
+            [
+                "0- connected to 10.0.0.5:8080",
+                "0< Display 1920x1080 Rgba32 size=8294400 ts=42 crc=5d2f0c11 compact",
+                "1< Sync payload 00000000000f4240",
+                "0< AuthChallenge payload [32 bytes]",
+                "0> Auth [redacted]",
+                "0> AuthReply [redacted]",
+                "1> Resend { timestamp: 42 }",
+            ]
+        );
+        assert!(!text.contains("abab") && !text.contains("cdcd") && !text.contains("hunter2"));
+    }
+}
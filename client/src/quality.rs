@@ -0,0 +1,353 @@
+// IP Display Client - Stream Quality
+// Copyright (c) 2024
+// Licensed under MIT
+
+use clap::ValueEnum;
+use std::time::Duration;
+
+use crate::protocol::{Command, FrameFormat};
+
+/// How often the adaptive controller looks at the stream
+pub const QUALITY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Share of frames dropped in an interval above which the link is congested
+const CONGESTED_DROP_RATIO: f64 = 0.10;
+
+/// Share of frames dropped in an interval below which the link keeps up
+const CLEAR_DROP_RATIO: f64 = 0.02;
+
+/// Congested intervals in a row before stepping down
+const STEP_DOWN_AFTER: u32 = 2;
+
+/// Clear intervals in a row before trying the next level up; longer than
+/// stepping down so a marginal link doesn't oscillate
+const STEP_UP_AFTER: u32 = 10;
+
+/// Fraction of the measured throughput to cap the bit rate at once the
+/// lowest level still drops frames
+const THROUGHPUT_HEADROOM: f64 = 0.9;
+
+/// Limits the server applies to the frames it sends us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityLimits {
+    /// Bit rate cap in kbit/s (0 = no limit)
+    pub max_kbps: u32,
+    /// Resolution divisor in each direction: 1, 2 or 4
+    pub scale: u32,
+    /// Frame rate cap (0 = no limit)
+    pub max_fps: u32,
+    /// Pixel format to send frames in; `Rgba32` is the server's native one
+    pub format: FrameFormat,
+}
+
+impl QualityLimits {
+    pub const FULL: Self = Self { max_kbps: 0, scale: 1, max_fps: 0, format: FrameFormat::Rgba32 };
+
+    pub fn to_command(self) -> Command {
+        Command::Quality {
+            max_kbps: self.max_kbps,
+            scale: self.scale,
+            max_fps: self.max_fps,
+            format: self.format,
+        }
+    }
+}
+
+impl Default for QualityLimits {
+    fn default() -> Self {
+        Self::FULL
+    }
+}
+
+const HIGH: QualityLimits = QualityLimits { max_fps: 30, ..QualityLimits::FULL };
+const MEDIUM: QualityLimits = QualityLimits { scale: 2, ..HIGH };
+const LOW: QualityLimits = QualityLimits { scale: 4, max_fps: 15, ..QualityLimits::FULL };
+
+/// Frame rate caps the `Fps` step walks through
+const FPS_STEPS: [u32; 2] = [30, 15];
+
+/// Resolution divisors the `Resolution` step walks through
+const SCALE_STEPS: [u32; 2] = [2, 4];
+
+/// One kind of trade the adaptive controller can make under bandwidth
+/// pressure. The order given on the command line is the order they're
+/// tried in; kinds left out are never used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Degradation {
+    /// Cap the frame rate at 30, then 15 fps
+    Fps,
+    /// Halve, then quarter the resolution
+    Resolution,
+    /// Send 16-bit RGB565 instead of 32-bit pixels
+    Depth,
+    /// Send 4:2:0 YUV (NV12), 12 bits per pixel
+    Codec,
+}
+
+impl Degradation {
+    /// First drop frames, then pixels, then colour, then switch encoding
+    pub const DEFAULT_ORDER: [Degradation; 4] = [
+        Degradation::Fps,
+        Degradation::Resolution,
+        Degradation::Depth,
+        Degradation::Codec,
+    ];
+
+    /// Rungs this step adds below `from`, mildest first. Steps that wouldn't
+    /// lower anything (say `depth` after `codec`) add none.
+    fn rungs(self, from: QualityLimits) -> Vec<QualityLimits> {
+        match self {
+            Degradation::Fps => FPS_STEPS
+                .into_iter()
+                .filter(|&fps| from.max_fps == 0 || fps < from.max_fps)
+                .map(|max_fps| QualityLimits { max_fps, ..from })
+                .collect(),
+            Degradation::Resolution => SCALE_STEPS
+                .into_iter()
+                .filter(|&scale| scale > from.scale)
+                .map(|scale| QualityLimits { scale, ..from })
+                .collect(),
+            Degradation::Depth | Degradation::Codec => {
+                let format = if self == Degradation::Depth { FrameFormat::Rgb565 } else { FrameFormat::Nv12 };
+                if bits_per_pixel(format) < bits_per_pixel(from.format) {
+                    vec![QualityLimits { format, ..from }]
+                } else {
+                    Vec::new()
+                }
+            }
+        }
+    }
+}
+
+/// Wire cost of the formats the controller chooses between
+fn bits_per_pixel(format: FrameFormat) -> u32 {
+    match format {
+        FrameFormat::Rgb565 => 16,
+        FrameFormat::Nv12 => 12,
+        _ => 32,
+    }
+}
+
+/// The limits the adaptive controller moves through, best first
+pub fn ladder(policy: &[Degradation]) -> Vec<QualityLimits> {
+    let mut ladder = vec![QualityLimits::FULL];
+    for step in policy {
+        let rungs = step.rungs(*ladder.last().unwrap());
+        ladder.extend(rungs);
+    }
+    ladder
+}
+
+/// Quality chosen on the command line or from the Quality menu
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum QualityMode {
+    /// Follow measured throughput and frame drops
+    #[default]
+    Auto,
+    /// Full resolution and frame rate
+    Best,
+    /// Full resolution at up to 30 fps
+    High,
+    /// Half resolution at up to 30 fps
+    Medium,
+    /// Quarter resolution at up to 15 fps
+    Low,
+}
+
+impl QualityMode {
+    pub const ALL: [QualityMode; 5] = [
+        QualityMode::Auto,
+        QualityMode::Best,
+        QualityMode::High,
+        QualityMode::Medium,
+        QualityMode::Low,
+    ];
+
+    /// Fixed limits for the manual modes; `None` for auto
+    pub fn limits(self) -> Option<QualityLimits> {
+        match self {
+            QualityMode::Auto => None,
+            QualityMode::Best => Some(QualityLimits::FULL),
+            QualityMode::High => Some(HIGH),
+            QualityMode::Medium => Some(MEDIUM),
+            QualityMode::Low => Some(LOW),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            QualityMode::Auto => "auto",
+            QualityMode::Best => "best",
+            QualityMode::High => "high",
+            QualityMode::Medium => "medium",
+            QualityMode::Low => "low",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            QualityMode::Auto => "Automatic",
+            QualityMode::Best => "Best",
+            QualityMode::High => "High (30 fps)",
+            QualityMode::Medium => "Medium (half size)",
+            QualityMode::Low => "Low (quarter size)",
+        }
+    }
+}
+
+/// What the stream did over one `QUALITY_INTERVAL`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualitySample {
+    /// Frames that arrived
+    pub frames: u64,
+    /// Frames dropped before they could be shown
+    pub dropped: u64,
+    /// Receive throughput over all links
+    pub bytes_per_sec: f64,
+}
+
+/// Moves down the quality ladder while frames are being dropped and back up
+/// once the stream has been clean for a while
+#[derive(Debug)]
+pub struct AdaptiveQuality {
+    ladder: Vec<QualityLimits>,
+    level: usize,
+    max_kbps: u32,
+    congested: u32,
+    clear: u32,
+}
+
+impl AdaptiveQuality {
+    pub fn new(policy: &[Degradation]) -> Self {
+        Self { ladder: ladder(policy), level: 0, max_kbps: 0, congested: 0, clear: 0 }
+    }
+
+    pub fn limits(&self) -> QualityLimits {
+        QualityLimits { max_kbps: self.max_kbps, ..self.ladder[self.level] }
+    }
+
+    /// Feed one interval's statistics; returns the new limits when they
+    /// should change
+    pub fn update(&mut self, sample: QualitySample) -> Option<QualityLimits> {
+        if sample.frames + sample.dropped == 0 {
+            return None;
+        }
+
+        let ratio = sample.dropped as f64 / (sample.frames + sample.dropped) as f64;
+        if ratio > CONGESTED_DROP_RATIO {
+            self.clear = 0;
+            self.congested += 1;
+            if self.congested < STEP_DOWN_AFTER {
+                return None;
+            }
+            self.congested = 0;
+
+            if self.level + 1 < self.ladder.len() {
+                self.level += 1;
+            } else {
+                // Nothing left to trade; hold the server to what arrives
+                let kbps = (sample.bytes_per_sec * 8.0 / 1000.0 * THROUGHPUT_HEADROOM) as u32;
+                if kbps == 0 || (self.max_kbps != 0 && kbps >= self.max_kbps) {
+                    return None;
+                }
+                self.max_kbps = kbps;
+            }
+            return Some(self.limits());
+        }
+
+        self.congested = 0;
+        if ratio >= CLEAR_DROP_RATIO {
+            self.clear = 0;
+            return None;
+        }
+
+        self.clear += 1;
+        if self.clear < STEP_UP_AFTER {
+            return None;
+        }
+        self.clear = 0;
+
+        if self.max_kbps != 0 {
+            self.max_kbps = 0;
+        } else if self.level > 0 {
+            self.level -= 1;
+        } else {
+            return None;
+        }
+        Some(self.limits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(frames: u64, dropped: u64) -> QualitySample {
+        QualitySample { frames, dropped, bytes_per_sec: 1_000_000.0 }
+    }
+
+    #[test]
+    fn test_steps_down_on_drops_and_recovers() {
+        let mut quality = AdaptiveQuality::new(&Degradation::DEFAULT_ORDER);
+        assert_eq!(quality.update(sample(40, 20)), None);
+        assert_eq!(quality.update(sample(40, 20)), Some(HIGH));
+
+        // One clean interval isn't enough to step back up
+        assert_eq!(quality.update(sample(60, 0)), None);
+        for _ in 0..STEP_UP_AFTER - 2 {
+            assert_eq!(quality.update(sample(60, 0)), None);
+        }
+        assert_eq!(quality.update(sample(60, 0)), Some(QualityLimits::FULL));
+
+        // Idle streams tell us nothing
+        assert_eq!(quality.update(sample(0, 0)), None);
+    }
+
+    #[test]
+    fn test_caps_bitrate_at_lowest_level() {
+        let policy = [Degradation::Resolution];
+        let mut quality = AdaptiveQuality::new(&policy);
+        let steps = ladder(&policy);
+        for _ in 0..(steps.len() - 1) * STEP_DOWN_AFTER as usize {
+            quality.update(sample(10, 10));
+        }
+        assert_eq!(quality.limits(), steps[steps.len() - 1]);
+
+        quality.update(sample(10, 10));
+        let limits = quality.update(sample(10, 10)).unwrap();
+        assert_eq!(limits.max_kbps, 7200);
+        assert_eq!(limits.scale, 4);
+    }
+
+    #[test]
+    fn test_ladder_follows_policy() {
+        let steps = ladder(&Degradation::DEFAULT_ORDER);
+        let last = steps[steps.len() - 1];
+        assert_eq!(steps.len(), 7);
+        assert_eq!(steps[1], HIGH);
+        assert_eq!(steps[4], QualityLimits { scale: 4, max_fps: 15, ..QualityLimits::FULL });
+        assert_eq!(steps[5].format, FrameFormat::Rgb565);
+        assert_eq!(last, QualityLimits { format: FrameFormat::Nv12, ..steps[4] });
+
+        // Switching to YUV first leaves nothing for RGB565 to save
+        let steps = ladder(&[Degradation::Codec, Degradation::Depth, Degradation::Fps]);
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps[1].format, FrameFormat::Nv12);
+        assert_eq!(steps[3], QualityLimits { max_fps: 15, ..steps[1] });
+
+        assert_eq!(ladder(&[]), [QualityLimits::FULL]);
+    }
+
+    #[test]
+    fn test_mode_names() {
+        for mode in QualityMode::ALL {
+            assert_eq!(QualityMode::from_name(mode.name()), Some(mode));
+        }
+        assert_eq!(QualityMode::Auto.limits(), None);
+        assert_eq!(QualityMode::Medium.limits().unwrap().scale, 2);
+    }
+}
@@ -0,0 +1,153 @@
+// IP Display Client - Region Watch
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Noticing when part of the stream changes, e.g. a status panel on a
+//! monitored display. The region is sampled as a small greyscale grid, so
+//! compression noise and single pixels don't count as a change.
+
+use anyhow::Result;
+use cairo::{Format, ImageSurface};
+use std::fmt;
+use std::str::FromStr;
+
+/// Samples per side of the grid a region is reduced to
+const GRID_SIZE: i32 = 32;
+
+/// A rectangle of the stream, in stream pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Region {
+    type Err = anyhow::Error;
+
+    /// `X,Y,WIDTHxHEIGHT` or `X,Y,WIDTH,HEIGHT`, e.g. `0,0,400x300`
+    fn from_str(text: &str) -> Result<Self> {
+        let parse = || {
+            let (x, rest) = text.split_once(',')?;
+            let (y, size) = rest.split_once(',')?;
+            let (width, height) = size.split_once('x').or_else(|| size.split_once(','))?;
+            let region = Self {
+                x: x.trim().parse().ok()?,
+                y: y.trim().parse().ok()?,
+                width: width.trim().parse().ok()?,
+                height: height.trim().parse().ok()?,
+            };
+            (region.width > 0 && region.height > 0).then_some(region)
+        };
+        parse().ok_or_else(|| anyhow::anyhow!("Expected X,Y,WIDTHxHEIGHT or X,Y,WIDTH,HEIGHT, got {}", text))
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}x{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// Compares a region of each frame it is shown with the last one
+#[derive(Debug, Clone)]
+pub struct RegionWatch {
+    region: Region,
+    /// Mean change per sample, 0 to 1, that counts as a change
+    threshold: f64,
+    previous: Option<Vec<u8>>,
+}
+
+impl RegionWatch {
+    pub fn new(region: Region, threshold: f64) -> Self {
+        Self { region, threshold, previous: None }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Whether the region of `surface` differs from the last frame
+    /// checked. The first frame only sets the baseline.
+    pub fn check(&mut self, surface: &ImageSurface) -> Result<bool> {
+        let samples = self.sample(surface)?;
+        let changed = self.previous.as_ref().is_some_and(|previous| {
+            let total: u64 = previous.iter().zip(&samples).map(|(&a, &b)| a.abs_diff(b) as u64).sum();
+            total as f64 / (samples.len() as f64 * 255.0) >= self.threshold
+        });
+        self.previous = Some(samples);
+        Ok(changed)
+    }
+
+    /// The region scaled to the grid, as luma
+    fn sample(&self, surface: &ImageSurface) -> Result<Vec<u8>> {
+        let mut grid = ImageSurface::create(Format::Rgb24, GRID_SIZE, GRID_SIZE)?;
+        {
+            let context = cairo::Context::new(&grid)?;
+            context.scale(
+                GRID_SIZE as f64 / self.region.width as f64,
+                GRID_SIZE as f64 / self.region.height as f64,
+            );
+            context.set_source_surface(surface, -(self.region.x as f64), -(self.region.y as f64))?;
+            context.source().set_filter(cairo::Filter::Good);
+            context.paint()?;
+        }
+
+        let stride = grid.stride() as usize;
+        let data = grid.data()?;
+        Ok(data
+            .chunks_exact(stride)
+            .flat_map(|row| {
+                row[..GRID_SIZE as usize * 4].chunks_exact(4).map(|px| {
+                    ((px[2] as u32 * 77 + px[1] as u32 * 150 + px[0] as u32 * 29) >> 8) as u8
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn surface(fill: impl Fn(i32, i32) -> [u8; 4]) -> ImageSurface {
+        let mut surface = ImageSurface::create(Format::Rgb24, 200, 100).unwrap();
+        let stride = surface.stride() as usize;
+        {
+            let mut data = surface.data().unwrap();
+            for y in 0..100 {
+                for x in 0..200 {
+                    let at = y as usize * stride + x as usize * 4;
+                    data[at..at + 4].copy_from_slice(&fill(x, y));
+                }
+            }
+        }
+        surface
+    }
+
+    #[test]
+    fn test_region_watch() {
+        let region: Region = "100,0,100x100".parse().unwrap();
+        assert_eq!(region, Region { x: 100, y: 0, width: 100, height: 100 });
+        assert_eq!(region.to_string(), "100,0,100x100");
+        assert!("100,0,0x100".parse::<Region>().is_err());
+        assert!("100,0".parse::<Region>().is_err());
+        assert_eq!("1920, 0, 1920, 1080".parse::<Region>().unwrap(), Region { x: 1920, y: 0, width: 1920, height: 1080 });
+
+        let mut watch = RegionWatch::new(region, 0.05);
+        let grey = surface(|_, _| [128, 128, 128, 0]);
+        assert!(!watch.check(&grey).unwrap());
+        assert!(!watch.check(&grey).unwrap());
+
+        // The left half changing is outside the region
+        let left = surface(|x, _| if x < 100 { [255, 255, 255, 0] } else { [128, 128, 128, 0] });
+        assert!(!watch.check(&left).unwrap());
+
+        // A speck inside stays under the threshold, a panel doesn't
+        let speck = surface(|x, y| if x < 100 || (x == 150 && y == 50) { [255, 255, 255, 0] } else { [128, 128, 128, 0] });
+        assert!(!watch.check(&speck).unwrap());
+        let panel = surface(|x, y| if x < 100 || (x >= 120 && y < 40) { [255, 255, 255, 0] } else { [128, 128, 128, 0] });
+        assert!(watch.check(&panel).unwrap());
+    }
+}
@@ -4,53 +4,493 @@
 
 use anyhow::Result;
 use cairo::{ImageSurface, Format};
-use std::sync::{Arc, Mutex};
-use tracing::{debug, error};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+use std::time::Instant;
+use tracing::{debug, warn};
 
-#[derive(Debug)]
+use crate::convert::{self, ChromaLayout, Yuv420};
+use crate::stats::{FpsCounter, StatsHub};
+
+/// Converts one row of source pixels into surface pixels
+type ConvertRow = fn(&[u8], &mut [u8]);
+
+/// Told about each frame drawn into a `RenderTarget`
+type FrameCallback = Box<dyn Fn(&ImageSurface)>;
+
+/// Turns received frames into the picture on screen. Every backend
+/// presents through a `FrameRenderer` surface, so paintables, render
+/// targets and thumbnails work the same whichever one converts.
+pub trait Renderer: fmt::Debug {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+    
+    fn update_frame(&self, width: u32, height: u32, stride: usize, rgba_data: &[u8]) -> Result<()>;
+    
+    fn update_frame_rgb(&self, width: u32, height: u32, stride: usize, rgb_data: &[u8]) -> Result<()>;
+    
+    fn update_frame_rgb565(&self, width: u32, height: u32, stride: usize, data: &[u8]) -> Result<()>;
+    
+    fn update_frame_rgb10a2(&self, width: u32, height: u32, stride: usize, data: &[u8]) -> Result<()>;
+    
+    fn update_frame_yuv(&self, width: u32, height: u32, data: &[u8], layout: ChromaLayout) -> Result<()>;
+    
+    fn update_frame_jpeg(&self, width: u32, height: u32, data: &[u8]) -> Result<()>;
+}
+
+/// Which `Renderer` converts frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum RendererKind {
+    /// On the CPU, with SIMD where available
+    #[default]
+    Cairo,
+    /// Upload, YUV conversion and scaling on the GPU through wgpu
+    Wgpu,
+}
+
+impl RendererKind {
+    /// A renderer of this kind presenting through `output`
+    pub fn open(self, output: &FrameRenderer) -> Result<Box<dyn Renderer>> {
+        match self {
+            RendererKind::Cairo => Ok(Box::new(output.clone())),
+            #[cfg(feature = "wgpu")]
+            RendererKind::Wgpu => Ok(Box::new(crate::gpu_renderer::WgpuRenderer::new(output)?)),
+            #[cfg(not(feature = "wgpu"))]
+            RendererKind::Wgpu => Err(anyhow::anyhow!("Built without GPU rendering (the wgpu feature)")),
+        }
+    }
+}
+
+/// Still pictures for checking scaling and colour without a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum TestPattern {
+    /// Red across, green down and blue along the diagonal
+    #[default]
+    Gradient,
+    /// SMPTE colour bars with the PLUGE strip for setting black level
+    SmpteBars,
+    /// Black and white squares, sixteen along the shorter side
+    Checkerboard,
+    /// One-pixel lines every 16 pixels, red every 64, and a one-pixel
+    /// border, for spotting resampling
+    PixelGrid,
+}
+
+/// 75% bars on 7.5% setup, as sRGB
+const BAR_GREY: [u8; 3] = [192, 192, 192];
+const BAR_YELLOW: [u8; 3] = [192, 192, 0];
+const BAR_CYAN: [u8; 3] = [0, 192, 192];
+const BAR_GREEN: [u8; 3] = [0, 192, 0];
+const BAR_MAGENTA: [u8; 3] = [192, 0, 192];
+const BAR_RED: [u8; 3] = [192, 0, 0];
+const BAR_BLUE: [u8; 3] = [0, 0, 192];
+const BAR_BLACK: [u8; 3] = [19, 19, 19];
+
+impl TestPattern {
+    pub const ALL: [TestPattern; 4] = [
+        TestPattern::Gradient,
+        TestPattern::SmpteBars,
+        TestPattern::Checkerboard,
+        TestPattern::PixelGrid,
+    ];
+    
+    pub fn name(self) -> &'static str {
+        match self {
+            TestPattern::Gradient => "gradient",
+            TestPattern::SmpteBars => "smpte-bars",
+            TestPattern::Checkerboard => "checkerboard",
+            TestPattern::PixelGrid => "pixel-grid",
+        }
+    }
+    
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|pattern| pattern.name() == name)
+    }
+    
+    pub fn label(self) -> &'static str {
+        match self {
+            TestPattern::Gradient => "Gradient",
+            TestPattern::SmpteBars => "SMPTE Colour Bars",
+            TestPattern::Checkerboard => "Checkerboard",
+            TestPattern::PixelGrid => "Pixel Grid",
+        }
+    }
+    
+    /// Opaque RGBA pixels of the pattern, rows `width * 4` bytes apart
+    pub fn draw(self, width: u32, height: u32) -> Vec<u8> {
+        let (w, h) = (width as usize, height as usize);
+        let mut rgba_data = Vec::with_capacity(w * h * 4);
+        for y in 0..h {
+            for x in 0..w {
+                let [r, g, b] = self.pixel(x, y, w, h);
+                rgba_data.extend_from_slice(&[r, g, b, 255]);
+            }
+        }
+        rgba_data
+    }
+    
+    fn pixel(self, x: usize, y: usize, w: usize, h: usize) -> [u8; 3] {
+        match self {
+            TestPattern::Gradient => [
+                (x * 255 / w) as u8,
+                (y * 255 / h) as u8,
+                ((x + y) * 255 / (w + h)) as u8,
+            ],
+            TestPattern::SmpteBars => {
+                // Seven bars over two thirds, the reversed castellations,
+                // then -I, white, +Q and PLUGE under the last bars
+                let bar = x * 7 / w;
+                if y < h * 2 / 3 {
+                    [BAR_GREY, BAR_YELLOW, BAR_CYAN, BAR_GREEN, BAR_MAGENTA, BAR_RED, BAR_BLUE][bar]
+                } else if y < h * 3 / 4 {
+                    [BAR_BLUE, BAR_BLACK, BAR_MAGENTA, BAR_BLACK, BAR_CYAN, BAR_BLACK, BAR_GREY][bar]
+                } else if bar < 5 {
+                    [[0, 33, 76], [255, 255, 255], [50, 0, 106], BAR_BLACK][x * 4 * 7 / (w * 5)]
+                } else if bar == 5 {
+                    let level = [9, 19, 29][(x * 7 - 5 * w) * 3 / w];
+                    [level; 3]
+                } else {
+                    BAR_BLACK
+                }
+            }
+            TestPattern::Checkerboard => {
+                let square = (w.min(h) / 16).max(1);
+                if (x / square + y / square).is_multiple_of(2) { [255; 3] } else { [0; 3] }
+            }
+            TestPattern::PixelGrid => {
+                if x == 0 || y == 0 || x == w - 1 || y == h - 1 {
+                    [255; 3]
+                } else if x.is_multiple_of(64) || y.is_multiple_of(64) {
+                    [255, 0, 0]
+                } else if x.is_multiple_of(16) || y.is_multiple_of(16) {
+                    [255; 3]
+                } else {
+                    [0; 3]
+                }
+            }
+        }
+    }
+}
+
+/// How a frame is placed on a render target of a different size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TargetFit {
+    /// Scale to fit, keeping the aspect ratio, and centre; the rest is
+    /// transparent
+    #[default]
+    Contain,
+    /// Scale each axis to fill the target, e.g. for a texture the
+    /// application maps onto its own geometry
+    Stretch,
+}
+
+/// An offscreen surface the renderer also draws every new frame into, for
+/// applications that composite the remote display themselves. The surface
+/// can be one the application made (say over memory it uploads as a
+/// texture); `connect_frame` is called after each frame lands in it.
+pub struct RenderTarget {
+    surface: ImageSurface,
+    fit: TargetFit,
+    on_frame: Option<FrameCallback>,
+}
+
+impl RenderTarget {
+    /// A premultiplied ARGB32 target of the given size
+    pub fn new(width: u32, height: u32) -> Result<Self> {
+        Ok(Self::with_surface(ImageSurface::create(Format::ARgb32, width as i32, height as i32)?))
+    }
+    
+    pub fn with_surface(surface: ImageSurface) -> Self {
+        Self { surface, fit: TargetFit::default(), on_frame: None }
+    }
+    
+    pub fn fit(mut self, fit: TargetFit) -> Self {
+        self.fit = fit;
+        self
+    }
+    
+    pub fn connect_frame(mut self, on_frame: impl Fn(&ImageSurface) + 'static) -> Self {
+        self.on_frame = Some(Box::new(on_frame));
+        self
+    }
+    
+    pub fn surface(&self) -> &ImageSurface {
+        &self.surface
+    }
+    
+    /// Draw `frame` into the target, replacing what was there
+    fn present(&self, frame: &ImageSurface) -> Result<()> {
+        let (target_width, target_height) = (self.surface.width() as f64, self.surface.height() as f64);
+        let (scale_x, scale_y) = (target_width / frame.width() as f64, target_height / frame.height() as f64);
+        let (scale_x, scale_y) = match self.fit {
+            TargetFit::Stretch => (scale_x, scale_y),
+            TargetFit::Contain => (scale_x.min(scale_y), scale_x.min(scale_y)),
+        };
+        
+        {
+            let context = cairo::Context::new(&self.surface)?;
+            context.set_operator(cairo::Operator::Clear);
+            context.paint()?;
+            context.set_operator(cairo::Operator::Source);
+            context.translate(
+                (target_width - frame.width() as f64 * scale_x) / 2.0,
+                (target_height - frame.height() as f64 * scale_y) / 2.0,
+            );
+            context.scale(scale_x, scale_y);
+            // Pad rather than fade the edges when filtering, but only
+            // inside the frame's own rectangle
+            context.rectangle(0.0, 0.0, frame.width() as f64, frame.height() as f64);
+            context.clip();
+            context.set_source_surface(frame, 0.0, 0.0)?;
+            context.source().set_extend(cairo::Extend::Pad);
+            context.paint()?;
+        }
+        self.surface.flush();
+        
+        if let Some(on_frame) = &self.on_frame {
+            on_frame(&self.surface);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RenderTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RenderTarget")
+            .field("width", &self.surface.width())
+            .field("height", &self.surface.height())
+            .field("fit", &self.fit)
+            .finish_non_exhaustive()
+    }
+}
+
+// Lives on the GTK thread only; clones share the same surface so the draw
+// callback sees what the frame loop wrote.
 pub struct FrameRenderer {
-    surface: Arc<Mutex<Option<ImageSurface>>>,
-    width: Arc<Mutex<u32>>,
-    height: Arc<Mutex<u32>>,
+    surface: Rc<RefCell<Option<ImageSurface>>>,
+    width: Rc<Cell<u32>>,
+    height: Rc<Cell<u32>>,
+    targets: Rc<RefCell<Vec<RenderTarget>>>,
+    listeners: Rc<RefCell<Vec<FrameCallback>>>,
+    stats: Rc<StatsHub>,
+    fps: Rc<RefCell<FpsCounter>>,
+    frame_bytes: Rc<Cell<usize>>,
 }
 
 impl FrameRenderer {
     pub fn new() -> Result<Self> {
         Ok(Self {
-            surface: Arc::new(Mutex::new(None)),
-            width: Arc::new(Mutex::new(0)),
-            height: Arc::new(Mutex::new(0)),
+            surface: Rc::new(RefCell::new(None)),
+            width: Rc::new(Cell::new(0)),
+            height: Rc::new(Cell::new(0)),
+            targets: Rc::new(RefCell::new(Vec::new())),
+            listeners: Rc::new(RefCell::new(Vec::new())),
+            stats: Rc::new(StatsHub::new()),
+            fps: Rc::new(RefCell::new(FpsCounter::new())),
+            frame_bytes: Rc::new(Cell::new(0)),
         })
     }
     
-    pub fn update_frame(&self, width: u32, height: u32, rgba_data: &[u8]) -> Result<()> {
-        debug!("Updating frame: {}x{} with {} bytes", width, height, rgba_data.len());
+    /// Frame size, frame rate and received bytes, published on every frame.
+    /// The window adds the network side to the same snapshots.
+    pub fn stats(&self) -> &StatsHub {
+        &self.stats
+    }
+    
+    /// Publish the frame statistics again, so the frame rate falls off
+    /// while the stream is stalled
+    pub fn refresh_stats(&self) {
+        let fps = self.fps.borrow_mut().fps(Instant::now());
+        let (width, height, bytes) = (self.width.get(), self.height.get(), self.frame_bytes.get());
+        self.stats.update(|snapshot| {
+            snapshot.frame_width = width;
+            snapshot.frame_height = height;
+            snapshot.frame_bytes = bytes;
+            snapshot.fps = fps;
+        });
+    }
+    
+    /// Also draw every new frame into `target`
+    pub fn add_target(&self, target: RenderTarget) {
+        self.targets.borrow_mut().push(target);
+    }
+    
+    /// Stop drawing into the targets and hand them back
+    pub fn take_targets(&self) -> Vec<RenderTarget> {
+        self.targets.borrow_mut().drain(..).collect()
+    }
+    
+    /// Call `on_frame` with the renderer's own surface after each new frame
+    pub fn connect_frame_updated(&self, on_frame: impl Fn(&ImageSurface) + 'static) {
+        self.listeners.borrow_mut().push(Box::new(on_frame));
+    }
+    
+    /// Show an RGBA frame. Packed formats take `stride`, the bytes from one
+    /// source row to the next, so padded rows are read in place.
+    pub fn update_frame(&self, width: u32, height: u32, stride: usize, rgba_data: &[u8]) -> Result<()> {
+        self.update_frame_with_bpp(width, height, stride, rgba_data, 4)
+    }
+    
+    pub fn update_frame_rgb(&self, width: u32, height: u32, stride: usize, rgb_data: &[u8]) -> Result<()> {
+        self.update_frame_with_bpp(width, height, stride, rgb_data, 3)
+    }
+    
+    /// Show a little-endian RGB565 frame
+    pub fn update_frame_rgb565(&self, width: u32, height: u32, stride: usize, data: &[u8]) -> Result<()> {
+        self.update_frame_converted(width, height, stride, data, 2, (Format::Rgb24, convert::rgb565_to_bgra))
+    }
+    
+    /// Show a little-endian RGBA1010102 frame, keeping eight bits per channel
+    pub fn update_frame_rgb10a2(&self, width: u32, height: u32, stride: usize, data: &[u8]) -> Result<()> {
+        // Premultiplying by full alpha is a no-op, so opaque frames can use
+        // the same conversion into an Rgb24 surface
+        let opaque = Self::rows(data, width, stride, 4).all(|row| row.chunks_exact(4).all(|px| px[3] >= 0xc0));
+        let format = if opaque { Format::Rgb24 } else { Format::ARgb32 };
+        self.update_frame_converted(width, height, stride, data, 4, (format, convert::rgb10a2_to_bgra_premul))
+    }
+    
+    /// Show a frame already in the surface's layout, premultiplied BGRA
+    /// with rows `width * 4` bytes apart, e.g. converted on the GPU
+    pub fn update_frame_bgra(&self, width: u32, height: u32, data: &[u8], opaque: bool) -> Result<()> {
+        let format = if opaque { Format::Rgb24 } else { Format::ARgb32 };
+        self.update_frame_converted(width, height, width as usize * 4, data, 4, (format, copy_row))
+    }
+    
+    /// Decode and show one MJPEG frame, which must be `width` x `height`
+    pub fn update_frame_jpeg(&self, width: u32, height: u32, data: &[u8]) -> Result<()> {
+        let rgb = convert::decode_jpeg(data, width, height)?;
+        self.update_frame_converted(width, height, width as usize * 3, &rgb, 3, (Format::Rgb24, convert::rgb_to_bgra))?;
+        // Count the frame as received, not as decoded
+        self.frame_bytes.set(data.len());
+        self.refresh_stats();
+        Ok(())
+    }
+    
+    fn update_frame_with_bpp(&self, width: u32, height: u32, stride: usize, data: &[u8], bpp: usize) -> Result<()> {
+        // Opaque frames skip premultiplication entirely and go into an Rgb24
+        // surface, which Cairo can also paint without blending
+        let target: (Format, ConvertRow) = if bpp == 3 {
+            (Format::Rgb24, convert::rgb_to_bgra)
+        } else if Self::rows(data, width, stride, bpp).all(convert::is_opaque) {
+            (Format::Rgb24, convert::rgba_to_bgra)
+        } else {
+            (Format::ARgb32, convert::rgba_to_bgra_premul)
+        };
+        
+        self.update_frame_converted(width, height, stride, data, bpp, target)
+    }
+    
+    /// Check the size of a packed frame and convert it into a `format`
+    /// surface with `convert_row`
+    fn update_frame_converted(
+        &self,
+        width: u32,
+        height: u32,
+        stride: usize,
+        data: &[u8],
+        bpp: usize,
+        (format, convert_row): (Format, ConvertRow),
+    ) -> Result<()> {
+        debug!("Updating frame: {}x{} with {} bytes", width, height, data.len());
         
-        let expected_size = (width * height * 4) as usize;
-        if rgba_data.len() != expected_size {
+        if stride < width as usize * bpp {
+            return Err(anyhow::anyhow!("Row stride {} too small for {} pixels", stride, width));
+        }
+        
+        let expected_size = stride * height as usize;
+        if data.len() != expected_size {
             return Err(anyhow::anyhow!(
                 "Invalid data size: expected {}, got {}",
-                expected_size, rgba_data.len()
+                expected_size, data.len()
             ));
         }
         
-        // Create Cairo surface from RGBA data
-        let surface = self.create_surface_from_rgba(width, height, rgba_data)?;
+        self.write_frame(width, height, data.len(), format, |surface| {
+            Self::write_surface(surface, Self::rows(data, width, stride, bpp), convert_row)
+        })
+    }
+    
+    /// The pixels of each row, without padding
+    fn rows(data: &[u8], width: u32, stride: usize, bpp: usize) -> impl Iterator<Item = &[u8]> {
+        data.chunks_exact(stride).map(move |row| &row[..width as usize * bpp])
+    }
+    
+    /// Show a 4:2:0 frame, converting it row by row into an opaque surface
+    pub fn update_frame_yuv(&self, width: u32, height: u32, data: &[u8], layout: ChromaLayout) -> Result<()> {
+        debug!("Updating {:?} YUV frame: {}x{} with {} bytes", layout, width, height, data.len());
+        
+        let frame = Yuv420::new(data, width as usize, height as usize, layout).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Invalid data size: expected {}, got {}",
+                Yuv420::size(width as usize, height as usize, layout), data.len()
+            )
+        })?;
         
-        // Update stored surface
+        self.write_frame(width, height, data.len(), Format::Rgb24, |surface| {
+            let stride = surface.stride() as usize;
+            let mut dst = match surface.data() {
+                Ok(data) => data,
+                Err(_) => return false,
+            };
+            for (row, dst_row) in dst.chunks_mut(stride).take(height as usize).enumerate() {
+                frame.row_to_bgra(row, &mut dst_row[..width as usize * 4]);
+            }
+            true
+        })
+    }
+    
+    /// Fill a `width` x `height` surface of `format` with `fill`, which
+    /// returns false if it couldn't get at the surface data. `bytes` is
+    /// the frame's size as handed in, for the stats.
+    fn write_frame(
+        &self,
+        width: u32,
+        height: u32,
+        bytes: usize,
+        format: Format,
+        fill: impl Fn(&mut ImageSurface) -> bool,
+    ) -> Result<()> {
+        // Write into the existing surface when possible so steady-state
+        // streaming does not allocate
         {
-            let mut surf_guard = self.surface.lock().unwrap();
-            *surf_guard = Some(surface);
+            let mut surf_guard = self.surface.borrow_mut();
+            let reusable = matches!(
+                surf_guard.as_ref(),
+                Some(s) if s.width() == width as i32 && s.height() == height as i32 && s.format() == format
+            );
+            
+            if !reusable {
+                *surf_guard = Some(ImageSurface::create(format, width as i32, height as i32)?);
+            }
+            
+            let surface = surf_guard.as_mut().unwrap();
+            if !fill(surface) {
+                // Still referenced elsewhere (e.g. mid-draw): use a fresh one
+                let mut fresh = ImageSurface::create(format, width as i32, height as i32)?;
+                if !fill(&mut fresh) {
+                    return Err(anyhow::anyhow!("Failed to access surface data"));
+                }
+                *surf_guard = Some(fresh);
+            }
         }
         
         // Update dimensions
-        {
-            let mut width_guard = self.width.lock().unwrap();
-            *width_guard = width;
-        }
-        {
-            let mut height_guard = self.height.lock().unwrap();
-            *height_guard = height;
+        self.width.set(width);
+        self.height.set(height);
+        self.fps.borrow_mut().tick(Instant::now());
+        self.frame_bytes.set(bytes);
+        self.refresh_stats();
+        
+        if let Some(frame) = self.surface.borrow().as_ref() {
+            for target in self.targets.borrow().iter() {
+                if let Err(e) = target.present(frame) {
+                    warn!("Failed to draw into render target: {}", e);
+                }
+            }
+            for on_frame in self.listeners.borrow().iter() {
+                on_frame(frame);
+            }
         }
         
         debug!("Frame updated successfully");
@@ -58,94 +498,110 @@ impl FrameRenderer {
     }
     
     pub fn get_surface(&self) -> Option<ImageSurface> {
-        let surf_guard = self.surface.lock().unwrap();
-        surf_guard.clone()
+        self.surface.borrow().clone()
     }
     
     pub fn get_dimensions(&self) -> (u32, u32) {
-        let width = *self.width.lock().unwrap();
-        let height = *self.height.lock().unwrap();
-        (width, height)
+        (self.width.get(), self.height.get())
     }
     
-    fn create_surface_from_rgba(&self, width: u32, height: u32, rgba_data: &[u8]) -> Result<ImageSurface> {
-        // Convert RGBA to Cairo's ARGB32 format
-        let mut argb_data = Vec::with_capacity(rgba_data.len());
+    /// Convert packed RGB(A) rows into the surface with `convert_row`.
+    /// Returns false if the surface data can't be borrowed exclusively.
+    fn write_surface<'a>(
+        surface: &mut ImageSurface,
+        rows: impl Iterator<Item = &'a [u8]>,
+        convert_row: ConvertRow,
+    ) -> bool {
+        let width = surface.width() as usize;
+        let stride = surface.stride() as usize;
         
-        for chunk in rgba_data.chunks_exact(4) {
-            let r = chunk[0];
-            let g = chunk[1];
-            let b = chunk[2];
-            let a = chunk[3];
-            
-            // Cairo uses premultiplied alpha in ARGB32 format
-            // and expects BGRA byte order on little-endian systems
-            let alpha_f = a as f32 / 255.0;
-            let r_pre = ((r as f32 * alpha_f) as u8).min(a);
-            let g_pre = ((g as f32 * alpha_f) as u8).min(a);
-            let b_pre = ((b as f32 * alpha_f) as u8).min(a);
-            
-            // BGRA order for little-endian
-            argb_data.push(b_pre);
-            argb_data.push(g_pre);
-            argb_data.push(r_pre);
-            argb_data.push(a);
-        }
+        let mut dst = match surface.data() {
+            Ok(data) => data,
+            Err(_) => return false,
+        };
         
-        // Create Cairo image surface
-        let surface = ImageSurface::create_for_data(
-            argb_data,
-            Format::ARgb32,
-            width as i32,
-            height as i32,
-            width as i32 * 4,
-        )?;
+        for (src_row, dst_row) in rows.zip(dst.chunks_mut(stride)) {
+            convert_row(src_row, &mut dst_row[..width * 4]);
+        }
         
-        Ok(surface)
+        true
     }
     
     pub fn clear(&self) {
-        let mut surf_guard = self.surface.lock().unwrap();
-        *surf_guard = None;
-        
-        let mut width_guard = self.width.lock().unwrap();
-        *width_guard = 0;
-        
-        let mut height_guard = self.height.lock().unwrap();
-        *height_guard = 0;
+        *self.surface.borrow_mut() = None;
+        self.width.set(0);
+        self.height.set(0);
+        self.refresh_stats();
     }
     
-    pub fn create_test_pattern(&self, width: u32, height: u32) -> Result<()> {
-        debug!("Creating test pattern: {}x{}", width, height);
-        
-        // Create test pattern data
-        let mut rgba_data = Vec::with_capacity((width * height * 4) as usize);
-        
-        for y in 0..height {
-            for x in 0..width {
-                let r = ((x * 255) / width) as u8;
-                let g = ((y * 255) / height) as u8;
-                let b = ((x + y) * 255 / (width + height)) as u8;
-                let a = 255u8;
-                
-                rgba_data.extend_from_slice(&[r, g, b, a]);
-            }
-        }
+    pub fn create_test_pattern(&self, pattern: TestPattern, width: u32, height: u32) -> Result<()> {
+        debug!("Creating test pattern {:?}: {}x{}", pattern, width, height);
         
-        self.update_frame(width, height, &rgba_data)
+        let rgba_data = pattern.draw(width, height);
+        self.update_frame(width, height, width as usize * 4, &rgba_data)
+    }
+}
+
+fn copy_row(src: &[u8], dst: &mut [u8]) {
+    dst.copy_from_slice(src);
+}
+
+impl Renderer for FrameRenderer {
+    fn name(&self) -> &'static str {
+        "cairo"
+    }
+    
+    fn update_frame(&self, width: u32, height: u32, stride: usize, rgba_data: &[u8]) -> Result<()> {
+        FrameRenderer::update_frame(self, width, height, stride, rgba_data)
+    }
+    
+    fn update_frame_rgb(&self, width: u32, height: u32, stride: usize, rgb_data: &[u8]) -> Result<()> {
+        FrameRenderer::update_frame_rgb(self, width, height, stride, rgb_data)
+    }
+    
+    fn update_frame_rgb565(&self, width: u32, height: u32, stride: usize, data: &[u8]) -> Result<()> {
+        FrameRenderer::update_frame_rgb565(self, width, height, stride, data)
+    }
+    
+    fn update_frame_rgb10a2(&self, width: u32, height: u32, stride: usize, data: &[u8]) -> Result<()> {
+        FrameRenderer::update_frame_rgb10a2(self, width, height, stride, data)
+    }
+    
+    fn update_frame_yuv(&self, width: u32, height: u32, data: &[u8], layout: ChromaLayout) -> Result<()> {
+        FrameRenderer::update_frame_yuv(self, width, height, data, layout)
+    }
+    
+    fn update_frame_jpeg(&self, width: u32, height: u32, data: &[u8]) -> Result<()> {
+        FrameRenderer::update_frame_jpeg(self, width, height, data)
     }
 }
 
 impl Clone for FrameRenderer {
     fn clone(&self) -> Self {
         Self {
-            surface: Arc::clone(&self.surface),
-            width: Arc::clone(&self.width),
-            height: Arc::clone(&self.height),
+            surface: Rc::clone(&self.surface),
+            width: Rc::clone(&self.width),
+            height: Rc::clone(&self.height),
+            targets: Rc::clone(&self.targets),
+            listeners: Rc::clone(&self.listeners),
+            stats: Rc::clone(&self.stats),
+            fps: Rc::clone(&self.fps),
+            frame_bytes: Rc::clone(&self.frame_bytes),
         }
     }
 }
 
+impl fmt::Debug for FrameRenderer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameRenderer")
+            .field("surface", &self.surface)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("targets", &self.targets)
+            .finish_non_exhaustive()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -171,22 +627,123 @@ mod tests {
             255, 255, 255, 255 // White
         ];
         
-        renderer.update_frame(width, height, &rgba_data).unwrap();
+        renderer.update_frame(width, height, 8, &rgba_data).unwrap();
         
         let (w, h) = renderer.get_dimensions();
         assert_eq!(w, width);
         assert_eq!(h, height);
         assert!(renderer.get_surface().is_some());
+        let stats = renderer.stats().snapshot();
+        assert_eq!((stats.frame_width, stats.frame_height, stats.frame_bytes), (2, 2, 16));
+        assert_eq!(stats.fps, 1.0);
+    }
+    
+    #[test]
+    fn test_rgb_frame_reuses_surface() {
+        let renderer = FrameRenderer::new().unwrap();
+        let rgb_data = vec![
+            255, 0, 0,    0, 255, 0,
+            0, 0, 255,    255, 255, 255,
+        ];
+        
+        renderer.update_frame_rgb(2, 2, 6, &rgb_data).unwrap();
+        let first = renderer.get_surface().unwrap();
+        let first_ptr = first.to_raw_none();
+        drop(first);
+        
+        renderer.update_frame_rgb(2, 2, 6, &rgb_data).unwrap();
+        let mut surface = renderer.get_surface().unwrap();
+        assert_eq!(surface.to_raw_none(), first_ptr);
+        drop(renderer);
+        
+        // Red pixel, stored as BGRA
+        let data = surface.data().unwrap();
+        assert_eq!(data[0..4], [0, 0, 255, 255]);
+    }
+    
+    #[test]
+    fn test_opaque_frame_uses_rgb24() {
+        let renderer = FrameRenderer::new().unwrap();
+        let mut rgba_data = vec![
+            255, 0, 0, 255,    0, 255, 0, 255,
+            0, 0, 255, 255,    255, 255, 255, 255,
+        ];
+        
+        renderer.update_frame(2, 2, 8, &rgba_data).unwrap();
+        assert_eq!(renderer.get_surface().unwrap().format(), Format::Rgb24);
+        
+        // A single translucent pixel needs a premultiplied surface
+        rgba_data[7] = 128;
+        renderer.update_frame(2, 2, 8, &rgba_data).unwrap();
+        let mut surface = renderer.get_surface().unwrap();
+        assert_eq!(surface.format(), Format::ARgb32);
+        drop(renderer);
+        
+        let data = surface.data().unwrap();
+        assert_eq!(data[4..8], [0, 128, 0, 128]);
     }
     
     #[test]
     fn test_test_pattern() {
         let renderer = FrameRenderer::new().unwrap();
-        renderer.create_test_pattern(16, 16).unwrap();
+        renderer.create_test_pattern(TestPattern::Gradient, 16, 16).unwrap();
         
         let (width, height) = renderer.get_dimensions();
         assert_eq!(width, 16);
         assert_eq!(height, 16);
         assert!(renderer.get_surface().is_some());
+        
+        for pattern in TestPattern::ALL {
+            assert_eq!(TestPattern::from_name(pattern.name()), Some(pattern));
+            for (width, height) in [(1, 1), (7, 3), (1920, 1080)] {
+                let pixels = pattern.draw(width, height);
+                assert_eq!(pixels.len(), width as usize * height as usize * 4);
+            }
+        }
+        
+        let at = |pixels: &[u8], x: usize, y: usize| pixels[(y * 700 + x) * 4..][..4].to_vec();
+        let bars = TestPattern::SmpteBars.draw(700, 120);
+        assert_eq!(at(&bars, 50, 10), [192, 192, 192, 255]);
+        assert_eq!(at(&bars, 650, 10), [0, 0, 192, 255]);
+        assert_eq!(at(&bars, 150, 85), [19, 19, 19, 255]);
+        assert_eq!(at(&bars, 200, 110), [255, 255, 255, 255]);
+        assert_eq!(at(&bars, 510, 110), [9, 9, 9, 255]);
+        assert_eq!(at(&bars, 590, 110), [29, 29, 29, 255]);
+        
+        let grid = TestPattern::PixelGrid.draw(700, 120);
+        assert_eq!(at(&grid, 64, 5), [255, 0, 0, 255]);
+        assert_eq!(at(&grid, 16, 5), [255, 255, 255, 255]);
+        assert_eq!(at(&grid, 17, 5), [0, 0, 0, 255]);
+        assert_eq!(at(&grid, 699, 5), [255, 255, 255, 255]);
+    }
+    
+    #[test]
+    fn test_render_target() {
+        let renderer = FrameRenderer::new().unwrap();
+        let frames = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&frames);
+        renderer.add_target(RenderTarget::new(4, 2).unwrap().connect_frame(move |_| counter.set(counter.get() + 1)));
+        renderer.add_target(RenderTarget::new(4, 2).unwrap().fit(TargetFit::Stretch));
+        
+        // A 1x1 red frame
+        renderer.update_frame(1, 1, 4, &[255, 0, 0, 255]).unwrap();
+        assert_eq!(frames.get(), 1);
+        
+        let mut targets = renderer.take_targets();
+        assert_eq!(targets.len(), 2);
+        let mut stretched = targets.pop().unwrap().surface().clone();
+        let mut contained = targets.pop().unwrap().surface().clone();
+        
+        // Contain centres a 2x2 square; stretch fills the target
+        let data = contained.data().unwrap();
+        assert_eq!(data[0..4], [0, 0, 0, 0]);
+        assert_eq!(data[4..8], [0, 0, 255, 255]);
+        drop(data);
+        let data = stretched.data().unwrap();
+        assert!(data.chunks_exact(4).all(|px| px == [0, 0, 255, 255]));
+        drop(data);
+        
+        renderer.update_frame(1, 1, 4, &[0, 255, 0, 255]).unwrap();
+        assert_eq!(frames.get(), 1);
     }
 }
@@ -0,0 +1,392 @@
+// IP Display Client - Display Sources
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Where a window's frames come from. Each kind of source implements
+//! `DisplaySource`, and `--source` picks one by the scheme of its URI:
+//!
+//! | Scheme | Source |
+//! |--------|--------|
+//! | `ipds://host[:port]` | An IP Display server through `NetworkClient`, as `--server` |
+//! | `ipds+tls://host[:port]` | The same over TLS, which this build can't do |
+//! | `file:///path` | A recording made with `--dump-stream`, as `--play` |
+//! | `vnc://host[:port]` | A VNC server, see `vnc` |
+//! | `multicast://group:port` | Frames relayed by another client, see `multicast` |
+//! | `spice://host:port`, `ndi:NAME` | The `ipdisp-sources` backends, when built in |
+//!
+//! The window pumps frames from any source the same way, so a new kind
+//! only needs an entry in `SCHEMES`. Network sources reconnect by
+//! themselves and only return an error from `next_frame` when they can't
+//! go on.
+//!
+//! Links (`ipds://host:port?tls=1&profile=foo`) can carry the client's own
+//! options in their query; `parse_link` takes them out.
+
+use anyhow::Result;
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(feature = "recording")]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::{oneshot, Notify, RwLock};
+use tracing::{debug, warn};
+
+use ip_display_client::sources::{self, Picture};
+
+use crate::protocol::{Command, FrameData, FrameFormat, PacketHeader};
+#[cfg(feature = "recording")]
+use crate::stream_dump::ReplaySource;
+use crate::multicast::MulticastSource;
+use crate::vnc::VncSource;
+use crate::AppState;
+
+/// Port of an `ipds://` URI without one
+pub const DEFAULT_IPDS_PORT: u16 = 8080;
+
+/// What the `DisplaySource` methods return
+pub type SourceFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Counters a source keeps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SourceStats {
+    /// Connections made so far; each starts the stream over
+    pub connections: u64,
+    /// Frames returned by `next_frame`
+    pub frames: u64,
+}
+
+/// A stream of frames for one window
+pub trait DisplaySource: Send + Sync {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+
+    /// Open the connection. A network source that fails here keeps trying
+    /// from `next_frame`.
+    fn connect(&self) -> SourceFuture<'_, ()>;
+
+    /// The next frame to show, or `None` once the source has ended
+    fn next_frame(&self) -> SourceFuture<'_, Option<FrameData>>;
+
+    /// Pass on a request from the user, such as a mode change; sources
+    /// that can't act on it ignore it
+    fn send_input<'a>(&'a self, command: &'a Command) -> SourceFuture<'a, ()>;
+
+    fn stats(&self) -> SourceStats;
+
+    /// Close the connection for good
+    fn close(&self) -> SourceFuture<'_, ()>;
+}
+
+/// What `--source` names
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceSpec {
+    /// An IP Display server, reached through the window's network client
+    Ipds { server: String, port: u16 },
+    /// Anything else, opened with `open` when the window starts
+    Other(String),
+}
+
+type Opener = fn(&str, &str, &Arc<RwLock<AppState>>) -> Result<Arc<dyn DisplaySource>>;
+
+/// Every scheme besides `ipds` and what opens it, given the whole URI and
+/// the part after the scheme
+const SCHEMES: &[(&str, Opener)] = &[
+    ("file", open_file),
+    ("vnc", open_vnc),
+    ("multicast", open_multicast),
+    ("spice", open_spice),
+    ("ndi", open_ndi),
+    ("ipds+tls", open_tls),
+];
+
+/// Check `uri` names a source this build knows
+pub fn parse(uri: &str) -> Result<SourceSpec> {
+    let (scheme, rest) = uri
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Source {} has no scheme, e.g. ipds://host:8080", uri))?;
+    if scheme == "ipds" {
+        let (server, port) = host_port(rest, DEFAULT_IPDS_PORT)?;
+        return Ok(SourceSpec::Ipds { server, port });
+    }
+    if !SCHEMES.iter().any(|(known, _)| *known == scheme) {
+        let known: Vec<_> = std::iter::once("ipds").chain(SCHEMES.iter().map(|(known, _)| *known)).collect();
+        return Err(anyhow::anyhow!("Unknown source scheme {}: (have: {})", scheme, known.join(", ")));
+    }
+    Ok(SourceSpec::Other(uri.to_string()))
+}
+
+/// A URI given on the command line or by a link, with the options in its
+/// query taken out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    /// The source to show, without the query
+    pub source: String,
+    /// Profile to start with, as `--profile`
+    pub profile: Option<String>,
+}
+
+/// Split the options off an `ipds://` link: `tls=1` asks for `ipds+tls`,
+/// and `profile=NAME` names a profile. Other URIs are left alone.
+pub fn parse_link(uri: &str) -> Result<Link> {
+    let Some(rest) = uri.strip_prefix("ipds://") else {
+        return Ok(Link { source: uri.to_string(), profile: None });
+    };
+    let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (mut tls, mut profile) = (false, None);
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value)?;
+        match key {
+            "tls" => tls = matches!(value.as_str(), "1" | "true" | "yes"),
+            "profile" if !value.is_empty() => profile = Some(value),
+            _ => return Err(anyhow::anyhow!("Unknown option {} in {}", pair, uri)),
+        }
+    }
+    let scheme = if tls { "ipds+tls" } else { "ipds" };
+    Ok(Link { source: format!("{}://{}", scheme, address), profile })
+}
+
+/// `text` with `%XX` escapes (and `+` for space) decoded
+fn percent_decode(text: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+                let decoded = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                bytes.push(decoded.ok_or_else(|| anyhow::anyhow!("Bad escape in {}", text))?);
+                rest = &rest[2..];
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| anyhow::anyhow!("{} isn't UTF-8", text))
+}
+
+/// The source for a `SourceSpec::Other` URI
+pub fn open(uri: &str, state: &Arc<RwLock<AppState>>) -> Result<Arc<dyn DisplaySource>> {
+    let (scheme, rest) = uri.split_once(':').unwrap_or((uri, ""));
+    let (_, opener) = SCHEMES
+        .iter()
+        .find(|(known, _)| *known == scheme)
+        .ok_or_else(|| anyhow::anyhow!("Unknown source scheme {}:", scheme))?;
+    opener(uri, rest, state)
+}
+
+/// `host` and `port` from `//host[:port][/]`, IPv6 hosts in brackets
+pub fn host_port(rest: &str, default_port: u16) -> Result<(String, u16)> {
+    let address = rest
+        .strip_prefix("//")
+        .ok_or_else(|| anyhow::anyhow!("Expected //host after the scheme, got {}", rest))?
+        .trim_end_matches('/');
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            (host, port.parse().map_err(|_| anyhow::anyhow!("Invalid port {}", port))?)
+        }
+        _ => (address, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(anyhow::anyhow!("No host in {}", rest));
+    }
+    Ok((host.to_string(), port))
+}
+
+#[cfg(feature = "recording")]
+fn open_file(_uri: &str, rest: &str, state: &Arc<RwLock<AppState>>) -> Result<Arc<dyn DisplaySource>> {
+    let path = rest.strip_prefix("//").unwrap_or(rest);
+    Ok(Arc::new(ReplaySource::new(PathBuf::from(path), Arc::clone(state))))
+}
+
+#[cfg(not(feature = "recording"))]
+fn open_file(_uri: &str, _rest: &str, _state: &Arc<RwLock<AppState>>) -> Result<Arc<dyn DisplaySource>> {
+    Err(anyhow::anyhow!("Built without recordings (the recording feature)"))
+}
+
+fn open_vnc(_uri: &str, rest: &str, state: &Arc<RwLock<AppState>>) -> Result<Arc<dyn DisplaySource>> {
+    let (host, port) = host_port(rest, crate::vnc::DEFAULT_PORT)?;
+    Ok(Arc::new(VncSource::new(host, port, Arc::clone(state))))
+}
+
+fn open_multicast(_uri: &str, rest: &str, state: &Arc<RwLock<AppState>>) -> Result<Arc<dyn DisplaySource>> {
+    let (host, port) = host_port(rest, 0)?;
+    let group = host.parse().map_err(|_| anyhow::anyhow!("Expected a group address, got {}", host))?;
+    if port == 0 {
+        return Err(anyhow::anyhow!("No port in {}", rest));
+    }
+    Ok(Arc::new(MulticastSource::new(SocketAddr::new(group, port), Arc::clone(state))))
+}
+
+fn open_spice(uri: &str, _rest: &str, _state: &Arc<RwLock<AppState>>) -> Result<Arc<dyn DisplaySource>> {
+    Ok(Arc::new(LocalSource::new("spice", uri)))
+}
+
+fn open_ndi(uri: &str, _rest: &str, _state: &Arc<RwLock<AppState>>) -> Result<Arc<dyn DisplaySource>> {
+    Ok(Arc::new(LocalSource::new("ndi", uri)))
+}
+
+fn open_tls(_uri: &str, _rest: &str, _state: &Arc<RwLock<AppState>>) -> Result<Arc<dyn DisplaySource>> {
+    Err(anyhow::anyhow!("This build has no TLS support for ipds+tls://; use ipds:// through a tunnel such as stunnel"))
+}
+
+/// A source from `ipdisp-sources`. Those live on the GTK thread, so this
+/// opens one there and takes the newest picture it has drawn.
+struct LocalSource {
+    name: &'static str,
+    uri: String,
+    latest: Arc<StdMutex<Option<Picture>>>,
+    drawn: Arc<Notify>,
+    /// Dropping this closes the source on the GTK thread
+    stop: StdMutex<Option<oneshot::Sender<()>>>,
+    stats: StdMutex<SourceStats>,
+}
+
+impl LocalSource {
+    fn new(name: &'static str, uri: &str) -> Self {
+        Self {
+            name,
+            uri: uri.to_string(),
+            latest: Arc::default(),
+            drawn: Arc::default(),
+            stop: StdMutex::default(),
+            stats: StdMutex::default(),
+        }
+    }
+}
+
+impl DisplaySource for LocalSource {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn connect(&self) -> SourceFuture<'_, ()> {
+        Box::pin(async move {
+            let (opened_tx, opened_rx) = oneshot::channel();
+            let (stop_tx, stop_rx) = oneshot::channel::<()>();
+            let (uri, latest, drawn) = (self.uri.clone(), Arc::clone(&self.latest), Arc::clone(&self.drawn));
+            glib::MainContext::default().invoke(move || {
+                let opened = sources::open(&uri).and_then(|mut source| {
+                    source.connect(Box::new(move |picture| {
+                        *latest.lock().unwrap() = Some(picture);
+                        drawn.notify_one();
+                    }))?;
+                    Ok(source)
+                });
+                match opened {
+                    Ok(source) => {
+                        let _ = opened_tx.send(Ok(()));
+                        glib::MainContext::default().spawn_local(async move {
+                            let _ = stop_rx.await;
+                            drop(source);
+                        });
+                    }
+                    Err(e) => {
+                        let _ = opened_tx.send(Err(e));
+                    }
+                }
+            });
+            opened_rx.await.map_err(|_| anyhow::anyhow!("The GTK main loop has stopped"))??;
+            *self.stop.lock().unwrap() = Some(stop_tx);
+            self.stats.lock().unwrap().connections += 1;
+            Ok(())
+        })
+    }
+
+    fn next_frame(&self) -> SourceFuture<'_, Option<FrameData>> {
+        Box::pin(async move {
+            loop {
+                let picture = self.latest.lock().unwrap().take();
+                match picture {
+                    Some(picture) => match picture_frame(picture) {
+                        Ok(frame) => {
+                            self.stats.lock().unwrap().frames += 1;
+                            return Ok(Some(frame));
+                        }
+                        Err(e) => warn!("Skipping source picture: {}", e),
+                    },
+                    None => self.drawn.notified().await,
+                }
+            }
+        })
+    }
+
+    fn send_input<'a>(&'a self, command: &'a Command) -> SourceFuture<'a, ()> {
+        debug!("{} doesn't take {:?}", self.uri, command.packet_type());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn stats(&self) -> SourceStats {
+        *self.stats.lock().unwrap()
+    }
+
+    fn close(&self) -> SourceFuture<'_, ()> {
+        self.stop.lock().unwrap().take();
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A picture as an RGBA32 frame
+pub fn picture_frame(picture: Picture) -> Result<FrameData> {
+    let mut header = PacketHeader::new(picture.width, picture.height, FrameFormat::Rgba32, picture.data.len() as u32);
+    header.timestamp = picture.timestamp;
+    FrameData::new(header, picture.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            parse("ipds://10.0.0.5:9000").unwrap(),
+            SourceSpec::Ipds { server: "10.0.0.5".to_string(), port: 9000 }
+        );
+        assert_eq!(
+            parse("ipds://[fe80::1]/").unwrap(),
+            SourceSpec::Ipds { server: "fe80::1".to_string(), port: DEFAULT_IPDS_PORT }
+        );
+        assert_eq!(parse("vnc://host").unwrap(), SourceSpec::Other("vnc://host".to_string()));
+        assert!(parse("ipds://host:http").is_err());
+        assert!(parse("ipds:host").is_err());
+        assert!(parse("rdp://host").unwrap_err().to_string().contains("ipds, file, vnc"));
+        assert!(parse("host:8080").is_err());
+
+        assert_eq!(host_port("//::1", 5900).unwrap(), ("::1".to_string(), 5900));
+        assert_eq!(host_port("//[::1]:5901", 5900).unwrap(), ("::1".to_string(), 5901));
+
+        let state = Arc::new(RwLock::new(AppState::default()));
+        assert_eq!(open("file:///tmp/bug.ipds", &state).unwrap().name(), "file");
+        assert_eq!(open("vnc://host:5901", &state).unwrap().name(), "vnc");
+        assert_eq!(open("multicast://[ff15::1]:5000", &state).unwrap().name(), "multicast");
+        assert!(open("multicast://239.1.1.1", &state).is_err());
+        assert!(open("multicast://displays.local:5000", &state).is_err());
+        assert!(open("ipds+tls://host", &state).is_err());
+    }
+
+    #[test]
+    fn test_parse_link() {
+        let link = |uri| parse_link(uri).unwrap();
+        assert_eq!(link("ipds://host:9000"), Link { source: "ipds://host:9000".to_string(), profile: None });
+        assert_eq!(
+            link("ipds://host:9000/?tls=1&profile=lobby%20wall"),
+            Link { source: "ipds+tls://host:9000/".to_string(), profile: Some("lobby wall".to_string()) }
+        );
+        assert_eq!(link("ipds://[::1]?tls=0").source, "ipds://[::1]");
+        assert_eq!(link("vnc://host?x=1").source, "vnc://host?x=1");
+        assert!(parse_link("ipds://host?view=1").is_err());
+        assert!(parse_link("ipds://host?profile=%zz").is_err());
+    }
+
+    #[test]
+    fn test_picture_frame() {
+        let picture = Picture { width: 2, height: 1, timestamp: 42, data: vec![1; 8] };
+        let frame = picture_frame(picture).unwrap();
+        assert_eq!((frame.header.format, frame.header.timestamp), (FrameFormat::Rgba32, 42));
+        frame.validate().unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+// IP Display Client - Stream Statistics
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Stream statistics, and a typed snapshot of them that the HUD and any
+//! embedding dashboard read through a `StatsHub`, with the last minute of
+//! snapshots kept for graphs
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Rolling frame-rate estimate over the last second
+#[derive(Debug)]
+pub struct FpsCounter {
+    frames: VecDeque<Instant>,
+    window: Duration,
+}
+
+impl FpsCounter {
+    pub fn new() -> Self {
+        Self {
+            frames: VecDeque::new(),
+            window: Duration::from_secs(1),
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        self.frames.push_back(now);
+        self.expire(now);
+    }
+
+    pub fn fps(&mut self, now: Instant) -> f64 {
+        self.expire(now);
+        self.frames.len() as f64 / self.window.as_secs_f64()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&oldest) = self.frames.front() {
+            if now.duration_since(oldest) <= self.window {
+                break;
+            }
+            self.frames.pop_front();
+        }
+    }
+}
+
+impl Default for FpsCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rolling bytes-per-second over the last second
+#[derive(Debug, Clone)]
+pub struct ThroughputMeter {
+    samples: VecDeque<(Instant, usize)>,
+    window: Duration,
+}
+
+impl ThroughputMeter {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            window: Duration::from_secs(1),
+        }
+    }
+
+    pub fn record(&mut self, now: Instant, bytes: usize) {
+        self.samples.push_back((now, bytes));
+        while let Some(&(oldest, _)) = self.samples.front() {
+            if now.duration_since(oldest) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn bytes_per_sec(&self, now: Instant) -> f64 {
+        let bytes: usize = self
+            .samples
+            .iter()
+            .filter(|(time, _)| now.saturating_duration_since(*time) <= self.window)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        bytes as f64 / self.window.as_secs_f64()
+    }
+}
+
+impl Default for ThroughputMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Human readable bit rate, e.g. "940.2 Mbit/s"
+pub fn format_bitrate(bytes_per_sec: f64) -> String {
+    let bits = bytes_per_sec * 8.0;
+    if bits >= 1e9 {
+        format!("{:.2} Gbit/s", bits / 1e9)
+    } else if bits >= 1e6 {
+        format!("{:.1} Mbit/s", bits / 1e6)
+    } else {
+        format!("{:.0} kbit/s", bits / 1e3)
+    }
+}
+
+/// Whether frames at `fps` will judder on a panel refreshing at
+/// `refresh_hz`, i.e. the refresh isn't close to a whole multiple of the
+/// frame rate (60 fps on 120 Hz is fine, 60 fps on 75 Hz is not).
+pub fn cadence_mismatch(fps: f64, refresh_hz: f64) -> bool {
+    if fps < 1.0 || refresh_hz < 1.0 {
+        return false;
+    }
+
+    let ratio = refresh_hz / fps;
+    if ratio < 0.95 {
+        // Source is faster than the panel; frames are being skipped
+        return true;
+    }
+
+    (ratio - ratio.round()).abs() > 0.05 * ratio.round()
+}
+
+/// Receive rate of one network link
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkRate {
+    pub label: String,
+    pub bytes_per_sec: f64,
+}
+
+/// How the stream is doing at one moment
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsSnapshot {
+    /// Size of the frame on screen
+    pub frame_width: u32,
+    pub frame_height: u32,
+    /// Size of the last frame as received
+    pub frame_bytes: usize,
+    /// Frames shown over the last second
+    pub fps: f64,
+    /// Refresh rate frames are paced for; 0 if unknown
+    pub refresh_hz: f64,
+    /// Whether `refresh_hz` is a variable refresh maximum
+    pub vrr: bool,
+    /// Server stamp to draw latency, once the clocks are synchronised
+    pub latency_ms: Option<f64>,
+    /// Round trip of the best recent clock probe
+    pub rtt_ms: Option<f64>,
+    pub links: Vec<LinkRate>,
+    /// Frames dropped for a bad CRC-32 and asked for again
+    pub corrupt_frames: u64,
+    /// Times the stream lost its place and picked up at a later header
+    pub resyncs: u64,
+    /// Frames the pacer dropped, when pacing is on
+    pub paced_drops: Option<u64>,
+    /// Frames that matched the server's signed content hash
+    pub content_verified: u64,
+    /// Content hashes that were badly signed or didn't match their frame
+    pub content_mismatches: u64,
+    /// Who drives the server's input ("you", "nobody" or an address),
+    /// when one client at a time does
+    pub input_control: Option<String>,
+}
+
+impl StatsSnapshot {
+    /// Receive rate over all links
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.links.iter().map(|link| link.bytes_per_sec).sum()
+    }
+
+    /// One line for a status bar, e.g. "1920x1080  59.9 fps  24.3 Mbit/s",
+    /// then who has input control where that matters
+    pub fn summary(&self) -> String {
+        let summary = format!(
+            "{}x{}  {:.1} fps  {}",
+            self.frame_width,
+            self.frame_height,
+            self.fps,
+            format_bitrate(self.bytes_per_sec())
+        );
+        match &self.input_control {
+            Some(holder) => format!("{}  control: {}", summary, holder),
+            None => summary,
+        }
+    }
+
+    /// Whether the frame rate will judder on the display. With VRR any
+    /// cadence below the panel maximum presents cleanly.
+    pub fn cadence_mismatch(&self) -> bool {
+        if self.vrr {
+            self.refresh_hz > 0.0 && self.fps > self.refresh_hz * 1.05
+        } else {
+            cadence_mismatch(self.fps, self.refresh_hz)
+        }
+    }
+}
+
+/// How far back `StatsHistory` goes, a sample a second
+pub const HISTORY_SECS: usize = 60;
+
+/// Bit rate and frame rate at one moment of `StatsHistory`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StatsSample {
+    pub bytes_per_sec: f64,
+    pub fps: f64,
+}
+
+/// The last minute of snapshots, so periodic drops show up on a graph.
+/// Snapshots are bucketed into one-second slots from the first one; a
+/// slot holds the last snapshot recorded in it, and slots nothing was
+/// recorded in are left out.
+#[derive(Debug, Default)]
+pub struct StatsHistory {
+    samples: VecDeque<StatsSample>,
+    start: Option<Instant>,
+    /// Slot of the newest sample, in seconds since `start`
+    slot: u64,
+}
+
+impl StatsHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep `snapshot` as the sample for the second it falls in, replacing
+    /// one recorded earlier in the same second
+    pub fn record(&mut self, now: Instant, snapshot: &StatsSnapshot) {
+        let sample = StatsSample { bytes_per_sec: snapshot.bytes_per_sec(), fps: snapshot.fps };
+        let start = *self.start.get_or_insert(now);
+        let slot = now.saturating_duration_since(start).as_secs();
+        if slot == self.slot {
+            if let Some(last) = self.samples.back_mut() {
+                *last = sample;
+                return;
+            }
+        }
+        self.slot = slot;
+        if self.samples.len() == HISTORY_SECS {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Oldest first
+    pub fn samples(&self) -> impl ExactSizeIterator<Item = &StatsSample> {
+        self.samples.iter()
+    }
+
+    /// Highest bit rate and frame rate in the history
+    pub fn peak(&self) -> StatsSample {
+        self.samples.iter().fold(StatsSample::default(), |peak, sample| StatsSample {
+            bytes_per_sec: peak.bytes_per_sec.max(sample.bytes_per_sec),
+            fps: peak.fps.max(sample.fps),
+        })
+    }
+}
+
+/// Hands out the latest `StatsSnapshot`. Subscribers are woken on every
+/// publish and only ever see the newest snapshot, so a slow dashboard
+/// can't hold the stream up.
+#[derive(Debug)]
+pub struct StatsHub {
+    tx: watch::Sender<StatsSnapshot>,
+}
+
+impl StatsHub {
+    pub fn new() -> Self {
+        Self { tx: watch::Sender::new(StatsSnapshot::default()) }
+    }
+
+    pub fn publish(&self, snapshot: StatsSnapshot) {
+        self.tx.send_replace(snapshot);
+    }
+
+    /// Change some fields of the latest snapshot and publish it, leaving
+    /// what other publishers filled in
+    pub fn update(&self, modify: impl FnOnce(&mut StatsSnapshot)) {
+        self.tx.send_modify(modify);
+    }
+
+    /// The last published snapshot
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.tx.borrow().clone()
+    }
+
+    /// Follow snapshots as they are published
+    pub fn subscribe(&self) -> watch::Receiver<StatsSnapshot> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for StatsHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fps_counter_window() {
+        let mut counter = FpsCounter::new();
+        let start = Instant::now();
+
+        for i in 0..30 {
+            counter.tick(start + Duration::from_millis(i * 33));
+        }
+
+        let now = start + Duration::from_millis(990);
+        assert_eq!(counter.fps(now), 30.0);
+
+        // All frames age out of the window
+        assert_eq!(counter.fps(start + Duration::from_secs(3)), 0.0);
+    }
+
+    #[test]
+    fn test_cadence_mismatch() {
+        assert!(!cadence_mismatch(60.0, 60.0));
+        assert!(!cadence_mismatch(59.94, 60.0));
+        assert!(!cadence_mismatch(60.0, 120.0));
+        assert!(!cadence_mismatch(30.0, 120.0));
+        assert!(cadence_mismatch(60.0, 75.0));
+        assert!(cadence_mismatch(60.0, 50.0));
+        assert!(!cadence_mismatch(0.0, 60.0));
+    }
+
+    #[test]
+    fn test_throughput_window() {
+        let mut meter = ThroughputMeter::new();
+        let start = Instant::now();
+
+        meter.record(start, 1_000_000);
+        meter.record(start + Duration::from_millis(500), 500_000);
+        assert_eq!(meter.bytes_per_sec(start + Duration::from_millis(900)), 1_500_000.0);
+
+        // The first sample ages out
+        assert_eq!(meter.bytes_per_sec(start + Duration::from_millis(1200)), 500_000.0);
+        assert_eq!(format_bitrate(125_000_000.0), "1.00 Gbit/s");
+        assert_eq!(format_bitrate(1_250_000.0), "10.0 Mbit/s");
+    }
+
+    #[test]
+    fn test_stats_hub() {
+        let hub = StatsHub::new();
+        let mut rx = hub.subscribe();
+        assert!(!rx.has_changed().unwrap());
+
+        hub.publish(StatsSnapshot {
+            fps: 60.0,
+            refresh_hz: 75.0,
+            links: vec![
+                LinkRate { label: "eth0".to_string(), bytes_per_sec: 1000.0 },
+                LinkRate { label: "wlan0".to_string(), bytes_per_sec: 500.0 },
+            ],
+            ..Default::default()
+        });
+        assert!(rx.has_changed().unwrap());
+        let snapshot = rx.borrow_and_update().clone();
+        assert_eq!(snapshot, hub.snapshot());
+        assert_eq!(snapshot.bytes_per_sec(), 1500.0);
+        assert!(snapshot.cadence_mismatch());
+        assert_eq!(snapshot.summary(), "0x0  60.0 fps  12 kbit/s");
+        let held = StatsSnapshot { input_control: Some("10.0.0.5".to_string()), ..snapshot.clone() };
+        assert_eq!(held.summary(), "0x0  60.0 fps  12 kbit/s  control: 10.0.0.5");
+
+        // Below the VRR maximum is fine
+        assert!(!StatsSnapshot { vrr: true, ..snapshot }.cadence_mismatch());
+
+        // Updates keep the fields they don't touch
+        hub.update(|snapshot| snapshot.frame_width = 1920);
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(rx.borrow_and_update().frame_width, 1920);
+        assert_eq!(hub.snapshot().fps, 60.0);
+    }
+
+    #[test]
+    fn test_stats_history() {
+        let mut history = StatsHistory::new();
+        let start = Instant::now();
+        let snapshot = |fps: f64| StatsSnapshot {
+            fps,
+            links: vec![LinkRate { label: "eth0".to_string(), bytes_per_sec: fps * 1000.0 }],
+            ..Default::default()
+        };
+
+        history.record(start, &snapshot(60.0));
+        // Within the same second, replacing the first
+        history.record(start + Duration::from_millis(500), &snapshot(10.0));
+        assert_eq!(history.samples().len(), 1);
+        assert_eq!(history.peak().fps, 10.0);
+
+        // Publishing every 600 ms still fills one slot a second
+        let mut steady = StatsHistory::new();
+        for i in 0..10 {
+            steady.record(start + Duration::from_millis(i * 600), &snapshot(60.0));
+        }
+        assert_eq!(steady.samples().len(), 6);
+
+        for i in 1..=HISTORY_SECS as u64 {
+            history.record(start + Duration::from_secs(i), &snapshot(i as f64));
+        }
+        assert_eq!(history.samples().len(), HISTORY_SECS);
+        assert_eq!(history.samples().next(), Some(&StatsSample { bytes_per_sec: 1000.0, fps: 1.0 }));
+        assert_eq!(history.peak(), StatsSample { bytes_per_sec: 60_000.0, fps: 60.0 });
+    }
+}
@@ -0,0 +1,355 @@
+// IP Display Client - Stream Dumps
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Raw recordings of what the server sent, for reproducing rendering bugs
+//! without it (`--dump-stream`, played back with `--play`). An `.ipds`
+//! file is the magic `IPDS` and a little-endian `u32` version, then one
+//! record per packet in the order they arrived:
+//!
+//! ```text
+//! u64  nanoseconds since recording began
+//! u8   link the packet came in on
+//! u16  header length, extensions included
+//! u32  payload length
+//!      header and payload exactly as received
+//! ```
+//!
+//! Unlike the protocol log this holds every pixel, and files grow at the
+//! stream's bit rate. `ReplaySource` plays a dump back as a display
+//! source.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::damage::DamageCanvas;
+use crate::protocol::{self, Command, FrameData, PacketHeader, PacketType};
+use crate::source::{DisplaySource, SourceFuture, SourceStats};
+use crate::AppState;
+
+const MAGIC: &[u8; 4] = b"IPDS";
+const VERSION: u32 = 1;
+
+/// Most time a packet can sit in memory before it is flushed to the file
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+struct Recorder {
+    writer: BufWriter<File>,
+    last_flush: Instant,
+}
+
+/// A stream dump being written, shared by every link of a connection
+pub struct StreamDump {
+    path: PathBuf,
+    started: Instant,
+    /// `None` once writing has failed
+    recorder: Mutex<Option<Recorder>>,
+}
+
+impl fmt::Debug for StreamDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamDump")
+            .field("path", &self.path)
+            .field("started", &self.started)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StreamDump {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            started: Instant::now(),
+            recorder: Mutex::new(Some(Recorder { writer, last_flush: Instant::now() })),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A packet as it came in on `link`, whether or not it turns out to
+    /// be valid
+    pub fn record(&self, link: usize, header: &[u8], payload: &[u8]) {
+        let mut recorder = self.recorder.lock().unwrap();
+        let Some(active) = recorder.as_mut() else { return };
+        let now = Instant::now();
+        let mut written = write_record(&mut active.writer, now.duration_since(self.started), link, header, payload);
+        if written.is_ok() && now.duration_since(active.last_flush) >= FLUSH_INTERVAL {
+            active.last_flush = now;
+            written = active.writer.flush().map_err(Into::into);
+        }
+        if let Err(e) = written {
+            warn!("Failed to write stream dump, no longer recording: {}", e);
+            *recorder = None;
+        }
+    }
+}
+
+impl Drop for StreamDump {
+    fn drop(&mut self) {
+        if let Some(mut recorder) = self.recorder.lock().unwrap().take() {
+            if let Err(e) = recorder.writer.flush() {
+                warn!("Failed to finish stream dump: {}", e);
+            }
+        }
+    }
+}
+
+fn write_record(writer: &mut impl Write, offset: Duration, link: usize, header: &[u8], payload: &[u8]) -> Result<()> {
+    writer.write_all(&(offset.as_nanos() as u64).to_le_bytes())?;
+    writer.write_all(&[u8::try_from(link)?])?;
+    writer.write_all(&u16::try_from(header.len())?.to_le_bytes())?;
+    writer.write_all(&u32::try_from(payload.len())?.to_le_bytes())?;
+    writer.write_all(header)?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// One packet from a dump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpedPacket {
+    /// When it arrived, from the start of the recording
+    pub offset: Duration,
+    pub link: usize,
+    pub header: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// Reads a dump back one packet at a time
+pub struct DumpReader<R> {
+    reader: R,
+}
+
+impl DumpReader<tokio::io::BufReader<tokio::fs::File>> {
+    pub async fn open(path: &Path) -> Result<Self> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        Self::new(tokio::io::BufReader::new(file)).await.with_context(|| format!("Failed to read {}", path.display()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> DumpReader<R> {
+    pub async fn new(mut reader: R) -> Result<Self> {
+        let mut start = [0u8; 8];
+        reader.read_exact(&mut start).await.context("Not a stream dump")?;
+        if &start[..4] != MAGIC {
+            return Err(anyhow::anyhow!("Not a stream dump"));
+        }
+        let version = u32::from_le_bytes(start[4..].try_into()?);
+        if version != VERSION {
+            return Err(anyhow::anyhow!("Unsupported stream dump version: {}", version));
+        }
+        Ok(Self { reader })
+    }
+
+    /// The next packet, or `None` at the end. A dump cut off in the middle
+    /// of a packet, as when the client was killed, ends before it.
+    pub async fn next_packet(&mut self) -> Result<Option<DumpedPacket>> {
+        let mut fixed = [0u8; 15];
+        match self.reader.read_exact(&mut fixed[..1]).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        match self.read_rest(&mut fixed).await {
+            Ok(packet) => Ok(Some(packet)),
+            Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof) => {
+                warn!("Stream dump ends in the middle of a packet");
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn read_rest(&mut self, fixed: &mut [u8; 15]) -> Result<DumpedPacket> {
+        self.reader.read_exact(&mut fixed[1..]).await?;
+        let offset = Duration::from_nanos(u64::from_le_bytes(fixed[..8].try_into()?));
+        let link = fixed[8] as usize;
+        let header_len = u16::from_le_bytes(fixed[9..11].try_into()?) as usize;
+        let payload_len = u32::from_le_bytes(fixed[11..].try_into()?) as usize;
+
+        let mut header = vec![0u8; header_len];
+        self.reader.read_exact(&mut header).await?;
+        let mut payload = vec![0u8; payload_len];
+        self.reader.read_exact(&mut payload).await?;
+        Ok(DumpedPacket { offset, link, header, payload })
+    }
+}
+
+/// A dump being played back
+struct Replay {
+    reader: DumpReader<BufReader<tokio::fs::File>>,
+    started: tokio::time::Instant,
+    /// Last frame header from each link, which compact headers build on
+    previous: HashMap<usize, PacketHeader>,
+    /// Last whole frame, which recorded damage patches
+    canvas: DamageCanvas,
+}
+
+/// A dump played back at its original pace. Packets are parsed like the
+/// network client does, but only display info and frames have any effect.
+pub struct ReplaySource {
+    path: PathBuf,
+    state: Arc<RwLock<AppState>>,
+    replay: tokio::sync::Mutex<Option<Replay>>,
+    stats: Mutex<SourceStats>,
+}
+
+impl ReplaySource {
+    pub fn new(path: PathBuf, state: Arc<RwLock<AppState>>) -> Self {
+        Self { path, state, replay: tokio::sync::Mutex::default(), stats: Mutex::default() }
+    }
+
+    async fn next(&self, replay: &mut Replay) -> Result<Option<FrameData>> {
+        while let Some(packet) = replay.reader.next_packet().await? {
+            tokio::time::sleep_until(replay.started + packet.offset).await;
+
+            let header = match protocol::parse_header(&packet.header, replay.previous.get(&packet.link)) {
+                Ok(header) => header,
+                Err(e) => {
+                    warn!("Skipping recorded packet: {}", e);
+                    continue;
+                }
+            };
+            if header.is_frame_packet() {
+                replay.previous.insert(packet.link, header.clone());
+            }
+            if header.is_info_packet() {
+                let mut state = self.state.write().await;
+                state.display_width = header.width;
+                state.display_height = header.height;
+            }
+            let damage = header.packet_type == PacketType::Damage;
+            if !header.is_frame_packet() && !damage {
+                continue;
+            }
+
+            let mut frame = match FrameData::new(header, packet.payload) {
+                Ok(frame) if !frame.checksum_ok() => {
+                    warn!("Recorded frame {} failed its CRC-32 check", frame.header.timestamp);
+                    continue;
+                }
+                Ok(frame) => frame,
+                Err(e) => {
+                    warn!("Skipping recorded frame: {}", e);
+                    continue;
+                }
+            };
+            if damage {
+                frame = match replay.canvas.apply(&frame) {
+                    Ok(Some((header, pixels))) => FrameData::new(header, pixels.to_vec())?,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!("Skipping recorded damage: {}", e);
+                        continue;
+                    }
+                };
+            }
+            if let Err(e) = frame.validate() {
+                warn!("Skipping recorded frame: {}", e);
+                continue;
+            }
+            if !damage {
+                replay.canvas.keep(&frame);
+            }
+            return Ok(Some(frame));
+        }
+        Ok(None)
+    }
+}
+
+impl DisplaySource for ReplaySource {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn connect(&self) -> SourceFuture<'_, ()> {
+        Box::pin(async move {
+            let reader = DumpReader::open(&self.path).await?;
+            info!("Replaying {}", self.path.display());
+            let started = tokio::time::Instant::now();
+            *self.replay.lock().await = Some(Replay { reader, started, previous: HashMap::new(), canvas: DamageCanvas::keeping() });
+            self.stats.lock().unwrap().connections += 1;
+            Ok(())
+        })
+    }
+
+    fn next_frame(&self) -> SourceFuture<'_, Option<FrameData>> {
+        Box::pin(async move {
+            let mut replay = self.replay.lock().await;
+            let active = replay.as_mut().ok_or_else(|| anyhow::anyhow!("{} isn't open", self.path.display()))?;
+            let frame = self.next(active).await?;
+            match &frame {
+                Some(_) => self.stats.lock().unwrap().frames += 1,
+                None => {
+                    info!("Replay finished after {} frames", self.stats.lock().unwrap().frames);
+                    *replay = None;
+                }
+            }
+            Ok(frame)
+        })
+    }
+
+    fn send_input<'a>(&'a self, _command: &'a Command) -> SourceFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn stats(&self) -> SourceStats {
+        *self.stats.lock().unwrap()
+    }
+
+    fn close(&self) -> SourceFuture<'_, ()> {
+        Box::pin(async move {
+            self.replay.lock().await.take();
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stream_dump() {
+        let path = std::env::temp_dir().join(format!("ipds-test-{}.ipds", std::process::id()));
+        let dump = StreamDump::create(&path).unwrap();
+        dump.record(0, &[1, 2, 3], &[4, 5]);
+        dump.record(1, &[6], &[]);
+        drop(dump);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut reader = DumpReader::new(&bytes[..]).await.unwrap();
+        let first = reader.next_packet().await.unwrap().unwrap();
+        assert_eq!((first.link, &first.header[..], &first.payload[..]), (0, &[1, 2, 3][..], &[4, 5][..]));
+        let second = reader.next_packet().await.unwrap().unwrap();
+        assert_eq!((second.link, &second.header[..], second.payload.len()), (1, &[6][..], 0));
+        assert!(second.offset >= first.offset);
+        assert!(reader.next_packet().await.unwrap().is_none());
+
+        // A packet cut short ends the dump instead of failing it
+        bytes.pop();
+        let mut reader = DumpReader::new(&bytes[..]).await.unwrap();
+        assert!(reader.next_packet().await.unwrap().is_some());
+        assert!(reader.next_packet().await.unwrap().is_none());
+
+        assert!(DumpReader::new(&b"IPDX\x01\0\0\0"[..]).await.is_err());
+        assert!(DumpReader::new(&b"IPDS\x02\0\0\0"[..]).await.is_err());
+    }
+}
@@ -0,0 +1,199 @@
+// IP Display Client - Thumbnails
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Small JPEG previews of the stream for dashboards watching many clients.
+//! A thumbnail is only taken when frames have arrived since the last one,
+//! and the JPEG encoding happens on a worker thread.
+
+use anyhow::Result;
+use cairo::{Format, ImageSurface};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::renderer::FrameRenderer;
+
+/// How often a changing stream is thumbnailed by default
+pub const DEFAULT_THUMBNAIL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Default thumbnail width; the height follows the stream's aspect ratio
+pub const DEFAULT_THUMBNAIL_WIDTH: u32 = 320;
+
+/// JPEG quality of thumbnails, 1-100
+const THUMBNAIL_QUALITY: u8 = 75;
+
+/// One preview of the stream
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    pub jpeg: Arc<[u8]>,
+    /// When the frame was captured, before encoding
+    pub taken: SystemTime,
+}
+
+/// A downscaled frame waiting to be encoded. Taking one needs the GTK
+/// thread; encoding can happen anywhere.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    width: u32,
+    height: u32,
+    rgb: Vec<u8>,
+    taken: SystemTime,
+}
+
+impl Capture {
+    /// Copy `surface`, scaled down to at most `max_width` wide
+    pub fn take(surface: &ImageSurface, max_width: u32) -> Result<Self> {
+        let (width, height, rgb) = downscale(surface, max_width)?;
+        Ok(Self { width, height, rgb, taken: SystemTime::now() })
+    }
+
+    pub fn encode(&self) -> Result<Thumbnail> {
+        encode(self)
+    }
+}
+
+/// Keeps the latest thumbnail of a renderer's frames. Runs until `stop`.
+#[derive(Debug)]
+pub struct Thumbnailer {
+    rx: watch::Receiver<Option<Thumbnail>>,
+    source: glib::SourceId,
+}
+
+impl Thumbnailer {
+    /// Thumbnail `renderer` at most once per `interval`, scaled down to
+    /// `max_width`. Must be called on the GTK thread.
+    pub fn start(renderer: &FrameRenderer, interval: Duration, max_width: u32) -> Result<Self> {
+        let (tx, rx) = watch::channel(None);
+        // One capture in flight; ticks that find the worker busy retry later
+        let (capture_tx, capture_rx) = mpsc::sync_channel::<Capture>(1);
+        std::thread::Builder::new()
+            .name("thumbnailer".to_string())
+            .spawn(move || {
+                for capture in capture_rx {
+                    match encode(&capture) {
+                        Ok(thumbnail) => {
+                            tx.send_replace(Some(thumbnail));
+                        }
+                        Err(e) => warn!("Failed to encode thumbnail: {}", e),
+                    }
+                }
+            })?;
+
+        let dirty = Rc::new(Cell::new(renderer.get_surface().is_some()));
+        let damage = Rc::clone(&dirty);
+        renderer.connect_frame_updated(move |_| damage.set(true));
+
+        let renderer = renderer.clone();
+        let source = glib::timeout_add_local(interval, move || {
+            let Some(surface) = renderer.get_surface().filter(|_| dirty.get()) else {
+                return glib::ControlFlow::Continue;
+            };
+            let capture = match Capture::take(&surface, max_width) {
+                Ok(capture) => capture,
+                Err(e) => {
+                    warn!("Failed to capture thumbnail: {}", e);
+                    return glib::ControlFlow::Continue;
+                }
+            };
+            match capture_tx.try_send(capture) {
+                Ok(()) => dirty.set(false),
+                Err(mpsc::TrySendError::Full(_)) => {}
+                Err(mpsc::TrySendError::Disconnected(_)) => return glib::ControlFlow::Break,
+            }
+            glib::ControlFlow::Continue
+        });
+
+        Ok(Self { rx, source })
+    }
+
+    /// The newest thumbnail, once one has been taken
+    pub fn latest(&self) -> Option<Thumbnail> {
+        self.rx.borrow().clone()
+    }
+
+    /// Follow thumbnails as they are taken
+    pub fn subscribe(&self) -> watch::Receiver<Option<Thumbnail>> {
+        self.rx.clone()
+    }
+
+    /// Stop taking thumbnails; subscribers keep the last one
+    pub fn stop(self) {
+        self.source.remove();
+    }
+}
+
+/// Scale `surface` down to at most `max_width` wide (never up), keeping
+/// its aspect ratio, and return the size and packed RGB pixels
+fn downscale(surface: &ImageSurface, max_width: u32) -> Result<(u32, u32, Vec<u8>)> {
+    let (src_width, src_height) = (surface.width() as u32, surface.height() as u32);
+    let width = src_width.min(max_width).max(1);
+    let height = ((src_height as u64 * width as u64 / src_width.max(1) as u64) as u32).max(1);
+
+    let mut small = ImageSurface::create(Format::Rgb24, width as i32, height as i32)?;
+    {
+        let context = cairo::Context::new(&small)?;
+        context.scale(width as f64 / src_width as f64, height as f64 / src_height as f64);
+        context.set_source_surface(surface, 0.0, 0.0)?;
+        context.source().set_filter(cairo::Filter::Good);
+        context.paint()?;
+    }
+
+    let stride = small.stride() as usize;
+    let data = small.data()?;
+    let rgb = data
+        .chunks_exact(stride)
+        .flat_map(|row| row[..width as usize * 4].chunks_exact(4).flat_map(|px| [px[2], px[1], px[0]]))
+        .collect();
+    Ok((width, height, rgb))
+}
+
+fn encode(capture: &Capture) -> Result<Thumbnail> {
+    let mut jpeg = Vec::new();
+    jpeg_encoder::Encoder::new(&mut jpeg, THUMBNAIL_QUALITY).encode(
+        &capture.rgb,
+        capture.width as u16,
+        capture.height as u16,
+        jpeg_encoder::ColorType::Rgb,
+    )?;
+    Ok(Thumbnail {
+        width: capture.width,
+        height: capture.height,
+        jpeg: jpeg.into(),
+        taken: capture.taken,
+    })
+}
+
+// Checking the thumbnail means decoding it
+#[cfg(all(test, feature = "mjpeg"))]
+mod tests {
+    use super::*;
+    use crate::convert;
+
+    #[test]
+    fn test_thumbnail_capture() {
+        let renderer = FrameRenderer::new().unwrap();
+        let rgb: Vec<u8> = (0..1280 * 720).flat_map(|_| [30u8, 160, 220]).collect();
+        renderer.update_frame_rgb(1280, 720, 1280 * 3, &rgb).unwrap();
+
+        let (width, height, pixels) = downscale(&renderer.get_surface().unwrap(), 320).unwrap();
+        assert_eq!((width, height), (320, 180));
+        let thumbnail = encode(&Capture { width, height, rgb: pixels, taken: SystemTime::now() }).unwrap();
+
+        let decoded = convert::decode_jpeg(&thumbnail.jpeg, 320, 180).unwrap();
+        assert!(decoded.chunks_exact(3).all(|px| {
+            px.iter().zip([30u8, 160, 220]).all(|(&a, b)| a.abs_diff(b) <= 4)
+        }));
+
+        // Small streams are not scaled up
+        renderer.update_frame_rgb(100, 50, 300, &rgb[..100 * 50 * 3]).unwrap();
+        let (width, height, _) = downscale(&renderer.get_surface().unwrap(), 320).unwrap();
+        assert_eq!((width, height), (100, 50));
+    }
+}
@@ -0,0 +1,149 @@
+// IP Display Client - Touch Forwarding
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Touches on the stream, forwarded to the server's virtual touchscreen
+//! (`--forward-touch`). GDK tells contacts apart by event sequence; each
+//! holds the lowest free slot while it is down, as type B multitouch
+//! expects. Positions are taken back through the window's letterboxing,
+//! the stream's orientation and any crop to the remote display's pixels.
+
+use crate::paintable::Orientation;
+use ip_display_client::region::Region;
+
+/// Which contact holds each slot of the server's touchscreen
+#[derive(Debug)]
+pub struct TouchSlots<K> {
+    slots: Vec<Option<K>>,
+}
+
+impl<K: PartialEq> TouchSlots<K> {
+    pub fn new(count: u32) -> Self {
+        Self { slots: (0..count).map(|_| None).collect() }
+    }
+
+    /// A free slot for new contact `key`; none once every slot is held
+    pub fn press(&mut self, key: K) -> Option<u32> {
+        let slot = self.slots.iter().position(Option::is_none)?;
+        self.slots[slot] = Some(key);
+        Some(slot as u32)
+    }
+
+    /// The slot of contact `key`, if it is down
+    pub fn find(&self, key: &K) -> Option<u32> {
+        self.slots.iter().position(|held| held.as_ref() == Some(key)).map(|slot| slot as u32)
+    }
+
+    /// Free the slot of contact `key` as it lifts
+    pub fn release(&mut self, key: &K) -> Option<u32> {
+        let slot = self.find(key)?;
+        self.slots[slot as usize] = None;
+        Some(slot)
+    }
+}
+
+/// How the stream is laid out in the widget touched
+#[derive(Debug, Clone, Copy)]
+pub struct TouchMapping {
+    /// Size of the widget the stream fills, keeping its aspect ratio
+    pub widget: (f64, f64),
+    /// Size of the frames shown, before they are oriented
+    pub frame: (u32, u32),
+    pub orientation: Orientation,
+    /// Size of the server's touchscreen, the whole remote display
+    pub display: (u32, u32),
+    /// Part of the remote display the frames show
+    pub crop: Option<Region>,
+}
+
+impl TouchMapping {
+    /// The scale the stream is shown at, and where its top left corner is
+    fn placement(&self) -> Option<(f64, (f64, f64))> {
+        let (width, height) = self.orientation.size(self.frame.0 as f64, self.frame.1 as f64);
+        let scale = (self.widget.0 / width).min(self.widget.1 / height);
+        if !scale.is_finite() || scale <= 0.0 {
+            return None;
+        }
+        Some((scale, ((self.widget.0 - width * scale) / 2.0, (self.widget.1 - height * scale) / 2.0)))
+    }
+
+    /// Whether `x`, `y` of the widget is on the stream rather than the
+    /// bars beside it
+    pub fn covers(&self, x: f64, y: f64) -> bool {
+        let Some((scale, (left, top))) = self.placement() else { return false };
+        let (width, height) = self.orientation.size(self.frame.0 as f64, self.frame.1 as f64);
+        (left..left + width * scale).contains(&x) && (top..top + height * scale).contains(&y)
+    }
+
+    /// The remote display pixel under `x`, `y` of the widget, the nearest
+    /// one on the stream for a point off it
+    pub fn display_point(&self, x: f64, y: f64) -> Option<(u32, u32)> {
+        let (scale, (left, top)) = self.placement()?;
+        let (width, height) = (self.frame.0 as f64, self.frame.1 as f64);
+        let (shown_width, shown_height) = self.orientation.size(width, height);
+        let x = ((x - left) / scale).clamp(0.0, shown_width);
+        let y = ((y - top) / scale).clamp(0.0, shown_height);
+        let (x, y) = self.orientation.to_frame(x, y, width, height);
+
+        let area = self.crop.unwrap_or(Region { x: 0, y: 0, width: self.display.0, height: self.display.1 });
+        let along = |fraction: f64, size: u32| ((fraction * size as f64) as u32).min(size.saturating_sub(1));
+        Some((area.x + along(x / width, area.width), area.y + along(y / height, area.height)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paintable::Rotation;
+
+    #[test]
+    fn test_touch_slots() {
+        let mut slots = TouchSlots::new(2);
+        assert_eq!(slots.press("a"), Some(0));
+        assert_eq!(slots.press("b"), Some(1));
+        assert_eq!(slots.press("c"), None);
+        assert_eq!(slots.find(&"b"), Some(1));
+        assert_eq!(slots.release(&"a"), Some(0));
+        assert_eq!(slots.release(&"a"), None);
+        assert_eq!(slots.press("c"), Some(0));
+    }
+
+    #[test]
+    fn test_touch_mapping() {
+        // A 1920x1080 stream shrunk into a square: bars above and below
+        let mut mapping = TouchMapping {
+            widget: (960.0, 960.0),
+            frame: (1920, 1080),
+            orientation: Orientation::default(),
+            display: (1920, 1080),
+            crop: None,
+        };
+        assert!(!mapping.covers(480.0, 100.0));
+        assert!(mapping.covers(480.0, 480.0));
+        assert_eq!(mapping.display_point(480.0, 480.0), Some((960, 540)));
+        assert_eq!(mapping.display_point(0.0, 210.0), Some((0, 0)));
+        assert_eq!(mapping.display_point(2000.0, 2000.0), Some((1919, 1079)));
+
+        // Quarter-scale frames still land on full-size display pixels
+        mapping.frame = (480, 270);
+        assert_eq!(mapping.display_point(480.0, 480.0), Some((960, 540)));
+
+        // Turned clockwise, the top right of the window is the top left
+        // of the frame
+        mapping.orientation = Orientation::new(Rotation::Quarter, &[]);
+        mapping.widget = (1080.0, 1920.0);
+        assert_eq!(mapping.display_point(1080.0, 0.0), Some((0, 0)));
+
+        // A tile of a video wall
+        mapping.orientation = Orientation::default();
+        mapping.widget = (1920.0, 1080.0);
+        mapping.frame = (1920, 1080);
+        mapping.display = (3840, 1080);
+        mapping.crop = Some(Region { x: 1920, y: 0, width: 1920, height: 1080 });
+        assert_eq!(mapping.display_point(10.0, 20.0), Some((1930, 20)));
+
+        mapping.frame = (0, 0);
+        assert!(!mapping.covers(10.0, 20.0));
+        assert_eq!(mapping.display_point(10.0, 20.0), None);
+    }
+}
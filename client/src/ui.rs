@@ -3,33 +3,183 @@
 // Licensed under MIT
 
 use anyhow::Result;
-use gdk4::prelude::*;
-use gdk_pixbuf::Pixbuf;
 use gtk4::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, warn, error};
 
-use crate::protocol::{PacketHeader, FrameFormat};
-use crate::renderer::FrameRenderer;
+use crate::auth::SignInPrompt;
+use crate::network;
+use crate::pacing::{FrameScheduler, SyncDeadline};
+use crate::pairing::PairPrompt;
+use crate::pointer::PointerLock;
+use crate::protocol::{Capabilities, Command, FrameData, FrameFormat, InputControlAction, PromptStyle, SessionMode, SuperviseAction, TouchContact, MAX_TOUCH_PRESSURE};
+use crate::quality::QualityMode;
+use crate::adjustments::{self, Adjustments};
+use crate::hold::{HoldPolicy, Veil};
+use crate::osd::Osd;
+use crate::paintable::{Orientation, Rotation, StreamPaintable};
+use crate::renderer::{FrameRenderer, Renderer, TestPattern};
+use crate::graph;
+use crate::stats::{format_bitrate, LinkRate, StatsHistory, StatsHub, StatsSnapshot};
+use crate::timesync;
+use crate::touch::{TouchMapping, TouchSlots};
+use crate::letterbox::Letterbox;
+use crate::upload::UploadEvent;
 use crate::AppState;
 
+/// How long the identify overlay stays up, locally and on the server
+const IDENTIFY_DURATION_MS: u32 = 3000;
+
+/// Capture sources offered in Server → Capture Source; the server says
+/// which of them exist
+const CAPTURE_SOURCES: u32 = 4;
+
+/// How often stats are published while no frames are being drawn
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Least time between stats updates in the status bar
+const STATUS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Least width, and height, of the graph under the statistics HUD
+const GRAPH_SIZE: (f64, f64) = (240.0, 80.0);
+
+/// Size of a test pattern shown before any stream
+const TEST_PATTERN_SIZE: (u32, u32) = (1920, 1080);
+
+/// How long a status message stays up before stats replace it
+const MESSAGE_HOLD: Duration = Duration::from_secs(5);
+
+/// How often a stalled stream is checked on, often enough for a smooth fade
+const STALL_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often on-screen messages are redrawn while they fade out
+const OSD_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How close to the top of a fullscreen window the pointer brings the
+/// toolbar down
+const TOOLBAR_EDGE: f64 = 4.0;
+
+/// How far below the toolbar the pointer can go before it slides away
+const TOOLBAR_SLACK: f64 = 24.0;
+
+/// How long the window stays hidden before the stream is paused, so
+/// switching workspaces past it doesn't
+const PAUSE_DELAY: Duration = Duration::from_secs(2);
+
+/// How long the window has to keep its size before the server is asked to
+/// match it
+const RESIZE_SETTLE: Duration = Duration::from_millis(500);
+
+/// Smallest mode the server takes
+const MIN_MODE: (u32, u32) = (640, 480);
+
+/// GDK_TOPLEVEL_STATE_SUSPENDED: set from GTK 4.12 while the window can't
+/// be seen at all, such as when fully covered
+const TOPLEVEL_SUSPENDED: u32 = 1 << 16;
+
 #[derive(Debug)]
 pub struct DisplayWindow {
     window: gtk4::ApplicationWindow,
     drawing_area: gtk4::DrawingArea,
     status_bar: gtk4::Statusbar,
-    menu_bar: gtk4::MenuBar,
+    /// Bit rate and frame rate over the last minute, beside the status bar
+    sparkline: gtk4::DrawingArea,
+    menu_bar: gtk4::PopoverMenuBar,
+    /// In place of the bars in fullscreen, while the pointer is at the top
+    toolbar: gtk4::Revealer,
+    /// Connects or disconnects, from the toolbar
+    connect_button: gtk4::Button,
+    /// File → Recent Servers, rebuilt when the list changes
+    recent_menu: gio::Menu,
     state: Arc<RwLock<AppState>>,
     renderer: FrameRenderer,
+    /// Converts frames into `renderer`'s surface; the same renderer unless
+    /// another backend was picked
+    backend: Box<dyn Renderer>,
+    paintable: StreamPaintable,
     context_id: u32,
+    /// Status bar context the throttled stats line is pushed under
+    stats_context_id: u32,
+    /// Until when the last `set_status` message keeps the stats line off
+    message_until: Cell<Option<Instant>>,
+    /// Messages drawn over the stream
+    osd: RefCell<Osd>,
+    /// Whether the server was connected at the last check
+    was_connected: Cell<bool>,
+    commands: UnboundedSender<Command>,
+    /// Cancelled when the window closes, stopping the network tasks
+    shutdown: CancellationToken,
+    identify: RefCell<Option<(String, Instant)>>,
+    requested_mode: Cell<Option<(u32, u32, u32)>>,
+    /// View → Match Window Resolution is on
+    match_resolution: Cell<bool>,
+    /// Bumped on every resize, so only the last one of a drag asks
+    resize_serial: Cell<u32>,
+    /// The remote display resize last acted on
+    display_resized_at: Cell<Option<Instant>>,
+    /// When the last frame was shown, for sources without heartbeats
+    last_frame_at: Cell<Option<Instant>>,
+    /// Whether the last draw covered a stalled stream
+    veiled: Cell<bool>,
+    /// Minimized or otherwise out of sight
+    hidden: Cell<bool>,
+    /// Touches down on the stream, by GDK event sequence
+    touches: RefCell<TouchSlots<usize>>,
+    /// The pointer, while it is captured for the server's mouse
+    pointer: RefCell<Option<PointerLock>>,
+    /// Whether the captured pointer can be moved back to the middle
+    pointer_warps: Cell<bool>,
+    /// Server timestamp of the frame waiting for its first draw, and when
+    /// it arrived
+    undrawn: Cell<Option<(u64, Instant)>>,
+    /// Smoothed server-stamp-to-draw latency
+    latency_ms: Cell<Option<f64>>,
+    show_stats: Cell<bool>,
+    /// No decorations or bars, sized to the stream
+    borderless: Cell<bool>,
+    /// Stream size the borderless window was last fitted to
+    fitted_size: Cell<(u32, u32)>,
+    /// View → Adjustments, while it is open
+    adjustments_dialog: glib::WeakRef<gtk4::Window>,
+    /// What the server's sign-in last asked, while it is open
+    sign_in_dialog: glib::WeakRef<gtk4::Window>,
+    /// The upload under way and its progress bar
+    upload_dialog: RefCell<Option<(gtk4::Window, gtk4::ProgressBar)>>,
+    /// The last minute of published snapshots, for the graphs
+    history: RefCell<StatsHistory>,
+    scheduler: RefCell<FrameScheduler>,
+    paced: Cell<bool>,
+    /// Paced by timers at each frame's due time rather than the refresh,
+    /// for variable refresh displays
+    vrr: Cell<bool>,
+    /// Armed for the first frame waiting when `vrr` is set
+    due_timer: RefCell<Option<glib::SourceId>>,
+    /// Shown in place of the stream while set
+    test_pattern: Cell<Option<TestPattern>>,
 }
 
 impl DisplayWindow {
-    pub async fn new(app: &gtk4::Application, state: Arc<RwLock<AppState>>) -> Result<Arc<Self>> {
+    pub fn new(
+        app: &gtk4::Application,
+        state: Arc<RwLock<AppState>>,
+        commands: UnboundedSender<Command>,
+        shutdown: CancellationToken,
+    ) -> Result<Rc<Self>> {
+        // Titled with the server, to tell several windows apart
+        let title = {
+            let state_guard = state.blocking_read();
+            window_title(&network::server_address(&state_guard.server, state_guard.port))
+        };
         let window = gtk4::ApplicationWindow::builder()
             .application(app)
-            .title("IP Display Client")
+            .title(title)
             .default_width(800)
             .default_height(600)
             .build();
@@ -39,46 +189,161 @@ impl DisplayWindow {
         window.set_child(Some(&vbox));
         
         // Create menu bar
-        let menu_bar = Self::create_menu_bar(&window);
+        let recent_menu = gio::Menu::new();
+        let menu_bar = Self::create_menu_bar(&recent_menu);
         vbox.append(&menu_bar);
         
-        // Create drawing area
+        // Create renderer; a backend that can't start leaves the CPU one
+        let renderer = FrameRenderer::new()?;
+        let kind = state.blocking_read().renderer;
+        let backend = kind.open(&renderer).unwrap_or_else(|e| {
+            warn!("Can't use the {:?} renderer, converting on the CPU: {:#}", kind, e);
+            Box::new(renderer.clone())
+        });
+        
+        // The stream itself is a paintable GTK scales and composites; the
+        // drawing area on top only draws the placeholder and overlays
+        let paintable = StreamPaintable::new(&renderer);
+        {
+            let state_guard = state.blocking_read();
+            paintable.set_filter(state_guard.scale_filter);
+            paintable.set_orientation(state_guard.orientation);
+            paintable.set_adjustments(state_guard.adjustments);
+        }
+        let picture = gtk4::Picture::for_paintable(&paintable);
+        picture.set_can_shrink(true);
+        picture.add_css_class("stream");
+        let letterbox = state.blocking_read().letterbox.clone();
+        let css = gtk4::CssProvider::new();
+        css.load_from_data(&letterbox.css());
+        gtk4::style_context_add_provider_for_display(
+            &WidgetExt::display(&window),
+            &css,
+            gtk4::STYLE_PROVIDER_PRIORITY_APPLICATION,
+        );
+        
         let drawing_area = gtk4::DrawingArea::new();
-        drawing_area.set_hexpand(true);
-        drawing_area.set_vexpand(true);
+        let overlay = gtk4::Overlay::new();
+        if letterbox == Letterbox::Blur {
+            // The same frames again, stretched to the window and blurred
+            let backdrop = gtk4::Picture::for_paintable(&paintable);
+            backdrop.set_can_shrink(true);
+            backdrop.set_keep_aspect_ratio(false);
+            backdrop.set_overflow(gtk4::Overflow::Hidden);
+            backdrop.add_css_class("backdrop");
+            overlay.set_child(Some(&backdrop));
+            overlay.add_overlay(&picture);
+            overlay.set_measure_overlay(&picture, true);
+        } else {
+            overlay.set_child(Some(&picture));
+        }
+        overlay.add_overlay(&drawing_area);
+        
+        // The bars are hidden in fullscreen; this slides down instead
+        let connect_button = gtk4::Button::with_label("Disconnect");
+        let stats_button = gtk4::ToggleButton::with_label("Statistics");
+        stats_button.set_action_name(Some("win.show-stats"));
+        let exit_button = gtk4::Button::with_label("Exit Fullscreen");
+        let toolbar_box = gtk4::Box::new(gtk4::Orientation::Horizontal, 6);
+        toolbar_box.add_css_class("toolbar");
+        toolbar_box.add_css_class("osd");
+        toolbar_box.append(&connect_button);
+        toolbar_box.append(&stats_button);
+        toolbar_box.append(&exit_button);
+        let toolbar = gtk4::Revealer::new();
+        toolbar.set_transition_type(gtk4::RevealerTransitionType::SlideDown);
+        toolbar.set_halign(gtk4::Align::Center);
+        toolbar.set_valign(gtk4::Align::Start);
+        toolbar.set_child(Some(&toolbar_box));
+        overlay.add_overlay(&toolbar);
+        overlay.set_hexpand(true);
+        overlay.set_vexpand(true);
         
         // Set initial size
         {
-            let state_guard = state.read().await;
-            drawing_area.set_size_request(
+            let state_guard = state.blocking_read();
+            overlay.set_size_request(
                 state_guard.display_width as i32,
                 state_guard.display_height as i32,
             );
         }
         
-        vbox.append(&drawing_area);
+        vbox.append(&overlay);
         
         // Create status bar
         let status_bar = gtk4::Statusbar::new();
         let context_id = status_bar.context_id("main");
+        let stats_context_id = status_bar.context_id("stats");
         status_bar.push(context_id, "Ready");
-        vbox.append(&status_bar);
+        status_bar.set_hexpand(true);
+        let sparkline = gtk4::DrawingArea::new();
+        sparkline.set_content_width(120);
+        sparkline.set_margin_end(6);
+        sparkline.set_margin_top(4);
+        sparkline.set_margin_bottom(4);
+        sparkline.set_tooltip_text(Some("Bit rate (blue) and frame rate (orange) over the last minute"));
+        let status_box = gtk4::Box::new(gtk4::Orientation::Horizontal, 0);
+        status_box.append(&status_bar);
+        status_box.append(&sparkline);
+        vbox.append(&status_box);
         
-        // Create renderer
-        let renderer = FrameRenderer::new()?;
+        let scheduler = {
+            let state_guard = state.blocking_read();
+            FrameScheduler::new(
+                state_guard.pacing,
+                Duration::from_millis(state_guard.playout_delay_ms as u64),
+            )
+        };
         
-        let display_window = Arc::new(Self {
+        let display_window = Rc::new(Self {
             window,
             drawing_area,
             status_bar,
+            sparkline,
             menu_bar,
+            toolbar,
+            connect_button,
+            recent_menu,
             state: Arc::clone(&state),
             renderer,
+            backend,
+            paintable,
             context_id,
+            stats_context_id,
+            message_until: Cell::new(None),
+            osd: RefCell::new(Osd::new()),
+            was_connected: Cell::new(false),
+            commands,
+            shutdown,
+            identify: RefCell::new(None),
+            requested_mode: Cell::new(None),
+            match_resolution: Cell::new(false),
+            resize_serial: Cell::new(0),
+            display_resized_at: Cell::new(None),
+            last_frame_at: Cell::new(None),
+            veiled: Cell::new(false),
+            hidden: Cell::new(false),
+            touches: RefCell::new(TouchSlots::new(network::TOUCH_SLOTS)),
+            pointer: RefCell::new(None),
+            pointer_warps: Cell::new(false),
+            undrawn: Cell::new(None),
+            latency_ms: Cell::new(None),
+            show_stats: Cell::new(false),
+            borderless: Cell::new(false),
+            fitted_size: Cell::new((0, 0)),
+            adjustments_dialog: glib::WeakRef::new(),
+            sign_in_dialog: glib::WeakRef::new(),
+            upload_dialog: RefCell::new(None),
+            history: RefCell::new(StatsHistory::new()),
+            scheduler: RefCell::new(scheduler),
+            paced: Cell::new(false),
+            vrr: Cell::new(false),
+            due_timer: RefCell::new(None),
+            test_pattern: Cell::new(None),
         });
         
         // Setup drawing area callbacks
-        let window_weak = Arc::downgrade(&display_window);
+        let window_weak = Rc::downgrade(&display_window);
         display_window.drawing_area.set_draw_func(move |_, context, width, height| {
             if let Some(window) = window_weak.upgrade() {
                 if let Err(e) = window.on_draw(context, width, height) {
@@ -87,8 +352,25 @@ impl DisplayWindow {
             }
         });
         
+        let window_weak = Rc::downgrade(&display_window);
+        display_window.sparkline.set_draw_func(move |_, context, width, height| {
+            if let Some(window) = window_weak.upgrade() {
+                let history = window.history.borrow();
+                if let Err(e) = graph::draw(context, &history, 0.0, 0.0, width as f64, height as f64, false) {
+                    error!("Draw error: {}", e);
+                }
+            }
+        });
+        
+        let window_weak = Rc::downgrade(&display_window);
+        display_window.drawing_area.connect_resize(move |_, _, _| {
+            if let Some(window) = window_weak.upgrade() {
+                window.on_resized();
+            }
+        });
+        
         // Setup window callbacks
-        let window_weak = Arc::downgrade(&display_window);
+        let window_weak = Rc::downgrade(&display_window);
         display_window.window.connect_close_request(move |_| {
             if let Some(window) = window_weak.upgrade() {
                 window.on_close_request()
@@ -97,25 +379,421 @@ impl DisplayWindow {
             }
         });
         
+        // Keep stats subscribers current while the stream is stalled
+        let window_weak = Rc::downgrade(&display_window);
+        glib::timeout_add_local(STATS_INTERVAL, move || match window_weak.upgrade() {
+            Some(window) => {
+                window.publish_stats();
+                window.check_connection();
+                window.check_session_mode();
+                glib::ControlFlow::Continue
+            }
+            None => glib::ControlFlow::Break,
+        });
+        
+        // Redraw while the stream is stalled, to fade it out, and once more
+        // when it comes back
+        if display_window.state.blocking_read().on_stall != HoldPolicy::Hold {
+            let window_weak = Rc::downgrade(&display_window);
+            glib::timeout_add_local(STALL_CHECK_INTERVAL, move || match window_weak.upgrade() {
+                Some(window) => {
+                    if window.veiled.get() || window.stall_veil().is_some() {
+                        window.drawing_area.queue_draw();
+                    }
+                    glib::ControlFlow::Continue
+                }
+                None => glib::ControlFlow::Break,
+            });
+        }
+        
+        // Redraw while on-screen messages fade out
+        let window_weak = Rc::downgrade(&display_window);
+        glib::timeout_add_local(OSD_INTERVAL, move || match window_weak.upgrade() {
+            Some(window) => {
+                if !window.osd.borrow().is_empty() {
+                    window.drawing_area.queue_draw();
+                }
+                glib::ControlFlow::Continue
+            }
+            None => glib::ControlFlow::Break,
+        });
+        
+        // The status bar follows the stats at a fixed rate, however fast
+        // frames arrive
+        let window_weak = Rc::downgrade(&display_window);
+        let mut stats_rx = display_window.stats().subscribe();
+        glib::MainContext::default().spawn_local(async move {
+            while stats_rx.changed().await.is_ok() {
+                let Some(window) = window_weak.upgrade() else { break };
+                let snapshot = stats_rx.borrow_and_update().clone();
+                window.show_stats_status(&snapshot);
+                drop(window);
+                glib::timeout_future(STATUS_INTERVAL).await;
+            }
+        });
+        
+        // Nobody needs frames while the window can't be seen
+        if display_window.state.blocking_read().idle_pause {
+            let window_weak = Rc::downgrade(&display_window);
+            display_window.window.connect_realize(move |window| {
+                let Ok(toplevel) = window.surface().downcast::<gdk4::Toplevel>() else { return };
+                let window_weak = window_weak.clone();
+                toplevel.connect_state_notify(move |toplevel| {
+                    if let Some(window) = window_weak.upgrade() {
+                        let state = toplevel.state();
+                        window.on_visibility_changed(
+                            state.contains(gdk4::ToplevelState::MINIMIZED) || state.bits() & TOPLEVEL_SUSPENDED != 0,
+                        );
+                    }
+                });
+            });
+        }
+        
+        let window_weak = Rc::downgrade(&display_window);
+        let motion = gtk4::EventControllerMotion::new();
+        motion.connect_motion(move |_, _, y| {
+            if let Some(window) = window_weak.upgrade() {
+                window.on_top_edge_motion(y);
+            }
+        });
+        let window_weak = Rc::downgrade(&display_window);
+        motion.connect_leave(move |_| {
+            if let Some(window) = window_weak.upgrade() {
+                window.toolbar.set_reveal_child(false);
+            }
+        });
+        overlay.add_controller(motion);
+        
+        let window_weak = Rc::downgrade(&display_window);
+        display_window.connect_button.connect_clicked(move |_| {
+            if let Some(window) = window_weak.upgrade() {
+                window.toggle_connection();
+            }
+        });
+        let window_weak = Rc::downgrade(&display_window);
+        exit_button.connect_clicked(move |_| {
+            if let Some(window) = window_weak.upgrade() {
+                window.window.unfullscreen();
+            }
+        });
+        
+        // Hide the bars in fullscreen, and match the server's mode to the
+        // monitor we go fullscreen on
+        let window_weak = Rc::downgrade(&display_window);
+        display_window.window.connect_fullscreened_notify(move |_| {
+            if let Some(window) = window_weak.upgrade() {
+                window.on_fullscreen_changed();
+            }
+        });
+        
+        // A kiosk shows nothing but the stream; blocking input also keeps
+        // clicks and keys from reaching anything
+        let (kiosk, block_input) = {
+            let state_guard = state.blocking_read();
+            (state_guard.kiosk, state_guard.block_input)
+        };
+        if kiosk {
+            display_window.window.set_decorated(false);
+            display_window.menu_bar.set_visible(false);
+            display_window.status_bar.set_visible(false);
+            display_window.sparkline.set_visible(false);
+        }
+        if block_input {
+            vbox.set_can_target(false);
+        }
+        
         // Setup fullscreen toggle
-        let window_weak = Arc::downgrade(&display_window);
-        display_window.window.connect_key_pressed(move |_, key, _, _| {
+        let window_weak = Rc::downgrade(&display_window);
+        let key_controller = gtk4::EventControllerKey::new();
+        if block_input {
+            key_controller.set_propagation_phase(gtk4::PropagationPhase::Capture);
+        }
+        key_controller.connect_key_pressed(move |_, key, keycode, _| {
             if let Some(window) = window_weak.upgrade() {
-                window.on_key_pressed(key)
+                window.on_key_pressed(key, keycode)
             } else {
                 glib::Propagation::Proceed
             }
         });
+        let window_weak = Rc::downgrade(&display_window);
+        key_controller.connect_key_released(move |_, key, keycode, _| {
+            if let Some(window) = window_weak.upgrade() {
+                window.forward_key(key, keycode, false);
+            }
+        });
+        display_window.window.add_controller(key_controller);
+        
+        // Window actions
+        let identify_action = gio::SimpleAction::new("identify", None);
+        let window_weak = Rc::downgrade(&display_window);
+        identify_action.connect_activate(move |_, _| {
+            if let Some(window) = window_weak.upgrade() {
+                window.identify();
+            }
+        });
+        display_window.window.add_action(&identify_action);
+        
+        let stats_action = gio::SimpleAction::new_stateful("show-stats", None, &false.to_variant());
+        let window_weak = Rc::downgrade(&display_window);
+        stats_action.connect_activate(move |action, _| {
+            let enabled = !action.state().and_then(|v| v.get::<bool>()).unwrap_or(false);
+            action.set_state(&enabled.to_variant());
+            if let Some(window) = window_weak.upgrade() {
+                window.show_stats.set(enabled);
+                window.drawing_area.queue_draw();
+            }
+        });
+        display_window.window.add_action(&stats_action);
+        
+        let (borderless, on_top) = {
+            let state_guard = state.blocking_read();
+            (state_guard.borderless, state_guard.on_top)
+        };
+        let borderless_action = gio::SimpleAction::new_stateful("borderless", None, &borderless.to_variant());
+        let window_weak = Rc::downgrade(&display_window);
+        borderless_action.connect_activate(move |action, _| {
+            let enabled = !action.state().and_then(|v| v.get::<bool>()).unwrap_or(false);
+            action.set_state(&enabled.to_variant());
+            if let Some(window) = window_weak.upgrade() {
+                window.set_borderless(enabled);
+            }
+        });
+        display_window.window.add_action(&borderless_action);
+        
+        let on_top_action = gio::SimpleAction::new_stateful("on-top", None, &on_top.to_variant());
+        let window_weak = Rc::downgrade(&display_window);
+        on_top_action.connect_activate(move |action, _| {
+            let enabled = !action.state().and_then(|v| v.get::<bool>()).unwrap_or(false);
+            action.set_state(&enabled.to_variant());
+            if let Some(window) = window_weak.upgrade() {
+                window.set_on_top(enabled);
+            }
+        });
+        display_window.window.add_action(&on_top_action);
+        
+        let pointer_action = gio::SimpleAction::new_stateful("pointer-lock", None, &false.to_variant());
+        pointer_action.set_enabled(!block_input);
+        let window_weak = Rc::downgrade(&display_window);
+        pointer_action.connect_activate(move |action, _| {
+            let enabled = !action.state().and_then(|v| v.get::<bool>()).unwrap_or(false);
+            action.set_state(&enabled.to_variant());
+            if let Some(window) = window_weak.upgrade() {
+                window.set_pointer_captured(enabled);
+            }
+        });
+        display_window.window.add_action(&pointer_action);
+        
+        let match_action = gio::SimpleAction::new_stateful("match-resolution", None, &false.to_variant());
+        let window_weak = Rc::downgrade(&display_window);
+        match_action.connect_activate(move |action, _| {
+            let enabled = !action.state().and_then(|v| v.get::<bool>()).unwrap_or(false);
+            action.set_state(&enabled.to_variant());
+            if let Some(window) = window_weak.upgrade() {
+                window.match_resolution.set(enabled);
+                window.request_window_mode();
+            }
+        });
+        display_window.window.add_action(&match_action);
+        
+        // Switching to another window gives the pointer back
+        let window_weak = Rc::downgrade(&display_window);
+        display_window.window.connect_is_active_notify(move |window| {
+            let captured = window_weak.upgrade().is_some_and(|window| window.pointer.borrow().is_some());
+            if captured && !window.is_active() {
+                let _ = WidgetExt::activate_action(window, "win.pointer-lock", None);
+            }
+        });
+        
+        let orientation = state.blocking_read().orientation;
+        let rotate_action = gio::SimpleAction::new_stateful(
+            "rotate",
+            Some(glib::VariantTy::UINT32),
+            &orientation.rotation.degrees().to_variant(),
+        );
+        let window_weak = Rc::downgrade(&display_window);
+        rotate_action.connect_activate(move |action, parameter| {
+            let Some(rotation) = parameter.and_then(|v| v.get::<u32>()).and_then(Rotation::from_degrees) else {
+                return;
+            };
+            action.set_state(&rotation.degrees().to_variant());
+            if let Some(window) = window_weak.upgrade() {
+                let orientation = window.state.blocking_read().orientation;
+                window.set_orientation(Orientation { rotation, ..orientation });
+            }
+        });
+        display_window.window.add_action(&rotate_action);
+        
+        for (name, flipped) in [
+            ("flip-horizontal", orientation.flip_horizontal),
+            ("flip-vertical", orientation.flip_vertical),
+        ] {
+            let flip_action = gio::SimpleAction::new_stateful(name, None, &flipped.to_variant());
+            let window_weak = Rc::downgrade(&display_window);
+            flip_action.connect_activate(move |action, _| {
+                let enabled = !action.state().and_then(|v| v.get::<bool>()).unwrap_or(false);
+                action.set_state(&enabled.to_variant());
+                if let Some(window) = window_weak.upgrade() {
+                    let mut orientation = window.state.blocking_read().orientation;
+                    if action.name() == "flip-horizontal" {
+                        orientation.flip_horizontal = enabled;
+                    } else {
+                        orientation.flip_vertical = enabled;
+                    }
+                    window.set_orientation(orientation);
+                }
+            });
+            display_window.window.add_action(&flip_action);
+        }
+        
+        let adjustments_action = gio::SimpleAction::new("adjustments", None);
+        let window_weak = Rc::downgrade(&display_window);
+        adjustments_action.connect_activate(move |_, _| {
+            if let Some(window) = window_weak.upgrade() {
+                window.show_adjustments();
+            }
+        });
+        display_window.window.add_action(&adjustments_action);
+        
+        // Without decorations the window is moved by dragging the stream
+        let window_weak = Rc::downgrade(&display_window);
+        let drag = gtk4::GestureClick::new();
+        drag.set_button(gdk4::BUTTON_PRIMARY);
+        drag.connect_pressed(move |gesture, _, x, y| {
+            if let Some(window) = window_weak.upgrade() {
+                window.begin_move(gesture, x, y);
+            }
+        });
+        display_window.drawing_area.add_controller(drag);
+        
+        // Touches go to the server's touchscreen once it has one, ahead of
+        // the drag above
+        let window_weak = Rc::downgrade(&display_window);
+        let touch = gtk4::EventControllerLegacy::new();
+        touch.set_propagation_phase(gtk4::PropagationPhase::Capture);
+        touch.connect_event(move |_, event| match window_weak.upgrade() {
+            Some(window) => window.on_touch(event),
+            None => glib::Propagation::Proceed,
+        });
+        display_window.drawing_area.add_controller(touch);
+        
+        // So does the captured pointer, as motion for the server's mouse
+        let window_weak = Rc::downgrade(&display_window);
+        let pointer = gtk4::EventControllerLegacy::new();
+        pointer.set_propagation_phase(gtk4::PropagationPhase::Capture);
+        pointer.connect_event(move |_, event| match window_weak.upgrade() {
+            Some(window) => window.on_pointer(event),
+            None => glib::Propagation::Proceed,
+        });
+        display_window.drawing_area.add_controller(pointer);
+        if borderless {
+            display_window.set_borderless(true);
+        }
+        
+        let initial_quality = state.blocking_read().quality_mode.name();
+        let quality_action = gio::SimpleAction::new_stateful(
+            "quality",
+            Some(glib::VariantTy::STRING),
+            &initial_quality.to_variant(),
+        );
+        let window_weak = Rc::downgrade(&display_window);
+        quality_action.connect_activate(move |action, parameter| {
+            let Some(mode) = parameter.and_then(|v| v.str()).and_then(QualityMode::from_name) else {
+                return;
+            };
+            action.set_state(&mode.name().to_variant());
+            if let Some(window) = window_weak.upgrade() {
+                window.set_quality_mode(mode);
+            }
+        });
+        display_window.window.add_action(&quality_action);
+        
+        let recent_action = gio::SimpleAction::new("connect-recent", Some(glib::VariantTy::STRING));
+        let window_weak = Rc::downgrade(&display_window);
+        recent_action.connect_activate(move |_, parameter| {
+            let Some(addr) = parameter.and_then(|v| v.str()) else {
+                return;
+            };
+            if let Some(window) = window_weak.upgrade() {
+                window.switch_server(addr);
+            }
+        });
+        display_window.window.add_action(&recent_action);
+        
+        let supervise_action = gio::SimpleAction::new("supervise", Some(glib::VariantTy::STRING));
+        let window_weak = Rc::downgrade(&display_window);
+        supervise_action.connect_activate(move |_, parameter| {
+            let Some(request) = parameter.and_then(|v| v.str()) else {
+                return;
+            };
+            if let Some(window) = window_weak.upgrade() {
+                window.supervise(request);
+            }
+        });
+        display_window.window.add_action(&supervise_action);
+        
+        let initial_session = state.blocking_read().session_mode.name();
+        let session_action = gio::SimpleAction::new_stateful(
+            "session-mode",
+            Some(glib::VariantTy::STRING),
+            &initial_session.to_variant(),
+        );
+        let window_weak = Rc::downgrade(&display_window);
+        session_action.connect_activate(move |action, parameter| {
+            let Some(mode) = parameter.and_then(|v| v.str()).and_then(|name| name.parse::<SessionMode>().ok()) else {
+                return;
+            };
+            action.set_state(&mode.name().to_variant());
+            if let Some(window) = window_weak.upgrade() {
+                window.set_session_mode(mode);
+            }
+        });
+        display_window.window.add_action(&session_action);
+        
+        let control_action = gio::SimpleAction::new("input-control", Some(glib::VariantTy::STRING));
+        let window_weak = Rc::downgrade(&display_window);
+        control_action.connect_activate(move |_, parameter| {
+            let Some(request) = parameter.and_then(|v| v.str()) else {
+                return;
+            };
+            if let Some(window) = window_weak.upgrade() {
+                window.input_control(request);
+            }
+        });
+        display_window.window.add_action(&control_action);
+        
+        let initial_pattern = state.blocking_read().test_pattern;
+        let pattern_action = gio::SimpleAction::new_stateful(
+            "test-pattern",
+            Some(glib::VariantTy::STRING),
+            &initial_pattern.map_or("off", TestPattern::name).to_variant(),
+        );
+        let window_weak = Rc::downgrade(&display_window);
+        pattern_action.connect_activate(move |action, parameter| {
+            let Some(name) = parameter.and_then(|v| v.str()) else {
+                return;
+            };
+            action.set_state(&name.to_variant());
+            if let Some(window) = window_weak.upgrade() {
+                window.set_test_pattern(TestPattern::from_name(name));
+            }
+        });
+        display_window.window.add_action(&pattern_action);
+        if initial_pattern.is_some() {
+            display_window.set_test_pattern(initial_pattern);
+        }
+        
+        display_window.refresh_recent_menu();
         
         Ok(display_window)
     }
     
-    fn create_menu_bar(window: &gtk4::ApplicationWindow) -> gtk4::MenuBar {
-        let menu_bar = gtk4::MenuBar::new();
+    fn create_menu_bar(recent_menu: &gio::Menu) -> gtk4::PopoverMenuBar {
+        let menu_model = gio::Menu::new();
         
         // File menu
         let file_menu = gio::Menu::new();
         file_menu.append(Some("Connect"), Some("app.connect"));
+        file_menu.append_submenu(Some("Recent Servers"), recent_menu);
         file_menu.append(Some("Disconnect"), Some("app.disconnect"));
         file_menu.append(Some("Quit"), Some("app.quit"));
         
@@ -124,142 +802,1585 @@ impl DisplayWindow {
         view_menu.append(Some("Fullscreen"), Some("app.fullscreen"));
         view_menu.append(Some("Fit to Window"), Some("app.fit"));
         view_menu.append(Some("Actual Size"), Some("app.actual-size"));
+        view_menu.append(Some("Match Window Resolution"), Some("win.match-resolution"));
+        let rotation_menu = gio::Menu::new();
+        for rotation in Rotation::ALL {
+            let item = gio::MenuItem::new(Some(&format!("{}°", rotation.degrees())), None);
+            item.set_action_and_target_value(Some("win.rotate"), Some(&rotation.degrees().to_variant()));
+            rotation_menu.append_item(&item);
+        }
+        view_menu.append_submenu(Some("Rotation"), &rotation_menu);
+        view_menu.append(Some("Flip Horizontally"), Some("win.flip-horizontal"));
+        view_menu.append(Some("Flip Vertically"), Some("win.flip-vertical"));
+        view_menu.append(Some("Adjustments…"), Some("win.adjustments"));
+        view_menu.append(Some("Identify Display"), Some("win.identify"));
+        view_menu.append(Some("Statistics"), Some("win.show-stats"));
+        view_menu.append(Some("Borderless"), Some("win.borderless"));
+        view_menu.append(Some("Always on Top"), Some("win.on-top"));
+        view_menu.append(Some("Capture Pointer"), Some("win.pointer-lock"));
+        
+        // Quality menu
+        let quality_menu = gio::Menu::new();
+        for mode in QualityMode::ALL {
+            let item = gio::MenuItem::new(Some(mode.label()), None);
+            item.set_action_and_target_value(Some("win.quality"), Some(&mode.name().to_variant()));
+            quality_menu.append_item(&item);
+        }
+        
+        // Server menu: ask the server to fix itself without logging in
+        let server_menu = gio::Menu::new();
+        for (request, label) in [("resend-frame", "Resend Frame"), ("restart-capture", "Restart Capture")] {
+            let item = gio::MenuItem::new(Some(label), None);
+            item.set_action_and_target_value(Some("win.supervise"), Some(&request.to_variant()));
+            server_menu.append_item(&item);
+        }
+        let source_menu = gio::Menu::new();
+        for source in 0..CAPTURE_SOURCES {
+            let item = gio::MenuItem::new(Some(&format!("Source {}", source + 1)), None);
+            item.set_action_and_target_value(Some("win.supervise"), Some(&format!("source:{}", source).to_variant()));
+            source_menu.append_item(&item);
+        }
+        server_menu.append_submenu(Some("Capture Source"), &source_menu);
+        let item = gio::MenuItem::new(Some("Rotate Logs"), None);
+        item.set_action_and_target_value(Some("win.supervise"), Some(&"rotate-logs".to_variant()));
+        server_menu.append_item(&item);
+        let session_menu = gio::Menu::new();
+        for (mode, label) in [
+            (SessionMode::View, "View Only"),
+            (SessionMode::Input, "Input"),
+            (SessionMode::Full, "Full Control"),
+        ] {
+            let item = gio::MenuItem::new(Some(label), None);
+            item.set_action_and_target_value(Some("win.session-mode"), Some(&mode.name().to_variant()));
+            session_menu.append_item(&item);
+        }
+        server_menu.append_section(Some("Session"), &session_menu);
+        let control_menu = gio::Menu::new();
+        for (request, label) in [("request", "Request Control"), ("release", "Release Control"), ("steal", "Take Control")] {
+            let item = gio::MenuItem::new(Some(label), None);
+            item.set_action_and_target_value(Some("win.input-control"), Some(&request.to_variant()));
+            control_menu.append_item(&item);
+        }
+        server_menu.append_section(Some("Input Control"), &control_menu);
         
         // Help menu
         let help_menu = gio::Menu::new();
+        let pattern_menu = gio::Menu::new();
+        let item = gio::MenuItem::new(Some("Off"), None);
+        item.set_action_and_target_value(Some("win.test-pattern"), Some(&"off".to_variant()));
+        pattern_menu.append_item(&item);
+        for pattern in TestPattern::ALL {
+            let item = gio::MenuItem::new(Some(pattern.label()), None);
+            item.set_action_and_target_value(Some("win.test-pattern"), Some(&pattern.name().to_variant()));
+            pattern_menu.append_item(&item);
+        }
+        help_menu.append_submenu(Some("Show Test Pattern"), &pattern_menu);
         help_menu.append(Some("About"), Some("app.about"));
         
         // Add menus to menu bar
-        menu_bar.append_submenu(Some("File"), &file_menu);
-        menu_bar.append_submenu(Some("View"), &view_menu);
-        menu_bar.append_submenu(Some("Help"), &help_menu);
+        menu_model.append_submenu(Some("File"), &file_menu);
+        menu_model.append_submenu(Some("View"), &view_menu);
+        menu_model.append_submenu(Some("Quality"), &quality_menu);
+        menu_model.append_submenu(Some("Server"), &server_menu);
+        menu_model.append_submenu(Some("Help"), &help_menu);
         
-        menu_bar
+        gtk4::PopoverMenuBar::from_model(Some(&menu_model))
+    }
+    
+    /// The renderer the stream is drawn through
+    pub fn renderer(&self) -> &FrameRenderer {
+        &self.renderer
+    }
+    
+    /// Where the stream's stats are published: the renderer's frame
+    /// stats, with the network side added on every draw
+    pub fn stats(&self) -> &StatsHub {
+        self.renderer.stats()
+    }
+    
+    /// Activate the window action `name`, as its menu item would
+    pub fn activate_action(&self, name: &str, parameter: Option<&glib::Variant>) {
+        match self.window.lookup_action(name) {
+            Some(action) => action.activate(parameter),
+            None => warn!("No window action {}", name),
+        }
     }
     
     pub fn show(&self) {
         self.window.present();
+        let (fullscreen, on_top) = {
+            let state_guard = self.state.blocking_read();
+            (state_guard.fullscreen, state_guard.on_top)
+        };
+        if fullscreen {
+            self.window.fullscreen();
+        }
+        if on_top {
+            self.set_on_top(true);
+        }
     }
     
-    pub fn downgrade(&self) -> glib::WeakRef<Self> {
-        // Note: This is a simplified implementation
-        // In a real application, you'd want to use proper weak references
-        glib::WeakRef::new()
+    /// Drop (or bring back) the decorations, menu bar and status bar, and
+    /// size the window to the stream, for picture-in-picture use
+    fn set_borderless(&self, borderless: bool) {
+        self.borderless.set(borderless);
+        let kiosk = self.state.blocking_read().kiosk;
+        self.window.set_decorated(!borderless && !kiosk);
+        self.update_bars();
+        self.fitted_size.set((0, 0));
+        self.fit_to_stream();
     }
     
-    pub async fn update_frame(&self, header: &PacketHeader, data: &[u8]) -> Result<()> {
-        debug!("Updating frame: {}x{} {} bytes", header.width, header.height, data.len());
+    /// Turn and/or mirror the stream on screen
+    fn set_orientation(&self, orientation: Orientation) {
+        self.state.blocking_write().orientation = orientation;
+        self.paintable.set_orientation(orientation);
+        self.fitted_size.set((0, 0));
+        self.fit_to_stream();
+    }
+    
+    /// Change how the stream's colours are shown
+    fn set_adjustments(&self, adjustments: Adjustments) {
+        self.state.blocking_write().adjustments = adjustments;
+        self.paintable.set_adjustments(adjustments);
+    }
+    
+    /// Sliders for brightness, contrast, gamma and saturation, applied as
+    /// they move
+    fn show_adjustments(self: &Rc<Self>) {
+        if let Some(dialog) = self.adjustments_dialog.upgrade() {
+            dialog.present();
+            return;
+        }
+        let dialog = gtk4::Window::builder()
+            .title("Adjustments")
+            .transient_for(&self.window)
+            .resizable(false)
+            .build();
         
-        // Convert frame data to displayable format
-        let rgba_data = match header.format {
-            FrameFormat::Rgba32 => data.to_vec(),
-            FrameFormat::Rgb24 => {
-                let mut rgba = Vec::with_capacity(data.len() * 4 / 3);
-                for chunk in data.chunks_exact(3) {
-                    rgba.extend_from_slice(&[chunk[0], chunk[1], chunk[2], 255]);
+        let grid = gtk4::Grid::new();
+        grid.set_row_spacing(12);
+        grid.set_column_spacing(12);
+        grid.set_margin_top(18);
+        grid.set_margin_bottom(18);
+        grid.set_margin_start(18);
+        grid.set_margin_end(18);
+        
+        type Field = fn(&mut Adjustments) -> &mut f64;
+        let settings: [(&str, (f64, f64), Field); 4] = [
+            ("Brightness", adjustments::BRIGHTNESS_RANGE, |a| &mut a.brightness),
+            ("Contrast", adjustments::CONTRAST_RANGE, |a| &mut a.contrast),
+            ("Gamma", adjustments::GAMMA_RANGE, |a| &mut a.gamma),
+            ("Saturation", adjustments::SATURATION_RANGE, |a| &mut a.saturation),
+        ];
+        let mut current = self.state.blocking_read().adjustments;
+        let mut scales = Vec::new();
+        for (row, (name, (low, high), field)) in settings.into_iter().enumerate() {
+            let label = gtk4::Label::new(Some(name));
+            label.set_halign(gtk4::Align::Start);
+            let scale = gtk4::Scale::with_range(gtk4::Orientation::Horizontal, low, high, 0.01);
+            scale.set_digits(2);
+            scale.set_draw_value(true);
+            scale.set_size_request(240, -1);
+            scale.set_value(*field(&mut current));
+            
+            let window_weak = Rc::downgrade(self);
+            scale.connect_value_changed(move |scale| {
+                if let Some(window) = window_weak.upgrade() {
+                    let mut adjustments = window.state.blocking_read().adjustments;
+                    *field(&mut adjustments) = scale.value();
+                    window.set_adjustments(adjustments);
                 }
-                rgba
+            });
+            grid.attach(&label, 0, row as i32, 1, 1);
+            grid.attach(&scale, 1, row as i32, 1, 1);
+            scales.push((scale, field));
+        }
+        
+        let reset = gtk4::Button::with_label("Reset");
+        reset.set_halign(gtk4::Align::End);
+        reset.connect_clicked(move |_| {
+            let mut defaults = Adjustments::default();
+            for (scale, field) in &scales {
+                scale.set_value(*field(&mut defaults));
             }
-            FrameFormat::H264 | FrameFormat::H265 => {
-                warn!("Codec formats not yet supported");
-                return Ok(());
+        });
+        grid.attach(&reset, 1, settings.len() as i32, 1, 1);
+        
+        dialog.set_child(Some(&grid));
+        self.adjustments_dialog.set(Some(&dialog));
+        dialog.present();
+    }
+    
+    /// Show `pattern` in place of the stream, at the stream's size, or go
+    /// back to the stream with `None`
+    fn set_test_pattern(&self, pattern: Option<TestPattern>) {
+        self.test_pattern.set(pattern);
+        let Some(pattern) = pattern else {
+            // The next frame replaces the pattern; without a stream there
+            // won't be one
+            if !self.state.blocking_read().connected {
+                self.renderer.clear();
+                self.drawing_area.queue_draw();
             }
+            return;
         };
         
-        // Update renderer
-        self.renderer.update_frame(header.width, header.height, &rgba_data)?;
-        
-        // Update status
-        let status = format!("Frame: {}x{} - {} bytes", header.width, header.height, data.len());
-        self.status_bar.push(self.context_id, &status);
-        
-        // Trigger redraw
+        let (width, height) = match self.renderer.get_dimensions() {
+            (0, _) | (_, 0) => {
+                let state_guard = self.state.blocking_read();
+                match (state_guard.display_width, state_guard.display_height) {
+                    (0, _) | (_, 0) => TEST_PATTERN_SIZE,
+                    size => size,
+                }
+            }
+            size => size,
+        };
+        if let Err(e) = self.renderer.create_test_pattern(pattern, width, height) {
+            warn!("Failed to draw test pattern: {}", e);
+            return;
+        }
+        self.set_status(&format!("Showing {} at {}x{}", pattern.label(), width, height));
+        self.fit_to_stream();
         self.drawing_area.queue_draw();
-        
-        Ok(())
     }
     
-    fn on_draw(&self, context: &cairo::Context, width: i32, height: i32) -> Result<()> {
-        // Clear background
-        context.set_source_rgb(0.0, 0.0, 0.0);
-        context.paint()?;
-        
-        // Draw frame if available
-        if let Some(surface) = self.renderer.get_surface() {
-            let surface_width = surface.width() as f64;
-            let surface_height = surface.height() as f64;
-            
-            // Calculate scaling to fit in window
-            let scale_x = width as f64 / surface_width;
-            let scale_y = height as f64 / surface_height;
-            let scale = scale_x.min(scale_y);
-            
-            // Center the image
-            let x = (width as f64 - surface_width * scale) / 2.0;
-            let y = (height as f64 - surface_height * scale) / 2.0;
-            
-            context.save()?;
-            context.translate(x, y);
-            context.scale(scale, scale);
-            context.set_source_surface(&surface, 0.0, 0.0)?;
-            context.paint()?;
-            context.restore()?;
-        } else {
-            // Draw placeholder text
-            context.set_source_rgb(0.5, 0.5, 0.5);
-            context.select_font_face("Arial", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
-            context.set_font_size(24.0);
-            
-            let text = "Waiting for connection...";
-            let text_extents = context.text_extents(text)?;
-            let x = (width as f64 - text_extents.width()) / 2.0;
-            let y = (height as f64 + text_extents.height()) / 2.0;
-            
-            context.move_to(x, y);
-            context.show_text(text)?;
+    /// The remote display changed size: forget the frames waiting at the old
+    /// size and start the surface over. With `--resize-window` the window is
+    /// sized to the stream, unless it is sizing the display itself.
+    fn on_display_resized(&self) {
+        self.scheduler.borrow_mut().discard_pending();
+        // Nothing shown yet: this is the size the stream starts at
+        if self.renderer.get_dimensions().0 == 0 || self.test_pattern.get().is_some() {
+            return;
         }
+        self.renderer.clear();
         
-        Ok(())
+        let (size, orientation, resize_window) = {
+            let state = self.state.blocking_read();
+            let size = match state.crop {
+                Some(crop) => (crop.width, crop.height),
+                None => (state.display_width, state.display_height),
+            };
+            (size, state.orientation, state.resize_window)
+        };
+        info!("Remote display resized to {}x{}", size.0, size.1);
+        self.set_status(&format!("Remote display resized to {}x{}", size.0, size.1));
+        
+        // A borderless window refits itself on the next frame
+        let fixed = self.window.is_fullscreen() || self.window.is_maximized();
+        if !resize_window || fixed || self.borderless.get() || self.match_resolution.get() {
+            return;
+        }
+        let scale = self.drawing_area.scale_factor().max(1);
+        let (width, height) = orientation.size(size.0 as i32 / scale, size.1 as i32 / scale);
+        let chrome = (
+            self.window.width() - self.drawing_area.width(),
+            self.window.height() - self.drawing_area.height(),
+        );
+        self.window.set_default_size(width + chrome.0, height + chrome.1);
     }
     
-    fn on_close_request(&self) -> glib::Propagation {
-        info!("Close request received");
-        glib::Propagation::Proceed
+    /// Size a borderless window to the stream, once per stream size
+    fn fit_to_stream(&self) {
+        let (width, height) = self.renderer.get_dimensions();
+        let size = self.state.blocking_read().orientation.size(width, height);
+        if !self.borderless.get() || size.0 == 0 || size == self.fitted_size.get() {
+            return;
+        }
+        self.fitted_size.set(size);
+        self.window.set_default_size(size.0 as i32, size.1 as i32);
     }
     
-    fn on_key_pressed(&self, key: gdk4::Key) -> glib::Propagation {
-        match key {
-            gdk4::Key::F11 => {
-                // Toggle fullscreen
-                if self.window.is_fullscreen() {
-                    self.window.unfullscreen();
-                } else {
-                    self.window.fullscreen();
-                }
-                glib::Propagation::Stop
-            }
-            gdk4::Key::Escape => {
-                if self.window.is_fullscreen() {
-                    self.window.unfullscreen();
-                    glib::Propagation::Stop
-                } else {
-                    glib::Propagation::Proceed
-                }
+    /// Start moving a borderless window from a press on the stream
+    fn begin_move(&self, gesture: &gtk4::GestureClick, x: f64, y: f64) {
+        if !self.borderless.get() || self.window.is_fullscreen() {
+            return;
+        }
+        let Ok(toplevel) = self.window.surface().downcast::<gdk4::Toplevel>() else {
+            return;
+        };
+        let Some(device) = gesture.current_event_device() else {
+            return;
+        };
+        let (x, y) = self
+            .drawing_area
+            .translate_coordinates(&self.window, x, y)
+            .unwrap_or((x, y));
+        toplevel.begin_move(&device, gesture.current_button() as i32, x, y, gesture.current_event_time());
+    }
+    
+    /// Forward a touch on the stream to the server's touchscreen
+    fn on_touch(&self, event: &gdk4::Event) -> glib::Propagation {
+        let event_type = event.event_type();
+        if !matches!(
+            event_type,
+            gdk4::EventType::TouchBegin | gdk4::EventType::TouchUpdate | gdk4::EventType::TouchEnd | gdk4::EventType::TouchCancel
+        ) {
+            return glib::Propagation::Proceed;
+        }
+        let (device, orientation, crop) = {
+            let state = self.state.blocking_read();
+            (state.touch_device, state.orientation, state.crop)
+        };
+        let Some(device) = device else { return glib::Propagation::Proceed };
+        let Some((x, y)) = self.event_position(event) else {
+            return glib::Propagation::Proceed;
+        };
+        let mapping = TouchMapping {
+            widget: (self.drawing_area.width() as f64, self.drawing_area.height() as f64),
+            frame: self.renderer.get_dimensions(),
+            orientation,
+            display: (device.width, device.height),
+            crop,
+        };
+        
+        let sequence = event.event_sequence().as_ptr() as usize;
+        let mut touches = self.touches.borrow_mut();
+        let (slot, pressure) = match event_type {
+            gdk4::EventType::TouchBegin if mapping.covers(x, y) => (touches.press(sequence), self.touch_pressure(event)),
+            gdk4::EventType::TouchBegin => return glib::Propagation::Proceed,
+            gdk4::EventType::TouchUpdate => (touches.find(&sequence), self.touch_pressure(event)),
+            _ => (touches.release(&sequence), 0),
+        };
+        let contact = slot
+            .filter(|&slot| slot < device.slots)
+            .zip(mapping.display_point(x, y))
+            .map(|(slot, (x, y))| TouchContact { slot, x, y, pressure });
+        if let Some(contact) = contact {
+            if let Err(e) = self.commands.send(Command::Touch { contacts: vec![contact] }) {
+                warn!("Failed to send touch: {}", e);
             }
-            _ => glib::Propagation::Proceed,
         }
+        glib::Propagation::Stop
     }
     
-    pub async fn set_status(&self, message: &str) {
-        self.status_bar.push(self.context_id, message);
+    /// Send files dropped on the stream to `uploads`. GTK 4.6 is needed
+    /// for the whole list, so a drop of several sends the first.
+    pub fn accept_uploads(&self, uploads: UnboundedSender<PathBuf>) {
+        let target = gtk4::DropTarget::new(gio::File::static_type(), gdk4::DragAction::COPY);
+        let state = Arc::clone(&self.state);
+        target.connect_drop(move |_, value, _, _| {
+            let mode = state.blocking_read().effective_session_mode();
+            if mode < SessionMode::Full {
+                warn!("Uploads need the full session mode, not {}", mode);
+                return false;
+            }
+            match value.get::<gio::File>().ok().and_then(|file| file.path()) {
+                Some(path) => uploads.send(path).is_ok(),
+                None => false,
+            }
+        });
+        self.drawing_area.add_controller(target);
     }
     
-    pub async fn set_connected(&self, connected: bool) {
-        let status = if connected {
-            "Connected"
-        } else {
-            "Disconnected"
+    /// Show how an upload is going
+    pub fn on_upload_event(&self, event: UploadEvent) {
+        match event {
+            UploadEvent::Started { name, size, cancel } => {
+                let dialog = gtk4::Window::builder()
+                    .title(format!("Uploading {}", name))
+                    .transient_for(&self.window)
+                    .resizable(false)
+                    .build();
+                let vbox = gtk4::Box::new(gtk4::Orientation::Vertical, 12);
+                vbox.set_margin_top(18);
+                vbox.set_margin_bottom(18);
+                vbox.set_margin_start(18);
+                vbox.set_margin_end(18);
+                
+                let progress = gtk4::ProgressBar::new();
+                progress.set_show_text(true);
+                progress.set_text(Some(&upload_progress_text(0, size)));
+                progress.set_size_request(320, -1);
+                vbox.append(&progress);
+                
+                // Closing the dialog gives up as well
+                let cancel_button = gtk4::Button::with_label("Cancel");
+                cancel_button.set_halign(gtk4::Align::End);
+                let button_cancel = cancel.clone();
+                cancel_button.connect_clicked(move |_| button_cancel.cancel());
+                vbox.append(&cancel_button);
+                dialog.connect_close_request(move |_| {
+                    cancel.cancel();
+                    glib::Propagation::Proceed
+                });
+                
+                dialog.set_child(Some(&vbox));
+                dialog.present();
+                if let Some((old, _)) = self.upload_dialog.replace(Some((dialog, progress))) {
+                    old.destroy();
+                }
+            }
+            UploadEvent::Progress { received, size } => {
+                if let Some((_, progress)) = &*self.upload_dialog.borrow() {
+                    progress.set_fraction(received as f64 / size.max(1) as f64);
+                    progress.set_text(Some(&upload_progress_text(received, size)));
+                }
+            }
+            UploadEvent::Finished { name } => {
+                self.close_upload_dialog();
+                info!("Uploaded {}", name);
+                self.set_status(&format!("Uploaded {}", name));
+            }
+            UploadEvent::Failed { name, error } => {
+                self.close_upload_dialog();
+                warn!("Upload of {} failed: {}", name, error);
+                self.set_status(&format!("Upload of {} failed: {}", name, error));
+            }
+        }
+    }
+    
+    fn close_upload_dialog(&self) {
+        if let Some((dialog, _)) = self.upload_dialog.take() {
+            dialog.destroy();
+        }
+    }
+    
+    /// Where `event` happened on the drawing area; event positions are on
+    /// the window's surface
+    fn event_position(&self, event: &gdk4::Event) -> Option<(f64, f64)> {
+        let (x, y) = event.position()?;
+        let native = self.drawing_area.native()?;
+        let (left, top) = native.surface_transform();
+        native.translate_coordinates(&self.drawing_area, x - left, y - top)
+    }
+    
+    /// Capture the pointer for the server's mouse, or give it back
+    fn set_pointer_captured(&self, captured: bool) {
+        if !captured {
+            let Some(lock) = self.pointer.take() else { return };
+            // Let go of anything still held, so it doesn't stay down remotely
+            if lock.buttons() != 0 {
+                let _ = self.commands.send(Command::Pointer { dx: 0, dy: 0, wheel: 0, buttons: 0 });
+            }
+            for &code in lock.keys() {
+                let _ = self.commands.send(Command::Key { code, pressed: false });
+            }
+            self.drawing_area.set_cursor(None::<&gdk4::Cursor>);
+            self.set_status("Pointer released");
+            return;
+        }
+        
+        let backend = WidgetExt::display(&self.window).type_().name();
+        self.pointer_warps.set(backend.contains("X11"));
+        if !self.pointer_warps.get() {
+            warn!("The captured pointer can't be kept in the window on {}; it stops at the screen's edge", backend);
+        }
+        self.pointer.replace(Some(PointerLock::new()));
+        self.drawing_area.set_cursor_from_name(Some("none"));
+        self.set_status("Pointer and keyboard captured; F8 releases them");
+    }
+    
+    /// Send motion, wheel and buttons of the captured pointer to the
+    /// server's mouse
+    fn on_pointer(&self, event: &gdk4::Event) -> glib::Propagation {
+        let mut pointer = self.pointer.borrow_mut();
+        let Some(lock) = pointer.as_mut() else { return glib::Propagation::Proceed };
+        let buttons = lock.buttons();
+        let (mut dx, mut dy, mut wheel) = (0, 0, 0);
+        match event.event_type() {
+            gdk4::EventType::MotionNotify => {
+                let Some((x, y)) = self.event_position(event) else { return glib::Propagation::Stop };
+                // Deltas in device pixels, as the server's mouse would move
+                let scale = self.drawing_area.scale_factor() as f64;
+                (dx, dy) = lock.motion(x * scale, y * scale, Instant::now()).unwrap_or_default();
+                let (width, height) = (self.drawing_area.width() as f64 * scale, self.drawing_area.height() as f64 * scale);
+                if let Some(target) = lock.warp_target(x * scale, y * scale, width, height) {
+                    if self.warp_pointer(target.0 / scale, target.1 / scale) {
+                        lock.warping(target, Instant::now());
+                    }
+                }
+            }
+            event_type @ (gdk4::EventType::ButtonPress | gdk4::EventType::ButtonRelease) => {
+                if let Some(button) = event.downcast_ref::<gdk4::ButtonEvent>() {
+                    lock.button(button.button(), event_type == gdk4::EventType::ButtonPress);
+                }
+            }
+            gdk4::EventType::Scroll => {
+                if let Some(scroll) = event.downcast_ref::<gdk4::ScrollEvent>() {
+                    wheel = match scroll.direction() {
+                        gdk4::ScrollDirection::Up => 1,
+                        gdk4::ScrollDirection::Down => -1,
+                        gdk4::ScrollDirection::Smooth => lock.scroll(scroll.deltas().1),
+                        _ => 0,
+                    };
+                }
+            }
+            _ => return glib::Propagation::Proceed,
+        }
+        
+        if (dx, dy, wheel) != (0, 0, 0) || lock.buttons() != buttons {
+            let command = Command::Pointer { dx, dy, wheel, buttons: lock.buttons() };
+            if let Err(e) = self.commands.send(command) {
+                warn!("Failed to send pointer motion: {}", e);
+            }
+        }
+        glib::Propagation::Stop
+    }
+    
+    /// Move the pointer to `x`, `y` of the drawing area. GTK 4 can't, so on
+    /// X11 this goes through `xdotool`, the window being the active one.
+    fn warp_pointer(&self, x: f64, y: f64) -> bool {
+        if !self.pointer_warps.get() {
+            return false;
+        }
+        let Some(native) = self.drawing_area.native() else { return false };
+        let Some((x, y)) = self.drawing_area.translate_coordinates(&native, x, y) else { return false };
+        let (left, top) = native.surface_transform();
+        let scale = self.drawing_area.scale_factor() as f64;
+        let (x, y) = (((x + left) * scale).round().to_string(), ((y + top) * scale).round().to_string());
+        let argv = ["xdotool", "getactivewindow", "mousemove", "--window", "%1", &x, &y];
+        let argv: Vec<&std::ffi::OsStr> = argv.iter().map(std::ffi::OsStr::new).collect();
+        match gio::Subprocess::newv(&argv, gio::SubprocessFlags::NONE) {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Keeping the captured pointer in the window needs xdotool: {}", e);
+                self.pointer_warps.set(false);
+                false
+            }
+        }
+    }
+    
+    /// How firmly `event` presses, full for panels that don't say
+    fn touch_pressure(&self, event: &gdk4::Event) -> u32 {
+        match event.axis(gdk4::AxisUse::Pressure) {
+            Some(pressure) => ((pressure * MAX_TOUCH_PRESSURE as f64).round() as u32).clamp(1, MAX_TOUCH_PRESSURE),
+            None => MAX_TOUCH_PRESSURE,
+        }
+    }
+    
+    /// Ask the window manager to keep the window above others. GTK 4 has no
+    /// call for this, so on X11 it goes through `wmctrl`; Wayland
+    /// compositors only let the user set it.
+    fn set_on_top(&self, on_top: bool) {
+        self.state.blocking_write().on_top = on_top;
+        let backend = WidgetExt::display(&self.window).type_().name();
+        if !backend.contains("X11") {
+            warn!("Always on top has to be set from the compositor's window menu on {}", backend);
+            return;
+        }
+        let title = self.window.title().map(|title| title.to_string()).unwrap_or_default();
+        let change = if on_top { "add,above" } else { "remove,above" };
+        let result = std::process::Command::new("wmctrl").args(["-r", &title, "-b", change]).status();
+        match result {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("wmctrl could not change the window: {}", status),
+            Err(e) => warn!("Always on top needs wmctrl: {}", e),
+        }
+    }
+    
+    /// Present frames on the display refresh from now on, via the drawing
+    /// area's tick callback, instead of as soon as they arrive. A variable
+    /// refresh display has no refresh to wait for, so with `vrr` each frame
+    /// goes up the moment it is due instead.
+    pub fn start_pacing(self: &Rc<Self>, vrr: bool) {
+        if self.paced.replace(true) {
+            return;
+        }
+        if vrr {
+            self.vrr.set(true);
+            return;
+        }
+        
+        let window_weak = Rc::downgrade(self);
+        self.drawing_area.add_tick_callback(move |_, _| {
+            if let Some(window) = window_weak.upgrade() {
+                window.on_tick();
+                glib::ControlFlow::Continue
+            } else {
+                glib::ControlFlow::Break
+            }
+        });
+    }
+    
+    /// Hand over a received frame, either to draw now or when it is due
+    pub fn submit_frame(self: &Rc<Self>, frame: FrameData) -> Result<()> {
+        // Frames that were on their way when the remote display was resized
+        // are at the old size
+        let resized_at = self.state.blocking_read().display_resized_at;
+        if resized_at.is_some_and(|at| frame.received < at) {
+            debug!("Dropping a frame from before the display was resized");
+            return Ok(());
+        }
+        if resized_at != self.display_resized_at.replace(resized_at) {
+            self.on_display_resized();
+        }
+        
+        if self.paced.get() {
+            // The clock estimate keeps improving, so the deadlines follow it
+            let sync = {
+                let state = self.state.blocking_read();
+                state.clock().zip(state.sync_delay).map(|(clock, delay)| SyncDeadline { clock, delay })
+            };
+            let mut scheduler = self.scheduler.borrow_mut();
+            scheduler.set_sync(sync);
+            scheduler.push(frame);
+            drop(scheduler);
+            if self.vrr.get() {
+                self.present_when_due();
+            }
+            Ok(())
+        } else {
+            self.update_frame(&frame)
+        }
+    }
+    
+    /// Arm the timer for the first frame waiting, in place of one armed
+    /// for a later frame
+    fn present_when_due(self: &Rc<Self>) {
+        if let Some(timer) = self.due_timer.take() {
+            timer.remove();
+        }
+        let Some(due) = self.scheduler.borrow().next_due() else { return };
+        
+        let window_weak = Rc::downgrade(self);
+        let timer = glib::timeout_add_local_once(due.saturating_duration_since(Instant::now()), move || {
+            if let Some(window) = window_weak.upgrade() {
+                // Fired, so there is nothing to remove
+                window.due_timer.take();
+                window.on_tick();
+                window.present_when_due();
+            }
+        });
+        self.due_timer.replace(Some(timer));
+    }
+    
+    fn on_tick(&self) {
+        let frame = self.scheduler.borrow_mut().next_for_tick(Instant::now());
+        if let Some(frame) = frame {
+            if let Err(e) = self.update_frame(&frame) {
+                warn!("Failed to update frame: {}", e);
+            }
+        }
+    }
+    
+    fn update_frame(&self, frame: &FrameData) -> Result<()> {
+        let header = &frame.header;
+        let data = frame.data.as_slice();
+        debug!("Updating frame: {}x{} {} bytes", header.width, header.height, data.len());
+        
+        if self.test_pattern.get().is_some() {
+            return Ok(());
+        }
+        
+        if !render_frame(self.backend.as_ref(), frame)? {
+            return Ok(());
+        }
+        
+        self.last_frame_at.set(Some(Instant::now()));
+        self.fit_to_stream();
+        if header.timestamp != 0 {
+            self.undrawn.set(Some((header.timestamp, frame.received)));
+        }
+        
+        // Trigger redraw
+        self.drawing_area.queue_draw();
+        
+        Ok(())
+    }
+    
+    fn on_draw(&self, context: &cairo::Context, width: i32, height: i32) -> Result<()> {
+        if let Some((timestamp, received)) = self.undrawn.take() {
+            self.record_latency(timestamp);
+            self.acknowledge(timestamp, received);
+        }
+        self.publish_stats();
+        
+        // The frame is drawn by the picture underneath
+        if self.renderer.get_surface().is_none() {
+            // Draw placeholder text
+            context.set_source_rgb(0.5, 0.5, 0.5);
+            context.select_font_face("Arial", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
+            context.set_font_size(24.0);
+            
+            let text = "Waiting for connection...";
+            let text_extents = context.text_extents(text)?;
+            let x = (width as f64 - text_extents.width()) / 2.0;
+            let y = (height as f64 + text_extents.height()) / 2.0;
+            
+            context.move_to(x, y);
+            context.show_text(text)?;
+        }
+        
+        let veil = self.stall_veil();
+        self.veiled.set(veil.is_some());
+        if let Some(veil) = veil {
+            self.draw_stall_veil(context, width, height, veil)?;
+        }
+        
+        if self.show_stats.get() {
+            self.draw_stats_hud(context)?;
+        }
+        
+        self.draw_identify_overlay(context, width, height)?;
+        
+        self.osd.borrow_mut().draw(context, width, height, Instant::now())?;
+        
+        Ok(())
+    }
+    
+    /// Time from the server stamping the frame about to be drawn until now,
+    /// on the server's clock as estimated from Ping/Pong
+    fn record_latency(&self, timestamp: u64) {
+        let clock = match self.state.blocking_read().clock() {
+            Some(clock) => clock,
+            None => return,
+        };
+        
+        let latency_ms = (timesync::now_ns() as i64 - clock.to_local_ns(timestamp)) as f64 / 1e6;
+        let smoothed = match self.latency_ms.get() {
+            Some(previous) => previous * 0.9 + latency_ms * 0.1,
+            None => latency_ms,
+        };
+        self.latency_ms.set(Some(smoothed));
+    }
+    
+    /// With `--ack` agreed, tell the server the frame about to be drawn and every
+    /// one before it are through, so it sends no more until they are
+    fn acknowledge(&self, timestamp: u64, received: Instant) {
+        let (ack, clock) = {
+            let state = self.state.blocking_read();
+            (state.capabilities.supports(Capabilities::ACK), state.clock())
+        };
+        if !ack {
+            return;
+        }
+        
+        // Times are only worth sending on the server's clock
+        let (received_ns, presented_ns) = match clock {
+            Some(clock) => {
+                let now = timesync::now_ns();
+                let received_ns = now.saturating_sub(received.elapsed().as_nanos() as u64);
+                (clock.to_server_ns(received_ns), clock.to_server_ns(now))
+            }
+            None => (0, 0),
+        };
+        if let Err(e) = self.commands.send(Command::Ack { timestamp, received_ns, presented_ns }) {
+            debug!("Failed to acknowledge frame {}: {}", timestamp, e);
+        }
+    }
+    
+    /// Add the network side of the stream statistics to the renderer's
+    /// frame stats, for the HUD and any other subscriber
+    fn publish_stats(&self) {
+        let now = Instant::now();
+        self.renderer.refresh_stats();
+        {
+            let state = self.state.blocking_read();
+            let paced_drops = self.paced.get().then(|| self.scheduler.borrow().dropped());
+            self.stats().update(|snapshot| {
+                snapshot.refresh_hz = state.refresh_mhz as f64 / 1000.0;
+                snapshot.vrr = state.vrr;
+                snapshot.latency_ms = state.clock().and(self.latency_ms.get());
+                snapshot.rtt_ms = state.clock().map(|clock| clock.rtt_ns as f64 / 1e6);
+                snapshot.links = state
+                    .links
+                    .iter()
+                    .map(|link| LinkRate { label: link.label.clone(), bytes_per_sec: link.rx.bytes_per_sec(now) })
+                    .collect();
+                snapshot.corrupt_frames = state.corrupt_frames;
+                snapshot.resyncs = state.resyncs;
+                snapshot.paced_drops = paced_drops;
+                snapshot.content_verified = state.content_verified;
+                snapshot.content_mismatches = state.content_mismatches;
+                snapshot.input_control = state
+                    .input_control
+                    .filter(|control| !control.shared)
+                    .map(|control| network::control_holder(&control));
+            });
+        }
+        self.history.borrow_mut().record(now, &self.stats().snapshot());
+    }
+    
+    fn draw_stats_hud(&self, context: &cairo::Context) -> Result<()> {
+        let stats = self.stats().snapshot();
+        let (fps, refresh_hz) = (stats.fps, stats.refresh_hz);
+        
+        let mut lines = vec![
+            format!(
+                "{}x{}  {:.1} KiB/frame",
+                stats.frame_width,
+                stats.frame_height,
+                stats.frame_bytes as f64 / 1024.0
+            ),
+            if stats.vrr && refresh_hz > 0.0 {
+                format!("{:.1} fps (VRR up to {:.2} Hz)", fps, refresh_hz)
+            } else if refresh_hz > 0.0 {
+                format!("{:.1} fps / {:.2} Hz target", fps, refresh_hz)
+            } else {
+                format!("{:.1} fps", fps)
+            },
+        ];
+        // Highlighted, wherever the lines around it put it
+        let warning = stats.cadence_mismatch().then(|| {
+            lines.push("Frame rate does not match display refresh".to_string());
+            lines.len() - 1
+        });
+        if let (Some(latency), Some(rtt)) = (stats.latency_ms, stats.rtt_ms) {
+            lines.push(format!("latency {:.1} ms (rtt {:.1} ms)", latency, rtt));
+        }
+        lines.extend(stats.links.iter().map(|link| format!("{}: {}", link.label, format_bitrate(link.bytes_per_sec))));
+        if stats.corrupt_frames > 0 {
+            lines.push(format!("CRC errors: {} frames resent", stats.corrupt_frames));
+        }
+        if stats.resyncs > 0 {
+            lines.push(format!("resynchronised {} times", stats.resyncs));
+        }
+        if let Some(dropped) = stats.paced_drops {
+            lines.push(format!("pacing: {} frames dropped", dropped));
+        }
+        if stats.content_verified + stats.content_mismatches > 0 {
+            lines.push(format!(
+                "content: {} verified, {} mismatched", stats.content_verified, stats.content_mismatches
+            ));
+        }
+        
+        context.save()?;
+        context.select_font_face("Monospace", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
+        context.set_font_size(14.0);
+        
+        let line_height = 18.0;
+        let box_width = lines
+            .iter()
+            .filter_map(|line| context.text_extents(line).ok())
+            .map(|extents| extents.x_advance())
+            .fold(GRAPH_SIZE.0, f64::max) + 16.0;
+        let text_height = line_height * lines.len() as f64 + 8.0;
+        
+        context.set_source_rgba(0.0, 0.0, 0.0, 0.7);
+        context.rectangle(8.0, 8.0, box_width, text_height + GRAPH_SIZE.1 + 8.0);
+        context.fill()?;
+        graph::draw(context, &self.history.borrow(), 16.0, 8.0 + text_height, box_width - 16.0, GRAPH_SIZE.1, true)?;
+        
+        for (i, line) in lines.iter().enumerate() {
+            if warning == Some(i) {
+                context.set_source_rgb(1.0, 0.8, 0.0);
+            } else {
+                context.set_source_rgb(1.0, 1.0, 1.0);
+            }
+            context.move_to(16.0, 8.0 + line_height * (i as f64 + 1.0));
+            context.show_text(line)?;
+        }
+        
+        context.restore()?;
+        Ok(())
+    }
+    
+    /// Flash the display identifier here and ask the server to do the same
+    pub fn identify(&self) {
+        let name = {
+            let state_guard = self.state.blocking_read();
+            network::server_address(&state_guard.server, state_guard.port)
+        };
+        info!("Identifying display {}", name);
+        
+        let command = Command::Identify { duration_ms: IDENTIFY_DURATION_MS };
+        if self.commands.send(command).is_err() {
+            warn!("Network task is gone, identifying locally only");
+        }
+        
+        let duration = Duration::from_millis(IDENTIFY_DURATION_MS as u64);
+        *self.identify.borrow_mut() = Some((name, Instant::now() + duration));
+        self.drawing_area.queue_draw();
+        
+        let drawing_area = self.drawing_area.clone();
+        glib::timeout_add_local_once(duration, move || drawing_area.queue_draw());
+    }
+    
+    /// Ask for the code the server is showing; closing the prompt cancels
+    /// the pairing
+    pub fn prompt_pairing_code(&self, prompt: PairPrompt) {
+        let dialog = gtk4::Window::builder()
+            .title("Pair with display")
+            .transient_for(&self.window)
+            .modal(true)
+            .resizable(false)
+            .build();
+        
+        let content = gtk4::Box::new(gtk4::Orientation::Vertical, 12);
+        content.set_margin_top(18);
+        content.set_margin_bottom(18);
+        content.set_margin_start(18);
+        content.set_margin_end(18);
+        
+        let label = gtk4::Label::new(Some(&format!("Enter the 6-digit code shown on {}", prompt.server)));
+        let entry = gtk4::Entry::builder()
+            .input_purpose(gtk4::InputPurpose::Digits)
+            .max_length(7)
+            .activates_default(true)
+            .build();
+        let button = gtk4::Button::with_label("Pair");
+        button.add_css_class("suggested-action");
+        
+        content.append(&label);
+        content.append(&entry);
+        content.append(&button);
+        dialog.set_child(Some(&content));
+        dialog.set_default_widget(Some(&button));
+        
+        let reply = Rc::new(RefCell::new(Some(prompt.reply)));
+        let dialog_weak = dialog.downgrade();
+        button.connect_clicked(move |_| {
+            if let Some(reply) = reply.borrow_mut().take() {
+                let _ = reply.send(entry.text().to_string());
+            }
+            if let Some(dialog) = dialog_weak.upgrade() {
+                dialog.close();
+            }
+        });
+        
+        dialog.present();
+    }
+    
+    /// Show what a server's sign-in says: questions, and notices such as
+    /// where to sign in, in a dialog, and how it went in the status bar
+    pub fn prompt_sign_in(&self, prompt: SignInPrompt) {
+        // Each prompt takes the last one's place, which was answered or
+        // given up on; destroying it doesn't decline again
+        if let Some(dialog) = self.sign_in_dialog.upgrade() {
+            dialog.destroy();
+        }
+        if prompt.reply.is_none() {
+            self.set_status(&prompt.text);
+            if prompt.style != PromptStyle::Info {
+                return;
+            }
+        }
+        
+        let dialog = gtk4::Window::builder()
+            .title(format!("Sign in to {}", prompt.server))
+            .transient_for(&self.window)
+            .modal(true)
+            .resizable(false)
+            .build();
+        
+        let content = gtk4::Box::new(gtk4::Orientation::Vertical, 12);
+        content.set_margin_top(18);
+        content.set_margin_bottom(18);
+        content.set_margin_start(18);
+        content.set_margin_end(18);
+        
+        // Selectable, so a sign-in link can be copied
+        let label = gtk4::Label::builder().label(&prompt.text).selectable(true).wrap(true).build();
+        content.append(&label);
+        
+        let Some(reply) = prompt.reply else {
+            let button = gtk4::Button::with_label("Close");
+            let dialog_weak = dialog.downgrade();
+            button.connect_clicked(move |_| {
+                if let Some(dialog) = dialog_weak.upgrade() {
+                    dialog.close();
+                }
+            });
+            content.append(&button);
+            dialog.set_child(Some(&content));
+            self.sign_in_dialog.set(Some(&dialog));
+            dialog.present();
+            return;
+        };
+        
+        let secret = prompt.style == PromptStyle::Secret;
+        let entry = gtk4::Entry::builder()
+            .visibility(!secret)
+            .input_purpose(if secret { gtk4::InputPurpose::Password } else { gtk4::InputPurpose::FreeForm })
+            .activates_default(true)
+            .build();
+        let buttons = gtk4::Box::new(gtk4::Orientation::Horizontal, 6);
+        buttons.set_halign(gtk4::Align::End);
+        let cancel = gtk4::Button::with_label("Cancel");
+        let sign_in = gtk4::Button::with_label("Sign In");
+        sign_in.add_css_class("suggested-action");
+        buttons.append(&cancel);
+        buttons.append(&sign_in);
+        
+        content.append(&entry);
+        content.append(&buttons);
+        dialog.set_child(Some(&content));
+        dialog.set_default_widget(Some(&sign_in));
+        
+        // Closing the dialog any other way declines to answer
+        let reply = Rc::new(RefCell::new(Some(reply)));
+        dialog.connect_close_request({
+            let reply = Rc::clone(&reply);
+            move |_| {
+                if let Some(reply) = reply.borrow_mut().take() {
+                    let _ = reply.send(String::new());
+                }
+                glib::Propagation::Proceed
+            }
+        });
+        let dialog_weak = dialog.downgrade();
+        sign_in.connect_clicked(move |_| {
+            if let Some(reply) = reply.borrow_mut().take() {
+                let _ = reply.send(entry.text().to_string());
+            }
+            if let Some(dialog) = dialog_weak.upgrade() {
+                dialog.close();
+            }
+        });
+        let dialog_weak = dialog.downgrade();
+        cancel.connect_clicked(move |_| {
+            if let Some(dialog) = dialog_weak.upgrade() {
+                dialog.close();
+            }
+        });
+        
+        self.sign_in_dialog.set(Some(&dialog));
+        dialog.present();
+    }
+    
+    /// How to cover the stream if it has stalled, going by the last frame
+    /// shown or packet from the server, whichever is newer
+    fn stall_veil(&self) -> Option<Veil> {
+        let state = self.state.blocking_read();
+        let last = self.last_frame_at.get().max(state.last_signal)?;
+        state.on_stall.veil(last.elapsed(), state.stall_timeout)
+    }
+    
+    fn draw_stall_veil(&self, context: &cairo::Context, width: i32, height: i32, veil: Veil) -> Result<()> {
+        context.save()?;
+        context.set_source_rgba(0.0, 0.0, 0.0, veil.opacity);
+        context.paint()?;
+        
+        if veil.banner {
+            context.select_font_face("Sans", cairo::FontSlant::Normal, cairo::FontWeight::Bold);
+            context.set_source_rgb(1.0, 1.0, 1.0);
+            context.set_font_size(height as f64 / 12.0);
+            let text = "Signal lost";
+            let extents = context.text_extents(text)?;
+            context.move_to(
+                (width as f64 - extents.width()) / 2.0 - extents.x_bearing(),
+                (height as f64 - extents.height()) / 2.0 - extents.y_bearing(),
+            );
+            context.show_text(text)?;
+        }
+        
+        context.restore()?;
+        Ok(())
+    }
+    
+    fn draw_identify_overlay(&self, context: &cairo::Context, width: i32, height: i32) -> Result<()> {
+        let identify = self.identify.borrow();
+        let name = match identify.as_ref() {
+            Some((name, until)) if Instant::now() < *until => name,
+            _ => return Ok(()),
+        };
+        
+        context.save()?;
+        context.set_source_rgba(0.0, 0.0, 0.0, 0.6);
+        context.paint()?;
+        
+        context.select_font_face("Sans", cairo::FontSlant::Normal, cairo::FontWeight::Bold);
+        context.set_source_rgb(1.0, 1.0, 1.0);
+        
+        // The card the server flashes, once it has said; older servers
+        // don't, and only the name is shown
+        if let Some(index) = self.state.blocking_read().display_index {
+            context.set_font_size(height as f64 / 3.0);
+            let number = index.to_string();
+            let extents = context.text_extents(&number)?;
+            context.move_to(
+                (width as f64 - extents.width()) / 2.0 - extents.x_bearing(),
+                height as f64 / 2.0,
+            );
+            context.show_text(&number)?;
+        }
+        
+        context.set_font_size(height as f64 / 20.0);
+        let extents = context.text_extents(name)?;
+        context.move_to(
+            (width as f64 - extents.width()) / 2.0 - extents.x_bearing(),
+            height as f64 / 2.0 + extents.height() * 2.0,
+        );
+        context.show_text(name)?;
+        
+        context.restore()?;
+        Ok(())
+    }
+    
+    fn on_close_request(&self) -> glib::Propagation {
+        if self.state.blocking_read().block_input {
+            info!("Ignoring close request in kiosk mode");
+            return glib::Propagation::Stop;
+        }
+        info!("Close request received");
+        self.shutdown.cancel();
+        glib::Propagation::Proceed
+    }
+    
+    /// Refresh rate of the monitor the window is on, in mHz (0 if unknown)
+    pub fn monitor_refresh_mhz(&self) -> u32 {
+        let surface = self.window.surface();
+        WidgetExt::display(&self.window)
+            .monitor_at_surface(&surface)
+            .map(|monitor| monitor.refresh_rate().max(0) as u32)
+            .unwrap_or(0)
+    }
+    
+    /// List the recent servers in the File menu, most recent first
+    fn refresh_recent_menu(&self) {
+        self.recent_menu.remove_all();
+        for addr in self.state.blocking_read().recent.servers() {
+            let item = gio::MenuItem::new(Some(addr), None);
+            item.set_action_and_target_value(Some("win.connect-recent"), Some(&addr.to_variant()));
+            self.recent_menu.append_item(&item);
+        }
+    }
+    
+    /// Drop the current server for `addr` (`server:port`); the network
+    /// links notice and reconnect
+    fn switch_server(&self, addr: &str) {
+        let Some((server, port)) = network::parse_server_address(addr) else {
+            warn!("Not a server address: {}", addr);
+            return;
         };
-        self.set_status(status).await;
+        {
+            let mut state = self.state.blocking_write();
+            if (state.server.as_str(), state.port) == (server.as_str(), port) {
+                return;
+            }
+            info!("Switching to {}", addr);
+            state.server = server;
+            state.port = port;
+            state.usage_account = addr.to_string();
+            state.server_generation += 1;
+            if let Err(e) = state.recent.touch(addr) {
+                warn!("Failed to save recent servers: {:#}", e);
+            }
+        }
+        self.window.set_title(Some(&window_title(addr)));
+        self.refresh_recent_menu();
     }
+    
+    /// Send a Server menu request: `resend-frame`, `restart-capture`,
+    /// `rotate-logs` or `source:N`; the reply shows up in the status bar
+    fn supervise(&self, request: &str) {
+        let (action, arg) = match request.split_once(':') {
+            Some(("source", source)) => match source.parse() {
+                Ok(source) => (SuperviseAction::SetSource, source),
+                Err(_) => return,
+            },
+            _ if request == "resend-frame" => (SuperviseAction::ResendFrame, 0),
+            _ if request == "restart-capture" => (SuperviseAction::RestartCapture, 0),
+            _ if request == "rotate-logs" => (SuperviseAction::RotateLogs, 0),
+            _ => return,
+        };
+        info!("Asking the server to {}", request);
+        if self.commands.send(Command::Supervise { action, arg }).is_err() {
+            warn!("Network task is gone, can't reach the server");
+        }
+    }
+    
+    /// Switch between automatic and fixed quality. Fixed modes are sent
+    /// right away; auto takes over on its next interval.
+    fn set_quality_mode(&self, mode: QualityMode) {
+        let mut state = self.state.blocking_write();
+        state.quality_mode = mode;
+        if let Some(limits) = mode.limits() {
+            info!("Requesting {} quality", mode.name());
+            state.quality = limits;
+            if let Err(e) = self.commands.send(limits.to_command()) {
+                warn!("Failed to request quality: {}", e);
+            }
+        }
+    }
+    
+    /// Ask the server for another session mode, giving the pointer back
+    /// first if it takes no input
+    fn set_session_mode(&self, mode: SessionMode) {
+        if mode < SessionMode::Input {
+            self.release_pointer();
+        }
+        info!("Requesting the {} session mode", mode);
+        if let Err(e) = self.commands.send(Command::SessionMode { mode }) {
+            warn!("Failed to request the session mode: {}", e);
+        }
+    }
+    
+    /// Ask for, give up or take input control; giving it up gives the
+    /// pointer back too
+    fn input_control(&self, request: &str) {
+        let action = match request {
+            "request" => InputControlAction::Request,
+            "release" => InputControlAction::Release,
+            "steal" => InputControlAction::Steal,
+            _ => return,
+        };
+        if action == InputControlAction::Release {
+            self.release_pointer();
+        }
+        info!("Asking the server to {} input control", request);
+        if self.commands.send(Command::InputControl { action }).is_err() {
+            warn!("Network task is gone, can't reach the server");
+        }
+    }
+    
+    /// Show the session mode in effect, which the server may have
+    /// lowered, and only offer what it and input control allow
+    fn check_session_mode(&self) {
+        let (mode, block_input, control) = {
+            let state = self.state.blocking_read();
+            (state.effective_session_mode(), state.block_input, state.input_control)
+        };
+        if let Some(action) = self.window.lookup_action("session-mode") {
+            if action.state().and_then(|v| v.str().map(str::to_string)).as_deref() != Some(mode.name()) {
+                action.change_state(&mode.name().to_variant());
+            }
+        }
+        // Another client took control
+        let driving = control.is_none_or(|control| control.can_drive());
+        if mode < SessionMode::Input || !driving {
+            self.release_pointer();
+        }
+        let following = control.is_some_and(|control| !control.shared);
+        for (name, enabled) in [
+            ("pointer-lock", !block_input && mode >= SessionMode::Input),
+            ("supervise", mode >= SessionMode::Full),
+            ("input-control", following && mode >= SessionMode::Input),
+        ] {
+            if let Some(action) = self.window.lookup_action(name).and_downcast::<gio::SimpleAction>() {
+                action.set_enabled(enabled);
+            }
+        }
+    }
+    
+    /// Give the captured pointer back, as its menu item would
+    fn release_pointer(&self) {
+        if self.pointer.borrow().is_some() {
+            let _ = WidgetExt::activate_action(&self.window, "win.pointer-lock", None);
+        }
+    }
+    
+    /// Whether the seat the window is on has a touchscreen
+    pub fn has_touchscreen(&self) -> bool {
+        WidgetExt::display(&self.window)
+            .default_seat()
+            .is_some_and(|seat| seat.capabilities().contains(gtk4::gdk::SeatCapabilities::TOUCH))
+    }
+    
+    /// Whether GSK is rendering through GL (the only path where the
+    /// compositor can present our commits on a variable refresh display)
+    pub fn uses_gl_renderer(&self) -> bool {
+        let renderer = self.window.renderer();
+        debug!("GSK renderer: {}", renderer.type_().name());
+        renderer.is::<gtk4::gsk::GLRenderer>()
+    }
+    
+    /// Pause the stream once the window has been out of sight for
+    /// `PAUSE_DELAY`, and resume it as soon as it shows again
+    fn on_visibility_changed(self: &Rc<Self>, hidden: bool) {
+        if hidden == self.hidden.replace(hidden) {
+            return;
+        }
+        if !hidden {
+            self.set_paused(false);
+            return;
+        }
+        
+        let window_weak = Rc::downgrade(self);
+        glib::timeout_add_local_once(PAUSE_DELAY, move || {
+            if let Some(window) = window_weak.upgrade().filter(|window| window.hidden.get()) {
+                window.set_paused(true);
+            }
+        });
+    }
+    
+    /// Ask the server to match the window once it has settled on a size
+    fn on_resized(self: &Rc<Self>) {
+        if !self.match_resolution.get() {
+            return;
+        }
+        let serial = self.resize_serial.get().wrapping_add(1);
+        self.resize_serial.set(serial);
+        
+        let window_weak = Rc::downgrade(self);
+        glib::timeout_add_local_once(RESIZE_SETTLE, move || {
+            if let Some(window) = window_weak.upgrade().filter(|window| window.resize_serial.get() == serial) {
+                window.request_window_mode();
+            }
+        });
+    }
+    
+    /// With View → Match Window Resolution on, ask the server for a mode
+    /// the size of the stream area, so the remote desktop fills the window
+    /// pixel for pixel
+    fn request_window_mode(&self) {
+        if !self.match_resolution.get() {
+            return;
+        }
+        
+        let scale = self.drawing_area.scale_factor().max(1);
+        let width = (self.drawing_area.width() * scale).max(0) as u32;
+        let height = (self.drawing_area.height() * scale).max(0) as u32;
+        // A turned stream needs the other orientation, and mode timings
+        // come in 8 pixel columns
+        let (width, height) = self.state.blocking_read().orientation.size(width, height);
+        let mode = (width / 8 * 8, height, 0);
+        if mode.0 < MIN_MODE.0 || mode.1 < MIN_MODE.1 {
+            self.set_status(&format!(
+                "The window is too small to match; the server's modes start at {}x{}",
+                MIN_MODE.0, MIN_MODE.1
+            ));
+            return;
+        }
+        if self.requested_mode.get() == Some(mode) {
+            return;
+        }
+        
+        let (width, height, refresh_mhz) = mode;
+        info!("Asking the server for {}x{} to match the window", width, height);
+        if self.commands.send(Command::RequestMode { width, height, refresh_mhz }).is_ok() {
+            self.requested_mode.set(Some(mode));
+            self.set_status(&format!("Asked the server for {}x{}", width, height));
+        }
+    }
+    
+    fn set_paused(&self, paused: bool) {
+        {
+            let mut state = self.state.blocking_write();
+            if state.paused == paused {
+                return;
+            }
+            state.paused = paused;
+        }
+        info!("{} the stream", if paused { "Window hidden, pausing" } else { "Window shown, resuming" });
+        if let Err(e) = self.commands.send(Command::Pause { paused }) {
+            warn!("Failed to send pause request: {}", e);
+        }
+    }
+    
+    /// Show the menu and status bars unless borderless, fullscreen or a
+    /// kiosk
+    fn update_bars(&self) {
+        let fullscreen = self.window.is_fullscreen();
+        let bars = !self.borderless.get() && !fullscreen && !self.state.blocking_read().kiosk;
+        self.menu_bar.set_visible(bars);
+        self.status_bar.set_visible(bars);
+        self.sparkline.set_visible(bars);
+        if !fullscreen {
+            self.toolbar.set_reveal_child(false);
+        }
+    }
+    
+    /// Bring the toolbar down when the pointer reaches the top of a
+    /// fullscreen window, and put it away once the pointer leaves it
+    fn on_top_edge_motion(&self, y: f64) {
+        if !self.window.is_fullscreen() || self.state.blocking_read().kiosk || self.pointer.borrow().is_some() {
+            return;
+        }
+        if y <= TOOLBAR_EDGE && !self.toolbar.reveals_child() {
+            let disconnected = self.state.blocking_read().user_disconnected;
+            self.connect_button.set_label(if disconnected { "Connect" } else { "Disconnect" });
+            self.toolbar.set_reveal_child(true);
+        } else if y > self.toolbar.height() as f64 + TOOLBAR_SLACK {
+            self.toolbar.set_reveal_child(false);
+        }
+    }
+    
+    /// Drop the connection, or pick it up again, as the control port's
+    /// `disconnect` and `connect` do
+    fn toggle_connection(&self) {
+        let disconnected = {
+            let mut state = self.state.blocking_write();
+            state.user_disconnected = !state.user_disconnected;
+            state.user_disconnected
+        };
+        info!("{} from the toolbar", if disconnected { "Disconnecting" } else { "Connecting" });
+        self.connect_button.set_label(if disconnected { "Connect" } else { "Disconnect" });
+    }
+    
+    fn on_fullscreen_changed(&self) {
+        self.update_bars();
+        if !self.window.is_fullscreen() && self.state.blocking_read().kiosk {
+            self.window.fullscreen();
+            return;
+        }
+        if !self.window.is_fullscreen() || !self.state.blocking_read().auto_mode {
+            return;
+        }
+        
+        let surface = self.window.surface();
+        let monitor = match WidgetExt::display(&self.window).monitor_at_surface(&surface) {
+            Some(monitor) => monitor,
+            None => {
+                warn!("Fullscreen window is not on any monitor, keeping current mode");
+                return;
+            }
+        };
+        
+        // A turned stream fills the monitor in the other orientation
+        let (width, height, refresh_mhz) = native_mode(&monitor);
+        let (width, height) = self.state.blocking_read().orientation.size(width, height);
+        let mode = (width, height, refresh_mhz);
+        if self.requested_mode.get() == Some(mode) {
+            return;
+        }
+        
+        let (width, height, refresh_mhz) = mode;
+        info!(
+            "Fullscreen on {} {} ({}): requesting {}x{}@{:.2}Hz",
+            monitor.manufacturer().unwrap_or_default(),
+            monitor.model().unwrap_or_default(),
+            monitor.connector().unwrap_or_default(),
+            width, height, refresh_mhz as f64 / 1000.0
+        );
+        
+        if self.commands.send(Command::RequestMode { width, height, refresh_mhz }).is_ok() {
+            self.requested_mode.set(Some(mode));
+        }
+    }
+    
+    /// Send a key to the server's keyboard while the pointer is captured;
+    /// false when the pointer isn't, or for F8, which stays local
+    fn forward_key(&self, key: gdk4::Key, keycode: u32, pressed: bool) -> bool {
+        let mut pointer = self.pointer.borrow_mut();
+        let Some(lock) = pointer.as_mut().filter(|_| key != gdk4::Key::F8) else { return false };
+        // Hardware keycodes are evdev codes offset by 8, on X11 and Wayland
+        let code = keycode.saturating_sub(8);
+        if code != 0 && lock.key(code, pressed) {
+            let _ = self.commands.send(Command::Key { code, pressed });
+        }
+        true
+    }
+    
+    fn on_key_pressed(&self, key: gdk4::Key, keycode: u32) -> glib::Propagation {
+        // Nothing gets a kiosk out of fullscreen
+        {
+            let state = self.state.blocking_read();
+            if state.block_input || (state.kiosk && matches!(key, gdk4::Key::F11 | gdk4::Key::Escape)) {
+                return glib::Propagation::Stop;
+            }
+        }
+        
+        if self.forward_key(key, keycode, true) {
+            return glib::Propagation::Stop;
+        }
+        
+        match key {
+            gdk4::Key::F8 => {
+                let _ = WidgetExt::activate_action(&self.window, "win.pointer-lock", None);
+                glib::Propagation::Stop
+            }
+            gdk4::Key::F9 => {
+                // The menu bar is gone while borderless
+                let _ = WidgetExt::activate_action(&self.window, "win.borderless", None);
+                glib::Propagation::Stop
+            }
+            gdk4::Key::F11 => {
+                // Toggle fullscreen
+                if self.window.is_fullscreen() {
+                    self.window.unfullscreen();
+                } else {
+                    self.window.fullscreen();
+                }
+                glib::Propagation::Stop
+            }
+            gdk4::Key::Escape => {
+                if self.window.is_fullscreen() {
+                    self.window.unfullscreen();
+                    glib::Propagation::Stop
+                } else {
+                    glib::Propagation::Proceed
+                }
+            }
+            _ => glib::Propagation::Proceed,
+        }
+    }
+    
+    /// Show `message` over the stream for a moment, and in place of the
+    /// stats line for a while. A kiosk only shows it in the hidden status
+    /// bar.
+    pub fn set_status(&self, message: &str) {
+        self.status_bar.remove_all(self.context_id);
+        self.status_bar.push(self.context_id, message);
+        self.message_until.set(Some(Instant::now() + MESSAGE_HOLD));
+        if !self.state.blocking_read().kiosk {
+            self.osd.borrow_mut().post(message, Instant::now());
+            self.drawing_area.queue_draw();
+        }
+    }
+    
+    /// Replace the stats line, unless a message is still being shown
+    fn show_stats_status(&self, stats: &StatsSnapshot) {
+        if self.message_until.get().is_some_and(|until| Instant::now() < until) {
+            return;
+        }
+        let text = if self.state.blocking_read().connected {
+            stats.summary()
+        } else {
+            "Disconnected".to_string()
+        };
+        self.status_bar.remove_all(self.stats_context_id);
+        self.status_bar.push(self.stats_context_id, &text);
+        self.sparkline.queue_draw();
+    }
+    
+    /// Say so when the server connects or goes away
+    fn check_connection(&self) {
+        let connected = self.state.blocking_read().connected;
+        if connected != self.was_connected.replace(connected) {
+            self.set_connected(connected);
+        }
+    }
+    
+    pub fn set_connected(&self, connected: bool) {
+        let status = {
+            let state = self.state.blocking_read();
+            let server = network::server_address(&state.server, state.port);
+            if connected {
+                format!("Connected to {}", server)
+            } else if state.user_disconnected {
+                "Disconnected".to_string()
+            } else {
+                format!("Reconnecting to {}…", server)
+            }
+        };
+        self.set_status(&status);
+    }
+}
+
+fn upload_progress_text(received: u64, size: u64) -> String {
+    format!("{:.1} of {:.1} MB", received as f64 / 1e6, size as f64 / 1e6)
+}
+
+fn window_title(server: &str) -> String {
+    format!("IP Display Client - {}", server)
+}
+
+/// Convert `frame` straight into the renderer's surface; false if its
+/// format can't be shown yet
+pub fn render_frame(renderer: &dyn Renderer, frame: &FrameData) -> Result<bool> {
+    let header = &frame.header;
+    let data = frame.data.as_slice();
+    let stride = frame.stride();
+    match header.format {
+        FrameFormat::Rgba32 => renderer.update_frame(header.width, header.height, stride, data)?,
+        FrameFormat::Rgb24 => renderer.update_frame_rgb(header.width, header.height, stride, data)?,
+        FrameFormat::Rgb565 => renderer.update_frame_rgb565(header.width, header.height, stride, data)?,
+        FrameFormat::Rgba1010102 => renderer.update_frame_rgb10a2(header.width, header.height, stride, data)?,
+        FrameFormat::Yuv420p | FrameFormat::Nv12 | FrameFormat::P010 => {
+            let layout = frame.chroma_layout().unwrap();
+            renderer.update_frame_yuv(header.width, header.height, data, layout)?
+        }
+        FrameFormat::Jpeg => renderer.update_frame_jpeg(header.width, header.height, data)?,
+        FrameFormat::H264 | FrameFormat::H265 => {
+            // Announced once when the stream switches; the last frame stays
+            debug!("Codec formats not yet supported");
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Native mode of a monitor as (width, height, refresh in mHz). GDK reports
+/// geometry in logical pixels, so undo the scale to get the EDID resolution.
+fn native_mode(monitor: &gdk4::Monitor) -> (u32, u32, u32) {
+    let geometry = monitor.geometry();
+    let scale = monitor.scale_factor().max(1);
+    (
+        (geometry.width() * scale) as u32,
+        (geometry.height() * scale) as u32,
+        monitor.refresh_rate().max(0) as u32,
+    )
 }
@@ -0,0 +1,250 @@
+// IP Display Client - File Upload
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Files dropped on the window, uploaded to the server's `upload_dir`. A
+//! FILE_BEGIN names the file and gives its size, then the file follows in
+//! FILE_DATA chunks. The server answers every request with FILE_STATUS, and
+//! only `WINDOW` chunks go out ahead of those answers, so an upload never
+//! queues more than a moment's worth of data in front of the frames. An
+//! empty chunk abandons the upload.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+use crate::protocol::{Command, FileStatus};
+
+/// Bytes in a FILE_DATA chunk, the most the server takes
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Chunks sent ahead of the server's answers
+const WINDOW: usize = 8;
+
+/// Longest file name the server takes, in bytes
+pub const MAX_NAME: usize = 200;
+
+/// How long the server may take to answer before the upload is given up
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default for `--upload-limit`, in MB
+pub const DEFAULT_UPLOAD_LIMIT_MB: u64 = 100;
+
+/// What the window hears about an upload
+#[derive(Debug, Clone)]
+pub enum UploadEvent {
+    /// The server took the file; cancelling `cancel` abandons it
+    Started { name: String, size: u64, cancel: CancellationToken },
+    /// Bytes the server has written so far
+    Progress { received: u64, size: u64 },
+    Finished { name: String },
+    Failed { name: String, error: String },
+}
+
+/// Sends dropped files one at a time over the primary link
+#[derive(Debug)]
+pub struct Uploader {
+    commands: UnboundedSender<Command>,
+    statuses: UnboundedReceiver<FileStatus>,
+    /// Largest file to send, in bytes
+    limit: u64,
+    next_id: u32,
+}
+
+impl Uploader {
+    pub fn new(commands: UnboundedSender<Command>, statuses: UnboundedReceiver<FileStatus>, limit: u64) -> Self {
+        Self { commands, statuses, limit, next_id: 0 }
+    }
+
+    /// Upload `path`, telling `events` how it goes
+    pub async fn upload(&mut self, path: &Path, events: &UnboundedSender<UploadEvent>) {
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let event = match self.send(path, &name, events).await {
+            Ok(()) => UploadEvent::Finished { name },
+            Err(e) => UploadEvent::Failed { name, error: format!("{:#}", e) },
+        };
+        let _ = events.send(event);
+    }
+
+    async fn send(&mut self, path: &Path, name: &str, events: &UnboundedSender<UploadEvent>) -> Result<()> {
+        check_name(name)?;
+        let mut file = tokio::fs::File::open(path).await.with_context(|| format!("Can't open {}", path.display()))?;
+        let size = file.metadata().await?.len();
+        if size > self.limit {
+            return Err(anyhow::anyhow!(
+                "{} MB is over the {} MB upload limit",
+                size.div_ceil(1_000_000),
+                self.limit / 1_000_000
+            ));
+        }
+
+        self.next_id = self.next_id.wrapping_add(1);
+        let id = self.next_id;
+        self.commands.send(Command::FileBegin { id, size, name: name.to_string() })?;
+        let cancel = CancellationToken::new();
+        if self.next_status(id, &cancel).await?.status == 1 {
+            return Ok(());
+        }
+        let _ = events.send(UploadEvent::Started { name: name.to_string(), size, cancel: cancel.clone() });
+
+        let result = self.send_chunks(id, &mut file, size, &cancel, events).await;
+        if result.is_err() {
+            let _ = self.commands.send(Command::FileData { id, data: Vec::new() });
+        }
+        result
+    }
+
+    async fn send_chunks(
+        &mut self,
+        id: u32,
+        file: &mut tokio::fs::File,
+        size: u64,
+        cancel: &CancellationToken,
+        events: &UnboundedSender<UploadEvent>,
+    ) -> Result<()> {
+        let (mut sent, mut unanswered) = (0u64, 0);
+        loop {
+            while sent < size && unanswered < WINDOW {
+                let mut data = vec![0u8; (size - sent).min(CHUNK_SIZE as u64) as usize];
+                file.read_exact(&mut data).await.context("File changed while uploading")?;
+                sent += data.len() as u64;
+                unanswered += 1;
+                self.commands.send(Command::FileData { id, data })?;
+            }
+
+            let status = self.next_status(id, cancel).await?;
+            unanswered -= 1;
+            let _ = events.send(UploadEvent::Progress { received: status.received, size });
+            if status.status == 1 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// The server's next answer about upload `id`, failing if it refused
+    async fn next_status(&mut self, id: u32, cancel: &CancellationToken) -> Result<FileStatus> {
+        loop {
+            let status = tokio::select! {
+                _ = cancel.cancelled() => return Err(anyhow::anyhow!("Cancelled")),
+                status = tokio::time::timeout(STATUS_TIMEOUT, self.statuses.recv()) => status,
+            };
+            let status = match status {
+                Ok(Some(status)) => status,
+                Ok(None) => return Err(anyhow::anyhow!("Disconnected")),
+                Err(_) => return Err(anyhow::anyhow!("The server stopped answering; does it take uploads?")),
+            };
+            // Answers to an upload given up on can still be on their way
+            if status.id != id {
+                continue;
+            }
+            if status.status < 0 {
+                return Err(refusal(-status.status));
+            }
+            return Ok(status);
+        }
+    }
+}
+
+/// Names the server takes: no directories, nothing hidden
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') {
+        return Err(anyhow::anyhow!("Hidden files and folders can't be uploaded"));
+    }
+    if name.len() > MAX_NAME {
+        return Err(anyhow::anyhow!("The name is longer than {} bytes", MAX_NAME));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(anyhow::anyhow!("The name has control characters in it"));
+    }
+    Ok(())
+}
+
+/// Why the server turned an upload down, from its errno
+fn refusal(errno: i32) -> anyhow::Error {
+    const EPERM: i32 = 1;
+    const EACCES: i32 = 13;
+    const EEXIST: i32 = 17;
+    const EFBIG: i32 = 27;
+    match errno {
+        EACCES => anyhow::anyhow!("The server doesn't take uploads (its upload_dir is unset)"),
+        EPERM => anyhow::anyhow!("The server only takes uploads from paired clients"),
+        EEXIST => anyhow::anyhow!("The server already has a file of that name"),
+        EFBIG => anyhow::anyhow!("Too large for the server's upload_max_mb"),
+        _ => anyhow::anyhow!("The server couldn't store it: {}", std::io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    /// A server that writes what it is sent into a Vec, refusing names
+    /// starting with "taken"
+    async fn serve(mut commands: UnboundedReceiver<Command>, statuses: UnboundedSender<FileStatus>) -> Vec<u8> {
+        let (mut received, mut size, mut stored) = (0u64, 0u64, Vec::new());
+        while let Some(command) = commands.recv().await {
+            let (id, status) = match command {
+                Command::FileBegin { id, name, .. } if name.starts_with("taken") => (id, -17),
+                Command::FileBegin { id, size: file_size, .. } => {
+                    (received, size) = (0, file_size);
+                    (id, (file_size == 0) as i32)
+                }
+                Command::FileData { data, .. } if data.is_empty() => break,
+                Command::FileData { id, data } => {
+                    received += data.len() as u64;
+                    stored.extend_from_slice(&data);
+                    (id, (received == size) as i32)
+                }
+                _ => continue,
+            };
+            statuses.send(FileStatus { received, id, status }).unwrap();
+        }
+        stored
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let dir = std::env::temp_dir().join(format!("ipdisp-upload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 11 + 5).map(|i| i as u8).collect();
+        let path = dir.join("report.bin");
+        std::fs::write(&path, &contents).unwrap();
+        let taken = dir.join("taken.txt");
+        std::fs::write(&taken, b"x").unwrap();
+
+        let (command_tx, command_rx) = unbounded_channel();
+        let (status_tx, status_rx) = unbounded_channel();
+        let server = tokio::spawn(serve(command_rx, status_tx));
+        let (event_tx, mut event_rx) = unbounded_channel();
+        let mut uploader = Uploader::new(command_tx, status_rx, contents.len() as u64);
+
+        uploader.upload(&path, &event_tx).await;
+        let mut events = Vec::new();
+        while let Ok(event) = event_rx.try_recv() {
+            events.push(event);
+        }
+        assert!(matches!(&events[0], UploadEvent::Started { size, .. } if *size == contents.len() as u64));
+        assert!(matches!(&events[events.len() - 2], UploadEvent::Progress { received, size } if received == size));
+        assert!(matches!(events.last(), Some(UploadEvent::Finished { name }) if name == "report.bin"));
+
+        uploader.upload(&taken, &event_tx).await;
+        let Ok(UploadEvent::Failed { error, .. }) = event_rx.try_recv() else { panic!("taken.txt was uploaded") };
+        assert!(error.contains("already has"), "{}", error);
+
+        // Over the limit, or hidden: never offered to the server
+        uploader.limit = 10;
+        uploader.upload(&path, &event_tx).await;
+        assert!(matches!(event_rx.try_recv(), Ok(UploadEvent::Failed { error, .. }) if error.contains("limit")));
+        uploader.upload(&dir.join(".hidden"), &event_tx).await;
+        assert!(matches!(event_rx.try_recv(), Ok(UploadEvent::Failed { error, .. }) if error.contains("Hidden")));
+
+        drop(uploader);
+        assert_eq!(server.await.unwrap(), contents);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+// IP Display Client - Bandwidth Accounting
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Bytes received per account and billing month, kept on disk for clients
+//! on metered links. An optional monthly budget warns, or pauses the stream
+//! until the next cycle, once it is used up.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Share of the budget at which a warning is logged ahead of running out
+const WARN_FRACTION: f64 = 0.8;
+
+/// Latest day a billing cycle can start on, so every month has it
+pub const MAX_CYCLE_DAY: u32 = 28;
+
+/// How often a paused client checks whether a new cycle has started
+pub const BUDGET_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the ledger is written out while streaming
+pub const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A billing cycle, named after the month it starts in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Period {
+    pub year: i32,
+    pub month: u32,
+}
+
+impl Period {
+    /// The cycle containing `now` (UTC) when cycles start on `cycle_day`
+    pub fn containing(now: SystemTime, cycle_day: u32) -> Self {
+        let days = now.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() / 86_400);
+        let (year, month, day) = civil_from_days(days as i64);
+        match (day >= cycle_day, month) {
+            (true, _) => Self { year, month },
+            (false, 1) => Self { year: year - 1, month: 12 },
+            (false, _) => Self { year, month: month - 1 },
+        }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        let (year, month) = text.split_once('-')?;
+        let month = month.parse().ok().filter(|month| (1..=12).contains(month))?;
+        Some(Self { year: year.parse().ok()?, month })
+    }
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}", self.year, self.month)
+    }
+}
+
+/// Year, month and day of a count of days since 1970-01-01 (Howard
+/// Hinnant's `civil_from_days`)
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year as i32, month as u32, day as u32)
+}
+
+/// What happens once a cycle's budget is used up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum BudgetAction {
+    /// Log a warning and keep streaming
+    #[default]
+    Warn,
+    /// Disconnect until the next cycle starts
+    Pause,
+}
+
+/// Where a cycle's usage stands against the budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BudgetLevel {
+    Under,
+    Nearly,
+    Exhausted,
+}
+
+/// A cap on the bytes received per billing cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    pub limit_bytes: u64,
+    pub action: BudgetAction,
+    /// Day of the month cycles start on, 1 to `MAX_CYCLE_DAY`
+    pub cycle_day: u32,
+}
+
+impl Budget {
+    pub fn level(&self, used: u64) -> BudgetLevel {
+        if used >= self.limit_bytes {
+            BudgetLevel::Exhausted
+        } else if used as f64 >= self.limit_bytes as f64 * WARN_FRACTION {
+            BudgetLevel::Nearly
+        } else {
+            BudgetLevel::Under
+        }
+    }
+
+    /// Whether `account` has to stay disconnected at `now`
+    pub fn pauses(&self, ledger: &UsageLedger, account: &str, now: SystemTime) -> bool {
+        let used = ledger.used(account, Period::containing(now, self.cycle_day));
+        self.action == BudgetAction::Pause && self.level(used) == BudgetLevel::Exhausted
+    }
+}
+
+/// Bytes received per account and cycle. Changes stay in memory until
+/// `save`, so counting a frame doesn't touch the disk.
+#[derive(Debug, Clone, Default)]
+pub struct UsageLedger {
+    path: Option<PathBuf>,
+    totals: BTreeMap<(String, Period), u64>,
+    unsaved: bool,
+}
+
+impl UsageLedger {
+    /// `$XDG_STATE_HOME/ip-display-client/usage`, falling back to
+    /// `~/.local/state`
+    pub fn default_path() -> Option<PathBuf> {
+        let state = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state")))?;
+        Some(state.join("ip-display-client").join("usage"))
+    }
+
+    /// Load from `path`; a missing file is an empty ledger
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut totals = BTreeMap::new();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            let parsed = (|| {
+                let account = fields.next()?;
+                let period = Period::parse(fields.next()?)?;
+                let bytes = fields.next()?.parse().ok()?;
+                Some(((account.to_string(), period), bytes))
+            })();
+            match parsed {
+                Some((key, bytes)) => {
+                    totals.insert(key, bytes);
+                }
+                None => return Err(anyhow::anyhow!("Malformed line in {}", path.display())),
+            }
+        }
+
+        Ok(Self { path: Some(path), totals, unsaved: false })
+    }
+
+    /// Count `bytes` against `account` in `period`, returning its new total
+    pub fn add(&mut self, account: &str, period: Period, bytes: u64) -> u64 {
+        let total = self.totals.entry((account.to_string(), period)).or_default();
+        *total += bytes;
+        self.unsaved = true;
+        *total
+    }
+
+    pub fn used(&self, account: &str, period: Period) -> u64 {
+        self.totals.get(&(account.to_string(), period)).copied().unwrap_or(0)
+    }
+
+    /// Write the ledger out if anything changed since it was last saved
+    pub fn save(&mut self) -> Result<()> {
+        let Some(path) = self.path.as_ref().filter(|_| self.unsaved) else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut contents = String::new();
+        for ((account, period), bytes) in &self.totals {
+            contents.push_str(&format!("{} {} {}\n", account, period, bytes));
+        }
+        // Replaced in one step so a crash never leaves half a ledger
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        fs::write(&partial, contents)
+            .and_then(|_| fs::rename(&partial, path))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        self.unsaved = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(days: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(days * 86_400 + 3600)
+    }
+
+    #[test]
+    fn test_billing_period() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        // 2024-02-29 and 2026-10-16
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(20_742), (2026, 10, 16));
+
+        assert_eq!(Period::containing(date(20_742), 1).to_string(), "2026-10");
+        assert_eq!(Period::containing(date(20_742), 16).to_string(), "2026-10");
+        assert_eq!(Period::containing(date(20_742), 17).to_string(), "2026-09");
+        // 2026-01-05 belongs to December's cycle with a cycle day of 10
+        assert_eq!(Period::containing(date(20_458), 10).to_string(), "2025-12");
+    }
+
+    #[test]
+    fn test_budget_and_ledger() {
+        let dir = std::env::temp_dir().join(format!("ipdisp-usage-{}", std::process::id()));
+        let path = dir.join("usage");
+        let october = Period { year: 2026, month: 10 };
+
+        let mut ledger = UsageLedger::load(path.clone()).unwrap();
+        assert_eq!(ledger.add("display:8080", october, 700), 700);
+        assert_eq!(ledger.add("display:8080", october, 150), 850);
+        ledger.add("other:8080", october, 5);
+        ledger.save().unwrap();
+
+        let reloaded = UsageLedger::load(path).unwrap();
+        assert_eq!(reloaded.used("display:8080", october), 850);
+        assert_eq!(reloaded.used("display:8080", Period { year: 2026, month: 11 }), 0);
+        fs::remove_dir_all(dir).unwrap();
+
+        let mut budget = Budget { limit_bytes: 1000, action: BudgetAction::Warn, cycle_day: 1 };
+        assert_eq!(budget.level(799), BudgetLevel::Under);
+        assert_eq!(budget.level(850), BudgetLevel::Nearly);
+        assert_eq!(budget.level(1000), BudgetLevel::Exhausted);
+
+        // Only a pausing budget stops the stream, and only in that cycle
+        let mut ledger = UsageLedger::default();
+        ledger.add("display:8080", Period::containing(date(20_742), 1), 1000);
+        assert!(!budget.pauses(&ledger, "display:8080", date(20_742)));
+        budget.action = BudgetAction::Pause;
+        assert!(budget.pauses(&ledger, "display:8080", date(20_742)));
+        assert!(!budget.pauses(&ledger, "display:8080", date(20_765)));
+    }
+}
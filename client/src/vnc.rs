@@ -0,0 +1,448 @@
+// IP Display Client - VNC Source
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! `vnc://host[:port]`: a small RFB (VNC) viewer, enough to show a
+//! desktop shared without a password. It speaks RFB 3.3, 3.7 and 3.8,
+//! asks for 32-bit true colour, and takes the raw and CopyRect encodings
+//! plus DesktopSize, which every server supports. After each update the
+//! whole desktop is sent on as an RGBA32 frame, and the next incremental
+//! update requested, so the server paces the stream to what we draw.
+//! Input isn't forwarded.
+
+use anyhow::{Context, Result};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::network::{RECONNECT_DELAY, MAX_RECONNECT_DELAY};
+use crate::protocol::{Command, FrameData, FrameFormat, PacketHeader};
+use crate::source::{DisplaySource, SourceFuture, SourceStats};
+use crate::AppState;
+
+pub const DEFAULT_PORT: u16 = 5900;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest desktop side accepted from a server
+const MAX_SIDE: u16 = 16384;
+
+/// Longest clipboard text read from a server; it is skipped anyway
+const MAX_CUT_TEXT: u32 = 1 << 20;
+
+const SECURITY_NONE: u8 = 1;
+
+const ENCODING_RAW: i32 = 0;
+const ENCODING_COPY_RECT: i32 = 1;
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+/// An open connection and the desktop as far as it has been drawn
+struct Session {
+    stream: BufReader<TcpStream>,
+    width: u16,
+    height: u16,
+    /// Straight RGBA, `width * 4` bytes a row
+    desktop: Vec<u8>,
+}
+
+pub struct VncSource {
+    host: String,
+    port: u16,
+    state: Arc<RwLock<AppState>>,
+    session: Mutex<Option<Session>>,
+    delay: StdMutex<Duration>,
+    stats: StdMutex<SourceStats>,
+}
+
+impl VncSource {
+    pub fn new(host: String, port: u16, state: Arc<RwLock<AppState>>) -> Self {
+        Self {
+            host,
+            port,
+            state,
+            session: Mutex::default(),
+            delay: StdMutex::new(RECONNECT_DELAY),
+            stats: StdMutex::default(),
+        }
+    }
+
+    async fn open(&self) -> Result<Session> {
+        let address = (self.host.as_str(), self.port);
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out connecting to {}:{}", self.host, self.port))?
+            .with_context(|| format!("Failed to connect to {}:{}", self.host, self.port))?;
+        stream.set_nodelay(true)?;
+        let session = handshake(BufReader::new(stream)).await?;
+        info!("Showing VNC desktop {}:{} ({}x{})", self.host, self.port, session.width, session.height);
+
+        let mut state = self.state.write().await;
+        state.connected = true;
+        state.display_width = session.width as u32;
+        state.display_height = session.height as u32;
+        drop(state);
+        self.stats.lock().unwrap().connections += 1;
+        Ok(session)
+    }
+
+    async fn lost(&self, session: &mut Option<Session>) {
+        session.take();
+        self.state.write().await.connected = false;
+    }
+}
+
+impl DisplaySource for VncSource {
+    fn name(&self) -> &'static str {
+        "vnc"
+    }
+
+    fn connect(&self) -> SourceFuture<'_, ()> {
+        Box::pin(async move {
+            let opened = self.open().await?;
+            *self.session.lock().await = Some(opened);
+            Ok(())
+        })
+    }
+
+    fn next_frame(&self) -> SourceFuture<'_, Option<FrameData>> {
+        Box::pin(async move {
+            let mut session = self.session.lock().await;
+            loop {
+                let Some(active) = session.as_mut() else {
+                    let delay = *self.delay.lock().unwrap();
+                    tokio::time::sleep(delay).await;
+                    let delay = match self.open().await {
+                        Ok(opened) => {
+                            *session = Some(opened);
+                            RECONNECT_DELAY
+                        }
+                        Err(e) => {
+                            warn!("Reconnect failed, retrying in {:?}: {:#}", delay, e);
+                            (delay * 2).min(MAX_RECONNECT_DELAY)
+                        }
+                    };
+                    *self.delay.lock().unwrap() = delay;
+                    continue;
+                };
+                match active.next_update().await {
+                    Ok(true) => {
+                        self.stats.lock().unwrap().frames += 1;
+                        return Ok(Some(active.frame()?));
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        warn!("Lost VNC server {}:{}: {:#}", self.host, self.port, e);
+                        self.lost(&mut session).await;
+                    }
+                }
+            }
+        })
+    }
+
+    fn send_input<'a>(&'a self, command: &'a Command) -> SourceFuture<'a, ()> {
+        debug!("VNC servers don't take {:?}", command.packet_type());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn stats(&self) -> SourceStats {
+        *self.stats.lock().unwrap()
+    }
+
+    fn close(&self) -> SourceFuture<'_, ()> {
+        Box::pin(async move {
+            let mut session = self.session.lock().await;
+            if let Some(mut active) = session.take() {
+                let _ = active.stream.get_mut().shutdown().await;
+                self.state.write().await.connected = false;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Agree a version, skip security, and set up the pixel format
+async fn handshake(mut stream: BufReader<TcpStream>) -> Result<Session> {
+    let mut version = [0u8; 12];
+    stream.read_exact(&mut version).await?;
+    let minor = parse_version(&version)?;
+    let reply = match minor {
+        8.. => b"RFB 003.008\n",
+        7 => b"RFB 003.007\n",
+        _ => b"RFB 003.003\n",
+    };
+    stream.get_mut().write_all(reply).await?;
+
+    if minor >= 7 {
+        let count = stream.read_u8().await?;
+        if count == 0 {
+            return Err(anyhow::anyhow!("Server refused: {}", read_reason(&mut stream).await?));
+        }
+        let mut types = vec![0u8; count as usize];
+        stream.read_exact(&mut types).await?;
+        if !types.contains(&SECURITY_NONE) {
+            return Err(anyhow::anyhow!("Server wants a password or other security (types {:?}), which isn't supported", types));
+        }
+        stream.get_mut().write_all(&[SECURITY_NONE]).await?;
+    } else {
+        match stream.read_u32().await? {
+            0 => return Err(anyhow::anyhow!("Server refused: {}", read_reason(&mut stream).await?)),
+            1 => {}
+            other => return Err(anyhow::anyhow!("Server wants security type {}, which isn't supported", other)),
+        }
+    }
+    // RFB 3.8 reports the result of even no security
+    if minor >= 8 && stream.read_u32().await? != 0 {
+        return Err(anyhow::anyhow!("Server refused: {}", read_reason(&mut stream).await?));
+    }
+
+    // Share the desktop with other viewers
+    stream.get_mut().write_all(&[1]).await?;
+    let width = stream.read_u16().await?;
+    let height = stream.read_u16().await?;
+    let mut server_format = [0u8; 16];
+    stream.read_exact(&mut server_format).await?;
+    let name_len = stream.read_u32().await?;
+    let mut name = vec![0u8; name_len.min(4096) as usize];
+    stream.read_exact(&mut name).await?;
+    debug!("VNC desktop {:?}", String::from_utf8_lossy(&name));
+    check_size(width, height)?;
+
+    let writer = stream.get_mut();
+    writer.write_all(&set_pixel_format()).await?;
+    writer.write_all(&set_encodings(&[ENCODING_RAW, ENCODING_COPY_RECT, ENCODING_DESKTOP_SIZE])).await?;
+    writer.write_all(&update_request(false, width, height)).await?;
+    Ok(Session { stream, width, height, desktop: vec![0; width as usize * height as usize * 4] })
+}
+
+/// The minor version of an `RFB 003.00x` greeting
+fn parse_version(version: &[u8; 12]) -> Result<u32> {
+    let text = std::str::from_utf8(version).ok().filter(|text| text.starts_with("RFB 003.") && text.ends_with('\n'));
+    text.and_then(|text| text[8..11].parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Not a VNC server: {:?}", String::from_utf8_lossy(version)))
+}
+
+async fn read_reason(stream: &mut BufReader<TcpStream>) -> Result<String> {
+    let len = stream.read_u32().await?;
+    let mut reason = vec![0u8; len.min(4096) as usize];
+    stream.read_exact(&mut reason).await?;
+    Ok(String::from_utf8_lossy(&reason).into_owned())
+}
+
+fn check_size(width: u16, height: u16) -> Result<()> {
+    if width == 0 || height == 0 || width > MAX_SIDE || height > MAX_SIDE {
+        return Err(anyhow::anyhow!("Unsupported desktop size {}x{}", width, height));
+    }
+    Ok(())
+}
+
+/// 32 bits a pixel, little-endian, red in the low byte: RGBX in memory
+fn set_pixel_format() -> [u8; 20] {
+    let mut message = [0u8; 20];
+    message[4..8].copy_from_slice(&[32, 24, 0, 1]);
+    for (i, max) in [255u16; 3].iter().enumerate() {
+        message[8 + i * 2..10 + i * 2].copy_from_slice(&max.to_be_bytes());
+    }
+    message[14..17].copy_from_slice(&[0, 8, 16]);
+    message
+}
+
+fn set_encodings(encodings: &[i32]) -> Vec<u8> {
+    let mut message = vec![2, 0];
+    message.extend_from_slice(&(encodings.len() as u16).to_be_bytes());
+    for encoding in encodings {
+        message.extend_from_slice(&encoding.to_be_bytes());
+    }
+    message
+}
+
+fn update_request(incremental: bool, width: u16, height: u16) -> [u8; 10] {
+    let mut message = [3, incremental as u8, 0, 0, 0, 0, 0, 0, 0, 0];
+    message[6..8].copy_from_slice(&width.to_be_bytes());
+    message[8..10].copy_from_slice(&height.to_be_bytes());
+    message
+}
+
+impl Session {
+    /// Read one server message, returning whether it changed the desktop
+    async fn next_update(&mut self) -> Result<bool> {
+        match self.stream.read_u8().await? {
+            0 => {
+                self.stream.read_u8().await?;
+                let rects = self.stream.read_u16().await?;
+                for _ in 0..rects {
+                    self.read_rect().await?;
+                }
+                let request = update_request(true, self.width, self.height);
+                self.stream.get_mut().write_all(&request).await?;
+                Ok(true)
+            }
+            // Colour map entries, which true colour doesn't use
+            1 => {
+                self.stream.read_u8().await?;
+                let _first = self.stream.read_u16().await?;
+                let count = self.stream.read_u16().await?;
+                self.skip(count as u64 * 6).await?;
+                Ok(false)
+            }
+            // Bell
+            2 => Ok(false),
+            // Clipboard text
+            3 => {
+                self.skip(3).await?;
+                let len = self.stream.read_u32().await?;
+                if len > MAX_CUT_TEXT {
+                    return Err(anyhow::anyhow!("Clipboard text of {} bytes", len));
+                }
+                self.skip(len as u64).await?;
+                Ok(false)
+            }
+            other => Err(anyhow::anyhow!("Unknown server message type {}", other)),
+        }
+    }
+
+    async fn read_rect(&mut self) -> Result<()> {
+        let x = self.stream.read_u16().await? as usize;
+        let y = self.stream.read_u16().await? as usize;
+        let w = self.stream.read_u16().await?;
+        let h = self.stream.read_u16().await?;
+        let encoding = self.stream.read_i32().await?;
+
+        if encoding == ENCODING_DESKTOP_SIZE {
+            check_size(w, h)?;
+            info!("VNC desktop resized to {}x{}", w, h);
+            (self.width, self.height) = (w, h);
+            self.desktop = vec![0; w as usize * h as usize * 4];
+            return Ok(());
+        }
+        let (w, h) = (w as usize, h as usize);
+        if x + w > self.width as usize || y + h > self.height as usize {
+            return Err(anyhow::anyhow!("Rectangle {}x{}+{}+{} is off the desktop", w, h, x, y));
+        }
+        let stride = self.width as usize * 4;
+        match encoding {
+            ENCODING_RAW => {
+                let mut pixels = vec![0u8; w * h * 4];
+                self.stream.read_exact(&mut pixels).await?;
+                for (row, source) in pixels.chunks_exact(w * 4).enumerate() {
+                    let start = (y + row) * stride + x * 4;
+                    let target = &mut self.desktop[start..start + w * 4];
+                    target.copy_from_slice(source);
+                    target.chunks_exact_mut(4).for_each(|pixel| pixel[3] = 255);
+                }
+            }
+            ENCODING_COPY_RECT => {
+                let src_x = self.stream.read_u16().await? as usize;
+                let src_y = self.stream.read_u16().await? as usize;
+                if src_x + w > self.width as usize || src_y + h > self.height as usize {
+                    return Err(anyhow::anyhow!("CopyRect source is off the desktop"));
+                }
+                let copied: Vec<u8> = (0..h)
+                    .flat_map(|row| {
+                        let start = (src_y + row) * stride + src_x * 4;
+                        self.desktop[start..start + w * 4].to_vec()
+                    })
+                    .collect();
+                for (row, source) in copied.chunks_exact(w * 4).enumerate() {
+                    let start = (y + row) * stride + x * 4;
+                    self.desktop[start..start + w * 4].copy_from_slice(source);
+                }
+            }
+            other => return Err(anyhow::anyhow!("Server sent encoding {}, which wasn't asked for", other)),
+        }
+        Ok(())
+    }
+
+    async fn skip(&mut self, len: u64) -> Result<()> {
+        let skipped = tokio::io::copy(&mut (&mut self.stream).take(len), &mut tokio::io::sink()).await?;
+        if skipped < len {
+            return Err(anyhow::anyhow!("Connection closed"));
+        }
+        Ok(())
+    }
+
+    /// The desktop as it stands
+    fn frame(&self) -> Result<FrameData> {
+        let size = self.desktop.len() as u32;
+        let mut header = PacketHeader::new(self.width as u32, self.height as u32, FrameFormat::Rgba32, size);
+        header.timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        FrameData::new(header, self.desktop.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A VNC server with a 2x1 desktop that draws it once, then grows it
+    async fn serve(listener: TcpListener) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"RFB 003.008\n").await.unwrap();
+        let mut version = [0u8; 12];
+        stream.read_exact(&mut version).await.unwrap();
+        assert_eq!(&version, b"RFB 003.008\n");
+        stream.write_all(&[2, 2, SECURITY_NONE]).await.unwrap();
+        assert_eq!(stream.read_u8().await.unwrap(), SECURITY_NONE);
+        stream.write_all(&0u32.to_be_bytes()).await.unwrap();
+        assert_eq!(stream.read_u8().await.unwrap(), 1);
+
+        let mut init = vec![0, 2, 0, 1];
+        init.extend_from_slice(&[0; 16]);
+        init.extend_from_slice(&4u32.to_be_bytes());
+        init.extend_from_slice(b"test");
+        stream.write_all(&init).await.unwrap();
+        let mut setup = [0u8; 20 + 16 + 10];
+        stream.read_exact(&mut setup).await.unwrap();
+        assert_eq!(setup[..20], set_pixel_format());
+        assert_eq!(&setup[36..], &update_request(false, 2, 1));
+
+        // A bell, then both pixels raw
+        let mut update = vec![2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 1];
+        update.extend_from_slice(&ENCODING_RAW.to_be_bytes());
+        update.extend_from_slice(&[10, 20, 30, 0, 40, 50, 60, 0]);
+        stream.write_all(&update).await.unwrap();
+        let mut request = [0u8; 10];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, update_request(true, 2, 1));
+
+        // Grow to 3x1 and copy nothing in
+        let mut update = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 3, 0, 1];
+        update.extend_from_slice(&ENCODING_DESKTOP_SIZE.to_be_bytes());
+        stream.write_all(&update).await.unwrap();
+        stream.read_exact(&mut request).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_vnc_source() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve(listener));
+
+        let state = Arc::new(RwLock::new(AppState::default()));
+        let source = VncSource::new("127.0.0.1".to_string(), port, Arc::clone(&state));
+        source.connect().await.unwrap();
+        assert!(state.read().await.connected);
+
+        let frame = source.next_frame().await.unwrap().unwrap();
+        assert_eq!((frame.header.width, frame.header.height, frame.header.format), (2, 1, FrameFormat::Rgba32));
+        assert_eq!(&frame.data[..], &[10, 20, 30, 255, 40, 50, 60, 255]);
+        frame.validate().unwrap();
+        let frame = source.next_frame().await.unwrap().unwrap();
+        assert_eq!((frame.header.width, frame.data.len()), (3, 12));
+        assert_eq!(source.stats(), SourceStats { connections: 1, frames: 2 });
+
+        server.await.unwrap();
+        source.close().await.unwrap();
+        assert!(!state.read().await.connected);
+    }
+
+    #[test]
+    fn test_vnc_version() {
+        assert_eq!(parse_version(b"RFB 003.008\n").unwrap(), 8);
+        assert_eq!(parse_version(b"RFB 003.889\n").unwrap(), 889);
+        assert!(parse_version(b"HTTP/1.1 200").is_err());
+    }
+}
@@ -0,0 +1,801 @@
+// IP Display Codecs - Pixel Format Conversion
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Conversions from wire pixel formats into Cairo's ARGB32 layout
+//! (premultiplied, BGRA byte order on little-endian). Each routine has a
+//! scalar reference and SIMD variants picked once at runtime; all variants
+//! produce identical output.
+
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Sse2,
+    #[cfg(target_arch = "x86_64")]
+    Ssse3,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Scalar => "scalar",
+            #[cfg(target_arch = "x86_64")]
+            Backend::Sse2 => "sse2",
+            #[cfg(target_arch = "x86_64")]
+            Backend::Ssse3 => "ssse3",
+            #[cfg(target_arch = "aarch64")]
+            Backend::Neon => "neon",
+        }
+    }
+}
+
+/// Best backend supported by the running CPU. Set `IPDISP_NO_SIMD` to force
+/// the scalar path when chasing conversion bugs.
+pub fn backend() -> Backend {
+    static BACKEND: OnceLock<Backend> = OnceLock::new();
+    *BACKEND.get_or_init(detect)
+}
+
+fn detect() -> Backend {
+    if std::env::var_os("IPDISP_NO_SIMD").is_some() {
+        return Backend::Scalar;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("ssse3") {
+            return Backend::Ssse3;
+        }
+        // SSE2 is part of the x86_64 baseline
+        Backend::Sse2
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        // NEON is mandatory on aarch64
+        Backend::Neon
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        Backend::Scalar
+    }
+}
+
+/// RGBA (straight alpha) to premultiplied BGRA. `dst` must hold as many
+/// pixels as `src`.
+pub fn rgba_to_bgra_premul(src: &[u8], dst: &mut [u8]) {
+    rgba_to_bgra_premul_with(backend(), src, dst)
+}
+
+/// RGBA to BGRA without premultiplying, for frames known to be opaque.
+/// `dst` must hold as many pixels as `src`.
+pub fn rgba_to_bgra(src: &[u8], dst: &mut [u8]) {
+    rgba_to_bgra_with(backend(), src, dst)
+}
+
+/// RGB to opaque BGRA. `dst` must hold as many pixels as `src`.
+pub fn rgb_to_bgra(src: &[u8], dst: &mut [u8]) {
+    rgb_to_bgra_with(backend(), src, dst)
+}
+
+pub fn rgba_to_bgra_premul_with(backend: Backend, src: &[u8], dst: &mut [u8]) {
+    debug_assert!(dst.len() >= src.len());
+    match backend {
+        Backend::Scalar => scalar::rgba_to_bgra_premul(src, dst),
+        // SAFETY: SSE2 is always available on x86_64
+        #[cfg(target_arch = "x86_64")]
+        Backend::Sse2 | Backend::Ssse3 => unsafe { x86::rgba_to_bgra_premul(src, dst) },
+        // SAFETY: NEON is always available on aarch64
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::rgba_to_bgra_premul(src, dst) },
+    }
+}
+
+pub fn rgba_to_bgra_with(backend: Backend, src: &[u8], dst: &mut [u8]) {
+    debug_assert!(dst.len() >= src.len());
+    match backend {
+        Backend::Scalar => scalar::rgba_to_bgra(src, dst),
+        #[cfg(target_arch = "x86_64")]
+        Backend::Sse2 => scalar::rgba_to_bgra(src, dst),
+        // SAFETY: only selected when the CPU reports SSSE3
+        #[cfg(target_arch = "x86_64")]
+        Backend::Ssse3 => unsafe { x86::rgba_to_bgra(src, dst) },
+        // SAFETY: NEON is always available on aarch64
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::rgba_to_bgra(src, dst) },
+    }
+}
+
+pub fn rgb_to_bgra_with(backend: Backend, src: &[u8], dst: &mut [u8]) {
+    debug_assert!(dst.len() / 4 >= src.len() / 3);
+    match backend {
+        Backend::Scalar => scalar::rgb_to_bgra(src, dst),
+        #[cfg(target_arch = "x86_64")]
+        Backend::Sse2 => scalar::rgb_to_bgra(src, dst),
+        // SAFETY: only selected when the CPU reports SSSE3
+        #[cfg(target_arch = "x86_64")]
+        Backend::Ssse3 => unsafe { x86::rgb_to_bgra(src, dst) },
+        // SAFETY: NEON is always available on aarch64
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::rgb_to_bgra(src, dst) },
+    }
+}
+
+/// Little-endian RGB565 to opaque BGRA. `dst` must hold as many pixels as
+/// `src`.
+pub fn rgb565_to_bgra(src: &[u8], dst: &mut [u8]) {
+    debug_assert!(dst.len() / 4 >= src.len() / 2);
+    scalar::rgb565_to_bgra(src, dst)
+}
+
+/// Little-endian RGBA1010102 (red in the low bits, two bits of straight
+/// alpha on top) to premultiplied BGRA, keeping the top eight bits of each
+/// colour channel
+pub fn rgb10a2_to_bgra_premul(src: &[u8], dst: &mut [u8]) {
+    debug_assert!(dst.len() >= src.len());
+    scalar::rgb10a2_to_bgra_premul(src, dst)
+}
+
+/// Decode one JPEG (an MJPEG frame) into packed RGB, expanding greyscale.
+/// The image must be `width` x `height`; that is checked from its headers
+/// before anything is decompressed.
+#[cfg(feature = "mjpeg")]
+pub fn decode_jpeg(data: &[u8], width: u32, height: u32) -> anyhow::Result<Vec<u8>> {
+    use jpeg_decoder::{Decoder, PixelFormat};
+
+    let mut decoder = Decoder::new(data);
+    decoder.read_info()?;
+    let info = decoder.info().ok_or_else(|| anyhow::anyhow!("JPEG has no frame header"))?;
+    if (info.width as u32, info.height as u32) != (width, height) {
+        return Err(anyhow::anyhow!(
+            "JPEG is {}x{} but the frame is {}x{}",
+            info.width, info.height, width, height
+        ));
+    }
+
+    let pixels = decoder.decode()?;
+    match info.pixel_format {
+        PixelFormat::RGB24 => Ok(pixels),
+        PixelFormat::L8 => Ok(pixels.iter().flat_map(|&l| [l, l, l]).collect()),
+        format => Err(anyhow::anyhow!("Unsupported JPEG pixel format {:?}", format)),
+    }
+}
+
+#[cfg(not(feature = "mjpeg"))]
+pub fn decode_jpeg(_data: &[u8], _width: u32, _height: u32) -> anyhow::Result<Vec<u8>> {
+    Err(anyhow::anyhow!("Built without MJPEG support (the mjpeg feature)"))
+}
+
+/// Chroma layout of a 4:2:0 frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaLayout {
+    /// Separate U and V planes (I420 / YUV420p)
+    Planar,
+    /// One plane of interleaved U,V pairs (NV12)
+    Interleaved,
+    /// NV12 layout with 16-bit little-endian samples holding 10 significant
+    /// bits at the top (P010)
+    Interleaved16,
+}
+
+impl ChromaLayout {
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            ChromaLayout::Planar | ChromaLayout::Interleaved => 1,
+            ChromaLayout::Interleaved16 => 2,
+        }
+    }
+}
+
+/// A BT.601 limited-range 4:2:0 frame: a full-size luma plane followed by
+/// chroma at half resolution in each direction, rounded up
+#[derive(Debug, Clone, Copy)]
+pub struct Yuv420<'a> {
+    width: usize,
+    chroma_width: usize,
+    layout: ChromaLayout,
+    y: &'a [u8],
+    chroma: &'a [u8],
+}
+
+impl<'a> Yuv420<'a> {
+    /// Bytes in a frame of this size and layout
+    pub fn size(width: usize, height: usize, layout: ChromaLayout) -> usize {
+        (width * height + 2 * width.div_ceil(2) * height.div_ceil(2)) * layout.bytes_per_sample()
+    }
+
+    /// Split `data` into planes; `None` if it's the wrong size
+    pub fn new(data: &'a [u8], width: usize, height: usize, layout: ChromaLayout) -> Option<Self> {
+        if data.len() != Self::size(width, height, layout) {
+            return None;
+        }
+
+        let (y, chroma) = data.split_at(width * height * layout.bytes_per_sample());
+        Some(Self { width, chroma_width: width.div_ceil(2), layout, y, chroma })
+    }
+
+    /// Convert one row into opaque BGRA. `dst` must hold `width` pixels.
+    pub fn row_to_bgra(&self, row: usize, dst: &mut [u8]) {
+        self.row_to_bgra_with(backend(), row, dst)
+    }
+
+    pub fn row_to_bgra_with(&self, backend: Backend, row: usize, dst: &mut [u8]) {
+        debug_assert!(dst.len() >= self.width * 4);
+        let sample = self.layout.bytes_per_sample();
+        let y = &self.y[row * self.width * sample..][..self.width * sample];
+        let chroma_row = (row / 2) * self.chroma_width;
+        let (u, v) = match self.layout {
+            ChromaLayout::Planar => {
+                let plane = self.chroma.len() / 2;
+                (&self.chroma[chroma_row..][..self.chroma_width],
+                 &self.chroma[plane + chroma_row..][..self.chroma_width])
+            }
+            ChromaLayout::Interleaved => (&self.chroma[chroma_row * 2..][..self.chroma_width * 2], &[][..]),
+            ChromaLayout::Interleaved16 => (&self.chroma[chroma_row * 4..][..self.chroma_width * 4], &[][..]),
+        };
+
+        match (backend, self.layout) {
+            (Backend::Scalar, ChromaLayout::Planar) => scalar::yuv_planar_to_bgra(y, u, v, dst),
+            (Backend::Scalar, ChromaLayout::Interleaved) => scalar::nv12_to_bgra(y, u, dst),
+            // 10-bit frames are rare enough that the scalar path serves every backend
+            (_, ChromaLayout::Interleaved16) => scalar::p010_to_bgra(y, u, dst),
+            // SAFETY: SSE2 is always available on x86_64
+            #[cfg(target_arch = "x86_64")]
+            (Backend::Sse2 | Backend::Ssse3, ChromaLayout::Planar) => unsafe { x86::yuv_planar_to_bgra(y, u, v, dst) },
+            #[cfg(target_arch = "x86_64")]
+            (Backend::Sse2 | Backend::Ssse3, ChromaLayout::Interleaved) => unsafe { x86::nv12_to_bgra(y, u, dst) },
+            #[cfg(target_arch = "aarch64")]
+            (Backend::Neon, ChromaLayout::Planar) => scalar::yuv_planar_to_bgra(y, u, v, dst),
+            #[cfg(target_arch = "aarch64")]
+            (Backend::Neon, ChromaLayout::Interleaved) => scalar::nv12_to_bgra(y, u, dst),
+        }
+    }
+}
+
+/// Whether every pixel of an RGBA buffer has alpha 255. Checks eight bytes
+/// at a time and bails out early on the first translucent block.
+pub fn is_opaque(src: &[u8]) -> bool {
+    const ALPHA: u64 = 0xFF00_0000_FF00_0000;
+
+    let mut blocks = src.chunks_exact(4096);
+    for block in &mut blocks {
+        let acc = block
+            .chunks_exact(8)
+            .fold(ALPHA, |acc, word| acc & u64::from_le_bytes(word.try_into().unwrap()));
+        if acc != ALPHA {
+            return false;
+        }
+    }
+
+    blocks.remainder().chunks_exact(4).all(|px| px[3] == 255)
+}
+
+/// c * a / 255, rounded to nearest, exactly as the SIMD paths compute it
+#[inline]
+fn mul_div255(c: u8, a: u8) -> u8 {
+    let t = c as u32 * a as u32 + 128;
+    ((t + (t >> 8)) >> 8) as u8
+}
+
+/// Widen a `bits`-bit channel to eight bits by repeating its top bits, so
+/// zero and full scale map to 0 and 255
+#[inline]
+fn expand_bits(c: u32, bits: u32) -> u8 {
+    ((c << (8 - bits)) | (c >> (2 * bits - 8))) as u8
+}
+
+/// BT.601 limited range to 8-bit RGB in 8.8 fixed point. The SIMD paths
+/// compute exactly the same sums.
+#[inline]
+fn yuv_to_bgr(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let clamp = |x: i32| (x >> 8).clamp(0, 255) as u8;
+    [
+        clamp(c + 516 * d + 128),
+        clamp(c - 100 * d - 208 * e + 128),
+        clamp(c + 409 * e + 128),
+    ]
+}
+
+mod scalar {
+    use super::{expand_bits, mul_div255, yuv_to_bgr};
+
+    pub fn rgba_to_bgra_premul(src: &[u8], dst: &mut [u8]) {
+        for (px, out) in src.chunks_exact(4).zip(dst.chunks_exact_mut(4)) {
+            let a = px[3];
+            out[0] = mul_div255(px[2], a);
+            out[1] = mul_div255(px[1], a);
+            out[2] = mul_div255(px[0], a);
+            out[3] = a;
+        }
+    }
+
+    pub fn rgba_to_bgra(src: &[u8], dst: &mut [u8]) {
+        for (px, out) in src.chunks_exact(4).zip(dst.chunks_exact_mut(4)) {
+            out[0] = px[2];
+            out[1] = px[1];
+            out[2] = px[0];
+            out[3] = px[3];
+        }
+    }
+
+    pub fn rgb_to_bgra(src: &[u8], dst: &mut [u8]) {
+        for (px, out) in src.chunks_exact(3).zip(dst.chunks_exact_mut(4)) {
+            out[0] = px[2];
+            out[1] = px[1];
+            out[2] = px[0];
+            out[3] = 255;
+        }
+    }
+
+    /// One row of luma with its half-width U and V rows
+    pub fn yuv_planar_to_bgra(y: &[u8], u: &[u8], v: &[u8], dst: &mut [u8]) {
+        for (i, (&luma, out)) in y.iter().zip(dst.chunks_exact_mut(4)).enumerate() {
+            let [b, g, r] = yuv_to_bgr(luma, u[i / 2], v[i / 2]);
+            out.copy_from_slice(&[b, g, r, 255]);
+        }
+    }
+
+    /// One row of luma with its row of interleaved U,V pairs
+    pub fn nv12_to_bgra(y: &[u8], uv: &[u8], dst: &mut [u8]) {
+        for (i, (&luma, out)) in y.iter().zip(dst.chunks_exact_mut(4)).enumerate() {
+            let [b, g, r] = yuv_to_bgr(luma, uv[i / 2 * 2], uv[i / 2 * 2 + 1]);
+            out.copy_from_slice(&[b, g, r, 255]);
+        }
+    }
+
+    /// P010 row: the high byte of each little-endian sample carries the top
+    /// eight of its ten bits
+    pub fn p010_to_bgra(y: &[u8], uv: &[u8], dst: &mut [u8]) {
+        for (i, (luma, out)) in y.chunks_exact(2).zip(dst.chunks_exact_mut(4)).enumerate() {
+            let [b, g, r] = yuv_to_bgr(luma[1], uv[i / 2 * 4 + 1], uv[i / 2 * 4 + 3]);
+            out.copy_from_slice(&[b, g, r, 255]);
+        }
+    }
+
+    pub fn rgb565_to_bgra(src: &[u8], dst: &mut [u8]) {
+        for (px, out) in src.chunks_exact(2).zip(dst.chunks_exact_mut(4)) {
+            let p = u16::from_le_bytes([px[0], px[1]]);
+            out.copy_from_slice(&[
+                expand_bits((p & 0x1f) as u32, 5),
+                expand_bits(((p >> 5) & 0x3f) as u32, 6),
+                expand_bits((p >> 11) as u32, 5),
+                255,
+            ]);
+        }
+    }
+
+    pub fn rgb10a2_to_bgra_premul(src: &[u8], dst: &mut [u8]) {
+        for (px, out) in src.chunks_exact(4).zip(dst.chunks_exact_mut(4)) {
+            let p = u32::from_le_bytes([px[0], px[1], px[2], px[3]]);
+            let a = (p >> 30) as u8 * 85;
+            out.copy_from_slice(&[
+                mul_div255((p >> 22) as u8, a),
+                mul_div255((p >> 12) as u8, a),
+                mul_div255((p >> 2) as u8, a),
+                a,
+            ]);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    /// Four pixels per iteration: widen to u16, multiply by alpha (255 for
+    /// the alpha lane itself), divide by 255 with rounding, narrow.
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn rgba_to_bgra_premul(src: &[u8], dst: &mut [u8]) {
+        let zero = _mm_setzero_si128();
+        let rgb_lanes = _mm_set_epi16(0, -1, -1, -1, 0, -1, -1, -1);
+        let alpha_lane = _mm_set_epi16(255, 0, 0, 0, 255, 0, 0, 0);
+        let round = _mm_set1_epi16(128);
+
+        let blocks = src.len() / 16;
+        for i in 0..blocks {
+            let v = _mm_loadu_si128(src.as_ptr().add(i * 16) as *const __m128i);
+
+            let mut halves = [_mm_unpacklo_epi8(v, zero), _mm_unpackhi_epi8(v, zero)];
+            for half in halves.iter_mut() {
+                // RGBA -> BGRA within each pixel
+                let px = _mm_shufflehi_epi16(_mm_shufflelo_epi16(*half, 0b11_00_01_10), 0b11_00_01_10);
+                let alpha = _mm_shufflehi_epi16(_mm_shufflelo_epi16(px, 0xFF), 0xFF);
+                let factor = _mm_or_si128(_mm_and_si128(alpha, rgb_lanes), alpha_lane);
+
+                let t = _mm_add_epi16(_mm_mullo_epi16(px, factor), round);
+                *half = _mm_srli_epi16(_mm_add_epi16(t, _mm_srli_epi16(t, 8)), 8);
+            }
+
+            let out = _mm_packus_epi16(halves[0], halves[1]);
+            _mm_storeu_si128(dst.as_mut_ptr().add(i * 16) as *mut __m128i, out);
+        }
+
+        let done = blocks * 16;
+        super::scalar::rgba_to_bgra_premul(&src[done..], &mut dst[done..]);
+    }
+
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn rgba_to_bgra(src: &[u8], dst: &mut [u8]) {
+        let shuffle = _mm_setr_epi8(2, 1, 0, 3, 6, 5, 4, 7, 10, 9, 8, 11, 14, 13, 12, 15);
+
+        let blocks = src.len() / 16;
+        for i in 0..blocks {
+            let v = _mm_loadu_si128(src.as_ptr().add(i * 16) as *const __m128i);
+            _mm_storeu_si128(dst.as_mut_ptr().add(i * 16) as *mut __m128i, _mm_shuffle_epi8(v, shuffle));
+        }
+
+        let done = blocks * 16;
+        super::scalar::rgba_to_bgra(&src[done..], &mut dst[done..]);
+    }
+
+    /// Four pixels per iteration via one byte shuffle. Each 16-byte load only
+    /// uses 12 bytes, so stop while a full load still fits in `src`.
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn rgb_to_bgra(src: &[u8], dst: &mut [u8]) {
+        let shuffle = _mm_setr_epi8(2, 1, 0, -128, 5, 4, 3, -128, 8, 7, 6, -128, 11, 10, 9, -128);
+        let alpha = _mm_set1_epi32(0xFF00_0000u32 as i32);
+
+        let mut pixels = 0;
+        while pixels * 3 + 16 <= src.len() {
+            let v = _mm_loadu_si128(src.as_ptr().add(pixels * 3) as *const __m128i);
+            let out = _mm_or_si128(_mm_shuffle_epi8(v, shuffle), alpha);
+            _mm_storeu_si128(dst.as_mut_ptr().add(pixels * 4) as *mut __m128i, out);
+            pixels += 4;
+        }
+
+        super::scalar::rgb_to_bgra(&src[pixels * 3..], &mut dst[pixels * 4..]);
+    }
+
+    /// Eight pixels from eight luma bytes and four U and V samples (as i16
+    /// in the low lanes). Pairs each pixel's terms so `_mm_madd_epi16` forms
+    /// the same 32-bit sums as the scalar path.
+    #[target_feature(enable = "sse2")]
+    unsafe fn yuv_block(y: __m128i, u: __m128i, v: __m128i, dst: *mut u8) {
+        let zero = _mm_setzero_si128();
+        let c = _mm_sub_epi16(_mm_unpacklo_epi8(y, zero), _mm_set1_epi16(16));
+        let d = _mm_sub_epi16(u, _mm_set1_epi16(128));
+        let e = _mm_sub_epi16(v, _mm_set1_epi16(128));
+        // Each chroma sample covers two pixels
+        let d = _mm_unpacklo_epi16(d, d);
+        let e = _mm_unpacklo_epi16(e, e);
+        let one = _mm_set1_epi16(1);
+
+        let r_coeff = _mm_set1_epi32((409 << 16) | 298);
+        let g_coeff = _mm_set1_epi32((-100i32 << 16) | 298);
+        let g_coeff_e = _mm_set1_epi32((128 << 16) | (-208i32 & 0xffff));
+        let b_coeff = _mm_set1_epi32((516 << 16) | 298);
+        let round = _mm_set1_epi32(128);
+
+        let channel = |first: __m128i, second: __m128i, coeff: __m128i| {
+            let lo = _mm_madd_epi16(_mm_unpacklo_epi16(first, second), coeff);
+            let hi = _mm_madd_epi16(_mm_unpackhi_epi16(first, second), coeff);
+            (lo, hi)
+        };
+
+        let (r_lo, r_hi) = channel(c, e, r_coeff);
+        let (g_lo, g_hi) = channel(c, d, g_coeff);
+        let (ge_lo, ge_hi) = channel(e, one, g_coeff_e);
+        let (b_lo, b_hi) = channel(c, d, b_coeff);
+
+        let narrow = |lo: __m128i, hi: __m128i| {
+            let words = _mm_packs_epi32(_mm_srai_epi32(lo, 8), _mm_srai_epi32(hi, 8));
+            _mm_packus_epi16(words, words)
+        };
+        let r = narrow(_mm_add_epi32(r_lo, round), _mm_add_epi32(r_hi, round));
+        let g = narrow(_mm_add_epi32(g_lo, ge_lo), _mm_add_epi32(g_hi, ge_hi));
+        let b = narrow(_mm_add_epi32(b_lo, round), _mm_add_epi32(b_hi, round));
+
+        let bg = _mm_unpacklo_epi8(b, g);
+        let ra = _mm_unpacklo_epi8(r, _mm_set1_epi8(-1));
+        _mm_storeu_si128(dst as *mut __m128i, _mm_unpacklo_epi16(bg, ra));
+        _mm_storeu_si128(dst.add(16) as *mut __m128i, _mm_unpackhi_epi16(bg, ra));
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn yuv_planar_to_bgra(y: &[u8], u: &[u8], v: &[u8], dst: &mut [u8]) {
+        let zero = _mm_setzero_si128();
+        let mut x = 0;
+        while x + 8 <= y.len() {
+            let luma = _mm_loadl_epi64(y.as_ptr().add(x) as *const __m128i);
+            let load4 = |plane: &[u8]| {
+                let word = (plane.as_ptr().add(x / 2) as *const i32).read_unaligned();
+                _mm_unpacklo_epi8(_mm_cvtsi32_si128(word), zero)
+            };
+            yuv_block(luma, load4(u), load4(v), dst.as_mut_ptr().add(x * 4));
+            x += 8;
+        }
+
+        super::scalar::yuv_planar_to_bgra(&y[x..], &u[x / 2..], &v[x / 2..], &mut dst[x * 4..]);
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn nv12_to_bgra(y: &[u8], uv: &[u8], dst: &mut [u8]) {
+        let low_bytes = _mm_set1_epi16(0xff);
+        let mut x = 0;
+        while x + 8 <= y.len() {
+            let luma = _mm_loadl_epi64(y.as_ptr().add(x) as *const __m128i);
+            let pairs = _mm_loadl_epi64(uv.as_ptr().add(x) as *const __m128i);
+            let u = _mm_and_si128(pairs, low_bytes);
+            let v = _mm_srli_epi16(pairs, 8);
+            yuv_block(luma, u, v, dst.as_mut_ptr().add(x * 4));
+            x += 8;
+        }
+
+        super::scalar::nv12_to_bgra(&y[x..], &uv[x..], &mut dst[x * 4..]);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    #[inline]
+    unsafe fn premul(c: uint8x16_t, a: uint8x16_t) -> uint8x16_t {
+        // (t + (t >> 8)) >> 8 with t = c * a + 128, as in the scalar path
+        let lo = vmull_u8(vget_low_u8(c), vget_low_u8(a));
+        let hi = vmull_u8(vget_high_u8(c), vget_high_u8(a));
+        vcombine_u8(
+            vraddhn_u16(lo, vrshrq_n_u16(lo, 8)),
+            vraddhn_u16(hi, vrshrq_n_u16(hi, 8)),
+        )
+    }
+
+    /// Sixteen pixels per iteration using de-interleaving loads
+    pub unsafe fn rgba_to_bgra_premul(src: &[u8], dst: &mut [u8]) {
+        let blocks = src.len() / 64;
+        for i in 0..blocks {
+            let px = vld4q_u8(src.as_ptr().add(i * 64));
+            let out = uint8x16x4_t(
+                premul(px.2, px.3),
+                premul(px.1, px.3),
+                premul(px.0, px.3),
+                px.3,
+            );
+            vst4q_u8(dst.as_mut_ptr().add(i * 64), out);
+        }
+
+        let done = blocks * 64;
+        super::scalar::rgba_to_bgra_premul(&src[done..], &mut dst[done..]);
+    }
+
+    pub unsafe fn rgba_to_bgra(src: &[u8], dst: &mut [u8]) {
+        let blocks = src.len() / 64;
+        for i in 0..blocks {
+            let px = vld4q_u8(src.as_ptr().add(i * 64));
+            vst4q_u8(dst.as_mut_ptr().add(i * 64), uint8x16x4_t(px.2, px.1, px.0, px.3));
+        }
+
+        let done = blocks * 64;
+        super::scalar::rgba_to_bgra(&src[done..], &mut dst[done..]);
+    }
+
+    pub unsafe fn rgb_to_bgra(src: &[u8], dst: &mut [u8]) {
+        let alpha = vdupq_n_u8(255);
+        let blocks = src.len() / 48;
+        for i in 0..blocks {
+            let px = vld3q_u8(src.as_ptr().add(i * 48));
+            vst4q_u8(dst.as_mut_ptr().add(i * 64), uint8x16x4_t(px.2, px.1, px.0, alpha));
+        }
+
+        super::scalar::rgb_to_bgra(&src[blocks * 48..], &mut dst[blocks * 64..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// Deterministic pseudo-random bytes covering every alpha value
+    fn test_pixels(len: usize) -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_mul_div255_exact() {
+        for c in 0..=255u32 {
+            for a in 0..=255u32 {
+                let expected = ((c * a) as f64 / 255.0).round() as u8;
+                assert_eq!(mul_div255(c as u8, a as u8), expected, "c={} a={}", c, a);
+            }
+        }
+    }
+
+    #[test]
+    fn test_simd_matches_scalar() {
+        // Odd pixel count exercises the scalar tail
+        let pixels = 1021;
+        let rgba = test_pixels(pixels * 4);
+        let rgb = test_pixels(pixels * 3);
+
+        let mut expected = vec![0u8; pixels * 4];
+        let mut actual = vec![0u8; pixels * 4];
+
+        scalar::rgba_to_bgra_premul(&rgba, &mut expected);
+        rgba_to_bgra_premul(&rgba, &mut actual);
+        assert_eq!(expected, actual, "rgba backend {}", backend().name());
+
+        scalar::rgba_to_bgra(&rgba, &mut expected);
+        rgba_to_bgra(&rgba, &mut actual);
+        assert_eq!(expected, actual, "opaque backend {}", backend().name());
+
+        scalar::rgb_to_bgra(&rgb, &mut expected);
+        rgb_to_bgra(&rgb, &mut actual);
+        assert_eq!(expected, actual, "rgb backend {}", backend().name());
+    }
+
+    #[test]
+    fn test_yuv_matches_scalar() {
+        // Odd size exercises rounded-up chroma and the scalar tail
+        let (width, height) = (37, 5);
+        let mut expected = vec![0u8; width * 4];
+        let mut actual = vec![0u8; width * 4];
+
+        for layout in [ChromaLayout::Planar, ChromaLayout::Interleaved, ChromaLayout::Interleaved16] {
+            let data = test_pixels(Yuv420::size(width, height, layout));
+            let frame = Yuv420::new(&data, width, height, layout).unwrap();
+            for row in 0..height {
+                frame.row_to_bgra_with(Backend::Scalar, row, &mut expected);
+                frame.row_to_bgra(row, &mut actual);
+                assert_eq!(expected, actual, "{:?} row {} backend {}", layout, row, backend().name());
+            }
+        }
+        let data = test_pixels(Yuv420::size(width, height, ChromaLayout::Planar));
+        assert!(Yuv420::new(&data[1..], width, height, ChromaLayout::Planar).is_none());
+        assert!(Yuv420::new(&data, width, height, ChromaLayout::Interleaved16).is_none());
+    }
+
+    #[test]
+    fn test_yuv_values() {
+        // Black, white and pure red at BT.601 limited range
+        assert_eq!(yuv_to_bgr(16, 128, 128), [0, 0, 0]);
+        assert_eq!(yuv_to_bgr(235, 128, 128), [255, 255, 255]);
+        assert_eq!(yuv_to_bgr(81, 90, 240), [0, 0, 255]);
+
+        // The same red as a 2x2 P010 frame, with junk in the low bits
+        let mut p010 = Vec::new();
+        for sample in [81u16; 4].into_iter().chain([90, 240]) {
+            p010.extend_from_slice(&((sample << 8) | 0x00c0).to_le_bytes());
+        }
+        let frame = Yuv420::new(&p010, 2, 2, ChromaLayout::Interleaved16).unwrap();
+        let mut out = [0u8; 8];
+        frame.row_to_bgra(1, &mut out);
+        assert_eq!(out, [0, 0, 255, 255, 0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_packed_rgb_values() {
+        let mut out = [0u8; 12];
+        // Red, green and blue at full scale
+        rgb565_to_bgra(&[0x00, 0xf8, 0xe0, 0x07, 0x1f, 0x00], &mut out);
+        assert_eq!(out, [0, 0, 255, 255, 0, 255, 0, 255, 255, 0, 0, 255]);
+
+        // Opaque red, then white at alpha 1/3
+        let mut out = [0u8; 8];
+        let mut src = Vec::new();
+        src.extend_from_slice(&(0xc000_03ffu32).to_le_bytes());
+        src.extend_from_slice(&(0x7fff_ffffu32).to_le_bytes());
+        rgb10a2_to_bgra_premul(&src, &mut out);
+        assert_eq!(out, [0, 0, 255, 255, 85, 85, 85, 85]);
+    }
+
+    #[test]
+    fn test_premultiply_values() {
+        let mut out = [0u8; 8];
+        rgba_to_bgra_premul(&[255, 128, 0, 255, 255, 128, 0, 128], &mut out);
+        assert_eq!(out, [0, 128, 255, 255, 0, 64, 128, 128]);
+    }
+
+    #[test]
+    #[cfg(feature = "mjpeg")]
+    fn test_decode_jpeg() {
+        let (width, height) = (16u16, 8u16);
+        let rgb: Vec<u8> = (0..width as usize * height as usize).flat_map(|_| [200, 40, 90]).collect();
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, 100)
+            .encode(&rgb, width, height, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+
+        let decoded = decode_jpeg(&jpeg, 16, 8).unwrap();
+        assert_eq!(decoded.len(), rgb.len());
+        // Lossy, but a flat colour at full quality comes back within a step
+        // or two
+        assert!(decoded.iter().zip(&rgb).all(|(&a, &b)| a.abs_diff(b) <= 3));
+
+        let mut grey = Vec::new();
+        jpeg_encoder::Encoder::new(&mut grey, 100)
+            .encode(&[128; 16 * 8], width, height, jpeg_encoder::ColorType::Luma)
+            .unwrap();
+        let decoded = decode_jpeg(&grey, 16, 8).unwrap();
+        assert!(decoded.chunks_exact(3).all(|px| px[0] == px[1] && px[1] == px[2]));
+
+        assert!(decode_jpeg(&jpeg, 8, 16).is_err());
+        assert!(decode_jpeg(&jpeg[..20], 16, 8).is_err());
+    }
+
+    #[test]
+    fn test_is_opaque() {
+        let mut rgba = vec![255u8; 1021 * 4];
+        assert!(is_opaque(&rgba));
+
+        // Translucent pixel in the unaligned tail
+        rgba[1020 * 4 + 3] = 254;
+        assert!(!is_opaque(&rgba));
+
+        // Translucent pixel inside a full block
+        rgba[1020 * 4 + 3] = 255;
+        rgba[7] = 0;
+        assert!(!is_opaque(&rgba));
+        assert!(is_opaque(&[]));
+    }
+
+    /// Throughput on a 4K frame; run with
+    /// `cargo test --release -p ipdisp-codecs -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_4k_conversion() {
+        let pixels = 3840 * 2160;
+        let rgba = test_pixels(pixels * 4);
+        let rgb = test_pixels(pixels * 3);
+        let mut out = vec![0u8; pixels * 4];
+        let iterations = 20;
+
+        let time = |f: &mut dyn FnMut()| {
+            f();
+            let start = Instant::now();
+            for _ in 0..iterations {
+                f();
+            }
+            start.elapsed().as_secs_f64() * 1000.0 / iterations as f64
+        };
+
+        let simd = backend();
+        let rgba_scalar = time(&mut || rgba_to_bgra_premul_with(Backend::Scalar, &rgba, &mut out));
+        let rgba_simd = time(&mut || rgba_to_bgra_premul_with(simd, &rgba, &mut out));
+        let swizzle_scalar = time(&mut || rgba_to_bgra_with(Backend::Scalar, &rgba, &mut out));
+        let swizzle_simd = time(&mut || rgba_to_bgra_with(simd, &rgba, &mut out));
+        let opaque = vec![255u8; pixels * 4];
+        let detect = time(&mut || assert!(is_opaque(&opaque)));
+        let rgb_scalar = time(&mut || rgb_to_bgra_with(Backend::Scalar, &rgb, &mut out));
+        let rgb_simd = time(&mut || rgb_to_bgra_with(simd, &rgb, &mut out));
+        let yuv = test_pixels(Yuv420::size(3840, 2160, ChromaLayout::Interleaved));
+        let nv12 = Yuv420::new(&yuv, 3840, 2160, ChromaLayout::Interleaved).unwrap();
+        let mut yuv_time = |backend| time(&mut || {
+            for (row, dst) in out.chunks_exact_mut(3840 * 4).enumerate() {
+                nv12.row_to_bgra_with(backend, row, dst);
+            }
+        });
+        let yuv_scalar = yuv_time(Backend::Scalar);
+        let yuv_simd = yuv_time(simd);
+
+        println!("4K RGBA premultiply: scalar {:.2} ms, {} {:.2} ms ({:.1}x)",
+                 rgba_scalar, simd.name(), rgba_simd, rgba_scalar / rgba_simd);
+        println!("4K RGBA swizzle:     scalar {:.2} ms, {} {:.2} ms ({:.1}x), opacity check {:.2} ms",
+                 swizzle_scalar, simd.name(), swizzle_simd, swizzle_scalar / swizzle_simd, detect);
+        println!("4K RGB to BGRA:      scalar {:.2} ms, {} {:.2} ms ({:.1}x)",
+                 rgb_scalar, simd.name(), rgb_simd, rgb_scalar / rgb_simd);
+        println!("4K NV12 to BGRA:     scalar {:.2} ms, {} {:.2} ms ({:.1}x)",
+                 yuv_scalar, simd.name(), yuv_simd, yuv_scalar / yuv_simd);
+
+        if simd != Backend::Scalar {
+            assert!(rgba_simd < rgba_scalar);
+        }
+    }
+}
@@ -0,0 +1,18 @@
+// IP Display Core - Shared Session Pieces
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! What both ends of a session agree on beyond the wire format in
+//! `ipds-protocol`: the clock Ping/Pong timestamps are read from, how
+//! offsets between the two clocks are estimated, and how often an idle
+//! connection says it is still there.
+
+use std::time::Duration;
+
+pub mod timesync;
+
+/// Send a heartbeat once nothing else has gone to the peer for this long
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Give up on a connection that has been silent for this long
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
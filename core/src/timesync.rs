@@ -0,0 +1,130 @@
+// IP Display Core - Clock Synchronisation
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! NTP-style estimate of the offset between the server's clock (which
+//! stamps frame headers) and ours, from Ping/Pong exchanges.
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// How often the client pings the server
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Exchanges kept for the minimum-delay filter
+const SAMPLE_WINDOW: usize = 8;
+
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+/// Local monotonic clock in nanoseconds, used for Ping/Pong timestamps
+pub fn now_ns() -> u64 {
+    epoch().elapsed().as_nanos() as u64
+}
+
+/// The `Instant` a reading of `now_ns` stands for
+pub fn instant_at(local_ns: i64) -> Instant {
+    let epoch = epoch();
+    if local_ns >= 0 {
+        epoch + Duration::from_nanos(local_ns as u64)
+    } else {
+        epoch.checked_sub(Duration::from_nanos(local_ns.unsigned_abs())).unwrap_or(epoch)
+    }
+}
+
+/// Clock offset and path delay measured by a Ping/Pong exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockEstimate {
+    /// Server clock minus local clock
+    pub offset_ns: i64,
+    /// Round trip time excluding the server's processing time
+    pub rtt_ns: u64,
+}
+
+impl ClockEstimate {
+    /// t0 ping sent (ours), t1 ping received and t2 pong sent (server's),
+    /// t3 pong received (ours)
+    pub fn from_exchange(t0: u64, t1: u64, t2: u64, t3: u64) -> Self {
+        let (t0, t1, t2, t3) = (t0 as i128, t1 as i128, t2 as i128, t3 as i128);
+        Self {
+            offset_ns: (((t1 - t0) + (t2 - t3)) / 2) as i64,
+            rtt_ns: ((t3 - t0) - (t2 - t1)).max(0) as u64,
+        }
+    }
+
+    /// A server timestamp expressed on the local clock
+    pub fn to_local_ns(self, server_ns: u64) -> i64 {
+        (server_ns as i128 - self.offset_ns as i128) as i64
+    }
+
+    /// A local timestamp expressed on the server's clock
+    pub fn to_server_ns(self, local_ns: u64) -> u64 {
+        (local_ns as i128 + self.offset_ns as i128).max(0) as u64
+    }
+}
+
+/// Keeps recent exchanges and trusts the one with the shortest round trip,
+/// as queueing delay only ever inflates it and skews the offset
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    samples: VecDeque<ClockEstimate>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, sample: ClockEstimate) -> ClockEstimate {
+        if self.samples.len() == SAMPLE_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.estimate().unwrap_or(sample)
+    }
+
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        self.samples.iter().min_by_key(|sample| sample.rtt_ns).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exchange_offset_and_rtt() {
+        // Server runs 1 s ahead, 2 ms each way, 1 ms processing
+        let estimate = ClockEstimate::from_exchange(
+            10_000_000,
+            1_012_000_000,
+            1_013_000_000,
+            15_000_000,
+        );
+        assert_eq!(estimate.offset_ns, 1_000_000_000);
+        assert_eq!(estimate.rtt_ns, 4_000_000);
+        assert_eq!(estimate.to_local_ns(1_020_000_000), 20_000_000);
+        assert_eq!(estimate.to_server_ns(20_000_000), 1_020_000_000);
+
+        let now = now_ns() as i64;
+        assert_eq!(instant_at(now + 5_000_000) - instant_at(now), Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_min_rtt_sample_wins() {
+        let mut sync = ClockSync::new();
+        sync.add(ClockEstimate { offset_ns: 500, rtt_ns: 9_000 });
+        sync.add(ClockEstimate { offset_ns: 100, rtt_ns: 1_000 });
+        let best = sync.add(ClockEstimate { offset_ns: 900, rtt_ns: 20_000 });
+        assert_eq!(best.offset_ns, 100);
+
+        // The good sample eventually ages out of the window
+        for _ in 0..SAMPLE_WINDOW {
+            sync.add(ClockEstimate { offset_ns: 300, rtt_ns: 5_000 });
+        }
+        assert_eq!(sync.estimate().unwrap().offset_ns, 300);
+    }
+}
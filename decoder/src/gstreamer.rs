@@ -0,0 +1,276 @@
+// IP Display Decoder - GStreamer Backend
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Decoding through the system's GStreamer. Frames go into an
+//! `appsrc ! decodebin ! videoconvert ! appsink` pipeline, so H.264, H.265
+//! and MJPEG are handled by whatever decoders the distribution ships,
+//! including hardware ones (VA-API, V4L2) that decodebin ranks first.
+//!
+//! `GstDecoder::vaapi` (the `vaapi` feature) skips decodebin and builds
+//! `appsrc ! parser ! VA-API decoder ! videoconvert ! appsink`, so a
+//! codec the GPU can't decode is reported missing instead of quietly
+//! falling back to software.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::{AppSink, AppSrc};
+
+use crate::{Codec, Decoder, Picture};
+
+/// Longest `decode` waits for a picture when none is ready yet
+const DECODE_WAIT: gst::ClockTime = gst::ClockTime::from_mseconds(40);
+
+/// Decoded pictures the sink holds before dropping the oldest
+const MAX_QUEUED: u32 = 4;
+
+/// Elements the pipeline is built from, besides the decoders
+const PIPELINE_ELEMENTS: [&str; 4] = ["appsrc", "decodebin", "videoconvert", "appsink"];
+
+pub struct GstDecoder {
+    /// Codecs some installed decoder takes
+    supported: Vec<Codec>,
+    /// Only VA-API decoders, rather than whatever decodebin picks
+    vaapi: bool,
+    stream: Option<Stream>,
+}
+
+impl GstDecoder {
+    /// Initialise GStreamer and check the pipeline can be built
+    pub fn new() -> Result<Self> {
+        gst::init().context("Failed to initialise GStreamer")?;
+        for name in PIPELINE_ELEMENTS {
+            if gst::ElementFactory::find(name).is_none() {
+                return Err(anyhow::anyhow!(
+                    "GStreamer has no {} element; install its base plugins", name
+                ));
+            }
+        }
+
+        let decoders = gst::ElementFactory::factories_with_type(gst::ElementFactoryType::DECODER, gst::Rank::Marginal);
+        let supported = Codec::ALL
+            .into_iter()
+            .filter(|&codec| decoders.iter().any(|factory| factory.can_sink_any_caps(&caps(codec))))
+            .collect();
+        Ok(Self { supported, vaapi: false, stream: None })
+    }
+
+    /// Decode only on the GPU through VA-API, for the codecs it has a
+    /// decoder for
+    #[cfg(feature = "vaapi")]
+    pub fn vaapi() -> Result<Self> {
+        let decoder = Self::new()?;
+        let supported: Vec<Codec> = Codec::ALL.into_iter().filter(|&codec| vaapi_elements(codec).is_some()).collect();
+        if supported.is_empty() {
+            return Err(anyhow::anyhow!("GStreamer has no VA-API decoders; install its va or vaapi plugin"));
+        }
+        Ok(Self { supported, vaapi: true, ..decoder })
+    }
+}
+
+impl Decoder for GstDecoder {
+    fn name(&self) -> &'static str {
+        if self.vaapi { "vaapi" } else { "gstreamer" }
+    }
+
+    fn supports(&self, codec: Codec) -> bool {
+        self.supported.contains(&codec)
+    }
+
+    fn decode(&mut self, codec: Codec, data: &[u8], timestamp: u64) -> Result<Option<Picture>> {
+        if !self.supports(codec) {
+            return Err(anyhow::anyhow!("GStreamer has no {} decoder installed", codec));
+        }
+        if self.stream.as_ref().map(|stream| stream.codec) != Some(codec) {
+            self.stream = Some(Stream::open(codec, self.vaapi)?);
+        }
+
+        // A failed pipeline is rebuilt on the next frame
+        let decoded = self.stream.as_ref().unwrap().decode(data, timestamp);
+        if decoded.is_err() {
+            self.reset();
+        }
+        decoded
+    }
+
+    fn reset(&mut self) {
+        self.stream = None;
+    }
+}
+
+/// A running pipeline for one codec
+struct Stream {
+    codec: Codec,
+    pipeline: gst::Pipeline,
+    src: AppSrc,
+    sink: AppSink,
+}
+
+impl Stream {
+    fn open(codec: Codec, vaapi: bool) -> Result<Self> {
+        let src = AppSrc::builder()
+            .caps(&caps(codec))
+            .is_live(true)
+            .format(gst::Format::Time)
+            .build();
+        let convert = gst::ElementFactory::make("videoconvert").build()?;
+        let sink = AppSink::builder()
+            .caps(&gst::Caps::builder("video/x-raw").field("format", "RGBA").build())
+            .sync(false)
+            .max_buffers(MAX_QUEUED)
+            .drop(true)
+            .build();
+
+        let pipeline = gst::Pipeline::new();
+        pipeline.add_many([src.upcast_ref(), &convert, sink.upcast_ref()])?;
+        convert.link(&sink)?;
+        if vaapi {
+            let (parser, decoder) = vaapi_elements(codec)
+                .with_context(|| format!("No VA-API decoder for {}", codec))?;
+            let parser = gst::ElementFactory::make(parser).build()?;
+            let decoder = gst::ElementFactory::make(decoder).build()?;
+            pipeline.add_many([&parser, &decoder])?;
+            gst::Element::link_many([src.upcast_ref(), &parser, &decoder, &convert])?;
+        } else {
+            let decodebin = gst::ElementFactory::make("decodebin").build()?;
+            pipeline.add(&decodebin)?;
+            src.link(&decodebin)?;
+
+            // decodebin only has a source pad once it has picked a decoder
+            let convert_sink = convert.static_pad("sink").context("videoconvert has no sink pad")?;
+            decodebin.connect_pad_added(move |_, pad| {
+                if !convert_sink.is_linked() {
+                    let _ = pad.link(&convert_sink);
+                }
+            });
+        }
+
+        pipeline.set_state(gst::State::Playing)?;
+        Ok(Self { codec, pipeline, src, sink })
+    }
+
+    fn decode(&self, data: &[u8], timestamp: u64) -> Result<Option<Picture>> {
+        let mut buffer = gst::Buffer::from_slice(data.to_vec());
+        buffer.get_mut().unwrap().set_pts(gst::ClockTime::from_nseconds(timestamp));
+        self.src
+            .push_buffer(buffer)
+            .map_err(|e| anyhow::anyhow!("GStreamer refused the frame: {:?}", e))?;
+        self.check_bus()?;
+
+        let mut newest = None;
+        while let Some(sample) = self.sink.try_pull_sample(gst::ClockTime::ZERO) {
+            newest = Some(sample);
+        }
+        if newest.is_none() {
+            newest = self.sink.try_pull_sample(DECODE_WAIT);
+            self.check_bus()?;
+        }
+        newest.map(|sample| picture(&sample)).transpose()
+    }
+
+    /// The first error the pipeline has posted, if any
+    fn check_bus(&self) -> Result<()> {
+        let bus = self.pipeline.bus().context("Pipeline has no bus")?;
+        match bus.pop_filtered(&[gst::MessageType::Error]) {
+            Some(message) => match message.view() {
+                gst::MessageView::Error(error) => Err(anyhow::anyhow!("GStreamer: {}", error.error())),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// The installed parser and VA-API decoder for `codec`, preferring the `va`
+/// plugin to the older `vaapi` one. The decoders want the profile and
+/// size in their caps, which the parser fills in.
+fn vaapi_elements(codec: Codec) -> Option<(&'static str, &'static str)> {
+    let (parser, decoders) = match codec {
+        Codec::H264 => ("h264parse", ["vah264dec", "vaapih264dec"]),
+        Codec::H265 => ("h265parse", ["vah265dec", "vaapih265dec"]),
+        Codec::Mjpeg => ("jpegparse", ["vajpegdec", "vaapijpegdec"]),
+    };
+    gst::ElementFactory::find(parser)?;
+    let decoder = decoders.into_iter().find(|name| gst::ElementFactory::find(name).is_some())?;
+    Some((parser, decoder))
+}
+
+/// What appsrc announces for `codec`: whole frames, with H.264 and H.265
+/// as Annex B start codes
+fn caps(codec: Codec) -> gst::Caps {
+    let byte_stream = |name| {
+        gst::Caps::builder(name)
+            .field("stream-format", "byte-stream")
+            .field("alignment", "au")
+            .build()
+    };
+    match codec {
+        Codec::H264 => byte_stream("video/x-h264"),
+        Codec::H265 => byte_stream("video/x-h265"),
+        Codec::Mjpeg => gst::Caps::builder("image/jpeg").build(),
+    }
+}
+
+fn picture(sample: &gst::Sample) -> Result<Picture> {
+    let structure = sample
+        .caps()
+        .and_then(|caps| caps.structure(0))
+        .context("Decoded sample has no caps")?;
+    let width = structure.get::<i32>("width")? as u32;
+    let height = structure.get::<i32>("height")? as u32;
+    let buffer = sample.buffer().context("Decoded sample has no buffer")?;
+    let map = buffer.map_readable()?;
+
+    // RGBA rows are already 4-byte aligned, so videoconvert packs them
+    let size = width as usize * height as usize * 4;
+    if map.len() < size {
+        return Err(anyhow::anyhow!(
+            "Decoded {}x{} picture has {} bytes, expected {}",
+            width, height, map.len(), size
+        ));
+    }
+    Ok(Picture {
+        width,
+        height,
+        timestamp: buffer.pts().map_or(0, |pts| pts.nseconds()),
+        data: map[..size].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jpeg_encoder::{ColorType, Encoder};
+
+    #[test]
+    fn test_gstreamer_mjpeg() {
+        // Only where GStreamer and its base plugins are installed
+        let Ok(mut decoder) = GstDecoder::new() else { return };
+        if !decoder.supports(Codec::Mjpeg) {
+            return;
+        }
+
+        let (width, height) = (32u16, 16u16);
+        let pixels: Vec<u8> = (0..width as usize * height as usize).flat_map(|_| [200, 40, 40]).collect();
+        let mut jpeg = Vec::new();
+        Encoder::new(&mut jpeg, 95).encode(&pixels, width, height, ColorType::Rgb).unwrap();
+
+        // The picture may lag a frame behind, so feed a few
+        let mut decoded = None;
+        for timestamp in 1..=4 {
+            decoded = decoder.decode(Codec::Mjpeg, &jpeg, timestamp * 1_000_000).unwrap().or(decoded);
+        }
+        let picture = decoded.expect("no picture decoded");
+        assert_eq!((picture.width, picture.height), (32, 16));
+        assert_eq!(picture.data.len(), 32 * 16 * 4);
+        let pixel = &picture.data[..4];
+        assert!(pixel[0].abs_diff(200) < 8 && pixel[1].abs_diff(40) < 8 && pixel[3] == 255);
+    }
+}
@@ -0,0 +1,68 @@
+// IP Display Decoder - Compressed Frame Decoding
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Decoders for the compressed frame formats. Unlike the conversions in
+//! `ipdisp-codecs` these keep state from one frame to the next, so a
+//! decoder follows one stream at a time. Each backend sits behind its own
+//! feature, since each links a system library.
+
+use anyhow::Result;
+use std::fmt;
+
+#[cfg(feature = "gstreamer")]
+pub mod gstreamer;
+
+/// Compressed formats a decoder may take
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Codec {
+    /// Annex B byte stream, one access unit per frame
+    H264,
+    /// Annex B byte stream, one access unit per frame
+    H265,
+    /// One complete JPEG image per frame
+    Mjpeg,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 3] = [Codec::H264, Codec::H265, Codec::Mjpeg];
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::H264 => "H.264",
+            Codec::H265 => "H.265",
+            Codec::Mjpeg => "MJPEG",
+        })
+    }
+}
+
+/// A decoded frame, packed straight-alpha RGBA with rows `width * 4` bytes
+/// apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Picture {
+    pub width: u32,
+    pub height: u32,
+    /// Timestamp of the frame this was decoded from
+    pub timestamp: u64,
+    pub data: Vec<u8>,
+}
+
+/// A decoder for one stream at a time
+pub trait Decoder: Send {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+
+    /// Whether frames in `codec` can be decoded at all
+    fn supports(&self, codec: Codec) -> bool;
+
+    /// Feed one whole frame stamped with `timestamp`. Decoders may hold
+    /// frames back, so this returns the newest picture that is ready,
+    /// which need not be the frame just given, and drops any older ones.
+    /// Switching codec starts a new stream.
+    fn decode(&mut self, codec: Codec, data: &[u8], timestamp: u64) -> Result<Option<Picture>>;
+
+    /// Forget the current stream, e.g. after a reconnect
+    fn reset(&mut self);
+}
@@ -0,0 +1,68 @@
+// IP Display GPU - Frame Conversion Shaders
+// Copyright (c) 2024
+// Licensed under MIT
+
+// One triangle covers the output; each fragment entry point samples the
+// planes of one input layout and writes premultiplied colour. Scaling is
+// the sampler's linear filtering.
+
+struct VertexOut {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOut {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOut;
+    out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+@group(0) @binding(0) var planes: sampler;
+@group(0) @binding(1) var plane0: texture_2d<f32>;
+@group(0) @binding(2) var plane1: texture_2d<f32>;
+@group(0) @binding(3) var plane2: texture_2d<f32>;
+
+// BT.601 limited range with the coefficients the CPU converters use
+fn yuv_to_rgba(y: f32, u: f32, v: f32) -> vec4<f32> {
+    let c = 298.0 / 256.0 * (y - 16.0 / 255.0);
+    let d = u - 128.0 / 255.0;
+    let e = v - 128.0 / 255.0;
+    let rgb = vec3<f32>(
+        c + 409.0 / 256.0 * e,
+        c - 100.0 / 256.0 * d - 208.0 / 256.0 * e,
+        c + 516.0 / 256.0 * d,
+    );
+    return vec4<f32>(clamp(rgb, vec3<f32>(0.0), vec3<f32>(1.0)), 1.0);
+}
+
+@fragment
+fn fs_rgba(in: VertexOut) -> @location(0) vec4<f32> {
+    let colour = textureSample(plane0, planes, in.uv);
+    return vec4<f32>(colour.rgb * colour.a, colour.a);
+}
+
+@fragment
+fn fs_i420(in: VertexOut) -> @location(0) vec4<f32> {
+    return yuv_to_rgba(
+        textureSample(plane0, planes, in.uv).r,
+        textureSample(plane1, planes, in.uv).r,
+        textureSample(plane2, planes, in.uv).r,
+    );
+}
+
+@fragment
+fn fs_nv12(in: VertexOut) -> @location(0) vec4<f32> {
+    let uv = textureSample(plane1, planes, in.uv);
+    return yuv_to_rgba(textureSample(plane0, planes, in.uv).r, uv.r, uv.g);
+}
+
+// 16-bit little-endian samples uploaded as bytes: the high byte of each is
+// the second channel, which keeps the top eight bits like the CPU path
+@fragment
+fn fs_p010(in: VertexOut) -> @location(0) vec4<f32> {
+    let uv = textureSample(plane1, planes, in.uv);
+    return yuv_to_rgba(textureSample(plane0, planes, in.uv).g, uv.g, uv.a);
+}
@@ -0,0 +1,449 @@
+// IP Display GPU - Frame Upload and Conversion
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Frame conversion on the GPU through wgpu on Vulkan, or on OpenGL ES
+//! through EGL as well with the `gl` feature, for machines where converting on the CPU is too slow or GTK's own GL
+//! renderer misbehaves. Each frame is uploaded as textures, one per plane,
+//! converted and scaled by a fragment shader, and read back as the
+//! premultiplied BGRA that Cairo surfaces hold.
+
+use anyhow::{Context, Result};
+use ipdisp_codecs::{ChromaLayout, Yuv420};
+
+/// A frame as it arrived, in a format the GPU path converts
+#[derive(Debug, Clone, Copy)]
+pub enum Frame<'a> {
+    /// Straight-alpha RGBA with rows `stride` bytes apart
+    Rgba { data: &'a [u8], stride: usize },
+    /// BT.601 limited-range 4:2:0
+    Yuv420 { data: &'a [u8], layout: ChromaLayout },
+}
+
+/// Which fragment shader reads a frame, and the index of its pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Input {
+    Rgba,
+    I420,
+    Nv12,
+    P010,
+}
+
+impl Input {
+    const ALL: [Input; 4] = [Input::Rgba, Input::I420, Input::Nv12, Input::P010];
+
+    fn entry_point(self) -> &'static str {
+        match self {
+            Input::Rgba => "fs_rgba",
+            Input::I420 => "fs_i420",
+            Input::Nv12 => "fs_nv12",
+            Input::P010 => "fs_p010",
+        }
+    }
+}
+
+/// Where one plane sits in a frame and how it is uploaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Plane {
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    offset: usize,
+    bytes_per_row: usize,
+}
+
+impl Plane {
+    fn end(&self) -> usize {
+        self.offset + self.bytes_per_row * (self.height as usize - 1) + self.width as usize * bytes_per_texel(self.format)
+    }
+}
+
+fn bytes_per_texel(format: wgpu::TextureFormat) -> usize {
+    match format {
+        wgpu::TextureFormat::R8Unorm => 1,
+        wgpu::TextureFormat::Rg8Unorm => 2,
+        _ => 4,
+    }
+}
+
+/// The input shader and planes of a `width` x `height` frame
+fn planes(frame: &Frame, width: u32, height: u32) -> Result<(Input, Vec<Plane>)> {
+    use wgpu::TextureFormat::{R8Unorm, Rg8Unorm, Rgba8Unorm};
+
+    let (w, h) = (width as usize, height as usize);
+    let (cw, ch) = (width.div_ceil(2), height.div_ceil(2));
+    let chroma = |format, offset, bytes_per_row| Plane { format, width: cw, height: ch, offset, bytes_per_row };
+    let luma = |format, bytes_per_row| Plane { format, width, height, offset: 0, bytes_per_row };
+
+    let (input, planes, len) = match *frame {
+        Frame::Rgba { data, stride } => (Input::Rgba, vec![luma(Rgba8Unorm, stride)], data.len()),
+        Frame::Yuv420 { data, layout } => {
+            if Yuv420::new(data, w, h, layout).is_none() {
+                return Err(anyhow::anyhow!("{:?} frame of {} bytes is not {}x{}", layout, data.len(), width, height));
+            }
+            let planes = match layout {
+                ChromaLayout::Planar => (Input::I420, vec![
+                    luma(R8Unorm, w),
+                    chroma(R8Unorm, w * h, cw as usize),
+                    chroma(R8Unorm, w * h + (cw * ch) as usize, cw as usize),
+                ]),
+                ChromaLayout::Interleaved => (Input::Nv12, vec![
+                    luma(R8Unorm, w),
+                    chroma(Rg8Unorm, w * h, cw as usize * 2),
+                ]),
+                ChromaLayout::Interleaved16 => (Input::P010, vec![
+                    luma(Rg8Unorm, w * 2),
+                    chroma(Rgba8Unorm, w * h * 2, cw as usize * 4),
+                ]),
+            };
+            (planes.0, planes.1, data.len())
+        }
+    };
+
+    if let Some(plane) = planes.iter().find(|plane| plane.end() > len) {
+        return Err(anyhow::anyhow!("Frame of {} bytes is too short for a {}x{} plane", len, plane.width, plane.height));
+    }
+    Ok((input, planes))
+}
+
+/// Input textures, kept while frames keep the same size and layout
+struct Uploads {
+    input: Input,
+    planes: Vec<Plane>,
+    textures: Vec<wgpu::Texture>,
+    bind_group: wgpu::BindGroup,
+}
+
+/// The render target and the buffer it is read back through
+struct Output {
+    width: u32,
+    height: u32,
+    texture: wgpu::Texture,
+    readback: wgpu::Buffer,
+    /// Row pitch of `readback`, padded as copies require
+    padded_row: u32,
+}
+
+/// A GPU device set up to convert frames
+pub struct GpuConverter {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    adapter: wgpu::AdapterInfo,
+    layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    pipelines: Vec<wgpu::RenderPipeline>,
+    /// Bound in place of the planes a layout doesn't have
+    unused_plane: wgpu::Texture,
+    uploads: Option<Uploads>,
+    output: Option<Output>,
+}
+
+impl GpuConverter {
+    /// Open the first suitable GPU; fails where there is none
+    pub fn new() -> Result<Self> {
+        let backends = if cfg!(feature = "gl") {
+            wgpu::Backends::VULKAN | wgpu::Backends::GL
+        } else {
+            wgpu::Backends::VULKAN
+        };
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor { backends, ..Default::default() });
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+            .context("No GPU adapter found")?;
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("ipdisp"),
+                required_features: wgpu::Features::empty(),
+                // Frames can be larger than the default 8192 pixel limit
+                required_limits: wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits()),
+            },
+            None,
+        ))?;
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("convert"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("convert.wgsl").into()),
+        });
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("planes"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                texture_entry(1),
+                texture_entry(2),
+                texture_entry(3),
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("convert"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipelines = Input::ALL
+            .iter()
+            .map(|input| {
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some(input.entry_point()),
+                    layout: Some(&pipeline_layout),
+                    vertex: wgpu::VertexState { module: &shader, entry_point: "vs_main", buffers: &[] },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &shader,
+                        entry_point: input.entry_point(),
+                        targets: &[Some(wgpu::TextureFormat::Bgra8Unorm.into())],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+            })
+            .collect();
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("planes"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let unused_plane = plane_texture(&device, wgpu::TextureFormat::R8Unorm, 1, 1);
+
+        Ok(Self {
+            adapter: adapter.get_info(),
+            device,
+            queue,
+            layout,
+            sampler,
+            pipelines,
+            unused_plane,
+            uploads: None,
+            output: None,
+        })
+    }
+
+    /// The GPU and API in use, for logs
+    pub fn adapter_name(&self) -> String {
+        format!("{} ({:?})", self.adapter.name, self.adapter.backend)
+    }
+
+    /// Convert a `width` x `height` frame, scaled to `out_width` x
+    /// `out_height`, into premultiplied BGRA in `dst` with rows
+    /// `out_width * 4` bytes apart
+    pub fn convert(
+        &mut self,
+        frame: Frame,
+        (width, height): (u32, u32),
+        (out_width, out_height): (u32, u32),
+        dst: &mut [u8],
+    ) -> Result<()> {
+        if width == 0 || height == 0 || out_width == 0 || out_height == 0 {
+            return Err(anyhow::anyhow!("Can't convert an empty frame"));
+        }
+        let row = out_width as usize * 4;
+        if dst.len() < row * out_height as usize {
+            return Err(anyhow::anyhow!("Output buffer too small for {}x{}", out_width, out_height));
+        }
+
+        let (input, planes) = planes(&frame, width, height)?;
+        self.upload(input, planes, frame)?;
+        self.prepare_output(out_width, out_height);
+        let uploads = self.uploads.as_ref().unwrap();
+        let output = self.output.as_ref().unwrap();
+
+        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("convert") });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("convert"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations { load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT), store: wgpu::StoreOp::Store },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            pass.set_pipeline(&self.pipelines[input as usize]);
+            pass.set_bind_group(0, &uploads.bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        encoder.copy_texture_to_buffer(
+            output.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &output.readback,
+                layout: wgpu::ImageDataLayout { offset: 0, bytes_per_row: Some(output.padded_row), rows_per_image: None },
+            },
+            extent(out_width, out_height),
+        );
+        self.queue.submit([encoder.finish()]);
+
+        // Wait for the copy, then strip the row padding
+        let slice = output.readback.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver.recv().context("GPU readback was abandoned")??;
+        {
+            let mapped = slice.get_mapped_range();
+            for (src, dst) in mapped.chunks(output.padded_row as usize).zip(dst.chunks_mut(row)) {
+                dst.copy_from_slice(&src[..row]);
+            }
+        }
+        output.readback.unmap();
+        Ok(())
+    }
+
+    /// Write each plane into its texture, making new ones when the frame's
+    /// size or layout changed
+    fn upload(&mut self, input: Input, planes: Vec<Plane>, frame: Frame) -> Result<()> {
+        let reuse = self.uploads.as_ref().is_some_and(|uploads| uploads.input == input && uploads.planes == planes);
+        if !reuse {
+            let textures: Vec<_> = planes
+                .iter()
+                .map(|plane| plane_texture(&self.device, plane.format, plane.width, plane.height))
+                .collect();
+            let views: Vec<_> = (0..3)
+                .map(|i| textures.get(i).unwrap_or(&self.unused_plane).create_view(&wgpu::TextureViewDescriptor::default()))
+                .collect();
+            let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("planes"),
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::Sampler(&self.sampler) },
+                    wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::TextureView(&views[0]) },
+                    wgpu::BindGroupEntry { binding: 2, resource: wgpu::BindingResource::TextureView(&views[1]) },
+                    wgpu::BindGroupEntry { binding: 3, resource: wgpu::BindingResource::TextureView(&views[2]) },
+                ],
+            });
+            self.uploads = Some(Uploads { input, planes, textures, bind_group });
+        }
+
+        let data = match frame {
+            Frame::Rgba { data, .. } | Frame::Yuv420 { data, .. } => data,
+        };
+        let uploads = self.uploads.as_ref().unwrap();
+        for (plane, texture) in uploads.planes.iter().zip(&uploads.textures) {
+            self.queue.write_texture(
+                texture.as_image_copy(),
+                &data[plane.offset..plane.end()],
+                wgpu::ImageDataLayout { offset: 0, bytes_per_row: Some(plane.bytes_per_row as u32), rows_per_image: None },
+                extent(plane.width, plane.height),
+            );
+        }
+        Ok(())
+    }
+
+    fn prepare_output(&mut self, width: u32, height: u32) {
+        if self.output.as_ref().is_some_and(|output| (output.width, output.height) == (width, height)) {
+            return;
+        }
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("output"),
+            size: extent(width, height),
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Bgra8Unorm,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let padded_row = (width * 4).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let readback = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: padded_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        self.output = Some(Output { width, height, texture, readback, padded_row });
+    }
+}
+
+fn plane_texture(device: &wgpu::Device, format: wgpu::TextureFormat, width: u32, height: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("plane"),
+        size: extent(width, height),
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+fn extent(width: u32, height: u32) -> wgpu::Extent3d {
+    wgpu::Extent3d { width, height, depth_or_array_layers: 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_planes() {
+        let nv12 = vec![0u8; Yuv420::size(5, 3, ChromaLayout::Interleaved)];
+        let (input, chroma) = planes(&Frame::Yuv420 { data: &nv12, layout: ChromaLayout::Interleaved }, 5, 3).unwrap();
+        assert_eq!(input, Input::Nv12);
+        assert_eq!((chroma[1].width, chroma[1].height, chroma[1].offset, chroma[1].bytes_per_row), (3, 2, 15, 6));
+        assert_eq!(chroma[1].end(), nv12.len());
+
+        let p010 = vec![0u8; Yuv420::size(4, 2, ChromaLayout::Interleaved16)];
+        let (_, wide) = planes(&Frame::Yuv420 { data: &p010, layout: ChromaLayout::Interleaved16 }, 4, 2).unwrap();
+        assert_eq!(wide.iter().map(Plane::end).collect::<Vec<_>>(), [16, 24]);
+
+        // Padded rows only need to reach the end of the last row's pixels
+        let rgba = vec![0u8; 64 + 8];
+        assert!(planes_ok(&rgba, 64, 2, 2));
+        assert!(!planes_ok(&rgba[..71], 64, 2, 2));
+        assert!(planes(&Frame::Yuv420 { data: &nv12[1..], layout: ChromaLayout::Interleaved }, 5, 3).is_err());
+    }
+
+    fn planes_ok(data: &[u8], stride: usize, width: u32, height: u32) -> bool {
+        planes(&Frame::Rgba { data, stride }, width, height).is_ok()
+    }
+
+    #[test]
+    fn test_gpu_convert() {
+        // Only where there is a GPU or a software driver such as llvmpipe
+        let Ok(mut gpu) = GpuConverter::new() else { return };
+
+        // Flat colours match the CPU converter to within rounding, in
+        // every layout
+        let (width, height) = (6usize, 4usize);
+        let chroma = width.div_ceil(2) * height.div_ceil(2);
+        let layouts = [
+            (ChromaLayout::Planar, [vec![150; width * height], vec![90; chroma], vec![200; chroma]].concat()),
+            (ChromaLayout::Interleaved, [vec![150; width * height], [90, 200].repeat(chroma)].concat()),
+            (ChromaLayout::Interleaved16, [[0, 150].repeat(width * height), [0, 90, 0, 200].repeat(chroma)].concat()),
+        ];
+        for (layout, data) in layouts {
+            let mut expected = vec![0u8; width * 4];
+            Yuv420::new(&data, width, height, layout).unwrap().row_to_bgra(0, &mut expected);
+            let mut out = vec![0u8; width * height * 4];
+            gpu.convert(Frame::Yuv420 { data: &data, layout }, (6, 4), (6, 4), &mut out).unwrap();
+            assert!(out.iter().zip(expected.iter().cycle()).all(|(a, b)| a.abs_diff(*b) <= 2), "{:?}", layout);
+        }
+
+        // RGBA comes back premultiplied BGRA, at the size asked for
+        let rgba: Vec<u8> = std::iter::repeat_n([200, 100, 50, 128], 16).flatten().collect();
+        let mut out = vec![0u8; 2 * 2 * 4];
+        gpu.convert(Frame::Rgba { data: &rgba, stride: 16 }, (4, 4), (2, 2), &mut out).unwrap();
+        let premultiplied = [25u8, 50, 100, 128];
+        assert!(out.chunks(4).all(|pixel| pixel.iter().zip(&premultiplied).all(|(a, b)| a.abs_diff(*b) <= 1)));
+    }
+}
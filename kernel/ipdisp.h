@@ -7,6 +7,7 @@
 #define IPDISP_H
 
 #include <linux/module.h>
+#include <linux/version.h>
 #include <linux/kernel.h>
 #include <linux/init.h>
 #include <linux/platform_device.h>
@@ -22,7 +23,23 @@
 #include <linux/net.h>
 #include <linux/socket.h>
 #include <linux/in.h>
+#include <linux/random.h>
+#include <linux/crc32.h>
+#include <linux/input.h>
+#include <linux/input/mt.h>
+#include <linux/fs.h>
+#include <linux/namei.h>
+#include <linux/hrtimer.h>
+#include <linux/kfifo.h>
+#include <linux/ctype.h>
+#include <sound/core.h>
+#include <sound/pcm.h>
 #include <net/sock.h>
+#include <crypto/algapi.h>
+#include <crypto/hash.h>
+#include <crypto/chacha20poly1305.h>
+#include <crypto/curve25519.h>
+#include <crypto/sha2.h>
 
 #include <drm/drm_device.h>
 #include <drm/drm_drv.h>
@@ -40,6 +57,26 @@
 #include <drm/drm_encoder.h>
 #include <drm/drm_crtc.h>
 #include <drm/drm_mode_config.h>
+#include <drm/drm_damage_helper.h>
+#include <drm/drm_rect.h>
+
+/* Console emulation: the generic helper became the DMA one, with damage
+ * handling, in 6.11, and in 6.13 the DMA one became a probe hook in the
+ * driver for the common client setup */
+#if LINUX_VERSION_CODE >= KERNEL_VERSION(6, 13, 0)
+#include <drm/drm_client_setup.h>
+#include <drm/drm_fbdev_dma.h>
+#define IPDISP_FBDEV_DRIVER_OPS DRM_FBDEV_DMA_DRIVER_OPS,
+#define ipdisp_fbdev_setup(drm) drm_client_setup_with_color_mode(drm, 32)
+#elif LINUX_VERSION_CODE >= KERNEL_VERSION(6, 11, 0)
+#include <drm/drm_fbdev_dma.h>
+#define IPDISP_FBDEV_DRIVER_OPS
+#define ipdisp_fbdev_setup(drm) drm_fbdev_dma_setup(drm, 32)
+#else
+#include <drm/drm_fbdev_generic.h>
+#define IPDISP_FBDEV_DRIVER_OPS
+#define ipdisp_fbdev_setup(drm) drm_fbdev_generic_setup(drm, 32)
+#endif
 
 /* Module information */
 #define DRIVER_NAME "ipdisp"
@@ -59,7 +96,158 @@
 /* Network protocol */
 #define IPDISP_MAGIC 0x49504453  /* "IPDS" */
 #define IPDISP_VERSION 1
-#define IPDISP_HEADER_SIZE 32
+#define IPDISP_HEADER_SIZE 36
+#define IPDISP_MAX_REQUEST_SIZE 256 /* Largest client request payload */
+#define IPDISP_HEARTBEAT_INTERVAL_MS 1000 /* Send a heartbeat after this much silence */
+#define IPDISP_DEFAULT_HEARTBEAT_TIMEOUT_MS 5000
+#define IPDISP_PACKET_TYPE_MASK 0xffff     /* Flags live in the upper half */
+#define IPDISP_PACKET_FLAG_CRC32 (1u << 31) /* __be32 CRC-32 follows header */
+
+/* Client capabilities, from the fourth HELLO word */
+#define IPDISP_CAP_CRC32 (1u << 0)         /* Wants a CRC-32 on each frame */
+#define IPDISP_CAP_COMPACT_HEADER (1u << 1) /* Takes compact frame headers */
+#define IPDISP_CAP_CONTENT_HASH (1u << 2)  /* Wants signed content hashes */
+#define IPDISP_CAP_SYNC (1u << 3)          /* Presents frames at deadlines */
+#define IPDISP_CAP_FORMAT_ANNOUNCE (1u << 4) /* Wants FORMAT before a change */
+#define IPDISP_CAP_DAMAGE (1u << 5)        /* Takes DAMAGE for RGBA32 frames */
+#define IPDISP_CAP_AUDIO (1u << 6)         /* Plays AUDIO */
+#define IPDISP_CAP_ACK (1u << 7)           /* Sends ACK for frames it shows */
+#define IPDISP_CAP_NOISE (1u << 8)         /* Encrypts after a Noise handshake */
+#define IPDISP_CAP_INPUT_CONTROL (1u << 9) /* Follows who holds input control */
+#define IPDISP_CAP_RESUME (1u << 10)       /* Resumes its session on reconnect */
+#define IPDISP_CAP_AUTH_PROMPT (1u << 11)  /* Answers AUTH_PROMPT; not ours */
+#define IPDISP_CAPS_ALL 0xfff               /* Every IPDISP_CAP_* above */
+
+/* Compact frame header: u8 marker | flags, u24 timestamp step in us since
+ * the connection's previous frame, __be32 size. Only for frames matching
+ * the previous one's geometry; every IPDISP_KEYFRAME_INTERVAL-th frame gets
+ * a full header anyway. */
+#define IPDISP_COMPACT_HEADER_SIZE 8
+#define IPDISP_COMPACT_MARKER 0xc0
+#define IPDISP_COMPACT_FLAG_CRC32 0x01     /* __be32 CRC-32 follows */
+#define IPDISP_COMPACT_MAX_DELTA_US 0xffffff
+#define IPDISP_KEYFRAME_INTERVAL 60
+
+/* Pairing */
+#define IPDISP_PAIR_KEY_SIZE CURVE25519_KEY_SIZE
+#define IPDISP_PAIR_NONCE_SIZE 16
+#define IPDISP_PAIR_MAC_SIZE SHA256_DIGEST_SIZE
+#define IPDISP_PAIR_TOKEN_ID_SIZE 8
+#define IPDISP_PAIR_TIMEOUT_MS 120000 /* How long a pairing code is valid */
+#define IPDISP_MAX_PAIRED 16          /* Tokens kept; the oldest is replaced */
+#define IPDISP_MAX_AUTH_PROVIDERS 4
+
+/* Noise: a client that agrees to IPDISP_CAP_NOISE sends NOISE with its
+ * ephemeral key, we answer with ours and our static key, and it finishes
+ * with its static key. After that everything goes in records both ways: a
+ * __be16 length, then that much ciphertext ending in a tag. A client's
+ * record carries exactly one request. */
+#define IPDISP_NOISE_PATTERN "Noise_XX_25519_ChaChaPoly_SHA256"
+#define IPDISP_NOISE_KEY_SIZE CURVE25519_KEY_SIZE
+#define IPDISP_NOISE_START_SIZE 32
+#define IPDISP_NOISE_REPLY_SIZE 96
+#define IPDISP_NOISE_FINISH_SIZE 64
+#define IPDISP_NOISE_MAX_RECORD 65535
+#define IPDISP_NOISE_TAG_SIZE CHACHA20POLY1305_AUTHTAG_SIZE
+
+/* Session resume: a client that agrees to IPDISP_CAP_RESUME is sent a
+ * token once any handshake is done and it has proved a credential. A
+ * connection lost rather than closed is kept parked for resume_timeout
+ * seconds, and the same credential sending RESUME with its token in that
+ * time picks the session up where it was. */
+#define IPDISP_RESUME_TOKEN_SIZE 16
+#define IPDISP_DEFAULT_RESUME_TIMEOUT_S 30
+
+/* Content hashes: a client that asks gets the SHA-256 of a frame ahead of
+ * it about once per interval, signed with our Ed25519 key */
+#define IPDISP_CONTENT_HASH_INTERVAL_MS 1000
+#define IPDISP_SIGN_KEY_SIZE 32
+#define IPDISP_SIGNATURE_SIZE 64
+#define IPDISP_CONTENT_HASH_SIZE (sizeof(__be64) + SHA256_DIGEST_SIZE + \
+                                  IPDISP_SIGNATURE_SIZE)
+
+/* Quality requests: frames can be sent at 1/2 or 1/4 scale, and as RGB565
+ * or NV12 instead of RGBA32 */
+#define IPDISP_MAX_SCALE_SHIFT 2
+#define IPDISP_PACKED_FORMATS 2
+
+/* Rate control: a client whose socket still holds a frame's worth when the
+ * next is due skips frames and backs off a level (lower frame rate, then
+ * smaller frames) each interval that happens; it recovers a level after
+ * IPDISP_RATE_RECOVER_INTERVALS clean ones */
+#define IPDISP_MAX_BACKOFF 4
+#define IPDISP_RATE_INTERVAL_MS 1000
+#define IPDISP_RATE_RECOVER_INTERVALS 5
+
+/* Frame acknowledgements: a client that sends them gets no more than
+ * ack_window frames ahead of the last it acknowledged. Frames still
+ * unacknowledged IPDISP_ACK_TIMEOUT_MS after the last one went out are
+ * given up on, e.g. ones its window dropped while hidden. */
+#define IPDISP_MAX_ACK_WINDOW 8
+#define IPDISP_DEFAULT_ACK_WINDOW 2
+#define IPDISP_ACK_TIMEOUT_MS 1000
+
+/* IDENTIFY: the output's edge flashes, inverted every blink, for as long
+ * as a client asks, up to a limit */
+#define IPDISP_IDENTIFY_BLINK_MS 250
+#define IPDISP_IDENTIFY_MAX_MS 10000
+
+/* File uploads */
+#define IPDISP_FILE_CHUNK (32 * 1024)  /* Largest FILE_DATA chunk */
+#define IPDISP_FILE_DATA_MAX (sizeof(__be32) + IPDISP_FILE_CHUNK)
+#define IPDISP_FILE_NAME_MAX 200
+#define IPDISP_DEFAULT_UPLOAD_MAX_MB 100
+
+/* Virtual touchscreen */
+#define IPDISP_TOUCH_MAX_SLOTS 10
+#define IPDISP_TOUCH_MAX_PRESSURE 255
+
+/* Sound card: 16-bit stereo PCM at 48 kHz, sent in chunks of up to
+ * IPDISP_AUDIO_CHUNK bytes */
+#define IPDISP_AUDIO_RATE 48000
+#define IPDISP_AUDIO_CHANNELS 2
+#define IPDISP_AUDIO_FRAME_SIZE (IPDISP_AUDIO_CHANNELS * 2)
+#define IPDISP_AUDIO_CHUNK (16 * 1024)
+#define IPDISP_AUDIO_FIFO_SIZE (64 * 1024) /* Power of two for kfifo */
+
+/* Encoding of AUDIO samples */
+enum ipdisp_audio_codec {
+    IPDISP_AUDIO_PCM = 0,        /* Interleaved S16LE */
+    IPDISP_AUDIO_OPUS,           /* Reserved; no encoder in the kernel */
+};
+
+/* Who may drive the virtual input devices, besides being authenticated */
+enum ipdisp_input_policy {
+    IPDISP_INPUT_ON = 0,         /* Every authenticated client */
+    IPDISP_INPUT_CONFIRM,        /* Clients written to allow_input */
+    IPDISP_INPUT_OFF,            /* Nobody, clients only view */
+};
+
+/* What a client may do besides watching, as it asks in HELLO or
+ * SESSION_MODE and no more than the session_mode parameter allows */
+enum ipdisp_session_mode {
+    IPDISP_SESSION_VIEW = 0,     /* Frames and sound only */
+    IPDISP_SESSION_INPUT,        /* Also input devices and mode requests */
+    IPDISP_SESSION_FULL,         /* Also uploads and supervision */
+};
+
+/* Whether clients share the input devices or one at a time holds control */
+enum ipdisp_control_policy {
+    IPDISP_CONTROL_POLICY_SHARED = 0,  /* Every client drives at once */
+    IPDISP_CONTROL_POLICY_REQUEST,     /* Control passes on once released */
+    IPDISP_CONTROL_POLICY_STEAL,       /* Any client may take control */
+};
+
+/* What an INPUT_CONTROL request asks for */
+enum ipdisp_control_action {
+    IPDISP_CONTROL_REQUEST = 0,  /* Take control if nobody holds it */
+    IPDISP_CONTROL_RELEASE,      /* Give control up */
+    IPDISP_CONTROL_STEAL,        /* Take control from its holder */
+};
+
+/* Bits of struct ipdisp_input_control flags */
+#define IPDISP_CONTROL_YOURS (1u << 0)  /* The client reading it holds control */
+#define IPDISP_CONTROL_SHARED (1u << 1) /* Nobody needs control */
 
 /* Frame formats */
 enum ipdisp_format {
@@ -67,9 +255,146 @@ enum ipdisp_format {
     IPDISP_FORMAT_RGB24,
     IPDISP_FORMAT_H264,
     IPDISP_FORMAT_H265,
+    IPDISP_FORMAT_YUV420P,       /* I420: Y plane, then U and V at half size */
+    IPDISP_FORMAT_NV12,          /* Y plane, then interleaved UV at half size */
+    IPDISP_FORMAT_RGB565,        /* 16-bit LE 5:6:5, blue in the low bits */
+    IPDISP_FORMAT_RGBA1010102,   /* 32-bit LE 10:10:10:2, red low, alpha top */
+    IPDISP_FORMAT_P010,          /* NV12 with 10-bit samples in 16-bit LE words */
+    IPDISP_FORMAT_JPEG,          /* one JPEG image per frame (MJPEG) */
+};
+
+/* Packet types (carried in the header word v1 left reserved) */
+enum ipdisp_packet_type {
+    IPDISP_PACKET_DISPLAY = 0,   /* Display info (size == 0) or frame data */
+    IPDISP_PACKET_IDENTIFY,      /* Client: u32 duration_ms to flash the
+                                  * output; server: u32 DRM card index */
+    IPDISP_PACKET_MODE_REQUEST,  /* Client: switch virtual display mode
+                                  * (u32 width, u32 height,
+                                  * u32 refresh_mhz) */
+    IPDISP_PACKET_HELLO,         /* Client: handshake (u32 refresh_mhz,
+                                  * u32 session_id, u32 link_mode,
+                                  * u32 capabilities) */
+    IPDISP_PACKET_PING,          /* Client: clock probe (u64 client_ns) */
+    IPDISP_PACKET_PONG,          /* Server: client_ns, rx_ns, tx_ns (u64) */
+    IPDISP_PACKET_HEARTBEAT,     /* Either side: keepalive, no payload */
+    IPDISP_PACKET_GOODBYE,       /* Either: closing, no payload */
+    IPDISP_PACKET_PAIR_COMMIT,   /* Client: SHA-256 of its key and nonce */
+    IPDISP_PACKET_PAIR_KEY,      /* Server: public key, nonce */
+    IPDISP_PACKET_PAIR_REVEAL,   /* Client: public key, nonce */
+    IPDISP_PACKET_PAIR_CONFIRM,  /* Either side: HMAC proving the key;
+                                  * server adds its content key */
+    IPDISP_PACKET_AUTH_CHALLENGE, /* Server: nonce for paired clients */
+    IPDISP_PACKET_AUTH,          /* Client: token id, HMAC of the nonce */
+    IPDISP_PACKET_RESEND,        /* Client: last frame was corrupted */
+    IPDISP_PACKET_TOUCH_DEVICE,  /* Client: u32 slots; server: u32 width,
+                                  * u32 height, u32 slots once registered */
+    IPDISP_PACKET_QUALITY,       /* Client: u32 max_kbps, u32 scale,
+                                  * u32 max_fps (0 = no limit), then
+                                  * optionally u32 format */
+    IPDISP_PACKET_CONTENT_HASH,  /* Server: u64 timestamp of the next frame,
+                                  * SHA-256 of its payload, Ed25519
+                                  * signature of both */
+    IPDISP_PACKET_SYNC,          /* Server: u64 delay_ns; show each frame
+                                  * at its timestamp plus this */
+    IPDISP_PACKET_SUPERVISE,     /* Client: u32 action, u32 arg; server:
+                                  * struct ipdisp_supervise_result */
+    IPDISP_PACKET_FORMAT,        /* Server: struct ipdisp_format_announce
+                                  * ahead of frames in another format */
+    IPDISP_PACKET_CROP,          /* Client: u32 x, u32 y, u32 width,
+                                  * u32 height of the display to send;
+                                  * width 0 for all of it */
+    IPDISP_PACKET_PAUSE,         /* Client: u32 paused; no frames while
+                                  * non-zero, a fresh one on resume */
+    IPDISP_PACKET_TOUCH,         /* Client: u32 count, then count times
+                                  * u32 slot, x, y, pressure (0 = lifted) */
+    IPDISP_PACKET_POINTER,       /* Client: s32 dx, dy, wheel, u32 buttons
+                                  * (bit 0 left, 1 right, 2 middle) */
+    IPDISP_PACKET_FILE_BEGIN,    /* Client: u64 size, u32 id, u32 name
+                                  * length, name */
+    IPDISP_PACKET_FILE_DATA,     /* Client: u32 id, then the next bytes of
+                                  * the file; none to give up */
+    IPDISP_PACKET_FILE_STATUS,   /* Server: u64 received, u32 id, s32
+                                  * status (0 going, 1 done, -errno) */
+    IPDISP_PACKET_DAMAGE,        /* Server: struct ipdisp_damage, then that
+                                  * rectangle of the frame as RGBA32 */
+    IPDISP_PACKET_KEY,           /* Client: u32 evdev key code, u32 pressed */
+    IPDISP_PACKET_AUDIO,         /* Server: struct ipdisp_audio_header,
+                                  * then the samples */
+    IPDISP_PACKET_ACK,           /* Client: u64 timestamp of the last frame
+                                  * shown, u64 received_ns, presented_ns on
+                                  * the server's clock (0 = unknown) */
+    IPDISP_PACKET_CAPABILITIES,  /* Server: u32 capabilities from the
+                                  * client's HELLO that it will use */
+    IPDISP_PACKET_NOISE,         /* Both: a Noise handshake message */
+    IPDISP_PACKET_SESSION_MODE,  /* Client: u32 mode asked for; server:
+                                  * u32 mode in effect */
+    IPDISP_PACKET_INPUT_CONTROL, /* Client: u32 enum ipdisp_control_action;
+                                  * server: struct ipdisp_input_control */
+    IPDISP_PACKET_RESUME,        /* Client: token of a session to resume;
+                                  * server: this session's token, then u32
+                                  * resumed */
+    IPDISP_PACKET_AUTH_PROMPT,   /* ipdisp-server: u32 style, then text; the
+                                  * module never sends it */
+    IPDISP_PACKET_AUTH_REPLY,    /* Client: the answer to an AUTH_PROMPT */
+};
+
+/* What a SUPERVISE request asks of the server */
+enum ipdisp_supervise_action {
+    IPDISP_SUPERVISE_RESEND_FRAME = 0,    /* Fresh full frame to everyone */
+    IPDISP_SUPERVISE_SET_SOURCE,          /* Stream capture source arg */
+    IPDISP_SUPERVISE_ROTATE_LOGS,         /* Start new log files */
+    IPDISP_SUPERVISE_RESTART_CAPTURE,     /* Reopen the capture backend */
+};
+
+#define IPDISP_SUPERVISE_MESSAGE_SIZE 64
+
+/* Reply to SUPERVISE */
+struct ipdisp_supervise_result {
+    __be32 action;
+    __be32 status;   /* 0 or -errno */
+    char message[IPDISP_SUPERVISE_MESSAGE_SIZE]; /* NUL-padded */
+} __packed;
+
+/* Format and size of the frames that follow a FORMAT packet */
+struct ipdisp_format_announce {
+    __be32 format;   /* enum ipdisp_format */
+    __be32 width;
+    __be32 height;
+} __packed;
+
+/* Part of the frame that changed since the client's last one; the header
+ * gives the whole frame's size */
+struct ipdisp_damage {
+    __be32 x;
+    __be32 y;
+    __be32 width;
+    __be32 height;
+} __packed;
+
+/* Ahead of the samples of an AUDIO packet */
+struct ipdisp_audio_header {
+    __be32 codec;    /* enum ipdisp_audio_codec */
+    __be32 rate;     /* Hz */
+    __be32 channels;
+} __packed;
+
+/* Who holds input control, sent to every client when that changes */
+struct ipdisp_input_control {
+    __be32 owner;    /* Client id, 0 for nobody */
+    u8 addr[4];      /* Its IPv4 address */
+    __be32 flags;    /* IPDISP_CONTROL_YOURS, IPDISP_CONTROL_SHARED */
+    __be32 status;   /* 0, or -errno refusing this client's request */
+} __packed;
+
+/* How frames are spread over the links of an aggregated session */
+enum ipdisp_link_mode {
+    IPDISP_LINK_FAILOVER = 0,    /* First live link gets every frame */
+    IPDISP_LINK_STRIPE,          /* Frames alternate between live links */
 };
 
-/* Network packet header */
+/* Network packet header. Every field, and every integer in a payload, is
+ * big-endian on the wire; the client also reads headers whose magic comes
+ * out byte-swapped as little-endian, but we always send network order. */
 struct ipdisp_packet_header {
     u32 magic;      /* Magic number */
     u32 version;    /* Protocol version */
@@ -78,16 +403,205 @@ struct ipdisp_packet_header {
     u32 format;     /* Frame format */
     u64 timestamp;  /* Frame timestamp */
     u32 size;       /* Data size */
-    u32 reserved;   /* Reserved for future use */
+    u32 packet_type; /* Packet type (enum ipdisp_packet_type) */
 } __packed;
 
+/* Pairing in progress; only one client can pair at a time */
+struct ipdisp_pairing {
+    struct ipdisp_client *client;  /* NULL when idle */
+    u64 started_ns;
+    u8 commit[SHA256_DIGEST_SIZE];
+    u8 secret[IPDISP_PAIR_KEY_SIZE];
+    u8 server_key[IPDISP_PAIR_KEY_SIZE + IPDISP_PAIR_NONCE_SIZE];
+    u8 key[SHA256_DIGEST_SIZE];    /* Set once the client reveals */
+    u32 code;                      /* Shown to the user, 0-999999 */
+    bool revealed;
+    bool confirmed;                /* The client proved the shared key */
+    bool approved;                 /* The code was written to sysfs */
+};
+
+/* Long-term credential of a paired client, listed in sysfs paired and
+ * revoked through unpair */
+struct ipdisp_paired_token {
+    u8 id[IPDISP_PAIR_TOKEN_ID_SIZE];
+    u8 token[SHA256_DIGEST_SIZE];
+    struct in_addr addr;  /* Where the client paired from */
+    time64_t paired_at;
+    time64_t used_at;     /* Last proved, or when paired */
+    bool used;
+};
+
+struct ipdisp_device;
+struct ipdisp_client;
+
+/* A way of answering AUTH_CHALLENGE, enabled by naming it in the auth
+ * module parameter. AUTH carries a token id and an HMAC of the challenge;
+ * providers are asked in turn whether the id is one of theirs. */
+struct ipdisp_auth_provider {
+    const char *name;
+    int (*init)(struct ipdisp_device *idev);       /* Optional */
+    void (*cleanup)(struct ipdisp_device *idev);   /* Optional */
+    /* 0 to accept, -ENOENT if id isn't ours, another error to reject;
+     * caller holds clients_lock and client->lock */
+    int (*verify)(struct ipdisp_device *idev, struct ipdisp_client *client,
+                  const u8 *id, const u8 *mac);
+};
+
+struct ipdisp_upload;
+struct ipdisp_audio;
+
+/* Where a client is up to with its Noise handshake */
+enum ipdisp_noise_stage {
+    IPDISP_NOISE_STARTED = 0,    /* Agreed in HELLO, waiting for its first
+                                  * message */
+    IPDISP_NOISE_REPLIED,        /* Answered, waiting for its last */
+    IPDISP_NOISE_READY,          /* Encrypted both ways */
+};
+
+/* A client's Noise session (client->lock) */
+struct ipdisp_noise {
+    enum ipdisp_noise_stage stage;
+    
+    /* Handshake state, cleared once it is done */
+    u8 h[SHA256_DIGEST_SIZE];
+    u8 ck[SHA256_DIGEST_SIZE];
+    u8 k[CHACHA20POLY1305_KEY_SIZE];
+    u64 n;
+    u8 e[IPDISP_NOISE_KEY_SIZE];  /* Our ephemeral secret */
+    u8 re[IPDISP_NOISE_KEY_SIZE]; /* The client's ephemeral key */
+    
+    u8 rs[IPDISP_NOISE_KEY_SIZE]; /* The client's static key */
+    u8 send_key[CHACHA20POLY1305_KEY_SIZE];
+    u8 recv_key[CHACHA20POLY1305_KEY_SIZE];
+    u64 send_nonce;
+    u64 recv_nonce;
+    u8 *send_buf;        /* A record going out */
+    u8 *recv_buf;        /* A record coming in */
+};
+
 /* Client connection */
 struct ipdisp_client {
     struct socket *sock;
     struct sockaddr_in addr;
     struct list_head list;
     bool active;
+    u32 id;              /* Listed in sysfs clients, written to kick */
     struct mutex lock;
+    
+    /* Pacing: don't send faster than the client's display refreshes */
+    u32 refresh_mhz;     /* From the client's HELLO, 0 = unknown */
+    u64 last_frame_ns;
+    bool frame_pending;  /* A frame was held back by pacing */
+    
+    /* Link aggregation: connections sharing a non-zero session_id are
+     * paths to the same client */
+    u32 session_id;
+    u32 link_mode;       /* enum ipdisp_link_mode */
+    
+    u32 capabilities;    /* IPDISP_CAP_* agreed after the client's HELLO */
+    
+    /* Quality request: frames are downscaled by 1 << scale_shift, sent as
+     * format and paced to stay under both caps */
+    u32 max_kbps;
+    u32 max_fps;
+    u32 scale_shift;
+    u32 format;          /* enum ipdisp_format */
+    
+    /* Crop request: only this part of the display is sent, as RGBA32 at
+     * full scale, for one tile of a video wall; crop_buf is NULL for the
+     * whole display */
+    u32 crop_x;
+    u32 crop_y;
+    u32 crop_width;
+    u32 crop_height;
+    void *crop_buf;      /* crop_width * crop_height * 4 bytes */
+    
+    /* Nobody is looking, e.g. the window is minimized: only heartbeats */
+    bool paused;
+    
+    /* Last full or compact frame header sent, which compact headers are
+     * relative to */
+    bool compact_ready;
+    u32 compact_width;
+    u32 compact_height;
+    u64 compact_ts;      /* Timestamp as the client reconstructs it */
+    u32 compact_count;   /* Compact headers since the last full one */
+    u32 sent_format;     /* Format of the last frame sent (RGBA32 at first) */
+    
+    /* What changed since the last frame sent, which goes as DAMAGE once
+     * the client has a whole RGBA32 frame to apply it to */
+    struct drm_rect damage;
+    bool damage_ready;
+    
+    /* Rate control (clients_lock) */
+    u32 backoff;         /* Level, 0 for none */
+    bool backlogged;     /* Skipped a frame this interval */
+    u64 backoff_ns;      /* Start of the interval */
+    u32 clean_intervals;
+    
+    /* Frame acknowledgements (clients_lock): timestamps of the frames sent
+     * and not acknowledged yet, as the client sees them, oldest first, and
+     * the smoothed latencies the client reports, 0 until it does */
+    u64 unacked_ts[IPDISP_MAX_ACK_WINDOW];
+    u32 unacked;
+    u32 receive_latency_us;
+    u32 present_latency_us;
+    
+    /* Liveness: dropped after heartbeat_timeout_ms without hearing from it */
+    u64 last_rx_ns;
+    u64 last_tx_ns;
+    
+    /* Pairing: with require_pairing, frames only go to authenticated
+     * clients */
+    bool authenticated;
+    bool verified;       /* Proved a credential, rather than let in */
+    u8 auth_id[IPDISP_PAIR_TOKEN_ID_SIZE]; /* Token id it proved */
+    bool paired;         /* Proved the paired token paired_id */
+    u8 paired_id[IPDISP_PAIR_TOKEN_ID_SIZE];
+    u8 challenge[IPDISP_PAIR_NONCE_SIZE];
+    
+    /* When the last content hash went out */
+    u64 last_hash_ns;
+    
+    bool touch;          /* Asked for the virtual touchscreen */
+    bool pointer;        /* Drives the virtual mouse */
+    bool keyboard;       /* Drives the virtual keyboard */
+    /* What it holds down, let go when it leaves */
+    u32 touches_down;    /* Touchscreen slots */
+    u32 buttons_down;    /* Mouse buttons, as in POINTER */
+    DECLARE_BITMAP(keys_down, KEY_CNT);
+    bool input_allowed;  /* Written to allow_input */
+    bool input_asked;    /* Sent input while waiting for allow_input */
+    u32 session_mode;    /* enum ipdisp_session_mode */
+    bool control_changed; /* Not told of the new input control holder yet */
+    struct ipdisp_upload *upload; /* File being received (client->lock) */
+    struct ipdisp_noise *noise;   /* Once it agrees to encrypt */
+    
+    /* Session resume, once the client has a token; a GOODBYE or kick
+     * clears resumable, so only lost connections are parked */
+    bool resumable;
+    bool resume_pending; /* Not sent its token yet */
+    bool resume_requested; /* Sent RESUME, waiting to authenticate */
+    bool parked;         /* Kept for resuming as it is removed */
+    u8 resume_token[IPDISP_RESUME_TOKEN_SIZE];
+    u8 resume_request[IPDISP_RESUME_TOKEN_SIZE];
+};
+
+/* What is kept of a lost client's session until it resumes or expires
+ * (clients_lock) */
+struct ipdisp_parked_session {
+    u8 token[IPDISP_RESUME_TOKEN_SIZE];
+    u8 auth_id[IPDISP_PAIR_TOKEN_ID_SIZE]; /* Only this may resume it */
+    u64 expires_ns;      /* 0 for a free slot */
+    u32 id;              /* Taken back on resume */
+    u32 max_kbps;
+    u32 max_fps;
+    u32 scale_shift;
+    u32 format;
+    bool paused;
+    u32 backoff;
+    u32 receive_latency_us;
+    u32 present_latency_us;
 };
 
 /* Main device structure */
@@ -95,16 +609,33 @@ struct ipdisp_device {
     struct drm_device drm;
     struct platform_device *pdev;
     
-    /* Display properties */
+    /* Display properties, following the mode in use (fb_lock) */
     u32 width;
     u32 height;
     u32 pitch;
+    u32 max_width;       /* Largest mode the buffers are allocated for */
+    u32 max_height;
+    bool resized;        /* Clients not told of the new size yet */
+    
+    /* Mode offered as preferred, changed by MODE_REQUEST */
+    u32 mode_width;
+    u32 mode_height;
+    u32 mode_refresh;    /* Hz */
+    bool allow_mode_requests;
+    struct work_struct mode_work; /* Tells the desktop to probe again */
+    
+    /* With hotplug, the output is only connected while a client is
+     * (clients_lock to change, read locklessly by detect) */
+    bool hotplug;
+    bool plugged;
     
     /* Frame buffer */
     void *framebuffer;
     dma_addr_t fb_dma_addr;
     size_t fb_size;
     struct mutex fb_lock;
+    struct drm_rect damage; /* Changed since the last send (fb_lock) */
+    void *damage_buf;    /* Rows of a client's damage, allocated on first use */
     
     /* Network */
     struct socket *listen_sock;
@@ -112,11 +643,78 @@ struct ipdisp_device {
     struct task_struct *network_thread;
     struct list_head clients;
     struct mutex clients_lock;
+    u32 next_client_id;
+    u32 heartbeat_timeout_ms;
+    u32 resume_timeout_ms; /* Lost sessions are kept this long, 0 = never */
+    struct ipdisp_parked_session parked[IPDISP_MAX_CLIENTS]; /* clients_lock */
+    u32 sync_delay_ms;   /* Published to CAP_SYNC clients, 0 = off */
+    bool allow_supervise; /* Verified clients may send SUPERVISE */
+    bool rate_control;   /* Back off clients whose links fall behind */
+    u32 ack_window;      /* Frames ahead of a client's ACKs, 0 = no limit */
+    u64 frame_seq;       /* Frames sent, for striping across links */
+    
+    /* Pairing (protected by clients_lock) */
+    bool require_pairing;
+    struct crypto_shash *pair_sha256;
+    struct crypto_shash *pair_hmac;
+    struct ipdisp_pairing pairing;
+    struct ipdisp_paired_token paired[IPDISP_MAX_PAIRED];
+    unsigned int paired_next;
+    
+    /* Authentication providers, asked in this order */
+    const struct ipdisp_auth_provider *auth[IPDISP_MAX_AUTH_PROVIDERS];
+    unsigned int auth_count;
+    const char *auth_token;   /* Shared secret for the token provider */
+    u8 auth_token_key[SHA256_DIGEST_SIZE];
+    u8 auth_token_id[IPDISP_PAIR_TOKEN_ID_SIZE];
+    
+    /* Noise static key, when noise_key is set; with require_noise only
+     * clients that encrypt get frames */
+    bool noise;
+    bool require_noise;
+    u8 noise_secret[IPDISP_NOISE_KEY_SIZE];
+    u8 noise_public[IPDISP_NOISE_KEY_SIZE];
+    
+    /* Ed25519 key content hashes are signed with: the expanded secret
+     * (scalar, then nonce prefix) and the public key clients pin */
+    struct crypto_shash *sign_sha512;
+    u8 sign_secret[SHA512_DIGEST_SIZE];
+    u8 sign_public[IPDISP_SIGN_KEY_SIZE];
+    
+    /* Virtual touchscreen, while a client wants one (clients_lock) */
+    struct input_dev *touch;
+    u32 touch_slots;
+    /* Virtual mouse, while a client captures its pointer (clients_lock) */
+    struct input_dev *mouse;
+    /* Virtual keyboard, likewise (clients_lock) */
+    struct input_dev *keyboard;
+    enum ipdisp_input_policy input_policy;
+    enum ipdisp_session_mode session_mode; /* Most a client may do */
+    /* Client holding input control, 0 for none (clients_lock) */
+    enum ipdisp_control_policy control_policy;
+    u32 control_owner;
+    struct in_addr control_owner_addr;
+    
+    /* Sound card, with the audio parameter */
+    struct ipdisp_audio *audio;
+    
+    /* Uploads are written here, none when unset */
+    const char *upload_dir;
+    u32 upload_max_mb;
     
     /* Encoder/streaming */
     struct workqueue_struct *stream_wq;
     struct work_struct stream_work;
     bool streaming_enabled;
+    void *scaled[IPDISP_MAX_SCALE_SHIFT]; /* 1/2 and 1/4 size frames */
+    /* RGB565 and NV12 frames at each scale, allocated on first use */
+    void *packed[IPDISP_MAX_SCALE_SHIFT + 1][IPDISP_PACKED_FORMATS];
+    
+    /* IDENTIFY: flashing until then (ns, read locklessly) */
+    u64 identify_until;
+    struct delayed_work identify_work; /* Redraws at each blink */
+    bool identify_shown; /* Last frame sent had the border (fb_lock) */
+    void *identify_buf;  /* Frame with the border, allocated on first use */
     
     /* DRM components */
     struct drm_simple_display_pipe pipe;
@@ -130,22 +728,157 @@ struct ipdisp_device {
 int ipdisp_drm_init(struct ipdisp_device *idev);
 void ipdisp_drm_cleanup(struct ipdisp_device *idev);
 void ipdisp_drm_update_frame(struct ipdisp_device *idev);
+int ipdisp_drm_request_mode(struct ipdisp_device *idev,
+                            struct ipdisp_client *client,
+                            const u8 *payload, u32 size);
+void ipdisp_drm_set_plugged(struct ipdisp_device *idev, bool plugged);
 
 /* Network functions */
 int ipdisp_network_init(struct ipdisp_device *idev);
 void ipdisp_network_cleanup(struct ipdisp_device *idev);
 int ipdisp_network_send_frame(struct ipdisp_device *idev, 
                              const void *data, size_t size);
+int ipdisp_network_send_packet(struct ipdisp_client *client, u32 packet_type,
+                               const void *payload, u32 size);
+void ipdisp_network_announce_display(struct ipdisp_device *idev);
+void ipdisp_network_send_audio(struct ipdisp_device *idev,
+                               const void *payload, u32 size);
+
+/* Pairing functions */
+int ipdisp_pair_init(struct ipdisp_device *idev);
+void ipdisp_pair_cleanup(struct ipdisp_device *idev);
+int ipdisp_pair_hmac(struct ipdisp_device *idev, const u8 *key,
+                     unsigned int key_len, const void *data, unsigned int len,
+                     u8 *out);
+int ipdisp_pair_verify_token(struct ipdisp_device *idev,
+                             struct ipdisp_client *client, const u8 *id,
+                             const u8 *mac);
+int ipdisp_pair_send_challenge(struct ipdisp_device *idev,
+                               struct ipdisp_client *client);
+int ipdisp_pair_challenge(struct ipdisp_client *client);
+int ipdisp_pair_send_content_hash(struct ipdisp_device *idev,
+                                  struct ipdisp_client *client, u64 timestamp,
+                                  const u8 *digest);
+int ipdisp_pair_handle_request(struct ipdisp_device *idev,
+                               struct ipdisp_client *client, u32 packet_type,
+                               const u8 *payload, u32 size);
+void ipdisp_pair_forget_client(struct ipdisp_device *idev,
+                               struct ipdisp_client *client);
+
+/* Authentication functions */
+int ipdisp_auth_init(struct ipdisp_device *idev, const char *providers);
+void ipdisp_auth_cleanup(struct ipdisp_device *idev);
+int ipdisp_auth_verify(struct ipdisp_device *idev,
+                       struct ipdisp_client *client,
+                       const u8 *payload, u32 size);
+
+/* Content signature functions */
+int ipdisp_sign_init(struct ipdisp_device *idev, const char *key);
+void ipdisp_sign_cleanup(struct ipdisp_device *idev);
+int ipdisp_sign(struct ipdisp_device *idev, const void *msg, size_t len,
+                u8 *sig);
+
+/* Noise functions */
+int ipdisp_noise_init(struct ipdisp_device *idev, const char *key);
+void ipdisp_noise_cleanup(struct ipdisp_device *idev);
+int ipdisp_noise_start(struct ipdisp_device *idev,
+                       struct ipdisp_client *client,
+                       const struct kvec *prologue, size_t count);
+int ipdisp_noise_handle_request(struct ipdisp_device *idev,
+                                struct ipdisp_client *client,
+                                const u8 *payload, u32 size);
+int ipdisp_noise_sendmsg(struct ipdisp_client *client, struct kvec *iov,
+                         size_t count, size_t total);
+int ipdisp_noise_recv(struct ipdisp_client *client, u8 **plain);
+void ipdisp_noise_forget_client(struct ipdisp_client *client);
+
+/* Input functions */
+int ipdisp_input_handle_request(struct ipdisp_device *idev,
+                                struct ipdisp_client *client,
+                                const u8 *payload, u32 size);
+int ipdisp_input_handle_touch(struct ipdisp_device *idev,
+                              struct ipdisp_client *client,
+                              const u8 *payload, u32 size);
+int ipdisp_input_handle_pointer(struct ipdisp_device *idev,
+                                struct ipdisp_client *client,
+                                const u8 *payload, u32 size);
+int ipdisp_input_handle_key(struct ipdisp_device *idev,
+                            struct ipdisp_client *client,
+                            const u8 *payload, u32 size);
+int ipdisp_input_handle_control(struct ipdisp_device *idev,
+                                struct ipdisp_client *client,
+                                const u8 *payload, u32 size);
+int ipdisp_input_send_control(struct ipdisp_device *idev,
+                              struct ipdisp_client *client, int status);
+void ipdisp_input_drop_control(struct ipdisp_device *idev, u32 id);
+void ipdisp_input_forget_client(struct ipdisp_device *idev,
+                                struct ipdisp_client *client);
+void ipdisp_input_cleanup(struct ipdisp_device *idev);
+
+/* Audio functions */
+int ipdisp_audio_init(struct ipdisp_device *idev);
+void ipdisp_audio_cleanup(struct ipdisp_device *idev);
+
+/* Session resume functions */
+int ipdisp_resume_handle_request(struct ipdisp_device *idev,
+                                 struct ipdisp_client *client,
+                                 const u8 *payload, u32 size);
+int ipdisp_resume_poll(struct ipdisp_device *idev,
+                       struct ipdisp_client *client);
+void ipdisp_resume_park(struct ipdisp_device *idev,
+                        struct ipdisp_client *client);
+void ipdisp_resume_expire(struct ipdisp_device *idev);
+
+/* Upload functions */
+int ipdisp_upload_handle_begin(struct ipdisp_device *idev,
+                               struct ipdisp_client *client,
+                               const u8 *payload, u32 size);
+int ipdisp_upload_handle_data(struct ipdisp_client *client,
+                              const u8 *payload, u32 size);
+int ipdisp_upload_recv(struct ipdisp_client *client, u32 size);
+void ipdisp_upload_forget_client(struct ipdisp_client *client);
 
 /* Encoder functions */
 int ipdisp_encoder_init(struct ipdisp_device *idev);
 void ipdisp_encoder_cleanup(struct ipdisp_device *idev);
 void ipdisp_encoder_queue_frame(struct ipdisp_device *idev);
+void ipdisp_encoder_identify(struct ipdisp_device *idev, u32 duration_ms);
+const void *ipdisp_encoder_downscale(struct ipdisp_device *idev,
+                                     const void *frame, unsigned int shift);
+const void *ipdisp_encoder_pack(struct ipdisp_device *idev, const void *frame,
+                                u32 pitch, unsigned int shift, u32 format,
+                                size_t *size);
 
 /* Utility macros */
 #define ipdisp_dev(dev) container_of(dev, struct ipdisp_device, drm)
 #define to_ipdisp_device(x) container_of(x, struct ipdisp_device, drm)
 
+/* Whether packets the client didn't ask for, such as frames, may go to it:
+ * not in the middle of a handshake, and only encrypted with require_noise */
+static inline bool ipdisp_noise_ready(struct ipdisp_device *idev,
+                                      struct ipdisp_client *client)
+{
+    if (client->noise)
+        return client->noise->stage == IPDISP_NOISE_READY;
+    return !idev->require_noise;
+}
+
+/* Grow damage to cover rect as well */
+static inline void ipdisp_damage_add(struct drm_rect *damage,
+                                     const struct drm_rect *rect)
+{
+    if (!drm_rect_visible(rect))
+        return;
+    if (!drm_rect_visible(damage)) {
+        *damage = *rect;
+        return;
+    }
+    damage->x1 = min(damage->x1, rect->x1);
+    damage->y1 = min(damage->y1, rect->y1);
+    damage->x2 = max(damage->x2, rect->x2);
+    damage->y2 = max(damage->y2, rect->y2);
+}
+
 /* Debug macros */
 #ifdef DEBUG
 #define ipdisp_debug(fmt, ...) \
@@ -0,0 +1,261 @@
+/* IP Display Driver - Audio
+ * Copyright (C) 2024
+ * Licensed under GPL v2
+ *
+ * With the audio parameter the driver also registers a sound card, "IP
+ * Display Audio", with one playback device, much as a monitor's HDMI audio
+ * comes with its output. The desktop's sound server (PipeWire, PulseAudio)
+ * plays into it like any card, so no capture is needed. A timer takes each
+ * period out of the ring buffer at the rate it would be played, and a work
+ * item sends it on in AUDIO packets to clients that set CAP_AUDIO.
+ *
+ * Samples go out as 16-bit PCM, about 1.5 Mbit/s. Opus would need an
+ * encoder, which the kernel doesn't have; AUDIO names its codec so one
+ * can be added in userspace later.
+ */
+
+#include "ipdisp.h"
+
+struct ipdisp_audio {
+    struct ipdisp_device *idev;
+    struct snd_card *card;
+    struct snd_pcm_substream *substream; /* While open */
+    struct hrtimer timer;
+    ktime_t period_time;
+    spinlock_t lock;                     /* pos, running and fifo */
+    snd_pcm_uframes_t pos;               /* Next period to take */
+    bool running;
+    DECLARE_KFIFO_PTR(fifo, u8);         /* Taken, not sent yet */
+    struct work_struct send_work;
+    /* An AUDIO payload, too big for the stack */
+    struct {
+        struct ipdisp_audio_header header;
+        u8 samples[IPDISP_AUDIO_CHUNK];
+    } __packed packet;
+};
+
+static const struct snd_pcm_hardware ipdisp_audio_hardware = {
+    .info = SNDRV_PCM_INFO_INTERLEAVED | SNDRV_PCM_INFO_BLOCK_TRANSFER |
+            SNDRV_PCM_INFO_MMAP | SNDRV_PCM_INFO_MMAP_VALID,
+    .formats = SNDRV_PCM_FMTBIT_S16_LE,
+    .rates = SNDRV_PCM_RATE_48000,
+    .rate_min = IPDISP_AUDIO_RATE,
+    .rate_max = IPDISP_AUDIO_RATE,
+    .channels_min = IPDISP_AUDIO_CHANNELS,
+    .channels_max = IPDISP_AUDIO_CHANNELS,
+    .buffer_bytes_max = IPDISP_AUDIO_FIFO_SIZE,
+    /* 5 to 85 ms, so a period always fits one packet */
+    .period_bytes_min = IPDISP_AUDIO_RATE / 200 * IPDISP_AUDIO_FRAME_SIZE,
+    .period_bytes_max = IPDISP_AUDIO_CHUNK,
+    .periods_min = 2,
+    .periods_max = 32,
+};
+
+/* Take the period just played and let ALSA move on */
+static enum hrtimer_restart ipdisp_audio_tick(struct hrtimer *timer)
+{
+    struct ipdisp_audio *audio = container_of(timer, struct ipdisp_audio,
+                                              timer);
+    struct snd_pcm_runtime *runtime = audio->substream->runtime;
+    
+    spin_lock(&audio->lock);
+    if (!audio->running) {
+        spin_unlock(&audio->lock);
+        return HRTIMER_NORESTART;
+    }
+    /* Clients that fall behind miss what doesn't fit */
+    kfifo_in(&audio->fifo,
+             runtime->dma_area + frames_to_bytes(runtime, audio->pos),
+             frames_to_bytes(runtime, runtime->period_size));
+    audio->pos = (audio->pos + runtime->period_size) % runtime->buffer_size;
+    spin_unlock(&audio->lock);
+    
+    snd_pcm_period_elapsed(audio->substream);
+    schedule_work(&audio->send_work);
+    
+    hrtimer_forward_now(timer, audio->period_time);
+    return READ_ONCE(audio->running) ? HRTIMER_RESTART : HRTIMER_NORESTART;
+}
+
+static void ipdisp_audio_send(struct work_struct *work)
+{
+    struct ipdisp_audio *audio = container_of(work, struct ipdisp_audio,
+                                              send_work);
+    unsigned int len;
+    
+    while ((len = kfifo_out_spinlocked(&audio->fifo, audio->packet.samples,
+                                       sizeof(audio->packet.samples),
+                                       &audio->lock)))
+        ipdisp_network_send_audio(audio->idev, &audio->packet,
+                                  sizeof(audio->packet.header) + len);
+}
+
+static int ipdisp_audio_open(struct snd_pcm_substream *substream)
+{
+    struct ipdisp_audio *audio = snd_pcm_substream_chip(substream);
+    int ret;
+    
+    substream->runtime->hw = ipdisp_audio_hardware;
+    /* Periods end where the buffer does, so each is taken in one piece */
+    ret = snd_pcm_hw_constraint_integer(substream->runtime,
+                                        SNDRV_PCM_HW_PARAM_PERIODS);
+    if (ret < 0)
+        return ret;
+    
+    audio->substream = substream;
+    return 0;
+}
+
+static int ipdisp_audio_close(struct snd_pcm_substream *substream)
+{
+    struct ipdisp_audio *audio = snd_pcm_substream_chip(substream);
+    
+    hrtimer_cancel(&audio->timer);
+    audio->substream = NULL;
+    return 0;
+}
+
+static int ipdisp_audio_prepare(struct snd_pcm_substream *substream)
+{
+    struct ipdisp_audio *audio = snd_pcm_substream_chip(substream);
+    struct snd_pcm_runtime *runtime = substream->runtime;
+    
+    audio->pos = 0;
+    audio->period_time = ns_to_ktime(div_u64((u64)runtime->period_size *
+                                             NSEC_PER_SEC, runtime->rate));
+    return 0;
+}
+
+static int ipdisp_audio_trigger(struct snd_pcm_substream *substream, int cmd)
+{
+    struct ipdisp_audio *audio = snd_pcm_substream_chip(substream);
+    
+    switch (cmd) {
+    case SNDRV_PCM_TRIGGER_START:
+        spin_lock(&audio->lock);
+        audio->running = true;
+        spin_unlock(&audio->lock);
+        hrtimer_start(&audio->timer, audio->period_time,
+                      HRTIMER_MODE_REL_SOFT);
+        return 0;
+    case SNDRV_PCM_TRIGGER_STOP:
+        spin_lock(&audio->lock);
+        audio->running = false;
+        spin_unlock(&audio->lock);
+        /* The timer may be waiting for the stream lock we hold */
+        hrtimer_try_to_cancel(&audio->timer);
+        return 0;
+    default:
+        return -EINVAL;
+    }
+}
+
+static int ipdisp_audio_sync_stop(struct snd_pcm_substream *substream)
+{
+    struct ipdisp_audio *audio = snd_pcm_substream_chip(substream);
+    
+    hrtimer_cancel(&audio->timer);
+    return 0;
+}
+
+static snd_pcm_uframes_t ipdisp_audio_pointer(struct snd_pcm_substream *substream)
+{
+    struct ipdisp_audio *audio = snd_pcm_substream_chip(substream);
+    snd_pcm_uframes_t pos;
+    
+    spin_lock(&audio->lock);
+    pos = audio->pos;
+    spin_unlock(&audio->lock);
+    return pos;
+}
+
+static const struct snd_pcm_ops ipdisp_audio_ops = {
+    .open = ipdisp_audio_open,
+    .close = ipdisp_audio_close,
+    .prepare = ipdisp_audio_prepare,
+    .trigger = ipdisp_audio_trigger,
+    .sync_stop = ipdisp_audio_sync_stop,
+    .pointer = ipdisp_audio_pointer,
+};
+
+/* Register the sound card; without it the display works as before */
+int ipdisp_audio_init(struct ipdisp_device *idev)
+{
+    struct ipdisp_audio *audio;
+    struct snd_pcm *pcm;
+    int ret;
+    
+    audio = kzalloc(sizeof(*audio), GFP_KERNEL);
+    if (!audio)
+        return -ENOMEM;
+    
+    audio->idev = idev;
+    spin_lock_init(&audio->lock);
+    INIT_WORK(&audio->send_work, ipdisp_audio_send);
+#if LINUX_VERSION_CODE >= KERNEL_VERSION(6, 13, 0)
+    hrtimer_setup(&audio->timer, ipdisp_audio_tick, CLOCK_MONOTONIC,
+                  HRTIMER_MODE_REL_SOFT);
+#else
+    hrtimer_init(&audio->timer, CLOCK_MONOTONIC, HRTIMER_MODE_REL_SOFT);
+    audio->timer.function = ipdisp_audio_tick;
+#endif
+    audio->packet.header.codec = cpu_to_be32(IPDISP_AUDIO_PCM);
+    audio->packet.header.rate = cpu_to_be32(IPDISP_AUDIO_RATE);
+    audio->packet.header.channels = cpu_to_be32(IPDISP_AUDIO_CHANNELS);
+    
+    ret = kfifo_alloc(&audio->fifo, IPDISP_AUDIO_FIFO_SIZE, GFP_KERNEL);
+    if (ret)
+        goto err_free;
+    
+    ret = snd_card_new(&idev->pdev->dev, -1, NULL, THIS_MODULE, 0,
+                       &audio->card);
+    if (ret)
+        goto err_fifo;
+    
+    strscpy(audio->card->driver, DRIVER_NAME, sizeof(audio->card->driver));
+    strscpy(audio->card->shortname, "IP Display",
+            sizeof(audio->card->shortname));
+    strscpy(audio->card->longname, "IP Display Audio",
+            sizeof(audio->card->longname));
+    
+    ret = snd_pcm_new(audio->card, "IP Display", 0, 1, 0, &pcm);
+    if (ret)
+        goto err_card;
+    
+    pcm->private_data = audio;
+    strscpy(pcm->name, "IP Display Audio", sizeof(pcm->name));
+    snd_pcm_set_ops(pcm, SNDRV_PCM_STREAM_PLAYBACK, &ipdisp_audio_ops);
+    snd_pcm_set_managed_buffer_all(pcm, SNDRV_DMA_TYPE_VMALLOC, NULL, 0, 0);
+    
+    ret = snd_card_register(audio->card);
+    if (ret)
+        goto err_card;
+    
+    idev->audio = audio;
+    ipdisp_info("Sound card registered\n");
+    return 0;
+    
+err_card:
+    snd_card_free(audio->card);
+err_fifo:
+    kfifo_free(&audio->fifo);
+err_free:
+    kfree(audio);
+    ipdisp_err("Failed to register sound card: %d\n", ret);
+    return ret;
+}
+
+void ipdisp_audio_cleanup(struct ipdisp_device *idev)
+{
+    struct ipdisp_audio *audio = idev->audio;
+    
+    if (!audio)
+        return;
+    
+    /* Waits for the device to be closed, which stops the timer */
+    snd_card_free(audio->card);
+    cancel_work_sync(&audio->send_work);
+    kfifo_free(&audio->fifo);
+    kfree(audio);
+    idev->audio = NULL;
+}
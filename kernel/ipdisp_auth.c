@@ -0,0 +1,185 @@
+/* IP Display Driver - Authentication Providers
+ * Copyright (C) 2024
+ * Licensed under GPL v2
+ *
+ * AUTH carries a token id and an HMAC of the client's AUTH_CHALLENGE. The
+ * providers named in the auth module parameter are asked in turn whether
+ * the id is theirs; the first that recognises it decides. New ways to
+ * authenticate plug in as another struct ipdisp_auth_provider.
+ *
+ *   pairing  tokens from pairing with the 6-digit code (ipdisp_pair.c)
+ *   token    one shared secret from the auth_token parameter; its key is
+ *            SHA-256 of the secret and its id is derived from the key the
+ *            same way a paired token's is
+ */
+
+#include "ipdisp.h"
+
+static const char ipdisp_auth_token_id_label[] = "ipdisp token id";
+
+static int ipdisp_auth_token_init(struct ipdisp_device *idev)
+{
+    u8 id[SHA256_DIGEST_SIZE];
+    int ret;
+    
+    if (!idev->auth_token || !*idev->auth_token) {
+        ipdisp_err("The token auth provider needs auth_token set\n");
+        return -EINVAL;
+    }
+    
+    ret = crypto_shash_tfm_digest(idev->pair_sha256, idev->auth_token,
+                                  strlen(idev->auth_token),
+                                  idev->auth_token_key);
+    if (ret)
+        return ret;
+    
+    ret = ipdisp_pair_hmac(idev, idev->auth_token_key,
+                           sizeof(idev->auth_token_key),
+                           ipdisp_auth_token_id_label,
+                           strlen(ipdisp_auth_token_id_label), id);
+    if (ret)
+        return ret;
+    memcpy(idev->auth_token_id, id, sizeof(idev->auth_token_id));
+    return 0;
+}
+
+static void ipdisp_auth_token_cleanup(struct ipdisp_device *idev)
+{
+    memzero_explicit(idev->auth_token_key, sizeof(idev->auth_token_key));
+}
+
+static int ipdisp_auth_token_verify(struct ipdisp_device *idev,
+                                    struct ipdisp_client *client,
+                                    const u8 *id, const u8 *mac)
+{
+    u8 expected[SHA256_DIGEST_SIZE];
+    int ret;
+    
+    if (memcmp(id, idev->auth_token_id, sizeof(idev->auth_token_id)))
+        return -ENOENT;
+    
+    ret = ipdisp_pair_hmac(idev, idev->auth_token_key,
+                           sizeof(idev->auth_token_key), client->challenge,
+                           sizeof(client->challenge), expected);
+    if (ret)
+        return ret;
+    return crypto_memneq(expected, mac, sizeof(expected)) ? -EACCES : 0;
+}
+
+static const struct ipdisp_auth_provider ipdisp_auth_providers[] = {
+    {
+        .name = "pairing",
+        .verify = ipdisp_pair_verify_token,
+    },
+    {
+        .name = "token",
+        .init = ipdisp_auth_token_init,
+        .cleanup = ipdisp_auth_token_cleanup,
+        .verify = ipdisp_auth_token_verify,
+    },
+};
+
+static const struct ipdisp_auth_provider *ipdisp_auth_find(const char *name)
+{
+    unsigned int i;
+    
+    for (i = 0; i < ARRAY_SIZE(ipdisp_auth_providers); i++) {
+        if (!strcmp(ipdisp_auth_providers[i].name, name))
+            return &ipdisp_auth_providers[i];
+    }
+    return NULL;
+}
+
+/* Enable the comma-separated providers, in the order they are asked;
+ * pairing must be set up already */
+int ipdisp_auth_init(struct ipdisp_device *idev, const char *providers)
+{
+    const struct ipdisp_auth_provider *provider;
+    char *list, *cursor, *name;
+    int ret = 0;
+    
+    list = kstrdup(providers, GFP_KERNEL);
+    if (!list)
+        return -ENOMEM;
+    
+    cursor = list;
+    while ((name = strsep(&cursor, ",")) != NULL) {
+        name = strim(name);
+        if (!*name)
+            continue;
+    
+        provider = ipdisp_auth_find(name);
+        if (!provider) {
+            ipdisp_err("Unknown auth provider %s\n", name);
+            ret = -EINVAL;
+            break;
+        }
+        if (idev->auth_count == IPDISP_MAX_AUTH_PROVIDERS) {
+            ret = -E2BIG;
+            break;
+        }
+    
+        if (provider->init) {
+            ret = provider->init(idev);
+            if (ret)
+                break;
+        }
+        idev->auth[idev->auth_count++] = provider;
+        ipdisp_info("Accepting %s authentication\n", provider->name);
+    }
+    
+    kfree(list);
+    if (ret)
+        ipdisp_auth_cleanup(idev);
+    return ret;
+}
+
+void ipdisp_auth_cleanup(struct ipdisp_device *idev)
+{
+    while (idev->auth_count) {
+        const struct ipdisp_auth_provider *provider =
+            idev->auth[--idev->auth_count];
+    
+        if (provider->cleanup)
+            provider->cleanup(idev);
+        idev->auth[idev->auth_count] = NULL;
+    }
+}
+
+/* Check an AUTH request against the enabled providers; caller holds
+ * clients_lock and client->lock. A negative return drops the client. */
+int ipdisp_auth_verify(struct ipdisp_device *idev,
+                       struct ipdisp_client *client,
+                       const u8 *payload, u32 size)
+{
+    const u8 *id = payload;
+    const u8 *mac = payload + IPDISP_PAIR_TOKEN_ID_SIZE;
+    unsigned int i;
+    int ret = -ENOENT;
+    
+    if (size != IPDISP_PAIR_TOKEN_ID_SIZE + IPDISP_PAIR_MAC_SIZE)
+        return -EPROTO;
+    
+    /* Only answers to a challenge we sent count */
+    if (!memchr_inv(client->challenge, 0, sizeof(client->challenge)))
+        return -EPROTO;
+    
+    for (i = 0; i < idev->auth_count && ret == -ENOENT; i++) {
+        ret = idev->auth[i]->verify(idev, client, id, mac);
+        if (!ret) {
+            client->authenticated = true;
+            client->verified = true;
+            memcpy(client->auth_id, id, sizeof(client->auth_id));
+            ipdisp_info("Client %pI4 authenticated by %s\n",
+                       &client->addr.sin_addr, idev->auth[i]->name);
+            return 0;
+        }
+    }
+    
+    ipdisp_warn("Client %pI4 failed to authenticate\n", &client->addr.sin_addr);
+    /* Without require_pairing a client answering with a stale credential,
+     * say to resume, just stays unverified */
+    if (ret == -ENOENT && !idev->require_pairing)
+        return 0;
+    return ret == -ENOENT ? -EACCES : ret;
+}
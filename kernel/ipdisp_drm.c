@@ -25,6 +25,15 @@ static const struct drm_display_mode default_modes[] = {
                DRM_MODE_FLAG_NHSYNC | DRM_MODE_FLAG_NVSYNC) },
 };
 
+/* Whether a default mode is the one to offer as preferred */
+static bool ipdisp_mode_preferred(struct ipdisp_device *idev,
+                                  const struct drm_display_mode *mode)
+{
+    return mode->hdisplay == idev->mode_width &&
+           mode->vdisplay == idev->mode_height &&
+           drm_mode_vrefresh(mode) == idev->mode_refresh;
+}
+
 /* Connector helper functions */
 static int ipdisp_connector_get_modes(struct drm_connector *connector)
 {
@@ -38,9 +47,8 @@ static int ipdisp_connector_get_modes(struct drm_connector *connector)
     for (i = 0; i < ARRAY_SIZE(default_modes); i++) {
         mode = drm_mode_duplicate(connector->dev, &default_modes[i]);
         if (mode) {
-            /* Mark current resolution as preferred */
-            if (mode->hdisplay == idev->width && 
-                mode->vdisplay == idev->height) {
+            /* Mark the mode asked for as preferred */
+            if (ipdisp_mode_preferred(idev, mode)) {
                 mode->type |= DRM_MODE_TYPE_PREFERRED;
             }
             drm_mode_probed_add(connector, mode);
@@ -51,16 +59,16 @@ static int ipdisp_connector_get_modes(struct drm_connector *connector)
     /* Add custom mode if not in defaults */
     bool found = false;
     for (i = 0; i < ARRAY_SIZE(default_modes); i++) {
-        if (default_modes[i].hdisplay == idev->width &&
-            default_modes[i].vdisplay == idev->height) {
+        if (ipdisp_mode_preferred(idev, &default_modes[i])) {
             found = true;
             break;
         }
     }
     
     if (!found) {
-        mode = drm_cvt_mode(connector->dev, idev->width, idev->height,
-                           60, false, false, false);
+        mode = drm_cvt_mode(connector->dev, idev->mode_width,
+                           idev->mode_height, idev->mode_refresh,
+                           false, false, false);
         if (mode) {
             mode->type |= DRM_MODE_TYPE_PREFERRED;
             drm_mode_probed_add(connector, mode);
@@ -91,6 +99,13 @@ ipdisp_connector_mode_valid(struct drm_connector *connector,
         return MODE_BAD;
     }
     
+    /* The framebuffer is allocated for the module's width and height */
+    if (mode->hdisplay > idev->max_width ||
+        mode->vdisplay > idev->max_height) {
+        ipdisp_debug("Mode larger than the framebuffer\n");
+        return MODE_BAD;
+    }
+    
     /* Allow current configured mode */
     if (mode->hdisplay == idev->width && mode->vdisplay == idev->height) {
         return MODE_OK;
@@ -107,8 +122,11 @@ static const struct drm_connector_helper_funcs ipdisp_connector_helper_funcs = {
 static enum drm_connector_status 
 ipdisp_connector_detect(struct drm_connector *connector, bool force)
 {
+    struct ipdisp_device *idev = to_ipdisp_device(connector->dev);
+    
     ipdisp_debug("Connector detect\n");
-    /* Always report connected */
+    if (idev->hotplug && !READ_ONCE(idev->plugged))
+        return connector_status_disconnected;
     return connector_status_connected;
 }
 
@@ -156,6 +174,7 @@ static void ipdisp_pipe_update(struct drm_simple_display_pipe *pipe,
     struct drm_framebuffer *fb = state->fb;
     struct drm_gem_object *gem_obj;
     struct drm_gem_dma_object *dma_obj;
+    struct drm_rect damage, whole;
     void *src_addr;
     
     if (!fb || !idev->streaming_enabled)
@@ -175,12 +194,43 @@ static void ipdisp_pipe_update(struct drm_simple_display_pipe *pipe,
     /* Copy framebuffer data */
     mutex_lock(&idev->fb_lock);
     
+    /* Frames follow a mode change; clients are sent display info before
+     * the first frame at the new size */
+    if ((fb->width != idev->width || fb->height != idev->height) &&
+        fb->width <= idev->max_width && fb->height <= idev->max_height) {
+        ipdisp_info("Display is now %ux%u\n", fb->width, fb->height);
+        idev->width = fb->width;
+        idev->height = fb->height;
+        idev->pitch = fb->width * 4;
+        idev->resized = true;
+    }
+    
+    /* What changed, from the commit's damage clips; a flip to another
+     * framebuffer, a commit without clips or a new size changes
+     * everything */
+    drm_rect_init(&whole, 0, 0, idev->width, idev->height);
+    if (idev->resized ||
+        !drm_atomic_helper_damage_merged(old_state, state, &damage))
+        damage = whole;
+    if (drm_rect_intersect(&damage, &whole))
+        ipdisp_damage_add(&idev->damage, &damage);
+    
     if (fb->format->format == DRM_FORMAT_XRGB8888 ||
         fb->format->format == DRM_FORMAT_ARGB8888) {
-        /* Direct copy for matching format */
-        size_t copy_size = min_t(size_t, idev->fb_size, 
-                                fb->height * fb->pitches[0]);
-        memcpy(idev->framebuffer, src_addr, copy_size);
+        if (fb->pitches[0] == idev->pitch) {
+            /* Direct copy for matching format */
+            size_t copy_size = min_t(size_t, idev->fb_size, 
+                                    fb->height * fb->pitches[0]);
+            memcpy(idev->framebuffer, src_addr, copy_size);
+        } else {
+            /* Padded rows, or a framebuffer larger than the mode */
+            unsigned int row;
+            
+            for (row = 0; row < idev->height && row < fb->height; row++)
+                memcpy(idev->framebuffer + row * idev->pitch,
+                       src_addr + row * fb->pitches[0],
+                       min(idev->pitch, fb->pitches[0]));
+        }
     } else {
         ipdisp_warn("Unsupported framebuffer format: %s\n",
                    drm_get_format_name(fb->format->format, NULL));
@@ -205,9 +255,78 @@ static const u32 ipdisp_formats[] = {
     DRM_FORMAT_ARGB8888,
 };
 
-/* Mode config functions */
+/* Announce the mode a client asked for, or the output coming and going;
+ * not done from the network thread, as probing may commit a new mode,
+ * which waits for fb_lock */
+static void ipdisp_mode_work_func(struct work_struct *work)
+{
+    struct ipdisp_device *idev = container_of(work, struct ipdisp_device,
+                                             mode_work);
+    
+    drm_kms_helper_hotplug_event(&idev->drm);
+}
+
+/* Offer a client's MODE_REQUEST as the preferred mode. Desktops that
+ * follow the preferred mode of a hotplugged output switch to it; the
+ * frames that follow have the new size. */
+int ipdisp_drm_request_mode(struct ipdisp_device *idev,
+                            struct ipdisp_client *client,
+                            const u8 *payload, u32 size)
+{
+    u32 width, height, refresh_mhz, refresh;
+    
+    if (size < 3 * sizeof(__be32))
+        return -EPROTO;
+    if (!idev->allow_mode_requests || !client->authenticated ||
+        client->session_mode < IPDISP_SESSION_INPUT)
+        return 0;
+    
+    width = be32_to_cpup((const __be32 *)payload);
+    height = be32_to_cpup((const __be32 *)payload + 1);
+    refresh_mhz = be32_to_cpup((const __be32 *)payload + 2);
+    if (width < 640 || height < 480 ||
+        width > idev->max_width || height > idev->max_height) {
+        ipdisp_warn("Client %pI4 asked for %ux%u, outside 640x480 to %ux%u\n",
+                   &client->addr.sin_addr, width, height,
+                   idev->max_width, idev->max_height);
+        return 0;
+    }
+    
+    /* CVT timings come in 8 pixel columns */
+    width &= ~7U;
+    refresh = refresh_mhz ?
+              clamp_t(u32, DIV_ROUND_CLOSEST(refresh_mhz, 1000), 24, 240) : 60;
+    if (width == idev->mode_width && height == idev->mode_height &&
+        refresh == idev->mode_refresh)
+        return 0;
+    
+    ipdisp_info("Client %pI4 asked for %ux%u@%u\n", &client->addr.sin_addr,
+                width, height, refresh);
+    idev->mode_width = width;
+    idev->mode_height = height;
+    idev->mode_refresh = refresh;
+    schedule_work(&idev->mode_work);
+    return 0;
+}
+
+/* With hotplug, plug the output in when the first client connects and
+ * unplug it when the last one leaves, so the desktop gains a monitor for
+ * the client and loses it again. Caller holds clients_lock. */
+void ipdisp_drm_set_plugged(struct ipdisp_device *idev, bool plugged)
+{
+    if (!idev->hotplug || idev->plugged == plugged)
+        return;
+    
+    ipdisp_info("Virtual display %s\n", plugged ? "connected" : "disconnected");
+    WRITE_ONCE(idev->plugged, plugged);
+    schedule_work(&idev->mode_work);
+}
+
+/* Mode config functions. Dumb buffers drawn in place (the console, or a
+ * KMS program without page flips) are only sent after DIRTYFB, which the
+ * dirty helper turns into a commit. */
 static const struct drm_mode_config_funcs ipdisp_mode_config_funcs = {
-    .fb_create = drm_gem_fb_create,
+    .fb_create = drm_gem_fb_create_with_dirty,
     .atomic_check = drm_atomic_helper_check,
     .atomic_commit = drm_atomic_helper_commit,
 };
@@ -234,6 +353,7 @@ int ipdisp_drm_init(struct ipdisp_device *idev)
     drm->mode_config.max_height = 4320;
     drm->mode_config.preferred_depth = 32;
     drm->mode_config.funcs = &ipdisp_mode_config_funcs;
+    INIT_WORK(&idev->mode_work, ipdisp_mode_work_func);
     
     /* Initialize connector */
     ret = drm_connector_init(drm, &idev->connector, &ipdisp_connector_funcs,
@@ -278,6 +398,8 @@ void ipdisp_drm_cleanup(struct ipdisp_device *idev)
 {
     ipdisp_debug("Cleaning up DRM subsystem\n");
     
+    cancel_work_sync(&idev->mode_work);
+    
     /* DRM managed resources will be cleaned up automatically */
 }
 
@@ -5,24 +5,82 @@
 
 #include "ipdisp.h"
 
+/* Whether the border is up: it blinks while an IDENTIFY lasts */
+static bool ipdisp_encoder_identify_on(struct ipdisp_device *idev)
+{
+    u64 until = READ_ONCE(idev->identify_until);
+    u64 now = ktime_get_ns();
+    
+    return now < until &&
+           !(div64_u64(until - now, IPDISP_IDENTIFY_BLINK_MS * NSEC_PER_MSEC) & 1);
+}
+
+/* The frame with a band around its edge inverted, in identify_buf; NULL
+ * if that can't be allocated. Caller holds fb_lock. */
+static const void *ipdisp_encoder_identify_frame(struct ipdisp_device *idev,
+                                                 size_t frame_size)
+{
+    u32 band = max(min(idev->width, idev->height) / 32, 4U);
+    u32 x, y;
+    __le32 *row;
+    
+    if (!idev->identify_buf) {
+        idev->identify_buf = vmalloc(idev->fb_size);
+        if (!idev->identify_buf)
+            return NULL;
+    }
+    memcpy(idev->identify_buf, idev->framebuffer, frame_size);
+    
+    band = min(band, min(idev->width, idev->height) / 2);
+    for (y = 0; y < idev->height; y++) {
+        row = idev->identify_buf + (size_t)y * idev->pitch;
+        for (x = 0; x < idev->width; x++) {
+            /* Rows in the band whole, the rest at either end */
+            if (y >= band && y < idev->height - band && x == band)
+                x = idev->width - band;
+            row[x] ^= cpu_to_le32(0x00ffffff);
+        }
+    }
+    return idev->identify_buf;
+}
+
 /* Streaming work function */
 static void ipdisp_stream_work_func(struct work_struct *work)
 {
     struct ipdisp_device *idev = container_of(work, struct ipdisp_device, 
                                              stream_work);
+    const void *frame;
+    struct drm_rect whole;
     size_t frame_size;
+    bool identify;
     int ret;
     
     if (!idev->streaming_enabled || !idev->framebuffer)
         return;
     
-    /* Calculate frame size */
-    frame_size = idev->width * idev->height * 4; /* RGBA32 */
-    
     /* Lock framebuffer and send frame */
     mutex_lock(&idev->fb_lock);
     
-    ret = ipdisp_network_send_frame(idev, idev->framebuffer, frame_size);
+    /* Calculate frame size, which changes with the mode */
+    frame_size = idev->width * idev->height * 4; /* RGBA32 */
+    
+    if (idev->resized) {
+        idev->resized = false;
+        ipdisp_network_announce_display(idev);
+    }
+    
+    /* The border coming or going changes the whole frame */
+    frame = idev->framebuffer;
+    identify = ipdisp_encoder_identify_on(idev);
+    if (identify != idev->identify_shown) {
+        drm_rect_init(&whole, 0, 0, idev->width, idev->height);
+        ipdisp_damage_add(&idev->damage, &whole);
+        idev->identify_shown = identify;
+    }
+    if (identify)
+        frame = ipdisp_encoder_identify_frame(idev, frame_size) ?: frame;
+    
+    ret = ipdisp_network_send_frame(idev, frame, frame_size);
     if (ret > 0) {
         ipdisp_debug("Frame sent to %d clients\n", ret);
     }
@@ -30,31 +88,76 @@ static void ipdisp_stream_work_func(struct work_struct *work)
     mutex_unlock(&idev->fb_lock);
 }
 
+/* Send a frame at each blink of an IDENTIFY, and one more to end it */
+static void ipdisp_identify_work_func(struct work_struct *work)
+{
+    struct ipdisp_device *idev = container_of(to_delayed_work(work),
+                                             struct ipdisp_device,
+                                             identify_work);
+    
+    queue_work(idev->stream_wq, &idev->stream_work);
+    if (ktime_get_ns() < READ_ONCE(idev->identify_until))
+        queue_delayed_work(idev->stream_wq, &idev->identify_work,
+                           msecs_to_jiffies(IPDISP_IDENTIFY_BLINK_MS));
+}
+
+/* Flash the output's edge for duration_ms, so whoever asked can tell which
+ * display it is */
+void ipdisp_encoder_identify(struct ipdisp_device *idev, u32 duration_ms)
+{
+    duration_ms = min_t(u32, duration_ms, IPDISP_IDENTIFY_MAX_MS);
+    WRITE_ONCE(idev->identify_until,
+               ktime_get_ns() + (u64)duration_ms * NSEC_PER_MSEC);
+    if (idev->stream_wq)
+        mod_delayed_work(idev->stream_wq, &idev->identify_work, 0);
+}
+
 /* Initialize encoder subsystem */
 int ipdisp_encoder_init(struct ipdisp_device *idev)
 {
+    unsigned int i;
+    
     ipdisp_debug("Initializing encoder subsystem\n");
     
+    /* Buffers for clients that asked for smaller frames */
+    for (i = 0; i < IPDISP_MAX_SCALE_SHIFT; i++) {
+        idev->scaled[i] = vmalloc(idev->fb_size >> (2 * (i + 1)));
+        if (!idev->scaled[i]) {
+            ipdisp_err("Failed to allocate scaled frame buffer\n");
+            goto err_scaled;
+        }
+    }
+    
     /* Create workqueue for streaming */
     idev->stream_wq = alloc_workqueue("ipdisp-stream", 
                                      WQ_UNBOUND | WQ_HIGHPRI, 1);
     if (!idev->stream_wq) {
         ipdisp_err("Failed to create stream workqueue\n");
-        return -ENOMEM;
+        goto err_scaled;
     }
     
     /* Initialize work structure */
     INIT_WORK(&idev->stream_work, ipdisp_stream_work_func);
+    INIT_DELAYED_WORK(&idev->identify_work, ipdisp_identify_work_func);
     
     idev->streaming_enabled = false;
     
     ipdisp_info("Encoder subsystem initialized\n");
     return 0;
+    
+err_scaled:
+    for (i = 0; i < IPDISP_MAX_SCALE_SHIFT; i++) {
+        vfree(idev->scaled[i]);
+        idev->scaled[i] = NULL;
+    }
+    return -ENOMEM;
 }
 
 /* Cleanup encoder subsystem */
 void ipdisp_encoder_cleanup(struct ipdisp_device *idev)
 {
+    unsigned int i, j;
+    
     ipdisp_debug("Cleaning up encoder subsystem\n");
     
     /* Disable streaming */
@@ -62,11 +165,28 @@ void ipdisp_encoder_cleanup(struct ipdisp_device *idev)
     
     /* Destroy workqueue */
     if (idev->stream_wq) {
+        cancel_delayed_work_sync(&idev->identify_work);
         flush_workqueue(idev->stream_wq);
         destroy_workqueue(idev->stream_wq);
         idev->stream_wq = NULL;
     }
     
+    for (i = 0; i < IPDISP_MAX_SCALE_SHIFT; i++) {
+        vfree(idev->scaled[i]);
+        idev->scaled[i] = NULL;
+    }
+    
+    for (i = 0; i <= IPDISP_MAX_SCALE_SHIFT; i++) {
+        for (j = 0; j < IPDISP_PACKED_FORMATS; j++) {
+            vfree(idev->packed[i][j]);
+            idev->packed[i][j] = NULL;
+        }
+    }
+    vfree(idev->damage_buf);
+    idev->damage_buf = NULL;
+    vfree(idev->identify_buf);
+    idev->identify_buf = NULL;
+    
     ipdisp_info("Encoder subsystem cleaned up\n");
 }
 
@@ -77,3 +197,120 @@ void ipdisp_encoder_queue_frame(struct ipdisp_device *idev)
         queue_work(idev->stream_wq, &idev->stream_work);
     }
 }
+
+/* Shrink an RGBA32 frame by 1 << shift in each direction, averaging each
+ * block of pixels. Called with fb_lock held; the result stays valid until
+ * the next call for the same shift. */
+const void *ipdisp_encoder_downscale(struct ipdisp_device *idev,
+                                     const void *frame, unsigned int shift)
+{
+    const u8 *src = frame;
+    u8 *dst = idev->scaled[shift - 1];
+    u32 width = idev->width >> shift;
+    u32 height = idev->height >> shift;
+    u32 block = 1u << shift;
+    u32 x, y, bx, by, c, sum;
+    
+    for (y = 0; y < height; y++) {
+        for (x = 0; x < width; x++) {
+            for (c = 0; c < 4; c++) {
+                sum = 0;
+                for (by = 0; by < block; by++)
+                    for (bx = 0; bx < block; bx++)
+                        sum += src[(y * block + by) * idev->pitch +
+                                   (x * block + bx) * 4 + c];
+                dst[(y * width + x) * 4 + c] = sum >> (2 * shift);
+            }
+        }
+    }
+    
+    return dst;
+}
+
+/* BT.601 limited range, matching what the client converts back with */
+static inline u8 ipdisp_encoder_luma(const u8 *px)
+{
+    return ((66 * px[0] + 129 * px[1] + 25 * px[2] + 128) >> 8) + 16;
+}
+
+static inline u8 ipdisp_encoder_cb(const u8 *px)
+{
+    return ((-38 * px[0] - 74 * px[1] + 112 * px[2] + 128) >> 8) + 128;
+}
+
+static inline u8 ipdisp_encoder_cr(const u8 *px)
+{
+    return ((112 * px[0] - 94 * px[1] - 18 * px[2] + 128) >> 8) + 128;
+}
+
+/* Convert an RGBA32 frame, already scaled by 1 << shift, to RGB565 or NV12
+ * for clients that asked for fewer bits per pixel. Called with fb_lock
+ * held; the result stays valid until the next call for the same shift and
+ * format. Returns NULL if the buffer can't be allocated. */
+const void *ipdisp_encoder_pack(struct ipdisp_device *idev, const void *frame,
+                                u32 pitch, unsigned int shift, u32 format,
+                                size_t *size)
+{
+    const u8 *src = frame;
+    const u8 *px;
+    u32 width = idev->width >> shift;
+    u32 height = idev->height >> shift;
+    u32 chroma_width = DIV_ROUND_UP(width, 2);
+    unsigned int index = format == IPDISP_FORMAT_NV12;
+    u32 max_width = idev->max_width >> shift;
+    u32 max_height = idev->max_height >> shift;
+    size_t buf_size;
+    __le16 *rgb565;
+    u8 *luma, *chroma;
+    u32 x, y;
+    
+    /* Sized for the largest mode at this scale: 2 bytes per pixel for
+     * RGB565; for NV12 a byte per pixel, then a U,V pair per 2x2 block,
+     * counting the blocks an odd width or height leaves half full */
+    if (!idev->packed[shift][index]) {
+        if (index)
+            buf_size = (size_t)max_width * max_height +
+                       2 * (size_t)DIV_ROUND_UP(max_width, 2) *
+                       DIV_ROUND_UP(max_height, 2);
+        else
+            buf_size = (size_t)max_width * max_height * 2;
+        idev->packed[shift][index] = vmalloc(buf_size);
+        if (!idev->packed[shift][index]) {
+            ipdisp_err("Failed to allocate packed frame buffer\n");
+            return NULL;
+        }
+    }
+    
+    if (format == IPDISP_FORMAT_RGB565) {
+        rgb565 = idev->packed[shift][index];
+        for (y = 0; y < height; y++) {
+            for (x = 0; x < width; x++) {
+                px = src + y * pitch + x * 4;
+                rgb565[y * width + x] = cpu_to_le16((px[0] >> 3) << 11 |
+                                                    (px[1] >> 2) << 5 |
+                                                    px[2] >> 3);
+            }
+        }
+        *size = (size_t)width * height * 2;
+        return rgb565;
+    }
+    
+    /* NV12: full-size luma, then a U,V pair per 2x2 block taken from its
+     * top-left pixel */
+    luma = idev->packed[shift][index];
+    chroma = luma + width * height;
+    for (y = 0; y < height; y++) {
+        for (x = 0; x < width; x++) {
+            px = src + y * pitch + x * 4;
+            luma[y * width + x] = ipdisp_encoder_luma(px);
+            if (!(x & 1) && !(y & 1)) {
+                chroma[(y / 2) * chroma_width * 2 + x] = ipdisp_encoder_cb(px);
+                chroma[(y / 2) * chroma_width * 2 + x + 1] =
+                    ipdisp_encoder_cr(px);
+            }
+        }
+    }
+    *size = (size_t)width * height +
+            2 * (size_t)chroma_width * DIV_ROUND_UP(height, 2);
+    return luma;
+}
@@ -0,0 +1,608 @@
+/* IP Display Driver - Virtual Input Devices
+ * Copyright (C) 2024
+ * Licensed under GPL v2
+ *
+ * A client with a touchscreen can ask for TOUCH_DEVICE. We then register a
+ * direct (on-screen) multitouch input device whose axes match the virtual
+ * display, so the desktop maps it onto our output and handles gestures as
+ * it would for a built-in panel. The device stays while any client that
+ * asked for it is connected. Those clients then send TOUCH reports, each
+ * replayed as one input frame.
+ *
+ * A client that captures its pointer sends POINTER reports instead: relative
+ * motion, wheel steps and buttons for a virtual mouse, registered with the
+ * first of them and removed once no client that sent any is connected.
+ * KEY reports from a captured pointer drive a virtual keyboard the same way.
+ *
+ * Only verified clients drive any of them: those that proved a pairing
+ * token or an auth provider's credential. Without require_pairing every
+ * client is let in as authenticated, so that alone says nothing about who
+ * is typing. The input parameter narrows it further: with "confirm" a
+ * client's input is dropped until its id is written to allow_input, with
+ * "off" every client just views. So does a client in the view session mode.
+ *
+ * Unless input_control is "shared", one client at a time holds control and
+ * only its input is replayed. A client takes control with INPUT_CONTROL,
+ * or by sending input while nobody holds it, so clients that never ask
+ * still work. It passes on once the holder releases it, leaves or drops
+ * to the view session mode, or with "steal" when another client takes it.
+ * A lost holder gives it up like any other, even if its session is parked
+ * for resuming, and has to take it again.
+ * Whatever the holder held down is let go first, and every client that
+ * agreed to IPDISP_CAP_INPUT_CONTROL is told who holds control now. A
+ * client that leaves lets go of whatever it held, even while others keep
+ * the devices.
+ */
+
+#include "ipdisp.h"
+
+/* Register the touchscreen, sized to the current display mode */
+static int ipdisp_input_create(struct ipdisp_device *idev, u32 slots)
+{
+    struct input_dev *input;
+    int ret;
+    
+    input = input_allocate_device();
+    if (!input)
+        return -ENOMEM;
+    
+    input->name = "IP Display Touchscreen";
+    input->phys = DRIVER_NAME "/input0";
+    input->id.bustype = BUS_VIRTUAL;
+    input->dev.parent = &idev->pdev->dev;
+    
+    input_set_abs_params(input, ABS_MT_POSITION_X, 0, idev->width - 1, 0, 0);
+    input_set_abs_params(input, ABS_MT_POSITION_Y, 0, idev->height - 1, 0, 0);
+    input_set_abs_params(input, ABS_MT_PRESSURE, 0, IPDISP_TOUCH_MAX_PRESSURE,
+                         0, 0);
+    
+    ret = input_mt_init_slots(input, slots, INPUT_MT_DIRECT);
+    if (ret)
+        goto err_free;
+    
+    ret = input_register_device(input);
+    if (ret)
+        goto err_free;
+    
+    idev->touch = input;
+    idev->touch_slots = slots;
+    ipdisp_info("Virtual touchscreen %ux%u with %u slots registered\n",
+                idev->width, idev->height, slots);
+    return 0;
+    
+err_free:
+    input_free_device(input);
+    ipdisp_err("Failed to register virtual touchscreen: %d\n", ret);
+    return ret;
+}
+
+static void ipdisp_input_destroy(struct ipdisp_device *idev)
+{
+    if (!idev->touch)
+        return;
+    
+    input_unregister_device(idev->touch);
+    idev->touch = NULL;
+    idev->touch_slots = 0;
+    ipdisp_info("Virtual touchscreen removed\n");
+}
+
+/* Register the mouse; relative, so games and CAD views can turn freely */
+static int ipdisp_input_create_mouse(struct ipdisp_device *idev)
+{
+    struct input_dev *input;
+    int ret;
+    
+    input = input_allocate_device();
+    if (!input)
+        return -ENOMEM;
+    
+    input->name = "IP Display Mouse";
+    input->phys = DRIVER_NAME "/input1";
+    input->id.bustype = BUS_VIRTUAL;
+    input->dev.parent = &idev->pdev->dev;
+    
+    input_set_capability(input, EV_REL, REL_X);
+    input_set_capability(input, EV_REL, REL_Y);
+    input_set_capability(input, EV_REL, REL_WHEEL);
+    input_set_capability(input, EV_KEY, BTN_LEFT);
+    input_set_capability(input, EV_KEY, BTN_RIGHT);
+    input_set_capability(input, EV_KEY, BTN_MIDDLE);
+    
+    ret = input_register_device(input);
+    if (ret) {
+        input_free_device(input);
+        ipdisp_err("Failed to register virtual mouse: %d\n", ret);
+        return ret;
+    }
+    
+    idev->mouse = input;
+    ipdisp_info("Virtual mouse registered\n");
+    return 0;
+}
+
+static void ipdisp_input_destroy_mouse(struct ipdisp_device *idev)
+{
+    if (!idev->mouse)
+        return;
+    
+    input_unregister_device(idev->mouse);
+    idev->mouse = NULL;
+    ipdisp_info("Virtual mouse removed\n");
+}
+
+/* Register the keyboard, with every key below the buttons; the input core
+ * repeats held keys itself */
+static int ipdisp_input_create_keyboard(struct ipdisp_device *idev)
+{
+    struct input_dev *input;
+    unsigned int code;
+    int ret;
+    
+    input = input_allocate_device();
+    if (!input)
+        return -ENOMEM;
+    
+    input->name = "IP Display Keyboard";
+    input->phys = DRIVER_NAME "/input2";
+    input->id.bustype = BUS_VIRTUAL;
+    input->dev.parent = &idev->pdev->dev;
+    
+    __set_bit(EV_KEY, input->evbit);
+    __set_bit(EV_REP, input->evbit);
+    for (code = KEY_ESC; code < BTN_MISC; code++)
+        __set_bit(code, input->keybit);
+    
+    ret = input_register_device(input);
+    if (ret) {
+        input_free_device(input);
+        ipdisp_err("Failed to register virtual keyboard: %d\n", ret);
+        return ret;
+    }
+    
+    idev->keyboard = input;
+    ipdisp_info("Virtual keyboard registered\n");
+    return 0;
+}
+
+static void ipdisp_input_destroy_keyboard(struct ipdisp_device *idev)
+{
+    if (!idev->keyboard)
+        return;
+    
+    input_unregister_device(idev->keyboard);
+    idev->keyboard = NULL;
+    ipdisp_info("Virtual keyboard removed\n");
+}
+
+/* Let go of every key, button and touch the devices hold down */
+static void ipdisp_input_release_all(struct ipdisp_device *idev)
+{
+    unsigned int code, slot;
+    
+    if (idev->touch) {
+        for (slot = 0; slot < idev->touch_slots; slot++) {
+            input_mt_slot(idev->touch, slot);
+            input_mt_report_slot_state(idev->touch, MT_TOOL_FINGER, false);
+        }
+        input_mt_sync_frame(idev->touch);
+        input_sync(idev->touch);
+    }
+    if (idev->mouse) {
+        input_report_key(idev->mouse, BTN_LEFT, 0);
+        input_report_key(idev->mouse, BTN_RIGHT, 0);
+        input_report_key(idev->mouse, BTN_MIDDLE, 0);
+        input_sync(idev->mouse);
+    }
+    if (idev->keyboard) {
+        for_each_set_bit(code, idev->keyboard->key, KEY_CNT)
+            input_report_key(idev->keyboard, code, 0);
+        input_sync(idev->keyboard);
+    }
+}
+
+/* Hand input control to a client, or to nobody; clients following control
+ * hear of it on their next poll. Caller holds clients_lock. */
+static void ipdisp_input_set_owner(struct ipdisp_device *idev,
+                                   struct ipdisp_client *owner)
+{
+    struct ipdisp_client *client;
+    
+    ipdisp_input_release_all(idev);
+    if (owner) {
+        idev->control_owner = owner->id;
+        idev->control_owner_addr = owner->addr.sin_addr;
+        ipdisp_info("Client %u (%pI4) has input control\n", owner->id,
+                   &owner->addr.sin_addr);
+    } else {
+        idev->control_owner = 0;
+        idev->control_owner_addr.s_addr = 0;
+        ipdisp_info("Nobody has input control\n");
+    }
+    
+    list_for_each_entry(client, &idev->clients, list) {
+        /* Nobody holds anything down any more */
+        client->touches_down = 0;
+        client->buttons_down = 0;
+        bitmap_zero(client->keys_down, KEY_CNT);
+        if (client->capabilities & IPDISP_CAP_INPUT_CONTROL)
+            client->control_changed = true;
+    }
+}
+
+/* Whether the client has proved a credential, as input needs. One that
+ * was only let in is challenged, which it answers on its own if it has
+ * paired, and its input is dropped meanwhile. Caller holds client->lock;
+ * fails only if the challenge can't be sent. */
+static int ipdisp_input_verify(struct ipdisp_client *client, bool *verified)
+{
+    *verified = client->verified;
+    if (client->verified ||
+        memchr_inv(client->challenge, 0, sizeof(client->challenge)))
+        return 0;
+    
+    ipdisp_warn("Client %pI4 sent input without authenticating\n",
+               &client->addr.sin_addr);
+    return ipdisp_pair_challenge(client);
+}
+
+/* Whether the input parameter lets a verified client drive the devices;
+ * with "confirm" the first refusal says how to allow it */
+static bool ipdisp_input_permitted(struct ipdisp_device *idev,
+                                   struct ipdisp_client *client)
+{
+    if (!client->verified || client->session_mode < IPDISP_SESSION_INPUT)
+        return false;
+    
+    switch (idev->input_policy) {
+    case IPDISP_INPUT_ON:
+        return true;
+    case IPDISP_INPUT_CONFIRM:
+        if (client->input_allowed)
+            return true;
+        if (!client->input_asked) {
+            client->input_asked = true;
+            ipdisp_info("Client %u (%pI4) wants to send input; write %u to allow_input to let it\n",
+                       client->id, &client->addr.sin_addr, client->id);
+        }
+        return false;
+    default:
+        return false;
+    }
+}
+
+/* Whether a permitted client's input is replayed now: everyone's when
+ * control is shared, otherwise the holder's, and input while nobody holds
+ * control claims it. Caller holds clients_lock. */
+static bool ipdisp_input_allowed(struct ipdisp_device *idev,
+                                 struct ipdisp_client *client)
+{
+    if (!ipdisp_input_permitted(idev, client))
+        return false;
+    if (idev->control_policy == IPDISP_CONTROL_POLICY_SHARED ||
+        idev->control_owner == client->id)
+        return true;
+    if (idev->control_owner)
+        return false;
+    
+    ipdisp_input_set_owner(idev, client);
+    return true;
+}
+
+/* Tell a client who holds input control, with 0 or why its INPUT_CONTROL
+ * request was refused; caller holds clients_lock and client->lock */
+int ipdisp_input_send_control(struct ipdisp_device *idev,
+                              struct ipdisp_client *client, int status)
+{
+    struct ipdisp_input_control reply = {
+        .owner = cpu_to_be32(idev->control_owner),
+        .status = cpu_to_be32(status),
+    };
+    u32 flags = 0;
+    
+    if (idev->control_policy == IPDISP_CONTROL_POLICY_SHARED)
+        flags |= IPDISP_CONTROL_SHARED;
+    else if (idev->control_owner == client->id)
+        flags |= IPDISP_CONTROL_YOURS;
+    reply.flags = cpu_to_be32(flags);
+    memcpy(reply.addr, &idev->control_owner_addr, sizeof(reply.addr));
+    
+    client->control_changed = false;
+    return ipdisp_network_send_packet(client, IPDISP_PACKET_INPUT_CONTROL,
+                                      &reply, sizeof(reply));
+}
+
+/* Request, release or steal input control as the input_control parameter
+ * allows, and answer with who holds it now. A refused request gets -EBUSY
+ * while another client holds control, or -EPERM when this one may not
+ * take it. */
+int ipdisp_input_handle_control(struct ipdisp_device *idev,
+                                struct ipdisp_client *client,
+                                const u8 *payload, u32 size)
+{
+    struct ipdisp_client *owner;
+    u32 action;
+    int status = 0;
+    
+    if (size < sizeof(__be32))
+        return -EPROTO;
+    action = be32_to_cpup((const __be32 *)payload);
+    
+    if (idev->control_policy == IPDISP_CONTROL_POLICY_SHARED) {
+        /* Nothing to hand over */
+    } else if (action == IPDISP_CONTROL_RELEASE) {
+        if (idev->control_owner == client->id)
+            ipdisp_input_set_owner(idev, NULL);
+    } else if (action > IPDISP_CONTROL_STEAL) {
+        status = -EINVAL;
+    } else if (!ipdisp_input_permitted(idev, client)) {
+        status = -EPERM;
+    } else if (!idev->control_owner) {
+        ipdisp_input_set_owner(idev, client);
+    } else if (idev->control_owner == client->id) {
+        /* Already holds it */
+    } else if (action == IPDISP_CONTROL_REQUEST) {
+        status = -EBUSY;
+    } else if (idev->control_policy != IPDISP_CONTROL_POLICY_STEAL) {
+        status = -EPERM;
+    } else {
+        list_for_each_entry(owner, &idev->clients, list) {
+            if (owner->id == idev->control_owner)
+                ipdisp_info("Client %u (%pI4) takes input control from client %u (%pI4)\n",
+                           client->id, &client->addr.sin_addr, owner->id,
+                           &owner->addr.sin_addr);
+        }
+        ipdisp_input_set_owner(idev, client);
+    }
+    
+    return ipdisp_input_send_control(idev, client, status);
+}
+
+/* Give up input control if the client with this id holds it, as it
+ * leaves or drops below the input session mode; caller holds clients_lock */
+void ipdisp_input_drop_control(struct ipdisp_device *idev, u32 id)
+{
+    if (idev->control_owner && idev->control_owner == id)
+        ipdisp_input_set_owner(idev, NULL);
+}
+
+/* Advertise the touchscreen to a client asking for it; only verified
+ * clients may drive it */
+int ipdisp_input_handle_request(struct ipdisp_device *idev,
+                                struct ipdisp_client *client,
+                                const u8 *payload, u32 size)
+{
+    __be32 reply[3];
+    bool verified;
+    u32 slots;
+    int ret;
+    
+    if (size < sizeof(__be32))
+        return -EPROTO;
+    
+    ret = ipdisp_input_verify(client, &verified);
+    if (ret < 0 || !verified)
+        return ret;
+    if (!ipdisp_input_permitted(idev, client))
+        return 0;
+    
+    slots = clamp_t(u32, be32_to_cpup((const __be32 *)payload),
+                    1, IPDISP_TOUCH_MAX_SLOTS);
+    
+    if (!idev->touch) {
+        ret = ipdisp_input_create(idev, slots);
+        if (ret)
+            return 0; /* Not fatal, the client just views */
+    }
+    client->touch = true;
+    
+    reply[0] = cpu_to_be32(idev->width);
+    reply[1] = cpu_to_be32(idev->height);
+    reply[2] = cpu_to_be32(idev->touch_slots);
+    return ipdisp_network_send_packet(client, IPDISP_PACKET_TOUCH_DEVICE,
+                                      reply, sizeof(reply));
+}
+
+/* Replay a TOUCH report from a client that was given the touchscreen */
+int ipdisp_input_handle_touch(struct ipdisp_device *idev,
+                              struct ipdisp_client *client,
+                              const u8 *payload, u32 size)
+{
+    const __be32 *words = (const __be32 *)payload;
+    struct input_dev *input = idev->touch;
+    u32 count, i, slot, x, y, pressure;
+    bool verified;
+    int ret;
+    
+    if (size < sizeof(__be32))
+        return -EPROTO;
+    count = be32_to_cpup(words);
+    if (count > IPDISP_TOUCH_MAX_SLOTS ||
+        size < (1 + 4 * count) * sizeof(__be32))
+        return -EPROTO;
+    
+    ret = ipdisp_input_verify(client, &verified);
+    if (ret < 0 || !verified)
+        return ret;
+    if (!client->touch || !input || !ipdisp_input_allowed(idev, client))
+        return 0;
+    
+    for (i = 0; i < count; i++) {
+        slot = be32_to_cpup(words + 1 + 4 * i);
+        x = be32_to_cpup(words + 2 + 4 * i);
+        y = be32_to_cpup(words + 3 + 4 * i);
+        pressure = min_t(u32, be32_to_cpup(words + 4 + 4 * i),
+                         IPDISP_TOUCH_MAX_PRESSURE);
+        if (slot >= idev->touch_slots)
+            continue;
+        
+        input_mt_slot(input, slot);
+        input_mt_report_slot_state(input, MT_TOOL_FINGER, pressure > 0);
+        if (!pressure) {
+            client->touches_down &= ~BIT(slot);
+            continue;
+        }
+        client->touches_down |= BIT(slot);
+        /* The axes keep the mode the device was registered in */
+        input_report_abs(input, ABS_MT_POSITION_X,
+                         min_t(u32, x, input_abs_get_max(input,
+                                                         ABS_MT_POSITION_X)));
+        input_report_abs(input, ABS_MT_POSITION_Y,
+                         min_t(u32, y, input_abs_get_max(input,
+                                                         ABS_MT_POSITION_Y)));
+        input_report_abs(input, ABS_MT_PRESSURE, pressure);
+    }
+    input_mt_sync_frame(input);
+    input_sync(input);
+    return 0;
+}
+
+/* Replay a POINTER report, registering the mouse on the first one; like
+ * the touchscreen, only verified clients may drive it */
+int ipdisp_input_handle_pointer(struct ipdisp_device *idev,
+                                struct ipdisp_client *client,
+                                const u8 *payload, u32 size)
+{
+    const __be32 *words = (const __be32 *)payload;
+    struct input_dev *input;
+    bool verified;
+    u32 buttons;
+    int ret;
+    
+    if (size < 4 * sizeof(__be32))
+        return -EPROTO;
+    
+    ret = ipdisp_input_verify(client, &verified);
+    if (ret < 0 || !verified)
+        return ret;
+    if (!ipdisp_input_allowed(idev, client))
+        return 0;
+    
+    if (!idev->mouse && ipdisp_input_create_mouse(idev))
+        return 0; /* Not fatal, the client just views */
+    client->pointer = true;
+    
+    input = idev->mouse;
+    buttons = be32_to_cpup(words + 3) & (BIT(0) | BIT(1) | BIT(2));
+    client->buttons_down = buttons;
+    input_report_rel(input, REL_X, (s32)be32_to_cpup(words));
+    input_report_rel(input, REL_Y, (s32)be32_to_cpup(words + 1));
+    input_report_rel(input, REL_WHEEL, (s32)be32_to_cpup(words + 2));
+    input_report_key(input, BTN_LEFT, buttons & BIT(0));
+    input_report_key(input, BTN_RIGHT, buttons & BIT(1));
+    input_report_key(input, BTN_MIDDLE, buttons & BIT(2));
+    input_sync(input);
+    return 0;
+}
+
+/* Press or release a key of the virtual keyboard, registering it on the
+ * first KEY report. A press of a key already down is dropped by the input
+ * core, so clients needn't filter their own key repeat. */
+int ipdisp_input_handle_key(struct ipdisp_device *idev,
+                            struct ipdisp_client *client,
+                            const u8 *payload, u32 size)
+{
+    const __be32 *words = (const __be32 *)payload;
+    bool verified, down;
+    u32 code;
+    int ret;
+    
+    if (size < 2 * sizeof(__be32))
+        return -EPROTO;
+    
+    ret = ipdisp_input_verify(client, &verified);
+    if (ret < 0 || !verified)
+        return ret;
+    if (!ipdisp_input_allowed(idev, client))
+        return 0;
+    
+    code = be32_to_cpup(words);
+    if (code < KEY_ESC || code >= BTN_MISC)
+        return 0;
+    
+    if (!idev->keyboard && ipdisp_input_create_keyboard(idev))
+        return 0; /* Not fatal, the client just views */
+    client->keyboard = true;
+    
+    down = be32_to_cpup(words + 1) != 0;
+    if (down)
+        __set_bit(code, client->keys_down);
+    else
+        __clear_bit(code, client->keys_down);
+    input_report_key(idev->keyboard, code, down);
+    input_sync(idev->keyboard);
+    return 0;
+}
+
+/* Let go of whatever this client holds down, as it leaves; another
+ * client may still be using the devices */
+static void ipdisp_input_release_client(struct ipdisp_device *idev,
+                                        struct ipdisp_client *client)
+{
+    unsigned int code, slot;
+    
+    if (idev->touch && client->touches_down) {
+        for (slot = 0; slot < idev->touch_slots; slot++) {
+            if (!(client->touches_down & BIT(slot)))
+                continue;
+            input_mt_slot(idev->touch, slot);
+            input_mt_report_slot_state(idev->touch, MT_TOOL_FINGER, false);
+        }
+        input_mt_sync_frame(idev->touch);
+        input_sync(idev->touch);
+    }
+    if (idev->mouse && client->buttons_down) {
+        if (client->buttons_down & BIT(0))
+            input_report_key(idev->mouse, BTN_LEFT, 0);
+        if (client->buttons_down & BIT(1))
+            input_report_key(idev->mouse, BTN_RIGHT, 0);
+        if (client->buttons_down & BIT(2))
+            input_report_key(idev->mouse, BTN_MIDDLE, 0);
+        input_sync(idev->mouse);
+    }
+    if (idev->keyboard && !bitmap_empty(client->keys_down, KEY_CNT)) {
+        for_each_set_bit(code, client->keys_down, KEY_CNT)
+            input_report_key(idev->keyboard, code, 0);
+        input_sync(idev->keyboard);
+    }
+    
+    client->touches_down = 0;
+    client->buttons_down = 0;
+    bitmap_zero(client->keys_down, KEY_CNT);
+}
+
+/* Remove the touchscreen, mouse and keyboard once no remaining client uses them
+ * (called with clients_lock held, before the client is unlinked) */
+void ipdisp_input_forget_client(struct ipdisp_device *idev,
+                                struct ipdisp_client *client)
+{
+    struct ipdisp_client *other;
+    bool touch = client->touch, pointer = client->pointer;
+    bool keyboard = client->keyboard;
+    
+    ipdisp_input_drop_control(idev, client->id);
+    ipdisp_input_release_client(idev, client);
+    client->touch = false;
+    client->pointer = false;
+    client->keyboard = false;
+    
+    list_for_each_entry(other, &idev->clients, list) {
+        touch &= !other->touch;
+        pointer &= !other->pointer;
+        keyboard &= !other->keyboard;
+    }
+    
+    if (touch)
+        ipdisp_input_destroy(idev);
+    if (pointer)
+        ipdisp_input_destroy_mouse(idev);
+    if (keyboard)
+        ipdisp_input_destroy_keyboard(idev);
+}
+
+void ipdisp_input_cleanup(struct ipdisp_device *idev)
+{
+    ipdisp_input_destroy(idev);
+    ipdisp_input_destroy_mouse(idev);
+    ipdisp_input_destroy_keyboard(idev);
+}
@@ -10,6 +10,27 @@ static unsigned int width = IPDISP_DEFAULT_WIDTH;
 static unsigned int height = IPDISP_DEFAULT_HEIGHT;
 static unsigned int port = IPDISP_DEFAULT_PORT;
 static char *codec = "raw";
+static unsigned int heartbeat_timeout = IPDISP_DEFAULT_HEARTBEAT_TIMEOUT_MS;
+static unsigned int resume_timeout = IPDISP_DEFAULT_RESUME_TIMEOUT_S;
+static bool require_pairing;
+static unsigned int sync_delay;
+static bool supervise;
+static bool mode_requests = true;
+static char *upload_dir;
+static unsigned int upload_max_mb = IPDISP_DEFAULT_UPLOAD_MAX_MB;
+static bool console;
+static bool audio;
+static bool hotplug;
+static bool rate_control = true;
+static unsigned int ack_window = IPDISP_DEFAULT_ACK_WINDOW;
+static char *input = "on";
+static char *session_mode = "full";
+static char *input_control = "shared";
+static char *auth = "pairing";
+static char *auth_token;
+static char *noise_key;
+static char *sign_key;
+static bool require_noise;
 
 module_param(width, uint, 0444);
 MODULE_PARM_DESC(width, "Display width (default: 1920)");
@@ -21,7 +42,106 @@ module_param(port, uint, 0444);
 MODULE_PARM_DESC(port, "Network port (default: 8080)");
 
 module_param(codec, charp, 0444);
-MODULE_PARM_DESC(codec, "Video codec: raw, h264, h265 (default: raw)");
+MODULE_PARM_DESC(codec, "Video codec; only raw is encoded, h264 and h265 are reserved (default: raw)");
+
+module_param(heartbeat_timeout, uint, 0444);
+MODULE_PARM_DESC(heartbeat_timeout, "Drop clients silent for this many ms, 0 = never (default: 5000)");
+
+module_param(resume_timeout, uint, 0444);
+MODULE_PARM_DESC(resume_timeout, "Keep a lost client's session this many seconds for it to resume, up to a day, 0 = never (default: 30)");
+
+module_param(require_pairing, bool, 0444);
+MODULE_PARM_DESC(require_pairing, "Only stream to clients that have authenticated (default: off)");
+
+module_param(auth, charp, 0444);
+MODULE_PARM_DESC(auth, "Comma-separated auth providers: pairing, token (default: pairing)");
+
+module_param(auth_token, charp, 0);
+MODULE_PARM_DESC(auth_token, "Shared secret for the token auth provider");
+
+module_param(noise_key, charp, 0);
+MODULE_PARM_DESC(noise_key, "Noise private key as 64 hex digits; clients that ask encrypt everything (default: none)");
+
+module_param(sign_key, charp, 0);
+MODULE_PARM_DESC(sign_key, "Ed25519 seed for signing content hashes, as 64 hex digits (default: a new one each load)");
+
+module_param(require_noise, bool, 0444);
+MODULE_PARM_DESC(require_noise, "Only stream to clients that encrypt, with noise_key set (default: off)");
+
+module_param(sync_delay, uint, 0444);
+MODULE_PARM_DESC(sync_delay, "Have clients that ask show frames this many ms after capture, in step, 0 = off (default: 0)");
+
+module_param(supervise, bool, 0444);
+MODULE_PARM_DESC(supervise, "Let authenticated clients ask for a fresh frame from the Server menu (default: off)");
+
+module_param(mode_requests, bool, 0444);
+MODULE_PARM_DESC(mode_requests, "Let authenticated clients pick the display mode, up to width x height (default: on)");
+
+module_param(upload_dir, charp, 0444);
+MODULE_PARM_DESC(upload_dir, "Directory files dropped on paired or authenticated clients' windows are written to (default: none, uploads refused)");
+
+module_param(upload_max_mb, uint, 0444);
+MODULE_PARM_DESC(upload_max_mb, "Largest file clients may upload, in MB (default: 100)");
+
+module_param(rate_control, bool, 0444);
+MODULE_PARM_DESC(rate_control, "Lower the frame rate, then the size, of clients whose links fall behind (default: on)");
+
+module_param(ack_window, uint, 0444);
+MODULE_PARM_DESC(ack_window, "Frames a client that acknowledges them may be sent ahead of its acknowledgements, up to 8 (default: 2, 0 = no limit)");
+
+module_param(input, charp, 0444);
+MODULE_PARM_DESC(input, "Which paired or authenticated clients drive the virtual touchscreen, mouse and keyboard: on (all), confirm (those written to allow_input), off (default: on)");
+
+module_param(session_mode, charp, 0444);
+MODULE_PARM_DESC(session_mode, "Most a client may do: view (frames and sound), input (also the input devices and mode requests), full (also uploads and supervision) (default: full)");
+
+module_param(input_control, charp, 0444);
+MODULE_PARM_DESC(input_control, "Who drives the input devices: shared (every client at once), request (one client at a time, the next once it releases control), steal (one at a time, any may take control) (default: shared)");
+
+module_param(hotplug, bool, 0444);
+MODULE_PARM_DESC(hotplug, "Only report the display connected while a client is, so desktops add it as a monitor on connect (default: off)");
+
+module_param(audio, bool, 0444);
+MODULE_PARM_DESC(audio, "Register a sound card whose output is streamed to clients that play audio (default: off)");
+
+module_param(console, bool, 0444);
+MODULE_PARM_DESC(console, "Show the kernel console on the virtual display, for machines without a compositor (default: off)");
+
+/* The input parameter's policy, or -EINVAL */
+static int ipdisp_input_policy(const char *value)
+{
+    if (!strcmp(value, "on"))
+        return IPDISP_INPUT_ON;
+    if (!strcmp(value, "confirm"))
+        return IPDISP_INPUT_CONFIRM;
+    if (!strcmp(value, "off"))
+        return IPDISP_INPUT_OFF;
+    return -EINVAL;
+}
+
+/* The session_mode parameter's mode, or -EINVAL */
+static int ipdisp_session_mode(const char *value)
+{
+    if (!strcmp(value, "view"))
+        return IPDISP_SESSION_VIEW;
+    if (!strcmp(value, "input"))
+        return IPDISP_SESSION_INPUT;
+    if (!strcmp(value, "full"))
+        return IPDISP_SESSION_FULL;
+    return -EINVAL;
+}
+
+/* The input_control parameter's policy, or -EINVAL */
+static int ipdisp_control_policy(const char *value)
+{
+    if (!strcmp(value, "shared"))
+        return IPDISP_CONTROL_POLICY_SHARED;
+    if (!strcmp(value, "request"))
+        return IPDISP_CONTROL_POLICY_REQUEST;
+    if (!strcmp(value, "steal"))
+        return IPDISP_CONTROL_POLICY_STEAL;
+    return -EINVAL;
+}
 
 /* Global device instance */
 static struct ipdisp_device *ipdisp_global_dev;
@@ -48,6 +168,8 @@ static struct platform_device ipdisp_platform_device = {
 static const struct drm_driver ipdisp_drm_driver = {
     .driver_features = DRIVER_MODESET | DRIVER_GEM | DRIVER_ATOMIC,
     .fops = &ipdisp_fops,
+    DRM_GEM_DMA_DRIVER_OPS,
+    IPDISP_FBDEV_DRIVER_OPS
     .name = DRIVER_NAME,
     .desc = DRIVER_DESC,
     .date = DRIVER_DATE,
@@ -64,7 +186,28 @@ static int ipdisp_device_init(struct ipdisp_device *idev)
     /* Initialize device structure */
     idev->width = width;
     idev->height = height;
+    idev->max_width = width;
+    idev->max_height = height;
+    idev->mode_width = width;
+    idev->mode_height = height;
+    idev->mode_refresh = 60;
+    idev->allow_mode_requests = mode_requests;
+    idev->hotplug = hotplug;
     idev->port = port;
+    idev->heartbeat_timeout_ms = heartbeat_timeout;
+    idev->resume_timeout_ms = min_t(u32, resume_timeout, 86400) * MSEC_PER_SEC;
+    idev->require_pairing = require_pairing;
+    idev->sync_delay_ms = sync_delay;
+    idev->allow_supervise = supervise;
+    idev->rate_control = rate_control;
+    idev->ack_window = min_t(u32, ack_window, IPDISP_MAX_ACK_WINDOW);
+    idev->input_policy = ipdisp_input_policy(input);
+    idev->session_mode = ipdisp_session_mode(session_mode);
+    idev->control_policy = ipdisp_control_policy(input_control);
+    idev->upload_dir = upload_dir;
+    idev->upload_max_mb = upload_max_mb;
+    idev->auth_token = auth_token;
+    idev->require_noise = require_noise;
     idev->pitch = width * 4; /* RGBA32 */
     idev->fb_size = idev->pitch * height;
     
@@ -98,6 +241,23 @@ static int ipdisp_device_init(struct ipdisp_device *idev)
         goto err_drm;
     }
     
+    /* Initialize pairing before clients can connect */
+    ret = ipdisp_pair_init(idev);
+    if (ret)
+        goto err_pair;
+    
+    ret = ipdisp_auth_init(idev, auth);
+    if (ret)
+        goto err_auth;
+    
+    ret = ipdisp_noise_init(idev, noise_key);
+    if (ret)
+        goto err_noise;
+    
+    ret = ipdisp_sign_init(idev, sign_key);
+    if (ret)
+        goto err_sign;
+    
     /* Initialize network subsystem */
     ret = ipdisp_network_init(idev);
     if (ret) {
@@ -112,12 +272,24 @@ static int ipdisp_device_init(struct ipdisp_device *idev)
         goto err_encoder;
     }
     
+    /* Not fatal, clients just get no sound */
+    if (audio)
+        ipdisp_audio_init(idev);
+    
     ipdisp_info("Device initialized successfully\n");
     return 0;
     
 err_encoder:
     ipdisp_network_cleanup(idev);
 err_network:
+    ipdisp_sign_cleanup(idev);
+err_sign:
+    ipdisp_noise_cleanup(idev);
+err_noise:
+    ipdisp_auth_cleanup(idev);
+err_auth:
+    ipdisp_pair_cleanup(idev);
+err_pair:
     ipdisp_drm_cleanup(idev);
 err_drm:
     dma_free_coherent(&idev->pdev->dev, idev->fb_size,
@@ -134,8 +306,14 @@ static void ipdisp_device_cleanup(struct ipdisp_device *idev)
     ipdisp_info("Cleaning up device\n");
     
     /* Cleanup subsystems */
+    ipdisp_audio_cleanup(idev);
     ipdisp_encoder_cleanup(idev);
     ipdisp_network_cleanup(idev);
+    ipdisp_input_cleanup(idev);
+    ipdisp_sign_cleanup(idev);
+    ipdisp_noise_cleanup(idev);
+    ipdisp_auth_cleanup(idev);
+    ipdisp_pair_cleanup(idev);
     ipdisp_drm_cleanup(idev);
     
     /* Free framebuffer */
@@ -185,9 +363,19 @@ static int ipdisp_probe(struct platform_device *pdev)
     
     ipdisp_global_dev = idev;
     
+    /* An fbdev for fbcon; its writes reach the pipe as dirty commits */
+    if (console)
+        ipdisp_fbdev_setup(&idev->drm);
+    
     ipdisp_info("IP Display driver loaded successfully\n");
     ipdisp_info("Resolution: %dx%d, Port: %d, Codec: %s\n",
                 width, height, port, codec);
+    /* Hardware encoders are driven through VA-API or NVENC from userspace,
+     * as ipdisp-server does; in here frames can only go out uncompressed
+     * or packed */
+    if (strcmp(codec, "raw"))
+        ipdisp_warn("No %s encoder in the kernel, sending raw frames; ipdisp-server --codec %s encodes\n",
+                    codec, codec);
     
     return 0;
     
@@ -245,6 +433,23 @@ static int __init ipdisp_init(void)
         return -EINVAL;
     }
     
+    if (ipdisp_input_policy(input) < 0) {
+        ipdisp_err("Invalid input: %s (must be on, confirm or off)\n", input);
+        return -EINVAL;
+    }
+    
+    if (ipdisp_session_mode(session_mode) < 0) {
+        ipdisp_err("Invalid session_mode: %s (must be view, input or full)\n",
+                  session_mode);
+        return -EINVAL;
+    }
+    
+    if (ipdisp_control_policy(input_control) < 0) {
+        ipdisp_err("Invalid input_control: %s (must be shared, request or steal)\n",
+                  input_control);
+        return -EINVAL;
+    }
+    
     /* Register platform device */
     ret = platform_device_register(&ipdisp_platform_device);
     if (ret) {
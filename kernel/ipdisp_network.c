@@ -9,6 +9,9 @@
 static int ipdisp_network_send_display_info(struct ipdisp_device *idev,
                                            struct ipdisp_client *client);
 static void ipdisp_network_cleanup_clients(struct ipdisp_device *idev);
+static void ipdisp_network_poll_clients(struct ipdisp_device *idev);
+static void ipdisp_network_rate_control(struct ipdisp_device *idev,
+                                        struct ipdisp_client *client, u64 now);
 
 /* Network thread function */
 static int ipdisp_network_thread(void *data)
@@ -26,6 +29,12 @@ static int ipdisp_network_thread(void *data)
             continue;
         }
         
+        /* Handle requests from connected clients, then remove those found
+         * gone, so a lost session is parked and its input control given up
+         * without waiting for the next connection */
+        ipdisp_network_poll_clients(idev);
+        ipdisp_network_cleanup_clients(idev);
+        
         /* Accept incoming connections */
         ret = kernel_accept(idev->listen_sock, &sock, O_NONBLOCK);
         if (ret < 0) {
@@ -76,17 +85,22 @@ static int ipdisp_network_thread(void *data)
         
         client->sock = sock;
         client->addr = addr;
+        client->id = ++idev->next_client_id;
         client->active = true;
+        client->session_mode = idev->session_mode;
+        client->last_rx_ns = ktime_get_ns();
+        client->last_tx_ns = client->last_rx_ns;
         mutex_init(&client->lock);
         list_add_tail(&client->list, &idev->clients);
+        ipdisp_drm_set_plugged(idev, true);
         
         mutex_unlock(&idev->clients_lock);
         
-        /* Send welcome message with display info */
+        /* Send welcome message with display info, then ask paired-only
+         * servers' clients to authenticate */
         ipdisp_network_send_display_info(idev, client);
-        
-        /* Cleanup inactive clients periodically */
-        ipdisp_network_cleanup_clients(idev);
+        if (ipdisp_pair_send_challenge(idev, client) < 0)
+            client->active = false; /* Mark for cleanup */
     }
     
     ipdisp_info("Network thread stopped\n");
@@ -99,7 +113,6 @@ static int ipdisp_network_send_display_info(struct ipdisp_device *idev,
 {
     struct ipdisp_packet_header header;
     struct kvec iov;
-    struct msghdr msg;
     int ret;
     
     /* Prepare header */
@@ -111,17 +124,14 @@ static int ipdisp_network_send_display_info(struct ipdisp_device *idev,
     header.format = cpu_to_be32(IPDISP_FORMAT_RGBA32);
     header.timestamp = cpu_to_be64(ktime_get_ns());
     header.size = 0; /* No data payload for info packet */
-    header.reserved = 0;
+    header.packet_type = cpu_to_be32(IPDISP_PACKET_DISPLAY);
     
     /* Send header */
     iov.iov_base = &header;
     iov.iov_len = sizeof(header);
     
-    memset(&msg, 0, sizeof(msg));
-    msg.msg_flags = MSG_DONTWAIT | MSG_NOSIGNAL;
-    
     mutex_lock(&client->lock);
-    ret = kernel_sendmsg(client->sock, &msg, &iov, 1, sizeof(header));
+    ret = ipdisp_noise_sendmsg(client, &iov, 1, sizeof(header));
     mutex_unlock(&client->lock);
     
     if (ret != sizeof(header)) {
@@ -129,23 +139,838 @@ static int ipdisp_network_send_display_info(struct ipdisp_device *idev,
         return ret < 0 ? ret : -EIO;
     }
     
+    client->last_tx_ns = ktime_get_ns();
+    return 0;
+}
+
+/* Send every client the display's new size, ahead of the first frame at
+ * it; called with fb_lock held */
+void ipdisp_network_announce_display(struct ipdisp_device *idev)
+{
+    struct ipdisp_client *client;
+    
+    mutex_lock(&idev->clients_lock);
+    list_for_each_entry(client, &idev->clients, list) {
+        if (!client->active || !client->authenticated ||
+            !ipdisp_noise_ready(idev, client))
+            continue;
+        client->damage_ready = false;
+        if (ipdisp_network_send_display_info(idev, client) < 0)
+            client->active = false; /* Mark for cleanup */
+    }
+    mutex_unlock(&idev->clients_lock);
+}
+
+/* Send a chunk of the sound card's output to every client that plays
+ * audio. A client whose socket can't take it all misses it: a late chunk
+ * is no use, and half a packet would end the connection. */
+void ipdisp_network_send_audio(struct ipdisp_device *idev,
+                               const void *payload, u32 size)
+{
+    struct ipdisp_client *client;
+    
+    mutex_lock(&idev->clients_lock);
+    list_for_each_entry(client, &idev->clients, list) {
+        if (!client->active || !client->authenticated ||
+            !ipdisp_noise_ready(idev, client) ||
+            !(client->capabilities & IPDISP_CAP_AUDIO))
+            continue;
+        mutex_lock(&client->lock);
+        if (sk_stream_wspace(client->sock->sk) >=
+            (int)(sizeof(struct ipdisp_packet_header) + size) &&
+            ipdisp_network_send_packet(client, IPDISP_PACKET_AUDIO,
+                                       payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        mutex_unlock(&client->lock);
+    }
+    mutex_unlock(&idev->clients_lock);
+}
+
+/* Send a packet with a small payload; caller holds client->lock */
+int ipdisp_network_send_packet(struct ipdisp_client *client, u32 packet_type,
+                               const void *payload, u32 size)
+{
+    struct ipdisp_packet_header header;
+    struct kvec iov[2];
+    int ret;
+    
+    memset(&header, 0, sizeof(header));
+    header.magic = cpu_to_be32(IPDISP_MAGIC);
+    header.version = cpu_to_be32(IPDISP_VERSION);
+    header.format = cpu_to_be32(IPDISP_FORMAT_RGBA32);
+    header.timestamp = cpu_to_be64(ktime_get_ns());
+    header.size = cpu_to_be32(size);
+    header.packet_type = cpu_to_be32(packet_type);
+    
+    iov[0].iov_base = &header;
+    iov[0].iov_len = sizeof(header);
+    iov[1].iov_base = (void *)payload;
+    iov[1].iov_len = size;
+    
+    ret = ipdisp_noise_sendmsg(client, iov, 2, sizeof(header) + size);
+    if (ret != sizeof(header) + size)
+        return ret < 0 ? ret : -EIO;
+    
+    client->last_tx_ns = ktime_get_ns();
+    return 0;
+}
+
+/* Tell a quiet client we're still here; caller holds client->lock */
+static int ipdisp_network_send_heartbeat(struct ipdisp_client *client)
+{
+    struct ipdisp_packet_header header;
+    struct kvec iov;
+    int ret;
+    
+    memset(&header, 0, sizeof(header));
+    header.magic = cpu_to_be32(IPDISP_MAGIC);
+    header.version = cpu_to_be32(IPDISP_VERSION);
+    header.format = cpu_to_be32(IPDISP_FORMAT_RGBA32);
+    header.timestamp = cpu_to_be64(ktime_get_ns());
+    header.packet_type = cpu_to_be32(IPDISP_PACKET_HEARTBEAT);
+    
+    iov.iov_base = &header;
+    iov.iov_len = sizeof(header);
+    
+    ret = ipdisp_noise_sendmsg(client, &iov, 1, sizeof(header));
+    if (ret != sizeof(header))
+        return ret < 0 ? ret : -EIO;
+    
+    client->last_tx_ns = ktime_get_ns();
+    return 0;
+}
+
+/* Answer a ping with our receive and send times; caller holds client->lock */
+static int ipdisp_network_send_pong(struct ipdisp_client *client,
+                                    u64 client_ns, u64 rx_ns)
+{
+    struct {
+        struct ipdisp_packet_header header;
+        __be64 client_ns;
+        __be64 rx_ns;
+        __be64 tx_ns;
+    } __packed pong;
+    struct kvec iov;
+    int ret;
+    
+    memset(&pong, 0, sizeof(pong));
+    pong.header.magic = cpu_to_be32(IPDISP_MAGIC);
+    pong.header.version = cpu_to_be32(IPDISP_VERSION);
+    pong.header.format = cpu_to_be32(IPDISP_FORMAT_RGBA32);
+    pong.header.size = cpu_to_be32(3 * sizeof(__be64));
+    pong.header.packet_type = cpu_to_be32(IPDISP_PACKET_PONG);
+    pong.client_ns = cpu_to_be64(client_ns);
+    pong.rx_ns = cpu_to_be64(rx_ns);
+    
+    iov.iov_base = &pong;
+    iov.iov_len = sizeof(pong);
+    
+    /* Stamp as late as possible */
+    pong.header.timestamp = cpu_to_be64(ktime_get_ns());
+    pong.tx_ns = pong.header.timestamp;
+    
+    ret = ipdisp_noise_sendmsg(client, &iov, 1, sizeof(pong));
+    if (ret != sizeof(pong)) {
+        ipdisp_debug("Failed to send pong: %d\n", ret);
+        return ret < 0 ? ret : -EIO;
+    }
+    
+    client->last_tx_ns = be64_to_cpu(pong.tx_ns);
     return 0;
 }
 
+/* Publish the playout delay that keeps synchronised clients in step; caller
+ * holds client->lock */
+static int ipdisp_network_send_sync(struct ipdisp_device *idev,
+                                    struct ipdisp_client *client)
+{
+    __be64 delay_ns = cpu_to_be64((u64)idev->sync_delay_ms * NSEC_PER_MSEC);
+    
+    ipdisp_info("Client %pI4 shows frames %u ms after capture\n",
+               &client->addr.sin_addr, idev->sync_delay_ms);
+    return ipdisp_network_send_packet(client, IPDISP_PACKET_SYNC,
+                                      &delay_ns, sizeof(delay_ns));
+}
+
+/* The capabilities this server can use as configured */
+static u32 ipdisp_network_capabilities(struct ipdisp_device *idev)
+{
+    u32 caps = IPDISP_CAPS_ALL;
+    
+    if (!idev->audio)
+        caps &= ~IPDISP_CAP_AUDIO;
+    if (!idev->sync_delay_ms)
+        caps &= ~IPDISP_CAP_SYNC;
+    if (!idev->noise)
+        caps &= ~IPDISP_CAP_NOISE;
+    /* Shared input has no holder to follow */
+    if (idev->control_policy == IPDISP_CONTROL_POLICY_SHARED)
+        caps &= ~IPDISP_CAP_INPUT_CONTROL;
+    if (!idev->resume_timeout_ms)
+        caps &= ~IPDISP_CAP_RESUME;
+    /* Only ipdisp-server's PAM and OIDC providers prompt */
+    caps &= ~IPDISP_CAP_AUTH_PROMPT;
+    return caps;
+}
+
+/* CAPABILITIES as sent, which the Noise prologue covers */
+struct ipdisp_capabilities_packet {
+    struct ipdisp_packet_header header;
+    __be32 caps;
+} __packed;
+
+/* Answer a HELLO with the capabilities agreed, leaving the packet in
+ * packet; caller holds client->lock */
+static int ipdisp_network_send_capabilities(struct ipdisp_client *client,
+                                            struct ipdisp_capabilities_packet *packet)
+{
+    struct kvec iov;
+    int ret;
+    
+    memset(packet, 0, sizeof(*packet));
+    packet->header.magic = cpu_to_be32(IPDISP_MAGIC);
+    packet->header.version = cpu_to_be32(IPDISP_VERSION);
+    packet->header.format = cpu_to_be32(IPDISP_FORMAT_RGBA32);
+    packet->header.timestamp = cpu_to_be64(ktime_get_ns());
+    packet->header.size = cpu_to_be32(sizeof(packet->caps));
+    packet->header.packet_type = cpu_to_be32(IPDISP_PACKET_CAPABILITIES);
+    packet->caps = cpu_to_be32(client->capabilities);
+    
+    iov.iov_base = packet;
+    iov.iov_len = sizeof(*packet);
+    ret = ipdisp_noise_sendmsg(client, &iov, 1, sizeof(*packet));
+    if (ret != sizeof(*packet))
+        return ret < 0 ? ret : -EIO;
+    
+    client->last_tx_ns = ktime_get_ns();
+    return 0;
+}
+
+static const char * const ipdisp_network_session_mode_names[] = {
+    [IPDISP_SESSION_VIEW] = "view",
+    [IPDISP_SESSION_INPUT] = "input",
+    [IPDISP_SESSION_FULL] = "full",
+};
+
+/* Give a client the session mode it asks for, or the most the
+ * session_mode parameter allows, and tell it which; caller holds
+ * client->lock */
+static int ipdisp_network_set_session_mode(struct ipdisp_device *idev,
+                                           struct ipdisp_client *client,
+                                           u32 mode)
+{
+    __be32 reply;
+    
+    /* A mode this side doesn't know gets the least */
+    if (mode > IPDISP_SESSION_FULL)
+        mode = IPDISP_SESSION_VIEW;
+    mode = min_t(u32, mode, idev->session_mode);
+    if (mode != client->session_mode)
+        ipdisp_info("Client %pI4 session mode %s\n", &client->addr.sin_addr,
+                   ipdisp_network_session_mode_names[mode]);
+    client->session_mode = mode;
+    if (mode < IPDISP_SESSION_INPUT)
+        ipdisp_input_drop_control(idev, client->id);
+    
+    reply = cpu_to_be32(mode);
+    return ipdisp_network_send_packet(client, IPDISP_PACKET_SESSION_MODE,
+                                      &reply, sizeof(reply));
+}
+
+/* Tell a client how its SUPERVISE request went; caller holds client->lock */
+static int ipdisp_network_send_supervise_result(struct ipdisp_client *client,
+                                                u32 action, int status,
+                                                const char *message)
+{
+    struct ipdisp_supervise_result result = {
+        .action = cpu_to_be32(action),
+        .status = cpu_to_be32((u32)status),
+    };
+    
+    strscpy_pad(result.message, message, sizeof(result.message));
+    return ipdisp_network_send_packet(client, IPDISP_PACKET_SUPERVISE,
+                                      &result, sizeof(result));
+}
+
+/* Act on a client's SUPERVISE request and report back; caller holds
+ * clients_lock and client->lock */
+static int ipdisp_network_supervise(struct ipdisp_device *idev,
+                                    struct ipdisp_client *client,
+                                    const u8 *payload, u32 size)
+{
+    struct ipdisp_client *other;
+    const char *message;
+    u32 action, arg;
+    int status = 0;
+    
+    if (size < 2 * sizeof(__be32))
+        return 0;
+    action = be32_to_cpup((const __be32 *)payload);
+    arg = be32_to_cpup((const __be32 *)payload + 1);
+    
+    if (!idev->allow_supervise) {
+        status = -EPERM;
+        message = "Supervision is off on this server";
+        goto reply;
+    }
+    if (client->session_mode < IPDISP_SESSION_FULL) {
+        status = -EPERM;
+        message = "Needs the full session mode";
+        goto reply;
+    }
+    
+    /* Being let in without require_pairing isn't enough; ask for proof,
+     * which the client answers on its own, and have it retry */
+    if (!client->verified) {
+        ipdisp_warn("Client %pI4 sent SUPERVISE without authenticating\n",
+                   &client->addr.sin_addr);
+        if (!memchr_inv(client->challenge, 0, sizeof(client->challenge)) &&
+            ipdisp_pair_challenge(client) < 0)
+            return -EIO;
+        status = -EACCES;
+        message = "Not authenticated; try again";
+        goto reply;
+    }
+    
+    switch (action) {
+    case IPDISP_SUPERVISE_RESEND_FRAME:
+        ipdisp_info("Client %pI4 asked for a fresh frame\n", &client->addr.sin_addr);
+        /* Everyone gets a full header and a fresh frame */
+        list_for_each_entry(other, &idev->clients, list) {
+            other->compact_ready = false;
+            other->damage_ready = false;
+            other->frame_pending = true;
+        }
+        ipdisp_encoder_queue_frame(idev);
+        message = "Sent a fresh frame";
+        break;
+    case IPDISP_SUPERVISE_SET_SOURCE:
+        /* The virtual display is the only thing this driver captures */
+        if (arg != 0) {
+            status = -EINVAL;
+            message = "Only source 1 exists";
+        } else {
+            message = "Streaming source 1";
+        }
+        break;
+    case IPDISP_SUPERVISE_ROTATE_LOGS:
+        /* Our messages go to the kernel log, which the system rotates */
+        status = -EOPNOTSUPP;
+        message = "Logs go to the kernel log";
+        break;
+    case IPDISP_SUPERVISE_RESTART_CAPTURE:
+        /* Frames come from the DRM commits themselves, with no capture
+         * backend between them and us to reopen */
+        status = -EOPNOTSUPP;
+        message = "Nothing to restart; use Resend Frame";
+        break;
+    default:
+        status = -EINVAL;
+        message = "Unknown request";
+        break;
+    }
+    
+reply:
+    return ipdisp_network_send_supervise_result(client, action, status,
+                                                message);
+}
+
+/* Take a CROP request, clamped to the display; caller holds clients_lock */
+static void ipdisp_network_set_crop(struct ipdisp_device *idev,
+                                    struct ipdisp_client *client,
+                                    const u8 *payload, u32 size)
+{
+    u32 x, y, width, height;
+    void *buf = NULL;
+    
+    if (size < 4 * sizeof(__be32))
+        return;
+    x = be32_to_cpup((const __be32 *)payload);
+    y = be32_to_cpup((const __be32 *)payload + 1);
+    width = be32_to_cpup((const __be32 *)payload + 2);
+    height = be32_to_cpup((const __be32 *)payload + 3);
+    
+    if (x >= idev->width || y >= idev->height)
+        width = height = 0;
+    width = min(width, idev->width - min(x, idev->width));
+    height = min(height, idev->height - min(y, idev->height));
+    
+    /* A crop covering the whole display is no crop at all */
+    if (width && height && (width < idev->width || height < idev->height)) {
+        buf = vmalloc(array3_size(width, height, 4));
+        if (!buf)
+            ipdisp_warn("No memory to crop for client %pI4\n",
+                       &client->addr.sin_addr);
+    }
+    
+    vfree(client->crop_buf);
+    client->crop_buf = buf;
+    client->crop_x = buf ? x : 0;
+    client->crop_y = buf ? y : 0;
+    client->crop_width = buf ? width : 0;
+    client->crop_height = buf ? height : 0;
+    if (buf)
+        ipdisp_info("Client %pI4 crop: %ux%u at %u,%u\n",
+                   &client->addr.sin_addr, width, height, x, y);
+    else
+        ipdisp_info("Client %pI4 gets the whole display\n",
+                   &client->addr.sin_addr);
+    
+    /* The next frame differs in size, which only a full header can say */
+    client->compact_ready = false;
+    client->frame_pending = true;
+}
+
+/* Average a latency into what a client reported before */
+static u32 ipdisp_network_smooth_us(u32 average, u64 sample_ns)
+{
+    u32 sample = min_t(u64, div_u64(sample_ns, NSEC_PER_USEC), U32_MAX);
+    
+    return average ? average - average / 8 + sample / 8 : sample;
+}
+
+/* Take an ACK: the frames up to the one acknowledged are through, and the
+ * times it arrived and was shown, on our clock as the client estimates it,
+ * give the client's latency; caller holds clients_lock */
+static void ipdisp_network_handle_ack(struct ipdisp_client *client,
+                                      const u8 *payload, u32 size)
+{
+    u64 timestamp, received_ns, presented_ns;
+    u32 acked = 0;
+    
+    if (size < 3 * sizeof(__be64))
+        return;
+    timestamp = be64_to_cpup((const __be64 *)payload);
+    received_ns = be64_to_cpup((const __be64 *)payload + 1);
+    presented_ns = be64_to_cpup((const __be64 *)payload + 2);
+    
+    while (acked < client->unacked && client->unacked_ts[acked] <= timestamp)
+        acked++;
+    client->unacked -= acked;
+    memmove(client->unacked_ts, client->unacked_ts + acked,
+            client->unacked * sizeof(client->unacked_ts[0]));
+    
+    if (received_ns > timestamp)
+        client->receive_latency_us =
+            ipdisp_network_smooth_us(client->receive_latency_us,
+                                     received_ns - timestamp);
+    if (presented_ns > timestamp)
+        client->present_latency_us =
+            ipdisp_network_smooth_us(client->present_latency_us,
+                                     presented_ns - timestamp);
+}
+
+/* Handle a complete request from a client, read at rx_ns; the payload
+ * follows its header */
+static void ipdisp_network_handle_request(struct ipdisp_device *idev,
+                                          struct ipdisp_client *client,
+                                          const struct ipdisp_packet_header *header,
+                                          const u8 *payload, u32 size,
+                                          u64 rx_ns)
+{
+    u32 packet_type = be32_to_cpu(header->packet_type) &
+                      IPDISP_PACKET_TYPE_MASK;
+    struct ipdisp_capabilities_packet caps;
+    struct kvec prologue[3];
+    __be32 index;
+    u32 format;
+    bool paused;
+    
+    switch (packet_type) {
+    case IPDISP_PACKET_HELLO:
+        if (size < sizeof(__be32))
+            break;
+        client->refresh_mhz = be32_to_cpup((const __be32 *)payload);
+        ipdisp_info("Client %pI4 display refresh %u.%03u Hz\n",
+                   &client->addr.sin_addr, client->refresh_mhz / 1000,
+                   client->refresh_mhz % 1000);
+        
+        /* Older clients send the refresh rate only */
+        if (size < 3 * sizeof(__be32))
+            break;
+        client->session_id = be32_to_cpup((const __be32 *)payload + 1);
+        client->link_mode = be32_to_cpup((const __be32 *)payload + 2);
+        if (client->session_id)
+            ipdisp_info("Client %pI4 is a %s link of session %08x\n",
+                       &client->addr.sin_addr,
+                       client->link_mode == IPDISP_LINK_STRIPE ?
+                       "striped" : "failover", client->session_id);
+        
+        if (size < 4 * sizeof(__be32))
+            break;
+        client->capabilities = be32_to_cpup((const __be32 *)payload + 3) &
+                               ipdisp_network_capabilities(idev);
+        if (ipdisp_network_send_capabilities(client, &caps) < 0) {
+            client->active = false; /* Mark for cleanup */
+            break;
+        }
+        if (client->capabilities & IPDISP_CAP_CRC32)
+            ipdisp_info("Client %pI4 wants frame checksums\n",
+                       &client->addr.sin_addr);
+        
+        /* Its handshake follows, once it has read the reply */
+        prologue[0].iov_base = (void *)header;
+        prologue[0].iov_len = sizeof(*header);
+        prologue[1].iov_base = (void *)payload;
+        prologue[1].iov_len = size;
+        prologue[2].iov_base = &caps;
+        prologue[2].iov_len = sizeof(caps);
+        if ((client->capabilities & IPDISP_CAP_NOISE) && !client->noise &&
+            ipdisp_noise_start(idev, client, prologue,
+                               ARRAY_SIZE(prologue)) < 0) {
+            client->active = false; /* Mark for cleanup */
+            break;
+        }
+        if (idev->require_noise && !client->noise)
+            ipdisp_warn("Client %pI4 doesn't encrypt, so gets no frames\n",
+                       &client->addr.sin_addr);
+        
+        if ((client->capabilities & IPDISP_CAP_SYNC) &&
+            ipdisp_network_send_sync(idev, client) < 0)
+            client->active = false; /* Mark for cleanup */
+        
+        /* Sent with the next poll, once the HELLO is answered and any
+         * handshake is done */
+        if (client->capabilities & IPDISP_CAP_INPUT_CONTROL)
+            client->control_changed = true;
+        if (client->capabilities & IPDISP_CAP_RESUME)
+            client->resume_pending = true;
+        
+        /* Older clients keep the mode they were given on connecting */
+        if (size >= 5 * sizeof(__be32) &&
+            ipdisp_network_set_session_mode(idev, client,
+                    be32_to_cpup((const __be32 *)payload + 4)) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_SESSION_MODE:
+        if (size < sizeof(__be32))
+            break;
+        if (ipdisp_network_set_session_mode(idev, client,
+                be32_to_cpup((const __be32 *)payload)) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_PING:
+        if (size < sizeof(__be64))
+            break;
+        /* Part of a pong would leave the stream out of step */
+        if (ipdisp_network_send_pong(client,
+                                     be64_to_cpup((const __be64 *)payload),
+                                     rx_ns) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_HEARTBEAT:
+        /* Only refreshes last_rx_ns */
+        break;
+    case IPDISP_PACKET_GOODBYE:
+        ipdisp_info("Client %pI4 disconnecting\n", &client->addr.sin_addr);
+        client->resumable = false; /* Closed on purpose, so nothing to keep */
+        client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_RESEND:
+        /* Picked up by ipdisp_network_poll_clients */
+        ipdisp_debug("Client %pI4 asked for a resend\n",
+                    &client->addr.sin_addr);
+        client->damage_ready = false;
+        client->frame_pending = true;
+        break;
+    case IPDISP_PACKET_QUALITY:
+        if (size < 3 * sizeof(__be32))
+            break;
+        client->max_kbps = be32_to_cpup((const __be32 *)payload);
+        client->scale_shift = min_t(u32, ilog2(max_t(u32, 1,
+                                    be32_to_cpup((const __be32 *)payload + 1))),
+                                    IPDISP_MAX_SCALE_SHIFT);
+        client->max_fps = be32_to_cpup((const __be32 *)payload + 2);
+        client->format = IPDISP_FORMAT_RGBA32;
+        if (size >= 4 * sizeof(__be32)) {
+            format = be32_to_cpup((const __be32 *)payload + 3);
+            if (format == IPDISP_FORMAT_RGB565 || format == IPDISP_FORMAT_NV12)
+                client->format = format;
+        }
+        ipdisp_info("Client %pI4 quality: 1/%u scale, %u fps max, %u kbit/s max, format %u\n",
+                   &client->addr.sin_addr, 1u << client->scale_shift,
+                   client->max_fps, client->max_kbps, client->format);
+        /* The next frame may differ in format, which only a full header
+         * can say */
+        client->compact_ready = false;
+        client->frame_pending = true;
+        break;
+    case IPDISP_PACKET_IDENTIFY:
+        if (size < sizeof(__be32) || !client->authenticated)
+            break;
+        /* The card number, as in /dev/dri/cardN and the connector's name
+         * in sysfs */
+        index = cpu_to_be32(idev->drm.primary->index);
+        ipdisp_info("Client %pI4 asked to identify card%u\n",
+                   &client->addr.sin_addr, idev->drm.primary->index);
+        ipdisp_encoder_identify(idev, be32_to_cpup((const __be32 *)payload));
+        if (ipdisp_network_send_packet(client, IPDISP_PACKET_IDENTIFY,
+                                       &index, sizeof(index)) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_MODE_REQUEST:
+        if (ipdisp_drm_request_mode(idev, client, payload, size) < 0)
+            client->active = false;
+        break;
+    case IPDISP_PACKET_CROP:
+        ipdisp_network_set_crop(idev, client, payload, size);
+        break;
+    case IPDISP_PACKET_PAUSE:
+        if (size < sizeof(__be32))
+            break;
+        paused = be32_to_cpup((const __be32 *)payload) != 0;
+        if (paused == client->paused)
+            break;
+        client->paused = paused;
+        ipdisp_info("Client %pI4 %s the stream\n", &client->addr.sin_addr,
+                   paused ? "paused" : "resumed");
+        /* The display has likely changed meanwhile */
+        if (!paused)
+            client->frame_pending = true;
+        break;
+    case IPDISP_PACKET_TOUCH_DEVICE:
+        if (ipdisp_input_handle_request(idev, client, payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_TOUCH:
+        if (ipdisp_input_handle_touch(idev, client, payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_POINTER:
+        if (ipdisp_input_handle_pointer(idev, client, payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_KEY:
+        if (ipdisp_input_handle_key(idev, client, payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_INPUT_CONTROL:
+        if (ipdisp_input_handle_control(idev, client, payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_RESUME:
+        if (ipdisp_resume_handle_request(idev, client, payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_FILE_BEGIN:
+        if (ipdisp_upload_handle_begin(idev, client, payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_FILE_DATA:
+        /* Full-size chunks in the clear come through ipdisp_upload_recv
+         * instead */
+        if (ipdisp_upload_handle_data(client, payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_PAIR_COMMIT:
+    case IPDISP_PACKET_PAIR_REVEAL:
+    case IPDISP_PACKET_PAIR_CONFIRM:
+    case IPDISP_PACKET_AUTH:
+        if (ipdisp_pair_handle_request(idev, client, packet_type,
+                                       payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_SUPERVISE:
+        if (ipdisp_network_supervise(idev, client, payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    case IPDISP_PACKET_ACK:
+        ipdisp_network_handle_ack(client, payload, size);
+        break;
+    case IPDISP_PACKET_NOISE:
+        if (ipdisp_noise_handle_request(idev, client, payload, size) < 0)
+            client->active = false; /* Mark for cleanup */
+        break;
+    default:
+        ipdisp_debug("Ignoring client packet type %u\n", packet_type);
+        break;
+    }
+}
+
+/* Read one pending request from a client that encrypts: a record holding
+ * exactly one packet, which may be a full-size FILE_DATA chunk */
+static int ipdisp_network_recv_record(struct ipdisp_device *idev,
+                                      struct ipdisp_client *client)
+{
+    const struct ipdisp_packet_header *header;
+    u32 size, packet_type;
+    u8 *plain;
+    int len;
+    
+    len = ipdisp_noise_recv(client, &plain);
+    if (len <= 0)
+        return len;
+    
+    header = (const struct ipdisp_packet_header *)plain;
+    if (len < sizeof(*header) || be32_to_cpu(header->magic) != IPDISP_MAGIC ||
+        be32_to_cpu(header->size) != len - sizeof(*header)) {
+        ipdisp_warn("Bad record from client %pI4\n", &client->addr.sin_addr);
+        return -EPROTO;
+    }
+    
+    size = be32_to_cpu(header->size);
+    packet_type = be32_to_cpu(header->packet_type) & IPDISP_PACKET_TYPE_MASK;
+    if (size > IPDISP_MAX_REQUEST_SIZE &&
+        !(packet_type == IPDISP_PACKET_FILE_DATA && client->upload &&
+          size <= IPDISP_FILE_DATA_MAX)) {
+        ipdisp_warn("Client request too large: %u bytes\n", size);
+        return -EMSGSIZE;
+    }
+    
+    client->last_rx_ns = ktime_get_ns();
+    ipdisp_network_handle_request(idev, client, header,
+                                  plain + sizeof(*header), size,
+                                  client->last_rx_ns);
+    return 1;
+}
+
+/* Read one pending request from a client without blocking */
+static int ipdisp_network_recv_request(struct ipdisp_device *idev,
+                                       struct ipdisp_client *client)
+{
+    struct {
+        struct ipdisp_packet_header header;
+        u8 payload[IPDISP_MAX_REQUEST_SIZE];
+    } __packed buf;
+    struct kvec iov;
+    struct msghdr msg;
+    size_t total;
+    u32 size;
+    int ret;
+    
+    if (client->noise && client->noise->stage == IPDISP_NOISE_READY)
+        return ipdisp_network_recv_record(idev, client);
+    
+    /* Peek until the whole packet has arrived, then consume it */
+    iov.iov_base = &buf;
+    iov.iov_len = sizeof(buf);
+    memset(&msg, 0, sizeof(msg));
+    ret = kernel_recvmsg(client->sock, &msg, &iov, 1, sizeof(buf),
+                        MSG_DONTWAIT | MSG_PEEK);
+    if (ret == -EAGAIN || ret == -EWOULDBLOCK)
+        return 0;
+    if (ret == 0)
+        return -ECONNRESET;
+    if (ret < 0)
+        return ret;
+    if (ret < sizeof(buf.header))
+        return 0;
+    
+    if (be32_to_cpu(buf.header.magic) != IPDISP_MAGIC) {
+        ipdisp_warn("Bad magic from client %pI4\n", &client->addr.sin_addr);
+        return -EPROTO;
+    }
+    
+    size = be32_to_cpu(buf.header.size);
+    if (size > IPDISP_MAX_REQUEST_SIZE &&
+        (be32_to_cpu(buf.header.packet_type) & IPDISP_PACKET_TYPE_MASK) ==
+        IPDISP_PACKET_FILE_DATA && client->upload)
+        return ipdisp_upload_recv(client, size);
+    if (size > IPDISP_MAX_REQUEST_SIZE) {
+        ipdisp_warn("Client request too large: %u bytes\n", size);
+        return -EMSGSIZE;
+    }
+    
+    total = sizeof(buf.header) + size;
+    if (ret < total)
+        return 0;
+    
+    iov.iov_base = &buf;
+    iov.iov_len = total;
+    memset(&msg, 0, sizeof(msg));
+    ret = kernel_recvmsg(client->sock, &msg, &iov, 1, total, MSG_DONTWAIT);
+    if (ret != total)
+        return ret < 0 ? ret : -EIO;
+    
+    client->last_rx_ns = ktime_get_ns();
+    ipdisp_network_handle_request(idev, client, &buf.header, buf.payload,
+                                  size, client->last_rx_ns);
+    return 1;
+}
+
+/* Drain pending client requests, keep connections alive and retry frames
+ * held back by pacing */
+static void ipdisp_network_poll_clients(struct ipdisp_device *idev)
+{
+    struct ipdisp_client *client;
+    u64 now, timeout_ns, interval_ns;
+    bool resend = false, connected = false;
+    int ret;
+    
+    timeout_ns = (u64)idev->heartbeat_timeout_ms * NSEC_PER_MSEC;
+    interval_ns = (u64)IPDISP_HEARTBEAT_INTERVAL_MS * NSEC_PER_MSEC;
+    
+    mutex_lock(&idev->clients_lock);
+    
+    list_for_each_entry(client, &idev->clients, list) {
+        if (!client->active)
+            continue;
+        
+        mutex_lock(&client->lock);
+        do {
+            ret = ipdisp_network_recv_request(idev, client);
+        } while (ret > 0 && client->active);
+        
+        /* Input control changed hands, maybe with this client's input */
+        if (ret == 0 && client->control_changed &&
+            ipdisp_noise_ready(idev, client))
+            ret = ipdisp_input_send_control(idev, client, 0);
+        if (ret == 0 && client->resume_pending &&
+            ipdisp_noise_ready(idev, client))
+            ret = ipdisp_resume_poll(idev, client);
+        
+        now = ktime_get_ns();
+        if (ret == 0 && timeout_ns && now - client->last_rx_ns > timeout_ns) {
+            ipdisp_warn("Client %pI4 silent for %u ms, dropping\n",
+                       &client->addr.sin_addr, idev->heartbeat_timeout_ms);
+            ret = -ETIMEDOUT;
+        } else if (ret == 0 && now - client->last_tx_ns >= interval_ns) {
+            ret = ipdisp_network_send_heartbeat(client);
+        }
+        mutex_unlock(&client->lock);
+        
+        if (ret < 0) {
+            ipdisp_debug("Client connection failed: %d\n", ret);
+            client->active = false; /* Mark for cleanup */
+            continue;
+        }
+        
+        ipdisp_network_rate_control(idev, client, now);
+        connected = true;
+        if (client->frame_pending && !client->paused)
+            resend = true;
+    }
+    
+    /* The last client gone unplugs the output */
+    ipdisp_drm_set_plugged(idev, connected);
+    mutex_unlock(&idev->clients_lock);
+    
+    /* Make sure the latest frame eventually reaches paced clients */
+    if (resend)
+        ipdisp_encoder_queue_frame(idev);
+}
+
 /* Remove inactive clients */
 static void ipdisp_network_cleanup_clients(struct ipdisp_device *idev)
 {
     struct ipdisp_client *client, *tmp;
     
     mutex_lock(&idev->clients_lock);
+    ipdisp_resume_expire(idev);
     
     list_for_each_entry_safe(client, tmp, &idev->clients, list) {
         if (!client->active) {
             ipdisp_debug("Removing inactive client\n");
+            ipdisp_resume_park(idev, client);
+            ipdisp_pair_forget_client(idev, client);
+            ipdisp_input_forget_client(idev, client);
+            ipdisp_upload_forget_client(client);
+            ipdisp_noise_forget_client(client);
             list_del(&client->list);
             if (client->sock)
                 sock_release(client->sock);
             mutex_destroy(&client->lock);
+            vfree(client->crop_buf);
             kfree(client);
         }
     }
@@ -153,73 +978,750 @@ static void ipdisp_network_cleanup_clients(struct ipdisp_device *idev)
     mutex_unlock(&idev->clients_lock);
 }
 
+/* Whether this frame goes over this client's connection. Plain clients get
+ * every frame; an aggregated session gets each frame over one of its live
+ * links. Caller holds clients_lock. */
+static bool ipdisp_network_link_selected(struct ipdisp_device *idev,
+                                         struct ipdisp_client *client)
+{
+    struct ipdisp_client *other;
+    unsigned int links = 0, position = 0;
+    u64 seq = idev->frame_seq;
+    
+    if (!client->session_id)
+        return true;
+    
+    list_for_each_entry(other, &idev->clients, list) {
+        if (!other->active || other->session_id != client->session_id)
+            continue;
+        if (other == client)
+            position = links;
+        links++;
+    }
+    
+    if (client->link_mode == IPDISP_LINK_STRIPE)
+        return do_div(seq, links) == position;
+    
+    return position == 0;
+}
+
+/* Pace all links of a session together, as one display */
+static void ipdisp_network_mark_session_sent(struct ipdisp_device *idev,
+                                             struct ipdisp_client *client,
+                                             u64 now)
+{
+    struct ipdisp_client *other;
+    
+    if (!client->session_id)
+        return;
+    
+    list_for_each_entry(other, &idev->clients, list) {
+        if (other->session_id == client->session_id) {
+            other->last_frame_ns = now;
+            other->frame_pending = false;
+        }
+    }
+}
+
+/* The current frame at one scale and format, built for the first client
+ * wanting it */
+struct ipdisp_frame_variant {
+    struct {
+        struct ipdisp_packet_header header;
+        __be32 crc32;
+    } __packed crc_header;
+    struct ipdisp_packet_header header;
+    const void *data;
+    size_t size;
+    __be32 crc32;
+    u8 digest[SHA256_DIGEST_SIZE];
+    bool ready;
+    bool crc_ready;
+    bool digest_ready;
+};
+
+/* Compact frame header, with room for the CRC extension */
+struct ipdisp_compact_header {
+    __be32 marker_delta; /* Marker and flags, then the timestamp step */
+    __be32 size;
+    __be32 crc32;
+} __packed;
+
+/* DAMAGE header, with room for the CRC extension, and its rectangle */
+struct ipdisp_damage_packet {
+    struct ipdisp_packet_header header;
+    __be32 crc32;
+    struct ipdisp_damage rect;
+} __packed;
+
+/* Index of a format among a scale's variants: native RGBA32 first, then
+ * the packed formats in the order of idev->packed */
+static unsigned int ipdisp_network_format_index(u32 format)
+{
+    switch (format) {
+    case IPDISP_FORMAT_RGB565:
+        return 1;
+    case IPDISP_FORMAT_NV12:
+        return 2;
+    default:
+        return 0;
+    }
+}
+
+static void ipdisp_network_fill_variant(struct ipdisp_frame_variant *variant,
+                                        u32 width, u32 height, u32 format,
+                                        const void *data, size_t size, u64 now)
+{
+    memset(&variant->header, 0, sizeof(variant->header));
+    variant->header.magic = cpu_to_be32(IPDISP_MAGIC);
+    variant->header.version = cpu_to_be32(IPDISP_VERSION);
+    variant->header.width = cpu_to_be32(width);
+    variant->header.height = cpu_to_be32(height);
+    variant->header.format = cpu_to_be32(format);
+    variant->header.timestamp = cpu_to_be64(now);
+    variant->header.size = cpu_to_be32(size);
+    variant->header.packet_type = cpu_to_be32(IPDISP_PACKET_DISPLAY);
+    variant->data = data;
+    variant->size = size;
+    variant->ready = true;
+}
+
+static void ipdisp_network_prepare_variant(struct ipdisp_device *idev,
+                                           struct ipdisp_frame_variant *variant,
+                                           unsigned int shift, u32 format,
+                                           const void *data, size_t size,
+                                           u64 now)
+{
+    u32 width = idev->width >> shift;
+    u32 height = idev->height >> shift;
+    u32 pitch = idev->pitch;
+    const void *packed;
+    size_t packed_size;
+    
+    if (shift) {
+        data = ipdisp_encoder_downscale(idev, data, shift);
+        size = (size_t)width * height * 4;
+        pitch = width * 4;
+    }
+    
+    /* Fall back to RGBA32 if the packed buffer can't be had */
+    if (format != IPDISP_FORMAT_RGBA32) {
+        packed = ipdisp_encoder_pack(idev, data, pitch, shift, format,
+                                     &packed_size);
+        if (packed) {
+            data = packed;
+            size = packed_size;
+        } else {
+            format = IPDISP_FORMAT_RGBA32;
+        }
+    }
+    
+    ipdisp_network_fill_variant(variant, width, height, format, data, size,
+                                now);
+}
+
+/* This client's crop of the frame, if it asked for one that still fits
+ * the display */
+static bool ipdisp_network_prepare_crop(struct ipdisp_device *idev,
+                                        struct ipdisp_client *client,
+                                        struct ipdisp_frame_variant *variant,
+                                        const void *data, u64 now)
+{
+    u32 width = client->crop_width;
+    u32 height = client->crop_height;
+    size_t row_size = (size_t)width * 4;
+    const u8 *src;
+    u8 *dst = client->crop_buf;
+    u32 row;
+    
+    /* The mode may have shrunk since the request */
+    if (!dst || client->crop_x + width > idev->width ||
+        client->crop_y + height > idev->height)
+        return false;
+    
+    src = (const u8 *)data + (size_t)client->crop_y * idev->pitch +
+          (size_t)client->crop_x * 4;
+    for (row = 0; row < height; row++)
+        memcpy(dst + row * row_size, src + (size_t)row * idev->pitch,
+               row_size);
+    
+    memset(variant, 0, sizeof(*variant));
+    ipdisp_network_fill_variant(variant, width, height, IPDISP_FORMAT_RGBA32,
+                                dst, row_size * height, now);
+    return true;
+}
+
+/* Checksum once per frame variant, and only if someone asked for it */
+static __be32 ipdisp_network_variant_crc(struct ipdisp_frame_variant *variant)
+{
+    if (!variant->crc_ready) {
+        variant->crc32 = cpu_to_be32(~crc32_le(~0, variant->data,
+                                               variant->size));
+        variant->crc_ready = true;
+    }
+    return variant->crc32;
+}
+
+/* Send the client a content hash ahead of this frame if one is due;
+ * caller holds clients_lock and client->lock, after the frame's header is
+ * chosen so compact_ts is the timestamp the client will see */
+static void ipdisp_network_send_content_hash(struct ipdisp_device *idev,
+                                             struct ipdisp_client *client,
+                                             struct ipdisp_frame_variant *variant,
+                                             u64 now)
+{
+    if (!(client->capabilities & IPDISP_CAP_CONTENT_HASH) ||
+        now - client->last_hash_ns <
+        (u64)IPDISP_CONTENT_HASH_INTERVAL_MS * NSEC_PER_MSEC)
+        return;
+    
+    /* Hashed once per frame variant */
+    if (!variant->digest_ready) {
+        if (crypto_shash_tfm_digest(idev->pair_sha256, variant->data,
+                                    variant->size, variant->digest))
+            return;
+        variant->digest_ready = true;
+    }
+    
+    if (!ipdisp_pair_send_content_hash(idev, client, client->compact_ts,
+                                       variant->digest))
+        client->last_hash_ns = now;
+}
+
+/* Fill in a compact header for this client if it takes them and the frame
+ * can be described relative to its previous one; otherwise note that a
+ * full header is going out */
+static bool ipdisp_network_compact_header(struct ipdisp_client *client,
+                                          struct ipdisp_frame_variant *variant,
+                                          u64 now,
+                                          struct ipdisp_compact_header *compact)
+{
+    u32 width = be32_to_cpu(variant->header.width);
+    u32 height = be32_to_cpu(variant->header.height);
+    u64 delta_us = 0;
+    u8 marker = IPDISP_COMPACT_MARKER;
+    
+    if (client->compact_ready)
+        delta_us = div_u64(now - client->compact_ts, NSEC_PER_USEC);
+    
+    if (!(client->capabilities & IPDISP_CAP_COMPACT_HEADER) ||
+        !client->compact_ready ||
+        client->compact_width != width || client->compact_height != height ||
+        client->sent_format != be32_to_cpu(variant->header.format) ||
+        client->compact_count >= IPDISP_KEYFRAME_INTERVAL ||
+        delta_us > IPDISP_COMPACT_MAX_DELTA_US) {
+        client->compact_ready = true;
+        client->compact_width = width;
+        client->compact_height = height;
+        client->compact_ts = now;
+        client->compact_count = 0;
+        return false;
+    }
+    
+    if (client->capabilities & IPDISP_CAP_CRC32)
+        marker |= IPDISP_COMPACT_FLAG_CRC32;
+    compact->marker_delta = cpu_to_be32((u32)marker << 24 | (u32)delta_us);
+    compact->size = cpu_to_be32(variant->size);
+    
+    /* Track the timestamp the client will compute, so rounding doesn't
+     * accumulate */
+    client->compact_ts += delta_us * NSEC_PER_USEC;
+    client->compact_count++;
+    return true;
+}
+
+/* Warn the client when this frame is in another format than the last one
+ * it got, so it can get ready without dropping the connection; caller
+ * holds client->lock */
+static void ipdisp_network_announce_format(struct ipdisp_client *client,
+                                           struct ipdisp_frame_variant *variant)
+{
+    struct ipdisp_format_announce announce = {
+        .format = variant->header.format,
+        .width = variant->header.width,
+        .height = variant->header.height,
+    };
+    u32 format = be32_to_cpu(variant->header.format);
+    
+    if (format == client->sent_format)
+        return;
+    client->sent_format = format;
+    if (!(client->capabilities & IPDISP_CAP_FORMAT_ANNOUNCE))
+        return;
+    
+    ipdisp_info("Client %pI4 now gets format %u\n",
+               &client->addr.sin_addr, format);
+    if (ipdisp_network_send_packet(client, IPDISP_PACKET_FORMAT,
+                                   &announce, sizeof(announce)) < 0)
+        ipdisp_debug("Failed to announce format to client\n");
+}
+
+/* Bytes of DAMAGE this client would get instead of the whole frame, or 0
+ * when it gets the frame: it must take DAMAGE, have the previous whole
+ * RGBA32 frame, and save at least half of it. Aggregated sessions spread
+ * frames over links, so no one link has the previous frame. */
+static size_t ipdisp_network_damage_size(struct ipdisp_device *idev,
+                                         struct ipdisp_client *client)
+{
+    u64 area = (u64)drm_rect_width(&client->damage) *
+               drm_rect_height(&client->damage);
+    
+    if (!(client->capabilities & IPDISP_CAP_DAMAGE) ||
+        !client->damage_ready || client->session_id ||
+        area * 2 > (u64)idev->width * idev->height)
+        return 0;
+    return sizeof(struct ipdisp_damage) + area * 4;
+}
+
+/* Copy the client's damage out of the frame and describe it in packet;
+ * iov[0] to iov[2] get the header, the rectangle and the rows. Caller holds fb_lock and
+ * clients_lock, which keep damage_buf to this client. */
+static bool ipdisp_network_prepare_damage(struct ipdisp_device *idev,
+                                          struct ipdisp_client *client,
+                                          struct ipdisp_damage_packet *packet,
+                                          const void *data, u64 now,
+                                          struct kvec *iov)
+{
+    struct drm_rect *rect = &client->damage;
+    u32 width = drm_rect_width(rect), height = drm_rect_height(rect);
+    size_t row_size = (size_t)width * 4, size = row_size * height;
+    bool crc = client->capabilities & IPDISP_CAP_CRC32;
+    const u8 *src = data;
+    u8 *dst;
+    u32 row, type = IPDISP_PACKET_DAMAGE;
+    
+    if (!idev->damage_buf) {
+        idev->damage_buf = vmalloc(idev->fb_size);
+        if (!idev->damage_buf)
+            return false;
+    }
+    
+    dst = idev->damage_buf;
+    src += (size_t)rect->y1 * idev->pitch + (size_t)rect->x1 * 4;
+    for (row = 0; row < height; row++)
+        memcpy(dst + row * row_size, src + (size_t)row * idev->pitch,
+               row_size);
+    
+    packet->rect.x = cpu_to_be32(width ? rect->x1 : 0);
+    packet->rect.y = cpu_to_be32(height ? rect->y1 : 0);
+    packet->rect.width = cpu_to_be32(width);
+    packet->rect.height = cpu_to_be32(height);
+    if (crc) {
+        type |= IPDISP_PACKET_FLAG_CRC32;
+        packet->crc32 = cpu_to_be32(~crc32_le(crc32_le(~0, (u8 *)&packet->rect,
+                                                       sizeof(packet->rect)),
+                                              dst, size));
+    }
+    
+    memset(&packet->header, 0, sizeof(packet->header));
+    packet->header.magic = cpu_to_be32(IPDISP_MAGIC);
+    packet->header.version = cpu_to_be32(IPDISP_VERSION);
+    packet->header.width = cpu_to_be32(idev->width);
+    packet->header.height = cpu_to_be32(idev->height);
+    packet->header.format = cpu_to_be32(IPDISP_FORMAT_RGBA32);
+    packet->header.timestamp = cpu_to_be64(now);
+    packet->header.size = cpu_to_be32(sizeof(packet->rect) + size);
+    packet->header.packet_type = cpu_to_be32(type);
+    
+    iov[0].iov_base = packet;
+    iov[0].iov_len = sizeof(packet->header) + (crc ? sizeof(packet->crc32) : 0);
+    iov[1].iov_base = &packet->rect;
+    iov[1].iov_len = sizeof(packet->rect);
+    iov[2].iov_base = dst;
+    iov[2].iov_len = size;
+    return true;
+}
+
+/* Frame rate cap and extra downscaling at each backoff level */
+static const struct {
+    u32 fps;
+    u32 shift;
+} ipdisp_network_backoff[IPDISP_MAX_BACKOFF + 1] = {
+    { 0, 0 }, { 30, 0 }, { 15, 0 }, { 15, 1 }, { 10, 2 },
+};
+
+/* Scale frames go to this client at: what it asked for, or smaller while
+ * it is backed off */
+static u32 ipdisp_network_scale_shift(struct ipdisp_client *client)
+{
+    return max(client->scale_shift,
+               ipdisp_network_backoff[client->backoff].shift);
+}
+
+/* Once an interval, back off a level if frames had to be skipped, or
+ * recover one after enough clean intervals; caller holds clients_lock */
+static void ipdisp_network_rate_control(struct ipdisp_device *idev,
+                                        struct ipdisp_client *client, u64 now)
+{
+    u32 backoff = client->backoff;
+    
+    if (!idev->rate_control ||
+        now - client->backoff_ns < (u64)IPDISP_RATE_INTERVAL_MS * NSEC_PER_MSEC)
+        return;
+    client->backoff_ns = now;
+    
+    if (client->backlogged) {
+        client->clean_intervals = 0;
+        backoff = min(backoff + 1, (u32)IPDISP_MAX_BACKOFF);
+    } else if (backoff &&
+               ++client->clean_intervals >= IPDISP_RATE_RECOVER_INTERVALS) {
+        client->clean_intervals = 0;
+        backoff--;
+    }
+    client->backlogged = false;
+    if (backoff == client->backoff)
+        return;
+    
+    client->backoff = backoff;
+    ipdisp_info("Client %pI4 backoff %u: %u fps max, 1/%u scale\n",
+                &client->addr.sin_addr, backoff,
+                ipdisp_network_backoff[backoff].fps,
+                1u << ipdisp_network_scale_shift(client));
+    /* The next frame may differ in size, which only a full header can say */
+    client->compact_ready = false;
+    client->frame_pending = true;
+}
+
+/* Shortest gap between frames this client accepts: its display refresh,
+ * its frame rate cap or backoff, and the time its bit rate cap needs for
+ * one frame */
+static u64 ipdisp_network_frame_interval(struct ipdisp_client *client,
+                                         size_t size)
+{
+    u32 backoff_fps = ipdisp_network_backoff[client->backoff].fps;
+    u64 interval = 0;
+    
+    if (client->refresh_mhz)
+        interval = div_u64(NSEC_PER_SEC * 1000ULL, client->refresh_mhz);
+    if (client->max_fps)
+        interval = max(interval, div_u64(NSEC_PER_SEC, client->max_fps));
+    if (backoff_fps)
+        interval = max(interval, div_u64(NSEC_PER_SEC, backoff_fps));
+    if (client->max_kbps)
+        interval = max(interval, div_u64((u64)size * 8 * USEC_PER_SEC,
+                                         client->max_kbps));
+    return interval;
+}
+
+/* Whether a client that acknowledges frames is ack_window frames ahead of
+ * its ACKs, and gets no more until it catches up. Aggregated sessions
+ * acknowledge on one link what came over another, so they aren't held. */
+static bool ipdisp_network_awaiting_ack(struct ipdisp_device *idev,
+                                        struct ipdisp_client *client, u64 now)
+{
+    if (!idev->ack_window || !(client->capabilities & IPDISP_CAP_ACK) ||
+        client->session_id || client->unacked < idev->ack_window)
+        return false;
+    
+    if (now - client->last_frame_ns >=
+        (u64)IPDISP_ACK_TIMEOUT_MS * NSEC_PER_MSEC) {
+        client->unacked = 0;
+        return false;
+    }
+    return true;
+}
+
+/* Note a frame sent to a client that acknowledges them, stamped as the
+ * client sees it */
+static void ipdisp_network_expect_ack(struct ipdisp_client *client,
+                                      u64 timestamp)
+{
+    if (client->unacked == IPDISP_MAX_ACK_WINDOW) {
+        client->unacked--;
+        memmove(client->unacked_ts, client->unacked_ts + 1,
+                client->unacked * sizeof(client->unacked_ts[0]));
+    }
+    client->unacked_ts[client->unacked++] = timestamp;
+}
+
 /* Send frame data to all clients */
 int ipdisp_network_send_frame(struct ipdisp_device *idev, 
                              const void *data, size_t size)
 {
     struct ipdisp_client *client;
-    struct ipdisp_packet_header header;
-    struct kvec iov[2];
-    struct msghdr msg;
-    int ret, clients_sent = 0;
+    struct ipdisp_frame_variant
+        variants[IPDISP_MAX_SCALE_SHIFT + 1][IPDISP_PACKED_FORMATS + 1] = {};
+    struct ipdisp_frame_variant *variant, cropped;
+    struct ipdisp_compact_header compact;
+    struct ipdisp_damage_packet damage;
+    bool crc, whole;
+    struct kvec iov[3];
+    size_t total, damage_size, iov_count;
+    u32 shift;
+    u64 now, interval, sent_ts;
+    int ret, clients_sent = 0, clients_paced = 0, clients_paused = 0;
     
     if (list_empty(&idev->clients))
         return 0;
     
-    /* Prepare header */
-    memset(&header, 0, sizeof(header));
-    header.magic = cpu_to_be32(IPDISP_MAGIC);
-    header.version = cpu_to_be32(IPDISP_VERSION);
-    header.width = cpu_to_be32(idev->width);
-    header.height = cpu_to_be32(idev->height);
-    header.format = cpu_to_be32(IPDISP_FORMAT_RGBA32);
-    header.timestamp = cpu_to_be64(ktime_get_ns());
-    header.size = cpu_to_be32(size);
-    header.reserved = 0;
-    
-    /* Prepare message */
-    iov[0].iov_base = &header;
-    iov[0].iov_len = sizeof(header);
-    iov[1].iov_base = (void *)data;
-    iov[1].iov_len = size;
-    
-    memset(&msg, 0, sizeof(msg));
-    msg.msg_flags = MSG_DONTWAIT | MSG_NOSIGNAL;
+    now = ktime_get_ns();
     
     /* Send to all active clients */
     mutex_lock(&idev->clients_lock);
     
+    /* Each client collects what changed until it is next sent a frame */
+    list_for_each_entry(client, &idev->clients, list)
+        ipdisp_damage_add(&client->damage, &idev->damage);
+    drm_rect_init(&idev->damage, 0, 0, 0, 0);
+    
     list_for_each_entry(client, &idev->clients, list) {
-        if (!client->active)
+        if (!client->active || !client->authenticated ||
+            !ipdisp_noise_ready(idev, client) ||
+            !ipdisp_network_link_selected(idev, client))
             continue;
+        
+        /* Not even scaled or packed for a paused client */
+        if (client->paused) {
+            clients_paused++;
+            continue;
+        }
+        
+        /* Nor for one that hasn't shown the frames it has; it gets the
+         * next once it acknowledges them, rather than a queue of frames
+         * in its socket, and backs off if that keeps happening */
+        if (ipdisp_network_awaiting_ack(idev, client, now)) {
+            client->backlogged = true;
+            client->frame_pending = true;
+            clients_paced++;
+            continue;
+        }
+        
+        /* Crop, or scale and pack, first, as the bit rate cap depends on
+         * the frame size. Crops are per client and ignore the scale and
+         * format of a quality request. */
+        if (ipdisp_network_prepare_crop(idev, client, &cropped, data, now)) {
+            variant = &cropped;
+        } else {
+            shift = ipdisp_network_scale_shift(client);
+            variant = &variants[shift]
+                               [ipdisp_network_format_index(client->format)];
+            if (!variant->ready)
+                ipdisp_network_prepare_variant(idev, variant, shift,
+                                               client->format, data, size,
+                                               now);
+        }
+        
+        /* Only the whole RGBA32 frame can be sent as damage */
+        whole = variant == &variants[0][0];
+        damage_size = whole ? ipdisp_network_damage_size(idev, client) : 0;
+        
+        /* Don't send faster than the client's display can show or its
+         * quality request allows */
+        interval = ipdisp_network_frame_interval(client, damage_size ?:
+                                                 variant->size);
+        if (interval && now - client->last_frame_ns < interval) {
+            client->frame_pending = true;
+            clients_paced++;
+            continue;
+        }
+        
+        /* With more than this frame still queued the link is behind, and
+         * another frame would only add to the delay; it gets the next one
+         * once the queue drains, and backs off if that keeps happening */
+        if (idev->rate_control &&
+            READ_ONCE(client->sock->sk->sk_wmem_queued) >
+            (damage_size ?: variant->size)) {
+            client->backlogged = true;
+            client->frame_pending = true;
+            clients_paced++;
+            continue;
+        }
+        
+        crc = client->capabilities & IPDISP_CAP_CRC32;
+        iov_count = 2;
+        sent_ts = now;
+        if (damage_size &&
+            ipdisp_network_prepare_damage(idev, client, &damage, data, now,
+                                          iov)) {
+            iov_count = 3;
+        } else if (ipdisp_network_compact_header(client, variant, now,
+                                                 &compact)) {
+            if (crc)
+                compact.crc32 = ipdisp_network_variant_crc(variant);
+            sent_ts = client->compact_ts;
+            iov[0].iov_base = &compact;
+            iov[0].iov_len = IPDISP_COMPACT_HEADER_SIZE +
+                             (crc ? sizeof(compact.crc32) : 0);
+        } else if (crc) {
+            variant->crc_header.header = variant->header;
+            variant->crc_header.header.packet_type =
+                cpu_to_be32(IPDISP_PACKET_DISPLAY | IPDISP_PACKET_FLAG_CRC32);
+            variant->crc_header.crc32 = ipdisp_network_variant_crc(variant);
+            iov[0].iov_base = &variant->crc_header;
+            iov[0].iov_len = sizeof(variant->crc_header);
+        } else {
+            iov[0].iov_base = &variant->header;
+            iov[0].iov_len = sizeof(variant->header);
+        }
+        if (iov_count == 2) {
+            iov[1].iov_base = (void *)variant->data;
+            iov[1].iov_len = variant->size;
+        }
+        total = iov[0].iov_len + iov[1].iov_len +
+                (iov_count == 3 ? iov[2].iov_len : 0);
             
         mutex_lock(&client->lock);
-        ret = kernel_sendmsg(client->sock, &msg, iov, 2, 
-                           sizeof(header) + size);
+        /* Damage keeps the format, and content hashes cover whole frames */
+        if (iov_count == 2) {
+            ipdisp_network_announce_format(client, variant);
+            ipdisp_network_send_content_hash(idev, client, variant, now);
+        }
+        ret = ipdisp_noise_sendmsg(client, iov, iov_count, total);
         mutex_unlock(&client->lock);
         
         if (ret < 0) {
             ipdisp_debug("Failed to send frame to client: %d\n", ret);
             client->active = false; /* Mark for cleanup */
-        } else if (ret != sizeof(header) + size) {
-            ipdisp_debug("Partial send to client: %d/%zu\n", 
-                        ret, sizeof(header) + size);
+        } else if (ret != total) {
+            ipdisp_debug("Partial send to client: %d/%zu\n", ret, total);
             client->active = false; /* Mark for cleanup */
         } else {
+            client->last_frame_ns = now;
+            client->last_tx_ns = now;
+            client->frame_pending = false;
+            drm_rect_init(&client->damage, 0, 0, 0, 0);
+            client->damage_ready = whole;
+            if (client->capabilities & IPDISP_CAP_ACK)
+                ipdisp_network_expect_ack(client, sent_ts);
+            ipdisp_network_mark_session_sent(idev, client, now);
             clients_sent++;
         }
     }
     
+    idev->frame_seq++;
     mutex_unlock(&idev->clients_lock);
     
-    /* Schedule cleanup if needed */
-    if (clients_sent == 0) {
+    /* Schedule cleanup if needed (paced clients are retried by the
+     * network thread instead, and paused ones wait to resume) */
+    if (clients_sent == 0 && clients_paced == 0 && clients_paused == 0) {
         schedule_work(&idev->stream_work);
     }
     
     return clients_sent;
 }
 
+static const char * const ipdisp_network_format_names[] = {
+    [IPDISP_FORMAT_RGBA32] = "rgba32",
+    [IPDISP_FORMAT_RGB24] = "rgb24",
+    [IPDISP_FORMAT_H264] = "h264",
+    [IPDISP_FORMAT_H265] = "h265",
+    [IPDISP_FORMAT_YUV420P] = "yuv420p",
+    [IPDISP_FORMAT_NV12] = "nv12",
+    [IPDISP_FORMAT_RGB565] = "rgb565",
+    [IPDISP_FORMAT_RGBA1010102] = "rgba1010102",
+    [IPDISP_FORMAT_P010] = "p010",
+    [IPDISP_FORMAT_JPEG] = "jpeg",
+};
+
+/* One line per connected client: its id, address, session, what it asked
+ * for and its state */
+static ssize_t clients_show(struct device *dev,
+                            struct device_attribute *attr, char *buf)
+{
+    struct ipdisp_device *idev = dev_get_drvdata(dev);
+    struct ipdisp_client *client;
+    const char *format;
+    ssize_t len = 0;
+    
+    mutex_lock(&idev->clients_lock);
+    list_for_each_entry(client, &idev->clients, list) {
+        if (!client->active)
+            continue;
+        format = client->format < ARRAY_SIZE(ipdisp_network_format_names) ?
+                 ipdisp_network_format_names[client->format] : "unknown";
+        len += sysfs_emit_at(buf, len,
+                             "%u %pI4:%u session=%u mode=%s format=%s "
+                             "scale=%u max_kbps=%u max_fps=%u backoff=%u "
+                             "latency_us=%u/%u%s%s%s%s%s%s%s\n",
+                             client->id, &client->addr.sin_addr,
+                             ntohs(client->addr.sin_port), client->session_id,
+                             ipdisp_network_session_mode_names[client->session_mode],
+                             format, client->scale_shift, client->max_kbps,
+                             client->max_fps, client->backoff,
+                             client->receive_latency_us,
+                             client->present_latency_us,
+                             client->authenticated ? " authenticated" : "",
+                             (client->noise && client->noise->stage ==
+                              IPDISP_NOISE_READY) ? " encrypted" : "",
+                             client->crop_buf ? " cropped" : "",
+                             client->paused ? " paused" : "",
+                             (client->touch || client->pointer ||
+                              client->keyboard) ? " input" : "",
+                             (client->input_asked && !client->input_allowed) ?
+                             " input_pending" : "",
+                             (idev->control_owner &&
+                              idev->control_owner == client->id) ?
+                             " control" : "");
+    }
+    mutex_unlock(&idev->clients_lock);
+    
+    return len;
+}
+static DEVICE_ATTR_RO(clients);
+
+/* Disconnect the client with the id written. Shutting the socket down
+ * tells the client at once; the network thread then drops it as it does
+ * any closed connection. */
+static ssize_t kick_store(struct device *dev, struct device_attribute *attr,
+                          const char *buf, size_t count)
+{
+    struct ipdisp_device *idev = dev_get_drvdata(dev);
+    struct ipdisp_client *client;
+    int ret = -ENOENT;
+    u32 id;
+    
+    if (kstrtou32(buf, 10, &id))
+        return -EINVAL;
+    
+    mutex_lock(&idev->clients_lock);
+    list_for_each_entry(client, &idev->clients, list) {
+        if (client->id != id || !client->active)
+            continue;
+        ipdisp_info("Kicking client %pI4\n", &client->addr.sin_addr);
+        mutex_lock(&client->lock);
+        kernel_sock_shutdown(client->sock, SHUT_RDWR);
+        client->resumable = false;
+        client->active = false;
+        mutex_unlock(&client->lock);
+        ret = 0;
+        break;
+    }
+    mutex_unlock(&idev->clients_lock);
+    
+    return ret ? ret : count;
+}
+static DEVICE_ATTR_WO(kick);
+
+/* Let the client with the id written drive the input devices, with
+ * input=confirm */
+static ssize_t allow_input_store(struct device *dev,
+                                 struct device_attribute *attr,
+                                 const char *buf, size_t count)
+{
+    struct ipdisp_device *idev = dev_get_drvdata(dev);
+    struct ipdisp_client *client;
+    int ret = -ENOENT;
+    u32 id;
+    
+    if (kstrtou32(buf, 10, &id))
+        return -EINVAL;
+    
+    mutex_lock(&idev->clients_lock);
+    list_for_each_entry(client, &idev->clients, list) {
+        if (client->id != id || !client->active)
+            continue;
+        ipdisp_info("Allowing input from client %pI4\n",
+                   &client->addr.sin_addr);
+        client->input_allowed = true;
+        ret = 0;
+        break;
+    }
+    mutex_unlock(&idev->clients_lock);
+    
+    return ret ? ret : count;
+}
+static DEVICE_ATTR_WO(allow_input);
+
 /* Initialize network subsystem */
 int ipdisp_network_init(struct ipdisp_device *idev)
 {
@@ -266,12 +1768,21 @@ int ipdisp_network_init(struct ipdisp_device *idev)
     
     idev->listen_sock = sock;
     
+    /* Managing clients is optional, streaming to them is not */
+    if (device_create_file(&idev->pdev->dev, &dev_attr_clients) ||
+        device_create_file(&idev->pdev->dev, &dev_attr_kick) ||
+        device_create_file(&idev->pdev->dev, &dev_attr_allow_input))
+        ipdisp_warn("Failed to add the clients, kick and allow_input sysfs files\n");
+    
     /* Start network thread */
     idev->network_thread = kthread_run(ipdisp_network_thread, idev,
                                       "ipdisp-net");
     if (IS_ERR(idev->network_thread)) {
         ret = PTR_ERR(idev->network_thread);
         ipdisp_err("Failed to start network thread: %d\n", ret);
+        device_remove_file(&idev->pdev->dev, &dev_attr_allow_input);
+        device_remove_file(&idev->pdev->dev, &dev_attr_kick);
+        device_remove_file(&idev->pdev->dev, &dev_attr_clients);
         sock_release(sock);
         idev->listen_sock = NULL;
         return ret;
@@ -288,6 +1799,10 @@ void ipdisp_network_cleanup(struct ipdisp_device *idev)
     
     ipdisp_debug("Cleaning up network subsystem\n");
     
+    device_remove_file(&idev->pdev->dev, &dev_attr_allow_input);
+    device_remove_file(&idev->pdev->dev, &dev_attr_kick);
+    device_remove_file(&idev->pdev->dev, &dev_attr_clients);
+    
     /* Stop network thread */
     if (idev->network_thread) {
         kthread_stop(idev->network_thread);
@@ -305,10 +1820,18 @@ void ipdisp_network_cleanup(struct ipdisp_device *idev)
     
     list_for_each_entry_safe(client, tmp, &idev->clients, list) {
         ipdisp_debug("Closing client connection\n");
+        /* Closing the socket still sends what is queued, so this reaches
+         * the client, which then knows the server left on purpose */
+        if (client->active)
+            ipdisp_network_send_packet(client, IPDISP_PACKET_GOODBYE,
+                                       NULL, 0);
+        ipdisp_upload_forget_client(client);
+        ipdisp_noise_forget_client(client);
         list_del(&client->list);
         if (client->sock)
             sock_release(client->sock);
         mutex_destroy(&client->lock);
+        vfree(client->crop_buf);
         kfree(client);
     }
     
@@ -0,0 +1,500 @@
+/* IP Display Driver - Noise Encryption
+ * Copyright (C) 2024
+ * Licensed under GPL v2
+ *
+ * Encryption without certificates, for clients that ask in HELLO. We take
+ * the responder's side of Noise_XX_25519_ChaChaPoly_SHA256 with the static
+ * key from the noise_key parameter, whose public half is in sysfs for
+ * users to compare with what their client pinned on first connect. The
+ * handshake goes in NOISE packets; after it every byte either way goes in
+ * records of a __be16 length and ChaCha20-Poly1305 ciphertext, with
+ * nonces counting up from 0 in each direction.
+ */
+
+#include "ipdisp.h"
+
+/* Largest plaintext in one record */
+#define IPDISP_NOISE_MAX_PLAIN (IPDISP_NOISE_MAX_RECORD - IPDISP_NOISE_TAG_SIZE)
+/* Record buffers: a length, then one record. Records are read in 2 bytes
+ * along, so the packet inside starts 4-byte aligned. */
+#define IPDISP_NOISE_BUF_SIZE (2 * sizeof(__be16) + IPDISP_NOISE_MAX_RECORD)
+/* Most kvecs one send takes: header, payload and CRC trailer */
+#define IPDISP_SEND_MAX_IOV 3
+/* How long a full send buffer may hold a send up before it gives up */
+#define IPDISP_SEND_WAIT_NS (20 * NSEC_PER_MSEC)
+
+/* The public key clients pin, as hex */
+static ssize_t noise_public_key_show(struct device *dev,
+                                     struct device_attribute *attr, char *buf)
+{
+    struct ipdisp_device *idev = dev_get_drvdata(dev);
+    
+    return sysfs_emit(buf, "%*phN\n", IPDISP_NOISE_KEY_SIZE,
+                      idev->noise_public);
+}
+static DEVICE_ATTR_RO(noise_public_key);
+
+/* Take the static key from the noise_key parameter, 64 hex digits */
+int ipdisp_noise_init(struct ipdisp_device *idev, const char *key)
+{
+    int ret;
+    
+    if (!key || !*key) {
+        if (!idev->require_noise)
+            return 0;
+        ipdisp_err("require_noise needs noise_key set\n");
+        return -EINVAL;
+    }
+    
+    if (strlen(key) != 2 * IPDISP_NOISE_KEY_SIZE ||
+        hex2bin(idev->noise_secret, key, IPDISP_NOISE_KEY_SIZE)) {
+        ipdisp_err("noise_key must be %d hex digits\n",
+                   2 * IPDISP_NOISE_KEY_SIZE);
+        return -EINVAL;
+    }
+    if (!curve25519_generate_public(idev->noise_public, idev->noise_secret)) {
+        ret = -EINVAL;
+        goto err;
+    }
+    
+    ret = device_create_file(&idev->pdev->dev, &dev_attr_noise_public_key);
+    if (ret)
+        goto err;
+    
+    idev->noise = true;
+    ipdisp_info("Encrypting for clients that ask, public key %*phN\n",
+                IPDISP_NOISE_KEY_SIZE, idev->noise_public);
+    if (idev->require_noise)
+        ipdisp_info("Streaming to encrypted clients only\n");
+    return 0;
+    
+err:
+    memzero_explicit(idev->noise_secret, sizeof(idev->noise_secret));
+    ipdisp_err("Failed to set up Noise: %d\n", ret);
+    return ret;
+}
+
+void ipdisp_noise_cleanup(struct ipdisp_device *idev)
+{
+    if (!idev->noise)
+        return;
+    
+    device_remove_file(&idev->pdev->dev, &dev_attr_noise_public_key);
+    memzero_explicit(idev->noise_secret, sizeof(idev->noise_secret));
+    idev->noise = false;
+}
+
+/* h = SHA-256(h || data) */
+static int ipdisp_noise_mix_hash(struct ipdisp_device *idev,
+                                 struct ipdisp_noise *noise,
+                                 const u8 *data, size_t len)
+{
+    u8 buf[SHA256_DIGEST_SIZE + IPDISP_NOISE_KEY_SIZE + IPDISP_NOISE_TAG_SIZE];
+    
+    if (WARN_ON(len > sizeof(buf) - SHA256_DIGEST_SIZE))
+        return -EINVAL;
+    memcpy(buf, noise->h, SHA256_DIGEST_SIZE);
+    memcpy(buf + SHA256_DIGEST_SIZE, data, len);
+    return crypto_shash_tfm_digest(idev->pair_sha256, buf,
+                                   SHA256_DIGEST_SIZE + len, noise->h);
+}
+
+/* h = SHA-256(h || prologue), for a prologue in pieces */
+static int ipdisp_noise_mix_prologue(struct ipdisp_device *idev,
+                                     struct ipdisp_noise *noise,
+                                     const struct kvec *prologue,
+                                     size_t count)
+{
+    SHASH_DESC_ON_STACK(desc, idev->pair_sha256);
+    size_t i;
+    int ret;
+    
+    desc->tfm = idev->pair_sha256;
+    ret = crypto_shash_init(desc);
+    if (!ret)
+        ret = crypto_shash_update(desc, noise->h, SHA256_DIGEST_SIZE);
+    for (i = 0; i < count && !ret; i++)
+        ret = crypto_shash_update(desc, prologue[i].iov_base,
+                                  prologue[i].iov_len);
+    if (!ret)
+        ret = crypto_shash_final(desc, noise->h);
+    shash_desc_zero(desc);
+    return ret;
+}
+
+/* Noise's HKDF: two outputs keyed by the chaining key. out1 may be ck. */
+static int ipdisp_noise_hkdf(struct ipdisp_device *idev, const u8 *ck,
+                             const u8 *ikm, size_t ikm_len,
+                             u8 *out1, u8 *out2)
+{
+    u8 temp[SHA256_DIGEST_SIZE];
+    u8 in[SHA256_DIGEST_SIZE + 1];
+    int ret;
+    
+    ret = ipdisp_pair_hmac(idev, ck, SHA256_DIGEST_SIZE, ikm, ikm_len, temp);
+    if (ret)
+        goto out;
+    
+    in[0] = 1;
+    ret = ipdisp_pair_hmac(idev, temp, sizeof(temp), in, 1, out1);
+    if (ret)
+        goto out;
+    
+    memcpy(in, out1, SHA256_DIGEST_SIZE);
+    in[SHA256_DIGEST_SIZE] = 2;
+    ret = ipdisp_pair_hmac(idev, temp, sizeof(temp), in, sizeof(in), out2);
+    
+out:
+    memzero_explicit(temp, sizeof(temp));
+    memzero_explicit(in, sizeof(in));
+    return ret;
+}
+
+/* Mix the shared secret of secret and public into the chaining key, for a
+ * fresh handshake key */
+static int ipdisp_noise_mix_dh(struct ipdisp_device *idev,
+                               struct ipdisp_noise *noise,
+                               const u8 *secret, const u8 *public)
+{
+    u8 dh[CURVE25519_KEY_SIZE];
+    int ret;
+    
+    /* Fails for low-order points */
+    if (!curve25519(dh, secret, public))
+        return -EINVAL;
+    
+    ret = ipdisp_noise_hkdf(idev, noise->ck, dh, sizeof(dh), noise->ck,
+                            noise->k);
+    noise->n = 0;
+    memzero_explicit(dh, sizeof(dh));
+    return ret;
+}
+
+/* Encrypt len bytes of plain to out, len + IPDISP_NOISE_TAG_SIZE bytes,
+ * bound to the handshake so far */
+static int ipdisp_noise_encrypt_and_hash(struct ipdisp_device *idev,
+                                         struct ipdisp_noise *noise,
+                                         const u8 *plain, size_t len, u8 *out)
+{
+    chacha20poly1305_encrypt(out, plain, len, noise->h, sizeof(noise->h),
+                             noise->n++, noise->k);
+    return ipdisp_noise_mix_hash(idev, noise, out,
+                                 len + IPDISP_NOISE_TAG_SIZE);
+}
+
+/* Decrypt len bytes of in, tag included, to out */
+static int ipdisp_noise_decrypt_and_hash(struct ipdisp_device *idev,
+                                         struct ipdisp_noise *noise,
+                                         const u8 *in, size_t len, u8 *out)
+{
+    if (!chacha20poly1305_decrypt(out, in, len, noise->h, sizeof(noise->h),
+                                  noise->n, noise->k))
+        return -EBADMSG;
+    noise->n++;
+    return ipdisp_noise_mix_hash(idev, noise, in, len);
+}
+
+/* Get ready for the handshake of a client that agreed to encrypt. The
+ * prologue is its HELLO and our CAPABILITIES exactly as they went over the
+ * wire, so a man in the middle who changed either, say to strip a
+ * capability, breaks the handshake. Caller holds clients_lock and
+ * client->lock. */
+int ipdisp_noise_start(struct ipdisp_device *idev,
+                       struct ipdisp_client *client,
+                       const struct kvec *prologue, size_t count)
+{
+    struct ipdisp_noise *noise;
+    
+    noise = kzalloc(sizeof(*noise), GFP_KERNEL);
+    if (!noise)
+        return -ENOMEM;
+    noise->send_buf = kvmalloc(IPDISP_NOISE_BUF_SIZE, GFP_KERNEL);
+    noise->recv_buf = kvmalloc(IPDISP_NOISE_BUF_SIZE, GFP_KERNEL);
+    client->noise = noise;
+    if (!noise->send_buf || !noise->recv_buf)
+        return -ENOMEM;
+    
+    /* The name is as long as a hash, so it is the first one */
+    BUILD_BUG_ON(sizeof(IPDISP_NOISE_PATTERN) - 1 != SHA256_DIGEST_SIZE);
+    memcpy(noise->h, IPDISP_NOISE_PATTERN, SHA256_DIGEST_SIZE);
+    memcpy(noise->ck, noise->h, SHA256_DIGEST_SIZE);
+    return ipdisp_noise_mix_prologue(idev, noise, prologue, count);
+}
+
+/* Take the client's ephemeral key and answer with ours and our static key
+ * (e, ee, s, es) */
+static int ipdisp_noise_reply(struct ipdisp_device *idev,
+                              struct ipdisp_client *client, const u8 *message)
+{
+    struct ipdisp_noise *noise = client->noise;
+    u8 reply[IPDISP_NOISE_REPLY_SIZE];
+    u8 *cursor = reply;
+    int ret;
+    
+    /* Its message has no payload */
+    memcpy(noise->re, message, IPDISP_NOISE_KEY_SIZE);
+    ret = ipdisp_noise_mix_hash(idev, noise, noise->re, IPDISP_NOISE_KEY_SIZE);
+    if (!ret)
+        ret = ipdisp_noise_mix_hash(idev, noise, message, 0);
+    if (ret)
+        return ret;
+    
+    curve25519_generate_secret(noise->e);
+    if (!curve25519_generate_public(cursor, noise->e))
+        return -EINVAL;
+    ret = ipdisp_noise_mix_hash(idev, noise, cursor, IPDISP_NOISE_KEY_SIZE);
+    cursor += IPDISP_NOISE_KEY_SIZE;
+    if (!ret)
+        ret = ipdisp_noise_mix_dh(idev, noise, noise->e, noise->re);
+    if (!ret)
+        ret = ipdisp_noise_encrypt_and_hash(idev, noise, idev->noise_public,
+                                            IPDISP_NOISE_KEY_SIZE, cursor);
+    cursor += IPDISP_NOISE_KEY_SIZE + IPDISP_NOISE_TAG_SIZE;
+    if (!ret)
+        ret = ipdisp_noise_mix_dh(idev, noise, idev->noise_secret, noise->re);
+    if (!ret)
+        ret = ipdisp_noise_encrypt_and_hash(idev, noise, cursor, 0, cursor);
+    if (ret)
+        return ret;
+    
+    ret = ipdisp_network_send_packet(client, IPDISP_PACKET_NOISE, reply,
+                                     sizeof(reply));
+    if (ret)
+        return ret;
+    noise->stage = IPDISP_NOISE_REPLIED;
+    return 0;
+}
+
+/* Take the client's static key (s, se) and switch to records */
+static int ipdisp_noise_finish(struct ipdisp_device *idev,
+                               struct ipdisp_client *client, const u8 *message)
+{
+    struct ipdisp_noise *noise = client->noise;
+    u8 empty[1];
+    int ret;
+    
+    ret = ipdisp_noise_decrypt_and_hash(idev, noise, message,
+                                        IPDISP_NOISE_KEY_SIZE +
+                                        IPDISP_NOISE_TAG_SIZE, noise->rs);
+    message += IPDISP_NOISE_KEY_SIZE + IPDISP_NOISE_TAG_SIZE;
+    if (!ret)
+        ret = ipdisp_noise_mix_dh(idev, noise, noise->e, noise->rs);
+    if (!ret)
+        ret = ipdisp_noise_decrypt_and_hash(idev, noise, message,
+                                            IPDISP_NOISE_TAG_SIZE, empty);
+    /* The client sends with the first key, we with the second */
+    if (!ret)
+        ret = ipdisp_noise_hkdf(idev, noise->ck, empty, 0, noise->recv_key,
+                                noise->send_key);
+    
+    memzero_explicit(noise->e, sizeof(noise->e));
+    memzero_explicit(noise->ck, sizeof(noise->ck));
+    memzero_explicit(noise->k, sizeof(noise->k));
+    if (ret)
+        return ret;
+    
+    noise->stage = IPDISP_NOISE_READY;
+    ipdisp_info("Client %pI4 encrypted, key %*phN\n", &client->addr.sin_addr,
+                IPDISP_NOISE_KEY_SIZE, noise->rs);
+    
+    /* Its answer to the challenge went in the clear, where anyone could
+     * have relayed it, so it proves itself again in here */
+    if (idev->require_pairing) {
+        client->authenticated = false;
+        client->verified = false;
+        client->paired = false;
+        memset(client->auth_id, 0, sizeof(client->auth_id));
+        ret = ipdisp_pair_challenge(client);
+    }
+    client->frame_pending = true;
+    return ret;
+}
+
+/* Take the client's next handshake message; caller holds clients_lock and
+ * client->lock. A negative return drops the client. */
+int ipdisp_noise_handle_request(struct ipdisp_device *idev,
+                                struct ipdisp_client *client,
+                                const u8 *payload, u32 size)
+{
+    struct ipdisp_noise *noise = client->noise;
+    int ret = -EPROTO;
+    
+    if (!noise)
+        return -EPROTO;
+    
+    if (noise->stage == IPDISP_NOISE_STARTED &&
+        size == IPDISP_NOISE_START_SIZE)
+        ret = ipdisp_noise_reply(idev, client, payload);
+    else if (noise->stage == IPDISP_NOISE_REPLIED &&
+             size == IPDISP_NOISE_FINISH_SIZE)
+        ret = ipdisp_noise_finish(idev, client, payload);
+    
+    if (ret < 0)
+        ipdisp_warn("Client %pI4 failed the Noise handshake: %d\n",
+                   &client->addr.sin_addr, ret);
+    return ret;
+}
+
+/* Send all of iov's total bytes without blocking the socket, going on from
+ * where a short send stopped and waiting briefly while the send buffer is
+ * full. Returns the bytes sent, short only if the buffer stayed full. */
+static int ipdisp_noise_send_all(struct socket *sock, struct kvec *iov,
+                                 size_t count, size_t total)
+{
+    struct kvec vec[IPDISP_SEND_MAX_IOV];
+    struct kvec *next = vec;
+    struct msghdr msg;
+    u64 deadline = ktime_get_ns() + IPDISP_SEND_WAIT_NS;
+    size_t sent = 0, n;
+    int ret;
+    
+    if (WARN_ON(count > IPDISP_SEND_MAX_IOV))
+        return -EINVAL;
+    memcpy(vec, iov, count * sizeof(*iov));
+    
+    while (sent < total) {
+        memset(&msg, 0, sizeof(msg));
+        msg.msg_flags = MSG_DONTWAIT | MSG_NOSIGNAL;
+        ret = kernel_sendmsg(sock, &msg, next, count, total - sent);
+        if (ret == -EAGAIN || ret == -EWOULDBLOCK) {
+            if (ktime_get_ns() > deadline)
+                break;
+            usleep_range(200, 500);
+            continue;
+        }
+        if (ret < 0)
+            return sent ? sent : ret;
+        
+        /* Skip what went, so the next send starts where this one ended */
+        sent += ret;
+        for (n = ret; count && n >= next->iov_len; count--)
+            n -= (next++)->iov_len;
+        if (count) {
+            next->iov_base = (u8 *)next->iov_base + n;
+            next->iov_len -= n;
+        }
+    }
+    return sent;
+}
+
+/* Send iov's total bytes: as they are to clients that don't encrypt, in
+ * records to those that do, and not at all between our reply and the
+ * client's last message, as it may be reading records already. Returns
+ * the bytes sent, short only if the client stopped reading; caller holds
+ * client->lock. */
+int ipdisp_noise_sendmsg(struct ipdisp_client *client, struct kvec *iov,
+                         size_t count, size_t total)
+{
+    struct ipdisp_noise *noise = client->noise;
+    u8 *plain;
+    struct kvec record;
+    size_t sent, len, taken, offset = 0, n;
+    int ret;
+    
+    if (!noise || noise->stage == IPDISP_NOISE_STARTED)
+        return ipdisp_noise_send_all(client->sock, iov, count, total);
+    if (noise->stage == IPDISP_NOISE_REPLIED)
+        return total;
+    
+    plain = noise->send_buf + sizeof(__be16);
+    for (sent = 0; sent < total; sent += len) {
+        len = min_t(size_t, total - sent, IPDISP_NOISE_MAX_PLAIN);
+        for (taken = 0; taken < len; taken += n) {
+            while (offset == iov->iov_len) {
+                iov++;
+                offset = 0;
+            }
+            n = min(len - taken, iov->iov_len - offset);
+            memcpy(plain + taken, (u8 *)iov->iov_base + offset, n);
+            offset += n;
+        }
+    
+        chacha20poly1305_encrypt(plain, plain, len, NULL, 0,
+                                 noise->send_nonce++, noise->send_key);
+        *(__be16 *)noise->send_buf = cpu_to_be16(len + IPDISP_NOISE_TAG_SIZE);
+        record.iov_base = noise->send_buf;
+        record.iov_len = sizeof(__be16) + len + IPDISP_NOISE_TAG_SIZE;
+    
+        /* Half a record would end the connection anyway */
+        ret = ipdisp_noise_send_all(client->sock, &record, 1, record.iov_len);
+        if (ret != record.iov_len)
+            return ret < 0 ? ret : -EIO;
+    }
+    return total;
+}
+
+/* Read and decrypt one record without blocking, leaving its plaintext at
+ * *plain; returns the plaintext's length, or 0 until all of it has
+ * arrived. Caller holds client->lock. */
+int ipdisp_noise_recv(struct ipdisp_client *client, u8 **plain)
+{
+    struct ipdisp_noise *noise = client->noise;
+    u8 *record = noise->recv_buf + sizeof(__be16);
+    struct kvec iov;
+    struct msghdr msg;
+    size_t total;
+    u32 len;
+    int ret;
+    
+    /* Peek until the whole record has arrived, then consume it */
+    iov.iov_base = record;
+    iov.iov_len = sizeof(__be16);
+    memset(&msg, 0, sizeof(msg));
+    ret = kernel_recvmsg(client->sock, &msg, &iov, 1, iov.iov_len,
+                        MSG_DONTWAIT | MSG_PEEK);
+    if (ret == -EAGAIN || ret == -EWOULDBLOCK)
+        return 0;
+    if (ret == 0)
+        return -ECONNRESET;
+    if (ret < 0)
+        return ret;
+    if (ret < sizeof(__be16))
+        return 0;
+    
+    len = be16_to_cpup((const __be16 *)record);
+    if (len <= IPDISP_NOISE_TAG_SIZE)
+        return -EPROTO;
+    total = sizeof(__be16) + len;
+    
+    iov.iov_base = record;
+    iov.iov_len = total;
+    memset(&msg, 0, sizeof(msg));
+    ret = kernel_recvmsg(client->sock, &msg, &iov, 1, total,
+                        MSG_DONTWAIT | MSG_PEEK);
+    if (ret == -EAGAIN || ret == -EWOULDBLOCK)
+        return 0;
+    if (ret < 0)
+        return ret;
+    if (ret < total)
+        return 0;
+    
+    memset(&msg, 0, sizeof(msg));
+    ret = kernel_recvmsg(client->sock, &msg, &iov, 1, total, MSG_DONTWAIT);
+    if (ret != total)
+        return ret < 0 ? ret : -EIO;
+    
+    record += sizeof(__be16);
+    if (!chacha20poly1305_decrypt(record, record, len, NULL, 0,
+                                  noise->recv_nonce, noise->recv_key)) {
+        ipdisp_warn("Forged or corrupt record from client %pI4\n",
+                   &client->addr.sin_addr);
+        return -EBADMSG;
+    }
+    noise->recv_nonce++;
+    
+    *plain = record;
+    return len - IPDISP_NOISE_TAG_SIZE;
+}
+
+void ipdisp_noise_forget_client(struct ipdisp_client *client)
+{
+    struct ipdisp_noise *noise = client->noise;
+    
+    if (!noise)
+        return;
+    
+    kvfree_sensitive(noise->send_buf, IPDISP_NOISE_BUF_SIZE);
+    kvfree_sensitive(noise->recv_buf, IPDISP_NOISE_BUF_SIZE);
+    kfree_sensitive(noise);
+    client->noise = NULL;
+}
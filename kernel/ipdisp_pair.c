@@ -0,0 +1,550 @@
+/* IP Display Driver - Client Pairing
+ * Copyright (C) 2024
+ * Licensed under GPL v2
+ *
+ * A client pairs by committing to an X25519 key, learning ours, then
+ * revealing its key. Both sides hash the exchange into a 6-digit code,
+ * which we log and expose in sysfs; the user types it into the client,
+ * which only confirms if it matches. Neither side can steer the code since
+ * the client's key was fixed before it saw ours. Anyone on the network
+ * could confirm without looking at the code, so the token is only issued
+ * once an operator has also written the code to sysfs pairing_code. Then
+ * both derive a long-term token the client uses to answer AUTH_CHALLENGE,
+ * and our confirmation carries the key content hashes are signed with,
+ * under the pairing key so nobody in between can swap it.
+ * The tokens are listed in sysfs paired; writing one's id, or "all", to
+ * unpair revokes it.
+ */
+
+#include "ipdisp.h"
+
+static const char ipdisp_pair_client_label[] = "ipdisp client confirm";
+static const char ipdisp_pair_server_label[] = "ipdisp server confirm";
+static const char ipdisp_pair_token_label[] = "ipdisp token";
+static const char ipdisp_pair_token_id_label[] = "ipdisp token id";
+
+int ipdisp_pair_hmac(struct ipdisp_device *idev, const u8 *key,
+                     unsigned int key_len, const void *data, unsigned int len,
+                     u8 *out)
+{
+    int ret;
+    
+    ret = crypto_shash_setkey(idev->pair_hmac, key, key_len);
+    if (ret)
+        return ret;
+    return crypto_shash_tfm_digest(idev->pair_hmac, data, len, out);
+}
+
+static bool ipdisp_pair_expired(struct ipdisp_pairing *pairing)
+{
+    return ktime_get_ns() - pairing->started_ns >
+           (u64)IPDISP_PAIR_TIMEOUT_MS * NSEC_PER_MSEC;
+}
+
+static void ipdisp_pair_reset(struct ipdisp_pairing *pairing)
+{
+    memzero_explicit(pairing, sizeof(*pairing));
+}
+
+/* Issue the token once the client has confirmed and an operator approved;
+ * caller holds clients_lock and client->lock */
+static int ipdisp_pair_complete(struct ipdisp_device *idev,
+                                struct ipdisp_client *client)
+{
+    struct ipdisp_pairing *pairing = &idev->pairing;
+    struct ipdisp_paired_token *paired;
+    u8 mac[SHA256_DIGEST_SIZE];
+    u8 confirm[sizeof(ipdisp_pair_server_label) - 1 + IPDISP_SIGN_KEY_SIZE];
+    u8 reply[SHA256_DIGEST_SIZE + IPDISP_SIGN_KEY_SIZE];
+    int ret;
+    
+    /* Replace the oldest token once the table is full */
+    paired = &idev->paired[idev->paired_next];
+    ret = ipdisp_pair_hmac(idev, pairing->key, sizeof(pairing->key),
+                           ipdisp_pair_token_label,
+                           strlen(ipdisp_pair_token_label), paired->token);
+    if (!ret)
+        ret = ipdisp_pair_hmac(idev, pairing->key, sizeof(pairing->key),
+                               ipdisp_pair_token_id_label,
+                               strlen(ipdisp_pair_token_id_label), mac);
+    if (ret) {
+        memzero_explicit(paired, sizeof(*paired));
+        goto out;
+    }
+    memcpy(paired->id, mac, sizeof(paired->id));
+    paired->addr = client->addr.sin_addr;
+    paired->paired_at = ktime_get_real_seconds();
+    paired->used_at = paired->paired_at;
+    paired->used = true;
+    client->paired = true;
+    memcpy(client->paired_id, paired->id, sizeof(client->paired_id));
+    idev->paired_next = (idev->paired_next + 1) % IPDISP_MAX_PAIRED;
+    client->authenticated = true;
+    client->verified = true;
+    memcpy(client->auth_id, paired->id, sizeof(client->auth_id));
+    
+    /* Our MAC covers the content signing key that follows it */
+    memcpy(confirm, ipdisp_pair_server_label,
+           sizeof(ipdisp_pair_server_label) - 1);
+    memcpy(confirm + sizeof(ipdisp_pair_server_label) - 1, idev->sign_public,
+           IPDISP_SIGN_KEY_SIZE);
+    ret = ipdisp_pair_hmac(idev, pairing->key, sizeof(pairing->key),
+                           confirm, sizeof(confirm), reply);
+    memcpy(reply + SHA256_DIGEST_SIZE, idev->sign_public,
+           IPDISP_SIGN_KEY_SIZE);
+    if (!ret)
+        ret = ipdisp_network_send_packet(client, IPDISP_PACKET_PAIR_CONFIRM,
+                                         reply, sizeof(reply));
+    if (!ret)
+        ipdisp_info("Paired with %pI4\n", &client->addr.sin_addr);
+    
+out:
+    ipdisp_pair_reset(pairing);
+    return ret;
+}
+
+/* Show the current pairing code, empty when nobody is pairing */
+static ssize_t pairing_code_show(struct device *dev,
+                                 struct device_attribute *attr, char *buf)
+{
+    struct ipdisp_device *idev = dev_get_drvdata(dev);
+    ssize_t len = 0;
+    
+    mutex_lock(&idev->clients_lock);
+    if (idev->pairing.revealed && !ipdisp_pair_expired(&idev->pairing))
+        len = sysfs_emit(buf, "%06u\n", idev->pairing.code);
+    mutex_unlock(&idev->clients_lock);
+    
+    return len;
+}
+
+/* Approve the pairing under way by writing its code. A wrong code ends the
+ * pairing, so the code can't be guessed through here either. */
+static ssize_t pairing_code_store(struct device *dev,
+                                  struct device_attribute *attr,
+                                  const char *buf, size_t count)
+{
+    struct ipdisp_device *idev = dev_get_drvdata(dev);
+    struct ipdisp_pairing *pairing = &idev->pairing;
+    struct ipdisp_client *client;
+    unsigned int code;
+    int ret;
+    
+    ret = kstrtouint(buf, 10, &code);
+    if (ret)
+        return ret;
+    
+    mutex_lock(&idev->clients_lock);
+    client = pairing->client;
+    if (!client || !pairing->revealed || ipdisp_pair_expired(pairing)) {
+        ret = -ENOENT;
+        goto out;
+    }
+    if (code != pairing->code) {
+        ipdisp_warn("Wrong pairing code for %pI4; pairing cancelled\n",
+                   &client->addr.sin_addr);
+        ipdisp_pair_reset(pairing);
+        ret = -EACCES;
+        goto out;
+    }
+    
+    pairing->approved = true;
+    ipdisp_info("Pairing with %pI4 approved\n", &client->addr.sin_addr);
+    if (pairing->confirmed) {
+        mutex_lock(&client->lock);
+        if (ipdisp_pair_complete(idev, client) < 0)
+            client->active = false; /* Mark for cleanup */
+        mutex_unlock(&client->lock);
+    }
+    
+out:
+    mutex_unlock(&idev->clients_lock);
+    return ret ? ret : count;
+}
+static DEVICE_ATTR_RW(pairing_code);
+
+/* One line per paired token: its id, where and when it was paired, when it
+ * was last used and whether a client holding it is connected */
+static ssize_t paired_show(struct device *dev, struct device_attribute *attr,
+                           char *buf)
+{
+    struct ipdisp_device *idev = dev_get_drvdata(dev);
+    struct ipdisp_paired_token *paired;
+    struct ipdisp_client *client;
+    bool connected;
+    ssize_t len = 0;
+    int i;
+    
+    mutex_lock(&idev->clients_lock);
+    for (i = 0; i < IPDISP_MAX_PAIRED; i++) {
+        paired = &idev->paired[i];
+        if (!paired->used)
+            continue;
+    
+        connected = false;
+        list_for_each_entry(client, &idev->clients, list) {
+            if (client->active && client->paired &&
+                !memcmp(client->paired_id, paired->id, sizeof(paired->id)))
+                connected = true;
+        }
+        len += sysfs_emit_at(buf, len, "%*phN %pI4 paired=%ptT used=%ptT%s\n",
+                             IPDISP_PAIR_TOKEN_ID_SIZE, paired->id,
+                             &paired->addr, &paired->paired_at,
+                             &paired->used_at,
+                             connected ? " connected" : "");
+    }
+    mutex_unlock(&idev->clients_lock);
+    
+    return len;
+}
+static DEVICE_ATTR_RO(paired);
+
+/* Revoke the paired token whose id is written in hex, or every one with
+ * "all". Clients that proved it are disconnected, as by kick, and have to
+ * pair again. */
+static ssize_t unpair_store(struct device *dev, struct device_attribute *attr,
+                            const char *buf, size_t count)
+{
+    struct ipdisp_device *idev = dev_get_drvdata(dev);
+    struct ipdisp_paired_token *paired;
+    struct ipdisp_client *client;
+    u8 id[IPDISP_PAIR_TOKEN_ID_SIZE];
+    bool all = sysfs_streq(buf, "all");
+    size_t len = count;
+    int i, ret = all ? 0 : -ENOENT;
+    
+    while (len && isspace(buf[len - 1]))
+        len--;
+    if (!all && (len != 2 * sizeof(id) || hex2bin(id, buf, sizeof(id))))
+        return -EINVAL;
+    
+    mutex_lock(&idev->clients_lock);
+    for (i = 0; i < IPDISP_MAX_PAIRED; i++) {
+        paired = &idev->paired[i];
+        if (!paired->used || (!all && memcmp(paired->id, id, sizeof(id))))
+            continue;
+    
+        ipdisp_info("Revoking pairing %*phN\n", IPDISP_PAIR_TOKEN_ID_SIZE,
+                    paired->id);
+        list_for_each_entry(client, &idev->clients, list) {
+            if (!client->active || !client->paired ||
+                memcmp(client->paired_id, paired->id, sizeof(paired->id)))
+                continue;
+            mutex_lock(&client->lock);
+            kernel_sock_shutdown(client->sock, SHUT_RDWR);
+            client->resumable = false;
+            client->active = false;
+            mutex_unlock(&client->lock);
+        }
+        memzero_explicit(paired, sizeof(*paired));
+        ret = 0;
+    }
+    mutex_unlock(&idev->clients_lock);
+    
+    return ret ? ret : count;
+}
+static DEVICE_ATTR_WO(unpair);
+
+int ipdisp_pair_init(struct ipdisp_device *idev)
+{
+    int ret;
+    
+    idev->pair_sha256 = crypto_alloc_shash("sha256", 0, 0);
+    if (IS_ERR(idev->pair_sha256)) {
+        ret = PTR_ERR(idev->pair_sha256);
+        goto err_sha256;
+    }
+    
+    idev->pair_hmac = crypto_alloc_shash("hmac(sha256)", 0, 0);
+    if (IS_ERR(idev->pair_hmac)) {
+        ret = PTR_ERR(idev->pair_hmac);
+        goto err_hmac;
+    }
+    
+    ret = device_create_file(&idev->pdev->dev, &dev_attr_pairing_code);
+    if (ret)
+        goto err_sysfs;
+    if (device_create_file(&idev->pdev->dev, &dev_attr_paired) ||
+        device_create_file(&idev->pdev->dev, &dev_attr_unpair))
+        ipdisp_warn("Failed to add the paired and unpair sysfs files\n");
+    
+    if (idev->require_pairing)
+        ipdisp_info("Streaming to paired clients only\n");
+    return 0;
+    
+err_sysfs:
+    crypto_free_shash(idev->pair_hmac);
+err_hmac:
+    crypto_free_shash(idev->pair_sha256);
+err_sha256:
+    idev->pair_sha256 = NULL;
+    idev->pair_hmac = NULL;
+    ipdisp_err("Failed to set up pairing: %d\n", ret);
+    return ret;
+}
+
+void ipdisp_pair_cleanup(struct ipdisp_device *idev)
+{
+    if (!idev->pair_sha256)
+        return;
+    
+    device_remove_file(&idev->pdev->dev, &dev_attr_unpair);
+    device_remove_file(&idev->pdev->dev, &dev_attr_paired);
+    device_remove_file(&idev->pdev->dev, &dev_attr_pairing_code);
+    crypto_free_shash(idev->pair_hmac);
+    crypto_free_shash(idev->pair_sha256);
+    idev->pair_sha256 = NULL;
+    idev->pair_hmac = NULL;
+    
+    ipdisp_pair_reset(&idev->pairing);
+    memzero_explicit(idev->paired, sizeof(idev->paired));
+}
+
+/* Send the client a fresh challenge to answer with its token; caller holds
+ * client->lock */
+int ipdisp_pair_challenge(struct ipdisp_client *client)
+{
+    get_random_bytes(client->challenge, sizeof(client->challenge));
+    return ipdisp_network_send_packet(client, IPDISP_PACKET_AUTH_CHALLENGE,
+                                      client->challenge,
+                                      sizeof(client->challenge));
+}
+
+/* Ask a newly connected client to prove it has paired */
+int ipdisp_pair_send_challenge(struct ipdisp_device *idev,
+                               struct ipdisp_client *client)
+{
+    int ret;
+    
+    if (!idev->require_pairing) {
+        client->authenticated = true;
+        return 0;
+    }
+    
+    mutex_lock(&client->lock);
+    ret = ipdisp_pair_challenge(client);
+    mutex_unlock(&client->lock);
+    
+    return ret;
+}
+
+/* Vouch for the content of the frame about to go out stamped timestamp;
+ * caller holds clients_lock and client->lock */
+int ipdisp_pair_send_content_hash(struct ipdisp_device *idev,
+                                  struct ipdisp_client *client, u64 timestamp,
+                                  const u8 *digest)
+{
+    u8 payload[IPDISP_CONTENT_HASH_SIZE];
+    __be64 stamp = cpu_to_be64(timestamp);
+    int ret;
+    
+    memcpy(payload, &stamp, sizeof(stamp));
+    memcpy(payload + sizeof(stamp), digest, SHA256_DIGEST_SIZE);
+    ret = ipdisp_sign(idev, payload, sizeof(stamp) + SHA256_DIGEST_SIZE,
+                      payload + sizeof(stamp) + SHA256_DIGEST_SIZE);
+    if (ret)
+        return ret;
+    
+    return ipdisp_network_send_packet(client, IPDISP_PACKET_CONTENT_HASH,
+                                      payload, sizeof(payload));
+}
+
+/* Drop a pairing whose client is going away */
+void ipdisp_pair_forget_client(struct ipdisp_device *idev,
+                               struct ipdisp_client *client)
+{
+    if (idev->pairing.client == client)
+        ipdisp_pair_reset(&idev->pairing);
+}
+
+static int ipdisp_pair_commit(struct ipdisp_device *idev,
+                              struct ipdisp_client *client,
+                              const u8 *payload, u32 size)
+{
+    struct ipdisp_pairing *pairing = &idev->pairing;
+    
+    if (size != sizeof(pairing->commit))
+        return -EPROTO;
+    
+    if (pairing->client && pairing->client != client &&
+        !ipdisp_pair_expired(pairing)) {
+        ipdisp_warn("Client %pI4 wants to pair while another is pairing\n",
+                   &client->addr.sin_addr);
+        return 0;
+    }
+    
+    ipdisp_pair_reset(pairing);
+    pairing->client = client;
+    pairing->started_ns = ktime_get_ns();
+    memcpy(pairing->commit, payload, size);
+    
+    curve25519_generate_secret(pairing->secret);
+    if (!curve25519_generate_public(pairing->server_key, pairing->secret)) {
+        ipdisp_pair_reset(pairing);
+        return -EINVAL;
+    }
+    get_random_bytes(pairing->server_key + IPDISP_PAIR_KEY_SIZE,
+                     IPDISP_PAIR_NONCE_SIZE);
+    
+    return ipdisp_network_send_packet(client, IPDISP_PACKET_PAIR_KEY,
+                                      pairing->server_key,
+                                      sizeof(pairing->server_key));
+}
+
+static int ipdisp_pair_reveal(struct ipdisp_device *idev,
+                              struct ipdisp_client *client,
+                              const u8 *payload, u32 size)
+{
+    struct ipdisp_pairing *pairing = &idev->pairing;
+    u8 transcript[sizeof(pairing->commit) + sizeof(pairing->server_key) +
+                  IPDISP_PAIR_KEY_SIZE + IPDISP_PAIR_NONCE_SIZE];
+    u8 digest[SHA256_DIGEST_SIZE];
+    u8 shared[CURVE25519_KEY_SIZE];
+    int ret;
+    
+    if (pairing->client != client || pairing->revealed)
+        return 0;
+    if (size != IPDISP_PAIR_KEY_SIZE + IPDISP_PAIR_NONCE_SIZE)
+        return -EPROTO;
+    
+    /* The key must be the one the client committed to */
+    ret = crypto_shash_tfm_digest(idev->pair_sha256, payload, size, digest);
+    if (ret)
+        goto out;
+    if (crypto_memneq(digest, pairing->commit, sizeof(digest))) {
+        ipdisp_warn("Client %pI4 revealed a key it didn't commit to\n",
+                   &client->addr.sin_addr);
+        ret = -EACCES;
+        goto out;
+    }
+    
+    if (!curve25519(shared, pairing->secret, payload)) {
+        ret = -EINVAL;
+        goto out;
+    }
+    
+    memcpy(transcript, pairing->commit, sizeof(pairing->commit));
+    memcpy(transcript + sizeof(pairing->commit), pairing->server_key,
+           sizeof(pairing->server_key));
+    memcpy(transcript + sizeof(pairing->commit) + sizeof(pairing->server_key),
+           payload, size);
+    ret = crypto_shash_tfm_digest(idev->pair_sha256, transcript,
+                                  sizeof(transcript), digest);
+    if (ret)
+        goto out;
+    
+    ret = ipdisp_pair_hmac(idev, digest, sizeof(digest), shared,
+                           sizeof(shared), pairing->key);
+    if (ret)
+        goto out;
+    
+    pairing->code = ((u32)digest[0] << 24 | digest[1] << 16 |
+                     digest[2] << 8 | digest[3]) % 1000000;
+    pairing->revealed = true;
+    ipdisp_info("Pairing code for %pI4: %06u\n", &client->addr.sin_addr,
+                pairing->code);
+    
+out:
+    memzero_explicit(pairing->secret, sizeof(pairing->secret));
+    memzero_explicit(shared, sizeof(shared));
+    if (ret)
+        ipdisp_pair_reset(pairing);
+    return ret;
+}
+
+static int ipdisp_pair_confirm(struct ipdisp_device *idev,
+                               struct ipdisp_client *client,
+                               const u8 *payload, u32 size)
+{
+    struct ipdisp_pairing *pairing = &idev->pairing;
+    u8 mac[SHA256_DIGEST_SIZE];
+    int ret;
+    
+    if (pairing->client != client || !pairing->revealed ||
+        pairing->confirmed)
+        return 0;
+    if (size != sizeof(mac))
+        return -EPROTO;
+    
+    if (ipdisp_pair_expired(pairing)) {
+        ipdisp_warn("Pairing code for %pI4 expired\n", &client->addr.sin_addr);
+        ret = -ETIMEDOUT;
+        goto out;
+    }
+    
+    ret = ipdisp_pair_hmac(idev, pairing->key, sizeof(pairing->key),
+                           ipdisp_pair_client_label,
+                           strlen(ipdisp_pair_client_label), mac);
+    if (ret)
+        goto out;
+    if (crypto_memneq(mac, payload, sizeof(mac))) {
+        ipdisp_warn("Client %pI4 failed to confirm pairing\n",
+                   &client->addr.sin_addr);
+        ret = -EACCES;
+        goto out;
+    }
+    
+    pairing->confirmed = true;
+    if (!pairing->approved) {
+        ipdisp_info("Pairing with %pI4 waits for the code %06u to be written to pairing_code\n",
+                    &client->addr.sin_addr, pairing->code);
+        return 0;
+    }
+    return ipdisp_pair_complete(idev, client);
+    
+out:
+    ipdisp_pair_reset(pairing);
+    return ret;
+}
+
+/* The pairing provider's check: is id one of our paired tokens, and mac
+ * its answer to the client's challenge? */
+int ipdisp_pair_verify_token(struct ipdisp_device *idev,
+                             struct ipdisp_client *client, const u8 *id,
+                             const u8 *mac)
+{
+    struct ipdisp_paired_token *paired;
+    u8 expected[SHA256_DIGEST_SIZE];
+    int i, ret;
+    
+    for (i = 0; i < IPDISP_MAX_PAIRED; i++) {
+        paired = &idev->paired[i];
+        if (!paired->used || memcmp(paired->id, id, sizeof(paired->id)))
+            continue;
+    
+        ret = ipdisp_pair_hmac(idev, paired->token, sizeof(paired->token),
+                               client->challenge, sizeof(client->challenge),
+                               expected);
+        if (ret)
+            return ret;
+        if (crypto_memneq(expected, mac, sizeof(expected)))
+            return -EACCES;
+    
+        paired->used_at = ktime_get_real_seconds();
+        client->paired = true;
+        memcpy(client->paired_id, paired->id, sizeof(client->paired_id));
+        return 0;
+    }
+    
+    return -ENOENT;
+}
+
+/* Handle a pairing or auth request; caller holds clients_lock and
+ * client->lock. A negative return drops the client. */
+int ipdisp_pair_handle_request(struct ipdisp_device *idev,
+                               struct ipdisp_client *client, u32 packet_type,
+                               const u8 *payload, u32 size)
+{
+    switch (packet_type) {
+    case IPDISP_PACKET_PAIR_COMMIT:
+        return ipdisp_pair_commit(idev, client, payload, size);
+    case IPDISP_PACKET_PAIR_REVEAL:
+        return ipdisp_pair_reveal(idev, client, payload, size);
+    case IPDISP_PACKET_PAIR_CONFIRM:
+        return ipdisp_pair_confirm(idev, client, payload, size);
+    case IPDISP_PACKET_AUTH:
+        return ipdisp_auth_verify(idev, client, payload, size);
+    default:
+        return 0;
+    }
+}
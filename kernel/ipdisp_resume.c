@@ -0,0 +1,231 @@
+/* IP Display Driver - Session Resume
+ * Copyright (C) 2024
+ * Licensed under GPL v2
+ *
+ * A laptop moving between Wi-Fi and Ethernet loses its connection and
+ * comes back from another address. So that it needn't start over, a client
+ * that agrees to IPDISP_CAP_RESUME is sent a random token once its HELLO
+ * and any Noise handshake are done and it has proved a credential, being
+ * challenged for one if nothing else asked. When its connection is lost,
+ * rather than closed with GOODBYE or kicked, what the server knows of the
+ * session is parked under that token for resume_timeout seconds: its id,
+ * quality, pause, rate control level and latencies, and the token id it
+ * proved. A client sending RESUME with the token in that time takes the
+ * session back and keeps the token, once it has proved the same token id.
+ * A session still on a live connection is never taken: the request waits
+ * until that connection is found dead and parked. The client repeats its
+ * crop, pause, quality and session mode requests itself, so a server that
+ * has forgotten the session loses nothing.
+ *
+ * The token alone is not enough, as it goes in the clear without Noise.
+ * Authentication is never resumed, and neither is anything it grants:
+ * allow_input and input control are given up with the old connection.
+ */
+
+#include "ipdisp.h"
+
+static bool ipdisp_resume_token_set(const u8 *token)
+{
+    return memchr_inv(token, 0, IPDISP_RESUME_TOKEN_SIZE) != NULL;
+}
+
+/* Copy what resuming gives back out of a client */
+static void ipdisp_resume_save(struct ipdisp_parked_session *parked,
+                               const struct ipdisp_client *client)
+{
+    memcpy(parked->token, client->resume_token, sizeof(parked->token));
+    memcpy(parked->auth_id, client->auth_id, sizeof(parked->auth_id));
+    parked->id = client->id;
+    parked->max_kbps = client->max_kbps;
+    parked->max_fps = client->max_fps;
+    parked->scale_shift = client->scale_shift;
+    parked->format = client->format;
+    parked->paused = client->paused;
+    parked->backoff = client->backoff;
+    parked->receive_latency_us = client->receive_latency_us;
+    parked->present_latency_us = client->present_latency_us;
+}
+
+static void ipdisp_resume_restore(struct ipdisp_client *client,
+                                  const struct ipdisp_parked_session *parked)
+{
+    memcpy(client->resume_token, parked->token, sizeof(client->resume_token));
+    client->id = parked->id;
+    client->max_kbps = parked->max_kbps;
+    client->max_fps = parked->max_fps;
+    client->scale_shift = parked->scale_shift;
+    client->format = parked->format;
+    client->paused = parked->paused;
+    client->backoff = parked->backoff;
+    client->receive_latency_us = parked->receive_latency_us;
+    client->present_latency_us = parked->present_latency_us;
+    client->compact_ready = false;
+    client->frame_pending = true;
+}
+
+/* Drop a parked session */
+static void ipdisp_resume_forget(struct ipdisp_parked_session *parked)
+{
+    ipdisp_info("Forgetting client %u's session\n", parked->id);
+    memzero_explicit(parked, sizeof(*parked));
+}
+
+/* Whether the session with this token is still on a live connection,
+ * which we leave alone; caller holds clients_lock */
+static bool ipdisp_resume_live(struct ipdisp_device *idev,
+                               struct ipdisp_client *client, const u8 *token)
+{
+    struct ipdisp_client *other;
+    
+    list_for_each_entry(other, &idev->clients, list) {
+        if (other != client && other->active && other->resumable &&
+            !crypto_memneq(other->resume_token, token,
+                           IPDISP_RESUME_TOKEN_SIZE))
+            return true;
+    }
+    return false;
+}
+
+/* Take the parked session with this token off the shelf if the client
+ * proved the token id it was parked with; caller holds clients_lock */
+static bool ipdisp_resume_take(struct ipdisp_device *idev,
+                               struct ipdisp_client *client, const u8 *token,
+                               struct ipdisp_parked_session *found)
+{
+    struct ipdisp_parked_session *parked;
+    unsigned int i;
+    
+    for (i = 0; i < ARRAY_SIZE(idev->parked); i++) {
+        parked = &idev->parked[i];
+        if (!parked->expires_ns ||
+            crypto_memneq(parked->token, token, IPDISP_RESUME_TOKEN_SIZE))
+            continue;
+        /* A token that got out is no use without the credential */
+        if (crypto_memneq(parked->auth_id, client->auth_id,
+                          sizeof(parked->auth_id))) {
+            ipdisp_warn("Client %pI4 sent client %u's resume token under another credential\n",
+                       &client->addr.sin_addr, parked->id);
+            return false;
+        }
+        *found = *parked;
+        memzero_explicit(parked, sizeof(*parked));
+        return true;
+    }
+    return false;
+}
+
+/* Send a client the token to resume its session with, making one up if it
+ * has none; caller holds client->lock */
+static int ipdisp_resume_send(struct ipdisp_client *client, bool resumed)
+{
+    struct {
+        u8 token[IPDISP_RESUME_TOKEN_SIZE];
+        __be32 resumed;
+    } __packed reply;
+    
+    if (!client->resumable)
+        get_random_bytes(client->resume_token, sizeof(client->resume_token));
+    client->resumable = true;
+    client->resume_pending = false;
+    
+    memcpy(reply.token, client->resume_token, sizeof(reply.token));
+    reply.resumed = cpu_to_be32(resumed);
+    return ipdisp_network_send_packet(client, IPDISP_PACKET_RESUME,
+                                      &reply, sizeof(reply));
+}
+
+/* Answer a client's pending RESUME, or send it its first token, once it
+ * has proved a credential; challenge it for one if nobody has. Caller
+ * holds clients_lock and client->lock. */
+int ipdisp_resume_poll(struct ipdisp_device *idev,
+                       struct ipdisp_client *client)
+{
+    struct ipdisp_parked_session found = {};
+    bool resumed = false;
+    
+    if (!client->verified) {
+        if (memchr_inv(client->challenge, 0, sizeof(client->challenge)))
+            return 0; /* Waiting for its answer */
+        return ipdisp_pair_challenge(client);
+    }
+    
+    if (client->resume_requested) {
+        if (ipdisp_resume_live(idev, client, client->resume_request))
+            return 0; /* Until the old connection is found dead */
+        if (ipdisp_resume_take(idev, client, client->resume_request,
+                               &found)) {
+            ipdisp_info("Client %u resumes its session from %pI4\n",
+                       found.id, &client->addr.sin_addr);
+            ipdisp_resume_restore(client, &found);
+            memzero_explicit(&found, sizeof(found));
+            resumed = true;
+        }
+        client->resume_requested = false;
+        memzero_explicit(client->resume_request,
+                         sizeof(client->resume_request));
+    }
+    return ipdisp_resume_send(client, resumed);
+}
+
+/* Note the session a client sends the token of, to give back once it has
+ * authenticated; caller holds client->lock */
+int ipdisp_resume_handle_request(struct ipdisp_device *idev,
+                                 struct ipdisp_client *client,
+                                 const u8 *payload, u32 size)
+{
+    if (size < IPDISP_RESUME_TOKEN_SIZE)
+        return -EPROTO;
+    if (!(client->capabilities & IPDISP_CAP_RESUME))
+        return 0;
+    
+    if (ipdisp_resume_token_set(payload)) {
+        memcpy(client->resume_request, payload,
+               sizeof(client->resume_request));
+        client->resume_requested = true;
+    }
+    /* Answered from the poll loop, after any handshake and challenge */
+    client->resume_pending = true;
+    return 0;
+}
+
+/* Keep the session of a client whose connection was lost, in place of the
+ * parked session closest to expiring when every slot is taken; caller
+ * holds clients_lock, as the client is removed */
+void ipdisp_resume_park(struct ipdisp_device *idev,
+                        struct ipdisp_client *client)
+{
+    struct ipdisp_parked_session *parked = &idev->parked[0];
+    unsigned int i;
+    
+    /* Only an authenticated session can be resumed */
+    if (!client->resumable || client->parked || !client->verified ||
+        !idev->resume_timeout_ms)
+        return;
+    
+    for (i = 1; i < ARRAY_SIZE(idev->parked); i++) {
+        if (idev->parked[i].expires_ns < parked->expires_ns)
+            parked = &idev->parked[i];
+    }
+    if (parked->expires_ns)
+        ipdisp_resume_forget(parked);
+    
+    ipdisp_resume_save(parked, client);
+    parked->expires_ns = ktime_get_ns() +
+                         (u64)idev->resume_timeout_ms * NSEC_PER_MSEC;
+    client->parked = true;
+    ipdisp_info("Keeping client %u's session for %u s\n", client->id,
+               idev->resume_timeout_ms / MSEC_PER_SEC);
+}
+
+/* Forget parked sessions nobody resumed in time; caller holds
+ * clients_lock */
+void ipdisp_resume_expire(struct ipdisp_device *idev)
+{
+    u64 now = ktime_get_ns();
+    unsigned int i;
+    
+    for (i = 0; i < ARRAY_SIZE(idev->parked); i++) {
+        if (idev->parked[i].expires_ns && now >= idev->parked[i].expires_ns)
+            ipdisp_resume_forget(&idev->parked[i]);
+    }
+}
@@ -0,0 +1,409 @@
+/* IP Display Driver - Content Signatures
+ * Copyright (C) 2024
+ * Licensed under GPL v2
+ *
+ * Content hashes are signed with Ed25519, so a client can check them but
+ * not make them, even holding its token. The key is the sign_key
+ * parameter, or made afresh when the module loads; its public half is in
+ * sysfs content_public_key and goes to clients in PAIR_CONFIRM, which is
+ * where they pin it. The kernel's crypto API only verifies signatures, so
+ * the arithmetic here follows TweetNaCl (public domain): radix 2^16 field
+ * elements, constant time, and a few milliseconds a signature, which is
+ * plenty for one a second.
+ */
+
+#include "ipdisp.h"
+
+/* A field element mod 2^255 - 19, sixteen 16-bit limbs */
+typedef s64 gf[16];
+
+static const gf gf0;
+static const gf gf1 = { 1 };
+/* 2 * d */
+static const gf D2 = {
+    0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0,
+    0xd130, 0xeef3, 0x80f2, 0x198e, 0xfce7, 0x56df, 0xd9dc, 0x2406,
+};
+/* The base point */
+static const gf X = {
+    0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c,
+    0xdc5c, 0xfdd6, 0xe231, 0xc0a4, 0x53fe, 0xcd6e, 0x36d3, 0x2169,
+};
+static const gf Y = {
+    0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+    0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+};
+/* The group order, little-endian */
+static const s64 L[32] = {
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58,
+    0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0x10,
+};
+
+static void ipdisp_gf_set(gf r, const gf a)
+{
+    memcpy(r, a, sizeof(gf));
+}
+
+static void ipdisp_gf_carry(gf o)
+{
+    s64 c;
+    int i;
+    
+    for (i = 0; i < 16; i++) {
+        o[i] += 1LL << 16;
+        c = o[i] >> 16;
+        o[(i + 1) * (i < 15)] += c - 1 + 37 * (c - 1) * (i == 15);
+        o[i] -= c * (1LL << 16);
+    }
+}
+
+/* Swap p and q if b is 1, without branching on it */
+static void ipdisp_gf_swap(gf p, gf q, int b)
+{
+    s64 t, c = ~((s64)b - 1);
+    int i;
+    
+    for (i = 0; i < 16; i++) {
+        t = c & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+static void ipdisp_gf_pack(u8 *o, const gf n)
+{
+    gf m, t;
+    int i, j, b;
+    
+    ipdisp_gf_set(t, n);
+    ipdisp_gf_carry(t);
+    ipdisp_gf_carry(t);
+    ipdisp_gf_carry(t);
+    for (j = 0; j < 2; j++) {
+        m[0] = t[0] - 0xffed;
+        for (i = 1; i < 15; i++) {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        ipdisp_gf_swap(t, m, 1 - b);
+    }
+    for (i = 0; i < 16; i++) {
+        o[2 * i] = t[i] & 0xff;
+        o[2 * i + 1] = t[i] >> 8;
+    }
+}
+
+static u8 ipdisp_gf_parity(const gf a)
+{
+    u8 d[32];
+    
+    ipdisp_gf_pack(d, a);
+    return d[0] & 1;
+}
+
+static void ipdisp_gf_add(gf o, const gf a, const gf b)
+{
+    int i;
+    
+    for (i = 0; i < 16; i++)
+        o[i] = a[i] + b[i];
+}
+
+static void ipdisp_gf_sub(gf o, const gf a, const gf b)
+{
+    int i;
+    
+    for (i = 0; i < 16; i++)
+        o[i] = a[i] - b[i];
+}
+
+static void ipdisp_gf_mul(gf o, const gf a, const gf b)
+{
+    s64 t[31];
+    int i, j;
+    
+    memset(t, 0, sizeof(t));
+    for (i = 0; i < 16; i++)
+        for (j = 0; j < 16; j++)
+            t[i + j] += a[i] * b[j];
+    /* 2^256 = 38 mod p */
+    for (i = 0; i < 15; i++)
+        t[i] += 38 * t[i + 16];
+    memcpy(o, t, sizeof(gf));
+    ipdisp_gf_carry(o);
+    ipdisp_gf_carry(o);
+}
+
+/* a^(p - 2) */
+static void ipdisp_gf_invert(gf o, const gf a)
+{
+    gf c;
+    int i;
+    
+    ipdisp_gf_set(c, a);
+    for (i = 253; i >= 0; i--) {
+        ipdisp_gf_mul(c, c, c);
+        if (i != 2 && i != 4)
+            ipdisp_gf_mul(c, c, a);
+    }
+    ipdisp_gf_set(o, c);
+}
+
+/* p += q, in extended coordinates */
+static void ipdisp_point_add(gf p[4], gf q[4])
+{
+    gf a, b, c, d, t, e, f, g, h;
+    
+    ipdisp_gf_sub(a, p[1], p[0]);
+    ipdisp_gf_sub(t, q[1], q[0]);
+    ipdisp_gf_mul(a, a, t);
+    ipdisp_gf_add(b, p[0], p[1]);
+    ipdisp_gf_add(t, q[0], q[1]);
+    ipdisp_gf_mul(b, b, t);
+    ipdisp_gf_mul(c, p[3], q[3]);
+    ipdisp_gf_mul(c, c, D2);
+    ipdisp_gf_mul(d, p[2], q[2]);
+    ipdisp_gf_add(d, d, d);
+    ipdisp_gf_sub(e, b, a);
+    ipdisp_gf_sub(f, d, c);
+    ipdisp_gf_add(g, d, c);
+    ipdisp_gf_add(h, b, a);
+    
+    ipdisp_gf_mul(p[0], e, f);
+    ipdisp_gf_mul(p[1], h, g);
+    ipdisp_gf_mul(p[2], g, f);
+    ipdisp_gf_mul(p[3], e, h);
+}
+
+static void ipdisp_point_swap(gf p[4], gf q[4], u8 b)
+{
+    int i;
+    
+    for (i = 0; i < 4; i++)
+        ipdisp_gf_swap(p[i], q[i], b);
+}
+
+static void ipdisp_point_pack(u8 *r, gf p[4])
+{
+    gf tx, ty, zi;
+    
+    ipdisp_gf_invert(zi, p[2]);
+    ipdisp_gf_mul(tx, p[0], zi);
+    ipdisp_gf_mul(ty, p[1], zi);
+    ipdisp_gf_pack(r, ty);
+    r[31] ^= ipdisp_gf_parity(tx) << 7;
+}
+
+/* p = s * B, for the base point B */
+static void ipdisp_point_base_mul(gf p[4], const u8 *s)
+{
+    gf q[4];
+    u8 b;
+    int i;
+    
+    ipdisp_gf_set(p[0], gf0);
+    ipdisp_gf_set(p[1], gf1);
+    ipdisp_gf_set(p[2], gf1);
+    ipdisp_gf_set(p[3], gf0);
+    ipdisp_gf_set(q[0], X);
+    ipdisp_gf_set(q[1], Y);
+    ipdisp_gf_set(q[2], gf1);
+    ipdisp_gf_mul(q[3], X, Y);
+    
+    /* Montgomery ladder, the same steps whatever the scalar */
+    for (i = 255; i >= 0; i--) {
+        b = (s[i / 8] >> (i & 7)) & 1;
+        ipdisp_point_swap(p, q, b);
+        ipdisp_point_add(q, p);
+        ipdisp_point_add(p, p);
+        ipdisp_point_swap(p, q, b);
+    }
+    memzero_explicit(q, sizeof(q));
+}
+
+/* r = x mod L, for x in 64 radix 2^8 limbs */
+static void ipdisp_scalar_mod(u8 *r, s64 x[64])
+{
+    s64 carry;
+    int i, j;
+    
+    for (i = 63; i >= 32; i--) {
+        carry = 0;
+        for (j = i - 32; j < i - 12; j++) {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry * 256;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+    carry = 0;
+    for (j = 0; j < 32; j++) {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for (j = 0; j < 32; j++)
+        x[j] -= carry * L[j];
+    for (i = 0; i < 32; i++) {
+        x[i + 1] += x[i] >> 8;
+        r[i] = x[i] & 255;
+    }
+}
+
+/* Reduce a 64-byte hash mod L into its first 32 bytes */
+static void ipdisp_scalar_reduce(u8 *r)
+{
+    s64 x[64];
+    int i;
+    
+    for (i = 0; i < 64; i++)
+        x[i] = r[i];
+    memset(r, 0, 64);
+    ipdisp_scalar_mod(r, x);
+}
+
+/* out = SHA-512(a || b || c); b and c may be empty */
+static int ipdisp_sign_hash(struct ipdisp_device *idev, const u8 *a,
+                            size_t a_len, const u8 *b, size_t b_len,
+                            const void *c, size_t c_len, u8 *out)
+{
+    SHASH_DESC_ON_STACK(desc, idev->sign_sha512);
+    int ret;
+    
+    desc->tfm = idev->sign_sha512;
+    ret = crypto_shash_init(desc);
+    if (!ret)
+        ret = crypto_shash_update(desc, a, a_len);
+    if (!ret && b_len)
+        ret = crypto_shash_update(desc, b, b_len);
+    if (!ret && c_len)
+        ret = crypto_shash_update(desc, c, c_len);
+    if (!ret)
+        ret = crypto_shash_final(desc, out);
+    shash_desc_zero(desc);
+    return ret;
+}
+
+/* Sign len bytes at msg into sig[IPDISP_SIGNATURE_SIZE] */
+int ipdisp_sign(struct ipdisp_device *idev, const void *msg, size_t len,
+                u8 *sig)
+{
+    u8 r[SHA512_DIGEST_SIZE], h[SHA512_DIGEST_SIZE];
+    gf p[4];
+    s64 x[64];
+    int i, j, ret;
+    
+    /* R = rB for r = H(prefix || M) */
+    ret = ipdisp_sign_hash(idev, idev->sign_secret + 32, 32, NULL, 0,
+                           msg, len, r);
+    if (ret)
+        goto out;
+    ipdisp_scalar_reduce(r);
+    ipdisp_point_base_mul(p, r);
+    ipdisp_point_pack(sig, p);
+    
+    /* S = r + H(R || A || M) a mod L */
+    ret = ipdisp_sign_hash(idev, sig, 32, idev->sign_public,
+                           sizeof(idev->sign_public), msg, len, h);
+    if (ret)
+        goto out;
+    ipdisp_scalar_reduce(h);
+    memset(x, 0, sizeof(x));
+    for (i = 0; i < 32; i++)
+        x[i] = r[i];
+    for (i = 0; i < 32; i++)
+        for (j = 0; j < 32; j++)
+            x[i + j] += h[i] * (s64)idev->sign_secret[j];
+    ipdisp_scalar_mod(sig + 32, x);
+    
+out:
+    memzero_explicit(r, sizeof(r));
+    memzero_explicit(x, sizeof(x));
+    memzero_explicit(p, sizeof(p));
+    return ret;
+}
+
+/* The public key clients pin, as hex */
+static ssize_t content_public_key_show(struct device *dev,
+                                       struct device_attribute *attr,
+                                       char *buf)
+{
+    struct ipdisp_device *idev = dev_get_drvdata(dev);
+    
+    return sysfs_emit(buf, "%*phN\n", IPDISP_SIGN_KEY_SIZE,
+                      idev->sign_public);
+}
+static DEVICE_ATTR_RO(content_public_key);
+
+/* Take the key from the sign_key parameter, 64 hex digits of seed, or
+ * make one that lasts until the module is unloaded */
+int ipdisp_sign_init(struct ipdisp_device *idev, const char *key)
+{
+    u8 seed[IPDISP_SIGN_KEY_SIZE];
+    gf p[4];
+    int ret;
+    
+    if (key && *key) {
+        if (strlen(key) != 2 * sizeof(seed) ||
+            hex2bin(seed, key, sizeof(seed))) {
+            ipdisp_err("sign_key must be %zu hex digits\n", 2 * sizeof(seed));
+            return -EINVAL;
+        }
+    } else {
+        get_random_bytes(seed, sizeof(seed));
+    }
+    
+    idev->sign_sha512 = crypto_alloc_shash("sha512", 0, 0);
+    if (IS_ERR(idev->sign_sha512)) {
+        ret = PTR_ERR(idev->sign_sha512);
+        idev->sign_sha512 = NULL;
+        goto err;
+    }
+    
+    /* The secret scalar and the nonce prefix, as RFC 8032 expands them */
+    ret = crypto_shash_tfm_digest(idev->sign_sha512, seed, sizeof(seed),
+                                  idev->sign_secret);
+    if (ret)
+        goto err_hash;
+    idev->sign_secret[0] &= 248;
+    idev->sign_secret[31] &= 127;
+    idev->sign_secret[31] |= 64;
+    ipdisp_point_base_mul(p, idev->sign_secret);
+    ipdisp_point_pack(idev->sign_public, p);
+    memzero_explicit(p, sizeof(p));
+    
+    ret = device_create_file(&idev->pdev->dev, &dev_attr_content_public_key);
+    if (ret)
+        goto err_hash;
+    
+    memzero_explicit(seed, sizeof(seed));
+    ipdisp_info("Signing content hashes with %*phN\n", IPDISP_SIGN_KEY_SIZE,
+                idev->sign_public);
+    return 0;
+    
+err_hash:
+    crypto_free_shash(idev->sign_sha512);
+    idev->sign_sha512 = NULL;
+err:
+    memzero_explicit(seed, sizeof(seed));
+    memzero_explicit(idev->sign_secret, sizeof(idev->sign_secret));
+    ipdisp_err("Failed to set up content signatures: %d\n", ret);
+    return ret;
+}
+
+void ipdisp_sign_cleanup(struct ipdisp_device *idev)
+{
+    if (!idev->sign_sha512)
+        return;
+    
+    device_remove_file(&idev->pdev->dev, &dev_attr_content_public_key);
+    crypto_free_shash(idev->sign_sha512);
+    idev->sign_sha512 = NULL;
+    memzero_explicit(idev->sign_secret, sizeof(idev->sign_secret));
+}
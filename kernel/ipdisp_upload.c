@@ -0,0 +1,249 @@
+/* IP Display Driver - File Uploads
+ * Copyright (C) 2024
+ * Licensed under GPL v2
+ *
+ * Files dropped on a client's window arrive as FILE_BEGIN, with the file's
+ * name and size, then FILE_DATA chunks in order. With upload_dir set, a
+ * verified client's file is written there under its own name, never over
+ * a file that exists. Being let in without require_pairing isn't enough,
+ * as the files are written as root. Each request is answered with FILE_STATUS,
+ * which also paces the client: it only sends a few chunks ahead of the
+ * answers. A file that doesn't arrive whole is removed again.
+ */
+
+#include "ipdisp.h"
+
+/* Kept once allocated, so chunks still on their way after a failure can
+ * be read and dropped */
+struct ipdisp_upload {
+    struct file *file;   /* NULL between uploads */
+    u32 id;
+    u64 size;
+    u64 received;
+    /* Room for a whole FILE_DATA packet, too big for the stack */
+    struct {
+        struct ipdisp_packet_header header;
+        u8 payload[IPDISP_FILE_DATA_MAX];
+    } __packed buf;
+};
+
+static int ipdisp_upload_status(struct ipdisp_client *client, u32 id,
+                                s32 status, u64 received)
+{
+    struct {
+        __be64 received;
+        __be32 id;
+        __be32 status;
+    } __packed reply = {
+        .received = cpu_to_be64(received),
+        .id = cpu_to_be32(id),
+        .status = cpu_to_be32(status),
+    };
+    
+    return ipdisp_network_send_packet(client, IPDISP_PACKET_FILE_STATUS,
+                                      &reply, sizeof(reply));
+}
+
+/* A plain file name: no directories, nothing hidden, no control bytes */
+static bool ipdisp_upload_name_ok(const u8 *name, u32 len)
+{
+    u32 i;
+    
+    if (!len || len > IPDISP_FILE_NAME_MAX || name[0] == '.')
+        return false;
+    for (i = 0; i < len; i++) {
+        if (name[i] == '/' || name[i] < 0x20 || name[i] == 0x7f)
+            return false;
+    }
+    return true;
+}
+
+/* Close the file, removing it unless it arrived whole */
+static void ipdisp_upload_close(struct ipdisp_client *client)
+{
+    struct ipdisp_upload *upload = client->upload;
+    struct dentry *dentry, *parent;
+    
+    if (!upload || !upload->file)
+        return;
+    
+    if (upload->received < upload->size) {
+        dentry = dget(upload->file->f_path.dentry);
+        parent = dget_parent(dentry);
+        inode_lock_nested(d_inode(parent), I_MUTEX_PARENT);
+        if (dentry->d_parent == parent && d_inode(dentry))
+            vfs_unlink(file_mnt_idmap(upload->file), d_inode(parent),
+                       dentry, NULL);
+        inode_unlock(d_inode(parent));
+        dput(parent);
+        dput(dentry);
+        ipdisp_warn("Upload from %pI4 stopped at %llu of %llu bytes\n",
+                   &client->addr.sin_addr, upload->received, upload->size);
+    }
+    filp_close(upload->file, NULL);
+    upload->file = NULL;
+}
+
+int ipdisp_upload_handle_begin(struct ipdisp_device *idev,
+                               struct ipdisp_client *client,
+                               const u8 *payload, u32 size)
+{
+    struct ipdisp_upload *upload;
+    struct file *file;
+    u32 id, name_len;
+    u64 file_size;
+    char *path;
+    int status = 0;
+    
+    if (size < 16)
+        return -EPROTO;
+    file_size = be64_to_cpup((const __be64 *)payload);
+    id = be32_to_cpup((const __be32 *)(payload + 8));
+    name_len = be32_to_cpup((const __be32 *)(payload + 12));
+    if (name_len > size - 16)
+        return -EPROTO;
+    
+    /* A new file replaces one that never finished */
+    ipdisp_upload_close(client);
+    
+    if (!idev->upload_dir || !*idev->upload_dir)
+        status = -EACCES;
+    else if (!client->verified ||
+             client->session_mode < IPDISP_SESSION_FULL)
+        status = -EPERM;
+    else if (file_size > (u64)idev->upload_max_mb * 1000000)
+        status = -EFBIG;
+    else if (!ipdisp_upload_name_ok(payload + 16, name_len))
+        status = -EINVAL;
+    /* Ask an unverified client for proof, which it answers on its own if
+     * it has paired, so the next drop goes through */
+    if (status == -EPERM && !client->verified &&
+        !memchr_inv(client->challenge, 0, sizeof(client->challenge)) &&
+        ipdisp_pair_challenge(client) < 0)
+        return -EIO;
+    if (status)
+        return ipdisp_upload_status(client, id, status, 0);
+    
+    if (!client->upload) {
+        client->upload = kvzalloc(sizeof(*client->upload), GFP_KERNEL);
+        if (!client->upload)
+            return ipdisp_upload_status(client, id, -ENOMEM, 0);
+    }
+    upload = client->upload;
+    
+    path = kasprintf(GFP_KERNEL, "%s/%.*s", idev->upload_dir, name_len,
+                     payload + 16);
+    if (!path)
+        return ipdisp_upload_status(client, id, -ENOMEM, 0);
+    
+    file = filp_open(path, O_WRONLY | O_CREAT | O_EXCL | O_NOFOLLOW |
+                     O_LARGEFILE, 0644);
+    if (IS_ERR(file)) {
+        ipdisp_warn("Can't create upload %s: %ld\n", path, PTR_ERR(file));
+        kfree(path);
+        return ipdisp_upload_status(client, id, PTR_ERR(file), 0);
+    }
+    ipdisp_info("Receiving %s (%llu bytes) from %pI4\n", path, file_size,
+                &client->addr.sin_addr);
+    kfree(path);
+    
+    upload->file = file;
+    upload->id = id;
+    upload->size = file_size;
+    upload->received = 0;
+    
+    if (!file_size) {
+        ipdisp_upload_close(client);
+        return ipdisp_upload_status(client, id, 1, 0);
+    }
+    return ipdisp_upload_status(client, id, 0, 0);
+}
+
+/* Append a chunk; an empty one means the client gave up */
+int ipdisp_upload_handle_data(struct ipdisp_client *client,
+                              const u8 *payload, u32 size)
+{
+    struct ipdisp_upload *upload = client->upload;
+    loff_t pos;
+    ssize_t written;
+    u32 id, len;
+    
+    if (size < sizeof(__be32))
+        return -EPROTO;
+    id = be32_to_cpup((const __be32 *)payload);
+    len = size - sizeof(__be32);
+    if (!upload || !upload->file || upload->id != id)
+        return 0; /* Already refused or abandoned */
+    
+    /* The client left full control part way through */
+    if (client->session_mode < IPDISP_SESSION_FULL) {
+        ipdisp_upload_close(client);
+        return ipdisp_upload_status(client, id, -EPERM, 0);
+    }
+    if (!len) {
+        ipdisp_upload_close(client);
+        return 0;
+    }
+    if (len > upload->size - upload->received) {
+        ipdisp_upload_close(client);
+        return ipdisp_upload_status(client, id, -EFBIG, 0);
+    }
+    
+    pos = upload->received;
+    written = kernel_write(upload->file, payload + sizeof(__be32), len, &pos);
+    if (written != len) {
+        ipdisp_upload_close(client);
+        return ipdisp_upload_status(client, id,
+                                    written < 0 ? written : -EIO, 0);
+    }
+    upload->received += len;
+    
+    if (upload->received == upload->size) {
+        ipdisp_info("Upload from %pI4 complete\n", &client->addr.sin_addr);
+        ipdisp_upload_close(client);
+        return ipdisp_upload_status(client, id, 1, pos);
+    }
+    return ipdisp_upload_status(client, id, 0, upload->received);
+}
+
+/* Read a FILE_DATA packet too large for the usual request buffer, without
+ * blocking; 0 until all of it has arrived */
+int ipdisp_upload_recv(struct ipdisp_client *client, u32 size)
+{
+    struct ipdisp_upload *upload = client->upload;
+    size_t total = sizeof(upload->buf.header) + size;
+    struct kvec iov;
+    struct msghdr msg;
+    int ret;
+    
+    if (!upload || size > IPDISP_FILE_DATA_MAX)
+        return -EMSGSIZE;
+    
+    iov.iov_base = &upload->buf;
+    iov.iov_len = total;
+    memset(&msg, 0, sizeof(msg));
+    ret = kernel_recvmsg(client->sock, &msg, &iov, 1, total,
+                        MSG_DONTWAIT | MSG_PEEK);
+    if (ret == -EAGAIN || ret == -EWOULDBLOCK)
+        return 0;
+    if (ret < 0)
+        return ret;
+    if (ret < total)
+        return 0;
+    
+    memset(&msg, 0, sizeof(msg));
+    ret = kernel_recvmsg(client->sock, &msg, &iov, 1, total, MSG_DONTWAIT);
+    if (ret != total)
+        return ret < 0 ? ret : -EIO;
+    
+    client->last_rx_ns = ktime_get_ns();
+    ret = ipdisp_upload_handle_data(client, upload->buf.payload, size);
+    return ret < 0 ? ret : 1;
+}
+
+void ipdisp_upload_forget_client(struct ipdisp_client *client)
+{
+    ipdisp_upload_close(client);
+    kvfree(client->upload);
+    client->upload = NULL;
+}
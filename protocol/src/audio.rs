@@ -0,0 +1,124 @@
+// IP Display Protocol - Audio
+// Copyright (c) 2024
+// Licensed under MIT
+
+use alloc::vec::Vec;
+use anyhow::Result;
+use bytes::BufMut;
+use core::fmt;
+
+use crate::ByteOrder;
+
+/// Most channels a stream may have
+pub const MAX_CHANNELS: u32 = 8;
+
+/// Bytes of format ahead of an AUDIO packet's samples
+pub const AUDIO_HEADER_SIZE: usize = 12;
+
+/// Largest AUDIO payload taken
+pub const MAX_AUDIO_SIZE: usize = 64 * 1024;
+
+/// How samples are encoded
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AudioCodec {
+    /// Interleaved signed 16-bit little-endian samples
+    Pcm = 0,
+    /// One Opus packet per chunk
+    Opus = 1,
+}
+
+impl TryFrom<u32> for AudioCodec {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(AudioCodec::Pcm),
+            1 => Ok(AudioCodec::Opus),
+            _ => Err(anyhow::anyhow!("Invalid audio codec: {}", value)),
+        }
+    }
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AudioCodec::Pcm => "PCM",
+            AudioCodec::Opus => "Opus",
+        })
+    }
+}
+
+/// What the samples of a chunk are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub codec: AudioCodec,
+    /// Hz
+    pub rate: u32,
+    pub channels: u32,
+}
+
+impl AudioFormat {
+    pub fn new(codec: AudioCodec, rate: u32, channels: u32) -> Result<Self> {
+        if rate == 0 || !(1..=MAX_CHANNELS).contains(&channels) {
+            return Err(anyhow::anyhow!("Invalid audio format: {} Hz, {} channels", rate, channels));
+        }
+        Ok(Self { codec, rate, channels })
+    }
+
+    /// Bytes of PCM samples a second; `None` for compressed audio
+    pub fn byte_rate(&self) -> Option<u32> {
+        (self.codec == AudioCodec::Pcm).then_some(self.rate * self.channels * 2)
+    }
+}
+
+impl fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {} Hz, {} channels", self.codec, self.rate, self.channels)
+    }
+}
+
+/// The format of an AUDIO packet's samples, and the samples
+pub fn parse_audio(payload: &[u8], order: ByteOrder) -> Result<(AudioFormat, &[u8])> {
+    if payload.len() < AUDIO_HEADER_SIZE {
+        return Err(anyhow::anyhow!("Audio payload too short: {} bytes", payload.len()));
+    }
+
+    let mut buf = payload;
+    let codec = AudioCodec::try_from(order.get_u32(&mut buf))?;
+    let rate = order.get_u32(&mut buf);
+    let channels = order.get_u32(&mut buf);
+    Ok((AudioFormat::new(codec, rate, channels)?, &payload[AUDIO_HEADER_SIZE..]))
+}
+
+/// An AUDIO payload: `format` followed by `samples`
+pub fn audio_payload(format: AudioFormat, samples: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(AUDIO_HEADER_SIZE + samples.len());
+    payload.put_u32(format.codec as u32);
+    payload.put_u32(format.rate);
+    payload.put_u32(format.channels);
+    payload.put_slice(samples);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio() {
+        let mut payload = Vec::new();
+        for word in [0u32, 48000, 2] {
+            payload.extend_from_slice(&word.to_be_bytes());
+        }
+        payload.extend_from_slice(&[1, 2, 3, 4]);
+        let (format, samples) = parse_audio(&payload, ByteOrder::Big).unwrap();
+        assert_eq!(format, AudioFormat::new(AudioCodec::Pcm, 48000, 2).unwrap());
+        assert_eq!(samples, [1, 2, 3, 4]);
+        assert_eq!(audio_payload(format, samples), payload);
+
+        assert!(parse_audio(&payload[..8], ByteOrder::Big).is_err());
+        payload[3] = 7;
+        assert!(parse_audio(&payload, ByteOrder::Big).is_err());
+    }
+}
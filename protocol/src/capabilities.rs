@@ -0,0 +1,91 @@
+// IP Display Protocol - Capabilities
+// Copyright (c) 2024
+// Licensed under MIT
+
+use core::fmt;
+
+bitflags::bitflags! {
+    /// Optional protocol features. The client offers those it takes in
+    /// `Command::Hello` and the server answers with those it will use in
+    /// `ServerMessage::Capabilities`; neither side uses a feature the other
+    /// hasn't agreed to. A new feature gets the next bit, here and as
+    /// `IPDISP_CAP_*` in the kernel's header.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct Capabilities: u32 {
+        /// A CRC-32 of the payload follows each frame header
+        const CRC32 = 1 << 0;
+        /// Compact headers for frames that repeat the last one's
+        const COMPACT_HEADER = 1 << 1;
+        /// A signed content hash ahead of each frame
+        const CONTENT_HASH = 1 << 2;
+        /// Frames shown a playout delay after their timestamps
+        const SYNC = 1 << 3;
+        /// FORMAT ahead of frames in a new format
+        const FORMAT_ANNOUNCE = 1 << 4;
+        /// DAMAGE in place of RGBA32 frames that barely changed
+        const DAMAGE = 1 << 5;
+        /// The server's sound
+        const AUDIO = 1 << 6;
+        /// ACK for frames shown, with frames held to an ACK window
+        const ACK = 1 << 7;
+        /// Everything after the handshake encrypted; see `NOISE_PATTERN`
+        const NOISE = 1 << 8;
+        /// `ServerMessage::InputControl` whenever control changes hands
+        const INPUT_CONTROL = 1 << 9;
+        /// `ServerMessage::Resume` with a token to resume the session with
+        /// after a reconnect
+        const RESUME = 1 << 10;
+        /// `ServerMessage::AuthPrompt` for the user to answer, and
+        /// `Command::AuthReply` to a challenge without a credential
+        const AUTH_PROMPT = 1 << 11;
+    }
+}
+
+impl Capabilities {
+    /// Whether every feature in `wanted` is here
+    pub fn supports(self, wanted: Capabilities) -> bool {
+        self.contains(wanted)
+    }
+
+    /// The features both sides take
+    pub fn intersect(self, other: Capabilities) -> Capabilities {
+        self & other
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("none");
+        }
+        for (i, (name, _)) in self.iter_names().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_capabilities() {
+        let client = Capabilities::CRC32 | Capabilities::SYNC | Capabilities::ACK;
+        let server = Capabilities::all() - Capabilities::SYNC;
+        let agreed = client.intersect(server);
+        assert_eq!(agreed, Capabilities::CRC32 | Capabilities::ACK);
+        assert!(agreed.supports(Capabilities::ACK));
+        assert!(agreed.supports(Capabilities::empty()));
+        assert!(!agreed.supports(Capabilities::ACK | Capabilities::SYNC));
+
+        assert_eq!(agreed.to_string(), "CRC32, ACK");
+        assert_eq!(Capabilities::empty().to_string(), "none");
+        // Bits from a newer peer are dropped rather than kept unnamed
+        assert_eq!(Capabilities::from_bits_truncate(1 << 31 | 1), Capabilities::CRC32);
+    }
+}
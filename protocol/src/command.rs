@@ -0,0 +1,611 @@
+// IP Display Protocol - Client Requests
+// Copyright (c) 2024
+// Licensed under MIT
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use anyhow::Result;
+use bytes::{BufMut, BytesMut};
+
+use crate::{control_packet, ByteOrder, Capabilities, FrameFormat, InputControlAction, PacketType, SessionMode, RESUME_TOKEN_SIZE};
+
+/// Requests sent from the client to the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Flash the edge of the server's output for this long; it answers
+    /// with `ServerMessage::Identify`
+    Identify { duration_ms: u32 },
+    /// Switch the virtual display to the given mode (refresh in mHz, 0 = any)
+    RequestMode { width: u32, height: u32, refresh_mhz: u32 },
+    /// Handshake describing the client display (refresh in mHz, 0 = unknown).
+    /// Connections sharing a non-zero `session_id` are aggregated links of
+    /// one client, served according to `link_mode`; `capabilities` are the
+    /// features offered, which the server answers with those it will use,
+    /// and `mode` the session mode asked for.
+    Hello { refresh_mhz: u32, session_id: u32, link_mode: u32, capabilities: Capabilities, mode: SessionMode },
+    /// Clock probe stamped with the local send time
+    Ping { client_ns: u64 },
+    /// Keepalive sent when the client has been otherwise quiet
+    Heartbeat,
+    /// Sent before closing so the server can drop us straight away
+    Goodbye,
+    /// Start pairing (see `pairing`)
+    PairCommit { commitment: [u8; 32] },
+    PairReveal { public: [u8; 32], nonce: [u8; 16] },
+    PairConfirm { mac: [u8; 32] },
+    /// Answer an AuthChallenge with the token identified by `id`
+    Auth { id: [u8; 8], mac: [u8; 32] },
+    /// Ask for the frame stamped `timestamp` again, as it arrived corrupted
+    Resend { timestamp: u64 },
+    /// Ask the server for a touchscreen with this many contacts
+    TouchDevice { slots: u32 },
+    /// Limit the stream: bit rate in kbit/s and frame rate (0 = no limit),
+    /// resolution divided by `scale` (1, 2 or 4), pixels sent as `format`
+    Quality { max_kbps: u32, scale: u32, max_fps: u32, format: FrameFormat },
+    /// Ask the server to act on itself (authenticated clients only);
+    /// `arg` is the source for `SetSource` and 0 otherwise
+    Supervise { action: SuperviseAction, arg: u32 },
+    /// Send only this rectangle of the display, or all of it with a zero
+    /// `width`
+    Crop { x: u32, y: u32, width: u32, height: u32 },
+    /// Stop sending frames, or start again with a fresh one
+    Pause { paused: bool },
+    /// Contacts that went down, moved or lifted on the virtual
+    /// touchscreen, reported together
+    Touch { contacts: Vec<TouchContact> },
+    /// Move the server's mouse by `dx`, `dy`, turn its wheel by `wheel`
+    /// steps (positive away from the user) and hold `buttons`
+    /// (`POINTER_BUTTON_*`)
+    Pointer { dx: i32, dy: i32, wheel: i32, buttons: u32 },
+    /// Press or release evdev key `code` on the server's keyboard
+    Key { code: u32, pressed: bool },
+    /// Start uploading a file of `size` bytes as `name`
+    FileBegin { id: u32, size: u64, name: String },
+    /// The next bytes of upload `id`; none to give it up
+    FileData { id: u32, data: Vec<u8> },
+    /// Every frame up to the one stamped `timestamp` is through; it
+    /// arrived and was shown at these times on the server's clock (0 =
+    /// unknown)
+    Ack { timestamp: u64, received_ns: u64, presented_ns: u64 },
+    /// The client's first or last Noise handshake message
+    Noise { message: Vec<u8> },
+    /// Switch to another session mode
+    SessionMode { mode: SessionMode },
+    /// Ask for, give up or take control of the server's input devices
+    InputControl { action: InputControlAction },
+    /// Pick up the session this token came with, if the server still
+    /// keeps it
+    Resume { token: [u8; RESUME_TOKEN_SIZE] },
+    /// Answer a `ServerMessage::AuthPrompt`; empty to decline it, or to
+    /// say there is no credential for an AuthChallenge
+    AuthReply { text: String },
+}
+
+impl Command {
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Command::Identify { .. } => PacketType::Identify,
+            Command::RequestMode { .. } => PacketType::ModeRequest,
+            Command::Hello { .. } => PacketType::Hello,
+            Command::Ping { .. } => PacketType::Ping,
+            Command::Heartbeat => PacketType::Heartbeat,
+            Command::Goodbye => PacketType::Goodbye,
+            Command::PairCommit { .. } => PacketType::PairCommit,
+            Command::PairReveal { .. } => PacketType::PairReveal,
+            Command::PairConfirm { .. } => PacketType::PairConfirm,
+            Command::Auth { .. } => PacketType::Auth,
+            Command::Resend { .. } => PacketType::Resend,
+            Command::TouchDevice { .. } => PacketType::TouchDevice,
+            Command::Quality { .. } => PacketType::Quality,
+            Command::Supervise { .. } => PacketType::Supervise,
+            Command::Crop { .. } => PacketType::Crop,
+            Command::Pause { .. } => PacketType::Pause,
+            Command::Touch { .. } => PacketType::Touch,
+            Command::Pointer { .. } => PacketType::Pointer,
+            Command::Key { .. } => PacketType::Key,
+            Command::FileBegin { .. } => PacketType::FileBegin,
+            Command::FileData { .. } => PacketType::FileData,
+            Command::Ack { .. } => PacketType::Ack,
+            Command::Noise { .. } => PacketType::Noise,
+            Command::SessionMode { .. } => PacketType::SessionMode,
+            Command::InputControl { .. } => PacketType::InputControl,
+            Command::Resume { .. } => PacketType::Resume,
+            Command::AuthReply { .. } => PacketType::AuthReply,
+        }
+    }
+
+    /// Encode the payload alone, in network order
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = BytesMut::new();
+        match self {
+            Command::Identify { duration_ms } => payload.put_u32(*duration_ms),
+            Command::RequestMode { width, height, refresh_mhz } => {
+                payload.put_u32(*width);
+                payload.put_u32(*height);
+                payload.put_u32(*refresh_mhz);
+            }
+            Command::Hello { refresh_mhz, session_id, link_mode, capabilities, mode } => {
+                payload.put_u32(*refresh_mhz);
+                payload.put_u32(*session_id);
+                payload.put_u32(*link_mode);
+                payload.put_u32(capabilities.bits());
+                payload.put_u32(*mode as u32);
+            }
+            Command::Ping { client_ns } => payload.put_u64(*client_ns),
+            Command::Heartbeat | Command::Goodbye => {}
+            Command::PairCommit { commitment } => payload.put_slice(commitment),
+            Command::PairReveal { public, nonce } => {
+                payload.put_slice(public);
+                payload.put_slice(nonce);
+            }
+            Command::PairConfirm { mac } => payload.put_slice(mac),
+            Command::Auth { id, mac } => {
+                payload.put_slice(id);
+                payload.put_slice(mac);
+            }
+            Command::Resend { timestamp } => payload.put_u64(*timestamp),
+            Command::TouchDevice { slots } => payload.put_u32(*slots),
+            Command::Quality { max_kbps, scale, max_fps, format } => {
+                payload.put_u32(*max_kbps);
+                payload.put_u32(*scale);
+                payload.put_u32(*max_fps);
+                payload.put_u32(*format as u32);
+            }
+            Command::Supervise { action, arg } => {
+                payload.put_u32(*action as u32);
+                payload.put_u32(*arg);
+            }
+            Command::Crop { x, y, width, height } => {
+                payload.put_u32(*x);
+                payload.put_u32(*y);
+                payload.put_u32(*width);
+                payload.put_u32(*height);
+            }
+            Command::Pause { paused } => payload.put_u32(*paused as u32),
+            Command::Touch { contacts } => {
+                payload.put_u32(contacts.len() as u32);
+                for contact in contacts {
+                    payload.put_u32(contact.slot);
+                    payload.put_u32(contact.x);
+                    payload.put_u32(contact.y);
+                    payload.put_u32(contact.pressure);
+                }
+            }
+            Command::Pointer { dx, dy, wheel, buttons } => {
+                payload.put_i32(*dx);
+                payload.put_i32(*dy);
+                payload.put_i32(*wheel);
+                payload.put_u32(*buttons);
+            }
+            Command::Key { code, pressed } => {
+                payload.put_u32(*code);
+                payload.put_u32(*pressed as u32);
+            }
+            Command::FileBegin { id, size, name } => {
+                payload.put_u64(*size);
+                payload.put_u32(*id);
+                payload.put_u32(name.len() as u32);
+                payload.put_slice(name.as_bytes());
+            }
+            Command::FileData { id, data } => {
+                payload.put_u32(*id);
+                payload.put_slice(data);
+            }
+            Command::Ack { timestamp, received_ns, presented_ns } => {
+                payload.put_u64(*timestamp);
+                payload.put_u64(*received_ns);
+                payload.put_u64(*presented_ns);
+            }
+            Command::Noise { message } => payload.put_slice(message),
+            Command::SessionMode { mode } => payload.put_u32(*mode as u32),
+            Command::InputControl { action } => payload.put_u32(*action as u32),
+            Command::Resume { token } => payload.put_slice(token),
+            Command::AuthReply { text } => payload.put_slice(text.as_bytes()),
+        }
+        payload.to_vec()
+    }
+
+    /// Encode as a complete packet (header followed by payload)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        control_packet(self.packet_type(), &self.to_payload())
+    }
+
+    /// Decode a request as the server reads it. Longer payloads are taken;
+    /// Hello and Quality may stop short, as from older clients, and the
+    /// fields they leave out are zero, RGBA32 and full control.
+    pub fn from_payload(packet_type: PacketType, payload: &[u8], order: ByteOrder) -> Result<Self> {
+        let need = |size: usize| {
+            if payload.len() < size {
+                return Err(anyhow::anyhow!("{:?} payload too short: {} bytes", packet_type, payload.len()));
+            }
+            Ok(())
+        };
+        let mut buf = payload;
+        let buf = &mut buf;
+
+        Ok(match packet_type {
+            PacketType::Identify => {
+                need(4)?;
+                Command::Identify { duration_ms: order.get_u32(buf) }
+            }
+            PacketType::ModeRequest => {
+                need(12)?;
+                Command::RequestMode {
+                    width: order.get_u32(buf),
+                    height: order.get_u32(buf),
+                    refresh_mhz: order.get_u32(buf),
+                }
+            }
+            PacketType::Hello => {
+                need(4)?;
+                let refresh_mhz = order.get_u32(buf);
+                // Session fields come as a pair
+                let (session_id, link_mode) = if buf.len() >= 8 {
+                    (order.get_u32(buf), order.get_u32(buf))
+                } else {
+                    (0, 0)
+                };
+                // Bits this side doesn't know are features it can't agree to
+                let capabilities = if buf.len() >= 4 { order.get_u32(buf) } else { 0 };
+                let capabilities = Capabilities::from_bits_truncate(capabilities);
+                // Clients from before session modes had full control; a
+                // mode this side doesn't know gets the least
+                let mode = if buf.len() >= 4 {
+                    SessionMode::try_from(order.get_u32(buf)).unwrap_or(SessionMode::View)
+                } else {
+                    SessionMode::Full
+                };
+                Command::Hello { refresh_mhz, session_id, link_mode, capabilities, mode }
+            }
+            PacketType::Ping => {
+                need(8)?;
+                Command::Ping { client_ns: order.get_u64(buf) }
+            }
+            PacketType::Heartbeat => Command::Heartbeat,
+            PacketType::Goodbye => Command::Goodbye,
+            PacketType::PairCommit => {
+                need(32)?;
+                Command::PairCommit { commitment: payload[..32].try_into()? }
+            }
+            PacketType::PairReveal => {
+                need(48)?;
+                Command::PairReveal { public: payload[..32].try_into()?, nonce: payload[32..48].try_into()? }
+            }
+            PacketType::PairConfirm => {
+                need(32)?;
+                Command::PairConfirm { mac: payload[..32].try_into()? }
+            }
+            PacketType::Auth => {
+                need(40)?;
+                Command::Auth { id: payload[..8].try_into()?, mac: payload[8..40].try_into()? }
+            }
+            PacketType::Resend => {
+                need(8)?;
+                Command::Resend { timestamp: order.get_u64(buf) }
+            }
+            PacketType::TouchDevice => {
+                need(4)?;
+                Command::TouchDevice { slots: order.get_u32(buf) }
+            }
+            PacketType::Quality => {
+                need(12)?;
+                Command::Quality {
+                    max_kbps: order.get_u32(buf),
+                    scale: order.get_u32(buf),
+                    max_fps: order.get_u32(buf),
+                    format: if buf.len() >= 4 {
+                        FrameFormat::try_from(order.get_u32(buf))?
+                    } else {
+                        FrameFormat::Rgba32
+                    },
+                }
+            }
+            PacketType::Supervise => {
+                need(8)?;
+                Command::Supervise { action: SuperviseAction::try_from(order.get_u32(buf))?, arg: order.get_u32(buf) }
+            }
+            PacketType::Crop => {
+                need(16)?;
+                Command::Crop {
+                    x: order.get_u32(buf),
+                    y: order.get_u32(buf),
+                    width: order.get_u32(buf),
+                    height: order.get_u32(buf),
+                }
+            }
+            PacketType::Pause => {
+                need(4)?;
+                Command::Pause { paused: order.get_u32(buf) != 0 }
+            }
+            PacketType::Touch => {
+                need(4)?;
+                let count = order.get_u32(buf) as usize;
+                need(count.saturating_mul(16).saturating_add(4))?;
+                let contacts = (0..count)
+                    .map(|_| TouchContact {
+                        slot: order.get_u32(buf),
+                        x: order.get_u32(buf),
+                        y: order.get_u32(buf),
+                        pressure: order.get_u32(buf),
+                    })
+                    .collect();
+                Command::Touch { contacts }
+            }
+            PacketType::Pointer => {
+                need(16)?;
+                Command::Pointer {
+                    dx: order.get_u32(buf) as i32,
+                    dy: order.get_u32(buf) as i32,
+                    wheel: order.get_u32(buf) as i32,
+                    buttons: order.get_u32(buf),
+                }
+            }
+            PacketType::Key => {
+                need(8)?;
+                Command::Key { code: order.get_u32(buf), pressed: order.get_u32(buf) != 0 }
+            }
+            PacketType::FileBegin => {
+                need(16)?;
+                let size = order.get_u64(buf);
+                let id = order.get_u32(buf);
+                let length = order.get_u32(buf) as usize;
+                need(length.saturating_add(16))?;
+                let name = String::from_utf8(buf[..length].to_vec())
+                    .map_err(|_| anyhow::anyhow!("File name isn't UTF-8"))?;
+                Command::FileBegin { id, size, name }
+            }
+            PacketType::FileData => {
+                need(4)?;
+                Command::FileData { id: order.get_u32(buf), data: buf.to_vec() }
+            }
+            PacketType::Ack => {
+                need(24)?;
+                Command::Ack {
+                    timestamp: order.get_u64(buf),
+                    received_ns: order.get_u64(buf),
+                    presented_ns: order.get_u64(buf),
+                }
+            }
+            PacketType::Noise => Command::Noise { message: payload.to_vec() },
+            PacketType::SessionMode => {
+                need(4)?;
+                Command::SessionMode { mode: SessionMode::try_from(order.get_u32(buf))? }
+            }
+            PacketType::InputControl => {
+                need(4)?;
+                Command::InputControl { action: InputControlAction::try_from(order.get_u32(buf))? }
+            }
+            PacketType::Resume => {
+                need(RESUME_TOKEN_SIZE)?;
+                Command::Resume { token: payload[..RESUME_TOKEN_SIZE].try_into()? }
+            }
+            PacketType::AuthReply => {
+                let text = String::from_utf8(payload.to_vec()).map_err(|_| anyhow::anyhow!("Auth reply isn't UTF-8"))?;
+                Command::AuthReply { text }
+            }
+            other => return Err(anyhow::anyhow!("{:?} is not a request", other)),
+        })
+    }
+}
+
+/// A contact on the virtual touchscreen, at a pixel of the remote display
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TouchContact {
+    pub slot: u32,
+    pub x: u32,
+    pub y: u32,
+    /// Up to `MAX_TOUCH_PRESSURE`; 0 once the contact has lifted
+    pub pressure: u32,
+}
+
+/// Pressure of a firm touch, and of any from a panel that can't tell
+pub const MAX_TOUCH_PRESSURE: u32 = 255;
+
+/// Buttons held on the server's mouse, as bits of `Command::Pointer`
+pub const POINTER_BUTTON_LEFT: u32 = 1 << 0;
+pub const POINTER_BUTTON_RIGHT: u32 = 1 << 1;
+pub const POINTER_BUTTON_MIDDLE: u32 = 1 << 2;
+
+/// Things a client can ask the server to do to itself, e.g. when the
+/// stream has wedged
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuperviseAction {
+    /// Send every client a full header and a fresh frame
+    ResendFrame = 0,
+    /// Stream another capture source, numbered from 0
+    SetSource = 1,
+    /// Start new log files
+    RotateLogs = 2,
+    /// Close the capture backend and open it again
+    RestartCapture = 3,
+}
+
+impl TryFrom<u32> for SuperviseAction {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(SuperviseAction::ResendFrame),
+            1 => Ok(SuperviseAction::SetSource),
+            2 => Ok(SuperviseAction::RotateLogs),
+            3 => Ok(SuperviseAction::RestartCapture),
+            _ => Err(anyhow::anyhow!("Invalid supervise action: {}", value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PacketHeader, HEADER_SIZE, NOISE_START_SIZE};
+    use alloc::vec;
+
+    #[test]
+    fn test_command_encoding() {
+        let bytes = Command::Identify { duration_ms: 3000 }.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + 4);
+
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.packet_type, PacketType::Identify);
+        assert_eq!(header.size, 4);
+        assert!(!header.is_info_packet());
+        assert!(header.validate().is_ok());
+        assert_eq!(bytes[HEADER_SIZE..], 3000u32.to_be_bytes());
+
+        let bytes = Command::RequestMode { width: 2560, height: 1440, refresh_mhz: 144000 }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.packet_type, PacketType::ModeRequest);
+        assert_eq!(header.size, 12);
+        assert_eq!(bytes[HEADER_SIZE..HEADER_SIZE + 4], 2560u32.to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 8..], 144000u32.to_be_bytes());
+
+        let bytes = Command::Hello { refresh_mhz: 59940, session_id: 7, link_mode: 1, capabilities: Capabilities::CRC32, mode: SessionMode::Input }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.packet_type, PacketType::Hello);
+        assert_eq!(header.size, 20);
+        assert_eq!(bytes[HEADER_SIZE + 12..HEADER_SIZE + 16], Capabilities::CRC32.bits().to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 16..], 1u32.to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE..HEADER_SIZE + 4], 59940u32.to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 4..HEADER_SIZE + 8], 7u32.to_be_bytes());
+
+        let bytes = Command::Quality { max_kbps: 8000, scale: 2, max_fps: 30, format: FrameFormat::Nv12 }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.packet_type, PacketType::Quality);
+        assert_eq!(header.size, 16);
+        assert_eq!(bytes[HEADER_SIZE + 12..], 5u32.to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 4..HEADER_SIZE + 8], 2u32.to_be_bytes());
+
+        let bytes = Command::Crop { x: 1920, y: 0, width: 1920, height: 1080 }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!((header.packet_type, header.size), (PacketType::Crop, 16));
+        assert_eq!(bytes[HEADER_SIZE..HEADER_SIZE + 4], 1920u32.to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 12..], 1080u32.to_be_bytes());
+
+        let bytes = Command::Pause { paused: true }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!((header.packet_type, header.size), (PacketType::Pause, 4));
+        assert_eq!(bytes[HEADER_SIZE..], 1u32.to_be_bytes());
+
+        let bytes = Command::Ping { client_ns: 42 }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.packet_type, PacketType::Ping);
+        assert!(!header.is_frame_packet());
+        assert_eq!(bytes[HEADER_SIZE..], 42u64.to_be_bytes());
+
+        let bytes = Command::Heartbeat.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE);
+        assert_eq!(PacketHeader::from_bytes(&bytes).unwrap().packet_type, PacketType::Heartbeat);
+
+        let bytes = Command::Goodbye.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE);
+        assert_eq!(PacketHeader::from_bytes(&bytes).unwrap().packet_type, PacketType::Goodbye);
+
+        let bytes = Command::Auth { id: [1; 8], mac: [2; 32] }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.packet_type, PacketType::Auth);
+        assert_eq!(header.size, 40);
+        assert_eq!(bytes[HEADER_SIZE..HEADER_SIZE + 8], [1; 8]);
+    }
+
+    #[test]
+    fn test_pointer() {
+        let bytes = Command::Pointer { dx: -3, dy: 5, wheel: 1, buttons: POINTER_BUTTON_RIGHT }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!((header.packet_type, header.size), (PacketType::Pointer, 16));
+        assert_eq!(bytes[HEADER_SIZE..HEADER_SIZE + 4], (-3i32).to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 8..HEADER_SIZE + 12], 1i32.to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 12..], 2u32.to_be_bytes());
+
+        let bytes = Command::Key { code: 30, pressed: true }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!((header.packet_type, header.size), (PacketType::Key, 8));
+        assert_eq!(bytes[HEADER_SIZE..], [0, 0, 0, 30, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_file_transfer() {
+        let bytes = Command::FileBegin { id: 7, size: 1 << 33, name: "notes.txt".into() }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!((header.packet_type, header.size), (PacketType::FileBegin, 25));
+        assert_eq!(bytes[HEADER_SIZE..HEADER_SIZE + 8], (1u64 << 33).to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 12..HEADER_SIZE + 16], 9u32.to_be_bytes());
+        assert_eq!(&bytes[HEADER_SIZE + 16..], b"notes.txt");
+
+        let bytes = Command::FileData { id: 7, data: vec![1, 2, 3] }.to_bytes();
+        assert_eq!(bytes[HEADER_SIZE..], [0, 0, 0, 7, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_command_round_trip() {
+        let commands = [
+            Command::Identify { duration_ms: 3000 },
+            Command::RequestMode { width: 2560, height: 1440, refresh_mhz: 144000 },
+            Command::Hello { refresh_mhz: 59940, session_id: 7, link_mode: 1, capabilities: Capabilities::CRC32, mode: SessionMode::View },
+            Command::Ping { client_ns: 42 },
+            Command::Heartbeat,
+            Command::Goodbye,
+            Command::PairCommit { commitment: [1; 32] },
+            Command::PairReveal { public: [2; 32], nonce: [3; 16] },
+            Command::PairConfirm { mac: [4; 32] },
+            Command::Auth { id: [5; 8], mac: [6; 32] },
+            Command::Resend { timestamp: 1 << 40 },
+            Command::TouchDevice { slots: 10 },
+            Command::Quality { max_kbps: 8000, scale: 2, max_fps: 30, format: FrameFormat::Rgb565 },
+            Command::Supervise { action: SuperviseAction::RotateLogs, arg: 0 },
+            Command::Crop { x: 1920, y: 0, width: 1920, height: 1080 },
+            Command::Pause { paused: true },
+            Command::Touch {
+                contacts: vec![
+                    TouchContact { slot: 0, x: 100, y: 200, pressure: MAX_TOUCH_PRESSURE },
+                    TouchContact { slot: 1, x: 300, y: 400, pressure: 0 },
+                ],
+            },
+            Command::Pointer { dx: -3, dy: 5, wheel: -1, buttons: POINTER_BUTTON_LEFT | POINTER_BUTTON_MIDDLE },
+            Command::Key { code: 30, pressed: false },
+            Command::FileBegin { id: 7, size: 1 << 33, name: "notes.txt".into() },
+            Command::FileData { id: 7, data: vec![1, 2, 3] },
+            Command::Ack { timestamp: 1 << 40, received_ns: (1 << 40) + 5, presented_ns: 0 },
+            Command::Noise { message: vec![7; NOISE_START_SIZE] },
+            Command::SessionMode { mode: SessionMode::Input },
+            Command::InputControl { action: InputControlAction::Steal },
+            Command::Resume { token: [6; RESUME_TOKEN_SIZE] },
+            Command::AuthReply { text: "hunter2".into() },
+            Command::AuthReply { text: String::new() },
+        ];
+        for command in commands {
+            let bytes = command.to_bytes();
+            let header = PacketHeader::from_bytes(&bytes).unwrap();
+            assert_eq!(header.size as usize, bytes.len() - HEADER_SIZE);
+            let decoded = Command::from_payload(header.packet_type, &bytes[HEADER_SIZE..], header.byte_order).unwrap();
+            assert_eq!(decoded, command);
+
+            // Every field counts
+            let payload = command.to_payload();
+            if !payload.is_empty() && !matches!(command, Command::Hello { .. } | Command::Quality { .. } | Command::FileData { .. } | Command::Noise { .. } | Command::AuthReply { .. }) {
+                assert!(Command::from_payload(command.packet_type(), &payload[..payload.len() - 1], ByteOrder::Big).is_err());
+            }
+        }
+
+        // What older clients send
+        let hello = Command::from_payload(PacketType::Hello, &60000u32.to_be_bytes(), ByteOrder::Big).unwrap();
+        assert_eq!(
+            hello,
+            Command::Hello { refresh_mhz: 60000, session_id: 0, link_mode: 0, capabilities: Capabilities::empty(), mode: SessionMode::Full }
+        );
+        let newer = [&[0; 16][..], &9u32.to_be_bytes()].concat();
+        let hello = Command::from_payload(PacketType::Hello, &newer, ByteOrder::Big).unwrap();
+        assert!(matches!(hello, Command::Hello { mode: SessionMode::View, .. }));
+        let quality = Command::from_payload(PacketType::Quality, &[0; 12], ByteOrder::Big).unwrap();
+        assert_eq!(quality, Command::Quality { max_kbps: 0, scale: 0, max_fps: 0, format: FrameFormat::Rgba32 });
+
+        // Little-endian senders, and things that aren't requests
+        let key = Command::from_payload(PacketType::Key, &[30, 0, 0, 0, 1, 0, 0, 0], ByteOrder::Little).unwrap();
+        assert_eq!(key, Command::Key { code: 30, pressed: true });
+        assert!(Command::from_payload(PacketType::Pong, &[0; 24], ByteOrder::Big).is_err());
+        assert!(Command::from_payload(PacketType::Supervise, &[0, 0, 0, 9, 0, 0, 0, 0], ByteOrder::Big).is_err());
+        assert!(Command::from_payload(PacketType::AuthReply, &[0xff, 0xfe], ByteOrder::Big).is_err());
+        let huge = [0xff; 4];
+        assert!(Command::from_payload(PacketType::Touch, &huge, ByteOrder::Big).is_err());
+    }
+}
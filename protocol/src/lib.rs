@@ -0,0 +1,889 @@
+// IP Display Protocol - Wire Format
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! The wire format spoken between IP Display servers and clients: packet
+//! headers, the requests a client sends (`Command`) and the control
+//! messages a server sends back (`ServerMessage`), each with an encoder and
+//! a decoder. The kernel module is the reference server and can't link
+//! Rust, so the tests check its `ipdisp.h` against this crate instead;
+//! neither half can renumber anything alone.
+//!
+//! Without the default `std` feature the crate is `no_std` and only needs
+//! `alloc`; headers it creates are then stamped 0.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(any(feature = "std", test))]
+extern crate std;
+
+use alloc::vec::Vec;
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+mod audio;
+mod capabilities;
+mod command;
+mod noise;
+mod server;
+mod session;
+
+pub use audio::*;
+pub use capabilities::*;
+pub use command::*;
+pub use noise::*;
+pub use server::*;
+pub use session::*;
+
+// Protocol constants
+pub const MAGIC: u32 = 0x49504453; // "IPDS"
+pub const VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 36;
+/// Version 2 headers append the row stride to the version 1 fields
+pub const VERSION_STRIDE: u32 = 2;
+pub const HEADER_SIZE_V2: usize = HEADER_SIZE + 4;
+
+/// The packet type word carries the type in its low half and flags above
+const PACKET_TYPE_MASK: u32 = 0xffff;
+/// A big-endian CRC-32 of the payload follows the header
+const FLAG_CRC32: u32 = 1 << 31;
+pub const CRC_SIZE: usize = 4;
+
+/// Largest control payload either side reads, uploads' FILE_DATA aside.
+/// Control payloads only grow: a newer peer appends fields, and a reader
+/// takes the ones it knows and skips the rest.
+pub const MAX_CONTROL_SIZE: usize = 256;
+
+/// Compact frame headers are this long and start with a byte whose high
+/// nibble is `COMPACT_MARKER`; full headers start with the magic's 'I'
+pub const COMPACT_HEADER_SIZE: usize = 8;
+const COMPACT_MARKER: u8 = 0xc0;
+const COMPACT_FLAG_CRC32: u8 = 1 << 0;
+/// Mask of the 24-bit timestamp step in microseconds
+const COMPACT_MAX_DELTA_US: u32 = 0xff_ffff;
+
+/// Length of the token a client resumes its session with after it
+/// reconnects; all zeros is no token
+pub const RESUME_TOKEN_SIZE: usize = 16;
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FrameFormat {
+    Rgba32 = 0,
+    Rgb24 = 1,
+    H264 = 2,
+    H265 = 3,
+    /// 4:2:0 with separate U and V planes (I420)
+    Yuv420p = 4,
+    /// 4:2:0 with interleaved UV (NV12)
+    Nv12 = 5,
+    /// 16-bit little-endian 5:6:5, blue in the low bits
+    Rgb565 = 6,
+    /// 32-bit little-endian, 10 bits each of R, G, B from the low bits up
+    /// and 2 bits of straight alpha on top
+    Rgba1010102 = 7,
+    /// NV12 layout with 10-bit samples in the top of 16-bit little-endian
+    /// words (P010)
+    P010 = 8,
+    /// One baseline or progressive JPEG image per frame (MJPEG)
+    Jpeg = 9,
+}
+
+/// Packet type, carried in the header word that v1 senders leave zeroed
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PacketType {
+    /// Display info (size == 0) or frame data
+    Display = 0,
+    /// Client asks the server to flash its display identifier
+    Identify = 1,
+    /// Client asks the server to switch its virtual display mode
+    ModeRequest = 2,
+    /// Client handshake, sent once after connecting
+    Hello = 3,
+    /// Client clock probe, answered with a Pong
+    Ping = 4,
+    /// Server reply to a Ping with its receive and send times
+    Pong = 5,
+    /// Keepalive from either side after a second with nothing else to send
+    Heartbeat = 6,
+    /// Either side is closing the connection on purpose
+    Goodbye = 7,
+    /// Client starts pairing with a hash of its key and nonce
+    PairCommit = 8,
+    /// Server's pairing key and nonce; it now shows the pairing code
+    PairKey = 9,
+    /// Client's pairing key and nonce, matching its commitment
+    PairReveal = 10,
+    /// Proof of the shared pairing key, from the client once the user has
+    /// entered the code and then from the server
+    PairConfirm = 11,
+    /// Server nonce a paired client must answer before it gets frames
+    AuthChallenge = 12,
+    /// Client's answer to an AuthChallenge
+    Auth = 13,
+    /// Client asks for a fresh frame after one failed its checksum
+    Resend = 14,
+    /// Client asks for a virtual touchscreen; the server answers with its
+    /// geometry once registered
+    TouchDevice = 15,
+    /// Client asks the server to lower (or restore) bit rate, resolution
+    /// or frame rate
+    Quality = 16,
+    /// Server's signed SHA-256 of the next frame, for clients that asked
+    ContentHash = 17,
+    /// Server's playout delay for clients presenting in step
+    Sync = 18,
+    /// Client asks an authorised server to act on itself; the server
+    /// answers with the outcome
+    Supervise = 19,
+    /// Server announces the format of the frames that follow, ahead of the
+    /// first one in a new format
+    Format = 20,
+    /// Client asks for only part of the display, e.g. its tile of a video
+    /// wall
+    Crop = 21,
+    /// Client asks for no frames while nobody can see them, or for them
+    /// again
+    Pause = 22,
+    /// Client's touches, for the virtual touchscreen it asked for
+    Touch = 23,
+    /// Client's captured mouse, for the server's virtual mouse
+    Pointer = 24,
+    /// Client starts uploading a file
+    FileBegin = 25,
+    /// Client's next bytes of the file it is uploading
+    FileData = 26,
+    /// Server's progress on an upload
+    FileStatus = 27,
+    /// Server's update of the part of the last RGBA32 frame that changed
+    Damage = 28,
+    /// Client's key presses while its pointer is captured, for the
+    /// server's virtual keyboard
+    Key = 29,
+    /// Server's sound, with its format
+    Audio = 30,
+    /// Client's acknowledgement of the frames up to one it has shown
+    Ack = 31,
+    /// Server's answer to Hello: the capabilities it will use
+    Capabilities = 32,
+    /// A Noise handshake message, either way; see `NOISE_PATTERN`
+    Noise = 33,
+    /// Client asks for another session mode; the server answers with the
+    /// one in effect
+    SessionMode = 34,
+    /// Client asks for, gives up or takes input control; the server tells
+    /// every client who holds it
+    InputControl = 35,
+    /// Client offers the token of a session to resume; the server answers
+    /// with the token to resume this one with
+    Resume = 36,
+    /// Server's prompt or notice from a userspace server's auth provider,
+    /// for clients that agreed to `Capabilities::AUTH_PROMPT`
+    AuthPrompt = 37,
+    /// Client's answer to an AuthPrompt, or to an AuthChallenge it has no
+    /// credential for
+    AuthReply = 38,
+}
+
+impl TryFrom<u32> for PacketType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(PacketType::Display),
+            1 => Ok(PacketType::Identify),
+            2 => Ok(PacketType::ModeRequest),
+            3 => Ok(PacketType::Hello),
+            4 => Ok(PacketType::Ping),
+            5 => Ok(PacketType::Pong),
+            6 => Ok(PacketType::Heartbeat),
+            7 => Ok(PacketType::Goodbye),
+            8 => Ok(PacketType::PairCommit),
+            9 => Ok(PacketType::PairKey),
+            10 => Ok(PacketType::PairReveal),
+            11 => Ok(PacketType::PairConfirm),
+            12 => Ok(PacketType::AuthChallenge),
+            13 => Ok(PacketType::Auth),
+            14 => Ok(PacketType::Resend),
+            15 => Ok(PacketType::TouchDevice),
+            16 => Ok(PacketType::Quality),
+            17 => Ok(PacketType::ContentHash),
+            18 => Ok(PacketType::Sync),
+            19 => Ok(PacketType::Supervise),
+            20 => Ok(PacketType::Format),
+            21 => Ok(PacketType::Crop),
+            22 => Ok(PacketType::Pause),
+            23 => Ok(PacketType::Touch),
+            24 => Ok(PacketType::Pointer),
+            25 => Ok(PacketType::FileBegin),
+            26 => Ok(PacketType::FileData),
+            27 => Ok(PacketType::FileStatus),
+            28 => Ok(PacketType::Damage),
+            29 => Ok(PacketType::Key),
+            30 => Ok(PacketType::Audio),
+            31 => Ok(PacketType::Ack),
+            32 => Ok(PacketType::Capabilities),
+            33 => Ok(PacketType::Noise),
+            34 => Ok(PacketType::SessionMode),
+            35 => Ok(PacketType::InputControl),
+            36 => Ok(PacketType::Resume),
+            37 => Ok(PacketType::AuthPrompt),
+            38 => Ok(PacketType::AuthReply),
+            _ => Err(anyhow::anyhow!("Invalid packet type: {}", value)),
+        }
+    }
+}
+
+impl FrameFormat {
+    /// Bytes per pixel of the packed RGB formats; `None` for planar and
+    /// compressed ones
+    pub fn bytes_per_pixel(self) -> Option<usize> {
+        match self {
+            FrameFormat::Rgba32 | FrameFormat::Rgba1010102 => Some(4),
+            FrameFormat::Rgb24 => Some(3),
+            FrameFormat::Rgb565 => Some(2),
+            FrameFormat::Yuv420p | FrameFormat::Nv12 | FrameFormat::P010 => None,
+            FrameFormat::H264 | FrameFormat::H265 | FrameFormat::Jpeg => None,
+        }
+    }
+}
+
+impl TryFrom<u32> for FrameFormat {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(FrameFormat::Rgba32),
+            1 => Ok(FrameFormat::Rgb24),
+            2 => Ok(FrameFormat::H264),
+            3 => Ok(FrameFormat::H265),
+            4 => Ok(FrameFormat::Yuv420p),
+            5 => Ok(FrameFormat::Nv12),
+            6 => Ok(FrameFormat::Rgb565),
+            7 => Ok(FrameFormat::Rgba1010102),
+            8 => Ok(FrameFormat::P010),
+            9 => Ok(FrameFormat::Jpeg),
+            _ => Err(anyhow::anyhow!("Invalid frame format: {}", value)),
+        }
+    }
+}
+
+/// Byte order of a peer's headers and control payloads. The magic tells
+/// them apart: senders that write native little-endian structs put "SDPI"
+/// on the wire instead of "IPDS".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ByteOrder {
+    /// Network order, used by the kernel module and for everything we send
+    #[default]
+    Big,
+    Little,
+}
+
+impl ByteOrder {
+    /// Byte order of a header starting with these bytes, if it's one of ours
+    pub fn detect(magic: [u8; 4]) -> Option<Self> {
+        match u32::from_be_bytes(magic) {
+            MAGIC => Some(ByteOrder::Big),
+            swapped if swapped == MAGIC.swap_bytes() => Some(ByteOrder::Little),
+            _ => None,
+        }
+    }
+
+    pub fn get_u32(self, buf: &mut &[u8]) -> u32 {
+        match self {
+            ByteOrder::Big => buf.get_u32(),
+            ByteOrder::Little => buf.get_u32_le(),
+        }
+    }
+
+    pub fn get_u64(self, buf: &mut &[u8]) -> u64 {
+        match self {
+            ByteOrder::Big => buf.get_u64(),
+            ByteOrder::Little => buf.get_u64_le(),
+        }
+    }
+
+    fn put_u32(self, buf: &mut BytesMut, value: u32) {
+        match self {
+            ByteOrder::Big => buf.put_u32(value),
+            ByteOrder::Little => buf.put_u32_le(value),
+        }
+    }
+
+    fn put_u64(self, buf: &mut BytesMut, value: u64) {
+        match self {
+            ByteOrder::Big => buf.put_u64(value),
+            ByteOrder::Little => buf.put_u64_le(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PacketHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    pub format: FrameFormat,
+    pub timestamp: u64,
+    pub size: u32,
+    pub packet_type: PacketType,
+    /// Bytes from one row of a packed RGB frame to the next; 0 means rows
+    /// aren't padded. Only version 2 headers carry it.
+    pub stride: u32,
+    /// CRC-32 of the payload, sent to clients with `Capabilities::CRC32`
+    pub crc32: Option<u32>,
+    /// How the sender wrote this header and its control payload
+    pub byte_order: ByteOrder,
+}
+
+impl PacketHeader {
+    pub fn new(width: u32, height: u32, format: FrameFormat, size: u32) -> Self {
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            width,
+            height,
+            format,
+            timestamp: now_ns(),
+            size,
+            packet_type: PacketType::Display,
+            stride: 0,
+            crc32: None,
+            byte_order: ByteOrder::Big,
+        }
+    }
+
+    /// Header of a control packet carrying `size` bytes of payload
+    pub fn control(packet_type: PacketType, size: u32) -> Self {
+        Self { packet_type, ..Self::new(0, 0, FrameFormat::Rgba32, size) }
+    }
+
+    fn byte_order_of(start: &[u8]) -> Result<ByteOrder> {
+        let magic = [start[0], start[1], start[2], start[3]];
+        ByteOrder::detect(magic)
+            .ok_or_else(|| anyhow::anyhow!("Invalid magic number: 0x{:08x}", u32::from_be_bytes(magic)))
+    }
+
+    /// Length of the fixed part of a full header, from its first bytes
+    pub fn fixed_size(start: &[u8; COMPACT_HEADER_SIZE]) -> Result<usize> {
+        let order = Self::byte_order_of(start)?;
+        match order.get_u32(&mut &start[4..]) {
+            VERSION => Ok(HEADER_SIZE),
+            VERSION_STRIDE => Ok(HEADER_SIZE_V2),
+            version => Err(anyhow::anyhow!("Unsupported version: {}", version)),
+        }
+    }
+
+    /// Bytes following the fixed header that belong to it, judging by its
+    /// flags
+    pub fn extension_size(fixed: &[u8; HEADER_SIZE]) -> Result<usize> {
+        let order = Self::byte_order_of(fixed)?;
+        let packet_type_raw = order.get_u32(&mut &fixed[32..]);
+        Ok(if packet_type_raw & FLAG_CRC32 != 0 { CRC_SIZE } else { 0 })
+    }
+
+    /// Parse a header, including the extension after the fixed part when
+    /// its flags announce one
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE {
+            return Err(anyhow::anyhow!("Header too short: {} bytes", data.len()));
+        }
+
+        let fixed_size = Self::fixed_size(data[..COMPACT_HEADER_SIZE].try_into()?)?;
+        let mut buf = data.get(..fixed_size)
+            .ok_or_else(|| anyhow::anyhow!("Header too short: {} bytes", data.len()))?;
+
+        // Little-endian senders are read as if they had swapped every field
+        let order = Self::byte_order_of(buf)?;
+        let magic = order.get_u32(&mut buf);
+        let version = order.get_u32(&mut buf);
+        let width = order.get_u32(&mut buf);
+        let height = order.get_u32(&mut buf);
+        let format_raw = order.get_u32(&mut buf);
+        let timestamp = order.get_u64(&mut buf);
+        let size = order.get_u32(&mut buf);
+        let packet_type_raw = order.get_u32(&mut buf);
+        let stride = if buf.has_remaining() { order.get_u32(&mut buf) } else { 0 };
+
+        let format = FrameFormat::try_from(format_raw)?;
+        let packet_type = PacketType::try_from(packet_type_raw & PACKET_TYPE_MASK)?;
+
+        let crc32 = if packet_type_raw & FLAG_CRC32 != 0 {
+            let mut extension = data.get(fixed_size..fixed_size + CRC_SIZE)
+                .ok_or_else(|| anyhow::anyhow!("Header too short for its CRC"))?;
+            Some(order.get_u32(&mut extension))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            magic,
+            version,
+            width,
+            height,
+            format,
+            timestamp,
+            size,
+            packet_type,
+            stride,
+            crc32,
+            byte_order: order,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(HEADER_SIZE_V2 + CRC_SIZE);
+        let order = self.byte_order;
+
+        order.put_u32(&mut buf, self.magic);
+        order.put_u32(&mut buf, self.version);
+        order.put_u32(&mut buf, self.width);
+        order.put_u32(&mut buf, self.height);
+        order.put_u32(&mut buf, self.format as u32);
+        order.put_u64(&mut buf, self.timestamp);
+        order.put_u32(&mut buf, self.size);
+        match self.crc32 {
+            Some(_) => order.put_u32(&mut buf, self.packet_type as u32 | FLAG_CRC32),
+            None => order.put_u32(&mut buf, self.packet_type as u32),
+        }
+        if self.version >= VERSION_STRIDE {
+            order.put_u32(&mut buf, self.stride);
+        }
+        if let Some(crc) = self.crc32 {
+            order.put_u32(&mut buf, crc);
+        }
+
+        buf.to_vec()
+    }
+
+    pub fn is_info_packet(&self) -> bool {
+        self.packet_type == PacketType::Display && self.size == 0
+    }
+
+    /// Display packet carrying pixel data
+    pub fn is_frame_packet(&self) -> bool {
+        self.packet_type == PacketType::Display && self.size > 0
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.magic != MAGIC {
+            return Err(anyhow::anyhow!("Invalid magic number"));
+        }
+
+        if self.version != VERSION && self.version != VERSION_STRIDE {
+            return Err(anyhow::anyhow!("Unsupported version"));
+        }
+
+        // Only display packets describe a frame
+        if self.packet_type != PacketType::Display {
+            return Ok(());
+        }
+
+        if self.width == 0 || self.height == 0 {
+            return Err(anyhow::anyhow!("Invalid dimensions: {}x{}", self.width, self.height));
+        }
+
+        if self.width > 7680 || self.height > 4320 {
+            return Err(anyhow::anyhow!("Dimensions too large: {}x{}", self.width, self.height));
+        }
+
+        if self.stride != 0 {
+            let Some(bpp) = self.format.bytes_per_pixel() else {
+                return Err(anyhow::anyhow!("Row stride given for {:?} frame", self.format));
+            };
+            if (self.stride as usize) < self.width as usize * bpp {
+                return Err(anyhow::anyhow!(
+                    "Row stride {} too small for {} pixels", self.stride, self.width
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Nanoseconds since the Unix epoch
+#[cfg(feature = "std")]
+fn now_ns() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+/// Without a clock, senders stamp their frames themselves
+#[cfg(not(feature = "std"))]
+fn now_ns() -> u64 {
+    0
+}
+
+/// `payload` behind a control header, as sent
+fn control_packet(packet_type: PacketType, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = PacketHeader::control(packet_type, payload.len() as u32).to_bytes();
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Short header for a frame that has the same geometry and format as the
+/// frame before it on the connection, sent to clients with
+/// `Capabilities::COMPACT_HEADER`: marker and flags, a 24-bit timestamp step in
+/// microseconds and the payload size, optionally followed by a CRC-32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactHeader {
+    pub delta_us: u32,
+    pub size: u32,
+    pub crc32: bool,
+}
+
+impl CompactHeader {
+    /// Whether a header starting with `first` is compact
+    pub fn is_compact(first: u8) -> bool {
+        first & 0xf0 == COMPACT_MARKER
+    }
+
+    /// Bytes following the compact header that belong to it
+    pub fn extension_size(&self) -> usize {
+        if self.crc32 { CRC_SIZE } else { 0 }
+    }
+
+    pub fn from_bytes(data: &[u8; COMPACT_HEADER_SIZE]) -> Result<Self> {
+        if !Self::is_compact(data[0]) {
+            return Err(anyhow::anyhow!("Not a compact header: 0x{:02x}", data[0]));
+        }
+
+        let mut buf = &data[..];
+        let word = buf.get_u32();
+        Ok(Self {
+            delta_us: word & COMPACT_MAX_DELTA_US,
+            size: buf.get_u32(),
+            crc32: data[0] & COMPACT_FLAG_CRC32 != 0,
+        })
+    }
+
+    /// Compact form of `header`, if it only differs from `previous` in
+    /// timestamp, size and CRC
+    pub fn between(previous: &PacketHeader, header: &PacketHeader) -> Option<Self> {
+        let same = (previous.width, previous.height, previous.format, previous.stride, previous.version)
+            == (header.width, header.height, header.format, header.stride, header.version)
+            && previous.packet_type == header.packet_type
+            && previous.byte_order == header.byte_order;
+        let delta_us = header.timestamp.checked_sub(previous.timestamp)? / 1000;
+        if !same || delta_us > COMPACT_MAX_DELTA_US as u64 || delta_us * 1000 + previous.timestamp != header.timestamp {
+            return None;
+        }
+        Some(Self { delta_us: delta_us as u32, size: header.size, crc32: header.crc32.is_some() })
+    }
+
+    /// Encode as sent, without the CRC extension
+    pub fn to_bytes(self) -> [u8; COMPACT_HEADER_SIZE] {
+        let flags = if self.crc32 { COMPACT_FLAG_CRC32 } else { 0 };
+        let word = ((COMPACT_MARKER | flags) as u32) << 24 | (self.delta_us & COMPACT_MAX_DELTA_US);
+        let mut bytes = [0u8; COMPACT_HEADER_SIZE];
+        bytes[..4].copy_from_slice(&word.to_be_bytes());
+        bytes[4..].copy_from_slice(&self.size.to_be_bytes());
+        bytes
+    }
+
+    /// Full header for this frame, given the previous frame's header;
+    /// `crc` is the extension value if the flag is set
+    pub fn expand(&self, previous: &PacketHeader, crc: Option<u32>) -> PacketHeader {
+        PacketHeader {
+            timestamp: previous.timestamp + self.delta_us as u64 * 1000,
+            size: self.size,
+            crc32: crc,
+            ..previous.clone()
+        }
+    }
+}
+
+/// Parse a header as read off the wire, full or compact with its
+/// extension; a compact header repeats `previous`, the last frame header on
+/// the same connection
+pub fn parse_header(data: &[u8], previous: Option<&PacketHeader>) -> Result<PacketHeader> {
+    let Some(&first) = data.first() else {
+        return Err(anyhow::anyhow!("Header too short: 0 bytes"));
+    };
+    if !CompactHeader::is_compact(first) {
+        return PacketHeader::from_bytes(data);
+    }
+
+    let start = data.get(..COMPACT_HEADER_SIZE)
+        .ok_or_else(|| anyhow::anyhow!("Header too short: {} bytes", data.len()))?;
+    let compact = CompactHeader::from_bytes(start.try_into()?)?;
+    let crc = if compact.crc32 {
+        let extension = data.get(COMPACT_HEADER_SIZE..COMPACT_HEADER_SIZE + CRC_SIZE)
+            .ok_or_else(|| anyhow::anyhow!("Header too short for its CRC"))?;
+        Some(u32::from_be_bytes(extension.try_into()?))
+    } else {
+        None
+    };
+    previous
+        .map(|previous| compact.expand(previous, crc))
+        .ok_or_else(|| anyhow::anyhow!("Compact header before any full frame header"))
+}
+
+/// Where a stream that has lost its place picks up again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resync {
+    /// A full header that parses and validates starts at this offset
+    Found(usize),
+    /// Nothing before this offset starts one; more bytes are needed to
+    /// judge the rest
+    NeedMore(usize),
+}
+
+/// Scan `data` for the next full header, by its magic in either byte order.
+/// Compact headers have no magic, so the stream picks up at a full one
+pub fn find_header(data: &[u8]) -> Resync {
+    let mut start = 0;
+    while let Some(found) = data[start..].windows(4).position(|magic| ByteOrder::detect(magic.try_into().unwrap()).is_some()) {
+        let at = start + found;
+        match header_at(&data[at..]) {
+            Some(true) => return Resync::Found(at),
+            Some(false) => start = at + 1,
+            None => return Resync::NeedMore(at),
+        }
+    }
+    // The last bytes could be the start of a magic
+    Resync::NeedMore(start.max(data.len().saturating_sub(3)))
+}
+
+/// Whether `data` starts with a valid full header; `None` if it is too
+/// short to tell
+fn header_at(data: &[u8]) -> Option<bool> {
+    let Ok(fixed_size) = PacketHeader::fixed_size(data.get(..COMPACT_HEADER_SIZE)?.try_into().unwrap()) else {
+        return Some(false);
+    };
+    let extension_size = PacketHeader::extension_size(data.get(..HEADER_SIZE)?.try_into().unwrap()).ok()?;
+    let header = data.get(..fixed_size + extension_size)?;
+    Some(PacketHeader::from_bytes(header).is_ok_and(|header| header.validate().is_ok()))
+}
+
+/// Encode `header` as sent to a client with `capabilities`: compact when it
+/// takes that and the header repeats `previous`, followed by the CRC
+/// extension
+pub fn encode_header(header: &PacketHeader, previous: Option<&PacketHeader>, capabilities: Capabilities) -> Vec<u8> {
+    let compact = previous
+        .filter(|_| capabilities.supports(Capabilities::COMPACT_HEADER))
+        .and_then(|previous| CompactHeader::between(previous, header));
+    let Some(compact) = compact else { return header.to_bytes() };
+    let mut bytes = compact.to_bytes().to_vec();
+    if let Some(crc) = header.crc32 {
+        bytes.extend_from_slice(&crc.to_be_bytes());
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::{String, ToString};
+
+    #[test]
+    fn test_header_serialization() {
+        let header = PacketHeader::new(1920, 1080, FrameFormat::Rgba32, 1024);
+        let bytes = header.to_bytes();
+        let parsed = PacketHeader::from_bytes(&bytes).unwrap();
+
+        assert_eq!(header.magic, parsed.magic);
+        assert_eq!(header.width, parsed.width);
+        assert_eq!(header.height, parsed.height);
+        assert_eq!(header.format, parsed.format);
+        assert_eq!(header.size, parsed.size);
+        assert_eq!(parsed.packet_type, PacketType::Display);
+    }
+
+    #[test]
+    fn test_compact_header() {
+        let mut previous = PacketHeader::new(960, 540, FrameFormat::Rgba32, 960 * 540 * 4);
+        previous.timestamp = 5_000_000;
+        assert!(!CompactHeader::is_compact(previous.to_bytes()[0]));
+
+        // Marker with the CRC flag, then a 24-bit step and the size
+        let mut bytes = [0u8; COMPACT_HEADER_SIZE];
+        bytes[..4].copy_from_slice(&(0xc100_0000u32 | 16_667).to_be_bytes());
+        bytes[4..].copy_from_slice(&(960u32 * 540 * 4).to_be_bytes());
+        assert!(CompactHeader::is_compact(bytes[0]));
+        let parsed = CompactHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, CompactHeader { delta_us: 16_667, size: 960 * 540 * 4, crc32: true });
+        assert_eq!(parsed.extension_size(), CRC_SIZE);
+
+        let header = parsed.expand(&previous, Some(7));
+        assert_eq!(header.timestamp, 5_000_000 + 16_667_000);
+        assert_eq!((header.width, header.height), (960, 540));
+        assert_eq!(header.packet_type, PacketType::Display);
+        assert_eq!(header.crc32, Some(7));
+
+        // Parsed whole, as read off the wire
+        let mut wire = bytes.to_vec();
+        wire.extend_from_slice(&7u32.to_be_bytes());
+        let parsed = parse_header(&wire, Some(&previous)).unwrap();
+        assert_eq!((parsed.timestamp, parsed.size, parsed.crc32), (header.timestamp, header.size, Some(7)));
+        assert!(parse_header(&wire, None).is_err());
+        assert!(parse_header(&bytes, Some(&previous)).is_err());
+        assert_eq!(parse_header(&previous.to_bytes(), None).unwrap().timestamp, 5_000_000);
+
+        // And back again, only when nothing but the time and size changed
+        assert_eq!(CompactHeader::between(&previous, &header), Some(CompactHeader::from_bytes(&bytes).unwrap()));
+        assert_eq!(CompactHeader::between(&previous, &header).unwrap().to_bytes(), bytes);
+        let resized = PacketHeader { width: 1920, ..header.clone() };
+        assert_eq!(CompactHeader::between(&previous, &resized), None);
+        let late = PacketHeader { timestamp: previous.timestamp + 20_000_000_000, ..header.clone() };
+        assert_eq!(CompactHeader::between(&previous, &late), None);
+
+        // Only for clients that take them
+        assert_eq!(encode_header(&header, Some(&previous), Capabilities::COMPACT_HEADER), wire);
+        assert_eq!(encode_header(&header, Some(&previous), Capabilities::empty()), header.to_bytes());
+        assert_eq!(encode_header(&header, None, Capabilities::COMPACT_HEADER), header.to_bytes());
+    }
+
+    #[test]
+    fn test_find_header() {
+        let header = PacketHeader::new(64, 48, FrameFormat::Rgba32, 0).to_bytes();
+        let mut little = PacketHeader::control(PacketType::Heartbeat, 0);
+        little.byte_order = ByteOrder::Little;
+        let little = little.to_bytes();
+
+        // Garbage, a magic that doesn't start a header, then a header
+        let mut data = alloc::vec![0xaa, 0x49, 0x50];
+        data.extend_from_slice(&MAGIC.to_be_bytes());
+        data.extend_from_slice(&[0xff; 8]);
+        data.extend_from_slice(&header);
+        assert_eq!(find_header(&data), Resync::Found(15));
+        assert_eq!(find_header(&data[..20]), Resync::NeedMore(15));
+        assert_eq!(find_header(&data[..5]), Resync::NeedMore(2));
+        assert_eq!(find_header(&data[..2]), Resync::NeedMore(0));
+        assert_eq!(find_header(&[0xaa; 10]), Resync::NeedMore(7));
+
+        data.truncate(15);
+        data.extend_from_slice(&little);
+        assert_eq!(find_header(&data), Resync::Found(15));
+    }
+
+    #[test]
+    fn test_little_endian_sender() {
+        // As a native struct written by a little-endian sender
+        let mut header = PacketHeader::new(640, 480, FrameFormat::Rgb565, 640 * 480 * 2);
+        header.byte_order = ByteOrder::Little;
+        header.crc32 = Some(0x1234_5678);
+        let bytes = header.to_bytes();
+        assert_eq!(bytes[..4], *b"SDPI");
+        assert_eq!(bytes[8..12], 640u32.to_le_bytes());
+        assert_eq!(bytes[HEADER_SIZE..], 0x1234_5678u32.to_le_bytes());
+
+        assert_eq!(PacketHeader::fixed_size(bytes[..8].try_into().unwrap()).unwrap(), HEADER_SIZE);
+        assert_eq!(PacketHeader::extension_size(bytes[..HEADER_SIZE].try_into().unwrap()).unwrap(), CRC_SIZE);
+        let parsed = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.byte_order, ByteOrder::Little);
+        assert_eq!(parsed.magic, MAGIC);
+        assert_eq!((parsed.width, parsed.height, parsed.format), (640, 480, FrameFormat::Rgb565));
+        assert_eq!(parsed.timestamp, header.timestamp);
+        assert_eq!(parsed.crc32, header.crc32);
+        assert!(parsed.validate().is_ok());
+
+        let payload = [1u32.to_le_bytes(), 2u32.to_le_bytes(), 3u32.to_le_bytes()].concat();
+        let device = TouchDevice::from_payload(&payload, ByteOrder::Little).unwrap();
+        assert_eq!((device.width, device.height, device.slots), (1, 2, 3));
+
+        let mut bytes = bytes;
+        bytes[0] = b'X';
+        assert!(PacketHeader::from_bytes(&bytes).is_err());
+    }
+
+    /// `ModeRequest` as the kernel spells it, `MODE_REQUEST`
+    fn kernel_name(name: &str) -> String {
+        let mut spelt = String::new();
+        for (i, c) in name.chars().enumerate() {
+            if i > 0 && c.is_ascii_uppercase() {
+                spelt.push('_');
+            }
+            spelt.push(c.to_ascii_uppercase());
+        }
+        spelt
+    }
+
+    /// Names and values of `enum name` in the kernel's header
+    fn kernel_enum(header: &str, name: &str, prefix: &str) -> Vec<(String, u32)> {
+        let body = header.split(&std::format!("enum {} {{", name)).nth(1).unwrap().split("};").next().unwrap();
+        let mut next = 0;
+        let mut entries = Vec::new();
+        for line in body.lines().map(str::trim).filter(|line| line.starts_with(prefix)) {
+            let entry = line.split(',').next().unwrap();
+            let (ident, value) = match entry.split_once('=') {
+                Some((ident, value)) => (ident.trim(), value.trim().parse().unwrap()),
+                None => (entry.trim(), next),
+            };
+            entries.push((ident[prefix.len()..].to_string(), value));
+            next = value + 1;
+        }
+        entries
+    }
+
+    /// Value of a kernel `#define` such as `0xc0`, `36` or `(1u << 5)`
+    fn kernel_define(header: &str, name: &str) -> u32 {
+        let line = header.lines().find(|line| line.starts_with(&std::format!("#define {} ", name))).unwrap();
+        let value = line[name.len() + 9..].split("/*").next().unwrap().trim();
+        if let Some(shift) = value.strip_prefix("(1u << ").and_then(|rest| rest.strip_suffix(')')) {
+            1 << shift.parse::<u32>().unwrap()
+        } else if let Some(hex) = value.strip_prefix("0x") {
+            u32::from_str_radix(hex, 16).unwrap()
+        } else {
+            value.parse().unwrap()
+        }
+    }
+
+    /// Every value `T` can decode from, in order, as the kernel names them
+    fn names<T: TryFrom<u32> + core::fmt::Debug>() -> Vec<(String, u32)> {
+        (0..).map_while(|value| T::try_from(value).ok().map(|decoded| (kernel_name(&std::format!("{:?}", decoded)), value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_kernel_header() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../kernel/ipdisp.h");
+        let header = std::fs::read_to_string(path).unwrap();
+
+        assert_eq!(kernel_enum(&header, "ipdisp_packet_type", "IPDISP_PACKET_"), names::<PacketType>());
+        assert_eq!(kernel_enum(&header, "ipdisp_format", "IPDISP_FORMAT_"), names::<FrameFormat>());
+        assert_eq!(kernel_enum(&header, "ipdisp_supervise_action", "IPDISP_SUPERVISE_"), names::<SuperviseAction>());
+        assert_eq!(kernel_enum(&header, "ipdisp_audio_codec", "IPDISP_AUDIO_"), names::<AudioCodec>());
+        assert_eq!(kernel_enum(&header, "ipdisp_session_mode", "IPDISP_SESSION_"), names::<SessionMode>());
+        assert_eq!(kernel_enum(&header, "ipdisp_control_action", "IPDISP_CONTROL_"), names::<InputControlAction>());
+
+        assert_eq!(kernel_define(&header, "IPDISP_MAGIC"), MAGIC);
+        assert_eq!(kernel_define(&header, "IPDISP_VERSION"), VERSION);
+        assert_eq!(kernel_define(&header, "IPDISP_HEADER_SIZE") as usize, HEADER_SIZE);
+        assert_eq!(kernel_define(&header, "IPDISP_PACKET_TYPE_MASK"), PACKET_TYPE_MASK);
+        assert_eq!(kernel_define(&header, "IPDISP_PACKET_FLAG_CRC32"), FLAG_CRC32);
+        assert_eq!(kernel_define(&header, "IPDISP_COMPACT_HEADER_SIZE") as usize, COMPACT_HEADER_SIZE);
+        assert_eq!(kernel_define(&header, "IPDISP_COMPACT_MARKER"), COMPACT_MARKER as u32);
+        assert_eq!(kernel_define(&header, "IPDISP_COMPACT_MAX_DELTA_US"), COMPACT_MAX_DELTA_US);
+        assert_eq!(kernel_define(&header, "IPDISP_SUPERVISE_MESSAGE_SIZE") as usize, SuperviseResult::SIZE - 8);
+        assert_eq!(kernel_define(&header, "IPDISP_TOUCH_MAX_PRESSURE"), MAX_TOUCH_PRESSURE);
+        assert_eq!(kernel_define(&header, "IPDISP_MAX_REQUEST_SIZE") as usize, MAX_CONTROL_SIZE);
+        assert_eq!(kernel_define(&header, "IPDISP_NOISE_START_SIZE") as usize, NOISE_START_SIZE);
+        assert_eq!(kernel_define(&header, "IPDISP_NOISE_REPLY_SIZE") as usize, NOISE_REPLY_SIZE);
+        assert_eq!(kernel_define(&header, "IPDISP_NOISE_FINISH_SIZE") as usize, NOISE_FINISH_SIZE);
+        assert_eq!(kernel_define(&header, "IPDISP_NOISE_MAX_RECORD") as usize, NOISE_MAX_RECORD);
+        assert_eq!(kernel_define(&header, "IPDISP_RESUME_TOKEN_SIZE") as usize, RESUME_TOKEN_SIZE);
+        assert_eq!(kernel_define(&header, "IPDISP_CONTROL_YOURS"), INPUT_CONTROL_YOURS);
+        assert_eq!(kernel_define(&header, "IPDISP_CONTROL_SHARED"), INPUT_CONTROL_SHARED);
+        assert!(header.contains(&std::format!("\"{}\"", NOISE_PATTERN)));
+
+        // Every capability the kernel knows, and no more
+        for (name, cap) in Capabilities::all().iter_names() {
+            assert_eq!(kernel_define(&header, &std::format!("IPDISP_CAP_{}", name)), cap.bits(), "{}", name);
+        }
+        assert_eq!(header.lines().filter(|line| line.starts_with("#define IPDISP_CAP_")).count(), Capabilities::all().iter().count());
+        assert_eq!(kernel_define(&header, "IPDISP_CAPS_ALL"), Capabilities::all().bits());
+    }
+}
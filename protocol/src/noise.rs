@@ -0,0 +1,28 @@
+// IP Display Protocol - Noise
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Encryption without certificates. A client that offers
+//! `Capabilities::NOISE` to a server that agrees runs a Noise XX handshake
+//! in NOISE packets straight after CAPABILITIES: its first message, the
+//! server's reply with its static key, and its last message with the
+//! client's. Every byte after that, both ways, goes in records: a
+//! big-endian 16-bit length then that much ciphertext, nonces counting from
+//! 0 in each direction. A client's record carries exactly one request.
+
+/// Noise protocol name; 32 bytes, so it is also the initial hash
+pub const NOISE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+
+/// The client's first handshake message: its ephemeral key
+pub const NOISE_START_SIZE: usize = 32;
+/// The server's reply: its ephemeral key, its encrypted static key and an
+/// empty encrypted payload
+pub const NOISE_REPLY_SIZE: usize = 96;
+/// The client's last message: its encrypted static key and an empty
+/// encrypted payload
+pub const NOISE_FINISH_SIZE: usize = 64;
+
+/// Largest record, tag included
+pub const NOISE_MAX_RECORD: usize = 65535;
+/// Bytes of authentication tag ending each record
+pub const NOISE_TAG_SIZE: usize = 16;
@@ -0,0 +1,752 @@
+// IP Display Protocol - Server Messages
+// Copyright (c) 2024
+// Licensed under MIT
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use anyhow::Result;
+use bytes::BufMut;
+use core::time::Duration;
+
+use crate::{control_packet, ByteOrder, Capabilities, FrameFormat, InputControl, PacketHeader, PacketType, SessionMode, NOISE_REPLY_SIZE, RESUME_TOKEN_SIZE};
+
+/// Server reply to `Command::Ping`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pong {
+    /// Echo of the Ping's `client_ns`
+    pub client_ns: u64,
+    /// Server clock when the Ping was read
+    pub server_rx_ns: u64,
+    /// Server clock when the Pong was sent
+    pub server_tx_ns: u64,
+}
+
+impl Pong {
+    pub const SIZE: usize = 24;
+
+    pub fn from_payload(payload: &[u8], order: ByteOrder) -> Result<Self> {
+        if payload.len() < Self::SIZE {
+            return Err(anyhow::anyhow!("Pong payload too short: {} bytes", payload.len()));
+        }
+
+        let mut buf = payload;
+        Ok(Self {
+            client_ns: order.get_u64(&mut buf),
+            server_rx_ns: order.get_u64(&mut buf),
+            server_tx_ns: order.get_u64(&mut buf),
+        })
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.put_u64(self.client_ns);
+        payload.put_u64(self.server_rx_ns);
+        payload.put_u64(self.server_tx_ns);
+        payload
+    }
+}
+
+/// Server reply to `Command::TouchDevice`: the touchscreen it registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TouchDevice {
+    pub width: u32,
+    pub height: u32,
+    /// Contacts the device tracks at once
+    pub slots: u32,
+}
+
+impl TouchDevice {
+    pub const SIZE: usize = 12;
+
+    pub fn from_payload(payload: &[u8], order: ByteOrder) -> Result<Self> {
+        if payload.len() < Self::SIZE {
+            return Err(anyhow::anyhow!("TouchDevice payload too short: {} bytes", payload.len()));
+        }
+
+        let mut buf = payload;
+        Ok(Self {
+            width: order.get_u32(&mut buf),
+            height: order.get_u32(&mut buf),
+            slots: order.get_u32(&mut buf),
+        })
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.put_u32(self.width);
+        payload.put_u32(self.height);
+        payload.put_u32(self.slots);
+        payload
+    }
+}
+
+/// The server vouching for a frame: its timestamp, the SHA-256 of its
+/// payload and the server's Ed25519 signature over both
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentHash {
+    pub timestamp: u64,
+    pub digest: [u8; 32],
+    pub signature: [u8; 64],
+    signed: [u8; 40],
+}
+
+impl ContentHash {
+    pub const SIZE: usize = 104;
+
+    /// As a server sends it, in network order
+    pub fn new(timestamp: u64, digest: [u8; 32], signature: [u8; 64]) -> Self {
+        let mut signed = [0u8; 40];
+        signed[..8].copy_from_slice(&timestamp.to_be_bytes());
+        signed[8..].copy_from_slice(&digest);
+        Self { timestamp, digest, signature, signed }
+    }
+
+    pub fn from_payload(payload: &[u8], order: ByteOrder) -> Result<Self> {
+        if payload.len() < Self::SIZE {
+            return Err(anyhow::anyhow!("ContentHash payload too short: {} bytes", payload.len()));
+        }
+
+        let mut buf = payload;
+        Ok(Self {
+            timestamp: order.get_u64(&mut buf),
+            digest: payload[8..40].try_into()?,
+            signature: payload[40..104].try_into()?,
+            signed: payload[..40].try_into()?,
+        })
+    }
+
+    /// The bytes the signature covers, as the server sent them
+    pub fn signed(&self) -> &[u8] {
+        &self.signed
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.put_slice(&self.signed);
+        payload.put_slice(&self.signature);
+        payload
+    }
+}
+
+/// Server deadline for synchronised playback: every client that asked with
+/// `Capabilities::SYNC` shows a frame this long after its timestamp, on the
+/// server's clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncDelay {
+    pub delay_ns: u64,
+}
+
+impl SyncDelay {
+    pub const SIZE: usize = 8;
+
+    pub fn from_payload(payload: &[u8], order: ByteOrder) -> Result<Self> {
+        if payload.len() < Self::SIZE {
+            return Err(anyhow::anyhow!("Sync payload too short: {} bytes", payload.len()));
+        }
+
+        let mut buf = payload;
+        Ok(Self { delay_ns: order.get_u64(&mut buf) })
+    }
+
+    pub fn delay(self) -> Duration {
+        Duration::from_nanos(self.delay_ns)
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        self.delay_ns.to_be_bytes().to_vec()
+    }
+}
+
+/// Server's notice, sent to clients with `Capabilities::FORMAT_ANNOUNCE`,
+/// that the next frames come in another format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatAnnouncement {
+    pub format: FrameFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FormatAnnouncement {
+    pub const SIZE: usize = 12;
+
+    pub fn from_payload(payload: &[u8], order: ByteOrder) -> Result<Self> {
+        if payload.len() < Self::SIZE {
+            return Err(anyhow::anyhow!("Format payload too short: {} bytes", payload.len()));
+        }
+
+        let mut buf = payload;
+        let format = FrameFormat::try_from(order.get_u32(&mut buf))?;
+        Ok(Self { format, width: order.get_u32(&mut buf), height: order.get_u32(&mut buf) })
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.put_u32(self.format as u32);
+        payload.put_u32(self.width);
+        payload.put_u32(self.height);
+        payload
+    }
+}
+
+/// Server's answer to `Command::Supervise`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuperviseResult {
+    /// `SuperviseAction` as sent, unknown values included
+    pub action: u32,
+    /// 0 on success, otherwise a negative errno
+    pub status: i32,
+    pub message: String,
+}
+
+impl SuperviseResult {
+    /// Action, status and a NUL-padded message
+    pub const SIZE: usize = 72;
+
+    pub fn from_payload(payload: &[u8], order: ByteOrder) -> Result<Self> {
+        if payload.len() < Self::SIZE {
+            return Err(anyhow::anyhow!("Supervise payload too short: {} bytes", payload.len()));
+        }
+
+        let mut buf = payload;
+        let action = order.get_u32(&mut buf);
+        let status = order.get_u32(&mut buf) as i32;
+        let text = &buf[..Self::SIZE - 8];
+        let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+        Ok(Self { action, status, message: String::from_utf8_lossy(&text[..end]).into_owned() })
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.status == 0
+    }
+
+    /// The message is cut to fit
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.put_u32(self.action);
+        payload.put_i32(self.status);
+        let message = self.message.as_bytes();
+        payload.put_slice(&message[..message.len().min(Self::SIZE - 8)]);
+        payload.resize(Self::SIZE, 0);
+        payload
+    }
+}
+
+/// How a client shows an `AuthPrompt`, after PAM's conversation styles
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptStyle {
+    /// A question whose answer can be shown as it is typed, e.g. a user name
+    Visible = 0,
+    /// A question whose answer is hidden, e.g. a password
+    Secret = 1,
+    /// Something to tell the user, e.g. where to sign in
+    Info = 2,
+    /// Why the last answer was turned down
+    Error = 3,
+    /// Signed in; frames follow
+    Accepted = 4,
+}
+
+impl PromptStyle {
+    /// Whether the client must answer with `Command::AuthReply`
+    pub fn expects_reply(self) -> bool {
+        matches!(self, PromptStyle::Visible | PromptStyle::Secret)
+    }
+}
+
+impl TryFrom<u32> for PromptStyle {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(PromptStyle::Visible),
+            1 => Ok(PromptStyle::Secret),
+            2 => Ok(PromptStyle::Info),
+            3 => Ok(PromptStyle::Error),
+            4 => Ok(PromptStyle::Accepted),
+            _ => Err(anyhow::anyhow!("Invalid prompt style: {}", value)),
+        }
+    }
+}
+
+/// A question or notice from a userspace server's auth provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthPrompt {
+    pub style: PromptStyle,
+    pub text: String,
+}
+
+impl AuthPrompt {
+    /// Style and NUL-padded text
+    pub const SIZE: usize = 196;
+
+    pub fn from_payload(payload: &[u8], order: ByteOrder) -> Result<Self> {
+        if payload.len() < Self::SIZE {
+            return Err(anyhow::anyhow!("AuthPrompt payload too short: {} bytes", payload.len()));
+        }
+
+        let mut buf = payload;
+        let style = PromptStyle::try_from(order.get_u32(&mut buf))?;
+        let text = &buf[..Self::SIZE - 4];
+        let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+        Ok(Self { style, text: String::from_utf8_lossy(&text[..end]).into_owned() })
+    }
+
+    /// The text is cut to fit, at a character boundary
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.put_u32(self.style as u32);
+        let mut end = self.text.len().min(Self::SIZE - 4);
+        while !self.text.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.put_slice(&self.text.as_bytes()[..end]);
+        payload.resize(Self::SIZE, 0);
+        payload
+    }
+}
+
+/// Server's progress on an upload, after each request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStatus {
+    /// Bytes written so far
+    pub received: u64,
+    pub id: u32,
+    /// 0 while the upload goes on, 1 once it is complete, otherwise a
+    /// negative errno
+    pub status: i32,
+}
+
+impl FileStatus {
+    pub const SIZE: usize = 16;
+
+    pub fn from_payload(payload: &[u8], order: ByteOrder) -> Result<Self> {
+        if payload.len() < Self::SIZE {
+            return Err(anyhow::anyhow!("FileStatus payload too short: {} bytes", payload.len()));
+        }
+
+        let mut buf = payload;
+        Ok(Self {
+            received: order.get_u64(&mut buf),
+            id: order.get_u32(&mut buf),
+            status: order.get_u32(&mut buf) as i32,
+        })
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.put_u64(self.received);
+        payload.put_u32(self.id);
+        payload.put_i32(self.status);
+        payload
+    }
+}
+
+/// The rectangle a DAMAGE packet replaces, in the frame its header gives
+/// the size of; its RGBA32 pixels follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Damage {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Damage {
+    pub const SIZE: usize = 16;
+
+    /// The rectangle of a DAMAGE packet, checked against its frame and
+    /// its pixels
+    pub fn from_packet(header: &PacketHeader, payload: &[u8]) -> Result<Self> {
+        if payload.len() < Self::SIZE {
+            return Err(anyhow::anyhow!("Damage payload too short: {} bytes", payload.len()));
+        }
+        let order = header.byte_order;
+        let mut buf = payload;
+        let damage = Self {
+            x: order.get_u32(&mut buf),
+            y: order.get_u32(&mut buf),
+            width: order.get_u32(&mut buf),
+            height: order.get_u32(&mut buf),
+        };
+        if damage.x.checked_add(damage.width).is_none_or(|right| right > header.width)
+            || damage.y.checked_add(damage.height).is_none_or(|bottom| bottom > header.height)
+        {
+            return Err(anyhow::anyhow!("Damage {:?} outside the {}x{} frame", damage, header.width, header.height));
+        }
+        let expected = Self::SIZE + damage.width as usize * damage.height as usize * 4;
+        if payload.len() != expected {
+            return Err(anyhow::anyhow!("Damage payload is {} bytes, expected {}", payload.len(), expected));
+        }
+        Ok(damage)
+    }
+
+    /// Largest DAMAGE payload a header can announce: the whole frame
+    pub fn max_size(header: &PacketHeader) -> usize {
+        Self::SIZE + header.width as usize * header.height as usize * 4
+    }
+
+    /// The rectangle followed by its rows of `pixels`
+    pub fn to_payload(&self, pixels: &[u8]) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::SIZE + pixels.len());
+        payload.put_u32(self.x);
+        payload.put_u32(self.y);
+        payload.put_u32(self.width);
+        payload.put_u32(self.height);
+        payload.put_slice(pixels);
+        payload
+    }
+}
+
+/// Control messages sent from the server to the client. Frames, damage and
+/// sound are headers followed by bulk data instead; see `PacketHeader`,
+/// `Damage` and `parse_audio`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerMessage {
+    Pong(Pong),
+    /// Keepalive sent when the server has been otherwise quiet
+    Heartbeat,
+    /// Sent before the server closes, e.g. as its module unloads
+    Goodbye,
+    /// The server's pairing key and nonce
+    PairKey { public: [u8; 32], nonce: [u8; 16] },
+    /// The server's proof of the shared pairing key, and the public key
+    /// its content hashes are signed with
+    PairConfirm { mac: [u8; 32], content_key: [u8; 32] },
+    /// Nonce a paired client answers with `Command::Auth`
+    AuthChallenge { nonce: [u8; 16] },
+    TouchDevice(TouchDevice),
+    ContentHash(ContentHash),
+    Sync(SyncDelay),
+    Supervise(SuperviseResult),
+    Format(FormatAnnouncement),
+    FileStatus(FileStatus),
+    /// The features offered in `Command::Hello` that the server will use
+    Capabilities(Capabilities),
+    /// The server's Noise handshake reply
+    Noise { message: Vec<u8> },
+    /// The session mode in effect, after Hello or `Command::SessionMode`
+    SessionMode(SessionMode),
+    InputControl(InputControl),
+    /// The token to resume this session with, sent once the handshake is
+    /// done and in answer to `Command::Resume`, which it may have picked up
+    Resume { token: [u8; RESUME_TOKEN_SIZE], resumed: bool },
+    /// Answer to `Command::Identify`: the server's DRM card index, which
+    /// its output now flashes
+    Identify { index: u32 },
+    AuthPrompt(AuthPrompt),
+}
+
+impl ServerMessage {
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            ServerMessage::Pong(_) => PacketType::Pong,
+            ServerMessage::Heartbeat => PacketType::Heartbeat,
+            ServerMessage::Goodbye => PacketType::Goodbye,
+            ServerMessage::PairKey { .. } => PacketType::PairKey,
+            ServerMessage::PairConfirm { .. } => PacketType::PairConfirm,
+            ServerMessage::AuthChallenge { .. } => PacketType::AuthChallenge,
+            ServerMessage::TouchDevice(_) => PacketType::TouchDevice,
+            ServerMessage::ContentHash(_) => PacketType::ContentHash,
+            ServerMessage::Sync(_) => PacketType::Sync,
+            ServerMessage::Supervise(_) => PacketType::Supervise,
+            ServerMessage::Format(_) => PacketType::Format,
+            ServerMessage::FileStatus(_) => PacketType::FileStatus,
+            ServerMessage::Capabilities(_) => PacketType::Capabilities,
+            ServerMessage::Noise { .. } => PacketType::Noise,
+            ServerMessage::SessionMode(_) => PacketType::SessionMode,
+            ServerMessage::InputControl(_) => PacketType::InputControl,
+            ServerMessage::Resume { .. } => PacketType::Resume,
+            ServerMessage::Identify { .. } => PacketType::Identify,
+            ServerMessage::AuthPrompt(_) => PacketType::AuthPrompt,
+        }
+    }
+
+    /// Payload this version sends for a control message of `packet_type`,
+    /// or `None` for packets that aren't one. A newer server's may be longer.
+    pub fn payload_size(packet_type: PacketType) -> Option<usize> {
+        Some(match packet_type {
+            PacketType::Pong => Pong::SIZE,
+            PacketType::Heartbeat | PacketType::Goodbye => 0,
+            PacketType::PairKey => 48,
+            PacketType::PairConfirm => 64,
+            PacketType::AuthChallenge => 16,
+            PacketType::TouchDevice => TouchDevice::SIZE,
+            PacketType::ContentHash => ContentHash::SIZE,
+            PacketType::Sync => SyncDelay::SIZE,
+            PacketType::Supervise => SuperviseResult::SIZE,
+            PacketType::Format => FormatAnnouncement::SIZE,
+            PacketType::FileStatus => FileStatus::SIZE,
+            PacketType::Capabilities => 4,
+            PacketType::Noise => NOISE_REPLY_SIZE,
+            PacketType::SessionMode => 4,
+            PacketType::InputControl => InputControl::SIZE,
+            PacketType::Resume => RESUME_TOKEN_SIZE + 4,
+            PacketType::Identify => 4,
+            PacketType::AuthPrompt => AuthPrompt::SIZE,
+            _ => return None,
+        })
+    }
+
+    /// Encode the payload alone, in network order
+    pub fn to_payload(&self) -> Vec<u8> {
+        match self {
+            ServerMessage::Pong(pong) => pong.to_payload(),
+            ServerMessage::Heartbeat | ServerMessage::Goodbye => Vec::new(),
+            ServerMessage::PairKey { public, nonce } => [&public[..], &nonce[..]].concat(),
+            ServerMessage::PairConfirm { mac, content_key } => [&mac[..], &content_key[..]].concat(),
+            ServerMessage::AuthChallenge { nonce } => nonce.to_vec(),
+            ServerMessage::TouchDevice(device) => device.to_payload(),
+            ServerMessage::ContentHash(hash) => hash.to_payload(),
+            ServerMessage::Sync(sync) => sync.to_payload(),
+            ServerMessage::Supervise(result) => result.to_payload(),
+            ServerMessage::Format(announcement) => announcement.to_payload(),
+            ServerMessage::FileStatus(status) => status.to_payload(),
+            ServerMessage::Capabilities(capabilities) => capabilities.bits().to_be_bytes().to_vec(),
+            ServerMessage::Noise { message } => message.clone(),
+            ServerMessage::SessionMode(mode) => (*mode as u32).to_be_bytes().to_vec(),
+            ServerMessage::InputControl(control) => control.to_payload(),
+            ServerMessage::Resume { token, resumed } => [&token[..], &(*resumed as u32).to_be_bytes()].concat(),
+            ServerMessage::Identify { index } => index.to_be_bytes().to_vec(),
+            ServerMessage::AuthPrompt(prompt) => prompt.to_payload(),
+        }
+    }
+
+    /// Encode as a complete packet (header followed by payload)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        control_packet(self.packet_type(), &self.to_payload())
+    }
+
+    /// Decode a control message as the client reads it. Longer payloads
+    /// are taken, the fields this version doesn't know skipped.
+    pub fn from_payload(packet_type: PacketType, payload: &[u8], order: ByteOrder) -> Result<Self> {
+        let bytes = |size: usize| {
+            payload.get(..size)
+                .ok_or_else(|| anyhow::anyhow!("{:?} payload too short: {} bytes", packet_type, payload.len()))
+        };
+        Ok(match packet_type {
+            PacketType::Pong => ServerMessage::Pong(Pong::from_payload(payload, order)?),
+            PacketType::Heartbeat => ServerMessage::Heartbeat,
+            PacketType::Goodbye => ServerMessage::Goodbye,
+            PacketType::PairKey => {
+                let key = bytes(48)?;
+                ServerMessage::PairKey { public: key[..32].try_into()?, nonce: key[32..].try_into()? }
+            }
+            PacketType::PairConfirm => {
+                let confirm = bytes(64)?;
+                ServerMessage::PairConfirm { mac: confirm[..32].try_into()?, content_key: confirm[32..].try_into()? }
+            }
+            PacketType::AuthChallenge => ServerMessage::AuthChallenge { nonce: bytes(16)?.try_into()? },
+            PacketType::TouchDevice => ServerMessage::TouchDevice(TouchDevice::from_payload(payload, order)?),
+            PacketType::ContentHash => ServerMessage::ContentHash(ContentHash::from_payload(payload, order)?),
+            PacketType::Sync => ServerMessage::Sync(SyncDelay::from_payload(payload, order)?),
+            PacketType::Supervise => ServerMessage::Supervise(SuperviseResult::from_payload(payload, order)?),
+            PacketType::Format => ServerMessage::Format(FormatAnnouncement::from_payload(payload, order)?),
+            PacketType::FileStatus => ServerMessage::FileStatus(FileStatus::from_payload(payload, order)?),
+            PacketType::Capabilities => {
+                ServerMessage::Capabilities(Capabilities::from_bits_truncate(order.get_u32(&mut bytes(4)?)))
+            }
+            PacketType::Noise => ServerMessage::Noise { message: bytes(NOISE_REPLY_SIZE)?.to_vec() },
+            PacketType::SessionMode => ServerMessage::SessionMode(SessionMode::try_from(order.get_u32(&mut bytes(4)?))?),
+            PacketType::InputControl => ServerMessage::InputControl(InputControl::from_payload(payload, order)?),
+            PacketType::Resume => {
+                let reply = bytes(RESUME_TOKEN_SIZE + 4)?;
+                ServerMessage::Resume {
+                    token: reply[..RESUME_TOKEN_SIZE].try_into()?,
+                    resumed: order.get_u32(&mut &reply[RESUME_TOKEN_SIZE..]) != 0,
+                }
+            }
+            PacketType::Identify => ServerMessage::Identify { index: order.get_u32(&mut bytes(4)?) },
+            PacketType::AuthPrompt => ServerMessage::AuthPrompt(AuthPrompt::from_payload(payload, order)?),
+            other => return Err(anyhow::anyhow!("{:?} is not a control message", other)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Command, TouchContact, SuperviseAction, HEADER_SIZE, MAX_TOUCH_PRESSURE};
+    use alloc::vec;
+
+    #[test]
+    fn test_pong_decoding() {
+        let mut payload = Vec::new();
+        for value in [1u64, 2, 3] {
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+
+        let pong = Pong::from_payload(&payload, ByteOrder::Big).unwrap();
+        assert_eq!(pong, Pong { client_ns: 1, server_rx_ns: 2, server_tx_ns: 3 });
+        assert!(Pong::from_payload(&payload[..16], ByteOrder::Big).is_err());
+    }
+
+    #[test]
+    fn test_touch_device() {
+        let bytes = Command::TouchDevice { slots: 10 }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.packet_type, PacketType::TouchDevice);
+        assert_eq!(bytes[HEADER_SIZE..], 10u32.to_be_bytes());
+
+        let mut payload = Vec::new();
+        for value in [1920u32, 1080, 10] {
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        let device = TouchDevice::from_payload(&payload, ByteOrder::Big).unwrap();
+        assert_eq!(device, TouchDevice { width: 1920, height: 1080, slots: 10 });
+        assert!(TouchDevice::from_payload(&payload[..8], ByteOrder::Big).is_err());
+
+        let contacts = vec![
+            TouchContact { slot: 0, x: 100, y: 200, pressure: MAX_TOUCH_PRESSURE },
+            TouchContact { slot: 1, x: 300, y: 400, pressure: 0 },
+        ];
+        let bytes = Command::Touch { contacts }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!((header.packet_type, header.size), (PacketType::Touch, 36));
+        assert_eq!(bytes[HEADER_SIZE..HEADER_SIZE + 4], 2u32.to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 20..HEADER_SIZE + 24], 1u32.to_be_bytes());
+        assert_eq!(bytes[HEADER_SIZE + 32..], 0u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_file_status() {
+        let mut payload = 4096u64.to_be_bytes().to_vec();
+        payload.extend_from_slice(&7u32.to_be_bytes());
+        payload.extend_from_slice(&(-28i32).to_be_bytes());
+        let status = FileStatus::from_payload(&payload, ByteOrder::Big).unwrap();
+        assert_eq!(status, FileStatus { received: 4096, id: 7, status: -28 });
+        assert!(FileStatus::from_payload(&payload[..12], ByteOrder::Big).is_err());
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let mut payload = 123_456_789u64.to_be_bytes().to_vec();
+        payload.extend_from_slice(&[0xaa; 32]);
+        payload.extend_from_slice(&[0x55; 64]);
+        let hash = ContentHash::from_payload(&payload, ByteOrder::Big).unwrap();
+        assert_eq!(hash.timestamp, 123_456_789);
+        assert_eq!(hash.digest, [0xaa; 32]);
+        assert_eq!(hash.signature, [0x55; 64]);
+        assert_eq!(hash.signed(), &payload[..40]);
+        assert!(ContentHash::from_payload(&payload[..103], ByteOrder::Big).is_err());
+    }
+
+    #[test]
+    fn test_sync_delay() {
+        let payload = 40_000_000u64.to_be_bytes();
+        let sync = SyncDelay::from_payload(&payload, ByteOrder::Big).unwrap();
+        assert_eq!(sync.delay(), Duration::from_millis(40));
+        assert_eq!(PacketType::try_from(18).unwrap(), PacketType::Sync);
+        assert!(SyncDelay::from_payload(&payload[..7], ByteOrder::Big).is_err());
+    }
+
+    #[test]
+    fn test_supervise() {
+        let bytes = Command::Supervise { action: SuperviseAction::SetSource, arg: 2 }.to_bytes();
+        let header = PacketHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(header.packet_type, PacketType::Supervise);
+        assert_eq!(&bytes[HEADER_SIZE..], &[0, 0, 0, 1, 0, 0, 0, 2]);
+        assert!(SuperviseAction::try_from(4).is_err());
+
+        let mut payload = vec![0u8; SuperviseResult::SIZE];
+        payload[..4].copy_from_slice(&1u32.to_be_bytes());
+        payload[4..8].copy_from_slice(&(-22i32).to_be_bytes());
+        payload[8..22].copy_from_slice(b"No such source");
+        let result = SuperviseResult::from_payload(&payload, ByteOrder::Big).unwrap();
+        assert_eq!((result.action, result.status), (1, -22));
+        assert_eq!(result.message, "No such source");
+        assert!(!result.succeeded());
+        assert!(SuperviseResult::from_payload(&payload[..71], ByteOrder::Big).is_err());
+    }
+
+    #[test]
+    fn test_format_announcement() {
+        let payload = [0, 0, 0, 5, 0, 0, 3, 0xc0, 0, 0, 2, 0x1c];
+        let announcement = FormatAnnouncement::from_payload(&payload, ByteOrder::Big).unwrap();
+        assert_eq!(announcement, FormatAnnouncement { format: FrameFormat::Nv12, width: 960, height: 540 });
+        assert_eq!(PacketType::try_from(20).unwrap(), PacketType::Format);
+        assert!(FormatAnnouncement::from_payload(&payload[..11], ByteOrder::Big).is_err());
+        assert!(FormatAnnouncement::from_payload(&[0, 0, 0, 99, 0, 0, 0, 1, 0, 0, 0, 1], ByteOrder::Big).is_err());
+    }
+
+    #[test]
+    fn test_damage() {
+        let mut header = PacketHeader::new(4, 3, FrameFormat::Rgba32, 0);
+        header.packet_type = PacketType::Damage;
+        let damage = Damage { x: 1, y: 1, width: 2, height: 1 };
+        let payload = damage.to_payload(&[9; 8]);
+        assert_eq!(Damage::from_packet(&header, &payload).unwrap(), damage);
+        assert_eq!(Damage::max_size(&header), 16 + 48);
+
+        // Outside the frame, or with the wrong number of pixels
+        let outside = Damage { x: 3, ..damage }.to_payload(&[9; 8]);
+        assert!(Damage::from_packet(&header, &outside).is_err());
+        assert!(Damage::from_packet(&header, &payload[..15]).is_err());
+        assert!(Damage::from_packet(&header, &payload[..20]).is_err());
+        let wrapping = Damage { x: u32::MAX, ..damage }.to_payload(&[9; 8]);
+        assert!(Damage::from_packet(&header, &wrapping).is_err());
+    }
+
+    #[test]
+    fn test_server_round_trip() {
+        let messages = [
+            ServerMessage::Pong(Pong { client_ns: 1, server_rx_ns: 2, server_tx_ns: 3 }),
+            ServerMessage::Heartbeat,
+            ServerMessage::Goodbye,
+            ServerMessage::PairKey { public: [1; 32], nonce: [2; 16] },
+            ServerMessage::PairConfirm { mac: [3; 32], content_key: [6; 32] },
+            ServerMessage::AuthChallenge { nonce: [4; 16] },
+            ServerMessage::TouchDevice(TouchDevice { width: 1920, height: 1080, slots: 10 }),
+            ServerMessage::ContentHash(ContentHash::new(123_456_789, [0xaa; 32], [0x55; 64])),
+            ServerMessage::Sync(SyncDelay { delay_ns: 40_000_000 }),
+            ServerMessage::Supervise(SuperviseResult { action: 1, status: -22, message: "No such source".into() }),
+            ServerMessage::Format(FormatAnnouncement { format: FrameFormat::P010, width: 960, height: 540 }),
+            ServerMessage::FileStatus(FileStatus { received: 4096, id: 7, status: 1 }),
+            ServerMessage::Capabilities(Capabilities::CRC32 | Capabilities::ACK),
+            ServerMessage::Noise { message: vec![5; NOISE_REPLY_SIZE] },
+            ServerMessage::SessionMode(SessionMode::Input),
+            ServerMessage::InputControl(InputControl {
+                owner: 3,
+                address: [192, 168, 1, 20],
+                yours: false,
+                shared: false,
+                status: -16,
+            }),
+            ServerMessage::Resume { token: [7; RESUME_TOKEN_SIZE], resumed: true },
+            ServerMessage::Identify { index: 1 },
+            ServerMessage::AuthPrompt(AuthPrompt { style: PromptStyle::Secret, text: "Password:".into() }),
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            let header = PacketHeader::from_bytes(&bytes).unwrap();
+            assert_eq!(header.size as usize, bytes.len() - HEADER_SIZE);
+            assert_eq!(ServerMessage::payload_size(header.packet_type), Some(header.size as usize));
+            let decoded = ServerMessage::from_payload(header.packet_type, &bytes[HEADER_SIZE..], header.byte_order);
+            assert_eq!(decoded.unwrap(), message);
+
+            // As from a newer server with more to say
+            let mut payload = message.to_payload();
+            payload.extend_from_slice(&[0xee; 8]);
+            let decoded = ServerMessage::from_payload(message.packet_type(), &payload, ByteOrder::Big);
+            assert_eq!(decoded.unwrap(), message);
+
+            let payload = message.to_payload();
+            if !payload.is_empty() {
+                assert!(ServerMessage::from_payload(message.packet_type(), &payload[..payload.len() - 1], ByteOrder::Big).is_err());
+            }
+        }
+
+        // Messages cut to fit, and things that aren't control messages
+        let long = SuperviseResult { action: 0, status: 0, message: "x".repeat(100) };
+        assert_eq!(long.to_payload().len(), SuperviseResult::SIZE);
+        assert_eq!(SuperviseResult::from_payload(&long.to_payload(), ByteOrder::Big).unwrap().message.len(), 64);
+        let long = AuthPrompt { style: PromptStyle::Info, text: "é".repeat(100) };
+        assert_eq!(long.to_payload().len(), AuthPrompt::SIZE);
+        assert_eq!(AuthPrompt::from_payload(&long.to_payload(), ByteOrder::Big).unwrap().text, "é".repeat(96));
+        let mut unknown = long.to_payload();
+        unknown[3] = 9;
+        assert!(AuthPrompt::from_payload(&unknown, ByteOrder::Big).is_err());
+        assert!(ServerMessage::from_payload(PacketType::Hello, &[0; 16], ByteOrder::Big).is_err());
+        assert_eq!(ServerMessage::payload_size(PacketType::Hello), None);
+    }
+}
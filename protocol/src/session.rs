@@ -0,0 +1,215 @@
+// IP Display Protocol - Session Modes and Input Control
+// Copyright (c) 2024
+// Licensed under MIT
+
+use alloc::vec::Vec;
+use anyhow::Result;
+use bytes::BufMut;
+use core::fmt;
+use core::str::FromStr;
+
+use crate::{ByteOrder, Command};
+
+/// How much a client may do besides watching. The client asks for a mode
+/// in `Command::Hello` and may switch with `Command::SessionMode`; the
+/// server answers with the mode in effect, which is never more than its
+/// own limit. Both sides refuse requests the mode doesn't allow.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum SessionMode {
+    /// Frames and sound only
+    View = 0,
+    /// Also the server's touchscreen, mouse and keyboard, and mode changes
+    Input = 1,
+    /// Also uploads, supervision and, in time, the clipboard
+    #[default]
+    Full = 2,
+}
+
+impl SessionMode {
+    pub const ALL: [SessionMode; 3] = [SessionMode::View, SessionMode::Input, SessionMode::Full];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SessionMode::View => "view",
+            SessionMode::Input => "input",
+            SessionMode::Full => "full",
+        }
+    }
+
+    /// Whether a client in this mode may send `command`
+    pub fn allows(self, command: &Command) -> bool {
+        let needed = match command {
+            Command::RequestMode { .. }
+            | Command::TouchDevice { .. }
+            | Command::Touch { .. }
+            | Command::Pointer { .. }
+            | Command::Key { .. } => SessionMode::Input,
+            Command::FileBegin { .. } | Command::FileData { .. } | Command::Supervise { .. } => SessionMode::Full,
+            _ => SessionMode::View,
+        };
+        self >= needed
+    }
+}
+
+impl TryFrom<u32> for SessionMode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(SessionMode::View),
+            1 => Ok(SessionMode::Input),
+            2 => Ok(SessionMode::Full),
+            _ => Err(anyhow::anyhow!("Invalid session mode: {}", value)),
+        }
+    }
+}
+
+impl FromStr for SessionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        SessionMode::ALL
+            .into_iter()
+            .find(|mode| mode.name() == text)
+            .ok_or_else(|| anyhow::anyhow!("Expected view, input or full, got {}", text))
+    }
+}
+
+impl fmt::Display for SessionMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What a client does about input control with `Command::InputControl`.
+/// Unless the server shares its input devices, one client at a time
+/// drives them; the server's policy decides whether control may be taken
+/// from a client that holds it or only passes on once released.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputControlAction {
+    /// Take control if nobody holds it
+    Request = 0,
+    /// Give control up
+    Release = 1,
+    /// Take control even from another client, where the server allows it
+    Steal = 2,
+}
+
+impl TryFrom<u32> for InputControlAction {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(InputControlAction::Request),
+            1 => Ok(InputControlAction::Release),
+            2 => Ok(InputControlAction::Steal),
+            _ => Err(anyhow::anyhow!("Invalid input control action: {}", value)),
+        }
+    }
+}
+
+/// Bits of `InputControl` flags on the wire
+pub const INPUT_CONTROL_YOURS: u32 = 1 << 0;
+pub const INPUT_CONTROL_SHARED: u32 = 1 << 1;
+
+/// Who drives the server's input devices, sent to every client when that
+/// changes and in answer to `Command::InputControl`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InputControl {
+    /// Id the server gave the client in control, 0 for nobody
+    pub owner: u32,
+    /// That client's IPv4 address
+    pub address: [u8; 4],
+    /// The client reading this is in control
+    pub yours: bool,
+    /// Every client may drive at once, so nobody needs control
+    pub shared: bool,
+    /// 0, or a negative errno when this client's last request was refused
+    pub status: i32,
+}
+
+impl InputControl {
+    pub const SIZE: usize = 16;
+
+    /// Whether this client may send input now
+    pub fn can_drive(&self) -> bool {
+        self.shared || self.yours || self.owner == 0
+    }
+
+    pub fn from_payload(payload: &[u8], order: ByteOrder) -> Result<Self> {
+        if payload.len() < Self::SIZE {
+            return Err(anyhow::anyhow!("InputControl payload too short: {} bytes", payload.len()));
+        }
+
+        let mut buf = payload;
+        let owner = order.get_u32(&mut buf);
+        let address = buf[..4].try_into()?;
+        buf = &buf[4..];
+        let flags = order.get_u32(&mut buf);
+        Ok(Self {
+            owner,
+            address,
+            yours: flags & INPUT_CONTROL_YOURS != 0,
+            shared: flags & INPUT_CONTROL_SHARED != 0,
+            status: order.get_u32(&mut buf) as i32,
+        })
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.yours {
+            flags |= INPUT_CONTROL_YOURS;
+        }
+        if self.shared {
+            flags |= INPUT_CONTROL_SHARED;
+        }
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.put_u32(self.owner);
+        payload.put_slice(&self.address);
+        payload.put_u32(flags);
+        payload.put_i32(self.status);
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SuperviseAction;
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_session_mode() {
+        let pointer = Command::Pointer { dx: 1, dy: 0, wheel: 0, buttons: 0 };
+        let upload = Command::FileBegin { id: 1, size: 3, name: "a".into() };
+        let supervise = Command::Supervise { action: SuperviseAction::RotateLogs, arg: 0 };
+        assert!(SessionMode::View.allows(&Command::Heartbeat));
+        assert!(!SessionMode::View.allows(&pointer));
+        assert!(SessionMode::Input.allows(&pointer));
+        assert!(!SessionMode::Input.allows(&upload));
+        assert!(!SessionMode::Input.allows(&supervise));
+        assert!(SessionMode::Full.allows(&upload));
+
+        for mode in SessionMode::ALL {
+            assert_eq!(mode.to_string().parse::<SessionMode>().unwrap(), mode);
+        }
+        assert!("everything".parse::<SessionMode>().is_err());
+        assert_eq!(SessionMode::default(), SessionMode::Full);
+    }
+
+    #[test]
+    fn test_input_control() {
+        let held = InputControl { owner: 2, address: [10, 0, 0, 5], ..Default::default() };
+        assert!(!held.can_drive());
+        assert!(InputControl { yours: true, ..held }.can_drive());
+        assert!(InputControl { shared: true, ..held }.can_drive());
+        assert!(InputControl::default().can_drive());
+
+        let payload = InputControl { yours: true, ..held }.to_payload();
+        assert_eq!(&payload[4..12], &[10, 0, 0, 5, 0, 0, 0, 1]);
+        assert!(InputControl::from_payload(&payload[..15], ByteOrder::Big).is_err());
+        assert!(InputControlAction::try_from(3).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+// IP Display Server - Authentication
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Who may watch. With `--auth`, a client gets no frames until one of the
+//! configured providers has accepted it, each tried in the order given:
+//!
+//! - `token`: the secret in `--auth-token-file`, proved the way the kernel
+//!   module's token provider has it proved. The client answers an
+//!   AUTH_CHALLENGE with an HMAC keyed by the secret's SHA-256, so the
+//!   secret itself never crosses the network.
+//! - `pam`: a user name and password, or whatever else the system's PAM
+//!   stack asks for, checked by PAM (the `pam` feature)
+//! - `oidc`: the OAuth device flow with an OpenID Connect issuer; the
+//!   client is shown where to sign in and with what code (the `oidc`
+//!   feature)
+//!
+//! The last two ask the user through AUTH_PROMPT, which is only sent to
+//! clients that agreed to `Capabilities::AUTH_PROMPT`. Other clients can
+//! only get in with a token. Passwords are only asked for over an
+//! encrypted link, i.e. QUIC, as plain TCP would show them to anyone on
+//! the way.
+//!
+//! PAM and the HTTP requests block, so the providers run on a blocking
+//! thread and reach the client through a `Conversation`, whose other end
+//! is the session.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use ipds_protocol::{AuthPrompt, Capabilities, Command, PromptStyle, ServerMessage};
+
+#[cfg(feature = "oidc")]
+pub mod oidc;
+#[cfg(feature = "pam")]
+pub mod pam;
+pub mod token;
+
+/// How long a client has to answer an AUTH_CHALLENGE, which it does
+/// without asking anyone
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the user has to answer a prompt, as long as the kernel
+/// module's pairing code lasts
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Which provider `--auth` lists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AuthMethod {
+    /// The shared secret in `--auth-token-file`
+    Token,
+    /// A password checked by PAM
+    Pam,
+    /// Signing in with an OpenID Connect issuer
+    Oidc,
+}
+
+/// What the providers `--auth` lists are configured with
+#[derive(Debug, Clone, Default)]
+pub struct AuthOptions {
+    pub token_file: Option<PathBuf>,
+    /// The PAM service, i.e. the file in /etc/pam.d, checking passwords
+    pub pam_service: String,
+    pub oidc_issuer: Option<String>,
+    pub oidc_client_id: Option<String>,
+    /// Users the issuer signs in who may watch, by name, e-mail address or
+    /// subject; everyone when empty
+    pub oidc_users: Vec<String>,
+}
+
+/// A way of telling who a client is
+pub trait AuthProvider: Send + Sync {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+
+    /// Find out who is at the other end of `conversation`: their name once
+    /// they have proved it, `None` if this provider can't tell and the
+    /// next should try, or an error once they have failed its check
+    fn authenticate(&self, conversation: &mut dyn Conversation) -> Result<Option<String>>;
+}
+
+/// The client an `AuthProvider` is checking, as seen from its thread
+pub trait Conversation {
+    /// Where the client connected from
+    fn peer(&self) -> SocketAddr;
+
+    /// Whether the user can be asked questions, and asked for a secret
+    /// such as a password if `secret`; told things, if not
+    fn prompts(&self, secret: bool) -> bool;
+
+    /// Send AUTH_CHALLENGE with `nonce` and wait for the token id and
+    /// HMAC the client answers with, or `None` if it has no credential
+    fn challenge(&mut self, nonce: [u8; 16]) -> Result<Option<([u8; 8], [u8; 32])>>;
+
+    /// Tell the user `text`, and wait for their answer to a question, or
+    /// `None` if they declined to answer
+    fn prompt(&mut self, style: PromptStyle, text: &str) -> Result<Option<String>>;
+
+    /// Whether the client is still connected, for providers that wait on
+    /// something else
+    fn is_open(&self) -> bool;
+}
+
+/// Open the provider for `method`
+pub fn open(method: AuthMethod, options: &AuthOptions) -> Result<Box<dyn AuthProvider>> {
+    match method {
+        AuthMethod::Token => {
+            let path = options.token_file.as_ref().context("--auth token needs --auth-token-file")?;
+            Ok(Box::new(token::TokenProvider::load(path)?))
+        }
+        AuthMethod::Pam => pam(options),
+        AuthMethod::Oidc => oidc(options),
+    }
+}
+
+#[cfg(feature = "pam")]
+fn pam(options: &AuthOptions) -> Result<Box<dyn AuthProvider>> {
+    Ok(Box::new(pam::PamProvider::load(&options.pam_service)?))
+}
+
+#[cfg(not(feature = "pam"))]
+fn pam(_options: &AuthOptions) -> Result<Box<dyn AuthProvider>> {
+    Err(anyhow::anyhow!("Built without PAM (the pam feature)"))
+}
+
+#[cfg(feature = "oidc")]
+fn oidc(options: &AuthOptions) -> Result<Box<dyn AuthProvider>> {
+    let issuer = options.oidc_issuer.as_deref().context("--auth oidc needs --oidc-issuer")?;
+    let client_id = options.oidc_client_id.as_deref().context("--auth oidc needs --oidc-client-id")?;
+    Ok(Box::new(oidc::OidcProvider::discover(issuer, client_id, options.oidc_users.clone())?))
+}
+
+#[cfg(not(feature = "oidc"))]
+fn oidc(_options: &AuthOptions) -> Result<Box<dyn AuthProvider>> {
+    Err(anyhow::anyhow!("Built without OpenID Connect (the oidc feature)"))
+}
+
+/// The providers a client must get past, tried in order
+pub struct Auth {
+    providers: Vec<Box<dyn AuthProvider>>,
+}
+
+/// Something a provider wants said to the client, with where its answer
+/// goes
+enum Exchange {
+    Challenge([u8; 16], oneshot::Sender<Option<([u8; 8], [u8; 32])>>),
+    Prompt(AuthPrompt, Option<oneshot::Sender<Option<String>>>),
+}
+
+/// The answer the session is waiting on, until `deadline`
+enum Awaiting {
+    Challenge(oneshot::Sender<Option<([u8; 8], [u8; 32])>>),
+    Prompt(oneshot::Sender<Option<String>>),
+}
+
+impl Auth {
+    pub fn new(providers: Vec<Box<dyn AuthProvider>>) -> Self {
+        Self { providers }
+    }
+
+    /// The providers' names, for logs
+    pub fn names(&self) -> Vec<&'static str> {
+        self.providers.iter().map(|provider| provider.name()).collect()
+    }
+
+    /// The first provider to accept the client, and who it said they are
+    fn check(&self, conversation: &mut dyn Conversation) -> Result<Option<(&'static str, String)>> {
+        for provider in &self.providers {
+            if let Some(user) = provider.authenticate(conversation)? {
+                return Ok(Some((provider.name(), user)));
+            }
+            debug!("{} can't tell who {} is", provider.name(), conversation.peer());
+        }
+        Ok(None)
+    }
+
+    /// Run the providers against the client at `peer`, whose requests
+    /// arrive on `requests`, until one accepts it. Returns who the client
+    /// is, or `None` if none did or it left; the session should then end.
+    /// Requests that aren't for the providers are kept in `deferred` for
+    /// after.
+    pub(crate) async fn authenticate<W: AsyncWrite + Unpin>(
+        self: &Arc<Self>,
+        writer: &mut W,
+        requests: &mut mpsc::UnboundedReceiver<(Command, u64)>,
+        deferred: &mut VecDeque<(Command, u64)>,
+        (peer, encrypted): (SocketAddr, bool),
+        capabilities: Capabilities,
+    ) -> Result<Option<String>> {
+        let prompts = capabilities.supports(Capabilities::AUTH_PROMPT);
+        let (exchanges_tx, mut exchanges) = mpsc::unbounded_channel();
+        let mut conversation = Session { peer, prompts, encrypted, exchanges: exchanges_tx };
+        let auth = Arc::clone(self);
+        let mut checking = tokio::task::spawn_blocking(move || auth.check(&mut conversation));
+
+        let mut awaiting: Option<(Awaiting, Instant)> = None;
+        let outcome = loop {
+            let deadline = awaiting.as_ref().map(|(_, deadline)| *deadline);
+            tokio::select! {
+                checked = &mut checking => break checked?,
+                Some(exchange) = exchanges.recv() => match exchange {
+                    Exchange::Challenge(nonce, reply) => {
+                        writer.write_all(&ServerMessage::AuthChallenge { nonce }.to_bytes()).await?;
+                        awaiting = Some((Awaiting::Challenge(reply), Instant::now() + CHALLENGE_TIMEOUT));
+                    }
+                    Exchange::Prompt(prompt, reply) => {
+                        writer.write_all(&ServerMessage::AuthPrompt(prompt).to_bytes()).await?;
+                        if let Some(reply) = reply {
+                            awaiting = Some((Awaiting::Prompt(reply), Instant::now() + PROMPT_TIMEOUT));
+                        }
+                    }
+                },
+                request = requests.recv() => match request {
+                    // Dropping what the thread waits on ends its conversation
+                    None | Some((Command::Goodbye, _)) => return Ok(None),
+                    Some((Command::Auth { id, mac }, _)) => match awaiting.take() {
+                        Some((Awaiting::Challenge(reply), _)) => {
+                            let _ = reply.send(Some((id, mac)));
+                        }
+                        other => {
+                            debug!("Client {} answered a challenge it wasn't sent", peer);
+                            awaiting = other;
+                        }
+                    },
+                    Some((Command::AuthReply { text }, _)) => match awaiting.take() {
+                        Some((Awaiting::Challenge(reply), _)) => {
+                            let _ = reply.send(None);
+                        }
+                        Some((Awaiting::Prompt(reply), _)) => {
+                            let _ = reply.send(Some(text).filter(|text| !text.is_empty()));
+                        }
+                        None => debug!("Client {} answered a prompt it wasn't sent", peer),
+                    },
+                    Some(request) => deferred.push_back(request),
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    debug!("Client {} didn't answer in time", peer);
+                    awaiting = None;
+                }
+            }
+        };
+
+        let (style, text, user) = match outcome {
+            Ok(Some((provider, user))) => {
+                info!("Client {} signed in as {} with {}", peer, user, provider);
+                (PromptStyle::Accepted, format!("Signed in as {}", user), Some(user))
+            }
+            Ok(None) => {
+                warn!("Client {} had no credential any of {} accepts", peer, self.names().join(", "));
+                (PromptStyle::Error, "Not signed in; this display needs a credential".to_string(), None)
+            }
+            Err(e) => {
+                warn!("Client {} failed to sign in: {:#}", peer, e);
+                (PromptStyle::Error, e.to_string(), None)
+            }
+        };
+        if prompts {
+            writer.write_all(&ServerMessage::AuthPrompt(AuthPrompt { style, text }).to_bytes()).await?;
+        }
+        Ok(user)
+    }
+}
+
+/// A session's end of a `Conversation`
+struct Session {
+    peer: SocketAddr,
+    prompts: bool,
+    encrypted: bool,
+    exchanges: mpsc::UnboundedSender<Exchange>,
+}
+
+impl Conversation for Session {
+    fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn prompts(&self, secret: bool) -> bool {
+        self.prompts && (self.encrypted || !secret)
+    }
+
+    fn challenge(&mut self, nonce: [u8; 16]) -> Result<Option<([u8; 8], [u8; 32])>> {
+        let (reply, answer) = oneshot::channel();
+        self.exchanges.send(Exchange::Challenge(nonce, reply)).map_err(|_| anyhow::anyhow!("Client left"))?;
+        // Older clients say nothing when they have no credential
+        Ok(answer.blocking_recv().unwrap_or(None))
+    }
+
+    fn prompt(&mut self, style: PromptStyle, text: &str) -> Result<Option<String>> {
+        if !self.prompts(style == PromptStyle::Secret) {
+            return Err(anyhow::anyhow!("Can't ask {} that", self.peer));
+        }
+        let prompt = AuthPrompt { style, text: text.to_string() };
+        if !style.expects_reply() {
+            self.exchanges.send(Exchange::Prompt(prompt, None)).map_err(|_| anyhow::anyhow!("Client left"))?;
+            return Ok(None);
+        }
+        let (reply, answer) = oneshot::channel();
+        self.exchanges.send(Exchange::Prompt(prompt, Some(reply))).map_err(|_| anyhow::anyhow!("Client left"))?;
+        answer.blocking_recv().map_err(|_| anyhow::anyhow!("No answer in time"))
+    }
+
+    fn is_open(&self) -> bool {
+        !self.exchanges.is_closed()
+    }
+}
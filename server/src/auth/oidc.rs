@@ -0,0 +1,204 @@
+// IP Display Server - OpenID Connect Authentication
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Signing in with an OpenID Connect issuer through the OAuth device
+//! authorization grant (RFC 8628), made for devices like this one that
+//! can't show the issuer's login page themselves. The server asks the
+//! issuer for a user code and shows the client where to enter it. It then
+//! polls the issuer until the user has signed in there from any browser,
+//! and asks the issuer's userinfo endpoint who they are.
+//!
+//! The issuer's endpoints come from its discovery document, over HTTPS
+//! only. The client must be registered with the issuer as a public
+//! client allowed the device flow.
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use ipds_protocol::PromptStyle;
+
+use super::{AuthProvider, Conversation};
+
+/// What's asked of the issuer: enough to say who the user is
+const SCOPE: &str = "openid profile email";
+
+/// How long to wait for the issuer to answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a client that left is noticed while waiting to poll
+const POLL_STEP: Duration = Duration::from_millis(500);
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// The parts of the issuer's `.well-known/openid-configuration` used
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    device_authorization_endpoint: Option<String>,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    // Google still calls it verification_url
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+/// RFC 8628's polling interval when the issuer doesn't give one
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenReply {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+    sub: String,
+    preferred_username: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+impl UserInfo {
+    /// An address the issuer vouches for, or doesn't say it doesn't
+    fn email(&self) -> Option<&str> {
+        self.email.as_deref().filter(|_| self.email_verified != Some(false))
+    }
+
+    /// The name to greet and log them by
+    fn name(&self) -> &str {
+        self.preferred_username.as_deref().or(self.email()).unwrap_or(&self.sub)
+    }
+
+    /// Whether they are one of `users`, by any of their names
+    fn is_one_of(&self, users: &[String]) -> bool {
+        let names = [Some(self.sub.as_str()), self.preferred_username.as_deref(), self.email()];
+        users.iter().any(|user| names.contains(&Some(user.as_str())))
+    }
+}
+
+/// The body of a successful request
+fn json<T: DeserializeOwned>(response: Result<ureq::Response, ureq::Error>) -> Result<T> {
+    Ok(response?.into_json()?)
+}
+
+pub struct OidcProvider {
+    agent: ureq::Agent,
+    client_id: String,
+    device_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    /// Who may watch; anyone the issuer signs in when empty
+    users: Vec<String>,
+}
+
+impl OidcProvider {
+    /// Look up `issuer`'s endpoints, to sign users in as `client_id`
+    pub fn discover(issuer: &str, client_id: &str, users: Vec<String>) -> Result<Self> {
+        let issuer = issuer.trim_end_matches('/');
+        if !issuer.starts_with("https://") {
+            return Err(anyhow::anyhow!("The OpenID Connect issuer must be an https:// URL"));
+        }
+        let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).https_only(true).build();
+        let url = format!("{}/.well-known/openid-configuration", issuer);
+        let discovery: Discovery = json(agent.get(&url).call())
+            .with_context(|| format!("Failed to discover {}", issuer))?;
+        let device_endpoint = discovery.device_authorization_endpoint
+            .with_context(|| format!("{} doesn't offer the device flow", issuer))?;
+        let userinfo_endpoint = discovery.userinfo_endpoint
+            .with_context(|| format!("{} has no userinfo endpoint", issuer))?;
+        Ok(Self {
+            agent,
+            client_id: client_id.to_string(),
+            device_endpoint,
+            token_endpoint: discovery.token_endpoint,
+            userinfo_endpoint,
+            users,
+        })
+    }
+
+    /// Ask the token endpoint whether the user has signed in yet
+    fn poll(&self, device_code: &str) -> Result<Result<String, TokenError>> {
+        let form = [("grant_type", DEVICE_CODE_GRANT), ("device_code", device_code), ("client_id", &self.client_id)];
+        match self.agent.post(&self.token_endpoint).send_form(&form) {
+            Ok(response) => Ok(Ok(response.into_json::<TokenReply>()?.access_token)),
+            // Not yet, or not at all, comes back as a 400
+            Err(ureq::Error::Status(_, response)) => Ok(Err(response.into_json()?)),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl AuthProvider for OidcProvider {
+    fn name(&self) -> &'static str {
+        "oidc"
+    }
+
+    fn authenticate(&self, conversation: &mut dyn Conversation) -> Result<Option<String>> {
+        if !conversation.prompts(false) {
+            return Ok(None);
+        }
+
+        let form = [("client_id", self.client_id.as_str()), ("scope", SCOPE)];
+        let device: DeviceAuthorization = json(self.agent.post(&self.device_endpoint).send_form(&form))
+            .context("The OpenID Connect issuer didn't start a sign-in")?;
+        let text = match &device.verification_uri_complete {
+            Some(uri) => format!("Sign in at {} and check the code is {}", uri, device.user_code),
+            None => format!("Sign in at {} with the code {}", device.verification_uri, device.user_code),
+        };
+        conversation.prompt(PromptStyle::Info, &text)?;
+
+        let expires = Instant::now() + Duration::from_secs(device.expires_in);
+        let mut interval = Duration::from_secs(device.interval.max(1));
+        let token = loop {
+            let next = Instant::now() + interval;
+            while Instant::now() < next {
+                if !conversation.is_open() {
+                    return Err(anyhow::anyhow!("Client left before signing in"));
+                }
+                thread::sleep(POLL_STEP.min(next.saturating_duration_since(Instant::now())));
+            }
+            if Instant::now() >= expires {
+                return Err(anyhow::anyhow!("The sign-in code expired"));
+            }
+            match self.poll(&device.device_code)? {
+                Ok(token) => break token,
+                Err(e) if e.error == "authorization_pending" => {}
+                Err(e) if e.error == "slow_down" => interval += Duration::from_secs(5),
+                Err(e) => {
+                    debug!("Issuer refused {}: {} {:?}", conversation.peer(), e.error, e.error_description);
+                    return Err(anyhow::anyhow!("Sign-in refused: {}", e.error_description.unwrap_or(e.error)));
+                }
+            }
+        };
+
+        let request = self.agent.get(&self.userinfo_endpoint).set("Authorization", &format!("Bearer {}", token));
+        let user: UserInfo = json(request.call())
+            .context("The OpenID Connect issuer didn't say who signed in")?;
+        if !self.users.is_empty() && !user.is_one_of(&self.users) {
+            return Err(anyhow::anyhow!("{} may not watch this display", user.name()));
+        }
+        Ok(Some(user.name().to_string()))
+    }
+}
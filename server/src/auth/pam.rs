@@ -0,0 +1,262 @@
+// IP Display Server - PAM Authentication
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Passwords, or whatever else the stack in `/etc/pam.d/<service>` asks
+//! for, checked by PAM. libpam is loaded when the server starts rather
+//! than linked, so builds don't need its headers and the binary still
+//! runs where it isn't installed. Each question PAM's modules ask goes to
+//! the user as an AUTH_PROMPT, starting with their user name, and the
+//! account must then pass the stack's account checks too.
+//!
+//! pam_unix can only check another user's password as root, so the
+//! server must run as root, or the stack must use modules that don't read
+//! /etc/shadow.
+
+use anyhow::{Context, Result};
+use libloading::Library;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr;
+use tracing::{debug, warn};
+
+use ipds_protocol::PromptStyle;
+
+use super::{AuthProvider, Conversation};
+
+/// Tries at a password before the client is turned away
+const MAX_ATTEMPTS: usize = 3;
+
+// From <security/_pam_types.h>
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_USER_UNKNOWN: c_int = 10;
+const PAM_CONV_ERR: c_int = 19;
+const PAM_USER: c_int = 2;
+const PAM_RHOST: c_int = 4;
+const PAM_DISALLOW_NULL_AUTHTOK: c_int = 0x1;
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+const PAM_ERROR_MSG: c_int = 3;
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    _retcode: c_int,
+}
+
+type ConvFn = unsafe extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: ConvFn,
+    appdata_ptr: *mut c_void,
+}
+
+type StartFn = unsafe extern "C" fn(*const c_char, *const c_char, *const PamConv, *mut *mut c_void) -> c_int;
+type SetItemFn = unsafe extern "C" fn(*mut c_void, c_int, *const c_void) -> c_int;
+type GetItemFn = unsafe extern "C" fn(*const c_void, c_int, *mut *const c_void) -> c_int;
+type FlagsFn = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+type StrerrorFn = unsafe extern "C" fn(*mut c_void, c_int) -> *const c_char;
+
+/// The functions used from libpam, valid while `_library` is loaded
+struct Pam {
+    start: StartFn,
+    set_item: SetItemFn,
+    get_item: GetItemFn,
+    authenticate: FlagsFn,
+    acct_mgmt: FlagsFn,
+    end: FlagsFn,
+    strerror: StrerrorFn,
+    _library: Library,
+}
+
+pub struct PamProvider {
+    pam: Pam,
+    service: CString,
+}
+
+/// Where PAM's questions go during one attempt, and why it stopped
+/// asking
+struct Talk<'a> {
+    conversation: &'a mut dyn Conversation,
+    error: Option<anyhow::Error>,
+    declined: bool,
+}
+
+/// How one attempt ended
+enum Attempt {
+    Accepted(String),
+    Refused(c_int, String),
+}
+
+impl PamProvider {
+    /// Load libpam to check passwords with the stack for `service`
+    pub fn load(service: &str) -> Result<Self> {
+        // SAFETY: libpam runs no code of its own when loaded, and each
+        // symbol is given the type <security/pam_appl.h> declares for it
+        let pam = unsafe {
+            let library = Library::new("libpam.so.0").context("Failed to load libpam.so.0; is PAM installed?")?;
+            Pam {
+                start: *library.get::<StartFn>(b"pam_start\0")?,
+                set_item: *library.get::<SetItemFn>(b"pam_set_item\0")?,
+                get_item: *library.get::<GetItemFn>(b"pam_get_item\0")?,
+                authenticate: *library.get::<FlagsFn>(b"pam_authenticate\0")?,
+                acct_mgmt: *library.get::<FlagsFn>(b"pam_acct_mgmt\0")?,
+                end: *library.get::<FlagsFn>(b"pam_end\0")?,
+                strerror: *library.get::<StrerrorFn>(b"pam_strerror\0")?,
+                _library: library,
+            }
+        };
+        let service = CString::new(service).context("Bad PAM service name")?;
+        Ok(Self { pam, service })
+    }
+
+    /// Run the stack once, asking the user through `talk`
+    fn attempt(&self, talk: &mut Talk) -> Result<Attempt> {
+        let conv = PamConv { conv: converse, appdata_ptr: talk as *mut Talk as *mut c_void };
+        let rhost = CString::new(talk.conversation.peer().ip().to_string())?;
+        let mut handle = ptr::null_mut();
+        // SAFETY: the service name, conversation and host outlive the
+        // handle, which is ended before returning; PAM copies the items
+        // it is given
+        unsafe {
+            let status = (self.pam.start)(self.service.as_ptr(), ptr::null(), &conv, &mut handle);
+            if status != PAM_SUCCESS {
+                return Err(anyhow::anyhow!("PAM failed to start service {:?}: error {}", self.service, status));
+            }
+            let mut status = (self.pam.set_item)(handle, PAM_RHOST, rhost.as_ptr() as *const c_void);
+            if status == PAM_SUCCESS {
+                status = (self.pam.authenticate)(handle, PAM_DISALLOW_NULL_AUTHTOK);
+            }
+            if status == PAM_SUCCESS {
+                status = (self.pam.acct_mgmt)(handle, PAM_DISALLOW_NULL_AUTHTOK);
+            }
+            let attempt = if status == PAM_SUCCESS {
+                let mut user = ptr::null();
+                if (self.pam.get_item)(handle, PAM_USER, &mut user) == PAM_SUCCESS && !user.is_null() {
+                    Attempt::Accepted(CStr::from_ptr(user as *const c_char).to_string_lossy().into_owned())
+                } else {
+                    Attempt::Refused(status, "PAM accepted nobody".to_string())
+                }
+            } else {
+                let message = (self.pam.strerror)(handle, status);
+                let message = if message.is_null() {
+                    format!("PAM error {}", status)
+                } else {
+                    CStr::from_ptr(message).to_string_lossy().into_owned()
+                };
+                Attempt::Refused(status, message)
+            };
+            (self.pam.end)(handle, status);
+            Ok(attempt)
+        }
+    }
+}
+
+impl AuthProvider for PamProvider {
+    fn name(&self) -> &'static str {
+        "pam"
+    }
+
+    fn authenticate(&self, conversation: &mut dyn Conversation) -> Result<Option<String>> {
+        if !conversation.prompts(true) {
+            // Tell the user why they weren't asked, if they can be told
+            // anything at all
+            if conversation.prompts(false) {
+                warn!("Not asking {} for a password over plain TCP", conversation.peer());
+                conversation.prompt(PromptStyle::Info, "Passwords are only asked for over QUIC")?;
+            }
+            return Ok(None);
+        }
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let mut talk = Talk { conversation: &mut *conversation, error: None, declined: false };
+            let outcome = self.attempt(&mut talk)?;
+            let (error, declined) = (talk.error.take(), talk.declined);
+            if let Some(e) = error {
+                return Err(e);
+            }
+            match outcome {
+                Attempt::Accepted(user) => return Ok(Some(user)),
+                // Maybe a later provider suits them better
+                Attempt::Refused(..) if declined => return Ok(None),
+                Attempt::Refused(PAM_AUTH_ERR | PAM_USER_UNKNOWN, message) if attempt < MAX_ATTEMPTS => {
+                    debug!("PAM turned {} down: {}", conversation.peer(), message);
+                    conversation.prompt(PromptStyle::Error, "Wrong user name or password; try again")?;
+                }
+                Attempt::Refused(_, message) => return Err(anyhow::anyhow!("Sign-in refused: {}", message)),
+            }
+        }
+        unreachable!("the last attempt returns")
+    }
+}
+
+/// PAM's conversation function: put each of its messages to the user and
+/// hand back their answers, which PAM frees
+unsafe extern "C" fn converse(
+    count: c_int,
+    messages: *mut *const PamMessage,
+    responses: *mut *mut PamResponse,
+    data: *mut c_void,
+) -> c_int {
+    if count <= 0 || messages.is_null() || responses.is_null() || data.is_null() {
+        return PAM_CONV_ERR;
+    }
+    let talk = &mut *(data as *mut Talk);
+    let count = count as usize;
+    let replies = libc::calloc(count, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+    if replies.is_null() {
+        return PAM_BUF_ERR;
+    }
+
+    for i in 0..count {
+        let message = &**messages.add(i);
+        let text = if message.msg.is_null() { String::new() } else { CStr::from_ptr(message.msg).to_string_lossy().into_owned() };
+        let style = match message.msg_style {
+            PAM_PROMPT_ECHO_ON => PromptStyle::Visible,
+            PAM_PROMPT_ECHO_OFF => PromptStyle::Secret,
+            PAM_ERROR_MSG => PromptStyle::Error,
+            _ => PromptStyle::Info,
+        };
+        let answer = match talk.conversation.prompt(style, text.trim_end()) {
+            Ok(answer) => answer,
+            Err(e) => {
+                talk.error = Some(e);
+                free_replies(replies, count);
+                return PAM_CONV_ERR;
+            }
+        };
+        if !style.expects_reply() {
+            continue;
+        }
+        match answer.and_then(|answer| CString::new(answer).ok()) {
+            Some(answer) => (*replies.add(i)).resp = libc::strdup(answer.as_ptr()),
+            None => {
+                talk.declined = true;
+                free_replies(replies, count);
+                return PAM_CONV_ERR;
+            }
+        }
+    }
+    *responses = replies;
+    PAM_SUCCESS
+}
+
+/// Free answers PAM won't be given, wiping them first
+unsafe fn free_replies(replies: *mut PamResponse, count: usize) {
+    for i in 0..count {
+        let answer = (*replies.add(i)).resp;
+        if !answer.is_null() {
+            ptr::write_bytes(answer, 0, libc::strlen(answer));
+            libc::free(answer as *mut c_void);
+        }
+    }
+    libc::free(replies as *mut c_void);
+}
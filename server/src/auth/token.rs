@@ -0,0 +1,80 @@
+// IP Display Server - Token Authentication
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! A secret shared with the clients, as the client's `--auth-token-file`
+//! and the kernel module's `auth_token` have it: the key is the secret's
+//! SHA-256, the token id the first bytes of an HMAC of "ipdisp token id"
+//! with it, and a client proves it holds the key with an HMAC of the
+//! challenge.
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use super::{AuthProvider, Conversation};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub struct TokenProvider {
+    key: [u8; 32],
+    id: [u8; 8],
+}
+
+impl TokenProvider {
+    pub fn new(secret: &str) -> Result<Self> {
+        let secret = secret.trim();
+        if secret.is_empty() {
+            return Err(anyhow::anyhow!("The auth token is empty"));
+        }
+        let key: [u8; 32] = Sha256::digest(secret).into();
+        let id = mac(&key, b"ipdisp token id")[..8].try_into()?;
+        Ok(Self { key, id })
+    }
+
+    /// Read the secret from its own file, so it stays out of `ps`
+    pub fn load(path: &Path) -> Result<Self> {
+        let secret = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::new(&secret).with_context(|| format!("No token in {}", path.display()))
+    }
+}
+
+fn mac(key: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes any key");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+impl fmt::Debug for TokenProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never log the key
+        f.debug_struct("TokenProvider").finish_non_exhaustive()
+    }
+}
+
+impl AuthProvider for TokenProvider {
+    fn name(&self) -> &'static str {
+        "token"
+    }
+
+    fn authenticate(&self, conversation: &mut dyn Conversation) -> Result<Option<String>> {
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        // Another id is a pairing with some other server, or another token
+        let Some((id, answer)) = conversation.challenge(nonce)? else {
+            return Ok(None);
+        };
+        if id != self.id {
+            return Ok(None);
+        }
+        let mut check = HmacSha256::new_from_slice(&self.key).expect("HMAC takes any key");
+        check.update(&nonce);
+        check.verify_slice(&answer).map_err(|_| anyhow::anyhow!("Wrong answer for the auth token"))?;
+        let id: String = self.id.iter().map(|byte| format!("{:02x}", byte)).collect();
+        Ok(Some(format!("token {}", id)))
+    }
+}
@@ -0,0 +1,242 @@
+// IP Display Server - Capture
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Where the served picture comes from. A `CaptureSource` is asked for
+//! the screen at the capture rate on a thread of its own, and a `Screen`
+//! shares whatever it returns with every connected client. The demo
+//! patterns are one source; the others mirror a real desktop, each behind
+//! its own feature since each needs a display server to talk to:
+//!
+//! - `x11`: the X server's root window through XShm, copying only when
+//!   XDamage reports a change
+//! - `pipewire`: a Wayland desktop through the xdg-desktop-portal
+//!   screencast interface, which asks the user which monitor to share, and
+//!   the PipeWire stream it hands back
+//!
+//! A `Screen` can be handed another source while it runs, or the same one
+//! opened again, and carries on capturing from that. A source that fails
+//! is dropped, and the screen stays still until it gets a new one.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::demo::{DemoPattern, DemoSource};
+use crate::encoder::Encoding;
+
+#[cfg(feature = "pipewire")]
+pub mod pipewire;
+#[cfg(feature = "x11")]
+pub mod x11;
+
+/// One picture of the screen as RGBA32, rows packed without padding
+#[derive(Debug, Clone, Default)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// A screen, real or made up, that the server can serve
+pub trait CaptureSource: Send {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+
+    /// Fill `frame` with the screen as it is now, unless nothing changed
+    /// since the last call; returns whether it did. `frame` may hold an
+    /// older picture or none, so a capture always fills all of it.
+    fn capture(&mut self, frame: &mut Frame) -> Result<bool>;
+}
+
+/// Which source to capture from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Capture {
+    /// The demo patterns
+    #[default]
+    Demo,
+    /// The X server in `DISPLAY`
+    X11,
+    /// A Wayland desktop, through the screencast portal
+    Pipewire,
+}
+
+/// Open the source for `capture`; `pattern` is what the demo shows
+pub async fn open(capture: Capture, pattern: DemoPattern) -> Result<Box<dyn CaptureSource>> {
+    match capture {
+        Capture::Demo => Ok(Box::new(DemoSource::new(pattern))),
+        Capture::X11 => x11(),
+        Capture::Pipewire => pipewire().await,
+    }
+}
+
+#[cfg(feature = "x11")]
+fn x11() -> Result<Box<dyn CaptureSource>> {
+    Ok(Box::new(x11::X11Source::connect()?))
+}
+
+#[cfg(not(feature = "x11"))]
+fn x11() -> Result<Box<dyn CaptureSource>> {
+    Err(anyhow::anyhow!("Built without X11 capture (the x11 feature)"))
+}
+
+#[cfg(feature = "pipewire")]
+async fn pipewire() -> Result<Box<dyn CaptureSource>> {
+    Ok(Box::new(pipewire::PipewireSource::start().await?))
+}
+
+#[cfg(not(feature = "pipewire"))]
+async fn pipewire() -> Result<Box<dyn CaptureSource>> {
+    Err(anyhow::anyhow!("Built without PipeWire capture (the pipewire feature)"))
+}
+
+/// What the capture thread is asked to do between two captures
+enum Request {
+    /// Capture from this source from now on
+    Switch(Box<dyn CaptureSource>),
+    /// Hand every client the current picture again
+    Resend,
+}
+
+/// The latest picture from a source, shared by every client
+#[derive(Debug, Clone)]
+pub struct Screen {
+    frames: watch::Receiver<Option<Arc<Frame>>>,
+    requests: Sender<Request>,
+    fps: u32,
+    /// How frames are compressed; raw without one
+    encoding: Option<Encoding>,
+}
+
+impl Screen {
+    /// Capture from `source` `fps` times a second on a thread of its own
+    /// until `shutdown`. The source is left alone while no client is
+    /// connected.
+    pub fn capture(source: Box<dyn CaptureSource>, fps: u32, shutdown: &CancellationToken) -> Result<Self> {
+        let fps = fps.max(1);
+        let (sender, frames) = watch::channel(None);
+        let (requests, received) = mpsc::channel();
+        let shutdown = shutdown.clone();
+        std::thread::Builder::new().name("capture".to_string()).spawn(move || {
+            let interval = Duration::from_secs(1) / fps;
+            let mut next = Instant::now();
+            let mut spare: Option<Frame> = None;
+            let mut source = Some(source);
+            while !shutdown.is_cancelled() {
+                for request in received.try_iter() {
+                    match request {
+                        Request::Switch(new) => {
+                            info!("Capturing from {}", new.name());
+                            source = Some(new);
+                        }
+                        Request::Resend => sender.send_modify(|_| {}),
+                    }
+                }
+
+                // The screen's own receiver is always there
+                if let Some(capturing) = source.as_mut().filter(|_| sender.receiver_count() > 1) {
+                    let mut frame = spare.take().unwrap_or_default();
+                    match capturing.capture(&mut frame) {
+                        // Reuse the previous picture's buffer once every
+                        // client is done with it
+                        Ok(true) => {
+                            let previous = sender.send_replace(Some(Arc::new(frame)));
+                            spare = previous.and_then(|previous| Arc::try_unwrap(previous).ok());
+                        }
+                        Ok(false) => spare = Some(frame),
+                        Err(e) => {
+                            warn!("{} capture failed: {:#}", capturing.name(), e);
+                            source = None;
+                        }
+                    }
+                }
+
+                next += interval;
+                match next.checked_duration_since(Instant::now()) {
+                    Some(wait) => std::thread::sleep(wait),
+                    None => next = Instant::now(),
+                }
+            }
+        })?;
+        Ok(Self { frames, requests, fps, encoding: None })
+    }
+
+    /// Capture from `source` in place of the current source, from the
+    /// next capture on
+    pub fn switch(&self, source: Box<dyn CaptureSource>) -> Result<()> {
+        self.requests.send(Request::Switch(source)).map_err(|_| anyhow::anyhow!("Capture stopped"))
+    }
+
+    /// Send every client the current picture again, as if it had changed
+    pub fn resend(&self) -> Result<()> {
+        self.requests.send(Request::Resend).map_err(|_| anyhow::anyhow!("Capture stopped"))
+    }
+
+    /// Send the picture compressed with `encoding`
+    pub fn encoded(self, encoding: Encoding) -> Self {
+        Self { encoding: Some(encoding), ..self }
+    }
+
+    /// The rate the source is captured at
+    pub fn fps(&self) -> u32 {
+        self.fps
+    }
+
+    /// How frames are compressed, if they are
+    pub fn encoding(&self) -> Option<&Encoding> {
+        self.encoding.as_ref()
+    }
+
+    /// A receiver that sees the current picture as new
+    pub(crate) fn subscribe(&self) -> watch::Receiver<Option<Arc<Frame>>> {
+        let mut frames = self.frames.clone();
+        frames.mark_changed();
+        frames
+    }
+}
+
+/// Shrink `frame` by `scale` in each direction into `pixels`, averaging
+/// each block of pixels; returns the size it came out at
+pub fn downscale(frame: &Frame, scale: u32, pixels: &mut Vec<u8>) -> (u32, u32) {
+    let (width, height) = (frame.width / scale, frame.height / scale);
+    let (scale, stride) = (scale as usize, frame.width as usize * 4);
+    pixels.clear();
+    pixels.reserve(width as usize * height as usize * 4);
+    let area = (scale * scale) as u32;
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            let mut sum = [0u32; 4];
+            for row in frame.pixels[y * scale * stride..].chunks(stride).take(scale) {
+                for pixel in row[x * scale * 4..][..scale * 4].chunks_exact(4) {
+                    for (total, &channel) in sum.iter_mut().zip(pixel) {
+                        *total += channel as u32;
+                    }
+                }
+            }
+            pixels.extend(sum.map(|total| (total / area) as u8));
+        }
+    }
+    (width, height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downscale() {
+        // Two by two blocks, the right-hand column dropped
+        let mut frame = Frame { width: 5, height: 2, pixels: vec![0; 5 * 2 * 4] };
+        frame.pixels[..4].copy_from_slice(&[200, 0, 40, 255]);
+        frame.pixels[20..24].copy_from_slice(&[100, 0, 40, 255]);
+        frame.pixels[16..20].copy_from_slice(&[255; 4]);
+        let mut pixels = Vec::new();
+        assert_eq!(downscale(&frame, 2, &mut pixels), (2, 1));
+        assert_eq!(pixels, [75, 0, 20, 127, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,125 @@
+// IP Display Server - PipeWire Capture
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Mirroring a Wayland desktop, which won't let clients read the screen
+//! directly. The xdg-desktop-portal screencast interface asks the user
+//! which monitor to share and hands back a PipeWire remote with the
+//! monitor's stream on it; the compositor only sends a buffer when the
+//! monitor changes. The stream is read with GStreamer's PipeWire plugin,
+//! `pipewiresrc ! videoconvert ! appsink`, which turns whatever format the
+//! compositor offers into RGBA. The pointer is drawn in where the portal
+//! can, and left out otherwise.
+
+use anyhow::{Context, Result};
+use ashpd::desktop::screencast::{CursorMode, Screencast, SourceType};
+use ashpd::desktop::{PersistMode, Session};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
+use std::os::fd::{AsRawFd, OwnedFd};
+use tracing::info;
+
+use super::{CaptureSource, Frame};
+
+pub struct PipewireSource {
+    pipeline: gst::Pipeline,
+    sink: AppSink,
+    /// The portal ends the screencast when these go
+    _session: Session<'static, Screencast<'static>>,
+    _portal: Screencast<'static>,
+    _remote: OwnedFd,
+}
+
+impl PipewireSource {
+    /// Ask the portal for a monitor and start reading its stream
+    pub async fn start() -> Result<Self> {
+        let portal = Screencast::new().await.context("No screencast portal; is xdg-desktop-portal running?")?;
+        let cursor = if portal.available_cursor_modes().await?.contains(CursorMode::Embedded) {
+            CursorMode::Embedded
+        } else {
+            CursorMode::Hidden
+        };
+        let session = portal.create_session().await?;
+        portal
+            .select_sources(&session, cursor, SourceType::Monitor.into(), false, None, PersistMode::DoNot)
+            .await?;
+        let streams = portal
+            .start(&session, None)
+            .await?
+            .response()
+            .context("The screencast was turned down")?;
+        let stream = streams.streams().first().context("The portal shared no monitor")?;
+        let node = stream.pipe_wire_node_id();
+        let remote = portal.open_pipe_wire_remote(&session).await?;
+
+        gst::init().context("Failed to initialise GStreamer")?;
+        let source = gst::ElementFactory::make("pipewiresrc")
+            .property("fd", remote.as_raw_fd())
+            .property("path", node.to_string())
+            .build()
+            .context("GStreamer has no pipewiresrc element; install its PipeWire plugin")?;
+        let convert = gst::ElementFactory::make("videoconvert").build()?;
+        let sink = AppSink::builder()
+            .caps(&gst::Caps::builder("video/x-raw").field("format", "RGBA").build())
+            .sync(false)
+            .max_buffers(1)
+            .drop(true)
+            .build();
+        let pipeline = gst::Pipeline::new();
+        pipeline.add_many([&source, &convert, sink.upcast_ref()])?;
+        gst::Element::link_many([&source, &convert, sink.upcast_ref()])?;
+        pipeline.set_state(gst::State::Playing)?;
+
+        match stream.size() {
+            Some((width, height)) => info!("Capturing PipeWire node {} at {}x{}", node, width, height),
+            None => info!("Capturing PipeWire node {}", node),
+        }
+        Ok(Self { pipeline, sink, _session: session, _portal: portal, _remote: remote })
+    }
+
+    fn check_bus(&self) -> Result<()> {
+        let bus = self.pipeline.bus().context("Pipeline has no bus")?;
+        match bus.pop_filtered(&[gst::MessageType::Error, gst::MessageType::Eos]) {
+            Some(message) => match message.view() {
+                gst::MessageView::Error(error) => Err(anyhow::anyhow!("GStreamer: {}", error.error())),
+                _ => Err(anyhow::anyhow!("The screencast ended")),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl CaptureSource for PipewireSource {
+    fn name(&self) -> &'static str {
+        "pipewire"
+    }
+
+    fn capture(&mut self, frame: &mut Frame) -> Result<bool> {
+        let Some(sample) = self.sink.try_pull_sample(gst::ClockTime::ZERO) else {
+            self.check_bus()?;
+            return Ok(false);
+        };
+        let structure = sample.caps().and_then(|caps| caps.structure(0)).context("Captured sample has no caps")?;
+        let width = structure.get::<i32>("width")? as u32;
+        let height = structure.get::<i32>("height")? as u32;
+        let buffer = sample.buffer().context("Captured sample has no buffer")?;
+        let map = buffer.map_readable()?;
+
+        // RGBA rows are already 4-byte aligned, so videoconvert packs them
+        let size = width as usize * height as usize * 4;
+        let pixels = map.get(..size).ok_or_else(|| {
+            anyhow::anyhow!("Captured {}x{} picture has {} bytes, expected {}", width, height, map.len(), size)
+        })?;
+        (frame.width, frame.height) = (width, height);
+        frame.pixels.clear();
+        frame.pixels.extend_from_slice(pixels);
+        Ok(true)
+    }
+}
+
+impl Drop for PipewireSource {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
@@ -0,0 +1,154 @@
+// IP Display Server - X11 Capture
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Mirroring the X server in `DISPLAY`. XDamage says when anything on the
+//! root window has changed, so a still desktop costs one poll a tick, and
+//! changes are copied out whole with XShm: the X server writes the root
+//! window into a tmpfs file both sides have open, and we read it back
+//! without it going through the socket. Servers without MIT-SHM 1.2 (fd
+//! passing), e.g. over the network, fall back to plain GetImage. The
+//! pointer isn't drawn into the picture.
+
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::FileExt;
+use tracing::{debug, info};
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::damage::{self, ConnectionExt as _};
+use x11rb::protocol::shm::{self, ConnectionExt as _};
+use x11rb::protocol::xfixes::ConnectionExt as _;
+use x11rb::protocol::xproto::{ConnectionExt as _, ImageFormat, ImageOrder, Window};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+use super::{CaptureSource, Frame};
+
+/// A shared memory segment the X server has attached
+struct Segment {
+    id: shm::Seg,
+    file: File,
+    size: usize,
+}
+
+pub struct X11Source {
+    conn: RustConnection,
+    root: Window,
+    /// Byte offsets of red, green and blue within a pixel
+    channels: [usize; 3],
+    damage: damage::Damage,
+    /// Anything changed since the last capture; true for the first
+    damaged: bool,
+    /// The X server takes segments as file descriptors
+    shm: bool,
+    segment: Option<Segment>,
+}
+
+impl X11Source {
+    /// Connect to the X server and start tracking damage to its first
+    /// screen's root window
+    pub fn connect() -> Result<Self> {
+        let (conn, screen_number) = x11rb::connect(None).context("Failed to connect to the X server")?;
+        let setup = conn.setup();
+        let screen = &setup.roots[screen_number];
+        let root = screen.root;
+
+        let bits = setup.pixmap_formats.iter().find(|format| format.depth == screen.root_depth).map(|format| format.bits_per_pixel);
+        if bits != Some(32) {
+            return Err(anyhow::anyhow!("X11 capture needs 32 bits a pixel, the screen has depth {}", screen.root_depth));
+        }
+        let visual = screen
+            .allowed_depths
+            .iter()
+            .flat_map(|depth| &depth.visuals)
+            .find(|visual| visual.visual_id == screen.root_visual)
+            .ok_or_else(|| anyhow::anyhow!("The X server doesn't describe its root visual"))?;
+        let lsb_first = setup.image_byte_order == ImageOrder::LSB_FIRST;
+        let channels = [visual.red_mask, visual.green_mask, visual.blue_mask].map(|mask| {
+            let byte = mask.trailing_zeros() as usize / 8;
+            if lsb_first { byte } else { 3 - byte }
+        });
+        let (width, height) = (screen.width_in_pixels, screen.height_in_pixels);
+
+        // Damage regions are XFixes regions, so XFixes has to be set up
+        // first even though we don't ask for them
+        conn.xfixes_query_version(5, 0)?.reply().context("The X server has no XFixes extension")?;
+        conn.damage_query_version(1, 1)?.reply().context("The X server has no DAMAGE extension")?;
+        let damage = conn.generate_id()?;
+        conn.damage_create(damage, root, damage::ReportLevel::NON_EMPTY)?.check()?;
+
+        let shm = conn.extension_information(shm::X11_EXTENSION_NAME)?.is_some()
+            && conn.shm_query_version()?.reply().is_ok_and(|version| (version.major_version, version.minor_version) >= (1, 2));
+        if !shm {
+            debug!("The X server can't take shared memory file descriptors; capturing with GetImage");
+        }
+        info!("Capturing the X11 screen at {}x{}", width, height);
+        Ok(Self { conn, root, channels, damage, damaged: true, shm, segment: None })
+    }
+
+    /// A segment of at least `size` bytes, replacing a smaller one
+    fn segment(&mut self, size: usize) -> Result<&Segment> {
+        if self.segment.as_ref().is_none_or(|segment| segment.size < size) {
+            if let Some(old) = self.segment.take() {
+                self.conn.shm_detach(old.id)?;
+            }
+            let path = format!("/dev/shm/ipdisp-server-{}", std::process::id());
+            let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)
+                .with_context(|| format!("Failed to create {}", path))?;
+            std::fs::remove_file(&path)?;
+            file.set_len(size as u64)?;
+
+            let id = self.conn.generate_id()?;
+            self.conn.shm_attach_fd(id, OwnedFd::from(file.try_clone()?), false)?.check()?;
+            self.segment = Some(Segment { id, file, size });
+        }
+        Ok(self.segment.as_ref().unwrap())
+    }
+}
+
+impl CaptureSource for X11Source {
+    fn name(&self) -> &'static str {
+        "x11"
+    }
+
+    fn capture(&mut self, frame: &mut Frame) -> Result<bool> {
+        while let Some(event) = self.conn.poll_for_event()? {
+            if let Event::DamageNotify(_) = event {
+                self.damaged = true;
+            }
+        }
+        if !self.damaged {
+            return Ok(false);
+        }
+        // Before copying, so changes while we copy count for next time
+        self.conn.damage_subtract(self.damage, x11rb::NONE, x11rb::NONE)?;
+        self.damaged = false;
+
+        // The size changes with the screen's mode
+        let geometry = self.conn.get_geometry(self.root)?.reply()?;
+        let (width, height) = (geometry.width, geometry.height);
+        let size = width as usize * height as usize * 4;
+        (frame.width, frame.height) = (width as u32, height as u32);
+        frame.pixels.resize(size, 0);
+
+        if self.shm {
+            let (root, id) = (self.root, self.segment(size)?.id);
+            self.conn
+                .shm_get_image(root, 0, 0, width, height, !0, ImageFormat::Z_PIXMAP.into(), id, 0)?
+                .reply()?;
+            self.segment(size)?.file.read_exact_at(&mut frame.pixels, 0)?;
+        } else {
+            let image = self.conn.get_image(ImageFormat::Z_PIXMAP, self.root, 0, 0, width, height, !0)?.reply()?;
+            let data = image.data.get(..size).ok_or_else(|| anyhow::anyhow!("GetImage came back short"))?;
+            frame.pixels.copy_from_slice(data);
+        }
+
+        let [red, green, blue] = self.channels;
+        for pixel in frame.pixels.chunks_exact_mut(4) {
+            let rgba = [pixel[red], pixel[green], pixel[blue], 255];
+            pixel.copy_from_slice(&rgba);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,183 @@
+// IP Display Server - Demo Patterns
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Moving test patterns, for trying clients and new protocol features
+//! without the kernel module or a desktop to capture. Each frame has its
+//! number and the time since the stream started burnt into its top-left
+//! corner.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use std::time::{Duration, Instant};
+
+use crate::capture::{CaptureSource, Frame};
+
+/// Size of the unscaled stream
+pub const DEMO_WIDTH: u32 = 1280;
+pub const DEMO_HEIGHT: u32 = 720;
+pub const DEMO_FPS: u32 = 30;
+
+/// What the demo server shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum DemoPattern {
+    /// A colour gradient scrolling sideways
+    Gradient,
+    /// 75% colour bars with a line sweeping across them
+    Bars,
+    /// A box bouncing off the edges
+    #[default]
+    Bounce,
+}
+
+/// Draw frame `number` of `pattern`, `elapsed` into the stream, into
+/// `pixels` as RGBA32
+pub fn draw(pattern: DemoPattern, number: u64, elapsed: Duration, width: u32, height: u32, pixels: &mut [u8]) {
+    let (w, h) = (width as usize, height as usize);
+    match pattern {
+        DemoPattern::Gradient => {
+            let shift = (number * 4) as usize;
+            for (y, row) in pixels.chunks_exact_mut(w * 4).enumerate() {
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let r = ((x + shift) % w * 255 / w) as u8;
+                    let g = (y * 255 / h) as u8;
+                    pixel.copy_from_slice(&[r, g, 255 - r, 255]);
+                }
+            }
+        }
+        DemoPattern::Bars => {
+            const BARS: [[u8; 3]; 7] = [
+                [191, 191, 191], [191, 191, 0], [0, 191, 191], [0, 191, 0], [191, 0, 191], [191, 0, 0], [0, 0, 191],
+            ];
+            let sweep = (number * 8) as usize % w;
+            for row in pixels.chunks_exact_mut(w * 4) {
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let [r, g, b] = if x.abs_diff(sweep) < 2 { [255; 3] } else { BARS[x * BARS.len() / w] };
+                    pixel.copy_from_slice(&[r, g, b, 255]);
+                }
+            }
+        }
+        DemoPattern::Bounce => {
+            let size = h / 6;
+            let left = bounce(number * 7, w - size);
+            let top = bounce(number * 5, h - size);
+            let colour = [(number * 3 % 256) as u8, 160, 255 - (number * 3 % 256) as u8, 255];
+            for (y, row) in pixels.chunks_exact_mut(w * 4).enumerate() {
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    let inside = (left..left + size).contains(&x) && (top..top + size).contains(&y);
+                    pixel.copy_from_slice(if inside { &colour } else { &[32, 32, 32, 255] });
+                }
+            }
+        }
+    }
+
+    let secs = elapsed.as_secs();
+    let text = format!("{:06} {:02}:{:02}.{:03}", number, secs / 60, secs % 60, elapsed.subsec_millis());
+    burn_in(&text, width, height, pixels);
+}
+
+/// The demo patterns as a capture source, a new frame every time
+#[derive(Debug)]
+pub struct DemoSource {
+    pattern: DemoPattern,
+    number: u64,
+    started: Instant,
+}
+
+impl DemoSource {
+    pub fn new(pattern: DemoPattern) -> Self {
+        Self { pattern, number: 0, started: Instant::now() }
+    }
+}
+
+impl CaptureSource for DemoSource {
+    fn name(&self) -> &'static str {
+        "demo"
+    }
+
+    fn capture(&mut self, frame: &mut Frame) -> Result<bool> {
+        (frame.width, frame.height) = (DEMO_WIDTH, DEMO_HEIGHT);
+        frame.pixels.resize(DEMO_WIDTH as usize * DEMO_HEIGHT as usize * 4, 0);
+        draw(self.pattern, self.number, self.started.elapsed(), DEMO_WIDTH, DEMO_HEIGHT, &mut frame.pixels);
+        self.number += 1;
+        Ok(true)
+    }
+}
+
+/// Position `travelled` steps along a track of `length`, turning back at
+/// either end
+fn bounce(travelled: u64, length: usize) -> usize {
+    let length = length.max(1) as u64;
+    let position = travelled % (2 * length);
+    (if position < length { position } else { 2 * length - position }) as usize
+}
+
+/// 3x5 glyphs for the burn-in, one row of three bits per byte
+fn glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        _ => [0; 5],
+    }
+}
+
+/// White `text` on a black box in the top-left corner
+fn burn_in(text: &str, width: u32, height: u32, pixels: &mut [u8]) {
+    let (w, h) = (width as usize, height as usize);
+    let dot = (h / 120).max(1);
+    let box_width = ((text.chars().count() * 4 + 1) * dot).min(w);
+    let box_height = (7 * dot).min(h);
+    for y in 0..box_height {
+        for x in 0..box_width {
+            let (column, line) = (x / dot, y / dot);
+            let lit = (1..=5).contains(&line) && column % 4 != 0 && text.chars().nth(column / 4).is_some_and(|c| {
+                glyph(c)[line - 1] & (0b100 >> (column % 4 - 1)) != 0
+            });
+            let value = if lit { 255 } else { 0 };
+            pixels[(y * w + x) * 4..][..4].copy_from_slice(&[value, value, value, 255]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(pixels: &[u8], width: u32, x: usize, y: usize) -> &[u8] {
+        &pixels[(y * width as usize + x) * 4..][..4]
+    }
+
+    #[test]
+    fn test_patterns() {
+        let (width, height) = (320, 180);
+        let mut first = vec![0u8; 320 * 180 * 4];
+        let mut second = first.clone();
+        for pattern in DemoPattern::value_variants() {
+            draw(*pattern, 0, Duration::ZERO, width, height, &mut first);
+            draw(*pattern, 10, Duration::from_millis(333), width, height, &mut second);
+            assert!(first.chunks_exact(4).all(|pixel| pixel[3] == 255));
+            // Moving, away from the burn-in too
+            let below = 320 * 10 * 4;
+            assert_ne!(first[below..], second[below..], "{:?}", pattern);
+        }
+
+        // The burn-in: "0" starts with a lit top row after a dark margin
+        draw(DemoPattern::Bars, 0, Duration::ZERO, width, height, &mut first);
+        assert_eq!(pixel(&first, width, 0, 0), [0, 0, 0, 255]);
+        assert_eq!(pixel(&first, width, 1, 1), [255, 255, 255, 255]);
+        assert_eq!(pixel(&first, width, 2, 2), [0, 0, 0, 255]);
+        assert_eq!(pixel(&first, width, 200, 100), [191, 0, 191, 255]);
+
+        assert_eq!((bounce(3, 10), bounce(13, 10), bounce(20, 10)), (3, 7, 0));
+    }
+}
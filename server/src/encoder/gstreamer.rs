@@ -0,0 +1,270 @@
+// IP Display Server - GStreamer Encoding
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Encoding through the system's GStreamer. Frames go into an
+//! `appsrc ! videoconvert ! encoder ! parser ! appsink` pipeline, which
+//! hands back Annex B access units with the parameter sets ahead of every
+//! keyframe. videoconvert passes RGBA straight through to encoders that
+//! take it and converts it for the rest.
+//!
+//! The encoder element is picked once, from those of each kind in turn:
+//! `vah264enc` and the like from the `va` plugin, then the older `vaapi`
+//! plugin's, then `nvh264enc` and `nvh265enc` from `nvcodec`, then
+//! `x264enc`, `openh264enc` or `x265enc`. An element counts once it has
+//! opened its device, as the `va` and `nvcodec` plugins only register the
+//! elements a GPU has but the `vaapi` plugin registers them all. A
+//! software encoder is kept in reserve behind a GPU's, and takes over if
+//! the GPU's pipeline fails, as a driver reset can make it.
+
+use anyhow::{Context, Result};
+use gstreamer as gst;
+use gstreamer::glib;
+use gstreamer::prelude::*;
+use gstreamer_app::{AppSink, AppSrc};
+use tracing::{debug, warn};
+
+use super::{Codec, Encoder, EncoderChoice};
+
+/// Elements the pipeline is built from, besides the encoder and parser
+const PIPELINE_ELEMENTS: [&str; 3] = ["appsrc", "videoconvert", "appsink"];
+
+/// Kinds `EncoderChoice::Auto` tries, in order
+const AUTO_ORDER: [EncoderChoice; 3] = [EncoderChoice::Vaapi, EncoderChoice::Nvenc, EncoderChoice::Software];
+
+/// Encoder elements of a kind, best first
+fn candidates(kind: EncoderChoice, codec: Codec) -> &'static [&'static str] {
+    match (kind, codec) {
+        (EncoderChoice::Vaapi, Codec::H264) => &["vah264enc", "vah264lpenc", "vaapih264enc"],
+        (EncoderChoice::Vaapi, Codec::H265) => &["vah265enc", "vah265lpenc", "vaapih265enc"],
+        (EncoderChoice::Nvenc, Codec::H264) => &["nvh264enc"],
+        (EncoderChoice::Nvenc, Codec::H265) => &["nvh265enc"],
+        (EncoderChoice::Software, Codec::H264) => &["x264enc", "openh264enc"],
+        (EncoderChoice::Software, Codec::H265) => &["x265enc"],
+        _ => &[],
+    }
+}
+
+/// Where the elements of a kind come from, for the error when none works
+fn install_hint(kind: EncoderChoice) -> &'static str {
+    match kind {
+        EncoderChoice::Vaapi => "GStreamer's va plugin and a VA-API driver for the GPU",
+        EncoderChoice::Nvenc => "GStreamer's nvcodec plugin and NVIDIA's driver",
+        EncoderChoice::Software => "GStreamer's x264, openh264 or x265 plugin",
+        EncoderChoice::Auto => "GStreamer's va, nvcodec, x264 or x265 plugin",
+    }
+}
+
+fn parser(codec: Codec) -> Result<&'static str> {
+    match codec {
+        Codec::H264 => Ok("h264parse"),
+        Codec::H265 => Ok("h265parse"),
+        Codec::Raw => Err(anyhow::anyhow!("Raw frames aren't encoded")),
+    }
+}
+
+/// The kind and element to encode `codec` with, and the software element
+/// to fall back to if that's a GPU's
+pub fn detect(choice: EncoderChoice, codec: Codec) -> Result<(EncoderChoice, &'static str, Option<&'static str>)> {
+    gst::init().context("Failed to initialise GStreamer")?;
+    for name in PIPELINE_ELEMENTS.into_iter().chain([parser(codec)?]) {
+        if gst::ElementFactory::find(name).is_none() {
+            return Err(anyhow::anyhow!("GStreamer has no {} element; install its base and bad plugins", name));
+        }
+    }
+
+    let kinds = if choice == EncoderChoice::Auto { AUTO_ORDER.to_vec() } else { vec![choice] };
+    for kind in kinds {
+        if let Some(&element) = candidates(kind, codec).iter().find(|name| usable(name)) {
+            let fallback = match kind {
+                EncoderChoice::Software => None,
+                _ => candidates(EncoderChoice::Software, codec).iter().copied().find(|name| usable(name)),
+            };
+            return Ok((kind, element, fallback));
+        }
+        debug!("No working {} encoder for {}", kind, codec);
+    }
+    Err(anyhow::anyhow!("No working {} encoder for {}; install {}", choice, codec, install_hint(choice)))
+}
+
+/// Whether `name` is installed and can open its device
+fn usable(name: &str) -> bool {
+    let Ok(element) = gst::ElementFactory::make(name).build() else { return false };
+    let ready = element.set_state(gst::State::Ready).is_ok();
+    let _ = element.set_state(gst::State::Null);
+    ready
+}
+
+/// Settings for low latency and a keyframe every two seconds, for
+/// whichever of them the element has
+fn tune(encoder: &gst::Element, fps: u32) {
+    let gop = (fps * 2).to_string();
+    let settings = [
+        // x264enc, x265enc
+        ("tune", "zerolatency"),
+        ("speed-preset", "ultrafast"),
+        // nvh264enc and nvh265enc, old and new
+        ("preset", "low-latency-hq"),
+        ("tune", "ultra-low-latency"),
+        ("zerolatency", "true"),
+        ("gop-size", &gop),
+        // The va plugin, x264enc and x265enc
+        ("key-int-max", &gop),
+        // The vaapi plugin
+        ("keyframe-period", &gop),
+    ];
+    for (name, value) in settings {
+        let Some(property) = encoder.find_property(name) else { continue };
+        match glib::Value::deserialize(value, property.value_type()) {
+            Ok(value) => encoder.set_property(name, value),
+            Err(_) => debug!("{} has no {} {}", encoder.name(), name, value),
+        }
+    }
+}
+
+pub struct GstEncoder {
+    codec: Codec,
+    element: &'static str,
+    /// Software element to switch to if `element` fails
+    fallback: Option<&'static str>,
+    fps: u32,
+    stream: Option<Stream>,
+}
+
+impl GstEncoder {
+    pub fn new(codec: Codec, element: &'static str, fallback: Option<&'static str>, fps: u32) -> Self {
+        Self { codec, element, fallback, fps, stream: None }
+    }
+
+    /// Drop a pipeline that failed, so the next frame builds another, with
+    /// the fallback if there is one. A pipeline that failed before it
+    /// encoded anything, with nothing to fall back to, won't do better
+    /// the next time, so that ends the session.
+    fn failed(&mut self, error: anyhow::Error) -> Result<()> {
+        let encoded = self.stream.take().is_some_and(|stream| stream.encoded);
+        match self.fallback.take() {
+            Some(fallback) => {
+                warn!("{} failed: {:#}; falling back to {}", self.element, error, fallback);
+                self.element = fallback;
+            }
+            None if encoded => warn!("{} failed: {:#}; starting it again", self.element, error),
+            None => return Err(error.context(format!("{} failed", self.element))),
+        }
+        Ok(())
+    }
+}
+
+impl Encoder for GstEncoder {
+    fn push(&mut self, width: u32, height: u32, pixels: &[u8], timestamp: u64) -> Result<()> {
+        if self.stream.as_ref().is_none_or(|stream| (stream.width, stream.height) != (width, height)) {
+            self.stream = None;
+            match Stream::open(self.codec, self.element, width, height, self.fps) {
+                Ok(stream) => self.stream = Some(stream),
+                Err(e) => return self.failed(e),
+            }
+        }
+        match self.stream.as_mut().unwrap().push(pixels, timestamp) {
+            Ok(()) => Ok(()),
+            Err(e) => self.failed(e),
+        }
+    }
+
+    fn pull(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(stream) = &mut self.stream else { return Ok(None) };
+        match stream.pull() {
+            Ok(unit) => Ok(unit),
+            Err(e) => self.failed(e).map(|()| None),
+        }
+    }
+
+    fn set_fps(&mut self, fps: u32) {
+        if fps != self.fps {
+            self.fps = fps;
+            self.stream = None;
+        }
+    }
+}
+
+/// A running pipeline for one size
+struct Stream {
+    width: u32,
+    height: u32,
+    pipeline: gst::Pipeline,
+    src: AppSrc,
+    sink: AppSink,
+    /// Timestamp of the first frame, which the stream starts from
+    start: Option<u64>,
+    /// Handed back an access unit
+    encoded: bool,
+}
+
+impl Stream {
+    fn open(codec: Codec, element: &str, width: u32, height: u32, fps: u32) -> Result<Self> {
+        let caps = gst::Caps::builder("video/x-raw")
+            .field("format", "RGBA")
+            .field("width", width as i32)
+            .field("height", height as i32)
+            .field("framerate", gst::Fraction::new(fps as i32, 1))
+            .build();
+        let src = AppSrc::builder()
+            .caps(&caps)
+            .is_live(true)
+            .format(gst::Format::Time)
+            .build();
+        let convert = gst::ElementFactory::make("videoconvert").build()?;
+        let encoder = gst::ElementFactory::make(element).build()?;
+        tune(&encoder, fps);
+        let parser = gst::ElementFactory::make(parser(codec)?).property("config-interval", -1i32).build()?;
+        let media = if codec == Codec::H264 { "video/x-h264" } else { "video/x-h265" };
+        let sink = AppSink::builder()
+            .caps(&gst::Caps::builder(media).field("stream-format", "byte-stream").field("alignment", "au").build())
+            .sync(false)
+            .build();
+
+        let pipeline = gst::Pipeline::new();
+        pipeline.add_many([src.upcast_ref(), &convert, &encoder, &parser, sink.upcast_ref()])?;
+        gst::Element::link_many([src.upcast_ref(), &convert, &encoder, &parser, sink.upcast_ref()])?;
+        pipeline.set_state(gst::State::Playing)?;
+        debug!("Encoding {}x{} with {}", width, height, element);
+        Ok(Self { width, height, pipeline, src, sink, start: None, encoded: false })
+    }
+
+    fn push(&mut self, pixels: &[u8], timestamp: u64) -> Result<()> {
+        let start = *self.start.get_or_insert(timestamp);
+        let mut buffer = gst::Buffer::from_slice(pixels.to_vec());
+        buffer.get_mut().unwrap().set_pts(gst::ClockTime::from_nseconds(timestamp.saturating_sub(start)));
+        self.src
+            .push_buffer(buffer)
+            .map_err(|e| anyhow::anyhow!("GStreamer refused the frame: {:?}", e))?;
+        self.check_bus()
+    }
+
+    fn pull(&mut self) -> Result<Option<Vec<u8>>> {
+        let Some(sample) = self.sink.try_pull_sample(gst::ClockTime::ZERO) else {
+            self.check_bus()?;
+            return Ok(None);
+        };
+        let buffer = sample.buffer().context("Encoded sample has no buffer")?;
+        let unit = buffer.map_readable()?.to_vec();
+        self.encoded = true;
+        Ok(Some(unit))
+    }
+
+    /// The first error the pipeline has posted, if any
+    fn check_bus(&self) -> Result<()> {
+        let bus = self.pipeline.bus().context("Pipeline has no bus")?;
+        match bus.pop_filtered(&[gst::MessageType::Error]) {
+            Some(message) => match message.view() {
+                gst::MessageView::Error(error) => Err(anyhow::anyhow!("GStreamer: {}", error.error())),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
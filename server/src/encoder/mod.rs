@@ -0,0 +1,154 @@
+// IP Display Server - Encoding
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Compressing frames to H.264 or H.265 instead of sending them raw, for
+//! screens too big to send uncompressed. Encoding goes through the
+//! system's GStreamer (the `gstreamer` feature), preferring the GPU's
+//! encoder: VA-API on Intel and AMD, then NVENC on NVIDIA, then x264,
+//! OpenH264 or x265 on the CPU. Which of those are usable is worked out
+//! once at startup, by finding each encoder element and opening its device.
+//!
+//! Each session has its own encoder, since each client may take a
+//! different size and needs its stream to start with a keyframe. Clients
+//! show the frames with a decoder of their own (`--decoder gstreamer`).
+
+use anyhow::Result;
+use clap::ValueEnum;
+use std::fmt;
+
+use ipds_protocol::FrameFormat;
+
+#[cfg(feature = "gstreamer")]
+pub mod gstreamer;
+
+/// What frames are sent as
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Codec {
+    /// Uncompressed RGBA, which every client shows
+    #[default]
+    Raw,
+    /// H.264, Annex B byte stream
+    H264,
+    /// H.265, Annex B byte stream
+    H265,
+}
+
+impl Codec {
+    pub fn format(self) -> FrameFormat {
+        match self {
+            Codec::Raw => FrameFormat::Rgba32,
+            Codec::H264 => FrameFormat::H264,
+            Codec::H265 => FrameFormat::H265,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Raw => "raw RGBA",
+            Codec::H264 => "H.264",
+            Codec::H265 => "H.265",
+        })
+    }
+}
+
+/// Which encoder compresses the frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum EncoderChoice {
+    /// The first of VA-API, NVENC and software that works here
+    #[default]
+    Auto,
+    /// The GPU's encoder through VA-API (Intel, AMD)
+    Vaapi,
+    /// NVIDIA's NVENC
+    Nvenc,
+    /// x264, OpenH264 or x265 on the CPU
+    Software,
+}
+
+impl fmt::Display for EncoderChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EncoderChoice::Auto => "auto",
+            EncoderChoice::Vaapi => "VA-API",
+            EncoderChoice::Nvenc => "NVENC",
+            EncoderChoice::Software => "software",
+        })
+    }
+}
+
+/// Compresses one session's frames
+pub trait Encoder: Send {
+    /// Take a `width` by `height` RGBA picture, stamped with `timestamp`
+    /// in nanoseconds. A new size starts a new stream. An encoder that
+    /// fails starts again, on the CPU if it was the GPU's, and only
+    /// errors once that can't help.
+    fn push(&mut self, width: u32, height: u32, pixels: &[u8], timestamp: u64) -> Result<()>;
+
+    /// The next access unit the encoder has finished, if any; never waits
+    fn pull(&mut self) -> Result<Option<Vec<u8>>>;
+
+    /// Encode at `fps` from the next picture on, in a new stream
+    fn set_fps(&mut self, fps: u32);
+}
+
+/// The encoder found for a codec, from which each session opens its own
+#[derive(Debug, Clone)]
+pub struct Encoding {
+    codec: Codec,
+    kind: EncoderChoice,
+    /// The GStreamer element that does the encoding
+    element: &'static str,
+    /// The software element that takes over if a GPU's fails
+    fallback: Option<&'static str>,
+}
+
+impl Encoding {
+    /// Find the encoder `choice` asks for, or with `Auto` the first kind
+    /// that works, for `codec`, which isn't `Raw`
+    pub fn detect(choice: EncoderChoice, codec: Codec) -> Result<Self> {
+        detect(choice, codec)
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Start a stream at `fps`
+    pub fn open(&self, fps: u32) -> Result<Box<dyn Encoder>> {
+        open(self, fps)
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} with {} ({})", self.codec, self.element, self.kind)?;
+        match self.fallback {
+            Some(fallback) => write!(f, ", falling back to {}", fallback),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "gstreamer")]
+fn detect(choice: EncoderChoice, codec: Codec) -> Result<Encoding> {
+    let (kind, element, fallback) = gstreamer::detect(choice, codec)?;
+    Ok(Encoding { codec, kind, element, fallback })
+}
+
+#[cfg(not(feature = "gstreamer"))]
+fn detect(_choice: EncoderChoice, codec: Codec) -> Result<Encoding> {
+    Err(anyhow::anyhow!("Built without {} encoding (the gstreamer feature)", codec))
+}
+
+#[cfg(feature = "gstreamer")]
+fn open(encoding: &Encoding, fps: u32) -> Result<Box<dyn Encoder>> {
+    Ok(Box::new(gstreamer::GstEncoder::new(encoding.codec, encoding.element, encoding.fallback, fps)))
+}
+
+#[cfg(not(feature = "gstreamer"))]
+fn open(encoding: &Encoding, _fps: u32) -> Result<Box<dyn Encoder>> {
+    Err(anyhow::anyhow!("Built without {} encoding (the gstreamer feature)", encoding.codec))
+}
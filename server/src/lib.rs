@@ -0,0 +1,657 @@
+// IP Display Server - Sessions
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! The protocol served from userspace rather than by the kernel module. It
+//! listens on a TCP port, and with the `quic` feature for QUIC on the same
+//! UDP port, and sends each client that connects the picture of a
+//! `Screen`: the demo patterns for the client's `--demo`, and the demo
+//! patterns or a captured desktop for the `ipdisp-server` binary.
+//!
+//! It speaks a small part of the protocol: display info, then RGBA32
+//! frames with a CRC and compact headers for clients that ask for them,
+//! Pongs, Quality limits on scale and frame rate, and Pause. A frame is
+//! only sent when the picture changed, with heartbeats in between while
+//! it doesn't or the client is paused. Everything else a client sends is
+//! ignored. With `auth`, a client is only sent frames once it has signed
+//! in, and with `supervise` as well, it may then ask the server to act on
+//! itself. On shutdown every client is sent Goodbye and given a moment to
+//! hang up, so it reconnects at once rather than timing out.
+
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+use ipdisp_core::{timesync, HEARTBEAT_INTERVAL};
+use ipds_protocol::{
+    encode_header, Capabilities, Command, FrameFormat, PacketHeader, Pong, ServerMessage, SuperviseAction,
+    SuperviseResult, HEADER_SIZE,
+};
+
+pub mod auth;
+pub mod capture;
+pub mod demo;
+pub mod encoder;
+pub mod log_file;
+pub mod supervise;
+pub mod systemd;
+
+use auth::Auth;
+use capture::Screen;
+use supervise::Supervisor;
+
+/// Largest request payload read from a client; theirs are all tiny
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// How long a client told Goodbye has to hang up before it is cut off
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+// From <errno.h>, for refused supervision requests
+const EPERM: i32 = 1;
+const EACCES: i32 = 13;
+
+/// The features of a client's Hello the server will use
+pub const SERVER_CAPABILITIES: Capabilities = Capabilities::CRC32.union(Capabilities::COMPACT_HEADER);
+
+/// What is served, and to whom
+#[derive(Clone)]
+pub struct Service {
+    screen: Screen,
+    auth: Option<Arc<Auth>>,
+    supervisor: Option<Arc<Supervisor>>,
+}
+
+impl Service {
+    /// Serve `screen` to anyone
+    pub fn new(screen: Screen) -> Self {
+        Self { screen, auth: None, supervisor: None }
+    }
+
+    /// Only serve clients one of `auth`'s providers accepts
+    pub fn authenticated(self, auth: Auth) -> Self {
+        Self { auth: Some(Arc::new(auth)), ..self }
+    }
+
+    /// Let signed-in clients in the full session mode have `supervisor`
+    /// act on the server; without `authenticated`, no client may
+    pub fn supervised(self, supervisor: Supervisor) -> Self {
+        Self { supervisor: Some(Arc::new(supervisor)), ..self }
+    }
+
+    /// Carry out a client's SUPERVISE request, if its session `allowed` it
+    async fn supervise(&self, action: SuperviseAction, arg: u32, allowed: bool) -> SuperviseResult {
+        let refused = |status: i32, message: &str| {
+            SuperviseResult { action: action as u32, status: -status, message: message.to_string() }
+        };
+        match &self.supervisor {
+            None => refused(EPERM, "Supervision is off on this server"),
+            Some(_) if self.auth.is_none() => refused(EACCES, "Needs a signed-in client; see --auth"),
+            Some(_) if !allowed => refused(EPERM, "Needs the full session mode"),
+            Some(supervisor) => supervisor.handle(&self.screen, action, arg).await,
+        }
+    }
+
+    /// The features of a client's Hello this service will use
+    fn capabilities(&self) -> Capabilities {
+        match self.auth {
+            Some(_) => SERVER_CAPABILITIES | Capabilities::AUTH_PROMPT,
+            None => SERVER_CAPABILITIES,
+        }
+    }
+}
+
+/// Serve `service` on `addr` until `shutdown`, returning the address
+/// bound, which has the port picked when `addr` asks for port 0
+pub fn start(
+    addr: SocketAddr,
+    service: Service,
+    rt: &tokio::runtime::Handle,
+    tasks: &TaskTracker,
+    shutdown: &CancellationToken,
+) -> Result<SocketAddr> {
+    start_on(std::net::TcpListener::bind(addr)?, service, rt, tasks, shutdown)
+}
+
+/// Serve `service` on a socket that is already listening, e.g. one
+/// systemd passed, until `shutdown`, returning its address
+pub fn start_on(
+    listener: std::net::TcpListener,
+    service: Service,
+    rt: &tokio::runtime::Handle,
+    tasks: &TaskTracker,
+    shutdown: &CancellationToken,
+) -> Result<SocketAddr> {
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    info!("Serving on {}", addr);
+
+    let (tasks, shutdown) = (tasks.clone(), shutdown.clone());
+    tasks.clone().spawn_on(async move {
+        let listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => return warn!("Server failed to start: {}", e),
+        };
+        while let Some(accepted) = shutdown.run_until_cancelled(listener.accept()).await {
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Server failed to accept: {}", e);
+                    continue;
+                }
+            };
+            debug!("Client connected from {}", peer);
+            let (service, shutdown) = (service.clone(), shutdown.clone());
+            tasks.spawn(async move {
+                let (reader, writer) = stream.into_split();
+                if let Err(e) = serve(reader, writer, service, (peer, false), shutdown).await {
+                    debug!("Client {} went away: {}", peer, e);
+                }
+            });
+        }
+    }, rt);
+    Ok(addr)
+}
+
+/// Serve `service` over QUIC on `addr` until `shutdown`, returning the
+/// address bound
+#[cfg(feature = "quic")]
+pub fn start_quic(
+    addr: SocketAddr,
+    service: Service,
+    rt: &tokio::runtime::Handle,
+    tasks: &TaskTracker,
+    shutdown: &CancellationToken,
+) -> Result<SocketAddr> {
+    let listener = {
+        let _runtime = rt.enter();
+        ipdisp_transports::quic::QuicListener::bind(addr)?
+    };
+    let addr = listener.local_addr()?;
+    info!("Serving over QUIC on {}", addr);
+
+    let (tasks, shutdown) = (tasks.clone(), shutdown.clone());
+    tasks.clone().spawn_on(async move {
+        while let Some(Some(accepted)) = shutdown.run_until_cancelled(listener.accept()).await {
+            let (reader, writer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Server failed to accept over QUIC: {}", e);
+                    continue;
+                }
+            };
+            let peer = writer.remote_address();
+            debug!("Client connected over QUIC from {}", peer);
+            let (service, shutdown) = (service.clone(), shutdown.clone());
+            tasks.spawn(async move {
+                if let Err(e) = serve(reader, writer, service, (peer, true), shutdown).await {
+                    debug!("Client {} went away: {}", peer, e);
+                }
+            });
+        }
+    }, rt);
+    Ok(addr)
+}
+
+/// Serve one client, at `peer` over a link that is `encrypted` or not
+async fn serve<R, W>(
+    mut reader: R,
+    mut writer: W,
+    service: Service,
+    (peer, encrypted): (SocketAddr, bool),
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    let (request_tx, mut requests) = mpsc::unbounded_channel();
+    let mut reading = tokio::spawn(async move { read_requests(&mut reader, request_tx).await });
+
+    // Nothing is sent before the handshake
+    let offered = service.capabilities();
+    let (mut capabilities, mut mode) = match shutdown.run_until_cancelled(requests.recv()).await {
+        Some(Some((Command::Hello { capabilities, mode, .. }, _))) => (capabilities.intersect(offered), mode),
+        _ => {
+            reading.abort();
+            return Ok(());
+        }
+    };
+    writer.write_all(&ServerMessage::Capabilities(capabilities).to_bytes()).await?;
+    // Any mode will do; only supervision needs the full one
+    writer.write_all(&ServerMessage::SessionMode(mode).to_bytes()).await?;
+
+    // Nor are frames before signing in; what else the client asks for
+    // meanwhile is done after
+    let mut deferred = VecDeque::new();
+    if let Some(auth) = &service.auth {
+        let signing_in = auth.authenticate(&mut writer, &mut requests, &mut deferred, (peer, encrypted), capabilities);
+        let signed_in = match shutdown.run_until_cancelled(signing_in).await {
+            Some(user) => user?.is_some(),
+            None => {
+                writer.write_all(&ServerMessage::Goodbye.to_bytes()).await?;
+                false
+            }
+        };
+        if !signed_in {
+            writer.shutdown().await?;
+            reading.abort();
+            return Ok(());
+        }
+    }
+
+    let screen = &service.screen;
+    let mut frames = screen.subscribe();
+    let (mut scale, mut fps) = (1, screen.fps());
+    let (format, mut encoder) = match screen.encoding() {
+        Some(encoding) => (encoding.codec().format(), Some(encoding.open(fps)?)),
+        None => (FrameFormat::Rgba32, None),
+    };
+    let mut ticks = frame_ticks(fps);
+    let mut previous: Option<PacketHeader> = None;
+    // Display info goes ahead of the first frame and any at a new size
+    let mut announced = None;
+    let mut scaled = Vec::new();
+    let mut paused = false;
+    let mut last_sent = Instant::now();
+
+    let result = loop {
+        tokio::select! {
+            // Wait for the client to hang up, so the Goodbye isn't lost
+            // with the connection
+            _ = shutdown.cancelled() => {
+                writer.write_all(&ServerMessage::Goodbye.to_bytes()).await?;
+                writer.shutdown().await?;
+                let _ = tokio::time::timeout(DRAIN_TIMEOUT, &mut reading).await;
+                break Ok(());
+            }
+            request = next_request(&mut deferred, &mut requests) => match request {
+                None | Some((Command::Goodbye, _)) => break Ok(()),
+                Some((Command::Hello { capabilities: requested, mode: requested_mode, .. }, _)) => {
+                    (capabilities, mode) = (requested.intersect(offered), requested_mode);
+                    writer.write_all(&ServerMessage::Capabilities(capabilities).to_bytes()).await?;
+                    writer.write_all(&ServerMessage::SessionMode(mode).to_bytes()).await?;
+                }
+                Some((Command::SessionMode { mode: requested_mode }, _)) => {
+                    mode = requested_mode;
+                    writer.write_all(&ServerMessage::SessionMode(mode).to_bytes()).await?;
+                }
+                Some((request @ Command::Supervise { action, arg }, _)) => {
+                    let result = service.supervise(action, arg, mode.allows(&request)).await;
+                    writer.write_all(&ServerMessage::Supervise(result).to_bytes()).await?;
+                }
+                Some((Command::Ping { client_ns }, server_rx_ns)) => {
+                    let pong = Pong { client_ns, server_rx_ns, server_tx_ns: timesync::now_ns() };
+                    writer.write_all(&ServerMessage::Pong(pong).to_bytes()).await?;
+                }
+                Some((Command::Quality { scale: requested_scale, max_fps, .. }, _)) => {
+                    let requested_scale = if matches!(requested_scale, 2 | 4) { requested_scale } else { 1 };
+                    // The picture again at the new size, even if it's still;
+                    // an encoder starts a new stream for it
+                    if requested_scale != scale {
+                        scale = requested_scale;
+                        frames.mark_changed();
+                    }
+                    let requested_fps = if max_fps == 0 { screen.fps() } else { max_fps.min(screen.fps()) };
+                    if requested_fps != fps {
+                        fps = requested_fps;
+                        ticks = frame_ticks(fps);
+                        if let Some(encoder) = &mut encoder {
+                            encoder.set_fps(fps);
+                            frames.mark_changed();
+                        }
+                    }
+                    debug!("Client asked for 1/{} scale at {} fps", scale, fps);
+                }
+                Some((Command::Pause { paused: requested }, _)) => paused = requested,
+                Some((other, _)) => debug!("Server ignoring {:?}", other.packet_type()),
+            },
+            _ = ticks.tick() => {
+                let changed = match frames.has_changed() {
+                    Ok(changed) => changed && !paused,
+                    Err(_) => break Err(anyhow::anyhow!("Capture stopped")),
+                };
+                let frame = if changed { frames.borrow_and_update().clone() } else { None };
+                if let Some(frame) = frame {
+                    let (width, height, pixels) = if scale == 1 {
+                        (frame.width, frame.height, &frame.pixels)
+                    } else {
+                        let (width, height) = capture::downscale(&frame, scale, &mut scaled);
+                        (width, height, &scaled)
+                    };
+                    if announced != Some((width, height)) {
+                        send_info(&mut writer, width, height, format).await?;
+                        announced = Some((width, height));
+                    }
+                    match &mut encoder {
+                        Some(encoder) => encoder.push(width, height, pixels, timesync::now_ns())?,
+                        None => {
+                            send_frame(&mut writer, (width, height, format), pixels, capabilities, &mut previous).await?;
+                            last_sent = Instant::now();
+                        }
+                    }
+                }
+
+                // Encoders hand frames back a tick or two after taking them
+                if let (Some(encoder), Some((width, height))) = (&mut encoder, announced) {
+                    while let Some(unit) = encoder.pull()? {
+                        send_frame(&mut writer, (width, height, format), &unit, capabilities, &mut previous).await?;
+                        last_sent = Instant::now();
+                    }
+                }
+
+                // Heartbeats keep a paused client, or one watching a still
+                // screen, from timing out
+                if last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                    writer.write_all(&ServerMessage::Heartbeat.to_bytes()).await?;
+                    last_sent = Instant::now();
+                }
+            }
+        }
+    };
+    reading.abort();
+    result
+}
+
+/// The next request, starting with those held back while signing in
+async fn next_request(
+    deferred: &mut VecDeque<(Command, u64)>,
+    requests: &mut UnboundedReceiver<(Command, u64)>,
+) -> Option<(Command, u64)> {
+    match deferred.pop_front() {
+        Some(request) => Some(request),
+        None => requests.recv().await,
+    }
+}
+
+fn frame_ticks(fps: u32) -> tokio::time::Interval {
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / fps);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    ticks
+}
+
+/// Display info for `format` frames of `width` by `height`
+async fn send_info<W: AsyncWrite + Unpin>(writer: &mut W, width: u32, height: u32, format: FrameFormat) -> Result<()> {
+    let info = PacketHeader::new(width, height, format, 0);
+    writer.write_all(&info.to_bytes()).await?;
+    Ok(())
+}
+
+/// Send `payload` as a frame of the given size and format, with a CRC and
+/// a compact header when the client takes them
+async fn send_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    (width, height, format): (u32, u32, FrameFormat),
+    payload: &[u8],
+    capabilities: Capabilities,
+    previous: &mut Option<PacketHeader>,
+) -> Result<()> {
+    // Whole microseconds, so compact headers can repeat them
+    let mut header = PacketHeader::new(width, height, format, payload.len() as u32);
+    header.timestamp = timesync::now_ns() / 1000 * 1000;
+    if capabilities.supports(Capabilities::CRC32) {
+        header.crc32 = Some(crc32fast::hash(payload));
+    }
+    writer.write_all(&encode_header(&header, previous.as_ref(), capabilities)).await?;
+    writer.write_all(payload).await?;
+    *previous = Some(header);
+    Ok(())
+}
+
+/// Parse what the client sends until it hangs up, passing on each request
+/// with the time it arrived. Clients send version 1 headers without
+/// extensions.
+async fn read_requests<R: AsyncRead + Unpin>(reader: &mut R, requests: UnboundedSender<(Command, u64)>) -> Result<()> {
+    loop {
+        let mut header_buf = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header_buf).await?;
+        let received_ns = timesync::now_ns();
+        let header = PacketHeader::from_bytes(&header_buf)?;
+        if header.size as usize > MAX_REQUEST_SIZE {
+            return Err(anyhow::anyhow!("{:?} request too large: {} bytes", header.packet_type, header.size));
+        }
+        let mut payload = vec![0u8; header.size as usize];
+        reader.read_exact(&mut payload).await?;
+
+        let request = match Command::from_payload(header.packet_type, &payload, header.byte_order) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Server ignoring a request: {}", e);
+                continue;
+            }
+        };
+        if requests.send((request, received_ns)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::token::TokenProvider;
+    use capture::Capture;
+    use demo::{DemoPattern, DemoSource, DEMO_FPS, DEMO_HEIGHT, DEMO_WIDTH};
+    use hmac::{Hmac, Mac};
+    use ipds_protocol::{parse_header, AuthPrompt, CompactHeader, PacketType, PromptStyle, SessionMode, COMPACT_HEADER_SIZE};
+    use sha2::{Digest, Sha256};
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpStream;
+
+    /// Read one packet the way a client would, expanding compact headers
+    /// against `previous`
+    async fn read_packet(stream: &mut TcpStream, previous: Option<&PacketHeader>) -> (PacketHeader, Vec<u8>) {
+        let mut bytes = vec![0u8; COMPACT_HEADER_SIZE];
+        stream.read_exact(&mut bytes).await.unwrap();
+        let start: &[u8; COMPACT_HEADER_SIZE] = bytes[..].try_into().unwrap();
+        let size = if CompactHeader::is_compact(bytes[0]) {
+            COMPACT_HEADER_SIZE + CompactHeader::from_bytes(start).unwrap().extension_size()
+        } else {
+            let fixed_size = PacketHeader::fixed_size(start).unwrap();
+            bytes.resize(HEADER_SIZE, 0);
+            stream.read_exact(&mut bytes[COMPACT_HEADER_SIZE..]).await.unwrap();
+            fixed_size + PacketHeader::extension_size(bytes[..].try_into().unwrap()).unwrap()
+        };
+        let read = bytes.len();
+        bytes.resize(size, 0);
+        stream.read_exact(&mut bytes[read..]).await.unwrap();
+        let header = parse_header(&bytes, previous).unwrap();
+        let mut payload = vec![0u8; header.size as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        (header, payload)
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let (tasks, shutdown) = (TaskTracker::new(), CancellationToken::new());
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let screen = Screen::capture(Box::new(DemoSource::new(DemoPattern::Bounce)), DEMO_FPS, &shutdown).unwrap();
+        let addr = start(addr, Service::new(screen), &tokio::runtime::Handle::current(), &tasks, &shutdown).unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let hello = Command::Hello {
+            refresh_mhz: 60_000,
+            session_id: 0,
+            link_mode: 0,
+            capabilities: Capabilities::all(),
+            mode: SessionMode::View,
+        };
+        stream.write_all(&hello.to_bytes()).await.unwrap();
+
+        // The features agreed and the session mode, display info, then
+        // CRC-checked frames, the later ones compact
+        let (header, payload) = read_packet(&mut stream, None).await;
+        let agreed = ServerMessage::from_payload(header.packet_type, &payload, header.byte_order).unwrap();
+        assert_eq!(agreed, ServerMessage::Capabilities(SERVER_CAPABILITIES));
+        let (header, _) = read_packet(&mut stream, None).await;
+        assert_eq!(header.packet_type, PacketType::SessionMode);
+        let (info, _) = read_packet(&mut stream, None).await;
+        assert!(info.is_info_packet());
+        assert_eq!((info.width, info.height), (DEMO_WIDTH, DEMO_HEIGHT));
+        let mut previous = None;
+        for _ in 0..3 {
+            let (frame, pixels) = read_packet(&mut stream, previous.as_ref()).await;
+            assert!(frame.is_frame_packet());
+            assert_eq!(frame.crc32, Some(crc32fast::hash(&pixels)));
+            assert!(previous.as_ref().is_none_or(|previous| previous.timestamp < frame.timestamp));
+            previous = Some(frame);
+        }
+
+        // Half size from the next info packet on
+        let quality = Command::Quality { max_kbps: 0, scale: 2, max_fps: 0, format: FrameFormat::Rgba32 };
+        stream.write_all(&quality.to_bytes()).await.unwrap();
+        let info = loop {
+            let (header, _) = read_packet(&mut stream, previous.as_ref()).await;
+            if header.is_info_packet() {
+                break header;
+            }
+            previous = Some(header);
+        };
+        assert_eq!((info.width, info.height), (DEMO_WIDTH / 2, DEMO_HEIGHT / 2));
+
+        // Shutting down says Goodbye, after any frames already on the way
+        shutdown.cancel();
+        let mut previous = None;
+        loop {
+            let (header, _) = read_packet(&mut stream, previous.as_ref()).await;
+            if header.packet_type == PacketType::Goodbye {
+                break;
+            }
+            previous = Some(header).filter(|header| header.is_frame_packet());
+        }
+        drop(stream);
+        tasks.close();
+        tasks.wait().await;
+    }
+
+    /// An HMAC-SHA256, as clients answer challenges with
+    fn mac(key: &[u8], data: &[u8]) -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// `screen` for clients with the token "hunter2", and its key and id
+    /// as the client derives them
+    fn token_service(screen: Screen) -> (Service, [u8; 32], [u8; 8]) {
+        let auth = Auth::new(vec![Box::new(TokenProvider::new("hunter2\n").unwrap())]);
+        let key: [u8; 32] = Sha256::digest("hunter2").into();
+        let id = mac(&key, b"ipdisp token id")[..8].try_into().unwrap();
+        (Service::new(screen).authenticated(auth), key, id)
+    }
+
+    /// Connect in `mode`, answer the challenge with `answer` and return
+    /// what the server says to it
+    async fn sign_in(addr: SocketAddr, mode: SessionMode, answer: impl FnOnce([u8; 16]) -> Command) -> (TcpStream, AuthPrompt) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let hello = Command::Hello { refresh_mhz: 60_000, session_id: 0, link_mode: 0, capabilities: Capabilities::all(), mode };
+        stream.write_all(&hello.to_bytes()).await.unwrap();
+        let (header, payload) = read_packet(&mut stream, None).await;
+        let agreed = ServerMessage::from_payload(header.packet_type, &payload, header.byte_order).unwrap();
+        assert_eq!(agreed, ServerMessage::Capabilities(SERVER_CAPABILITIES | Capabilities::AUTH_PROMPT));
+        let (header, _) = read_packet(&mut stream, None).await;
+        assert_eq!(header.packet_type, PacketType::SessionMode);
+        let (header, payload) = read_packet(&mut stream, None).await;
+        let ServerMessage::AuthChallenge { nonce } = ServerMessage::from_payload(header.packet_type, &payload, header.byte_order).unwrap() else {
+            panic!("Expected a challenge, not {:?}", header.packet_type);
+        };
+        stream.write_all(&answer(nonce).to_bytes()).await.unwrap();
+        let (header, payload) = read_packet(&mut stream, None).await;
+        match ServerMessage::from_payload(header.packet_type, &payload, header.byte_order).unwrap() {
+            ServerMessage::AuthPrompt(prompt) => (stream, prompt),
+            other => panic!("Expected a prompt, not {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let (tasks, shutdown) = (TaskTracker::new(), CancellationToken::new());
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let screen = Screen::capture(Box::new(DemoSource::new(DemoPattern::Bounce)), DEMO_FPS, &shutdown).unwrap();
+        let (service, key, id) = token_service(screen);
+        let addr = start(addr, service, &tokio::runtime::Handle::current(), &tasks, &shutdown).unwrap();
+
+        // Frames once the answer is right
+        let (mut stream, prompt) = sign_in(addr, SessionMode::View, |nonce| Command::Auth { id, mac: mac(&key, &nonce) }).await;
+        assert_eq!(prompt.style, PromptStyle::Accepted);
+        let (info, _) = read_packet(&mut stream, None).await;
+        assert!(info.is_info_packet());
+        drop(stream);
+
+        // Turned away with a wrong answer, or none
+        let (mut stream, prompt) = sign_in(addr, SessionMode::View, |nonce| Command::Auth { id, mac: mac(b"guess", &nonce) }).await;
+        assert_eq!(prompt, AuthPrompt { style: PromptStyle::Error, text: "Wrong answer for the auth token".to_string() });
+        assert_eq!(stream.read(&mut [0u8; 1]).await.unwrap(), 0);
+        let (mut stream, prompt) = sign_in(addr, SessionMode::View, |_| Command::AuthReply { text: String::new() }).await;
+        assert_eq!(prompt.style, PromptStyle::Error);
+        assert_eq!(stream.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+        shutdown.cancel();
+        tasks.close();
+        tasks.wait().await;
+    }
+
+    /// Ask for `action` and return the reply, past the frames sent
+    /// meanwhile, the last of which ends up in `previous`
+    async fn supervise(stream: &mut TcpStream, previous: &mut Option<PacketHeader>, action: SuperviseAction, arg: u32) -> SuperviseResult {
+        stream.write_all(&Command::Supervise { action, arg }.to_bytes()).await.unwrap();
+        loop {
+            let (header, payload) = read_packet(stream, previous.as_ref()).await;
+            match header.packet_type {
+                PacketType::Supervise => break SuperviseResult::from_payload(&payload, header.byte_order).unwrap(),
+                _ if header.is_frame_packet() => *previous = Some(header),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_supervise() {
+        let (tasks, shutdown) = (TaskTracker::new(), CancellationToken::new());
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let screen = Screen::capture(Box::new(DemoSource::new(DemoPattern::Bounce)), DEMO_FPS, &shutdown).unwrap();
+        let (service, key, id) = token_service(screen);
+        let supervisor = Supervisor::new(vec![Capture::Demo, Capture::Demo], DemoPattern::Bars, None);
+        let service = service.supervised(supervisor);
+        let addr = start(addr, service, &tokio::runtime::Handle::current(), &tasks, &shutdown).unwrap();
+        let (mut stream, _) = sign_in(addr, SessionMode::Full, |nonce| Command::Auth { id, mac: mac(&key, &nonce) }).await;
+
+        let mut previous = None;
+        let result = supervise(&mut stream, &mut previous, SuperviseAction::SetSource, 1).await;
+        assert_eq!((result.action, result.status), (1, 0));
+        assert_eq!(result.message, "Streaming source 2 (demo)");
+        assert_eq!(supervise(&mut stream, &mut previous, SuperviseAction::SetSource, 2).await.status, -22);
+        assert_eq!(supervise(&mut stream, &mut previous, SuperviseAction::RestartCapture, 0).await.status, 0);
+        assert_eq!(supervise(&mut stream, &mut previous, SuperviseAction::ResendFrame, 0).await.status, 0);
+        // Without --log-file
+        assert_eq!(supervise(&mut stream, &mut previous, SuperviseAction::RotateLogs, 0).await.status, -95);
+
+        // Not below the full session mode
+        stream.write_all(&Command::SessionMode { mode: SessionMode::Input }.to_bytes()).await.unwrap();
+        assert_eq!(supervise(&mut stream, &mut previous, SuperviseAction::ResendFrame, 0).await.status, -EPERM);
+
+        drop(stream);
+        shutdown.cancel();
+        tasks.close();
+        tasks.wait().await;
+    }
+
+    #[test]
+    fn test_compact_headers() {
+        // Only for clients that take them
+        let first = PacketHeader::new(320, 180, FrameFormat::Rgba32, 16);
+        let second = PacketHeader { timestamp: first.timestamp / 1000 * 1000 + 33_000_000, ..first.clone() };
+        let first = PacketHeader { timestamp: first.timestamp / 1000 * 1000, ..first };
+        assert_eq!(encode_header(&second, Some(&first), Capabilities::COMPACT_HEADER).len(), 8);
+        assert_eq!(encode_header(&second, Some(&first), Capabilities::empty()), second.to_bytes());
+        assert_eq!(encode_header(&second, None, Capabilities::COMPACT_HEADER), second.to_bytes());
+    }
+}
@@ -0,0 +1,67 @@
+// IP Display Server - Log File
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Logs written to `--log-file` instead of stderr. Rotating them is left
+//! to logrotate or the like, which moves the file aside; the server then
+//! opens the path again when a client asks it to rotate logs, and carries
+//! on in a new file. Until then it keeps writing to the moved one, so no
+//! line is lost.
+
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A log file that can be opened again at the same path
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl LogFile {
+    /// Append to the file at `path`, creating it if need be
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self { path: path.to_path_buf(), file: Arc::new(Mutex::new(append(path)?)) })
+    }
+
+    /// Write to whatever is at the path now, from the next line on
+    pub fn reopen(&self) -> Result<()> {
+        let file = append(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))
+}
+
+/// One log line's hold on the file, so lines from different threads don't
+/// interleave
+pub struct LogWriter<'a>(MutexGuard<'a, File>);
+
+impl Write for LogWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogWriter(self.file.lock().unwrap())
+    }
+}
@@ -0,0 +1,191 @@
+// IP Display Server - Command Line
+// Copyright (c) 2024
+// Licensed under MIT
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::info;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+use ipdisp_server::auth::{self, Auth, AuthMethod, AuthOptions};
+use ipdisp_server::capture::{self, Capture, Screen};
+use ipdisp_server::demo::{DemoPattern, DEMO_FPS};
+use ipdisp_server::encoder::{Codec, EncoderChoice, Encoding};
+use ipdisp_server::log_file::LogFile;
+use ipdisp_server::supervise::Supervisor;
+use ipdisp_server::{systemd, Service};
+
+#[derive(Parser, Debug)]
+#[command(name = "ipdisp-server")]
+#[command(about = "IP Display server in userspace")]
+struct Args {
+    /// Address to listen on
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    listen: IpAddr,
+
+    /// Port to listen on; the kernel module's default is the same
+    #[arg(short, long, default_value_t = 8080)]
+    port: u16,
+
+    /// What to serve: a demo pattern or a desktop. With several, the
+    /// first is served and `--supervise` lets clients switch
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "demo")]
+    capture: Vec<Capture>,
+
+    /// Pattern to show with `--capture demo`
+    #[arg(long, value_enum, default_value_t)]
+    pattern: DemoPattern,
+
+    /// How many times a second to capture; a still screen sends nothing
+    #[arg(long, default_value_t = DEMO_FPS)]
+    fps: u32,
+
+    /// What to send frames as; H.264 and H.265 need the gstreamer feature
+    #[arg(long, value_enum, default_value_t)]
+    codec: Codec,
+
+    /// Which encoder compresses H.264 and H.265; `auto` takes the GPU's
+    /// when it has one, else the CPU's
+    #[arg(long, value_enum, default_value_t)]
+    encoder: EncoderChoice,
+
+    /// Take QUIC connections on the same port as well, over UDP
+    #[cfg(feature = "quic")]
+    #[arg(long)]
+    quic: bool,
+
+    /// Only serve clients that sign in with one of these, tried in order;
+    /// `pam` and `oidc` need the features of the same names, and `pam`
+    /// only asks for passwords over QUIC
+    #[arg(long, value_enum, value_delimiter = ',')]
+    auth: Vec<AuthMethod>,
+
+    /// File holding the secret for `--auth token`, shared with the
+    /// clients' `--auth-token-file`
+    #[arg(long)]
+    auth_token_file: Option<PathBuf>,
+
+    /// PAM service, i.e. file in /etc/pam.d, that `--auth pam` checks
+    /// passwords with
+    #[arg(long, default_value = "ipdisp")]
+    pam_service: String,
+
+    /// OpenID Connect issuer `--auth oidc` signs users in with, as an
+    /// https:// URL
+    #[arg(long)]
+    oidc_issuer: Option<String>,
+
+    /// Client id this server is registered with at the issuer, for the
+    /// device flow
+    #[arg(long)]
+    oidc_client_id: Option<String>,
+
+    /// A user the issuer signs in who may watch, by name, e-mail address
+    /// or subject; without any, everyone it signs in may
+    #[arg(long = "oidc-user")]
+    oidc_users: Vec<String>,
+
+    /// Let signed-in clients in the full session mode restart capture,
+    /// switch between the `--capture` sources and rotate `--log-file`;
+    /// needs `--auth`
+    #[arg(long)]
+    supervise: bool,
+
+    /// Write logs to this file instead of stderr, opened again when a
+    /// client asks to rotate logs
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Run as a systemd service: take the listening socket from a .socket
+    /// unit if one started us, and say when ready and when draining
+    #[arg(long)]
+    systemd: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let log_file = args.log_file.as_deref().map(LogFile::open).transpose()?;
+    let writer = match &log_file {
+        Some(log_file) => BoxMakeWriter::new(log_file.clone()),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_ansi(log_file.is_none())
+        .with_writer(writer)
+        .init();
+    if args.supervise && args.auth.is_empty() {
+        return Err(anyhow::anyhow!("--supervise needs --auth, so only signed-in clients supervise"));
+    }
+
+    let (tasks, shutdown) = (TaskTracker::new(), CancellationToken::new());
+    let first = *args.capture.first().context("--capture needs a source")?;
+    let source = capture::open(first, args.pattern).await?;
+    info!("Capturing from {} at {} fps", source.name(), args.fps);
+    let mut screen = Screen::capture(source, args.fps, &shutdown)?;
+    if args.codec != Codec::Raw {
+        let encoding = Encoding::detect(args.encoder, args.codec)?;
+        info!("Encoding {}", encoding);
+        screen = screen.encoded(encoding);
+    }
+    let mut service = Service::new(screen);
+    if !args.auth.is_empty() {
+        let options = AuthOptions {
+            token_file: args.auth_token_file,
+            pam_service: args.pam_service,
+            oidc_issuer: args.oidc_issuer,
+            oidc_client_id: args.oidc_client_id,
+            oidc_users: args.oidc_users,
+        };
+        let providers = args.auth.iter().map(|&method| auth::open(method, &options)).collect::<Result<_>>()?;
+        let auth = Auth::new(providers);
+        info!("Clients sign in with {}", auth.names().join(", "));
+        service = service.authenticated(auth);
+    }
+    if args.supervise {
+        info!("Signed-in clients may supervise, with {} capture sources", args.capture.len());
+        service = service.supervised(Supervisor::new(args.capture, args.pattern, log_file));
+    }
+
+    let rt = tokio::runtime::Handle::current();
+    let activated = if args.systemd { systemd::listener()? } else { None };
+    let addr = match activated {
+        Some(listener) => ipdisp_server::start_on(listener, service.clone(), &rt, &tasks, &shutdown)?,
+        None => ipdisp_server::start(SocketAddr::new(args.listen, args.port), service.clone(), &rt, &tasks, &shutdown)?,
+    };
+    // On the UDP port matching the TCP one, which systemd may have picked
+    #[cfg(feature = "quic")]
+    if args.quic {
+        ipdisp_server::start_quic(SocketAddr::new(args.listen, addr.port()), service, &rt, &tasks, &shutdown)?;
+    }
+    if args.systemd {
+        systemd::ready(&format!("Serving on {}", addr))?;
+    }
+
+    terminated().await?;
+    info!("Shutting down");
+    if args.systemd {
+        systemd::stopping()?;
+    }
+    shutdown.cancel();
+    tasks.close();
+    tasks.wait().await;
+    Ok(())
+}
+
+/// Ctrl+C, or SIGTERM from systemd or `kill`
+async fn terminated() -> Result<()> {
+    let mut term = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = term.recv() => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+// IP Display Server - Supervision
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! What a client may ask of the server from its Server menu with
+//! `--supervise`, so a wedged stream can be fixed without logging in to
+//! the host. Only signed-in clients in the full session mode may ask.
+//!
+//! - Resend frame: every client gets the picture again.
+//! - Restart capture: the source is closed and opened again, e.g. after
+//!   it failed and the picture stopped.
+//! - Set source: capture from another of the `--capture` sources, numbered
+//!   from 0 in the order given. A source is only opened when picked, so a
+//!   PipeWire source asks which monitor to share then.
+//! - Rotate logs: open `--log-file` again, once it has been moved aside.
+
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use ipds_protocol::{SuperviseAction, SuperviseResult};
+
+use crate::capture::{self, Capture, Screen};
+use crate::demo::DemoPattern;
+use crate::log_file::LogFile;
+
+// From <errno.h>, negated in replies as the kernel module's are
+const EIO: i32 = 5;
+const EINVAL: i32 = 22;
+const EOPNOTSUPP: i32 = 95;
+
+/// Carries out supervision requests for every session
+#[derive(Debug)]
+pub struct Supervisor {
+    sources: Vec<Capture>,
+    /// What the demo sources show
+    pattern: DemoPattern,
+    /// The place in `sources` of the one being captured; held while a
+    /// source opens, so requests take turns
+    current: Mutex<usize>,
+    log_file: Option<LogFile>,
+}
+
+impl Supervisor {
+    /// Switch between `sources`, starting with the first, which the screen
+    /// already captures from
+    pub fn new(sources: Vec<Capture>, pattern: DemoPattern, log_file: Option<LogFile>) -> Self {
+        Self { sources, pattern, current: Mutex::new(0), log_file }
+    }
+
+    /// Carry out `action` on `screen` and say how it went
+    pub(crate) async fn handle(&self, screen: &Screen, action: SuperviseAction, arg: u32) -> SuperviseResult {
+        let (status, message) = match self.act(screen, action, arg).await {
+            Ok(message) => {
+                info!("{}", message);
+                (0, message)
+            }
+            Err((status, message)) => {
+                warn!("Refused {:?}: {}", action, message);
+                (-status, message)
+            }
+        };
+        SuperviseResult { action: action as u32, status, message }
+    }
+
+    async fn act(&self, screen: &Screen, action: SuperviseAction, arg: u32) -> Result<String, (i32, String)> {
+        let failed = |e: anyhow::Error| (EIO, format!("{:#}", e));
+        match action {
+            SuperviseAction::ResendFrame => {
+                screen.resend().map_err(failed)?;
+                Ok("Sent a fresh frame".to_string())
+            }
+            SuperviseAction::RestartCapture => {
+                let current = self.current.lock().await;
+                let source = capture::open(self.sources[*current], self.pattern).await.map_err(failed)?;
+                let message = format!("Restarted {} capture", source.name());
+                screen.switch(source).map_err(failed)?;
+                Ok(message)
+            }
+            SuperviseAction::SetSource => {
+                let Some(&capture) = self.sources.get(arg as usize) else {
+                    return Err((EINVAL, format!("Only sources 1 to {} exist", self.sources.len())));
+                };
+                let mut current = self.current.lock().await;
+                let source = capture::open(capture, self.pattern).await.map_err(failed)?;
+                let message = format!("Streaming source {} ({})", arg + 1, source.name());
+                screen.switch(source).map_err(failed)?;
+                *current = arg as usize;
+                Ok(message)
+            }
+            SuperviseAction::RotateLogs => {
+                let Some(log_file) = &self.log_file else {
+                    return Err((EOPNOTSUPP, "Logs go to stderr; see --log-file".to_string()));
+                };
+                log_file.reopen().map_err(failed)?;
+                Ok("Logs rotated".to_string())
+            }
+        }
+    }
+}
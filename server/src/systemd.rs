@@ -0,0 +1,42 @@
+// IP Display Server - systemd
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Running as a systemd service (`--systemd`). The server says it is
+//! ready once it listens, which `Type=notify` units wait for, and takes
+//! its listening socket from a `.socket` unit when one started it, so the
+//! port is open from boot and connections made while the server restarts
+//! wait in the backlog instead of being refused. Outside systemd every
+//! call here does nothing.
+
+use anyhow::{Context, Result};
+use socket2::{Socket, Type};
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, OwnedFd};
+
+/// The listening socket systemd passed, if it started us for a
+/// `ListenStream=` socket; only the first is used
+pub fn listener() -> Result<Option<TcpListener>> {
+    let Some(fd) = sd_notify::listen_fds().context("Bad LISTEN_FDS from systemd")?.next() else {
+        return Ok(None);
+    };
+    // SAFETY: `listen_fds` only yields descriptors systemd passed to this
+    // process, each once, and nothing else in the process owns them
+    let socket = Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    if socket.r#type()? != Type::STREAM {
+        return Err(anyhow::anyhow!("The socket systemd passed isn't a stream socket; use ListenStream="));
+    }
+    Ok(Some(socket.into()))
+}
+
+/// Tell systemd the server is serving, as it shows in `systemctl status`
+pub fn ready(status: &str) -> Result<()> {
+    sd_notify::notify(false, &[sd_notify::NotifyState::Ready, sd_notify::NotifyState::Status(status)])
+        .context("Failed to notify systemd")
+}
+
+/// Tell systemd the server is draining its clients
+pub fn stopping() -> Result<()> {
+    sd_notify::notify(false, &[sd_notify::NotifyState::Stopping, sd_notify::NotifyState::Status("Draining clients")])
+        .context("Failed to notify systemd")
+}
@@ -0,0 +1,87 @@
+// IP Display Sources - Other Remote Display Protocols
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Display sources other than an IP Display server, so the client can
+//! show any remote desktop it has a backend for. A source is opened from
+//! a URI whose scheme picks the backend (`spice://host:5900`,
+//! `ndi:Camera 1`). Each
+//! backend sits behind its own feature, since each links a system
+//! library.
+
+use anyhow::Result;
+
+pub use ipdisp_decoder::Picture;
+
+#[cfg(feature = "ndi")]
+pub mod ndi;
+#[cfg(feature = "spice")]
+pub mod spice;
+
+/// Takes each picture a source draws
+pub type PictureSink = Box<dyn FnMut(Picture)>;
+
+/// A remote display that draws into the client's renderer
+pub trait DisplaySource {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+
+    /// Start connecting. Sources run on the GLib main context of the
+    /// calling thread and hand `sink` each new picture there, stamped in
+    /// nanoseconds since the Unix epoch.
+    fn connect(&mut self, sink: PictureSink) -> Result<()>;
+
+    /// Close the connection; no more pictures arrive after this
+    fn disconnect(&mut self);
+}
+
+/// The source for `uri`
+pub fn open(uri: &str) -> Result<Box<dyn DisplaySource>> {
+    let (scheme, rest) = uri
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Source {} has no scheme, e.g. spice://host:5900", uri))?;
+    match scheme {
+        "spice" => spice(uri),
+        "ndi" => ndi(rest),
+        _ => Err(anyhow::anyhow!("Unknown source scheme {}: (have: spice:, ndi:)", scheme)),
+    }
+}
+
+#[cfg(feature = "spice")]
+fn spice(uri: &str) -> Result<Box<dyn DisplaySource>> {
+    Ok(Box::new(spice::SpiceSource::new(uri)))
+}
+
+#[cfg(not(feature = "spice"))]
+fn spice(_uri: &str) -> Result<Box<dyn DisplaySource>> {
+    Err(anyhow::anyhow!("Built without SPICE support (the spice feature)"))
+}
+
+#[cfg(feature = "ndi")]
+fn ndi(name: &str) -> Result<Box<dyn DisplaySource>> {
+    match name {
+        "" => Err(anyhow::anyhow!("No NDI source named, e.g. ndi:Camera 1")),
+        _ => Ok(Box::new(ndi::NdiSource::new(name))),
+    }
+}
+
+#[cfg(not(feature = "ndi"))]
+fn ndi(_name: &str) -> Result<Box<dyn DisplaySource>> {
+    Err(anyhow::anyhow!("Built without NDI support (the ndi feature)"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open() {
+        assert!(open("localhost:5900").is_err());
+        let error = open("rdp://localhost").err().unwrap();
+        assert!(error.to_string().contains("rdp:"));
+        let spice = open("spice://localhost:5900");
+        assert_eq!(spice.is_ok(), cfg!(feature = "spice"));
+        assert_eq!(open("ndi:STUDIO (Camera 1)").is_ok(), cfg!(feature = "ndi"));
+        assert!(open("ndi:").is_err());
+    }
+}
@@ -0,0 +1,345 @@
+// IP Display Sources - NDI
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! `ndi:NAME` through the NDI runtime. The runtime isn't redistributable,
+//! so it is loaded when a source is opened rather than linked: from
+//! `$NDI_RUNTIME_DIR_V6` or `$NDI_RUNTIME_DIR_V5` if set, else wherever
+//! the dynamic loader finds `libndi.so.6` or `libndi.so.5`.
+//!
+//! NAME is either the full NDI name, `MACHINE (Source)`, or just the part
+//! in brackets. A thread waits for the source to be announced, then
+//! receives its video as RGBA and hands each frame to the main context,
+//! dropping frames the main context hasn't caught up with.
+
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::{DisplaySource, Picture, PictureSink};
+
+/// How long each wait for sources or frames lasts, which bounds how long
+/// `disconnect` takes
+const WAIT_MS: u32 = 100;
+
+/// How often to say the source still hasn't been found
+const NOT_FOUND_REMINDER: Duration = Duration::from_secs(10);
+
+/// `NDIlib_recv_color_format_RGBX_RGBA`
+const COLOR_FORMAT_RGBX_RGBA: c_int = 2;
+/// `NDIlib_recv_bandwidth_highest`
+const BANDWIDTH_HIGHEST: c_int = 100;
+/// `NDIlib_frame_type_video`
+const FRAME_TYPE_VIDEO: c_int = 1;
+/// `NDIlib_frame_type_error`
+const FRAME_TYPE_ERROR: c_int = 4;
+
+const FOURCC_RGBA: u32 = u32::from_le_bytes(*b"RGBA");
+const FOURCC_RGBX: u32 = u32::from_le_bytes(*b"RGBX");
+
+/// `NDIlib_source_t`
+#[repr(C)]
+struct Source {
+    name: *const c_char,
+    url_address: *const c_char,
+}
+
+/// `NDIlib_find_create_t`
+#[repr(C)]
+struct FindCreate {
+    show_local_sources: bool,
+    groups: *const c_char,
+    extra_ips: *const c_char,
+}
+
+/// `NDIlib_recv_create_v3_t`
+#[repr(C)]
+struct RecvCreate {
+    source: Source,
+    color_format: c_int,
+    bandwidth: c_int,
+    allow_video_fields: bool,
+    recv_name: *const c_char,
+}
+
+/// `NDIlib_video_frame_v2_t`
+#[repr(C)]
+struct VideoFrame {
+    xres: c_int,
+    yres: c_int,
+    fourcc: u32,
+    frame_rate_n: c_int,
+    frame_rate_d: c_int,
+    picture_aspect_ratio: f32,
+    frame_format_type: c_int,
+    timecode: i64,
+    data: *mut u8,
+    line_stride: c_int,
+    metadata: *const c_char,
+    timestamp: i64,
+}
+
+/// The parts of the runtime used here
+struct Runtime {
+    initialize: unsafe extern "C" fn() -> bool,
+    find_create: unsafe extern "C" fn(*const FindCreate) -> *mut c_void,
+    find_wait: unsafe extern "C" fn(*mut c_void, u32) -> bool,
+    find_sources: unsafe extern "C" fn(*mut c_void, *mut u32) -> *const Source,
+    find_destroy: unsafe extern "C" fn(*mut c_void),
+    recv_create: unsafe extern "C" fn(*const RecvCreate) -> *mut c_void,
+    recv_capture: unsafe extern "C" fn(*mut c_void, *mut VideoFrame, *mut c_void, *mut c_void, u32) -> c_int,
+    recv_free_video: unsafe extern "C" fn(*mut c_void, *const VideoFrame),
+    recv_destroy: unsafe extern "C" fn(*mut c_void),
+    /// Keeps the functions above loaded
+    _library: libloading::Library,
+}
+
+impl Runtime {
+    fn load() -> Result<Self> {
+        let mut candidates: Vec<PathBuf> = Vec::new();
+        for (variable, file) in [("NDI_RUNTIME_DIR_V6", "libndi.so.6"), ("NDI_RUNTIME_DIR_V5", "libndi.so.5")] {
+            if let Some(dir) = std::env::var_os(variable) {
+                candidates.push(PathBuf::from(dir).join(file));
+            }
+        }
+        candidates.extend(["libndi.so.6", "libndi.so.5"].map(PathBuf::from));
+        // SAFETY: the NDI runtime has no load-time side effects to worry about
+        let library = candidates
+            .iter()
+            .find_map(|path| unsafe { libloading::Library::new(path) }.ok())
+            .ok_or_else(|| anyhow::anyhow!("NDI runtime not found; install it from ndi.video"))?;
+
+        // SAFETY: the types match the NDI SDK headers
+        unsafe {
+            let runtime = Self {
+                initialize: *library.get(b"NDIlib_initialize\0")?,
+                find_create: *library.get(b"NDIlib_find_create_v2\0")?,
+                find_wait: *library.get(b"NDIlib_find_wait_for_sources\0")?,
+                find_sources: *library.get(b"NDIlib_find_get_current_sources\0")?,
+                find_destroy: *library.get(b"NDIlib_find_destroy\0")?,
+                recv_create: *library.get(b"NDIlib_recv_create_v3\0")?,
+                recv_capture: *library.get(b"NDIlib_recv_capture_v2\0")?,
+                recv_free_video: *library.get(b"NDIlib_recv_free_video_v2\0")?,
+                recv_destroy: *library.get(b"NDIlib_recv_destroy\0")?,
+                _library: library,
+            };
+            if !(runtime.initialize)() {
+                return Err(anyhow::anyhow!("NDI isn't supported on this CPU"));
+            }
+            Ok(runtime)
+        }
+    }
+}
+
+/// An NDI sender on the local network
+pub struct NdiSource {
+    name: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NdiSource {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), stop: Arc::default(), thread: None }
+    }
+}
+
+impl DisplaySource for NdiSource {
+    fn name(&self) -> &'static str {
+        "ndi"
+    }
+
+    fn connect(&mut self, mut sink: PictureSink) -> Result<()> {
+        self.disconnect();
+        let runtime = Runtime::load()?;
+        // The thread keeps at most one frame waiting
+        let (mut pictures, mut received) = futures_channel::mpsc::channel(0);
+        self.stop = Arc::default();
+        let (name, stop) = (self.name.clone(), Arc::clone(&self.stop));
+        let thread = std::thread::Builder::new()
+            .name("ndi-receive".to_string())
+            .spawn(move || {
+                receive(&runtime, &name, &stop, |picture| match pictures.try_send(picture) {
+                    Ok(()) => true,
+                    Err(e) => !e.is_disconnected(),
+                })
+            })
+            .context("Failed to start the NDI receive thread")?;
+        self.thread = Some(thread);
+
+        glib::MainContext::ref_thread_default().spawn_local(async move {
+            while let Some(picture) = received.next().await {
+                sink(picture);
+            }
+        });
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for NdiSource {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+/// Find `wanted` and pass its frames to `send` until `stop` is set or
+/// `send` returns false
+fn receive(runtime: &Runtime, wanted: &str, stop: &AtomicBool, mut send: impl FnMut(Picture) -> bool) {
+    let Some(recv) = find(runtime, wanted, stop) else { return };
+    info!("Receiving NDI source {}", wanted);
+    let mut failing = false;
+    while !stop.load(Ordering::Relaxed) {
+        // SAFETY: an all-zero frame is what the SDK expects to fill in
+        let mut frame: VideoFrame = unsafe { std::mem::zeroed() };
+        // SAFETY: recv is live and audio and metadata are not wanted
+        let kind = unsafe {
+            (runtime.recv_capture)(recv, &mut frame, std::ptr::null_mut(), std::ptr::null_mut(), WAIT_MS)
+        };
+        match kind {
+            FRAME_TYPE_VIDEO => {
+                failing = false;
+                let picture = to_picture(&frame);
+                // SAFETY: the frame came from this receiver and isn't used again
+                unsafe { (runtime.recv_free_video)(recv, &frame) };
+                if let Some(picture) = picture {
+                    if !send(picture) {
+                        break;
+                    }
+                }
+            }
+            // The runtime reconnects by itself
+            FRAME_TYPE_ERROR if !std::mem::replace(&mut failing, true) => warn!("Lost NDI source {}", wanted),
+            _ => {}
+        }
+    }
+    // SAFETY: recv is live and not used after this
+    unsafe { (runtime.recv_destroy)(recv) };
+}
+
+/// A receiver for `wanted` once it has been announced, or `None` if
+/// stopped first
+fn find(runtime: &Runtime, wanted: &str, stop: &AtomicBool) -> Option<*mut c_void> {
+    let settings = FindCreate { show_local_sources: true, groups: std::ptr::null(), extra_ips: std::ptr::null() };
+    // SAFETY: settings outlives the call
+    let finder = unsafe { (runtime.find_create)(&settings) };
+    if finder.is_null() {
+        warn!("Failed to look for NDI sources");
+        return None;
+    }
+    let recv_name = CString::new("IP Display Client").unwrap();
+    let mut reminder = std::time::Instant::now() + NOT_FOUND_REMINDER;
+    let mut recv = None;
+    while recv.is_none() && !stop.load(Ordering::Relaxed) {
+        // SAFETY: finder is live
+        unsafe { (runtime.find_wait)(finder, WAIT_MS) };
+        let mut count = 0;
+        // SAFETY: the list stays valid until the next call on finder
+        let sources = unsafe { (runtime.find_sources)(finder, &mut count) };
+        let sources = match sources.is_null() {
+            true => &[][..],
+            // SAFETY: the runtime returned `count` sources here
+            false => unsafe { std::slice::from_raw_parts(sources, count as usize) },
+        };
+        let found = sources.iter().find(|source| {
+            // SAFETY: source names are NUL terminated strings
+            !source.name.is_null() && matches(&unsafe { CStr::from_ptr(source.name) }.to_string_lossy(), wanted)
+        });
+        if let Some(source) = found {
+            let settings = RecvCreate {
+                source: Source { name: source.name, url_address: source.url_address },
+                color_format: COLOR_FORMAT_RGBX_RGBA,
+                bandwidth: BANDWIDTH_HIGHEST,
+                allow_video_fields: false,
+                recv_name: recv_name.as_ptr(),
+            };
+            // SAFETY: settings and the source it points to outlive the call
+            let created = unsafe { (runtime.recv_create)(&settings) };
+            if created.is_null() {
+                warn!("Failed to connect to NDI source {}", wanted);
+                break;
+            }
+            recv = Some(created);
+        } else if std::time::Instant::now() >= reminder {
+            info!("Still looking for NDI source {}", wanted);
+            reminder += NOT_FOUND_REMINDER;
+        }
+    }
+    // SAFETY: finder is live and not used after this
+    unsafe { (runtime.find_destroy)(finder) };
+    recv
+}
+
+/// Whether the NDI name `name`, `MACHINE (Source)`, is the one asked for
+fn matches(name: &str, wanted: &str) -> bool {
+    let source = name
+        .rsplit_once(" (")
+        .and_then(|(_, source)| source.strip_suffix(')'));
+    name == wanted || source == Some(wanted)
+}
+
+fn to_picture(frame: &VideoFrame) -> Option<Picture> {
+    let opaque = match frame.fourcc {
+        FOURCC_RGBA => false,
+        FOURCC_RGBX => true,
+        _ => return None,
+    };
+    let (width, height, stride) = (frame.xres.max(0) as u32, frame.yres.max(0) as usize, frame.line_stride.max(0) as usize);
+    if frame.data.is_null() || stride < width as usize * 4 {
+        return None;
+    }
+    // SAFETY: the frame holds `height` rows `stride` bytes apart until freed
+    let data = unsafe { std::slice::from_raw_parts(frame.data, stride * height) };
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    Some(Picture { width, height: height as u32, timestamp, data: pack_rows(data, width, stride, opaque) })
+}
+
+/// Rows of RGBA or RGBX, `stride` bytes apart, as packed RGBA
+fn pack_rows(data: &[u8], width: u32, stride: usize, opaque: bool) -> Vec<u8> {
+    let mut packed: Vec<u8> = data.chunks_exact(stride).flat_map(|row| &row[..width as usize * 4]).copied().collect();
+    if opaque {
+        packed.chunks_exact_mut(4).for_each(|pixel| pixel[3] = 255);
+    }
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ndi_names() {
+        assert!(matches("STUDIO-PC (Camera 1)", "Camera 1"));
+        assert!(matches("STUDIO-PC (Camera 1)", "STUDIO-PC (Camera 1)"));
+        assert!(matches("OB (VAN) (Program)", "Program"));
+        assert!(!matches("STUDIO-PC (Camera 1)", "Camera"));
+        assert!(!matches("STUDIO-PC (Camera 1)", "STUDIO-PC"));
+    }
+
+    #[test]
+    fn test_ndi_pictures() {
+        let data = [1, 2, 3, 0, 9, 9, 4, 5, 6, 0, 9, 9];
+        assert_eq!(pack_rows(&data, 1, 6, true), [1, 2, 3, 255, 4, 5, 6, 255]);
+        assert_eq!(pack_rows(&data, 1, 6, false), [1, 2, 3, 0, 4, 5, 6, 0]);
+
+        let mut data = data;
+        let mut frame: VideoFrame = unsafe { std::mem::zeroed() };
+        (frame.xres, frame.yres, frame.line_stride, frame.data) = (1, 2, 6, data.as_mut_ptr());
+        assert!(to_picture(&frame).is_none());
+        frame.fourcc = FOURCC_RGBX;
+        let picture = to_picture(&frame).unwrap();
+        assert_eq!((picture.width, picture.height, picture.data.len()), (1, 2, 8));
+    }
+}
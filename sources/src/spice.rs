@@ -0,0 +1,180 @@
+// IP Display Sources - SPICE
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! `spice://host:port` through the system's spice-client-glib, which is
+//! called directly since it has no Rust bindings. Only the first display
+//! channel is shown. The session runs on the GLib main context; changed
+//! areas are collected until it goes idle and the whole primary surface
+//! is copied out then, so a burst of small updates costs one picture.
+//! Surfaces in formats other than 32-bit xRGB, which QEMU and the SPICE
+//! server always use for the primary surface, are not shown.
+
+use anyhow::Result;
+use glib::ffi::{gboolean, GFALSE};
+use glib::gobject_ffi::GObject;
+use glib::prelude::*;
+use glib::translate::from_glib_full;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{DisplaySource, Picture, PictureSink};
+
+/// `SPICE_SURFACE_FMT_32_xRGB`: B, G, R and an unused byte in memory
+const FORMAT_XRGB32: i32 = 32;
+
+#[link(name = "spice-client-glib-2.0")]
+extern "C" {
+    fn spice_session_new() -> *mut GObject;
+    fn spice_session_connect(session: *mut GObject) -> gboolean;
+    fn spice_session_disconnect(session: *mut GObject);
+    fn spice_channel_connect(channel: *mut GObject) -> gboolean;
+}
+
+/// A SPICE server, e.g. a QEMU guest started with `-spice port=5900`
+pub struct SpiceSource {
+    uri: String,
+    session: Option<glib::Object>,
+}
+
+impl SpiceSource {
+    pub fn new(uri: &str) -> Self {
+        Self { uri: uri.to_string(), session: None }
+    }
+}
+
+impl DisplaySource for SpiceSource {
+    fn name(&self) -> &'static str {
+        "spice"
+    }
+
+    fn connect(&mut self, sink: PictureSink) -> Result<()> {
+        self.disconnect();
+        // SAFETY: spice_session_new returns a new reference we take over
+        let session: glib::Object = unsafe { from_glib_full(spice_session_new()) };
+        session.set_property("uri", &self.uri);
+
+        let sink = Rc::new(RefCell::new(sink));
+        session.connect_local("channel-new", false, move |args| {
+            let channel = args[1].get::<glib::Object>().ok()?;
+            if channel.type_().name() == "SpiceDisplayChannel" && channel.property::<i32>("channel-id") == 0 {
+                watch_display(&channel, Rc::clone(&sink));
+                // SAFETY: the channel is a SpiceChannel the session keeps alive
+                unsafe { spice_channel_connect(channel.as_ptr()) };
+            }
+            None
+        });
+        // SAFETY: session is a live SpiceSession
+        if unsafe { spice_session_connect(session.as_ptr()) } == GFALSE {
+            return Err(anyhow::anyhow!("Failed to connect to {}", self.uri));
+        }
+        self.session = Some(session);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(session) = self.session.take() {
+            // SAFETY: session is a live SpiceSession
+            unsafe { spice_session_disconnect(session.as_ptr()) };
+        }
+    }
+}
+
+impl Drop for SpiceSource {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+/// The display channel's primary surface, owned by the channel
+struct Surface {
+    format: i32,
+    width: u32,
+    height: u32,
+    stride: usize,
+    data: *const u8,
+    /// An idle copy is already queued
+    pending: bool,
+}
+
+impl Surface {
+    fn picture(&self) -> Option<Picture> {
+        if self.format != FORMAT_XRGB32 || self.data.is_null() || self.stride < self.width as usize * 4 {
+            return None;
+        }
+        // SAFETY: the channel keeps the surface until display-primary-destroy,
+        // which drops this first; both run on this thread
+        let data = unsafe { std::slice::from_raw_parts(self.data, self.stride * self.height as usize) };
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        Some(Picture { width: self.width, height: self.height, timestamp, data: xrgb_to_rgba(data, self.width, self.stride) })
+    }
+}
+
+/// Hand `sink` a picture after each burst of updates on `channel`
+fn watch_display(channel: &glib::Object, sink: Rc<RefCell<PictureSink>>) {
+    let primary: Rc<RefCell<Option<Surface>>> = Rc::default();
+
+    let created = Rc::clone(&primary);
+    channel.connect_local("display-primary-create", false, move |args| {
+        let int = |index: usize| args[index].get::<i32>().unwrap_or(0).max(0);
+        *created.borrow_mut() = Some(Surface {
+            format: args[1].get::<i32>().ok()?,
+            width: int(2) as u32,
+            height: int(3) as u32,
+            stride: int(4) as usize,
+            data: args[6].get::<glib::Pointer>().ok()? as *const u8,
+            pending: false,
+        });
+        None
+    });
+    let destroyed = Rc::clone(&primary);
+    channel.connect_local("display-primary-destroy", false, move |_| {
+        destroyed.borrow_mut().take();
+        None
+    });
+    channel.connect_local("display-invalidate", false, move |_| {
+        let mut surface = primary.borrow_mut();
+        if !std::mem::replace(&mut surface.as_mut()?.pending, true) {
+            let (primary, sink) = (Rc::clone(&primary), Rc::clone(&sink));
+            glib::idle_add_local_once(move || {
+                let picture = match primary.borrow_mut().as_mut() {
+                    Some(surface) => {
+                        surface.pending = false;
+                        surface.picture()
+                    }
+                    None => None,
+                };
+                if let Some(picture) = picture {
+                    (sink.borrow_mut())(picture);
+                }
+            });
+        }
+        None
+    });
+}
+
+/// Rows of 32-bit xRGB, `stride` bytes apart, as packed opaque RGBA
+fn xrgb_to_rgba(data: &[u8], width: u32, stride: usize) -> Vec<u8> {
+    data.chunks_exact(stride)
+        .flat_map(|row| row[..width as usize * 4].chunks_exact(4))
+        .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], 255])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xrgb_to_rgba() {
+        // Two rows of one pixel, padded to eight bytes
+        let data = [1, 2, 3, 0, 9, 9, 9, 9, 4, 5, 6, 0, 9, 9, 9, 9];
+        assert_eq!(xrgb_to_rgba(&data, 1, 8), [3, 2, 1, 255, 6, 5, 4, 255]);
+
+        let surface = Surface { format: 16, width: 1, height: 2, stride: 8, data: data.as_ptr(), pending: false };
+        assert!(surface.picture().is_none());
+        let surface = Surface { format: FORMAT_XRGB32, ..surface };
+        assert_eq!(surface.picture().unwrap().data, [3, 2, 1, 255, 6, 5, 4, 255]);
+    }
+}
@@ -0,0 +1,13 @@
+// IP Display Transports - Alternatives to Plain TCP
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! Ways to carry the packet stream when a plain TCP connection to the
+//! server won't do. Each one ends up as an `AsyncRead` and `AsyncWrite`
+//! pair, so the network code reads and writes the same byte stream it
+//! would over TCP. Each sits behind its own feature.
+
+#[cfg(feature = "quic")]
+pub mod quic;
+#[cfg(feature = "websocket")]
+pub mod websocket;
@@ -0,0 +1,221 @@
+// IP Display Transports - QUIC
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! IPDS over QUIC, for links that lose packets or change address. The
+//! packet stream goes unchanged over one bidirectional QUIC stream, opened
+//! by the client with its Hello. A lost datagram only holds up the stream
+//! until it is resent, without TCP's backoff, and the connection outlives
+//! the client's address changing (a NAT rebinding, or Wi-Fi to wired)
+//! instead of having to reconnect and resume.
+//!
+//! TLS is there because QUIC needs it, not to say who the server is: the
+//! server makes up a self-signed certificate when it starts, and the
+//! client takes any certificate whose key signs the handshake. Knowing the
+//! server is still down to pairing and Noise, the same as over TCP. The
+//! kernel module doesn't speak QUIC; `ipdisp-server --quic` does.
+
+use anyhow::Result;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Protocol named in the TLS handshake, so neither end mistakes the other
+/// for some other QUIC service on the port
+pub const ALPN: &[u8] = b"ipds";
+
+/// Name in the server's certificate; the client asks for it but doesn't
+/// check it
+const SERVER_NAME: &str = "ipdisp";
+
+/// Read side of a QUIC connection
+#[derive(Debug)]
+pub struct QuicReader {
+    stream: RecvStream,
+    /// Keeps the socket served for as long as either side is in use
+    _endpoint: Endpoint,
+}
+
+/// Write side of a QUIC connection
+#[derive(Debug)]
+pub struct QuicWriter {
+    stream: SendStream,
+    connection: Connection,
+    _endpoint: Endpoint,
+}
+
+impl QuicWriter {
+    /// Where the other end is now; this changes if it moves
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+}
+
+impl AsyncRead for QuicReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.get_mut().stream), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.get_mut().stream), cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.get_mut().stream), cx)
+    }
+}
+
+/// Connect to `server` from `socket`, already bound as the caller wants,
+/// and open the stream. Must be called within a Tokio runtime.
+pub async fn connect(socket: UdpSocket, server: SocketAddr) -> Result<(QuicReader, QuicWriter)> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let runtime = quinn::default_runtime().ok_or_else(|| anyhow::anyhow!("QUIC needs a Tokio runtime"))?;
+    let mut endpoint = Endpoint::new(quinn::EndpointConfig::default(), None, socket, runtime)?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?)));
+
+    let connection = endpoint.connect(server, SERVER_NAME)?.await?;
+    let (send, recv) = connection.open_bi().await?;
+    Ok((
+        QuicReader { stream: recv, _endpoint: endpoint.clone() },
+        QuicWriter { stream: send, connection, _endpoint: endpoint },
+    ))
+}
+
+/// Accepts QUIC connections from clients
+#[derive(Debug)]
+pub struct QuicListener {
+    endpoint: Endpoint,
+}
+
+impl QuicListener {
+    /// Listen on `addr` with a new self-signed certificate. Must be called
+    /// within a Tokio runtime.
+    pub fn bind(addr: SocketAddr) -> Result<Self> {
+        let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(vec![certified.cert.der().clone()], key)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+        Ok(Self { endpoint: Endpoint::server(config, addr)? })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// The next client's stream, waiting for it to send its first bytes;
+    /// `None` once the listener is closed
+    pub async fn accept(&self) -> Option<Result<(QuicReader, QuicWriter)>> {
+        let incoming = self.endpoint.accept().await?;
+        let endpoint = self.endpoint.clone();
+        Some(async move {
+            let connection = incoming.await?;
+            let (send, recv) = connection.accept_bi().await?;
+            Ok((
+                QuicReader { stream: recv, _endpoint: endpoint.clone() },
+                QuicWriter { stream: send, connection, _endpoint: endpoint },
+            ))
+        }.await)
+    }
+}
+
+/// Takes whichever certificate the server shows, as long as its key signs
+/// the handshake
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let listener = QuicListener::bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut reader, mut writer) = listener.accept().await.unwrap().unwrap();
+            let mut hello = [0u8; 5];
+            reader.read_exact(&mut hello).await.unwrap();
+            writer.write_all(b"frame").await.unwrap();
+            writer.shutdown().await.unwrap();
+            // Dropping the connection would cut off what's still unsent
+            reader.read_to_end(&mut Vec::new()).await.unwrap();
+            hello
+        });
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let (mut reader, mut writer) = connect(socket, server_addr).await.unwrap();
+        assert_eq!(writer.remote_address(), server_addr);
+        // The server only sees the stream once something is sent on it
+        writer.write_all(b"hello").await.unwrap();
+        let mut frame = Vec::new();
+        reader.read_to_end(&mut frame).await.unwrap();
+        assert_eq!(frame, b"frame");
+        writer.shutdown().await.unwrap();
+        assert_eq!(&server.await.unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,472 @@
+// IP Display Transports - WebSocket
+// Copyright (c) 2024
+// Licensed under MIT
+
+//! IPDS over WebSocket, for reaching a server through reverse proxies that
+//! only pass HTTP. The packet stream is carried unchanged in binary
+//! messages, split wherever the sender likes, so a plain WebSocket-to-TCP
+//! bridge (e.g. websockify) in front of the kernel module is enough; the
+//! module itself has no WebSocket support. `WsReader` and `WsWriter` undo
+//! and apply the framing so the network code keeps reading and writing a
+//! byte stream, and between them answer the bridge's pings.
+
+use anyhow::Result;
+use rand_core::{OsRng, RngCore};
+use sha1::{Digest, Sha1};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Appended to the client's key to form the accept value (RFC 6455 1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest handshake response we read before giving up
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Control frames carry at most this much (RFC 6455 5.5)
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+/// Upgrade an HTTP connection to `host` (as sent in the Host header) to a
+/// WebSocket on `path`
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, host: &str, path: &str) -> Result<()> {
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+    let key = base64(&nonce);
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        path, host, key
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    // Byte at a time so nothing after the headers is consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE_SIZE {
+            return Err(anyhow::anyhow!("WebSocket handshake response too long"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let response = String::from_utf8_lossy(&response);
+
+    let mut lines = response.lines();
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(anyhow::anyhow!("Server refused the WebSocket upgrade: {}", status));
+    }
+    let accept = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-accept"))
+        .map(|(_, value)| value.trim());
+    if accept != Some(accept_key(&key).as_str()) {
+        return Err(anyhow::anyhow!("Server sent a bad Sec-WebSocket-Accept"));
+    }
+    Ok(())
+}
+
+fn accept_key(key: &str) -> String {
+    base64(&Sha1::new().chain_update(key).chain_update(ACCEPT_GUID).finalize())
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// What a frame's payload is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PayloadKind {
+    /// Part of the packet stream
+    Data,
+    /// A ping, kept to echo back in the pong
+    Ping,
+    /// Anything else, read and dropped
+    Skip,
+}
+
+#[derive(Debug)]
+enum ReadState {
+    /// Collecting a frame header; the first `filled` bytes of `header` are in
+    Header { filled: usize },
+    /// Inside a frame's payload
+    Payload { remaining: u64, mask: Option<[u8; 4]>, offset: usize, kind: PayloadKind },
+    Closed,
+}
+
+/// Framed pongs the reader has queued for the writer to send
+type Pongs = Arc<Mutex<Vec<u8>>>;
+
+/// Wrap the two halves of an upgraded connection. Pings read on `read` are
+/// answered on `write` ahead of the next message, which is never more than
+/// a heartbeat interval away.
+pub fn split<R, W>(read: R, write: W) -> (WsReader<R>, WsWriter<W>) {
+    let pongs = Pongs::default();
+    let reader = WsReader {
+        inner: read,
+        header: [0; 14],
+        state: ReadState::Header { filled: 0 },
+        ping: Vec::new(),
+        pongs: pongs.clone(),
+    };
+    let writer = WsWriter { inner: write, pending: Vec::new(), sent: 0, pongs };
+    (reader, writer)
+}
+
+/// Reads the payload bytes of the binary messages on `inner` as one stream.
+/// Pings are queued for the writer to answer, other control frames are
+/// skipped and a close frame reads as end of stream.
+#[derive(Debug)]
+pub struct WsReader<R> {
+    inner: R,
+    header: [u8; 14],
+    state: ReadState,
+    /// Payload of the ping being read
+    ping: Vec<u8>,
+    pongs: Pongs,
+}
+
+/// Length of a frame header given its first two bytes
+fn header_size(start: &[u8]) -> usize {
+    let extended = match start[1] & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    2 + extended + if start[1] & 0x80 != 0 { 4 } else { 0 }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for WsReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // Nothing to read into; don't mistake the empty read for EOF below
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            match this.state {
+                ReadState::Closed => return Poll::Ready(Ok(())),
+                ReadState::Header { filled } => {
+                    let needed = if filled < 2 { 2 } else { header_size(&this.header) };
+                    if filled < needed {
+                        let mut part = ReadBuf::new(&mut this.header[filled..needed]);
+                        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut part))?;
+                        let read = part.filled().len();
+                        if read == 0 {
+                            // A clean end between frames is an ordinary EOF
+                            if filled == 0 {
+                                this.state = ReadState::Closed;
+                                continue;
+                            }
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                        }
+                        this.state = ReadState::Header { filled: filled + read };
+                        continue;
+                    }
+
+                    let header = &this.header[..needed];
+                    let opcode = header[0] & 0x0f;
+                    let (length, rest) = match header[1] & 0x7f {
+                        126 => (u16::from_be_bytes([header[2], header[3]]) as u64, &header[4..]),
+                        127 => (u64::from_be_bytes(header[2..10].try_into().unwrap()), &header[10..]),
+                        length => (length as u64, &header[2..]),
+                    };
+                    let mask = (header[1] & 0x80 != 0).then(|| rest[..4].try_into().unwrap());
+                    this.state = match opcode {
+                        OPCODE_CLOSE => ReadState::Closed,
+                        OPCODE_TEXT => {
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "text WebSocket message on a binary stream",
+                            )));
+                        }
+                        OPCODE_PING if length > MAX_CONTROL_PAYLOAD => {
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "WebSocket ping longer than 125 bytes",
+                            )));
+                        }
+                        _ => {
+                            let kind = match opcode {
+                                OPCODE_BINARY | OPCODE_CONTINUATION => PayloadKind::Data,
+                                OPCODE_PING => PayloadKind::Ping,
+                                _ => PayloadKind::Skip,
+                            };
+                            this.ping.clear();
+                            ReadState::Payload { remaining: length, mask, offset: 0, kind }
+                        }
+                    };
+                }
+                ReadState::Payload { remaining: 0, kind, .. } => {
+                    if kind == PayloadKind::Ping {
+                        let pong = frame(OPCODE_PONG, &this.ping);
+                        this.pongs.lock().unwrap().extend_from_slice(&pong);
+                    }
+                    this.state = ReadState::Header { filled: 0 };
+                }
+                ReadState::Payload { remaining, mask, offset, kind } => {
+                    let data = kind == PayloadKind::Data;
+                    let mut skipped = [0u8; 128];
+                    let target = if data { buf.initialize_unfilled() } else { &mut skipped[..] };
+                    let want = remaining.min(target.len() as u64) as usize;
+                    let mut part = ReadBuf::new(&mut target[..want]);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut part))?;
+                    let read = part.filled().len();
+                    if read == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    if let Some(mask) = mask {
+                        for (i, byte) in target[..read].iter_mut().enumerate() {
+                            *byte ^= mask[(offset + i) % 4];
+                        }
+                    }
+                    if kind == PayloadKind::Ping {
+                        this.ping.extend_from_slice(&target[..read]);
+                    }
+                    this.state = ReadState::Payload {
+                        remaining: remaining - read as u64,
+                        mask,
+                        offset: offset + read,
+                        kind,
+                    };
+                    if data {
+                        buf.advance(read);
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sends each write as one masked binary message on `inner`, after any
+/// pongs the reader has queued
+#[derive(Debug)]
+pub struct WsWriter<W> {
+    inner: W,
+    /// Framed bytes not yet accepted by `inner`
+    pending: Vec<u8>,
+    sent: usize,
+    pongs: Pongs,
+}
+
+impl<W: AsyncWrite + Unpin> WsWriter<W> {
+    /// Write out `pending`, then any pongs queued meanwhile
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.sent == self.pending.len() {
+                self.pending.clear();
+                self.sent = 0;
+                std::mem::swap(&mut self.pending, &mut self.pongs.lock().unwrap());
+                if self.pending.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.sent..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.sent += written;
+        }
+    }
+}
+
+/// One masked frame carrying `payload`, as clients must send them
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mut mask = [0u8; 4];
+    OsRng.fill_bytes(&mut mask);
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    frame
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WsWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        this.pending = frame(OPCODE_BINARY, buf);
+        // Accepted once framed; the bytes go out on the next write or flush
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Server frame (unmasked) with the given opcode
+    fn server_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode];
+        if payload.len() < 126 {
+            frame.push(payload.len() as u8);
+        } else {
+            frame.push(126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // The example from RFC 6455 section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let bridge = tokio::spawn(async move {
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(server.read_u8().await.unwrap());
+            }
+            let request = String::from_utf8(request).unwrap();
+            assert!(request.starts_with("GET /ipds HTTP/1.1\r\nHost: display:8080\r\n"));
+            let key = request
+                .lines()
+                .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                .unwrap();
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(key)
+            );
+            server.write_all(response.as_bytes()).await.unwrap();
+            // First bytes of the stream must be left for the reader
+            server.write_all(&server_frame(OPCODE_BINARY, b"IPDS")).await.unwrap();
+        });
+
+        handshake(&mut client, "display:8080", "/ipds").await.unwrap();
+        let mut magic = [0u8; 4];
+        let (mut reader, _) = split(&mut client, tokio::io::sink());
+        reader.read_exact(&mut magic).await.unwrap();
+        assert_eq!(&magic, b"IPDS");
+        bridge.await.unwrap();
+
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut request = [0u8; 64];
+            let _ = server.read(&mut request).await;
+            server.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await.unwrap();
+        });
+        assert!(handshake(&mut client, "display:8080", "/").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reader_joins_messages() {
+        let packet: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+        let mut wire = server_frame(OPCODE_BINARY, &packet[..100]);
+        // Pings in between are skipped; a packet may span messages
+        wire.extend(server_frame(0x9, b"ping"));
+        wire.extend(server_frame(OPCODE_BINARY, &packet[100..]));
+        wire.extend(server_frame(OPCODE_CLOSE, &[]));
+        wire.extend(server_frame(OPCODE_BINARY, b"after close"));
+
+        let (mut reader, _) = split(&wire[..], tokio::io::sink());
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, packet);
+
+        // An empty buffer reads nothing without consuming the stream
+        let (mut reader, _) = split(&wire[..], tokio::io::sink());
+        assert_eq!(reader.read(&mut []).await.unwrap(), 0);
+        let mut first = [0u8; 1];
+        reader.read_exact(&mut first).await.unwrap();
+        assert_eq!(first[0], packet[0]);
+
+        let text = server_frame(OPCODE_TEXT, b"hello");
+        assert!(split(&text[..], tokio::io::sink()).0.read_to_end(&mut Vec::new()).await.is_err());
+        let truncated = &server_frame(OPCODE_BINARY, b"IPDS")[..4];
+        assert!(split(truncated, tokio::io::sink()).0.read_to_end(&mut Vec::new()).await.is_err());
+        let long_ping = server_frame(OPCODE_PING, &[0; 126]);
+        assert!(split(&long_ping[..], tokio::io::sink()).0.read_to_end(&mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pings_answered() {
+        let mut wire = server_frame(OPCODE_PING, b"are you there");
+        wire.extend(server_frame(OPCODE_BINARY, b"IPDS"));
+        let (mut reader, mut writer) = split(&wire[..], Vec::<u8>::new());
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).await.unwrap();
+        writer.write_all(b"hello").await.unwrap();
+        writer.flush().await.unwrap();
+
+        // The pong goes out first, echoing the ping, then the message
+        let sent = &writer.inner;
+        assert_eq!(sent[0], 0x80 | OPCODE_PONG);
+        assert_eq!(sent[1], 0x80 | 13);
+        let mask = &sent[2..6];
+        let echoed: Vec<u8> = sent[6..19].iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+        assert_eq!(echoed, b"are you there");
+        assert_eq!(sent[19], 0x80 | OPCODE_BINARY);
+    }
+
+    #[tokio::test]
+    async fn test_writer_masks_messages() {
+        let (_, mut writer) = split(tokio::io::empty(), Vec::new());
+        writer.write_all(b"hello").await.unwrap();
+        writer.write_all(&[7u8; 300]).await.unwrap();
+        writer.flush().await.unwrap();
+        let wire = writer.inner;
+
+        // The server side undoes masking the same way the reader does
+        let mut received = Vec::new();
+        split(&wire[..], tokio::io::sink()).0.read_to_end(&mut received).await.unwrap();
+        assert_eq!(&received[..5], b"hello");
+        assert_eq!(received[5..], [7u8; 300]);
+        assert_eq!(wire[0], 0x80 | OPCODE_BINARY);
+        assert_ne!(wire[1] & 0x80, 0);
+        assert_eq!(wire[1 + 1 + 4 + 5 + 1] & 0x7f, 126);
+    }
+}